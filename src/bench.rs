@@ -0,0 +1,226 @@
+use crate::tunnel::client::WsClient;
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+use url::Host;
+
+/// Name of the exec destination answering the measures, on the servers started with --bench-endpoint
+pub const BENCH_ENDPOINT: &str = "wstunnel-bench";
+
+const PING: u8 = b'P';
+const UPLOAD: u8 = b'U';
+const DOWNLOAD: u8 = b'D';
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The server does not let a client use its bandwidth for longer than this
+const MAX_MEASURE_DURATION: Duration = Duration::from_secs(60);
+const PING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Endpoint of the server, as the streams of an exec destination.
+/// The first byte sent by the client selects the measure:
+/// P => echo what is received, for the round trip times
+/// U + duration in ms (u64) => count the bytes received for this duration, and answer with the count (u64)
+/// D + duration in ms (u64) => send as many bytes as possible for this duration, then close
+pub(crate) fn serve_bench() -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) {
+    let (tunnel, endpoint) = tokio::io::duplex(CHUNK_SIZE * 4);
+    tokio::spawn(async move {
+        if let Err(err) = answer_measure(endpoint).await {
+            debug!("Bench measure stopped: {:?}", err);
+        }
+    });
+
+    tokio::io::split(tunnel)
+}
+
+async fn answer_measure(mut stream: DuplexStream) -> anyhow::Result<()> {
+    let measure = stream.read_u8().await?;
+    if measure == PING {
+        let (mut rx, mut tx) = tokio::io::split(stream);
+        tokio::io::copy(&mut rx, &mut tx).await?;
+        return Ok(());
+    }
+
+    let duration = Duration::from_millis(stream.read_u64().await?).min(MAX_MEASURE_DURATION);
+    let deadline = Instant::now() + duration;
+    let mut buf = vec![0; CHUNK_SIZE];
+    match measure {
+        UPLOAD => {
+            let mut received = 0;
+            while let Ok(len) = tokio::time::timeout_at(deadline, stream.read(&mut buf)).await {
+                match len? {
+                    0 => break,
+                    len => received += len as u64,
+                }
+            }
+            stream.write_u64(received).await?;
+        }
+        DOWNLOAD => {
+            while let Ok(ret) = tokio::time::timeout_at(deadline, stream.write_all(&buf)).await {
+                ret?;
+            }
+        }
+        _ => return Err(anyhow!("Unknown bench measure {}", measure)),
+    }
+
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchResult {
+    pub server: String,
+    pub duration_secs: f64,
+    pub upload_bits_per_sec: u64,
+    pub download_bits_per_sec: u64,
+    pub download_bytes: u64,
+    pub upload_bytes: u64,
+    pub pings: usize,
+    pub rtt_min_ms: f64,
+    pub rtt_avg_ms: f64,
+    pub rtt_max_ms: f64,
+    /// Mean difference between consecutive round trip times
+    pub jitter_ms: f64,
+}
+
+/// Connection accepted by the listener of the client, as the ones of its -L tunnels
+type BenchConnection = anyhow::Result<((ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), RemoteAddr)>;
+
+/// Open the tunnels of the measures through the client, to the bench endpoint of its server
+struct BenchTunnels {
+    listener: mpsc::Sender<BenchConnection>,
+}
+
+impl BenchTunnels {
+    fn new(client: WsClient) -> Self {
+        let (listener, connections) = mpsc::channel(1);
+        tokio::spawn(client.run_tunnel(ReceiverStream::new(connections)));
+        Self { listener }
+    }
+
+    async fn open(&self, measure: u8) -> anyhow::Result<DuplexStream> {
+        let (local, tunnel) = tokio::io::duplex(CHUNK_SIZE * 4);
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Exec,
+            host: Host::Domain(BENCH_ENDPOINT.to_string()),
+            port: 0,
+        };
+        self.listener
+            .send(Ok((tokio::io::split(tunnel), remote)))
+            .await
+            .map_err(|_| anyhow!("Client stopped"))?;
+
+        let mut local = local;
+        local.write_u8(measure).await?;
+        Ok(local)
+    }
+}
+
+/// Measure the round trip times, then the upload and download throughput through tunnels of the client
+pub async fn run_bench(
+    client: WsClient,
+    server: String,
+    duration: Duration,
+    pings: u32,
+) -> anyhow::Result<BenchResult> {
+    let tunnels = BenchTunnels::new(client);
+    // The server closes the tunnels to an exec destination it does not know, without answering
+    let no_answer = || anyhow!("No answer from the bench endpoint, is the server started with --bench-endpoint?");
+
+    let mut stream = tunnels.open(PING).await?;
+    let mut rtts = Vec::with_capacity(pings as usize);
+    for seq in 0..pings as u64 {
+        let start = Instant::now();
+        stream.write_u64(seq).await?;
+        let answer = tokio::time::timeout(Duration::from_secs(10), stream.read_u64())
+            .await
+            .context("Ping timed out")?
+            .map_err(|_| no_answer())?;
+        if answer != seq {
+            return Err(anyhow!("Unexpected ping answer {}, expected {}", answer, seq));
+        }
+        rtts.push(start.elapsed().as_secs_f64() * 1000.0);
+        tokio::time::sleep(PING_INTERVAL).await;
+    }
+    drop(stream);
+
+    let mut stream = tunnels.open(UPLOAD).await?;
+    stream.write_u64(duration.as_millis() as u64).await?;
+    let (mut rx, mut tx) = tokio::io::split(stream);
+    let chunk = vec![0; CHUNK_SIZE];
+    let send_chunks = async {
+        while tx.write_all(&chunk).await.is_ok() {}
+        // The tunnel is closed, the answer is read or its absence reported by the other branch
+        std::future::pending::<()>().await
+    };
+    let upload_bytes = tokio::select! {
+        received = rx.read_u64() => received.map_err(|_| no_answer())?,
+        _ = send_chunks => unreachable!("chunks are sent until the answer"),
+    };
+
+    let mut stream = tunnels.open(DOWNLOAD).await?;
+    stream.write_u64(duration.as_millis() as u64).await?;
+    let start = Instant::now();
+    let mut download_bytes = 0;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let len = tokio::time::timeout(duration + Duration::from_secs(10), stream.read(&mut buf))
+            .await
+            .context("Download timed out")??;
+        if len == 0 {
+            break;
+        }
+        download_bytes += len as u64;
+    }
+    let download_secs = start.elapsed().as_secs_f64();
+    let upload_secs = duration.min(MAX_MEASURE_DURATION).as_secs_f64().max(0.001);
+    if download_bytes == 0 {
+        return Err(no_answer());
+    }
+
+    let jitter = match rtts.len() {
+        0 | 1 => 0.0,
+        len => rtts.windows(2).map(|rtt| (rtt[1] - rtt[0]).abs()).sum::<f64>() / (len - 1) as f64,
+    };
+    Ok(BenchResult {
+        server,
+        duration_secs: duration.as_secs_f64(),
+        upload_bits_per_sec: (upload_bytes as f64 * 8.0 / upload_secs) as u64,
+        download_bits_per_sec: (download_bytes as f64 * 8.0 / download_secs.max(0.001)) as u64,
+        download_bytes,
+        upload_bytes,
+        pings: rtts.len(),
+        rtt_min_ms: rtts.iter().copied().reduce(f64::min).unwrap_or_default(),
+        rtt_avg_ms: rtts.iter().sum::<f64>() / rtts.len().max(1) as f64,
+        rtt_max_ms: rtts.iter().copied().reduce(f64::max).unwrap_or_default(),
+        jitter_ms: jitter,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_upload_measure() {
+        let (mut rx, mut tx) = serve_bench();
+        tx.write_u8(UPLOAD).await.unwrap();
+        tx.write_u64(200).await.unwrap();
+        tx.write_all(&[0; 1000]).await.unwrap();
+        assert_eq!(rx.read_u64().await.unwrap(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_download_measure() {
+        let (mut rx, mut tx) = serve_bench();
+        tx.write_u8(DOWNLOAD).await.unwrap();
+        tx.write_u64(100).await.unwrap();
+        let mut received = Vec::new();
+        rx.read_to_end(&mut received).await.unwrap();
+        assert!(!received.is_empty());
+    }
+}
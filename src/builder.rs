@@ -0,0 +1,384 @@
+//! Client and server embedded in another program: configured with typed options instead of the command line, they
+//! run in the tokio runtime of the caller until their handle or their cancellation token shuts them down.
+//! The options without a method of the builders are set with configure(), they have the name of the arguments
+
+use crate::client_tunnels::{parse_forward, ClientTunnels, TunnelOrigin};
+use crate::config::parsers::{parse_reverse_tunnel_arg, parse_tunnel_arg};
+use crate::config::{Client, Server};
+use crate::instance::InstanceState;
+use crate::protocols::packet::{PacketDevice, PacketNetwork};
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::events::TunnelEvents;
+use crate::tunnel::interceptor::Interceptors;
+use crate::{
+    new_server, run_client_until, run_server_until, ClientStatus, HostResolver, StreamInterceptor, TunnelEvent,
+    TunnelEventHandler, TunnelService, TunnelTransport,
+};
+use anyhow::{anyhow, Context};
+use hyper::http::{HeaderName, HeaderValue};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use url::Url;
+
+/// Options of an embedded client set in code, as they cannot be given on the command line
+#[derive(Clone, Default)]
+pub(crate) struct ClientExtensions {
+    pub transport: Option<Arc<dyn TunnelTransport>>,
+    pub dns_resolver: Option<Arc<dyn HostResolver>>,
+    pub interceptors: Interceptors,
+    pub cancel: CancellationToken,
+    pub events: TunnelEvents,
+    pub instance: InstanceState,
+    /// Set once the client is started, before the tunnels of its arguments. Given by the handle of an embedded
+    /// client, which can change its tunnels
+    pub tunnels: Option<Arc<OnceLock<Arc<ClientTunnels>>>>,
+    /// Devices of the tun tunnels, with the address of the client in each one
+    pub packet_tunnels: Vec<(IpAddr, PacketDevice)>,
+}
+
+/// Options of an embedded server set in code, as they cannot be given on the command line
+#[derive(Clone, Default)]
+pub(crate) struct ServerExtensions {
+    pub restrictions: Option<RestrictionsRules>,
+    pub event_handler: Option<Arc<dyn TunnelEventHandler>>,
+    pub transport: Option<Arc<dyn TunnelTransport>>,
+    pub dns_resolver: Option<Arc<dyn HostResolver>>,
+    pub interceptors: Interceptors,
+    pub packet_network: Option<Arc<dyn PacketNetwork>>,
+    pub cancel: CancellationToken,
+    pub events: TunnelEvents,
+    pub instance: InstanceState,
+}
+
+pub struct TunnelClientBuilder {
+    args: Client,
+    extensions: ClientExtensions,
+}
+
+impl TunnelClientBuilder {
+    /// Client of the server at ws[s]|http[s]://wstunnel.server.com[:port], with the defaults of the command line
+    pub fn new(remote_addr: Url) -> Self {
+        Self::from_args(Client::new(remote_addr))
+    }
+
+    /// Client configured like the command line, i.e: with the arguments of `wstunnel client` parsed by clap
+    pub fn from_args(args: Client) -> Self {
+        Self {
+            args,
+            extensions: ClientExtensions::default(),
+        }
+    }
+
+    /// Tunnel as given to -L, i.e: tcp://1212:google.com:443
+    pub fn local_to_remote(mut self, tunnel: &str) -> anyhow::Result<Self> {
+        let tunnel = parse_tunnel_arg(tunnel).with_context(|| format!("Invalid tunnel {}", tunnel))?;
+        self.args.local_to_remote.push(tunnel);
+        Ok(self)
+    }
+
+    /// Reverse tunnel as given to -R, i.e: tcp://1212:localhost:22
+    pub fn remote_to_local(mut self, tunnel: &str) -> anyhow::Result<Self> {
+        let tunnel = parse_reverse_tunnel_arg(tunnel).with_context(|| format!("Invalid reverse tunnel {}", tunnel))?;
+        self.args.remote_to_local.push(tunnel);
+        Ok(self)
+    }
+
+    pub fn http_upgrade_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.args.http_upgrade_path_prefix = prefix.into();
+        self
+    }
+
+    pub fn http_upgrade_bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.args.http_upgrade_bearer_token = Some(token.into());
+        self
+    }
+
+    pub fn http_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.args.http_headers.push((name, value));
+        self
+    }
+
+    pub fn tls_verify_certificate(mut self, verify: bool) -> Self {
+        self.args.tls_verify_certificate = verify;
+        self
+    }
+
+    /// Certificate and private key of the client, for mTLS
+    pub fn tls_client_certificate(mut self, certificate: PathBuf, private_key: PathBuf) -> Self {
+        self.args.tls_certificate = Some(certificate);
+        self.args.tls_private_key = Some(private_key);
+        self
+    }
+
+    pub fn connection_min_idle(mut self, min_idle: u32) -> Self {
+        self.args.connection_min_idle = min_idle;
+        self
+    }
+
+    /// Zero disables the pings
+    pub fn websocket_ping_frequency(mut self, frequency: Duration) -> Self {
+        self.args.websocket_ping_frequency = Some(frequency);
+        self
+    }
+
+    /// Transport of the tunnels instead of websocket or http2, the server must use the same one
+    pub fn transport(mut self, transport: impl TunnelTransport) -> Self {
+        self.extensions.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Resolver of the hostnames instead of the ones of --dns-resolver
+    pub fn dns_resolver(mut self, resolver: impl HostResolver) -> Self {
+        self.extensions.dns_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Stream interceptor enabled by the tunnels with ?interceptor=NAME
+    pub fn interceptor(mut self, name: impl Into<String>, interceptor: impl StreamInterceptor) -> Self {
+        self.extensions.interceptors.insert(name, Arc::new(interceptor));
+        self
+    }
+
+    /// Tun tunnel exchanging the ip packets of the device with the packet network of the server, i.e: the ones of
+    /// the tun interface of a mobile VPN app. The address is the one of the client in the tunnel
+    pub fn packet_tunnel(mut self, address: IpAddr, device: PacketDevice) -> Self {
+        self.extensions.packet_tunnels.push((address, device));
+        self
+    }
+
+    /// Stop the client when this token is cancelled, i.e: with the other tasks of the caller
+    pub fn cancellation_token(mut self, token: &CancellationToken) -> Self {
+        self.extensions.cancel = token.child_token();
+        self
+    }
+
+    /// Any other option of the client
+    pub fn configure(mut self, configure: impl FnOnce(&mut Client)) -> Self {
+        configure(&mut self.args);
+        self
+    }
+
+    /// Start the client in the current tokio runtime. The errors of its startup, i.e: a port already in use, are
+    /// returned when it is shut down or joined
+    pub fn spawn(self) -> TunnelHandle {
+        let Self { args, mut extensions } = self;
+        let tunnels = extensions.tunnels.insert(Arc::default()).clone();
+        let mut handle = TunnelHandle::spawn(extensions.cancel.clone(), extensions.events.clone(), async move {
+            match run_client_until(args, extensions).await? {
+                Some(exit_code) if exit_code != 0 => Err(anyhow!("The --exec command exited with {}", exit_code)),
+                _ => Ok(()),
+            }
+        });
+        handle.client_tunnels = Some(tunnels);
+        handle
+    }
+}
+
+pub struct TunnelServerBuilder {
+    args: Server,
+    extensions: ServerExtensions,
+}
+
+impl TunnelServerBuilder {
+    /// Server listening on ws[s]://0.0.0.0[:port], with the defaults of the command line
+    pub fn new(remote_addr: Url) -> Self {
+        Self {
+            args: Server::new(remote_addr),
+            extensions: ServerExtensions::default(),
+        }
+    }
+
+    /// Only allow the tunnels to this destination, as given to --restrict-to
+    pub fn restrict_to(mut self, host: &str, port: u16) -> Self {
+        self.args
+            .restrict_to
+            .get_or_insert_with(Vec::new)
+            .push(format!("{}:{}", host, port));
+        self
+    }
+
+    /// Only allow the clients using this path prefix, as given to --restrict-http-upgrade-path-prefix
+    pub fn restrict_http_upgrade_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.args
+            .restrict_http_upgrade_path_prefix
+            .get_or_insert_with(Vec::new)
+            .push(prefix.into());
+        self
+    }
+
+    /// Restrictions file, replacing restrict_to() and restrict_http_upgrade_path_prefix()
+    pub fn restrict_config(mut self, path: PathBuf) -> Self {
+        self.args.restrict_config = Some(path);
+        self
+    }
+
+    /// Restrictions built in code, i.e: with RestrictionsBuilder, replacing all the other restrict options
+    pub fn restrictions(mut self, restrictions: RestrictionsRules) -> Self {
+        self.extensions.restrictions = Some(restrictions);
+        self
+    }
+
+    /// Instead of the embedded self-signed certificate
+    pub fn tls_certificate(mut self, certificate: PathBuf, private_key: PathBuf) -> Self {
+        self.args.tls_certificate = Some(certificate);
+        self.args.tls_private_key = Some(private_key);
+        self
+    }
+
+    /// Only accept the clients with a certificate signed by these CAs (mTLS)
+    pub fn tls_client_ca_certs(mut self, path: PathBuf) -> Self {
+        self.args.tls_client_ca_certs = Some(path);
+        self
+    }
+
+    /// Hooks into the connections of the server, i.e: to reject the tunnels of a subject without quota left
+    pub fn event_handler(mut self, handler: impl TunnelEventHandler) -> Self {
+        self.extensions.event_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Transport of the tunnels instead of websocket or http2, the clients must use the same one
+    pub fn transport(mut self, transport: impl TunnelTransport) -> Self {
+        self.extensions.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Resolver of the hostnames instead of the ones of --dns-resolver
+    pub fn dns_resolver(mut self, resolver: impl HostResolver) -> Self {
+        self.extensions.dns_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Stream interceptor enabled by the restrictions with interceptors: [NAME]
+    pub fn interceptor(mut self, name: impl Into<String>, interceptor: impl StreamInterceptor) -> Self {
+        self.extensions.interceptors.insert(name, Arc::new(interceptor));
+        self
+    }
+
+    /// Network of the packets of the tun tunnels, they are rejected without it
+    pub fn packet_network(mut self, network: impl PacketNetwork) -> Self {
+        self.extensions.packet_network = Some(Arc::new(network));
+        self
+    }
+
+    /// Stop the server when this token is cancelled, i.e: with the other tasks of the caller
+    pub fn cancellation_token(mut self, token: &CancellationToken) -> Self {
+        self.extensions.cancel = token.child_token();
+        self
+    }
+
+    /// Any other option of the server
+    pub fn configure(mut self, configure: impl FnOnce(&mut Server)) -> Self {
+        configure(&mut self.args);
+        self
+    }
+
+    /// Start the server in the current tokio runtime. The errors of its startup, i.e: its port already in use, are
+    /// returned when it is shut down or joined
+    pub fn spawn(self) -> TunnelHandle {
+        let Self { args, extensions } = self;
+        let (cancel, events) = (extensions.cancel.clone(), extensions.events.clone());
+        TunnelHandle::spawn(cancel, events, run_server_until(args, extensions))
+    }
+
+    /// Server without its listener, to mount on the http server of the caller. Only the options about the tunnels
+    /// apply, the ones about the listener, i.e: tls, are up to the caller
+    pub async fn into_service(self) -> anyhow::Result<TunnelService> {
+        let Self { args, extensions } = self;
+        let (server, restrictions) = new_server(args, extensions).await?;
+        TunnelService::new(server, restrictions)
+    }
+}
+
+/// Running client or server. Dropping the handle shuts it down too, without waiting for it
+pub struct TunnelHandle {
+    cancel: CancellationToken,
+    _cancel_on_drop: DropGuard,
+    events: TunnelEvents,
+    /// Of a client only
+    client_tunnels: Option<Arc<OnceLock<Arc<ClientTunnels>>>>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TunnelHandle {
+    fn spawn(
+        cancel: CancellationToken,
+        events: TunnelEvents,
+        run: impl Future<Output = anyhow::Result<()>> + Send + 'static,
+    ) -> Self {
+        Self {
+            _cancel_on_drop: cancel.clone().drop_guard(),
+            cancel,
+            events,
+            client_tunnels: None,
+            task: tokio::spawn(run),
+        }
+    }
+
+    fn client_tunnels(&self) -> anyhow::Result<&Arc<ClientTunnels>> {
+        let Some(tunnels) = &self.client_tunnels else {
+            return Err(anyhow!("Only the tunnels of a client can be changed"));
+        };
+        tunnels
+            .get()
+            .ok_or_else(|| anyhow!("The client has not started its tunnels yet"))
+    }
+
+    /// Start a tunnel on the running client, as given to the add admin command: i.e: -L tcp://1212:google.com:443.
+    /// Return its id, and its local address for a -L tunnel
+    pub async fn add_tunnel(&self, tunnel: &str) -> anyhow::Result<(String, SocketAddr)> {
+        let tunnels = self.client_tunnels()?;
+        let (id, tunnel) = parse_forward(tunnel)?;
+        let local_addr = tunnels.start(id.clone(), tunnel, TunnelOrigin::Admin).await?;
+        Ok((id, local_addr))
+    }
+
+    /// Stop listening for the tunnel with this id, its open tunnels are left to drain. False if there is none
+    pub async fn remove_tunnel(&self, id: &str) -> anyhow::Result<bool> {
+        Ok(self.client_tunnels()?.stop(id).await)
+    }
+
+    /// State of the connection to the server and of each tunnel of the client, as answered to the status admin command
+    pub async fn status(&self) -> anyhow::Result<ClientStatus> {
+        Ok(self.client_tunnels()?.status().await)
+    }
+
+    /// Events of the client or server from now on, i.e: its tunnels opened and closed
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.events.subscribe()
+    }
+
+    /// Cancelled once the client or server stops, cancelling it stops the client or server like shutdown()
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// The client or server stopped by itself, i.e: after an error
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Wait for the client or server to stop by itself
+    pub async fn join(self) -> anyhow::Result<()> {
+        let Self {
+            cancel: _,
+            _cancel_on_drop,
+            events: _,
+            client_tunnels: _,
+            task,
+        } = self;
+        task.await?
+    }
+
+    /// Stop the listeners and the running connections, and wait for the listeners to be closed
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.cancel.cancel();
+        self.task.await?
+    }
+}
@@ -0,0 +1,265 @@
+//! Certificates for the tls of the server and the mTLS of its clients, signed by a private CA: wstunnel cert gen
+
+use crate::config::CertGen;
+use anyhow::{anyhow, Context};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose,
+};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use time::{Duration, OffsetDateTime};
+
+const CA_NAME: &str = "ca";
+const CA_COMMON_NAME: &str = "wstunnel CA";
+const CA_VALIDITY_DAYS: i64 = 10 * 365;
+const SERVER_NAME: &str = "server";
+
+pub fn generate_certificates(args: CertGen) -> anyhow::Result<()> {
+    if args.san.is_empty() && args.client.is_empty() {
+        return Err(anyhow!(
+            "Nothing to generate, give the names of the server with --san and/or the clients with --client"
+        ));
+    }
+
+    // Checked before writing anything, so a mistake does not leave half of the certificates behind
+    let mut names = Vec::with_capacity(args.client.len() + 1);
+    if !args.san.is_empty() {
+        names.push(SERVER_NAME);
+    }
+    for name in &args.client {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(anyhow!("Invalid client name {:?}, it is used as file name", name));
+        }
+        if name == CA_NAME || name == SERVER_NAME || names.contains(&name.as_str()) {
+            return Err(anyhow!("Client name {:?} is already used by another certificate", name));
+        }
+        names.push(name);
+    }
+    if !args.force {
+        for name in &names {
+            for path in [
+                args.out_dir.join(format!("{name}.pem")),
+                args.out_dir.join(format!("{name}.key")),
+            ] {
+                if path.exists() {
+                    return Err(anyhow!("{} already exists, use --force to overwrite it", path.display()));
+                }
+            }
+        }
+    }
+
+    std::fs::create_dir_all(&args.out_dir)
+        .with_context(|| format!("Cannot create directory {}", args.out_dir.display()))?;
+    let (ca, ca_key) = load_or_create_ca(&args.out_dir)?;
+    let validity = Duration::days(args.days.into());
+
+    if !args.san.is_empty() {
+        let mut params = CertificateParams::new(args.san.clone()).context("Invalid --san")?;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, &args.san[0]);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        write_signed_certificate(&args.out_dir, SERVER_NAME, params, validity, &ca, &ca_key)?;
+    }
+
+    for name in &args.client {
+        let mut params = CertificateParams::default();
+        // The server uses it as the HTTP upgrade path prefix of the client
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        write_signed_certificate(&args.out_dir, name, params, validity, &ca, &ca_key)?;
+    }
+
+    Ok(())
+}
+
+fn ca_params() -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, CA_COMMON_NAME);
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params
+}
+
+/// The CA of a previous run is reused from its key, otherwise a new one is created
+fn load_or_create_ca(dir: &Path) -> anyhow::Result<(Certificate, KeyPair)> {
+    let key_path = dir.join(format!("{CA_NAME}.key"));
+    match std::fs::read_to_string(&key_path) {
+        Ok(pem) => {
+            let key = KeyPair::from_pem(&pem).with_context(|| format!("Invalid CA key {}", key_path.display()))?;
+            // Same subject and key as when it was created, so what it signs is verified by the existing ca.pem
+            let ca = ca_params().self_signed(&key)?;
+            println!("Reusing the CA of {}", key_path.display());
+            Ok((ca, key))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let key = KeyPair::generate()?;
+            let mut params = ca_params();
+            set_validity(&mut params, Duration::days(CA_VALIDITY_DAYS));
+            let ca = params.self_signed(&key)?;
+            write_file(&key_path, &key.serialize_pem(), true)?;
+            write_file(&dir.join(format!("{CA_NAME}.pem")), &ca.pem(), false)?;
+            Ok((ca, key))
+        }
+        Err(err) => Err(anyhow!(err).context(format!("Cannot read CA key {}", key_path.display()))),
+    }
+}
+
+fn write_signed_certificate(
+    dir: &Path,
+    name: &str,
+    mut params: CertificateParams,
+    validity: Duration,
+    ca: &Certificate,
+    ca_key: &KeyPair,
+) -> anyhow::Result<()> {
+    set_validity(&mut params, validity);
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyEncipherment];
+    params.use_authority_key_identifier_extension = true;
+
+    let key = KeyPair::generate()?;
+    let cert = params.signed_by(&key, ca, ca_key)?;
+    write_file(&dir.join(format!("{name}.key")), &key.serialize_pem(), true)?;
+    write_file(&dir.join(format!("{name}.pem")), &cert.pem(), false)
+}
+
+fn set_validity(params: &mut CertificateParams, validity: Duration) {
+    let now = OffsetDateTime::now_utc();
+    // Tolerates a clock of the peer slightly behind
+    params.not_before = now - Duration::hours(1);
+    params.not_after = now + validity;
+}
+
+/// Private keys are only readable by their owner
+fn write_file(path: &Path, content: &str, is_private: bool) -> anyhow::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if is_private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Cannot write {}", path.display()))?;
+    // The mode is only applied to new files, not to the ones overwritten with --force
+    #[cfg(unix)]
+    if is_private {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(content.as_bytes())
+        .with_context(|| format!("Cannot write {}", path.display()))?;
+
+    println!("Written {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::tls;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::client::danger::ServerCertVerifier;
+    use tokio_rustls::rustls::client::WebPkiServerVerifier;
+    use tokio_rustls::rustls::crypto::CryptoProvider;
+    use tokio_rustls::rustls::pki_types::{ServerName, UnixTime};
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::RootCertStore;
+
+    fn cert_gen(out_dir: &Path, san: &[&str], client: &[&str]) -> anyhow::Result<()> {
+        generate_certificates(CertGen {
+            out_dir: out_dir.to_path_buf(),
+            san: san.iter().map(|s| s.to_string()).collect(),
+            client: client.iter().map(|s| s.to_string()).collect(),
+            days: 30,
+            force: false,
+        })
+    }
+
+    // The tests are built with both providers, so none is picked by default
+    fn provider() -> Arc<CryptoProvider> {
+        #[cfg(all(
+            any(target_os = "linux", target_os = "macos"),
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        return Arc::new(tokio_rustls::rustls::crypto::aws_lc_rs::default_provider());
+        #[cfg(not(all(
+            any(target_os = "linux", target_os = "macos"),
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        )))]
+        return Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    }
+
+    fn roots(dir: &Path) -> Arc<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        for cert in tls::load_certificates_from_pem(&dir.join("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        Arc::new(roots)
+    }
+
+    fn verify_client(dir: &Path, name: &str) {
+        let certs = tls::load_certificates_from_pem(&dir.join(format!("{name}.pem"))).unwrap();
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots(dir), provider())
+            .build()
+            .unwrap();
+        verifier.verify_client_cert(&certs[0], &[], UnixTime::now()).unwrap();
+        let cert = tls::find_leaf_certificate(&certs).unwrap();
+        assert_eq!(tls::cn_from_certificate(&cert).as_deref(), Some(name));
+    }
+
+    #[test]
+    fn test_cert_gen() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-cert-gen-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        cert_gen(&dir, &["localhost", "127.0.0.1"], &["alice"]).unwrap();
+        let verifier = WebPkiServerVerifier::builder_with_provider(roots(&dir), provider())
+            .build()
+            .unwrap();
+        let certs = tls::load_certificates_from_pem(&dir.join("server.pem")).unwrap();
+        for name in ["localhost", "127.0.0.1"] {
+            let name = ServerName::try_from(name).unwrap();
+            verifier
+                .verify_server_cert(&certs[0], &[], &name, &[], UnixTime::now())
+                .unwrap();
+        }
+        let name = ServerName::try_from("example.com").unwrap();
+        assert!(verifier
+            .verify_server_cert(&certs[0], &[], &name, &[], UnixTime::now())
+            .is_err());
+        tls::load_private_key_from_file(&dir.join("server.key")).unwrap();
+        verify_client(&dir, "alice");
+
+        // The CA is reused for the clients added later, and existing certificates are not overwritten
+        let ca = std::fs::read_to_string(dir.join("ca.pem")).unwrap();
+        cert_gen(&dir, &[], &["bob"]).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("ca.pem")).unwrap(), ca);
+        verify_client(&dir, "bob");
+        assert!(cert_gen(&dir, &[], &["alice"]).is_err());
+
+        #[cfg(unix)]
+        for name in ["ca.key", "server.key", "alice.key", "bob.key"] {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join(name)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{name}");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cert_gen_invalid_args() {
+        let dir = PathBuf::from("/nonexistent");
+        assert!(cert_gen(&dir, &[], &[]).is_err());
+        assert!(cert_gen(&dir, &[], &["../alice"]).is_err());
+        assert!(cert_gen(&dir, &[], &["ca"]).is_err());
+        assert!(cert_gen(&dir, &[], &["alice", "alice"]).is_err());
+    }
+}
@@ -0,0 +1,685 @@
+use crate::config::parsers::{parse_reverse_tunnel_arg, parse_tunnel_arg};
+use crate::config::LocalToRemote;
+use crate::health::{HealthStatus, HEALTH};
+use crate::protocols::http_proxy::{Htpasswd, HttpProxyAuth, ProxyAcl};
+#[cfg(target_os = "linux")]
+use crate::protocols::packet::{self, TunInterface};
+use crate::protocols::socks5::Socks5WriteHalf;
+use crate::tunnel::active_tunnels::ActiveTunnels;
+use crate::tunnel::client::{AccessLog, ClientConfigFile, ClientConfigWatcher, WsClient};
+use crate::tunnel::connectors::{Socks5TunnelConnector, TcpTunnelConnector, UdpTunnelConnector};
+use crate::tunnel::listeners::{HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener};
+use crate::tunnel::tunnel_metrics::TunnelMetrics;
+use crate::tunnel::{spawn_until_cancelled, to_host_port, LocalProtocol, RemoteAddr};
+use crate::{tunnel_metrics_name, with_exec_destination};
+use anyhow::anyhow;
+use futures_util::future;
+use futures_util::future::join_all;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+use url::Host;
+
+/// The -L/-R tunnels of the client, started from its arguments, its --config file or the admin commands.
+/// They are identified by their argument (i.e: -L tcp://1212:google.com:443), or by their name for the ones
+/// of the command line
+pub(crate) struct ClientTunnels {
+    client: WsClient,
+    access_log_file: Option<Arc<Mutex<File>>>,
+    tunnels: tokio::sync::Mutex<BTreeMap<String, RunningTunnel>>,
+    /// Set when the client has a --config file
+    config_reload: OnceLock<Arc<Notify>>,
+    config_watcher: Mutex<Option<JoinHandle<Option<()>>>>,
+}
+
+struct RunningTunnel {
+    origin: TunnelOrigin,
+    /// Stops the listener of the tunnel, its connections stop with the client
+    cancel: CancellationToken,
+    task: JoinHandle<Option<()>>,
+    listen: String,
+    reverse: bool,
+    metrics: Option<Arc<TunnelMetrics>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelOrigin {
+    Args,
+    Config,
+    /// Admin commands, or the handle of an embedded client
+    Admin,
+}
+
+/// As listed by the 'forwards' admin command
+#[derive(Debug, Serialize)]
+pub(crate) struct ClientTunnelInfo {
+    pub id: String,
+    pub origin: TunnelOrigin,
+    /// The listener stopped by itself, i.e: after an error
+    pub stopped: bool,
+}
+
+/// Answer of the 'status' admin command, for the scripts and GUIs monitoring the client.
+/// Fields are only added to it, a breaking change increases its schema
+#[derive(Debug, Serialize)]
+pub struct ClientStatus {
+    pub schema: u32,
+    pub version: &'static str,
+    pub transport: TransportStatus,
+    pub tunnels: Vec<TunnelStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransportStatus {
+    pub state: TransportState,
+    pub last_connection_secs_ago: Option<u64>,
+    /// Still reported once the server can be reached again
+    pub last_error: Option<LastError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportState {
+    /// No tunnel has been established with the server yet
+    Idle,
+    Up,
+    /// The last attempt to reach the server failed
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TunnelStatus {
+    pub id: String,
+    pub origin: TunnelOrigin,
+    /// The listener is on the server, i.e: a -R tunnel
+    pub reverse: bool,
+    /// Address or path of the listener, with the port chosen by the system for a -L tunnel on port 0
+    pub listen: String,
+    pub running: bool,
+    pub connections: u64,
+    pub errors: u64,
+    pub reconnects: u64,
+    pub last_error: Option<LastError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LastError {
+    pub message: String,
+    pub secs_ago: u64,
+}
+
+impl LastError {
+    fn new((at, message): (SystemTime, String)) -> Self {
+        Self {
+            message,
+            secs_ago: at.elapsed().unwrap_or_default().as_secs(),
+        }
+    }
+}
+
+impl From<&HealthStatus> for TransportState {
+    fn from(health: &HealthStatus) -> Self {
+        match (&health.last_transport_error, health.last_transport_connection_secs_ago) {
+            (Some(_), _) => Self::Down,
+            (None, Some(_)) => Self::Up,
+            (None, None) => Self::Idle,
+        }
+    }
+}
+
+impl ClientTunnels {
+    pub fn new(client: WsClient, access_log_file: Option<Arc<Mutex<File>>>) -> Arc<Self> {
+        Arc::new(Self {
+            client,
+            access_log_file,
+            tunnels: Default::default(),
+            config_reload: OnceLock::new(),
+            config_watcher: Mutex::new(None),
+        })
+    }
+
+    /// The tunnels open through the listeners of the client
+    pub(crate) fn active_tunnels(&self) -> &ActiveTunnels {
+        &self.client.config.instance.active_tunnels
+    }
+
+    /// Return the local address of the tunnel, the one chosen by the system for a tcp tunnel on port 0
+    pub async fn start(&self, id: String, tunnel: LocalToRemote, origin: TunnelOrigin) -> anyhow::Result<SocketAddr> {
+        let mut tunnels = self.tunnels.lock().await;
+        if tunnels.contains_key(&id) {
+            return Err(anyhow!("Tunnel {} is already started", id));
+        }
+        let reverse = tunnel.local_protocol.is_reverse_tunnel();
+        let listen = match &tunnel.local_protocol {
+            LocalProtocol::Unix { path, .. } | LocalProtocol::ReverseUnix { path, .. } => path.display().to_string(),
+            LocalProtocol::ReverseHttpIngress { hostname } => hostname.clone(),
+            _ => String::new(),
+        };
+        let cancel = self.client.config.cancel.child_token();
+        let (task, local_addr, metrics) =
+            spawn_tunnel(&self.client, tunnel, self.access_log_file.clone(), &cancel).await?;
+        info!("Started tunnel {}", id);
+        let listen = match listen.is_empty() {
+            true => local_addr.to_string(),
+            false => listen,
+        };
+        tunnels.insert(
+            id,
+            RunningTunnel {
+                origin,
+                cancel,
+                task,
+                listen,
+                reverse,
+                metrics,
+            },
+        );
+        Ok(local_addr)
+    }
+
+    /// Only the listener is stopped, its connections run in their own tasks until they close. False if there is
+    /// no tunnel with this id
+    pub async fn stop(&self, id: &str) -> bool {
+        let Some(tunnel) = self.tunnels.lock().await.remove(id) else {
+            return false;
+        };
+        // Wait for the listener to be dropped, a modified tunnel may listen again on the same port
+        tunnel.cancel.cancel();
+        let _ = tunnel.task.await;
+        info!("Stopped tunnel {}, its connections are left to drain", id);
+        true
+    }
+
+    pub async fn list(&self) -> Vec<ClientTunnelInfo> {
+        self.tunnels
+            .lock()
+            .await
+            .iter()
+            .map(|(id, tunnel)| ClientTunnelInfo {
+                id: id.clone(),
+                origin: tunnel.origin,
+                stopped: tunnel.task.is_finished(),
+            })
+            .collect()
+    }
+
+    pub async fn status(&self) -> ClientStatus {
+        let health = HEALTH.status();
+        let tunnels = self
+            .tunnels
+            .lock()
+            .await
+            .iter()
+            .map(|(id, tunnel)| {
+                let metrics = tunnel.metrics.as_deref();
+                TunnelStatus {
+                    id: id.clone(),
+                    origin: tunnel.origin,
+                    reverse: tunnel.reverse,
+                    listen: tunnel.listen.clone(),
+                    running: !tunnel.task.is_finished(),
+                    connections: metrics.map_or(0, TunnelMetrics::connections),
+                    errors: metrics.map_or(0, TunnelMetrics::errors),
+                    reconnects: metrics.map_or(0, TunnelMetrics::reconnects),
+                    last_error: metrics.and_then(TunnelMetrics::last_error).map(LastError::new),
+                }
+            })
+            .collect();
+
+        ClientStatus {
+            schema: 1,
+            version: env!("CARGO_PKG_VERSION"),
+            transport: TransportStatus {
+                state: TransportState::from(&health),
+                last_connection_secs_ago: health.last_transport_connection_secs_ago,
+                last_error: HEALTH.last_transport_error().map(LastError::new),
+            },
+            tunnels,
+        }
+    }
+
+    /// Wait for all the tunnels to stop by themselves, when nothing can start new ones
+    pub async fn join_all(&self) {
+        // Kept until they are done, so they are still stopped by stop_all() if the client stops before
+        let mut tunnels = self.tunnels.lock().await;
+        join_all(tunnels.values_mut().map(|tunnel| &mut tunnel.task)).await;
+        tunnels.clear();
+    }
+
+    /// Stop the listeners of all the tunnels and wait for them to be closed, when the client stops
+    pub async fn stop_all(&self) {
+        if let Some(watcher) = self.config_watcher.lock().take() {
+            watcher.abort();
+        }
+        let tunnels = std::mem::take(&mut *self.tunnels.lock().await);
+        for tunnel in tunnels.values() {
+            tunnel.cancel.cancel();
+        }
+        join_all(tunnels.into_values().map(|tunnel| tunnel.task)).await;
+    }
+
+    /// Reload the --config file, false if there is none
+    pub fn reload_config(&self) -> bool {
+        match self.config_reload.get() {
+            Some(reload) => {
+                reload.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Start the tunnels of the --config file, and apply the changes of the file until the client stops
+    pub async fn run_config_file(self: &Arc<Self>, path: PathBuf) -> anyhow::Result<()> {
+        let config = ClientConfigFile::from_file(&path)?;
+        let watcher = ClientConfigWatcher::new(&path)?;
+        let _ = self.config_reload.set(watcher.reload_notify());
+        self.apply_config_file(config).await?;
+
+        let this = self.clone();
+        let config_watcher = spawn_until_cancelled(&self.client.config.cancel, async move {
+            loop {
+                watcher.changed().await;
+                // Editors write the file in several steps, wait for them to be done
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let config = match ClientConfigFile::from_file(&path) {
+                    Ok(config) => config,
+                    Err(err) => {
+                        error!("Cannot reload client config file, keeping the current one. Error: {:?}", err);
+                        continue;
+                    }
+                };
+                match this.apply_config_file(config).await {
+                    Ok(()) => info!("Client config file has been reloaded"),
+                    Err(err) => error!("Client config file has been partially reloaded: {:?}", err),
+                }
+            }
+        });
+        *self.config_watcher.lock() = Some(config_watcher);
+
+        Ok(())
+    }
+
+    /// Stop the tunnels not in the config anymore and start the new ones, the others are not touched.
+    /// The tunnels which cannot be started are tried again on the next reload
+    async fn apply_config_file(&self, config: ClientConfigFile) -> anyhow::Result<()> {
+        self.client.config.reloadable.store(Arc::new(config.reloadable));
+        // The files of the env:/file: secrets given as arguments may have been rotated too
+        if let Err(err) = self.client.config.upgrade_secrets.reload() {
+            error!(
+                "Cannot resolve the secrets of the upgrade request, keeping the current ones: {:?}",
+                err
+            );
+        }
+
+        let removed: Vec<String> = self
+            .tunnels
+            .lock()
+            .await
+            .iter()
+            .filter(|(id, tunnel)| tunnel.origin == TunnelOrigin::Config && !config.tunnels.contains_key(*id))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in removed {
+            self.stop(&id).await;
+        }
+
+        let mut last_error = None;
+        for (id, tunnel) in config.tunnels {
+            if self.tunnels.lock().await.contains_key(&id) {
+                continue;
+            }
+            if let Err(err) = self.start(id.clone(), tunnel, TunnelOrigin::Config).await {
+                let err = err.context(format!("Cannot start tunnel {}", id));
+                error!("{:?}", err);
+                last_error = Some(err);
+            }
+        }
+
+        last_error.map_or(Ok(()), Err)
+    }
+}
+
+/// Start listening for the tunnel, in the background until the token is cancelled
+async fn spawn_tunnel(
+    client: &WsClient,
+    tunnel: LocalToRemote,
+    access_log_file: Option<Arc<Mutex<File>>>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<(JoinHandle<Option<()>>, SocketAddr, Option<Arc<TunnelMetrics>>)> {
+    let client = client
+        .clone()
+        .with_tunnel_metrics(tunnel_metrics_name(&tunnel))
+        .with_overrides(&tunnel.overrides)?;
+    let metrics = client.tunnel_metrics().cloned();
+    let mut local_addr = tunnel.local;
+    let task = match &tunnel.local_protocol {
+        LocalProtocol::ReverseTcp => spawn_until_cancelled(cancel, async move {
+            let cfg = client.config.clone();
+            let tcp_connector = TcpTunnelConnector::new(
+                &tunnel.remote.0,
+                tunnel.remote.1,
+                cfg.socket_so_mark,
+                client.timeout_connect(),
+                &cfg.dns_resolver,
+            );
+            let (host, port) = to_host_port(tunnel.local);
+            let remote = RemoteAddr {
+                protocol: LocalProtocol::ReverseTcp,
+                host,
+                port,
+            };
+            if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
+                error!("{:?}", err);
+            }
+        }),
+        LocalProtocol::ReverseUdp { timeout } => {
+            let timeout = *timeout;
+
+            spawn_until_cancelled(cancel, async move {
+                let cfg = client.config.clone();
+                let (host, port) = to_host_port(tunnel.local);
+                let remote = RemoteAddr {
+                    protocol: LocalProtocol::ReverseUdp { timeout },
+                    host,
+                    port,
+                };
+                let udp_connector = UdpTunnelConnector::new(
+                    &tunnel.remote.0,
+                    tunnel.remote.1,
+                    cfg.socket_so_mark,
+                    client.timeout_connect(),
+                    &cfg.dns_resolver,
+                );
+
+                if let Err(err) = client.run_reverse_tunnel(remote.clone(), udp_connector).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        LocalProtocol::ReverseSocks5 { timeout, credentials } => {
+            let credentials = credentials.clone();
+            let timeout = *timeout;
+            spawn_until_cancelled(cancel, async move {
+                let cfg = client.config.clone();
+                let (host, port) = to_host_port(tunnel.local);
+                let remote = RemoteAddr {
+                    protocol: LocalProtocol::ReverseSocks5 { timeout, credentials },
+                    host,
+                    port,
+                };
+                let socks_connector =
+                    Socks5TunnelConnector::new(cfg.socket_so_mark, client.timeout_connect(), &cfg.dns_resolver);
+
+                if let Err(err) = client.run_reverse_tunnel(remote, socks_connector).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        LocalProtocol::ReverseHttpProxy { timeout, credentials } => {
+            let credentials = credentials.clone();
+            let timeout = *timeout;
+            spawn_until_cancelled(cancel, async move {
+                let cfg = client.config.clone();
+                let (host, port) = to_host_port(tunnel.local);
+                let remote = RemoteAddr {
+                    protocol: LocalProtocol::ReverseHttpProxy { timeout, credentials },
+                    host,
+                    port,
+                };
+                let tcp_connector = TcpTunnelConnector::new(
+                    &tunnel.remote.0,
+                    tunnel.remote.1,
+                    cfg.socket_so_mark,
+                    client.timeout_connect(),
+                    &cfg.dns_resolver,
+                );
+
+                if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        LocalProtocol::ReverseUnix { path, mode } => {
+            let path = path.clone();
+            let mode = *mode;
+            spawn_until_cancelled(cancel, async move {
+                let cfg = client.config.clone();
+                let tcp_connector = TcpTunnelConnector::new(
+                    &tunnel.remote.0,
+                    tunnel.remote.1,
+                    cfg.socket_so_mark,
+                    client.timeout_connect(),
+                    &cfg.dns_resolver,
+                );
+
+                let (host, port) = to_host_port(tunnel.local);
+                let remote = RemoteAddr {
+                    protocol: LocalProtocol::ReverseUnix { path, mode },
+                    host,
+                    port,
+                };
+                if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        LocalProtocol::ReverseHttpIngress { hostname } => {
+            let hostname = hostname.clone();
+            spawn_until_cancelled(cancel, async move {
+                let cfg = client.config.clone();
+                let tcp_connector = TcpTunnelConnector::new(
+                    &tunnel.remote.0,
+                    tunnel.remote.1,
+                    cfg.socket_so_mark,
+                    client.timeout_connect(),
+                    &cfg.dns_resolver,
+                );
+
+                let remote = RemoteAddr {
+                    protocol: LocalProtocol::ReverseHttpIngress {
+                        hostname: hostname.clone(),
+                    },
+                    host: Host::Domain(hostname),
+                    port: 0,
+                };
+                if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        LocalProtocol::Tcp { proxy_protocol } => {
+            let server = TcpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *proxy_protocol).await?;
+            local_addr = server.local_addr()?;
+            let server = with_exec_destination(server, tunnel.exec);
+            spawn_until_cancelled(cancel, async move {
+                if let Err(err) = client.run_tunnel(server).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        #[cfg(target_os = "linux")]
+        LocalProtocol::TProxyTcp => {
+            use crate::tunnel::listeners::TproxyTcpTunnelListener;
+            let server = TproxyTcpTunnelListener::new(tunnel.local, false).await?;
+
+            spawn_until_cancelled(cancel, async move {
+                if let Err(err) = client.run_tunnel(server).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        #[cfg(unix)]
+        LocalProtocol::Unix {
+            path,
+            proxy_protocol,
+            mode,
+        } => {
+            use crate::tunnel::listeners::UnixTunnelListener;
+            let server = UnixTunnelListener::new(path, tunnel.remote.clone(), *proxy_protocol, *mode).await?;
+            let server = with_exec_destination(server, tunnel.exec);
+            spawn_until_cancelled(cancel, async move {
+                if let Err(err) = client.run_tunnel(server).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        #[cfg(not(unix))]
+        LocalProtocol::Unix { .. } => {
+            panic!("Unix socket is not available for non Unix platform")
+        }
+
+        #[cfg(target_os = "linux")]
+        LocalProtocol::TProxyUdp { timeout } => {
+            use crate::tunnel::listeners::new_tproxy_udp;
+            let server = new_tproxy_udp(tunnel.local, *timeout).await?;
+            spawn_until_cancelled(cancel, async move {
+                if let Err(err) = client.run_tunnel(server).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        #[cfg(not(target_os = "linux"))]
+        LocalProtocol::TProxyTcp | LocalProtocol::TProxyUdp { .. } => {
+            panic!("Transparent proxy is not available for non Linux platform")
+        }
+        LocalProtocol::Udp { timeout } => {
+            let server = UdpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *timeout).await?;
+
+            spawn_until_cancelled(cancel, async move {
+                if let Err(err) = client.run_tunnel(server).await {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        LocalProtocol::Socks5 {
+            timeout,
+            credentials,
+            gssapi,
+        } => {
+            let server = Socks5TunnelListener::new(tunnel.local, *timeout, credentials.clone(), gssapi.clone()).await?;
+            let access_log = AccessLog::new("socks5", access_log_file.clone());
+            spawn_until_cancelled(cancel, async move {
+                let on_established = |local_tx: Socks5WriteHalf, established| local_tx.send_reply(established);
+                if let Err(err) = client
+                    .run_tunnel_with_access_log(server, on_established, access_log)
+                    .await
+                {
+                    error!("{:?}", err);
+                }
+            })
+        }
+        LocalProtocol::HttpProxy {
+            timeout,
+            credentials,
+            proxy_protocol,
+            htpasswd,
+            acl,
+        } => {
+            let auth = match htpasswd {
+                Some(htpasswd) => HttpProxyAuth::Users {
+                    htpasswd: Htpasswd::load(htpasswd)?,
+                    acl: acl.as_deref().map(ProxyAcl::load).transpose()?,
+                },
+                None => HttpProxyAuth::from(credentials.clone()),
+            };
+            let server = HttpProxyTunnelListener::new(tunnel.local, *timeout, auth, *proxy_protocol).await?;
+            let access_log = AccessLog::new("http", access_log_file.clone());
+            spawn_until_cancelled(cancel, async move {
+                let on_established = |local_tx, _| future::ready(Ok(local_tx));
+                if let Err(err) = client
+                    .run_tunnel_with_access_log(server, on_established, access_log)
+                    .await
+                {
+                    error!("{:?}", err);
+                }
+            })
+        }
+
+        #[cfg(target_os = "linux")]
+        LocalProtocol::TunInterface { address, mtu, routes } => {
+            let interface = TunInterface::create(*address, *mtu, routes).await?;
+            info!("Tun interface {} is up with the address {}", interface.name(), address);
+            let (queue, device) = packet::channel(1024);
+            let remote = RemoteAddr {
+                protocol: LocalProtocol::Tun,
+                host: tunnel.remote.0.clone(),
+                port: 0,
+            };
+            spawn_until_cancelled(cancel, async move {
+                tokio::select! {
+                    ret = client.run_packet_tunnel(remote, device) => if let Err(err) = ret {
+                        error!("{:?}", err);
+                    },
+                    ret = interface.run(queue) => if let Err(err) = ret {
+                        error!("{:?}", err);
+                    },
+                }
+            })
+        }
+        #[cfg(not(target_os = "linux"))]
+        LocalProtocol::TunInterface { .. } => return Err(anyhow!("tun interfaces are only available on linux")),
+
+        LocalProtocol::Stdio { .. } => return Err(anyhow!("stdio tunnels can only be given with -L")),
+        LocalProtocol::Exec | LocalProtocol::Tun => return Err(anyhow!("Invalid protocol for a client tunnel")),
+    };
+
+    Ok((task, local_addr, metrics))
+}
+
+/// The tunnel of 'add', with its id. The same id as the tunnels of the --config file, so it is the same tunnel
+pub(crate) fn parse_forward(forward: &str) -> anyhow::Result<(String, LocalToRemote)> {
+    let (flag, arg) = forward.split_once(' ').unwrap_or((forward, ""));
+    let arg = arg.trim();
+    let tunnel = match flag {
+        "-L" => parse_tunnel_arg(arg)?,
+        "-R" => parse_reverse_tunnel_arg(arg)?,
+        _ => return Err(anyhow!("Invalid tunnel {}, expected -L ARG or -R ARG", forward)),
+    };
+
+    Ok((format!("{} {}", flag, arg), tunnel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn test_parse_forward() {
+        let (id, tunnel) = parse_forward("-L  tcp://1212:google.com:443").unwrap();
+        assert_eq!(id, "-L tcp://1212:google.com:443");
+        assert!(matches!(tunnel.local_protocol, LocalProtocol::Tcp { .. }));
+
+        let (id, tunnel) = parse_forward("-R tcp://8080:localhost:80").unwrap();
+        assert_eq!(id, "-R tcp://8080:localhost:80");
+        assert_eq!(tunnel.local_protocol, LocalProtocol::ReverseTcp);
+
+        assert!(parse_forward("-D tcp://1212:google.com:443").is_err());
+        assert!(parse_forward("-L tcp://google.com").is_err());
+    }
+
+    #[test_case(None, None => TransportState::Idle ; "never connected")]
+    #[test_case(Some(3), None => TransportState::Up ; "connected")]
+    #[test_case(Some(3), Some("connection refused") => TransportState::Down ; "failing")]
+    #[test_case(None, Some("connection refused") => TransportState::Down ; "never reached")]
+    fn test_transport_state(last_connection_secs_ago: Option<u64>, last_error: Option<&str>) -> TransportState {
+        TransportState::from(&HealthStatus {
+            ready: false,
+            listening: true,
+            certificate_expires_in_secs: None,
+            last_transport_connection_secs_ago: last_connection_secs_ago,
+            last_transport_error: last_error.map(str::to_string),
+        })
+    }
+}
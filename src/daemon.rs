@@ -0,0 +1,100 @@
+//! Run in the background without a service manager (--daemon), for the BSDs, embedded boxes and OpenWrt init scripts
+
+use anyhow::{anyhow, Context};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::{dup2, fork, setsid, ForkResult, Pid};
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Detach from the terminal and the session of the caller, and continue in a grandchild process whose parent has
+/// exited. The working directory is kept, so the relative paths of the arguments stay valid.
+/// Must be called before any thread is started, only the calling thread survives a fork.
+/// False in the parent processes, which must exit right away
+pub fn daemonize() -> anyhow::Result<bool> {
+    // Safety: single threaded, nothing is left in an inconsistent state in the child
+    if let ForkResult::Parent { .. } = unsafe { fork() }.context("Cannot fork the daemon")? {
+        return Ok(false);
+    }
+    setsid().context("Cannot create the session of the daemon")?;
+    // Not a session leader anymore, so the daemon can never acquire a controlling terminal
+    if let ForkResult::Parent { .. } = unsafe { fork() }.context("Cannot fork the daemon")? {
+        return Ok(false);
+    }
+
+    let null = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Cannot open /dev/null")?;
+    for fd in 0..=2 {
+        dup2(null.as_raw_fd(), fd).context("Cannot redirect the standard streams of the daemon")?;
+    }
+
+    Ok(true)
+}
+
+/// File with the pid of this process, removed when dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Fails if the file has the pid of another process still running, i.e: the daemon is started twice
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => {
+                if let Some(pid) = content.trim().parse::<i32>().ok().filter(|pid| *pid > 0) {
+                    // EPERM when the process runs as another user, only ESRCH tells it is not running
+                    let is_running = kill(Pid::from_raw(pid), None) != Err(Errno::ESRCH);
+                    if pid != std::process::id() as i32 && is_running {
+                        return Err(anyhow!("Already running with pid {}, according to {}", pid, path.display()));
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(anyhow!(err).context(format!("Cannot read pid file {}", path.display()))),
+        }
+
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Cannot write pid file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("wstunnel-{}.pid", std::process::id()));
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+
+        // pid 1 is always running
+        std::fs::write(&path, "1\n").unwrap();
+        assert!(PidFile::create(&path).is_err());
+
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_stale_pid_file() {
+        let path = std::env::temp_dir().join(format!("wstunnel-stale-{}.pid", std::process::id()));
+        std::fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        drop(PidFile::create(&path).unwrap());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::tunnel::tunnel_metrics::{TunnelMetricsInfo, TUNNEL_METRICS};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::LazyLock;
+use std::time::Instant;
+use tracing::{info, warn};
+
+static STARTED_AT: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+#[derive(Debug, Serialize)]
+struct ExitSummary<'a> {
+    uptime_secs: u64,
+    tunnels: Vec<TunnelSummary<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct TunnelSummary<'a> {
+    name: &'a str,
+    uptime_secs: u64,
+    connections: u64,
+    errors: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+/// Start of the uptime reported in the summary
+pub(crate) fn start_uptime() {
+    LazyLock::force(&STARTED_AT);
+}
+
+/// Log the connections, bytes and errors of each tunnel configured with -L/-R, and write them as json to the file
+pub(crate) fn report_exit_summary(file: Option<&Path>) {
+    let forwards = TUNNEL_METRICS.list();
+    let summary = ExitSummary {
+        uptime_secs: STARTED_AT.elapsed().as_secs(),
+        tunnels: forwards.iter().map(TunnelSummary::from).collect(),
+    };
+
+    info!("Exiting after {}", human_duration(summary.uptime_secs));
+    for tunnel in &summary.tunnels {
+        info!(
+            "{}: {} connections, {} sent, {} received, {} errors",
+            tunnel.name,
+            tunnel.connections,
+            human_bytes(tunnel.bytes_sent),
+            human_bytes(tunnel.bytes_received),
+            tunnel.errors
+        );
+    }
+
+    let Some(path) = file else {
+        return;
+    };
+    let ret = serde_json::to_vec_pretty(&summary)
+        .map_err(std::io::Error::from)
+        .and_then(|json| std::fs::write(path, json));
+    match ret {
+        Ok(()) => info!("Exit summary written to {}", path.display()),
+        Err(err) => warn!("Cannot write exit summary to {}: {}", path.display(), err),
+    }
+}
+
+impl<'a> From<&'a TunnelMetricsInfo> for TunnelSummary<'a> {
+    fn from(forward: &'a TunnelMetricsInfo) -> Self {
+        Self {
+            name: &forward.name,
+            uptime_secs: forward.uptime_secs,
+            connections: forward.connections,
+            errors: forward.errors,
+            bytes_sent: forward.bytes_sent,
+            bytes_received: forward.bytes_received,
+        }
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+fn human_duration(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(0 => "0 B")]
+    #[test_case(1023 => "1023 B")]
+    #[test_case(1536 => "1.5 KiB")]
+    #[test_case(5 * 1024 * 1024 * 1024 => "5.0 GiB")]
+    fn test_human_bytes(bytes: u64) -> String {
+        human_bytes(bytes)
+    }
+
+    #[test_case(42 => "42s")]
+    #[test_case(125 => "2m5s")]
+    #[test_case(7260 => "2h1m")]
+    fn test_human_duration(secs: u64) -> String {
+        human_duration(secs)
+    }
+}
@@ -0,0 +1,190 @@
+use crate::protocols::tls;
+use crate::tunnel::spawn_until_cancelled;
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{http, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Wait before accepting again after an error, i.e: when the process is out of file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// State of this client or server, reported by the health endpoints
+pub static HEALTH: LazyLock<Health> = LazyLock::new(Health::default);
+
+#[derive(Default)]
+pub struct Health {
+    listening: AtomicBool,
+    /// Unix timestamp after which the tls certificate is not valid anymore
+    certificate_not_after: Mutex<Option<i64>>,
+    last_transport_connection: Mutex<Option<SystemTime>>,
+    last_transport_error: Mutex<Option<(SystemTime, String)>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthStatus {
+    pub ready: bool,
+    pub listening: bool,
+    pub certificate_expires_in_secs: Option<i64>,
+    pub last_transport_connection_secs_ago: Option<u64>,
+    pub last_transport_error: Option<String>,
+}
+
+impl Health {
+    /// All the listeners are bound and accepting connections
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::Relaxed);
+    }
+
+    /// Track the expiry of the leaf certificate, to be called again when it is reloaded
+    pub fn set_certificate(&self, certificates: &[CertificateDer<'static>]) {
+        *self.certificate_not_after.lock() =
+            tls::find_leaf_certificate(certificates).map(|cert| cert.validity().not_after.timestamp());
+    }
+
+    /// A tunnel has been established with the remote side (the server for a client, a client for a server)
+    pub fn transport_connected(&self) {
+        *self.last_transport_connection.lock() = Some(SystemTime::now());
+    }
+
+    /// The client could not establish a tunnel with the server
+    pub fn transport_failed(&self, err: &anyhow::Error) {
+        *self.last_transport_error.lock() = Some((SystemTime::now(), err.to_string()));
+    }
+
+    /// Kept once the client could connect again, unlike in the status
+    pub fn last_transport_error(&self) -> Option<(SystemTime, String)> {
+        self.last_transport_error.lock().clone()
+    }
+
+    /// Ready when listening, with a valid certificate and when the last attempt to connect to the server did not fail
+    pub fn status(&self) -> HealthStatus {
+        let now = SystemTime::now();
+        let listening = self.listening.load(Ordering::Relaxed);
+        let certificate_expires_in_secs = self.certificate_not_after.lock().map(|not_after| {
+            let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+            not_after - now
+        });
+        let last_connection = *self.last_transport_connection.lock();
+        let last_error = self.last_transport_error.lock().clone();
+        let transport_failing = match (&last_error, last_connection) {
+            (Some((failed_at, _)), Some(connected_at)) => *failed_at > connected_at,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        HealthStatus {
+            ready: listening && certificate_expires_in_secs.is_none_or(|secs| secs > 0) && !transport_failing,
+            listening,
+            certificate_expires_in_secs,
+            last_transport_connection_secs_ago: last_connection
+                .map(|at| now.duration_since(at).unwrap_or_default().as_secs()),
+            last_transport_error: last_error.filter(|_| transport_failing).map(|(_, err)| err),
+        }
+    }
+}
+
+/// Serve /healthz (always 200 while running) and /readyz (503 when not ready) for kubernetes probes and load
+/// balancers. Both answer with the health status as json. The listener is closed once the token is cancelled
+pub async fn run_health_server(bind: SocketAddr, cancel: &CancellationToken) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    info!("Serving health endpoints on http://{}/healthz and http://{}/readyz", bind, bind);
+
+    let connections_cancel = cancel.clone();
+    spawn_until_cancelled(cancel, async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    // i.e: too many open files, retrying right away would only spin
+                    warn!("Error while accepting health connection {:?}", err);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            spawn_until_cancelled(&connections_cancel, async move {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    Ok::<_, http::Error>(health_response(req.uri().path(), &HEALTH.status()))
+                });
+                if let Err(err) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    warn!("Error while serving health request {:?}", err);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn health_response(path: &str, status: &HealthStatus) -> Response<Full<Bytes>> {
+    let code = match path {
+        "/healthz" => StatusCode::OK,
+        "/readyz" if status.ready => StatusCode::OK,
+        "/readyz" => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::NOT_FOUND,
+    };
+    let body = serde_json::to_vec(status).unwrap_or_default();
+
+    Response::builder()
+        .status(code)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readiness() {
+        let health = Health::default();
+        assert!(!health.status().ready);
+
+        health.set_listening(true);
+        assert!(health.status().ready);
+        assert_eq!(health_response("/readyz", &health.status()).status(), StatusCode::OK);
+
+        health.transport_failed(&anyhow::anyhow!("connection refused"));
+        let status = health.status();
+        assert!(!status.ready);
+        assert_eq!(status.last_transport_error.as_deref(), Some("connection refused"));
+        assert_eq!(health_response("/readyz", &status).status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(health_response("/healthz", &status).status(), StatusCode::OK);
+        assert_eq!(health_response("/", &status).status(), StatusCode::NOT_FOUND);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        health.transport_connected();
+        assert!(health.status().ready);
+
+        *health.certificate_not_after.lock() = Some(0);
+        assert!(!health.status().ready);
+    }
+
+    #[tokio::test]
+    async fn test_health_server_stops_when_cancelled() {
+        let bind = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        run_health_server(bind, &cancel).await.unwrap();
+        assert!(TcpListener::bind(bind).await.is_err());
+
+        cancel.cancel();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(TcpListener::bind(bind).await.is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+//! Servers of the client named in a hosts file, with their options, see hosts.yaml.
+//! `wstunnel client NAME` then replaces the url of the server and the options repeated on each invocation
+
+use crate::config::parsers::{parse_reverse_tunnel_arg, parse_server_url, parse_sni_override, parse_tunnel_arg};
+use crate::config::{Client, DEFAULT_CLIENT_UPGRADE_PATH_PREFIX};
+use anyhow::{anyhow, Context};
+use hyper::header::{HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Format of an alias of the hosts file, the options have the name of the arguments
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawHostAlias {
+    pub server: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub http_headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_upgrade_path_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_upgrade_credentials: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_upgrade_bearer_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_proxy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_sni_override: Option<String>,
+    #[serde(default)]
+    pub tls_verify_certificate: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_certificate: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_private_key: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub local_to_remote: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remote_to_local: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct HostAlias {
+    pub name: String,
+    pub path: PathBuf,
+    pub server: Url,
+    pub raw: RawHostAlias,
+}
+
+/// $WSTUNNEL_HOSTS_FILE, or the hosts file of the configuration directory of the user: ~/.config/wstunnel/hosts
+pub fn hosts_file() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("WSTUNNEL_HOSTS_FILE").filter(|path| !path.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ if cfg!(windows) => PathBuf::from(std::env::var_os("APPDATA")?),
+        _ => home_dir()?.join(".config"),
+    };
+    Some(config_dir.join("wstunnel").join("hosts"))
+}
+
+/// Server given on the command line: its url, or the name of an alias of the hosts file
+pub fn resolve_server(arg: &str) -> anyhow::Result<(Url, Option<HostAlias>)> {
+    if arg.contains("://") {
+        return Ok((parse_server_url(arg)?, None));
+    }
+    let path = hosts_file().context("Cannot find the hosts file, set WSTUNNEL_HOSTS_FILE")?;
+    let alias = HostAlias::load(&path, arg)?;
+    Ok((alias.server.clone(), Some(alias)))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" })
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Paths of the file can start with ~/ for the home directory
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), home_dir()) {
+        (Ok(path), Some(home)) => home.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+impl HostAlias {
+    pub fn load(path: &Path, name: &str) -> anyhow::Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("Cannot read hosts file {}", path.display()))?;
+        let mut aliases: BTreeMap<String, RawHostAlias> =
+            serde_yaml::from_str(&content).with_context(|| format!("Invalid hosts file {}", path.display()))?;
+        let raw = aliases
+            .remove(name)
+            .ok_or_else(|| anyhow!("No host {} in the hosts file {}", name, path.display()))?;
+        let server = parse_server_url(&raw.server)
+            .with_context(|| format!("Invalid server of host {} in {}", name, path.display()))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            server,
+            raw,
+        })
+    }
+
+    /// Apply the options of the alias the arguments did not give. Its headers are added to the ones of -H, and its
+    /// tunnels are only started when none is given with -L/-R
+    pub fn apply(&self, args: &mut Client) -> anyhow::Result<()> {
+        let raw = &self.raw;
+        let context = || format!("Invalid host {} in {}", self.name, self.path.display());
+
+        for (name, value) in &raw.http_headers {
+            let name = HeaderName::try_from(name).with_context(context)?;
+            if args.http_headers.iter().all(|(header, _)| *header != name) {
+                let value = HeaderValue::try_from(value).with_context(context)?;
+                args.http_headers.push((name, value));
+            }
+        }
+        if let Some(prefix) = &raw.http_upgrade_path_prefix {
+            if args.http_upgrade_path_prefix == DEFAULT_CLIENT_UPGRADE_PATH_PREFIX {
+                args.http_upgrade_path_prefix = prefix.clone();
+            }
+        }
+        args.http_upgrade_credentials = args
+            .http_upgrade_credentials
+            .take()
+            .or_else(|| raw.http_upgrade_credentials.clone());
+        args.http_upgrade_bearer_token = args
+            .http_upgrade_bearer_token
+            .take()
+            .or_else(|| raw.http_upgrade_bearer_token.clone());
+        args.http_proxy = args.http_proxy.take().or_else(|| raw.http_proxy.clone());
+        if let (None, Some(sni)) = (&args.tls_sni_override, &raw.tls_sni_override) {
+            args.tls_sni_override = Some(parse_sni_override(sni).with_context(context)?);
+        }
+        args.tls_verify_certificate |= raw.tls_verify_certificate;
+        args.tls_certificate = args
+            .tls_certificate
+            .take()
+            .or_else(|| raw.tls_certificate.as_deref().map(expand_home));
+        args.tls_private_key = args
+            .tls_private_key
+            .take()
+            .or_else(|| raw.tls_private_key.as_deref().map(expand_home));
+
+        if args.local_to_remote.is_empty() && args.remote_to_local.is_empty() {
+            for arg in &raw.local_to_remote {
+                let tunnel = parse_tunnel_arg(arg).with_context(|| format!("Invalid tunnel {}", arg))?;
+                args.local_to_remote.push(tunnel);
+            }
+            for arg in &raw.remote_to_local {
+                let tunnel =
+                    parse_reverse_tunnel_arg(arg).with_context(|| format!("Invalid reverse tunnel {}", arg))?;
+                args.remote_to_local.push(tunnel);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "clap"))]
+mod tests {
+    use super::*;
+    use clap::{Args, Command, FromArgMatches};
+
+    const HOSTS: &str = "
+work:
+  server: wss://wstunnel.corp.example.com
+  http_headers:
+    X-Team: infra
+    X-Api-Key: file:/run/secrets/key
+  http_upgrade_bearer_token: env:WORK_TOKEN
+  tls_certificate: ~/.config/wstunnel/work.pem
+  local_to_remote:
+    - socks5://127.0.0.1:1080
+";
+
+    fn client(args: &[&str]) -> Client {
+        let matches = Client::augment_args(Command::new("client"))
+            .try_get_matches_from(args)
+            .unwrap();
+        Client::from_arg_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn test_host_alias() {
+        let path = std::env::temp_dir().join(format!("wstunnel-hosts-{}.yaml", std::process::id()));
+        std::fs::write(&path, HOSTS).unwrap();
+        let alias = HostAlias::load(&path, "work").unwrap();
+        assert!(HostAlias::load(&path, "home").is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(alias.server.as_str(), "wss://wstunnel.corp.example.com/");
+
+        let mut args = client(&["client", "-H", "X-Team: sre", alias.server.as_str()]);
+        alias.apply(&mut args).unwrap();
+        assert_eq!(args.http_headers.len(), 2);
+        assert_eq!(args.http_headers[0].1, "sre");
+        assert_eq!(args.http_upgrade_bearer_token.as_deref(), Some("env:WORK_TOKEN"));
+        assert!(!args.tls_certificate.unwrap().starts_with("~"));
+        assert_eq!(args.local_to_remote.len(), 1);
+
+        // The tunnels of the command line replace the ones of the alias
+        let mut args = client(&["client", "-L", "tcp://2222:localhost:22", alias.server.as_str()]);
+        alias.apply(&mut args).unwrap();
+        assert_eq!(args.local_to_remote.len(), 1);
+        assert_eq!(args.local_to_remote[0].local.port(), 2222);
+    }
+}
@@ -0,0 +1,49 @@
+//! State of a client or server shared with the program running it, i.e: the command line tells systemd when it is
+//! ready and dumps its tunnels on SIGUSR1. Each client or server of the process has its own
+
+use crate::state_dump;
+use crate::tunnel::active_tunnels::ActiveTunnels;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceStatus {
+    Starting,
+    /// The listeners are bound, and the tunnels of the arguments are started
+    Ready,
+    /// Asked to stop, its tunnels are draining
+    Stopping,
+}
+
+#[derive(Clone)]
+pub struct InstanceState {
+    /// Tunnels currently open by the client or server, listed by the 'tunnels' admin command
+    pub(crate) active_tunnels: Arc<ActiveTunnels>,
+    status: Arc<watch::Sender<InstanceStatus>>,
+}
+
+impl Default for InstanceState {
+    fn default() -> Self {
+        Self {
+            active_tunnels: Arc::default(),
+            status: Arc::new(watch::channel(InstanceStatus::Starting).0),
+        }
+    }
+}
+
+impl InstanceState {
+    /// Changes of the status from now on, starting with the current one
+    pub fn status(&self) -> watch::Receiver<InstanceStatus> {
+        self.status.subscribe()
+    }
+
+    pub(crate) fn set_status(&self, status: InstanceStatus) {
+        self.status.send_replace(status);
+    }
+
+    /// Dump the internal state with the open tunnels, as one json line at the end of the file, or to the log
+    pub fn dump(&self, file: Option<&Path>) {
+        state_dump::write_dump(file, &self.active_tunnels);
+    }
+}
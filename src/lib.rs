@@ -1,49 +1,222 @@
+#[cfg(feature = "runtime")]
+mod bench;
+#[cfg(feature = "runtime")]
+pub mod builder;
+#[cfg(feature = "runtime")]
+mod cert_gen;
+#[cfg(feature = "runtime")]
+mod client_tunnels;
+#[cfg(feature = "runtime")]
 pub mod config;
+#[cfg(feature = "runtime")]
+pub mod config_check;
+#[cfg(all(feature = "runtime", unix))]
+pub mod daemon;
+#[cfg(feature = "runtime")]
 mod embedded_certificate;
+#[cfg(feature = "runtime")]
+mod exit_summary;
+#[cfg(feature = "runtime")]
+pub mod health;
+#[cfg(feature = "runtime")]
+pub mod host_aliases;
+#[cfg(feature = "runtime")]
+pub mod instance;
+#[cfg(feature = "runtime")]
+pub mod log_filter;
+#[cfg(feature = "runtime")]
+pub mod profile;
+pub mod protocol;
+#[cfg(feature = "runtime")]
 mod protocols;
-mod restrictions;
+#[cfg(feature = "runtime")]
+pub mod restrictions;
+#[cfg(feature = "runtime")]
+pub mod runtime_metrics;
+#[cfg(feature = "runtime")]
+pub mod sandbox;
+#[cfg(feature = "runtime")]
+mod secret;
+#[cfg(feature = "runtime")]
+mod self_update;
+#[cfg(feature = "sentry")]
+pub mod sentry;
+#[cfg(feature = "runtime")]
 mod somark;
-#[cfg(test)]
+#[cfg(feature = "runtime")]
+pub mod state_dump;
+#[cfg(feature = "runtime")]
+mod statsd;
+#[cfg(all(feature = "runtime", test))]
 mod test_integrations;
+#[cfg(feature = "runtime")]
 mod tunnel;
 
-use crate::config::{Client, Server, DEFAULT_CLIENT_UPGRADE_PATH_PREFIX};
-use crate::protocols::dns::DnsResolver;
+/// Udp server handing out a stream per peer, like a tcp listener, for the programs which only need it
+#[cfg(feature = "udp-stream")]
+pub mod udp_stream {
+    pub use crate::protocols::udp::{UdpServerBuilder, UdpStream, UdpStreamWriter};
+}
+
+/// Tun tunnels fed with the ip packets of an embedding app, i.e: the tun interface of a mobile VPN app
+#[cfg(feature = "runtime")]
+pub mod packet {
+    pub use crate::protocols::packet::{
+        channel, destination, source, PacketDevice, PacketNetwork, PacketQueue, PacketReceiver, PacketSender,
+    };
+    #[cfg(target_os = "linux")]
+    pub use crate::protocols::packet::{TunInterface, TunNetwork};
+}
+
+#[cfg(feature = "runtime")]
+use crate::builder::{ClientExtensions, ServerExtensions};
+#[cfg(feature = "runtime")]
+pub use crate::builder::{TunnelClientBuilder, TunnelHandle, TunnelServerBuilder};
+#[cfg(feature = "runtime")]
+use crate::client_tunnels::ClientTunnels;
+#[cfg(feature = "runtime")]
+pub use crate::client_tunnels::{ClientStatus, LastError, TransportState, TransportStatus, TunnelOrigin, TunnelStatus};
+#[cfg(feature = "runtime")]
+use crate::config::{
+    Bench, CertGen, CheckRestrictions, Client, LocalToRemote, SelfUpdate, Server, DEFAULT_CLIENT_UPGRADE_PATH_PREFIX,
+};
+#[cfg(feature = "runtime")]
+use crate::health::{run_health_server, HEALTH};
+#[cfg(feature = "runtime")]
+pub use crate::instance::{InstanceState, InstanceStatus};
+#[cfg(feature = "runtime")]
+pub use crate::protocols::dns::HostResolver;
+#[cfg(feature = "runtime")]
+use crate::protocols::dns::{DnsResolver, IpFamily};
+#[cfg(feature = "runtime")]
+use crate::protocols::pac::PacFile;
+#[cfg(feature = "runtime")]
+use crate::protocols::packet::PacketNetwork;
+#[cfg(all(feature = "runtime", target_os = "linux"))]
+use crate::protocols::packet::TunNetwork;
+#[cfg(feature = "runtime")]
 use crate::protocols::tls;
+#[cfg(feature = "runtime")]
+use crate::restrictions::geoip::GeoIpDatabases;
+#[cfg(feature = "runtime")]
 use crate::restrictions::types::RestrictionsRules;
+#[cfg(feature = "runtime")]
+use crate::secret::resolve_secret;
+#[cfg(feature = "runtime")]
+pub use crate::secret::Secret;
+#[cfg(feature = "runtime")]
 use crate::somark::SoMark;
-use crate::tunnel::client::{TlsClientConfig, WsClient, WsClientConfig};
-use crate::tunnel::connectors::{Socks5TunnelConnector, TcpTunnelConnector, UdpTunnelConnector};
-use crate::tunnel::listeners::{
-    new_stdio_listener, HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener,
+#[cfg(feature = "runtime")]
+pub use crate::somark::{set_socket_protector, SocketProtector};
+#[cfg(feature = "runtime")]
+use crate::state_dump::{PoolOccupancy, INTERNAL_STATE};
+#[cfg(feature = "runtime")]
+use crate::statsd::StatsdExporter;
+#[cfg(feature = "runtime")]
+use crate::tunnel::capture::CaptureFile;
+#[cfg(feature = "runtime")]
+use crate::tunnel::client::{
+    AccessLog, ClientConfigFile, ConnectMode, ReconnectPolicy, ReloadableClientConfig, ServerFailover, TlsClientConfig,
+    TotpCommand, UpgradeSecretArgs, UpgradeSecrets, WsClient, WsClientConfig,
+};
+#[cfg(feature = "runtime")]
+pub use crate::tunnel::events::TunnelEvent;
+#[cfg(feature = "runtime")]
+pub use crate::tunnel::interceptor::{InterceptedRead, InterceptedWrite, StreamInfo, StreamInterceptor};
+#[cfg(feature = "runtime")]
+use crate::tunnel::listeners::{new_stdio_listener, TunnelListener};
+#[cfg(feature = "runtime")]
+use crate::tunnel::server::{
+    AuditLog, AuthWebhook, BanPolicy, BearerAuth, EventWebhook, HttpAccessLog, LdapAuth, PrivilegeDrop, QuotaStore,
+    RateLimiter, RevocationList, ServerRegistries, TlsServerConfig, TotpVerifier, WsServer, WsServerConfig,
 };
-use crate::tunnel::server::{TlsServerConfig, WsServer, WsServerConfig};
+#[cfg(feature = "runtime")]
+pub use crate::tunnel::server::{AuthInfo, TunnelEventHandler, TunnelInfo, TunnelService, TunnelStats};
+#[cfg(feature = "runtime")]
+use crate::tunnel::spa::{SpaGate, SpaKnocker};
+#[cfg(feature = "runtime")]
+use crate::tunnel::spawn_until_cancelled;
+#[cfg(feature = "runtime")]
 use crate::tunnel::transport::{TransportAddr, TransportScheme};
+#[cfg(feature = "runtime")]
+pub use crate::tunnel::transport::{TransportRead, TransportWrite, TunnelTransport};
+#[cfg(feature = "runtime")]
 pub use crate::tunnel::LocalProtocol;
+#[cfg(feature = "runtime")]
 use crate::tunnel::{to_host_port, RemoteAddr};
+#[cfg(feature = "runtime")]
 use anyhow::{anyhow, Context};
-use futures_util::future::join_all;
+#[cfg(feature = "runtime")]
+use arc_swap::ArcSwap;
+#[cfg(feature = "runtime")]
+use futures_util::{future, StreamExt};
+#[cfg(feature = "runtime")]
 use hyper::header::HOST;
+#[cfg(feature = "runtime")]
 use hyper::http::HeaderValue;
+#[cfg(feature = "runtime")]
 use log::debug;
+#[cfg(feature = "runtime")]
 use parking_lot::{Mutex, RwLock};
+#[cfg(feature = "runtime")]
+pub use socket2::SockRef;
+#[cfg(feature = "runtime")]
+use std::mem;
+#[cfg(feature = "runtime")]
+use std::net::SocketAddr;
+#[cfg(feature = "runtime")]
+use std::path::Path;
+#[cfg(feature = "runtime")]
 use std::str::FromStr;
+#[cfg(feature = "runtime")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "runtime")]
 use std::sync::Arc;
+#[cfg(feature = "runtime")]
 use std::time::Duration;
+#[cfg(feature = "runtime")]
 use tokio::select;
-use tracing::{error, info};
+#[cfg(feature = "runtime")]
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+#[cfg(feature = "runtime")]
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "runtime")]
+use tracing::{error, info, warn};
+#[cfg(feature = "runtime")]
 use url::Url;
 
-pub async fn run_client(args: Client) -> anyhow::Result<()> {
-    let (tls_certificate, tls_key) = if let (Some(cert), Some(key)) =
-        (args.tls_certificate.as_ref(), args.tls_private_key.as_ref())
-    {
-        let tls_certificate = tls::load_certificates_from_pem(cert).expect("Cannot load client TLS certificate (mTLS)");
-        let tls_key = tls::load_private_key_from_file(key).expect("Cannot load client TLS private key (mTLS)");
-        (Some(tls_certificate), Some(tls_key))
-    } else {
-        (None, None)
-    };
+/// Client connecting to the server configured by the arguments, without any tunnel yet.
+/// With --failover-server, its connections go to the first reachable server
+#[cfg(feature = "runtime")]
+async fn new_client(args: &Client, extensions: ClientExtensions) -> anyhow::Result<WsClient> {
+    let shared = new_client_shared(args, extensions)?;
+    let client = new_server_client(args, &args.remote_addr, &shared).await?;
+    if args.failover_server.is_empty() {
+        return Ok(client);
+    }
+
+    let mut servers = vec![client.clone()];
+    for remote_addr in &args.failover_server {
+        servers.push(new_server_client(args, remote_addr, &shared).await?);
+    }
+    let failover = ServerFailover::new(servers);
+    failover.clone().run_health_checks(args.failover_check_interval);
+    Ok(client.with_failover(failover))
+}
+
+/// The --config file and the secrets apply to all the servers
+#[cfg(feature = "runtime")]
+fn new_client_shared(args: &Client, extensions: ClientExtensions) -> anyhow::Result<ServerClientShared> {
+    let (tls_certificate, tls_key) =
+        if let (Some(cert), Some(key)) = (args.tls_certificate.as_ref(), args.tls_private_key.as_ref()) {
+            let tls_certificate =
+                tls::load_certificates_from_pem(cert).context("Cannot load client TLS certificate (mTLS)")?;
+            let tls_key = tls::load_private_key_from_file(key).context("Cannot load client TLS private key (mTLS)")?;
+            HEALTH.set_certificate(&tls_certificate);
+            (Some(tls_certificate), Some(tls_key))
+        } else {
+            (None, None)
+        };
 
     let http_upgrade_path_prefix = if args.http_upgrade_path_prefix.eq(DEFAULT_CLIENT_UPGRADE_PATH_PREFIX) {
         // When using mTLS and no manual http upgrade path is specified configure the HTTP upgrade path
@@ -52,12 +225,65 @@ pub async fn run_client(args: Client) -> anyhow::Result<()> {
             .as_ref()
             .and_then(|certs| tls::find_leaf_certificate(certs.as_slice()))
             .and_then(|leaf_cert| tls::cn_from_certificate(&leaf_cert))
-            .unwrap_or(args.http_upgrade_path_prefix)
+            .unwrap_or_else(|| args.http_upgrade_path_prefix.clone())
     } else {
-        args.http_upgrade_path_prefix
+        args.http_upgrade_path_prefix.clone()
     };
+    let upgrade_secrets = UpgradeSecrets::new(UpgradeSecretArgs {
+        path_prefix: http_upgrade_path_prefix,
+        path_prefix_hmac_secret: args.http_upgrade_path_prefix_hmac_secret.clone(),
+        credentials: args.http_upgrade_credentials.clone(),
+        bearer_token: args.http_upgrade_bearer_token.clone(),
+        http_headers: args.http_headers.iter().filter(|(k, _)| k != HOST).cloned().collect(),
+    })
+    .context("Cannot resolve the secrets of the upgrade request")?;
 
-    let transport_scheme = TransportScheme::from_str(args.remote_addr.scheme()).expect("invalid scheme in server url");
+    Ok(ServerClientShared {
+        tls_certificate,
+        tls_key,
+        upgrade_secrets: Arc::new(upgrade_secrets),
+        reloadable: Arc::new(ArcSwap::from_pointee(ReloadableClientConfig::default())),
+        pac_file: args
+            .pac_url
+            .clone()
+            .map(|url| Arc::new(PacFile::new(url, extensions.cancel.clone()))),
+        capture: open_capture_file(args.capture_file.as_deref().filter(|_| args.check.is_none()))?,
+        extensions,
+    })
+}
+
+/// Part of the configuration of the client common to all its servers
+#[cfg(feature = "runtime")]
+struct ServerClientShared {
+    tls_certificate: Option<Vec<CertificateDer<'static>>>,
+    tls_key: Option<PrivateKeyDer<'static>>,
+    upgrade_secrets: Arc<UpgradeSecrets>,
+    reloadable: Arc<ArcSwap<ReloadableClientConfig>>,
+    /// Shared by the servers, so it is fetched once
+    pac_file: Option<Arc<PacFile>>,
+    capture: Option<Arc<CaptureFile>>,
+    extensions: ClientExtensions,
+}
+
+#[cfg(feature = "runtime")]
+async fn new_server_client(args: &Client, remote_addr: &Url, shared: &ServerClientShared) -> anyhow::Result<WsClient> {
+    let client_config = new_client_config(args, remote_addr, shared)?;
+    let min_idle = args.connect_mode.min_idle(args.connection_min_idle);
+    WsClient::new(client_config, min_idle, args.connection_retry_max_backoff).await
+}
+
+#[cfg(feature = "runtime")]
+fn new_client_config(args: &Client, remote_addr: &Url, shared: &ServerClientShared) -> anyhow::Result<WsClientConfig> {
+    // The url of an embedding program is not checked by clap, it must not panic
+    let transport_scheme = TransportScheme::from_str(remote_addr.scheme())
+        .map_err(|_| anyhow!("Invalid server url {}, expected ws, wss, http or https", remote_addr))?;
+    let remote_host = remote_addr
+        .host()
+        .ok_or_else(|| anyhow!("Invalid server url {}, no host", remote_addr))?
+        .to_owned();
+    let remote_port = remote_addr
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("Invalid server url {}, no port", remote_addr))?;
     let tls = match transport_scheme {
         TransportScheme::Ws | TransportScheme::Http => None,
         TransportScheme::Wss | TransportScheme::Https => Some(TlsClientConfig {
@@ -66,12 +292,12 @@ pub async fn run_client(args: Client) -> anyhow::Result<()> {
                     args.tls_verify_certificate,
                     transport_scheme.alpn_protocols(),
                     !args.tls_sni_disable,
-                    tls_certificate,
-                    tls_key,
+                    shared.tls_certificate.clone(),
+                    shared.tls_key.as_ref().map(|key| key.clone_key()),
                 )
-                .expect("Cannot create tls connector"),
+                .context("Cannot create tls connector")?,
             )),
-            tls_sni_override: args.tls_sni_override,
+            tls_sni_override: args.tls_sni_override.clone(),
             tls_verify_certificate: args.tls_verify_certificate,
             tls_sni_disabled: args.tls_sni_disable,
             tls_certificate_path: args.tls_certificate.clone(),
@@ -83,315 +309,532 @@ pub async fn run_client(args: Client) -> anyhow::Result<()> {
     let host_header = if let Some((_, host_val)) = args.http_headers.iter().find(|(h, _)| *h == HOST) {
         host_val.clone()
     } else {
-        let host = match args.remote_addr.port_or_known_default() {
-            None | Some(80) | Some(443) => args.remote_addr.host().unwrap().to_string(),
-            Some(port) => format!("{}:{}", args.remote_addr.host().unwrap(), port),
+        let host = match remote_port {
+            80 | 443 => remote_host.to_string(),
+            port => format!("{}:{}", remote_host, port),
         };
         HeaderValue::from_str(&host)?
     };
     if let Some(path) = &args.http_headers_file {
         if !path.exists() {
-            panic!("http headers file does not exists: {}", path.display());
+            return Err(anyhow!("http headers file does not exists: {}", path.display()));
         }
     }
 
-    let http_proxy = mk_http_proxy(args.http_proxy, args.http_proxy_login, args.http_proxy_password)?;
-    let client_config = WsClientConfig {
-        remote_addr: TransportAddr::new(
-            TransportScheme::from_str(args.remote_addr.scheme()).unwrap(),
-            args.remote_addr.host().unwrap().to_owned(),
-            args.remote_addr.port_or_known_default().unwrap(),
-            tls,
-        )
-        .unwrap(),
+    let ip_family = ip_family(args.ipv4_only, args.ipv6_only, args.dns_resolver_prefer_ipv4, args.prefer_ipv6);
+    let http_proxy = mk_http_proxy(
+        args.http_proxy.clone(),
+        args.http_proxy_login.clone(),
+        args.http_proxy_password.clone(),
+    )?;
+    Ok(WsClientConfig {
+        remote_addr: TransportAddr::new(transport_scheme, remote_host, remote_port, tls)
+            .ok_or_else(|| anyhow!("Invalid server url {}", remote_addr))?,
         socket_so_mark: SoMark::new(args.socket_so_mark),
-        http_upgrade_path_prefix,
-        http_upgrade_credentials: args.http_upgrade_credentials,
-        http_headers: args.http_headers.into_iter().filter(|(k, _)| k != HOST).collect(),
-        http_headers_file: args.http_headers_file,
+        upgrade_secrets: shared.upgrade_secrets.clone(),
+        http_upgrade_totp_command: args.http_upgrade_totp_command.clone().map(TotpCommand::new),
+        http_headers_file: args.http_headers_file.clone(),
         http_header_host: host_header,
         timeout_connect: Duration::from_secs(10),
         websocket_ping_frequency: args
             .websocket_ping_frequency
             .or(Some(Duration::from_secs(30)))
             .filter(|d| d.as_secs() > 0),
+        slow_consumer_timeout: args
+            .slow_consumer_timeout
+            .or(Some(Duration::from_secs(10)))
+            .filter(|d| !d.is_zero()),
         websocket_mask_frame: args.websocket_mask_frame,
-        dns_resolver: DnsResolver::new_from_urls(
-            &args.dns_resolver,
-            http_proxy.clone(),
-            SoMark::new(args.socket_so_mark),
-            !args.dns_resolver_prefer_ipv4,
-        )
-        .expect("cannot create dns resolver"),
+        dns_resolver: match &shared.extensions.dns_resolver {
+            Some(resolver) => DnsResolver::custom(resolver.clone(), ip_family),
+            None => DnsResolver::new_from_urls(
+                &args.dns_resolver,
+                http_proxy.clone(),
+                SoMark::new(args.socket_so_mark),
+                ip_family,
+            )
+            .context("Cannot create dns resolver")?,
+        },
         http_proxy,
+        connection_via: args.connection_via.clone(),
+        pac_file: shared.pac_file.clone(),
+        reverse_accept_hook: args.reverse_accept_hook.clone(),
+        spa_knocker: match (args.spa_port, &args.spa_secret) {
+            (Some(port), Some(secret)) => Some(Arc::new(SpaKnocker::new(resolve_secret(secret)?.as_bytes(), port))),
+            _ => None,
+        },
+        reconnect: ReconnectPolicy {
+            initial_delay: args.reconnect_delay,
+            max_delay: args.reconnect_max_delay,
+            multiplier: args.reconnect_multiplier,
+            jitter: args.reconnect_jitter,
+            max_attempts: args.reconnect_max_attempts,
+            give_up: args.reconnect_give_up,
+            reset_after: args.reconnect_reset_after,
+        },
+        reloadable: shared.reloadable.clone(),
+        transport: shared.extensions.transport.clone(),
+        interceptors: shared.extensions.interceptors.clone(),
+        cancel: shared.extensions.cancel.clone(),
+        events: shared.extensions.events.clone(),
+        instance: shared.extensions.instance.clone(),
+        capture: shared.capture.clone(),
+    })
+}
+
+/// --check of the client: its configuration for all its servers, without the connection pools reaching them
+#[cfg(feature = "runtime")]
+fn check_client(args: &Client) -> anyhow::Result<()> {
+    let shared = new_client_shared(args, ClientExtensions::default())?;
+    for remote_addr in std::iter::once(&args.remote_addr).chain(&args.failover_server) {
+        new_client_config(args, remote_addr, &shared)?;
+    }
+    if let Some(path) = &args.config {
+        ClientConfigFile::from_file(path)?;
+    }
+
+    Ok(())
+}
+
+/// Measure the latency and throughput to the bench endpoint of the server, and print them as json
+#[cfg(feature = "runtime")]
+pub async fn run_bench(args: Bench) -> anyhow::Result<()> {
+    let client = new_client(&args.client, ClientExtensions::default()).await?;
+    info!(
+        "Measuring the tunnels to {} for {:?} each way",
+        args.client.remote_addr, args.duration
+    );
+    let server = args.client.remote_addr.to_string();
+    let result = bench::run_bench(client, server, args.duration, args.pings).await?;
+    println!("{}", serde_json::to_string(&result)?);
+    Ok(())
+}
+
+/// Run the client until ctrl+c or SIGTERM.
+/// Some(exit_code) when the process must exit right away: the --exec command exited with --exec-exit, or a stdio
+/// tunnel is closed while its standard input can still be read by a blocking thread
+#[cfg(feature = "runtime")]
+pub async fn run_client(args: Client) -> anyhow::Result<Option<i32>> {
+    run_client_with_instance(args, InstanceState::default()).await
+}
+
+/// Same as run_client, the program running it follows the status of the client and can dump its state
+#[cfg(feature = "runtime")]
+pub async fn run_client_with_instance(args: Client, instance: InstanceState) -> anyhow::Result<Option<i32>> {
+    let extensions = ClientExtensions {
+        instance,
+        ..Default::default()
     };
+    tokio::spawn(cancel_on_shutdown_signal(extensions.cancel.clone()));
+    run_client_until(args, extensions).await
+}
+
+/// Run the client until its cancellation token is cancelled, which stops all its tasks.
+/// The token is cancelled when the client stops by itself too
+#[cfg(feature = "runtime")]
+pub(crate) async fn run_client_until(
+    mut args: Client,
+    mut extensions: ClientExtensions,
+) -> anyhow::Result<Option<i32>> {
+    let cancel = extensions.cancel.clone();
+    let _stop_tasks = cancel.clone().drop_guard();
+    // Last, the tunnels before it are started and the client exits with it
+    if let Some(tunnel) = args.stdio.take() {
+        args.local_to_remote.push(tunnel);
+    }
+    if let Some(profile) = args.profile {
+        profile.apply_to_tunnels(&mut args.local_to_remote);
+    }
+    if args.exec.is_some()
+        && args
+            .local_to_remote
+            .iter()
+            .any(|tunnel| matches!(tunnel.local_protocol, LocalProtocol::Stdio { .. }))
+    {
+        return Err(anyhow!(
+            "--exec cannot be used with a stdio tunnel, they would both read the standard input"
+        ));
+    }
+
+    if args.check.is_some() {
+        return check_client(&args).map(|_| None);
+    }
 
-    let client = WsClient::new(client_config, args.connection_min_idle, args.connection_retry_max_backoff).await?;
+    INTERNAL_STATE.set_config(&args);
+    exit_summary::start_uptime();
+    let exit_summary_file = args.exit_summary_file.clone();
+    let instance = extensions.instance.clone();
+    let started_tunnels = extensions.tunnels.clone();
+    let packet_tunnels = mem::take(&mut extensions.packet_tunnels);
+    let client = new_client(&args, extensions).await?;
+    if args.connect_mode != ConnectMode::Lazy {
+        client.connect_now().await?;
+        info!("Connected to the server at startup");
+    }
+    let cnx_pool = client.cnx_pool.clone();
+    INTERNAL_STATE.set_connection_pool(move || {
+        let state = cnx_pool.state();
+        PoolOccupancy {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+        }
+    });
     info!("Starting wstunnel client v{}", env!("CARGO_PKG_VERSION"),);
 
-    // Keep track of all spawned tunnels
-    let mut spawned_tunnels = Vec::new();
+    let access_log_file = args.access_log_file.as_deref().map(AccessLog::open_file).transpose()?;
+    let tunnels = ClientTunnels::new(client.clone(), access_log_file);
+    if let Some(started_tunnels) = &started_tunnels {
+        let _ = started_tunnels.set(tunnels.clone());
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &args.admin_socket {
+        let listener = protocols::unix_sock::run_server(path, Some(0o600)).await?;
+        spawn_until_cancelled(&cancel, tunnel::client::run_admin_server(listener, tunnels.clone()));
+    }
+    #[cfg(not(unix))]
+    if args.admin_socket.is_some() {
+        return Err(anyhow!("Admin socket is only available on unix platforms"));
+    }
+    if let Some(bind) = args.health_bind {
+        run_health_server(bind, &cancel).await?;
+    }
+    if let Some(addr) = args.statsd_addr {
+        let exporter = StatsdExporter {
+            addr,
+            prefix: args.statsd_prefix,
+            tags: args.statsd_tag,
+            interval: args.statsd_interval,
+        };
+        exporter.run(&cancel, instance.active_tunnels.clone()).await?;
+    }
 
     // Start tunnels
+    for (address, device) in packet_tunnels {
+        let client = client.clone().with_tunnel_metrics(format!("L:tun://{}", address));
+        let (host, _) = to_host_port(SocketAddr::new(address, 0));
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tun,
+            host,
+            port: 0,
+        };
+        spawn_until_cancelled(&cancel, async move {
+            if let Err(err) = client.run_packet_tunnel(remote, device).await {
+                error!("{:?}", err);
+            }
+        });
+    }
     for tunnel in args.remote_to_local.into_iter() {
-        let client = client.clone();
+        tunnels
+            .start(tunnel_metrics_name(&tunnel), tunnel, TunnelOrigin::Args)
+            .await?;
+    }
+    let mut local_addrs = Vec::with_capacity(args.local_to_remote.len());
+    for tunnel in args.local_to_remote.into_iter() {
         match &tunnel.local_protocol {
-            LocalProtocol::ReverseTcp { .. } => {
-                spawned_tunnels.push(tokio::spawn(async move {
-                    let cfg = client.config.clone();
-                    let tcp_connector = TcpTunnelConnector::new(
-                        &tunnel.remote.0,
-                        tunnel.remote.1,
-                        cfg.socket_so_mark,
-                        cfg.timeout_connect,
-                        &cfg.dns_resolver,
-                    );
-                    let (host, port) = to_host_port(tunnel.local);
-                    let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseTcp,
-                        host,
-                        port,
-                    };
-                    if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
-            LocalProtocol::ReverseUdp { timeout } => {
-                let timeout = *timeout;
-
-                spawned_tunnels.push(tokio::spawn(async move {
-                    let cfg = client.config.clone();
-                    let (host, port) = to_host_port(tunnel.local);
-                    let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseUdp { timeout },
-                        host,
-                        port,
-                    };
-                    let udp_connector = UdpTunnelConnector::new(
-                        &tunnel.remote.0,
-                        tunnel.remote.1,
-                        cfg.socket_so_mark,
-                        cfg.timeout_connect,
-                        &cfg.dns_resolver,
-                    );
-
-                    if let Err(err) = client.run_reverse_tunnel(remote.clone(), udp_connector).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
-            LocalProtocol::ReverseSocks5 { timeout, credentials } => {
-                let credentials = credentials.clone();
-                let timeout = *timeout;
-                spawned_tunnels.push(tokio::spawn(async move {
-                    let cfg = client.config.clone();
-                    let (host, port) = to_host_port(tunnel.local);
-                    let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseSocks5 { timeout, credentials },
-                        host,
-                        port,
-                    };
-                    let socks_connector =
-                        Socks5TunnelConnector::new(cfg.socket_so_mark, cfg.timeout_connect, &cfg.dns_resolver);
-
-                    if let Err(err) = client.run_reverse_tunnel(remote, socks_connector).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
-            LocalProtocol::ReverseHttpProxy { timeout, credentials } => {
-                let credentials = credentials.clone();
-                let timeout = *timeout;
-                spawned_tunnels.push(tokio::spawn(async move {
-                    let cfg = client.config.clone();
-                    let (host, port) = to_host_port(tunnel.local);
-                    let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseHttpProxy { timeout, credentials },
-                        host,
-                        port,
-                    };
-                    let tcp_connector = TcpTunnelConnector::new(
-                        &tunnel.remote.0,
-                        tunnel.remote.1,
-                        cfg.socket_so_mark,
-                        cfg.timeout_connect,
-                        &cfg.dns_resolver,
-                    );
-
-                    if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
-                        error!("{:?}", err);
+            LocalProtocol::Stdio { proxy_protocol } => {
+                // Opened only once, it fails instead of waiting for the server up to --connection-retry-max-backoff
+                let mut overrides = tunnel.overrides.clone();
+                overrides
+                    .connection_timeout
+                    .get_or_insert_with(|| client.config.timeout_connect());
+                let mut client = client
+                    .clone()
+                    .with_tunnel_metrics(tunnel_metrics_name(&tunnel))
+                    .with_overrides(&overrides)?;
+                if let Some(keepalive) = args.stdio_keepalive.filter(|keepalive| !keepalive.is_zero()) {
+                    client = client.with_ping_frequency(keepalive);
+                }
+                let (server, mut handle) = new_stdio_listener(tunnel.remote.clone(), *proxy_protocol).await?;
+                let server = with_exec_destination(server, tunnel.exec);
+                let established = Arc::new(AtomicBool::new(false));
+                let on_established = {
+                    let established = established.clone();
+                    move |local_tx, result: Result<_, _>| {
+                        established.store(result.is_ok(), Ordering::Relaxed);
+                        future::ready(Ok(local_tx))
                     }
-                }));
-            }
-            LocalProtocol::ReverseUnix { path } => {
-                let path = path.clone();
-                spawned_tunnels.push(tokio::spawn(async move {
-                    let cfg = client.config.clone();
-                    let tcp_connector = TcpTunnelConnector::new(
-                        &tunnel.remote.0,
-                        tunnel.remote.1,
-                        cfg.socket_so_mark,
-                        cfg.timeout_connect,
-                        &cfg.dns_resolver,
-                    );
-
-                    let (host, port) = to_host_port(tunnel.local);
-                    let remote = RemoteAddr {
-                        protocol: LocalProtocol::ReverseUnix { path },
-                        host,
-                        port,
-                    };
-                    if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
+                };
+                spawn_until_cancelled(&cancel, async move {
+                    if let Err(err) = client.run_tunnel_with_hook(server, on_established).await {
                         error!("{:?}", err);
                     }
-                }));
+                });
+
+                // We need to wait for either a ctrl+c of that the stdio tunnel is closed
+                // to force exit the program
+                let cancelled = select! {
+                   _ = handle.closed() => false,
+                   _ = cancel.cancelled() => true,
+                };
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                exit_summary::report_exit_summary(exit_summary_file.as_deref());
+                tunnels.stop_all().await;
+                // i.e: ssh reports that its ProxyCommand failed, instead of a connection closed by the server
+                let exit_code = if cancelled || established.load(Ordering::Relaxed) {
+                    0
+                } else {
+                    1
+                };
+                return Ok(Some(exit_code));
             }
-            LocalProtocol::Stdio { .. }
-            | LocalProtocol::TProxyTcp
-            | LocalProtocol::TProxyUdp { .. }
-            | LocalProtocol::Tcp { .. }
-            | LocalProtocol::Udp { .. }
-            | LocalProtocol::Socks5 { .. }
-            | LocalProtocol::HttpProxy { .. } => {}
-            LocalProtocol::Unix { .. } => {
-                panic!("Invalid protocol for reverse tunnel");
+            _ => {
+                let local_addr = tunnels
+                    .start(tunnel_metrics_name(&tunnel), tunnel, TunnelOrigin::Args)
+                    .await?;
+                local_addrs.push(local_addr);
             }
         }
     }
+    // The tunnels can change while running, the client only stops when asked to
+    let is_dynamic = args.config.is_some() || args.admin_socket.is_some() || started_tunnels.is_some();
+    if let Some(path) = args.config {
+        tunnels.run_config_file(path).await?;
+    }
 
-    for tunnel in args.local_to_remote.into_iter() {
-        let client = client.clone();
+    // All the local listeners are bound. With --connection-min-idle, the connections to the server are already up
+    HEALTH.set_listening(true);
+    instance.set_status(InstanceStatus::Ready);
 
-        match &tunnel.local_protocol {
-            LocalProtocol::Tcp { proxy_protocol } => {
-                let server = TcpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *proxy_protocol).await?;
-                spawned_tunnels.push(tokio::spawn(async move {
-                    if let Err(err) = client.run_tunnel(server).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
-            #[cfg(target_os = "linux")]
-            LocalProtocol::TProxyTcp => {
-                use crate::tunnel::listeners::TproxyTcpTunnelListener;
-                let server = TproxyTcpTunnelListener::new(tunnel.local, false).await?;
+    let exec = args
+        .exec
+        .map(|command| tokio::spawn(async move { tunnel::client::run_exec_hook(&command, &local_addrs).await }));
+    let exec_exited = async {
+        match exec {
+            Some(exec) if args.exec_exit => exec.await?,
+            _ => future::pending().await,
+        }
+    };
 
-                spawned_tunnels.push(tokio::spawn(async move {
-                    if let Err(err) = client.run_tunnel(server).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
-            #[cfg(unix)]
-            LocalProtocol::Unix { path, proxy_protocol } => {
-                use crate::tunnel::listeners::UnixTunnelListener;
-                let server = UnixTunnelListener::new(path, tunnel.remote.clone(), *proxy_protocol).await?;
-                spawned_tunnels.push(tokio::spawn(async move {
-                    if let Err(err) = client.run_tunnel(server).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
-            #[cfg(not(unix))]
-            LocalProtocol::Unix { .. } => {
-                panic!("Unix socket is not available for non Unix platform")
-            }
+    // wait for all tunnels to complete, or to be asked to stop
+    let mut gave_up = false;
+    let exit_code = select! {
+        _ = tunnels.join_all(), if !is_dynamic => None,
+        exit_code = exec_exited => Some(exit_code?),
+        _ = client.tunnel_gave_up() => {
+            gave_up = true;
+            None
+        },
+        _ = cancel.cancelled() => None,
+    };
+    instance.set_status(InstanceStatus::Stopping);
+    exit_summary::report_exit_summary(exit_summary_file.as_deref());
+    tunnels.stop_all().await;
+    if gave_up {
+        return Err(anyhow!(
+            "A reverse tunnel gave up reconnecting to the server, see --reconnect-give-up"
+        ));
+    }
+    Ok(exit_code)
+}
 
-            #[cfg(target_os = "linux")]
-            LocalProtocol::TProxyUdp { timeout } => {
-                use crate::tunnel::listeners::new_tproxy_udp;
-                let server = new_tproxy_udp(tunnel.local, *timeout).await?;
-                spawned_tunnels.push(tokio::spawn(async move {
-                    if let Err(err) = client.run_tunnel(server).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
-            #[cfg(not(target_os = "linux"))]
-            LocalProtocol::TProxyTcp | LocalProtocol::TProxyUdp { .. } => {
-                panic!("Transparent proxy is not available for non Linux platform")
+/// Ctrl+c, or SIGTERM on unix
+#[cfg(feature = "runtime")]
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = sigterm.recv() => {},
+            },
+            Err(err) => {
+                warn!("Cannot listen for SIGTERM: {}", err);
+                let _ = tokio::signal::ctrl_c().await;
             }
-            LocalProtocol::Udp { timeout } => {
-                let server = UdpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *timeout).await?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
 
-                spawned_tunnels.push(tokio::spawn(async move {
-                    if let Err(err) = client.run_tunnel(server).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
-            LocalProtocol::Socks5 { timeout, credentials } => {
-                let server = Socks5TunnelListener::new(tunnel.local, *timeout, credentials.clone()).await?;
-                spawned_tunnels.push(tokio::spawn(async move {
-                    if let Err(err) = client.run_tunnel(server).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
-            LocalProtocol::HttpProxy {
-                timeout,
-                credentials,
-                proxy_protocol,
-            } => {
-                let server =
-                    HttpProxyTunnelListener::new(tunnel.local, *timeout, credentials.clone(), *proxy_protocol).await?;
-                spawned_tunnels.push(tokio::spawn(async move {
-                    if let Err(err) = client.run_tunnel(server).await {
-                        error!("{:?}", err);
-                    }
-                }));
-            }
+/// Cancel the token on ctrl+c or SIGTERM, unless the client stops by itself before
+#[cfg(feature = "runtime")]
+async fn cancel_on_shutdown_signal(cancel: CancellationToken) {
+    if cancel.run_until_cancelled(shutdown_signal()).await.is_some() {
+        cancel.cancel();
+    }
+}
 
-            LocalProtocol::Stdio { proxy_protocol } => {
-                let (server, mut handle) = new_stdio_listener(tunnel.remote.clone(), *proxy_protocol).await?;
-                tokio::spawn(async move {
-                    if let Err(err) = client.run_tunnel(server).await {
-                        error!("{:?}", err);
-                    }
-                });
+/// Name of a configured tunnel in the metrics, as its -L/-R argument, i.e: L:tcp://127.0.0.1:8080:example.com:80
+#[cfg(feature = "runtime")]
+fn tunnel_metrics_name(tunnel: &LocalToRemote) -> String {
+    let local = tunnel.local.to_string();
+    let (scheme, local) = match &tunnel.local_protocol {
+        LocalProtocol::Tcp { .. } | LocalProtocol::ReverseTcp => ("tcp", local),
+        LocalProtocol::Udp { .. } | LocalProtocol::ReverseUdp { .. } => ("udp", local),
+        LocalProtocol::Socks5 { .. } | LocalProtocol::ReverseSocks5 { .. } => ("socks5", local),
+        LocalProtocol::HttpProxy { .. } | LocalProtocol::ReverseHttpProxy { .. } => ("http", local),
+        LocalProtocol::TProxyTcp => ("tproxy+tcp", local),
+        LocalProtocol::TProxyUdp { .. } => ("tproxy+udp", local),
+        LocalProtocol::Stdio { .. } => ("stdio", String::new()),
+        LocalProtocol::Unix { path, .. } | LocalProtocol::ReverseUnix { path, .. } => {
+            ("unix", path.display().to_string())
+        }
+        LocalProtocol::ReverseHttpIngress { hostname } => ("ingress", hostname.clone()),
+        LocalProtocol::Exec => ("exec", local),
+        LocalProtocol::Tun => ("tun", local),
+        LocalProtocol::TunInterface { address, .. } => ("tun", address.to_string()),
+    };
+    let flag = if tunnel.local_protocol.is_reverse_tunnel() {
+        'R'
+    } else {
+        'L'
+    };
+    format!("{}:{}://{}:{}:{}", flag, scheme, local, tunnel.remote.0, tunnel.remote.1)
+}
 
-                // We need to wait for either a ctrl+c of that the stdio tunnel is closed
-                // to force exit the program
-                select! {
-                   _ = handle.closed() => {},
-                   _ = tokio::signal::ctrl_c() => {}
-                }
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                std::process::exit(0);
+/// Ask the server to run the command named by the remote host (exec://NAME), instead of connecting to the remote
+#[cfg(feature = "runtime")]
+fn with_exec_destination<L: TunnelListener>(
+    listener: L,
+    exec: bool,
+) -> impl TunnelListener<Reader = L::Reader, Writer = L::Writer> {
+    listener.map(move |item| {
+        item.map(|(stream, mut remote)| {
+            if exec {
+                remote.protocol = LocalProtocol::Exec;
             }
-            LocalProtocol::ReverseTcp => {}
-            LocalProtocol::ReverseUdp { .. } => {}
-            LocalProtocol::ReverseSocks5 { .. } => {}
-            LocalProtocol::ReverseUnix { .. } => {}
-            LocalProtocol::ReverseHttpProxy { .. } => {}
-        }
+            (stream, remote)
+        })
+    })
+}
+
+/// Validate a restrictions file and explain whether the server would allow the given tunnel requests
+/// -4 and -6 win over the preferences
+#[cfg(feature = "runtime")]
+const fn ip_family(ipv4_only: bool, ipv6_only: bool, prefer_ipv4: bool, prefer_ipv6: bool) -> IpFamily {
+    match (ipv4_only, ipv6_only, prefer_ipv4, prefer_ipv6) {
+        (true, _, _, _) => IpFamily::Ipv4Only,
+        (_, true, _, _) => IpFamily::Ipv6Only,
+        (_, _, true, _) => IpFamily::PreferIpv4,
+        (_, _, _, true) => IpFamily::PreferIpv6,
+        _ => IpFamily::Any,
     }
+}
 
-    // wait for all tunnels to complete
-    join_all(spawned_tunnels).await;
-    Ok(())
+#[cfg(feature = "runtime")]
+pub fn run_check_restrictions(args: CheckRestrictions) -> anyhow::Result<()> {
+    tunnel::server::check_restrictions(args)
+}
+
+/// Generate a CA, and the certificates of a server and of its mTLS clients signed by it
+#[cfg(feature = "runtime")]
+pub fn run_cert_gen(args: CertGen) -> anyhow::Result<()> {
+    cert_gen::generate_certificates(args)
+}
+
+/// Replace the binary by the latest release of the feed, once its signature is verified
+#[cfg(feature = "runtime")]
+pub async fn run_self_update(args: SelfUpdate) -> anyhow::Result<()> {
+    self_update::update(args).await
 }
 
+#[cfg(feature = "runtime")]
 pub async fn run_server(args: Server) -> anyhow::Result<()> {
+    run_server_with_instance(args, InstanceState::default()).await
+}
+
+/// Same as run_server, the program running it follows the status of the server and can dump its state
+#[cfg(feature = "runtime")]
+pub async fn run_server_with_instance(args: Server, instance: InstanceState) -> anyhow::Result<()> {
+    let extensions = ServerExtensions {
+        instance,
+        ..Default::default()
+    };
+    run_server_until(args, extensions).await
+}
+
+/// Run the server until its cancellation token is cancelled, which stops its listeners and its running tunnels.
+/// The token is cancelled when the server stops by itself too
+#[cfg(feature = "runtime")]
+pub(crate) async fn run_server_until(mut args: Server, extensions: ServerExtensions) -> anyhow::Result<()> {
+    let cancel = extensions.cancel.clone();
+    let _stop_tasks = cancel.clone().drop_guard();
+    INTERNAL_STATE.set_config(&args);
+    // Only used once the server is created from the remaining arguments
+    let check = args.check.is_some();
+    let admin_socket = args.admin_socket.take();
+    let health_bind = args.health_bind;
+    let statsd_exporter = args.statsd_addr.take().map(|addr| StatsdExporter {
+        addr,
+        prefix: std::mem::take(&mut args.statsd_prefix),
+        tags: std::mem::take(&mut args.statsd_tag),
+        interval: args.statsd_interval,
+    });
+
+    let (server, restrictions) = new_server(args, extensions).await?;
+    if check {
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &admin_socket {
+        let listener = protocols::unix_sock::run_server(path, Some(0o600)).await?;
+        spawn_until_cancelled(&cancel, tunnel::server::run_admin_server(listener, server.config.clone()));
+    }
+    #[cfg(not(unix))]
+    if admin_socket.is_some() {
+        return Err(anyhow!("Admin socket is only available on unix platforms"));
+    }
+    if let Some(bind) = health_bind {
+        run_health_server(bind, &cancel).await?;
+    }
+    if let Some(exporter) = statsd_exporter {
+        exporter
+            .run(&cancel, server.config.instance.active_tunnels.clone())
+            .await?;
+    }
+    #[cfg(not(unix))]
+    if server.config.privilege_drop.is_some() {
+        return Err(anyhow!("--user, --group and --chroot are only available on unix platforms"));
+    }
+
+    info!(
+        "Starting wstunnel server v{} with config {:?}",
+        env!("CARGO_PKG_VERSION"),
+        server.config
+    );
+    debug!("Restriction rules: {:#?}", restrictions);
+    let instance = server.config.instance.clone();
+    select! {
+        ret = server.serve(restrictions) => ret.context("Cannot start wstunnel server"),
+        _ = cancel.cancelled() => {
+            instance.set_status(InstanceStatus::Stopping);
+            Ok(())
+        },
+    }
+}
+
+/// Server configured by the arguments, and its restrictions, without its listener
+#[cfg(feature = "runtime")]
+pub(crate) async fn new_server(
+    args: Server,
+    extensions: ServerExtensions,
+) -> anyhow::Result<(WsServer, RestrictionsRules)> {
     let tls_config = if args.remote_addr.scheme() == "wss" {
         let tls_certificate = if let Some(cert_path) = &args.tls_certificate {
-            tls::load_certificates_from_pem(cert_path).expect("Cannot load tls certificate")
+            tls::load_certificates_from_pem(cert_path).context("Cannot load tls certificate")?
         } else {
             embedded_certificate::TLS_CERTIFICATE.0.clone()
         };
+        HEALTH.set_certificate(&tls_certificate);
 
         let tls_key = if let Some(key_path) = &args.tls_private_key {
-            tls::load_private_key_from_file(key_path).expect("Cannot load tls private key")
+            tls::load_private_key_from_file(key_path).context("Cannot load tls private key")?
         } else {
             embedded_certificate::TLS_CERTIFICATE.1.clone_key()
         };
 
-        let tls_client_ca_certificates = args.tls_client_ca_certs.as_ref().map(|tls_client_ca| {
-            Mutex::new(
-                tls::load_certificates_from_pem(tls_client_ca).expect("Cannot load client CA certificate (mTLS)"),
-            )
-        });
+        let tls_client_ca_certificates = args
+            .tls_client_ca_certs
+            .as_ref()
+            .map(|tls_client_ca| {
+                tls::load_certificates_from_pem(tls_client_ca).context("Cannot load client CA certificate (mTLS)")
+            })
+            .transpose()?
+            .map(Mutex::new);
 
         Some(TlsServerConfig {
             tls_certificate: Mutex::new(tls_certificate),
@@ -405,8 +848,10 @@ pub async fn run_server(args: Server) -> anyhow::Result<()> {
         None
     };
 
-    let restrictions = if let Some(path) = &args.restrict_config {
-        RestrictionsRules::from_config_file(path).expect("Cannot parse restriction file")
+    let restrictions = if let Some(restrictions) = extensions.restrictions {
+        restrictions
+    } else if let Some(path) = &args.restrict_config {
+        RestrictionsRules::from_config_file(path).context("Cannot parse restriction file")?
     } else {
         let restrict_to: Vec<(String, u16)> = args
             .restrict_to
@@ -414,23 +859,76 @@ pub async fn run_server(args: Server) -> anyhow::Result<()> {
             .unwrap_or(&[])
             .iter()
             .map(|x| {
-                let (host, port) = x.rsplit_once(':').expect("Invalid restrict-to format");
-                (
-                    host.trim_matches(['[', ']']).to_string(),
-                    port.parse::<u16>().expect("Invalid restrict-to port format"),
-                )
+                let (host, port) = x
+                    .rsplit_once(':')
+                    .ok_or_else(|| anyhow!("Invalid restrict-to format {}", x))?;
+                let port = port
+                    .parse::<u16>()
+                    .with_context(|| format!("Invalid restrict-to port format {}", x))?;
+                Ok((host.trim_matches(['[', ']']).to_string(), port))
             })
-            .collect();
+            .collect::<anyhow::Result<_>>()?;
 
+        let resolve_secrets = |secrets: Option<&[String]>| -> anyhow::Result<Vec<String>> {
+            secrets
+                .unwrap_or(&[])
+                .iter()
+                .map(|secret| resolve_secret(secret))
+                .collect()
+        };
         let restriction_cfg = RestrictionsRules::from_path_prefix(
-            args.restrict_http_upgrade_path_prefix.as_deref().unwrap_or(&[]),
+            &resolve_secrets(args.restrict_http_upgrade_path_prefix.as_deref())?,
+            &resolve_secrets(args.restrict_http_upgrade_path_hmac_secret.as_deref())?,
             &restrict_to,
         )
-        .expect("Cannot convert restriction rules from path-prefix and restric-to");
+        .context("Cannot convert restriction rules from path-prefix and restric-to")?;
         restriction_cfg
     };
 
+    let registries = Arc::new(ServerRegistries::default());
+    if let Some(path) = args.quota_state_file {
+        let quota_store = QuotaStore::open(path, registries.quotas.clone())?;
+        if args.check.is_none() {
+            tokio::spawn(quota_store.run(extensions.cancel.clone()));
+        }
+    }
+
     let http_proxy = mk_http_proxy(args.http_proxy, args.http_proxy_login, args.http_proxy_password)?;
+    let bearer_auth = match (args.jwt_auth_secret, args.jwt_auth_jwks) {
+        (Some(secret), _) => Some(BearerAuth::from_secret(
+            resolve_secret(&secret)?.as_bytes(),
+            args.jwt_auth_audience,
+            args.jwt_auth_issuer,
+        )),
+        (None, Some(jwks)) => Some(BearerAuth::from_jwks(jwks, args.jwt_auth_audience, args.jwt_auth_issuer).await?),
+        (None, None) => None,
+    };
+    let ldap_auth = match (args.ldap_url, args.ldap_bind_dn) {
+        (Some(url), Some(bind_dn)) => {
+            if tls_config.is_none() {
+                warn!("LDAP authentication without TLS, passwords of the clients are sent in clear text");
+            }
+            Some(LdapAuth::new(
+                url,
+                bind_dn,
+                args.ldap_base_dn.unwrap_or_default(),
+                args.ldap_user_attribute,
+            )?)
+        }
+        _ => None,
+    };
+
+    // Created before the privileges are dropped, the interface stays usable without them
+    let packet_network: Option<Arc<dyn PacketNetwork>> = match (extensions.packet_network, args.tun_network) {
+        (Some(network), _) => Some(network),
+        #[cfg(target_os = "linux")]
+        (None, Some(network)) => Some(Arc::new(TunNetwork::create(network, args.tun_mtu).await?)),
+        #[cfg(not(target_os = "linux"))]
+        (None, Some(_)) => return Err(anyhow!("--tun-network is only available on linux")),
+        (None, None) => None,
+    };
+
+    let ip_family = ip_family(args.ipv4_only, args.ipv6_only, args.dns_resolver_prefer_ipv4, args.prefer_ipv6);
     let server_config = WsServerConfig {
         socket_so_mark: SoMark::new(args.socket_so_mark),
         bind: args.remote_addr.socket_addrs(|| Some(8080))?[0],
@@ -438,34 +936,115 @@ pub async fn run_server(args: Server) -> anyhow::Result<()> {
             .websocket_ping_frequency
             .or(Some(Duration::from_secs(30)))
             .filter(|d| d.as_secs() > 0),
+        slow_consumer_timeout: args
+            .slow_consumer_timeout
+            .or(Some(Duration::from_secs(10)))
+            .filter(|d| !d.is_zero()),
         timeout_connect: Duration::from_secs(10),
         websocket_mask_frame: args.websocket_mask_frame,
         tls: tls_config,
-        dns_resolver: DnsResolver::new_from_urls(
-            &args.dns_resolver,
-            None,
-            SoMark::new(args.socket_so_mark),
-            !args.dns_resolver_prefer_ipv4,
-        )
-        .expect("Cannot create DNS resolver"),
+        dns_resolver: match extensions.dns_resolver {
+            Some(resolver) => DnsResolver::custom(resolver, ip_family),
+            None => DnsResolver::new_from_urls(&args.dns_resolver, None, SoMark::new(args.socket_so_mark), ip_family)
+                .context("Cannot create DNS resolver")?,
+        },
         restriction_config: args.restrict_config,
+        deny_private_destinations: args.deny_private_destinations,
+        allow_from: args.allow_from,
+        deny_from: args.deny_from,
+        trusted_proxies: args.trusted_proxy,
         http_proxy,
         remote_server_idle_timeout: args.remote_to_local_server_idle_timeout,
+        remote_server_no_connection_timeout: args.remote_to_local_server_no_connection_timeout,
+        remote_server_max_lifetime: args.remote_to_local_server_max_lifetime,
+        exec_commands: args.exec_command.into_iter().collect(),
+        bench_endpoint: args.bench_endpoint,
+        http_ingress: args.http_ingress,
+        bearer_auth,
+        ldap_auth,
+        revocation_list: match &args.revocation_list {
+            Some(source) => Some(RevocationList::new(source, &extensions.cancel).await?),
+            None => None,
+        },
+        totp_verifier: args.totp_secrets.as_deref().map(TotpVerifier::from_file).transpose()?,
+        ban_policy: args
+            .ban_after_failures
+            .filter(|max| *max > 0)
+            .map(|max_failures| BanPolicy {
+                max_failures,
+                find_time: args.ban_find_time,
+                ban_time: args.ban_time,
+            }),
+        audit_log: args.audit_log.as_deref().map(AuditLog::open).transpose()?.map(Arc::new),
+        access_log: (args.access_log || args.access_log_file.is_some())
+            .then(|| HttpAccessLog::new(args.access_log_file.as_deref(), args.access_log_format))
+            .transpose()?
+            .map(Arc::new),
+        rate_limiter: args
+            .rate_limit_per_ip
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| RateLimiter::new(rate, args.rate_limit_burst)),
+        reuse_port: args.reuse_port,
+        accept_loops: args.accept_loops,
+        spa_gate: match (args.spa_bind, &args.spa_secret) {
+            (Some(bind), Some(secret)) => Some(Arc::new(SpaGate::new(
+                bind,
+                resolve_secret(secret)?.as_bytes(),
+                args.spa_window,
+            ))),
+            _ => None,
+        },
+        auth_webhook: args.auth_webhook.map(|url| {
+            AuthWebhook::new(
+                url,
+                args.auth_webhook_timeout,
+                args.auth_webhook_cache_ttl,
+                args.auth_webhook_fail_open,
+            )
+        }),
+        event_handler: extensions.event_handler,
+        transport: extensions.transport,
+        interceptors: extensions.interceptors,
+        packet_network,
+        restrict_syscalls: args.sandbox && !args.sandbox_without_seccomp,
+        privilege_drop: (args.user.is_some() || args.group.is_some() || args.chroot.is_some()).then_some(
+            PrivilegeDrop {
+                user: args.user,
+                group: args.group,
+                chroot: args.chroot,
+            },
+        ),
+        cancel: extensions.cancel,
+        events: extensions.events,
+        instance: extensions.instance,
+        registries,
+        capture: open_capture_file(args.capture_file.as_deref().filter(|_| args.check.is_none()))?,
+        geoip: Arc::new(GeoIpDatabases::load(&args.geoip_database)?),
     };
-    let server = WsServer::new(server_config);
+    if let Some(url) = args.event_webhook {
+        EventWebhook::new(url, args.event_webhook_retries, &server_config.cancel).subscribe(&server_config.events);
+    }
+    for restriction in &restrictions.restrictions {
+        server_config
+            .interceptors
+            .select(&restriction.interceptors)
+            .with_context(|| format!("Invalid restriction {}", restriction.name))?;
+    }
+    Ok((WsServer::new(server_config), restrictions))
+}
 
-    info!(
-        "Starting wstunnel server v{} with config {:?}",
-        env!("CARGO_PKG_VERSION"),
-        server.config
-    );
-    debug!("Restriction rules: {:#?}", restrictions);
-    server.serve(restrictions).await.unwrap_or_else(|err| {
-        panic!("Cannot start wstunnel server: {:?}", err);
-    });
-    Ok(())
+/// Capture of the plaintext of the tunnels, with --capture-file
+#[cfg(feature = "runtime")]
+fn open_capture_file(path: Option<&Path>) -> anyhow::Result<Option<Arc<CaptureFile>>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let capture = CaptureFile::create(path)?;
+    warn!("Writing the plaintext of the tunnels to {}", path.display());
+    Ok(Some(Arc::new(capture)))
 }
 
+#[cfg(feature = "runtime")]
 fn mk_http_proxy(
     http_proxy: Option<String>,
     proxy_login: Option<String>,
@@ -474,6 +1053,7 @@ fn mk_http_proxy(
     let Some(proxy) = http_proxy else {
         return Ok(None);
     };
+    let proxy = resolve_secret(&proxy)?;
 
     let mut proxy = if proxy.starts_with("http://") {
         Url::parse(&proxy).with_context(|| "Invalid http proxy url")?
@@ -482,12 +1062,14 @@ fn mk_http_proxy(
     };
 
     if let Some(login) = proxy_login {
+        let login = resolve_secret(&login)?;
         proxy
             .set_username(login.as_str())
             .map_err(|_| anyhow!("Cannot set http proxy login"))?;
     }
 
     if let Some(password) = proxy_password {
+        let password = resolve_secret(&password)?;
         proxy
             .set_password(Some(password.as_str()))
             .map_err(|_| anyhow!("Cannot set http proxy password"))?;
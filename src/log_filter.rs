@@ -0,0 +1,29 @@
+use parking_lot::Mutex;
+use std::sync::LazyLock;
+
+type Reloader = Box<dyn Fn(&str) -> anyhow::Result<()> + Send + Sync>;
+
+/// The tracing subscriber is owned by the binary, which registers how to change its filter
+static LOG_FILTER: LazyLock<Mutex<Option<(String, Reloader)>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Allow the log filter to be changed at runtime (i.e: with the log-level admin command), with `filter` the current one
+pub fn set_log_filter_reloader(filter: String, reloader: impl Fn(&str) -> anyhow::Result<()> + Send + Sync + 'static) {
+    *LOG_FILTER.lock() = Some((filter, Box::new(reloader)));
+}
+
+/// Current log filter, if it can be changed
+pub fn log_filter() -> Option<String> {
+    LOG_FILTER.lock().as_ref().map(|(filter, _)| filter.clone())
+}
+
+/// Replace the log filter, i.e: 'info,wstunnel::protocols::udp=trace'
+pub fn reload_log_filter(filter: &str) -> anyhow::Result<()> {
+    let mut log_filter = LOG_FILTER.lock();
+    let Some((current, reloader)) = log_filter.as_mut() else {
+        return Err(anyhow::anyhow!("The log filter cannot be changed at runtime"));
+    };
+    reloader(filter)?;
+    *current = filter.to_string();
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! --profile of the client: the options recommended for a common use case, given as the defaults of the arguments
+//! so each of them can still be set
+
+use crate::config::LocalToRemote;
+use crate::tunnel::LocalProtocol;
+use std::time::Duration;
+
+/// Default timeout of the udp tunnels, when they are given without timeout_sec
+const DEFAULT_UDP_TIMEOUT: Option<Duration> = Some(Duration::from_secs(30));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Profile {
+    /// WireGuard over -L udp: the udp tunnels never time out, and the pings keep the NATs on the way open
+    Wireguard,
+    /// ssh through -L tcp or stdio: the server is checked at startup, and the pings keep the idle sessions open
+    SshJump,
+    /// Browser through -L socks5 or http: many short tunnels, served by connections kept ready
+    BrowserSocks,
+    /// Throughput measures with iperf3: no pings nor slow consumer checks in the way of the transfers
+    Iperf,
+}
+
+impl Profile {
+    /// Arguments of the client, by their id, with the default value the profile gives them
+    pub fn defaults(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::Wireguard => &[("websocket_ping_frequency", "20s"), ("connect_mode", "eager")],
+            Self::SshJump => &[("websocket_ping_frequency", "15s"), ("connect_mode", "startup")],
+            Self::BrowserSocks => &[("connection_min_idle", "4"), ("connect_mode", "eager")],
+            Self::Iperf => &[
+                ("websocket_ping_frequency", "0s"),
+                ("slow_consumer_timeout", "0s"),
+                ("connection_min_idle", "2"),
+            ],
+        }
+    }
+
+    /// Set the profile's defaults on the arguments of the client or bench subcommand
+    #[cfg(feature = "clap")]
+    pub fn apply_defaults(self, mut command: clap::Command) -> clap::Command {
+        for (id, value) in self.defaults() {
+            command = command.mut_arg(*id, |arg| arg.default_value(*value));
+        }
+        command
+    }
+
+    /// Timeout of the udp tunnels given without timeout_sec
+    fn udp_timeout(self) -> Option<Duration> {
+        match self {
+            Self::Wireguard => None,
+            Self::SshJump | Self::BrowserSocks | Self::Iperf => DEFAULT_UDP_TIMEOUT,
+        }
+    }
+
+    /// Apply the profile to the -L udp tunnels left with the default timeout
+    pub fn apply_to_tunnels(self, tunnels: &mut [LocalToRemote]) {
+        for tunnel in tunnels {
+            if let LocalProtocol::Udp { timeout } = &mut tunnel.local_protocol {
+                if *timeout == DEFAULT_UDP_TIMEOUT {
+                    *timeout = self.udp_timeout();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parsers::parse_tunnel_arg;
+    use test_case::test_case;
+
+    #[test_case(Profile::Wireguard, "udp://51820:wg.example.com:51820" => None ; "wireguard")]
+    #[test_case(Profile::Wireguard, "udp://51820:wg.example.com:51820?timeout_sec=60" => Some(Duration::from_secs(60)) ; "explicit timeout")]
+    #[test_case(Profile::Iperf, "udp://5201:localhost:5201" => DEFAULT_UDP_TIMEOUT ; "iperf")]
+    fn test_apply_to_tunnels(profile: Profile, tunnel: &str) -> Option<Duration> {
+        let mut tunnels = [parse_tunnel_arg(tunnel).unwrap()];
+        profile.apply_to_tunnels(&mut tunnels);
+        match tunnels[0].local_protocol {
+            LocalProtocol::Udp { timeout } => timeout,
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(feature = "clap")]
+    #[test]
+    fn test_apply_defaults() {
+        use crate::config::Client;
+        use clap::{Args, Command, FromArgMatches};
+
+        let command = Profile::Iperf.apply_defaults(Client::augment_args(Command::new("client")));
+        let matches = command
+            .try_get_matches_from(["client", "--slow-consumer-timeout", "5s", "ws://localhost:8080"])
+            .unwrap();
+        let client = Client::from_arg_matches(&matches).unwrap();
+        assert_eq!(client.websocket_ping_frequency, Some(Duration::ZERO));
+        assert_eq!(client.slow_consumer_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(client.connection_min_idle, 2);
+    }
+}
@@ -0,0 +1,99 @@
+//! Types of the protocol between the client and the server, shared with the clients built without the runtime,
+//! i.e: compiled to wasm32 for the browsers
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Websocket protocol of the upgrade request carrying the jwt of the tunnel, after this prefix
+pub static JWT_HEADER_PREFIX: &str = "authorization.bearer.";
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum LocalProtocol {
+    Tcp {
+        proxy_protocol: bool,
+    },
+    Udp {
+        timeout: Option<Duration>,
+    },
+    Stdio {
+        proxy_protocol: bool,
+    },
+    Socks5 {
+        timeout: Option<Duration>,
+        credentials: Option<(String, String)>,
+        gssapi: Option<String>,
+    },
+    TProxyTcp,
+    TProxyUdp {
+        timeout: Option<Duration>,
+    },
+    HttpProxy {
+        timeout: Option<Duration>,
+        credentials: Option<(String, String)>,
+        proxy_protocol: bool,
+        htpasswd: Option<PathBuf>,
+        acl: Option<PathBuf>,
+    },
+    ReverseTcp,
+    ReverseUdp {
+        timeout: Option<Duration>,
+    },
+    ReverseSocks5 {
+        timeout: Option<Duration>,
+        credentials: Option<(String, String)>,
+    },
+    ReverseHttpProxy {
+        timeout: Option<Duration>,
+        credentials: Option<(String, String)>,
+    },
+    ReverseUnix {
+        path: PathBuf,
+        mode: Option<u32>,
+    },
+    Unix {
+        path: PathBuf,
+        proxy_protocol: bool,
+        mode: Option<u32>,
+    },
+    Exec,
+    ReverseHttpIngress {
+        hostname: String,
+    },
+    /// Ip packets of the client using the address of the remote host
+    Tun,
+    /// Local tun interface with this address, its packets are sent in a Tun tunnel
+    TunInterface {
+        address: IpNet,
+        mtu: u16,
+        routes: Vec<IpNet>,
+    },
+}
+
+impl LocalProtocol {
+    pub const fn is_reverse_tunnel(&self) -> bool {
+        matches!(
+            self,
+            Self::ReverseTcp
+                | Self::ReverseUdp { .. }
+                | Self::ReverseSocks5 { .. }
+                | Self::ReverseUnix { .. }
+                | Self::ReverseHttpProxy { .. }
+                | Self::ReverseHttpIngress { .. }
+        )
+    }
+
+    pub const fn is_dynamic_reverse_tunnel(&self) -> bool {
+        matches!(self, Self::ReverseSocks5 { .. } | Self::ReverseHttpProxy { .. })
+    }
+}
+
+/// Claims of the jwt of the upgrade request, its signature is not checked by the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JwtTunnelConfig {
+    pub id: String,       // tunnel id
+    pub p: LocalProtocol, // protocol to use
+    pub r: String,        // remote host
+    pub rp: u16,          // remote port
+}
@@ -0,0 +1,99 @@
+use crate::tunnel::tunnel_metrics::{Histogram, HistogramSnapshot, LATENCY_MS_BOUNDS};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use serde::Serialize;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Resolutions of the hostnames of the server and of the destinations, by their outcome
+pub static DNS_METRICS: LazyLock<DnsMetrics> = LazyLock::new(DnsMetrics::default);
+
+pub struct DnsMetrics {
+    duration_ms: Histogram,
+    resolved: AtomicU64,
+    not_found: AtomicU64,
+    timeout: AtomicU64,
+    error: AtomicU64,
+}
+
+impl Default for DnsMetrics {
+    fn default() -> Self {
+        Self {
+            duration_ms: Histogram::new(LATENCY_MS_BOUNDS),
+            resolved: AtomicU64::new(0),
+            not_found: AtomicU64::new(0),
+            timeout: AtomicU64::new(0),
+            error: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DnsMetricsInfo {
+    pub resolved: u64,
+    /// The domain does not exist, or has no address
+    pub not_found: u64,
+    pub timeout: u64,
+    pub error: u64,
+    pub duration_ms: HistogramSnapshot,
+}
+
+impl DnsMetrics {
+    pub(crate) fn observe(&self, ret: &anyhow::Result<Vec<SocketAddr>>, duration: Duration) {
+        self.duration_ms.observe(duration.as_millis() as u64);
+        let outcome = match ret {
+            Ok(addrs) if addrs.is_empty() => &self.not_found,
+            Ok(_) => &self.resolved,
+            Err(err) => match err.downcast_ref::<ResolveError>().map(|err| err.kind()) {
+                Some(ResolveErrorKind::NoRecordsFound { .. }) => &self.not_found,
+                Some(ResolveErrorKind::Timeout) => &self.timeout,
+                _ if err
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|err| err.kind() == io::ErrorKind::TimedOut) =>
+                {
+                    &self.timeout
+                }
+                _ => &self.error,
+            },
+        };
+        outcome.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> DnsMetricsInfo {
+        DnsMetricsInfo {
+            resolved: self.resolved.load(Ordering::Relaxed),
+            not_found: self.not_found.load(Ordering::Relaxed),
+            timeout: self.timeout.load(Ordering::Relaxed),
+            error: self.error.load(Ordering::Relaxed),
+            duration_ms: self.duration_ms.snapshot(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_observe() {
+        let metrics = DnsMetrics::default();
+        metrics.observe(&Ok(vec!["127.0.0.1:80".parse().unwrap()]), Duration::from_millis(3));
+        metrics.observe(&Ok(vec![]), Duration::from_millis(20));
+        metrics.observe(
+            &Err(anyhow!(ResolveError::from(ResolveErrorKind::Timeout))),
+            Duration::from_secs(5),
+        );
+        metrics.observe(&Err(anyhow!("failed to lookup address information")), Duration::from_millis(1));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            (snapshot.resolved, snapshot.not_found, snapshot.timeout, snapshot.error),
+            (1, 1, 1, 1)
+        );
+        assert_eq!(snapshot.duration_ms.count, 4);
+        assert_eq!(snapshot.duration_ms.max, 5000);
+    }
+}
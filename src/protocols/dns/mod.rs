@@ -1,3 +1,5 @@
+pub(crate) mod metrics;
 mod resolver;
 
-pub use resolver::DnsResolver;
+pub use metrics::{DnsMetricsInfo, DNS_METRICS};
+pub use resolver::{DnsLookupError, DnsResolver, HostResolver, IpFamily};
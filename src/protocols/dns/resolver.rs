@@ -1,6 +1,9 @@
 use crate::protocols;
+use crate::protocols::dns::DNS_METRICS;
 use crate::somark::SoMark;
+use ahash::AHashMap;
 use anyhow::{anyhow, Context};
+use futures_util::future::BoxFuture;
 use futures_util::{FutureExt, TryFutureExt};
 use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
 use hickory_resolver::name_server::{GenericConnector, RuntimeProvider, TokioRuntimeProvider};
@@ -8,11 +11,12 @@ use hickory_resolver::proto::iocompat::AsyncIoTokioAsStd;
 use hickory_resolver::proto::TokioTime;
 use hickory_resolver::{AsyncResolver, TokioHandle};
 use log::warn;
+use std::fmt;
 use std::future::Future;
 use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpStream, UdpSocket};
 use url::{Host, Url};
 
@@ -33,49 +37,205 @@ fn sort_socket_addrs(socket_addrs: &[SocketAddr], prefer_ipv6: bool) -> impl Ite
     })
 }
 
+/// Address families of the resolutions, and the one tried first when both are allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpFamily {
+    /// IPv6 first, except for the system resolver which keeps the order of libc
+    #[default]
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl IpFamily {
+    const fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Ipv4Only => addr.is_ipv4(),
+            Self::Ipv6Only => addr.is_ipv6(),
+            Self::Any | Self::PreferIpv4 | Self::PreferIpv6 => true,
+        }
+    }
+
+    /// Filter and order the addresses of a resolution
+    fn apply(self, mut addrs: Vec<SocketAddr>, sort_by_default: bool) -> Vec<SocketAddr> {
+        addrs.retain(|addr| self.allows(addr));
+        match self {
+            Self::PreferIpv4 => sort_socket_addrs(&addrs, false).copied().collect(),
+            Self::PreferIpv6 => sort_socket_addrs(&addrs, true).copied().collect(),
+            Self::Any if sort_by_default => sort_socket_addrs(&addrs, true).copied().collect(),
+            Self::Any | Self::Ipv4Only | Self::Ipv6Only => addrs,
+        }
+    }
+
+    const fn lookup_ip_strategy(self) -> LookupIpStrategy {
+        match self {
+            Self::Ipv4Only => LookupIpStrategy::Ipv4Only,
+            Self::Ipv6Only => LookupIpStrategy::Ipv6Only,
+            Self::Any | Self::PreferIpv4 | Self::PreferIpv6 => LookupIpStrategy::Ipv4AndIpv6,
+        }
+    }
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ipv4Only => f.write_str("IPv4"),
+            Self::Ipv6Only => f.write_str("IPv6"),
+            Self::Any | Self::PreferIpv4 | Self::PreferIpv6 => f.write_str("IP"),
+        }
+    }
+}
+
+/// Resolution of the hostnames by a program embedding wstunnel, i.e: from its own service discovery, instead of the
+/// resolvers of --dns-resolver
+pub trait HostResolver: Send + Sync + 'static {
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> BoxFuture<'a, anyhow::Result<Vec<SocketAddr>>>;
+}
+
 #[derive(Clone)]
 pub enum DnsResolver {
-    System,
+    System {
+        ip_family: IpFamily,
+    },
     TrustDns {
         resolver: AsyncResolver<GenericConnector<TokioRuntimeProviderWithSoMark>>,
-        prefer_ipv6: bool,
+        ip_family: IpFamily,
+    },
+    /// Names of a hosts file, the other ones are resolved by the next resolver
+    Hosts {
+        hosts: Arc<AHashMap<String, Vec<IpAddr>>>,
+        next: Box<DnsResolver>,
     },
+    Custom {
+        resolver: Arc<dyn HostResolver>,
+        ip_family: IpFamily,
+    },
+}
+
+/// libc resolver, with the addresses in its order
+impl Default for DnsResolver {
+    fn default() -> Self {
+        Self::System {
+            ip_family: IpFamily::Any,
+        }
+    }
+}
+
+/// Context of the errors of the resolutions, to tell them apart from the errors of the connections
+#[derive(Debug)]
+pub struct DnsLookupError;
+
+impl fmt::Display for DnsLookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("dns lookup failed")
+    }
 }
 
 impl DnsResolver {
     pub async fn lookup_host(&self, domain: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
-        let addrs: Vec<SocketAddr> = match self {
-            Self::System => tokio::net::lookup_host(format!("{}:{}", domain, port)).await?.collect(),
-            Self::TrustDns { resolver, prefer_ipv6 } => {
-                let addrs: Vec<_> = resolver
-                    .lookup_ip(domain)
-                    .await?
-                    .into_iter()
-                    .map(|ip| match ip {
-                        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
-                        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
-                    })
-                    .collect();
-                sort_socket_addrs(&addrs, *prefer_ipv6).copied().collect()
+        let started_at = Instant::now();
+        let ret = self.resolve(domain, port).await;
+        DNS_METRICS.observe(&ret, started_at.elapsed());
+        ret.context(DnsLookupError)
+    }
+
+    // Boxed as the hosts file falls back to the next resolver
+    fn resolve<'a>(&'a self, domain: &'a str, port: u16) -> BoxFuture<'a, anyhow::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let (addrs, ip_family) = match self {
+                Self::System { ip_family } => {
+                    let addrs = tokio::net::lookup_host(format!("{}:{}", domain, port)).await?.collect();
+                    (ip_family.apply(addrs, false), *ip_family)
+                }
+                Self::TrustDns { resolver, ip_family } => {
+                    let addrs = resolver
+                        .lookup_ip(domain)
+                        .await?
+                        .into_iter()
+                        .map(|ip| match ip {
+                            IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+                            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+                        })
+                        .collect();
+                    (ip_family.apply(addrs, true), *ip_family)
+                }
+                Self::Hosts { hosts, next } => match hosts.get(&domain.to_ascii_lowercase()) {
+                    Some(ips) => {
+                        let addrs = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+                        (next.ip_family().apply(addrs, false), next.ip_family())
+                    }
+                    None => return next.resolve(domain, port).await,
+                },
+                Self::Custom { resolver, ip_family } => {
+                    let addrs = resolver.resolve(domain, port).await?;
+                    (ip_family.apply(addrs, false), *ip_family)
+                }
+            };
+
+            if addrs.is_empty() {
+                return Err(anyhow!("No {} address found for {}", ip_family, domain));
             }
-        };
+            Ok(addrs)
+        })
+    }
+
+    fn ip_family(&self) -> IpFamily {
+        match self {
+            Self::System { ip_family } | Self::TrustDns { ip_family, .. } | Self::Custom { ip_family, .. } => {
+                *ip_family
+            }
+            Self::Hosts { next, .. } => next.ip_family(),
+        }
+    }
 
-        Ok(addrs)
+    /// Resolver of a program embedding wstunnel
+    pub fn custom(resolver: Arc<dyn HostResolver>, ip_family: IpFamily) -> Self {
+        Self::Custom { resolver, ip_family }
     }
 
+    /// The hosts files of the urls are looked up first, then the other resolvers
     pub fn new_from_urls(
         resolvers: &[Url],
         proxy: Option<Url>,
         so_mark: SoMark,
-        prefer_ipv6: bool,
+        ip_family: IpFamily,
+    ) -> anyhow::Result<Self> {
+        let (hosts_files, resolvers): (Vec<_>, Vec<_>) = resolvers.iter().cloned().partition(|r| r.scheme() == "hosts");
+        let resolver = Self::new_from_dns_urls(&resolvers, proxy, so_mark, ip_family)?;
+        if hosts_files.is_empty() {
+            return Ok(resolver);
+        }
+
+        let mut hosts = AHashMap::new();
+        for url in &hosts_files {
+            let content = std::fs::read_to_string(url.path())
+                .with_context(|| format!("Cannot read the hosts file {}", url.path()))?;
+            for (name, ip) in parse_hosts(&content) {
+                hosts.entry(name).or_insert_with(Vec::new).push(ip);
+            }
+        }
+        Ok(Self::Hosts {
+            hosts: Arc::new(hosts),
+            next: Box::new(resolver),
+        })
+    }
+
+    fn new_from_dns_urls(
+        resolvers: &[Url],
+        proxy: Option<Url>,
+        so_mark: SoMark,
+        ip_family: IpFamily,
     ) -> anyhow::Result<Self> {
         fn mk_resolver(
             cfg: ResolverConfig,
             mut opts: ResolverOpts,
             proxy: Option<Url>,
             so_mark: SoMark,
+            ip_family: IpFamily,
         ) -> AsyncResolver<GenericConnector<TokioRuntimeProviderWithSoMark>> {
-            opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+            opts.ip_strategy = ip_family.lookup_ip_strategy();
             opts.timeout = Duration::from_secs(1);
 
             // Windows end-up with too many dns resolvers, which causes a performance issue
@@ -134,18 +294,18 @@ impl DnsResolver {
         if resolvers.is_empty() {
             let Ok((cfg, opts)) = hickory_resolver::system_conf::read_system_conf() else {
                 warn!("Fall-backing to system dns resolver. You should consider specifying a dns resolver. To avoid performance issue");
-                return Ok(Self::System);
+                return Ok(Self::System { ip_family });
             };
 
             return Ok(Self::TrustDns {
-                resolver: mk_resolver(cfg, opts, proxy, so_mark),
-                prefer_ipv6,
+                resolver: mk_resolver(cfg, opts, proxy, so_mark, ip_family),
+                ip_family,
             });
         };
 
         // if one is specified as system, use the default one from libc
         if resolvers.iter().any(|r| r.scheme() == "system") {
-            return Ok(Self::System);
+            return Ok(Self::System { ip_family });
         }
 
         // otherwise, use the specified resolvers
@@ -155,12 +315,22 @@ impl DnsResolver {
         }
 
         Ok(Self::TrustDns {
-            resolver: mk_resolver(cfg, ResolverOpts::default(), proxy, so_mark),
-            prefer_ipv6,
+            resolver: mk_resolver(cfg, ResolverOpts::default(), proxy, so_mark, ip_family),
+            ip_family,
         })
     }
 }
 
+/// Names and addresses of a file in the format of /etc/hosts: an address then its names on each line, # comments
+fn parse_hosts(content: &str) -> impl Iterator<Item = (String, IpAddr)> + '_ {
+    content.lines().flat_map(|line| {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let ip = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+        fields.filter_map(move |name| Some((name.to_ascii_lowercase(), ip?)))
+    })
+}
+
 #[derive(Clone)]
 pub struct TokioRuntimeProviderWithSoMark {
     runtime: TokioRuntimeProvider,
@@ -206,7 +376,7 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
                     server_addr.port(),
                     so_mark,
                     Duration::from_secs(10),
-                    &DnsResolver::System, // not going to be used as host is directly an ip address
+                    &DnsResolver::default(), // not going to be used as host is directly an ip address
                 )
                 .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
                 .map(|s| s.map(AsyncIoTokioAsStd))
@@ -217,7 +387,7 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
                     server_addr.port(),
                     so_mark,
                     Duration::from_secs(10),
-                    &DnsResolver::System, // not going to be used as host is directly an ip address
+                    &DnsResolver::default(), // not going to be used as host is directly an ip address
                 )
                 .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
                 .map(|s| s.map(AsyncIoTokioAsStd))
@@ -233,20 +403,15 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
         local_addr: SocketAddr,
         _server_addr: SocketAddr,
     ) -> Pin<Box<dyn Send + Future<Output = std::io::Result<Self::Udp>>>> {
-        let socket = UdpSocket::bind(local_addr);
-
-        #[cfg(target_os = "linux")]
-        let socket = {
-            socket.map({
-                let so_mark = self.so_mark;
-                move |sock| {
-                    if let Ok(ref sock) = sock {
-                        so_mark.set_mark(socket2::SockRef::from(sock))?;
-                    }
-                    sock
+        let socket = UdpSocket::bind(local_addr).map({
+            let so_mark = self.so_mark;
+            move |sock| {
+                if let Ok(ref sock) = sock {
+                    so_mark.apply(socket2::SockRef::from(sock))?;
                 }
-            })
-        };
+                sock
+            }
+        });
 
         Box::pin(socket)
     }
@@ -256,6 +421,7 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
 mod tests {
     use super::*;
     use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use test_case::test_case;
 
     #[test]
     fn test_sort_socket_addrs() {
@@ -276,4 +442,67 @@ mod tests {
         let actual: Vec<_> = sort_socket_addrs(&addrs, true).copied().collect();
         assert_eq!(expected, *actual);
     }
+
+    #[test_case(IpFamily::Any, false => vec!["127.0.0.1:1", "[::1]:1", "127.0.0.2:1"] ; "system order")]
+    #[test_case(IpFamily::Any, true => vec!["[::1]:1", "127.0.0.1:1", "127.0.0.2:1"] ; "ipv6 first by default")]
+    #[test_case(IpFamily::PreferIpv4, true => vec!["127.0.0.1:1", "[::1]:1", "127.0.0.2:1"] ; "prefer ipv4")]
+    #[test_case(IpFamily::PreferIpv6, false => vec!["[::1]:1", "127.0.0.1:1", "127.0.0.2:1"] ; "prefer ipv6")]
+    #[test_case(IpFamily::Ipv4Only, true => vec!["127.0.0.1:1", "127.0.0.2:1"] ; "ipv4 only")]
+    #[test_case(IpFamily::Ipv6Only, false => vec!["[::1]:1"] ; "ipv6 only")]
+    fn test_ip_family(ip_family: IpFamily, sort_by_default: bool) -> Vec<String> {
+        let addrs = ["127.0.0.1:1", "[::1]:1", "127.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        ip_family
+            .apply(addrs, sort_by_default)
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_hosts() {
+        let hosts: Vec<_> = parse_hosts("# comment\n10.0.0.1 db.internal DB2 # primary\n\nnot-an-ip name\n::1 v6\n")
+            .map(|(name, ip)| format!("{name}={ip}"))
+            .collect();
+        assert_eq!(hosts, vec!["db.internal=10.0.0.1", "db2=10.0.0.1", "v6=::1"]);
+    }
+
+    struct FixedResolver;
+
+    impl HostResolver for FixedResolver {
+        fn resolve<'a>(&'a self, _domain: &'a str, port: u16) -> BoxFuture<'a, anyhow::Result<Vec<SocketAddr>>> {
+            Box::pin(async move {
+                Ok(vec![
+                    SocketAddr::from(([192, 0, 2, 1], port)),
+                    SocketAddr::from(([0; 16], port)),
+                ])
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hosts_before_the_next_resolver() {
+        let resolver = DnsResolver::Hosts {
+            hosts: Arc::new(AHashMap::from_iter([(
+                "db.internal".to_string(),
+                vec!["10.0.0.1".parse().unwrap()],
+            )])),
+            next: Box::new(DnsResolver::custom(Arc::new(FixedResolver), IpFamily::Ipv4Only)),
+        };
+        let addrs = resolver.lookup_host("DB.internal", 5432).await.unwrap();
+        assert_eq!(addrs, vec!["10.0.0.1:5432".parse::<SocketAddr>().unwrap()]);
+        let addrs = resolver.lookup_host("example.com", 443).await.unwrap();
+        assert_eq!(addrs, vec!["192.0.2.1:443".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_no_address_of_the_family() {
+        let resolver = DnsResolver::System {
+            ip_family: IpFamily::Ipv6Only,
+        };
+        let err = resolver.lookup_host("127.0.0.1", 1).await.unwrap_err();
+        assert_eq!(format!("{:#}", err), "dns lookup failed: No IPv6 address found for 127.0.0.1");
+    }
 }
@@ -0,0 +1,77 @@
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tracing::{info, warn};
+
+/// Stdout of a spawned command. The command is killed when it is dropped
+pub struct CommandStdout {
+    stdout: ChildStdout,
+    _child: Child,
+}
+
+impl AsyncRead for CommandStdout {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+/// Command running the given command line with the shell of the platform
+pub fn shell_command(command: &str) -> Command {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    cmd.arg(command);
+    cmd
+}
+
+/// Spawn the command with the shell of the platform, and return its stdout and stdin.
+/// What the command writes on stderr is forwarded to the logs
+pub fn spawn_command(name: &str, command: &str) -> anyhow::Result<(CommandStdout, ChildStdin)> {
+    info!("Spawning command {}: {}", name, command);
+    let mut child = shell_command(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let (Some(stdin), Some(stdout), Some(stderr)) = (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        return Err(anyhow::anyhow!("Cannot get stdio of command {}", name));
+    };
+
+    let name = name.to_string();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            warn!("command {}: {}", name, line);
+        }
+    });
+
+    Ok((CommandStdout { stdout, _child: child }, stdin))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_command_is_piped() {
+        let (mut stdout, mut stdin) = spawn_command("cat", "cat").unwrap();
+        stdin.write_all(b"hello").await.unwrap();
+        drop(stdin);
+
+        let mut buf = String::new();
+        stdout.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "hello");
+    }
+}
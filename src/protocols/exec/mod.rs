@@ -0,0 +1,4 @@
+mod command;
+
+pub use command::shell_command;
+pub use command::spawn_command;
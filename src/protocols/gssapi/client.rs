@@ -0,0 +1,100 @@
+use super::ffi::{
+    is_error, oid, GssApi, GssBuffer, GssCtx, GssName, OmUint32, GSS_C_MUTUAL_FLAG, GSS_C_NT_HOSTBASED_SERVICE,
+    GSS_MECH_SPNEGO, GSS_S_COMPLETE,
+};
+use anyhow::anyhow;
+use std::ptr;
+
+/// Security context of the initiator side, authenticating with the kerberos credentials of the current user
+/// (i.e: obtained with kinit)
+pub struct GssClientContext {
+    api: &'static GssApi,
+    target: GssName,
+    ctx: GssCtx,
+    complete: bool,
+}
+
+// Handles are only owned pointers to the library state, they can be moved between threads
+unsafe impl Send for GssClientContext {}
+
+impl GssClientContext {
+    /// `service` is an host based service name, i.e: HTTP@proxy.corp
+    pub fn new(service: &str) -> anyhow::Result<Self> {
+        let api = GssApi::get()?;
+        let mut minor: OmUint32 = 0;
+        let mut target: GssName = ptr::null_mut();
+        let mut name = GssBuffer::from_slice(service.as_bytes());
+        let name_type = oid(GSS_C_NT_HOSTBASED_SERVICE);
+
+        let major = unsafe { (api.import_name)(&mut minor, &mut name, &name_type, &mut target) };
+        if is_error(major) {
+            return Err(anyhow!(
+                "Invalid kerberos service name {}: error {:#x}/{}",
+                service,
+                major,
+                minor
+            ));
+        }
+
+        Ok(Self {
+            api,
+            target,
+            ctx: ptr::null_mut(),
+            complete: false,
+        })
+    }
+
+    pub const fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Consume the token sent by the acceptor (none for the first call), and produce the next one to send back.
+    pub fn step(&mut self, input_token: Option<&[u8]>) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.complete {
+            return Ok(None);
+        }
+
+        let mut minor: OmUint32 = 0;
+        let mut input = input_token.map(GssBuffer::from_slice).unwrap_or(GssBuffer::empty());
+        let mut output = GssBuffer::empty();
+        let mech = oid(GSS_MECH_SPNEGO);
+        let major = unsafe {
+            (self.api.init_sec_context)(
+                &mut minor,
+                ptr::null_mut(),
+                &mut self.ctx,
+                self.target,
+                &mech,
+                GSS_C_MUTUAL_FLAG,
+                0,
+                ptr::null_mut(),
+                &mut input,
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+
+        let token = unsafe { output.to_vec() };
+        unsafe { (self.api.release_buffer)(&mut minor, &mut output) };
+        if is_error(major) {
+            return Err(anyhow!("Cannot generate kerberos token: error {:#x}/{}", major, minor));
+        }
+
+        self.complete = major == GSS_S_COMPLETE;
+        Ok(if token.is_empty() { None } else { Some(token) })
+    }
+}
+
+impl Drop for GssClientContext {
+    fn drop(&mut self) {
+        let mut minor: OmUint32 = 0;
+        unsafe {
+            if !self.ctx.is_null() {
+                (self.api.delete_sec_context)(&mut minor, &mut self.ctx, ptr::null_mut());
+            }
+            (self.api.release_name)(&mut minor, &mut self.target);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Minimal bindings to the system GSSAPI library (MIT Kerberos or Heimdal).
+//! The library is loaded at runtime, so wstunnel does not require it to be installed if kerberos is not used.
+
+use anyhow::anyhow;
+use libloading::Library;
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::LazyLock;
+
+pub type OmUint32 = u32;
+pub type GssName = *mut c_void;
+pub type GssCtx = *mut c_void;
+pub type GssCred = *mut c_void;
+
+#[repr(C)]
+pub struct GssBuffer {
+    pub length: usize,
+    pub value: *mut c_void,
+}
+
+impl GssBuffer {
+    pub const fn empty() -> Self {
+        Self {
+            length: 0,
+            value: ptr::null_mut(),
+        }
+    }
+
+    /// The buffer borrows the data, it must not outlive it
+    pub fn from_slice(data: &[u8]) -> Self {
+        Self {
+            length: data.len(),
+            value: data.as_ptr() as *mut c_void,
+        }
+    }
+
+    /// # Safety
+    /// Buffer must have been filled by the gssapi library
+    pub unsafe fn to_vec(&self) -> Vec<u8> {
+        if self.value.is_null() || self.length == 0 {
+            return vec![];
+        }
+        std::slice::from_raw_parts(self.value as *const u8, self.length).to_vec()
+    }
+}
+
+#[repr(C)]
+pub struct GssOid {
+    pub length: OmUint32,
+    pub elements: *const c_void,
+}
+
+// 1.2.840.113554.1.2.1.4
+pub static GSS_C_NT_HOSTBASED_SERVICE: &[u8] = b"\x2a\x86\x48\x86\xf7\x12\x01\x02\x01\x04";
+// 1.3.6.1.5.5.2
+pub static GSS_MECH_SPNEGO: &[u8] = b"\x2b\x06\x01\x05\x05\x02";
+
+pub const GSS_C_MUTUAL_FLAG: OmUint32 = 2;
+pub const GSS_C_ACCEPT: i32 = 2;
+pub const GSS_S_COMPLETE: OmUint32 = 0;
+
+pub fn oid(der: &'static [u8]) -> GssOid {
+    GssOid {
+        length: der.len() as OmUint32,
+        elements: der.as_ptr() as *const c_void,
+    }
+}
+
+type ImportNameFn = unsafe extern "C" fn(*mut OmUint32, *mut GssBuffer, *const GssOid, *mut GssName) -> OmUint32;
+type ReleaseNameFn = unsafe extern "C" fn(*mut OmUint32, *mut GssName) -> OmUint32;
+type ReleaseBufferFn = unsafe extern "C" fn(*mut OmUint32, *mut GssBuffer) -> OmUint32;
+type DeleteSecContextFn = unsafe extern "C" fn(*mut OmUint32, *mut GssCtx, *mut GssBuffer) -> OmUint32;
+type ReleaseCredFn = unsafe extern "C" fn(*mut OmUint32, *mut GssCred) -> OmUint32;
+type DisplayNameFn = unsafe extern "C" fn(*mut OmUint32, GssName, *mut GssBuffer, *mut *mut GssOid) -> OmUint32;
+type WrapFn = unsafe extern "C" fn(
+    *mut OmUint32,  // minor_status
+    GssCtx,         // context_handle
+    i32,            // conf_req_flag
+    OmUint32,       // qop_req
+    *mut GssBuffer, // input_message_buffer
+    *mut i32,       // conf_state
+    *mut GssBuffer, // output_message_buffer
+) -> OmUint32;
+type UnwrapFn = unsafe extern "C" fn(
+    *mut OmUint32,  // minor_status
+    GssCtx,         // context_handle
+    *mut GssBuffer, // input_message_buffer
+    *mut GssBuffer, // output_message_buffer
+    *mut i32,       // conf_state
+    *mut OmUint32,  // qop_state
+) -> OmUint32;
+type AcquireCredFn = unsafe extern "C" fn(
+    *mut OmUint32,    // minor_status
+    GssName,          // desired_name
+    OmUint32,         // time_req
+    *mut c_void,      // desired_mechs
+    i32,              // cred_usage
+    *mut GssCred,     // output_cred_handle
+    *mut *mut c_void, // actual_mechs
+    *mut OmUint32,    // time_rec
+) -> OmUint32;
+type AcceptSecContextFn = unsafe extern "C" fn(
+    *mut OmUint32,    // minor_status
+    *mut GssCtx,      // context_handle
+    GssCred,          // acceptor_cred_handle
+    *mut GssBuffer,   // input_token_buffer
+    *mut c_void,      // input_chan_bindings
+    *mut GssName,     // src_name
+    *mut *mut GssOid, // mech_type
+    *mut GssBuffer,   // output_token
+    *mut OmUint32,    // ret_flags
+    *mut OmUint32,    // time_rec
+    *mut GssCred,     // delegated_cred_handle
+) -> OmUint32;
+type InitSecContextFn = unsafe extern "C" fn(
+    *mut OmUint32,    // minor_status
+    GssCred,          // initiator_cred_handle
+    *mut GssCtx,      // context_handle
+    GssName,          // target_name
+    *const GssOid,    // mech_type
+    OmUint32,         // req_flags
+    OmUint32,         // time_req
+    *mut c_void,      // input_chan_bindings
+    *mut GssBuffer,   // input_token
+    *mut *mut GssOid, // actual_mech_type
+    *mut GssBuffer,   // output_token
+    *mut OmUint32,    // ret_flags
+    *mut OmUint32,    // time_rec
+) -> OmUint32;
+
+pub struct GssApi {
+    _lib: Library,
+    pub import_name: ImportNameFn,
+    pub release_name: ReleaseNameFn,
+    pub release_buffer: ReleaseBufferFn,
+    pub delete_sec_context: DeleteSecContextFn,
+    pub init_sec_context: InitSecContextFn,
+    pub release_cred: ReleaseCredFn,
+    pub display_name: DisplayNameFn,
+    pub wrap: WrapFn,
+    pub unwrap: UnwrapFn,
+    pub acquire_cred: AcquireCredFn,
+    pub accept_sec_context: AcceptSecContextFn,
+}
+
+const LIBRARY_NAMES: &[&str] = &[
+    "libgssapi_krb5.so.2",
+    "libgssapi_krb5.so",
+    "libgssapi.so.3",
+    "libgssapi.so",
+];
+
+static GSSAPI: LazyLock<Result<GssApi, String>> = LazyLock::new(|| unsafe { GssApi::load() });
+
+impl GssApi {
+    unsafe fn load() -> Result<Self, String> {
+        let lib = LIBRARY_NAMES
+            .iter()
+            .find_map(|name| Library::new(name).ok())
+            .ok_or_else(|| format!("cannot find any gssapi library among {:?}", LIBRARY_NAMES))?;
+
+        macro_rules! sym {
+            ($name:literal) => {
+                *lib.get($name).map_err(|err| err.to_string())?
+            };
+        }
+
+        Ok(Self {
+            import_name: sym!(b"gss_import_name\0"),
+            release_name: sym!(b"gss_release_name\0"),
+            release_buffer: sym!(b"gss_release_buffer\0"),
+            delete_sec_context: sym!(b"gss_delete_sec_context\0"),
+            init_sec_context: sym!(b"gss_init_sec_context\0"),
+            release_cred: sym!(b"gss_release_cred\0"),
+            display_name: sym!(b"gss_display_name\0"),
+            wrap: sym!(b"gss_wrap\0"),
+            unwrap: sym!(b"gss_unwrap\0"),
+            acquire_cred: sym!(b"gss_acquire_cred\0"),
+            accept_sec_context: sym!(b"gss_accept_sec_context\0"),
+            _lib: lib,
+        })
+    }
+
+    pub fn get() -> anyhow::Result<&'static Self> {
+        GSSAPI
+            .as_ref()
+            .map_err(|err| anyhow!("Kerberos is not available: {}", err))
+    }
+}
+
+pub fn is_error(major: OmUint32) -> bool {
+    // Calling and routine errors are in the upper 16 bits, supplementary info in the lower ones
+    major & 0xffff_0000 != 0
+}
@@ -0,0 +1,6 @@
+mod client;
+mod ffi;
+mod server;
+
+pub use client::GssClientContext;
+pub use server::GssServerContext;
@@ -0,0 +1,175 @@
+use super::ffi::{
+    is_error, oid, GssApi, GssBuffer, GssCred, GssCtx, GssName, OmUint32, GSS_C_ACCEPT, GSS_C_NT_HOSTBASED_SERVICE,
+    GSS_S_COMPLETE,
+};
+use anyhow::anyhow;
+use std::ptr;
+
+/// Security context of the acceptor side, validating the tokens of clients with the keys of the keytab
+/// (i.e: /etc/krb5.keytab or the one pointed by KRB5_KTNAME)
+pub struct GssServerContext {
+    api: &'static GssApi,
+    cred: GssCred,
+    ctx: GssCtx,
+    client_name: GssName,
+    complete: bool,
+}
+
+// Handles are only owned pointers to the library state, they can be moved between threads
+unsafe impl Send for GssServerContext {}
+
+impl GssServerContext {
+    /// `service` is an host based service name, i.e: rcmd@proxy.corp
+    /// If None, any principal of the keytab is accepted.
+    pub fn new(service: Option<&str>) -> anyhow::Result<Self> {
+        let api = GssApi::get()?;
+        let mut minor: OmUint32 = 0;
+        let mut cred: GssCred = ptr::null_mut();
+
+        if let Some(service) = service {
+            let mut name: GssName = ptr::null_mut();
+            let mut buffer = GssBuffer::from_slice(service.as_bytes());
+            let name_type = oid(GSS_C_NT_HOSTBASED_SERVICE);
+            let major = unsafe { (api.import_name)(&mut minor, &mut buffer, &name_type, &mut name) };
+            if is_error(major) {
+                return Err(anyhow!(
+                    "Invalid kerberos service name {}: error {:#x}/{}",
+                    service,
+                    major,
+                    minor
+                ));
+            }
+
+            let major = unsafe {
+                (api.acquire_cred)(
+                    &mut minor,
+                    name,
+                    0,
+                    ptr::null_mut(),
+                    GSS_C_ACCEPT,
+                    &mut cred,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+            unsafe { (api.release_name)(&mut minor, &mut name) };
+            if is_error(major) {
+                return Err(anyhow!(
+                    "Cannot find keytab credentials for {}: error {:#x}/{}",
+                    service,
+                    major,
+                    minor
+                ));
+            }
+        }
+
+        Ok(Self {
+            api,
+            cred,
+            ctx: ptr::null_mut(),
+            client_name: ptr::null_mut(),
+            complete: false,
+        })
+    }
+
+    pub const fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Consume the token sent by the initiator, and produce the next one to send back if any.
+    pub fn step(&mut self, input_token: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut minor: OmUint32 = 0;
+        let mut input = GssBuffer::from_slice(input_token);
+        let mut output = GssBuffer::empty();
+        let major = unsafe {
+            (self.api.accept_sec_context)(
+                &mut minor,
+                &mut self.ctx,
+                self.cred,
+                &mut input,
+                ptr::null_mut(),
+                &mut self.client_name,
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+
+        let token = unsafe { output.to_vec() };
+        unsafe { (self.api.release_buffer)(&mut minor, &mut output) };
+        if is_error(major) {
+            return Err(anyhow!("Invalid kerberos token: error {:#x}/{}", major, minor));
+        }
+
+        self.complete = major == GSS_S_COMPLETE;
+        Ok(if token.is_empty() { None } else { Some(token) })
+    }
+
+    /// Principal of the authenticated client, i.e: user@CORP.COM
+    pub fn client_name(&self) -> anyhow::Result<String> {
+        if !self.complete {
+            return Err(anyhow!("Kerberos context is not established"));
+        }
+
+        let mut minor: OmUint32 = 0;
+        let mut output = GssBuffer::empty();
+        let major = unsafe { (self.api.display_name)(&mut minor, self.client_name, &mut output, ptr::null_mut()) };
+        let name = unsafe { output.to_vec() };
+        unsafe { (self.api.release_buffer)(&mut minor, &mut output) };
+        if is_error(major) {
+            return Err(anyhow!("Cannot get kerberos client name: error {:#x}/{}", major, minor));
+        }
+
+        Ok(String::from_utf8_lossy(&name).into_owned())
+    }
+
+    /// Integrity protect the message, without encrypting it
+    pub fn wrap(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut minor: OmUint32 = 0;
+        let mut input = GssBuffer::from_slice(message);
+        let mut output = GssBuffer::empty();
+        let major = unsafe { (self.api.wrap)(&mut minor, self.ctx, 0, 0, &mut input, ptr::null_mut(), &mut output) };
+        let wrapped = unsafe { output.to_vec() };
+        unsafe { (self.api.release_buffer)(&mut minor, &mut output) };
+        if is_error(major) {
+            return Err(anyhow!("Cannot wrap kerberos message: error {:#x}/{}", major, minor));
+        }
+
+        Ok(wrapped)
+    }
+
+    pub fn unwrap(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut minor: OmUint32 = 0;
+        let mut input = GssBuffer::from_slice(message);
+        let mut output = GssBuffer::empty();
+        let major = unsafe {
+            (self.api.unwrap)(&mut minor, self.ctx, &mut input, &mut output, ptr::null_mut(), ptr::null_mut())
+        };
+        let unwrapped = unsafe { output.to_vec() };
+        unsafe { (self.api.release_buffer)(&mut minor, &mut output) };
+        if is_error(major) {
+            return Err(anyhow!("Cannot unwrap kerberos message: error {:#x}/{}", major, minor));
+        }
+
+        Ok(unwrapped)
+    }
+}
+
+impl Drop for GssServerContext {
+    fn drop(&mut self) {
+        let mut minor: OmUint32 = 0;
+        unsafe {
+            if !self.ctx.is_null() {
+                (self.api.delete_sec_context)(&mut minor, &mut self.ctx, ptr::null_mut());
+            }
+            if !self.client_name.is_null() {
+                (self.api.release_name)(&mut minor, &mut self.client_name);
+            }
+            if !self.cred.is_null() {
+                (self.api.release_cred)(&mut minor, &mut self.cred);
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Context};
+use regex::Regex;
+use std::path::Path;
+use url::Host;
+
+#[derive(Debug, Clone)]
+struct Destination {
+    host: Regex,
+    port: Option<u16>,
+}
+
+/// Destinations that each user of the http proxy is allowed to reach. One rule per line:
+/// USER DESTINATION [DESTINATION...]
+/// With DESTINATION being HOST:PORT, HOST accepting * and ? wildcards and PORT being a number or *.
+/// A USER of * applies to every user, users without any rule cannot reach anything.
+/// i.e: alice *.corp.com:443 intranet:*
+#[derive(Debug, Clone)]
+pub struct ProxyAcl {
+    rules: Vec<(String, Vec<Destination>)>,
+}
+
+impl ProxyAcl {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("Cannot read acl file {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid acl file {}", path.display()))
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut rules = Vec::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let user = fields.next().unwrap_or_default().to_string();
+            let destinations = fields.map(parse_destination).collect::<anyhow::Result<Vec<_>>>()?;
+            if destinations.is_empty() {
+                return Err(anyhow!("No destination for user {}", user));
+            }
+            rules.push((user, destinations));
+        }
+
+        Ok(Self { rules })
+    }
+
+    pub fn is_allowed(&self, user: &str, host: &Host, port: u16) -> bool {
+        let host = match host {
+            Host::Domain(domain) => domain.to_ascii_lowercase(),
+            Host::Ipv4(ip) => ip.to_string(),
+            Host::Ipv6(ip) => ip.to_string(),
+        };
+
+        self.rules
+            .iter()
+            .filter(|(rule_user, _)| rule_user == user || rule_user == "*")
+            .flat_map(|(_, destinations)| destinations)
+            .any(|dest| dest.host.is_match(&host) && dest.port.is_none_or(|p| p == port))
+    }
+}
+
+fn parse_destination(destination: &str) -> anyhow::Result<Destination> {
+    let (host, port) = destination
+        .rsplit_once(':')
+        .with_context(|| format!("Missing port in destination {}", destination))?;
+    let port = match port {
+        "*" => None,
+        port => Some(
+            port.parse::<u16>()
+                .with_context(|| format!("Invalid port in destination {}", destination))?,
+        ),
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+    let host = format!("^{}$", regex::escape(&host).replace(r"\*", ".*").replace(r"\?", "."));
+    let host = Regex::new(&host).with_context(|| format!("Invalid host in destination {}", destination))?;
+
+    Ok(Destination { host, port })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+    use test_case::test_case;
+
+    const ACL: &str = "
+        # user    destinations
+        alice     *.corp.com:443 intranet:*
+        bob       [::1]:22
+        *         public.example.com:80
+    ";
+
+    #[test_case("alice", Host::Domain("www.Corp.com".to_string()), 443 => true ; "wildcard host")]
+    #[test_case("alice", Host::Domain("corp.com".to_string()), 443 => false ; "wildcard needs a subdomain")]
+    #[test_case("alice", Host::Domain("www.corp.com".to_string()), 80 => false ; "wrong port")]
+    #[test_case("alice", Host::Domain("intranet".to_string()), 8080 => true ; "any port")]
+    #[test_case("bob", Host::Ipv6(Ipv6Addr::LOCALHOST), 22 => true ; "ipv6")]
+    #[test_case("bob", Host::Domain("intranet".to_string()), 80 => false ; "rule of another user")]
+    #[test_case("bob", Host::Domain("public.example.com".to_string()), 80 => true ; "rule for everyone")]
+    #[test_case("carol", Host::Domain("intranet".to_string()), 80 => false ; "unknown user")]
+    fn test_is_allowed(user: &str, host: Host, port: u16) -> bool {
+        ProxyAcl::parse(ACL).unwrap().is_allowed(user, &host, port)
+    }
+
+    #[test]
+    fn test_invalid_acl() {
+        assert!(ProxyAcl::parse("alice").is_err());
+        assert!(ProxyAcl::parse("alice intranet").is_err());
+        assert!(ProxyAcl::parse("alice intranet:http").is_err());
+    }
+}
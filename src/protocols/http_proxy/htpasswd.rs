@@ -0,0 +1,162 @@
+use crate::protocols::tcp::md5;
+use crate::secret::{constant_time_eq, Secret};
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PasswordHash {
+    Plain(Secret),
+    // {SHA}base64(sha1(password)), i.e: htpasswd -s
+    Sha1(Vec<u8>),
+    // $apr1$salt$hash, i.e: htpasswd -m (default of apache htpasswd)
+    Apr1 { salt: String, hash: String },
+}
+
+/// Users of an htpasswd file. Supported hashes are apr1 (-m), sha1 (-s) and plain text (-p)
+#[derive(Debug, Clone)]
+pub struct Htpasswd {
+    users: HashMap<String, PasswordHash>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("Cannot read htpasswd file {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid htpasswd file {}", path.display()))
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut users = HashMap::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (user, hash) = line
+                .split_once(':')
+                .with_context(|| format!("Missing ':' separator in line {}", line))?;
+            let hash = if let Some(sha1) = hash.strip_prefix("{SHA}") {
+                PasswordHash::Sha1(
+                    base64::engine::general_purpose::STANDARD
+                        .decode(sha1)
+                        .with_context(|| format!("Invalid sha1 hash for user {}", user))?,
+                )
+            } else if let Some(apr1) = hash.strip_prefix("$apr1$") {
+                let (salt, hash) = apr1
+                    .split_once('$')
+                    .with_context(|| format!("Invalid apr1 hash for user {}", user))?;
+                PasswordHash::Apr1 {
+                    salt: salt.to_string(),
+                    hash: hash.to_string(),
+                }
+            } else if hash.starts_with('$') {
+                return Err(anyhow!(
+                    "Unsupported hash format for user {}, use apr1 (htpasswd -m), sha1 (-s) or plain text (-p)",
+                    user
+                ));
+            } else {
+                PasswordHash::Plain(Secret::new(hash.to_string()))
+            };
+            users.insert(user.to_string(), hash);
+        }
+
+        Ok(Self { users })
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            None => false,
+            Some(PasswordHash::Plain(expected)) => expected.matches(password),
+            Some(PasswordHash::Sha1(expected)) => {
+                constant_time_eq(Sha1::digest(password.as_bytes()).as_slice(), expected)
+            }
+            Some(PasswordHash::Apr1 { salt, hash }) => {
+                constant_time_eq(apr1_hash(password, salt).as_bytes(), hash.as_bytes())
+            }
+        }
+    }
+}
+
+/// MD5 based crypt of apache, the 22 characters after $apr1$salt$
+fn apr1_hash(password: &str, salt: &str) -> String {
+    const MAGIC: &[u8] = b"$apr1$";
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alternate = md5(&[password, salt, password].concat());
+    let mut ctx = [password, MAGIC, salt].concat();
+    for chunk in (0..password.len()).step_by(16) {
+        ctx.extend_from_slice(&alternate[..(password.len() - chunk).min(16)]);
+    }
+    let mut len = password.len();
+    while len > 0 {
+        ctx.push(if len & 1 == 1 { 0 } else { password[0] });
+        len >>= 1;
+    }
+    let mut digest = md5(&ctx);
+
+    for round in 0..1000 {
+        let mut ctx = Vec::with_capacity(64);
+        ctx.extend_from_slice(if round & 1 == 1 { password } else { &digest });
+        if round % 3 != 0 {
+            ctx.extend_from_slice(salt);
+        }
+        if round % 7 != 0 {
+            ctx.extend_from_slice(password);
+        }
+        ctx.extend_from_slice(if round & 1 == 1 { &digest } else { password });
+        digest = md5(&ctx);
+    }
+
+    let mut out = String::with_capacity(22);
+    let mut to64 = |mut value: u32, len: usize| {
+        for _ in 0..len {
+            out.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        to64((digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32, 4);
+    }
+    to64(digest[11] as u32, 2);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("secret", "saltsalt" => "LrttParrLPdxvgutaSXWJ0" ; "openssl vector")]
+    #[test_case("myPassword", "r31" => "xZkm/.q4DhO3zXfRL9FLE/" ; "short salt")]
+    fn test_apr1_hash(password: &str, salt: &str) -> String {
+        apr1_hash(password, salt)
+    }
+
+    #[test]
+    fn test_htpasswd_verify() {
+        let htpasswd = Htpasswd::parse(
+            "# comment\n\
+             alice:$apr1$saltsalt$LrttParrLPdxvgutaSXWJ0\n\
+             bob:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n\
+             carol:secret\n",
+        )
+        .unwrap();
+
+        for user in ["alice", "bob", "carol"] {
+            assert!(htpasswd.verify(user, "secret"));
+            assert!(!htpasswd.verify(user, "wrong"));
+        }
+        assert!(!htpasswd.verify("dave", "secret"));
+    }
+
+    #[test]
+    fn test_htpasswd_unsupported_hash() {
+        assert!(Htpasswd::parse("alice:$2y$05$c4WoMPo3SXsafkva.HHa6uXQZWr7oboPiC2bT/r7q1BB8I2s0BRqC").is_err());
+    }
+}
@@ -1,4 +1,9 @@
+mod acl;
+mod htpasswd;
 mod server;
 
+pub use acl::ProxyAcl;
+pub use htpasswd::Htpasswd;
 pub use server::run_server;
+pub use server::HttpProxyAuth;
 pub use server::HttpProxyListener;
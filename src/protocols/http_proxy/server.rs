@@ -3,17 +3,22 @@ use std::future::Future;
 
 use bytes::Bytes;
 use log::{debug, error};
+use std::fmt::{Debug, Formatter};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 
+use super::acl::ProxyAcl;
+use super::htpasswd::Htpasswd;
+use crate::secret::{constant_time_eq, Secret};
+
 use base64::Engine;
 use futures_util::{future, stream, Stream};
 use http_body_util::Empty;
 use hyper::body::Incoming;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response};
+use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioTimer;
 use parking_lot::Mutex;
 use std::time::Duration;
@@ -36,8 +41,38 @@ impl Stream for HttpProxyListener {
     }
 }
 
+/// How clients of the http proxy are authenticated, with the `Proxy-Authorization: Basic` header
+pub enum HttpProxyAuth {
+    None,
+    Credentials(String, Secret),
+    /// Users of an htpasswd file, with optionally the destinations each one is allowed to reach
+    Users {
+        htpasswd: Htpasswd,
+        acl: Option<ProxyAcl>,
+    },
+}
+
+impl From<Option<(String, String)>> for HttpProxyAuth {
+    fn from(credentials: Option<(String, String)>) -> Self {
+        match credentials {
+            Some((user, password)) => Self::Credentials(user, Secret::new(password)),
+            None => Self::None,
+        }
+    }
+}
+
+impl Debug for HttpProxyAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::Credentials(user, password) => write!(f, "Some({:?})", (user, password)),
+            Self::Users { acl, .. } => write!(f, "htpasswd (acl: {})", acl.is_some()),
+        }
+    }
+}
+
 fn handle_request(
-    credentials: &Option<String>,
+    auth: &HttpProxyAuth,
     dest: &Mutex<Option<(Host, u16)>>,
     req: Request<Incoming>,
 ) -> impl Future<Output = Result<Response<Empty<Bytes>>, &'static str>> {
@@ -46,13 +81,17 @@ fn handle_request(
         *dest.lock() = forward_to;
         Ok(Response::builder().status(200).body(Empty::new()).unwrap())
     };
-    fn err_response() -> Result<Response<Empty<Bytes>>, &'static str> {
-        info!("Un-authorized connection to http proxy");
-        Err("Un-authorized")
+    fn err_response(status: StatusCode) -> Result<Response<Empty<Bytes>>, &'static str> {
+        info!("Un-authorized connection to http proxy: {}", status);
+        let mut response = Response::builder().status(status);
+        if status == StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+            response = response.header(hyper::header::PROXY_AUTHENTICATE, "Basic realm=\"wstunnel\"");
+        }
+        Ok(response.body(Empty::new()).unwrap())
     }
 
     if req.method() != hyper::Method::CONNECT {
-        return future::ready(err_response());
+        return future::ready(Err("Un-authorized"));
     }
 
     debug!("HTTP Proxy CONNECT request to {}", req.uri());
@@ -60,30 +99,57 @@ fn handle_request(
         .ok()
         .map(|h| (h, req.uri().port_u16().unwrap_or(443)));
 
-    let Some(token) = credentials else {
+    if matches!(auth, HttpProxyAuth::None) {
         return future::ready(ok_response(forward_to));
-    };
+    }
 
-    let Some(auth) = req.headers().get(hyper::header::PROXY_AUTHORIZATION) else {
-        return future::ready(err_response());
+    let credentials = req
+        .headers()
+        .get(hyper::header::PROXY_AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.trim().strip_prefix(PROXY_AUTHORIZATION_PREFIX))
+        .and_then(|token| base64::engine::general_purpose::STANDARD.decode(token).ok())
+        .and_then(|token| String::from_utf8(token).ok());
+    let Some((user, password)) = credentials.as_deref().and_then(|c| c.split_once(':')) else {
+        return future::ready(err_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED));
     };
 
-    let auth = auth.to_str().unwrap_or_default().trim();
-    if auth.starts_with(PROXY_AUTHORIZATION_PREFIX) && &auth[PROXY_AUTHORIZATION_PREFIX.len()..] == token {
-        return future::ready(ok_response(forward_to));
+    match auth {
+        HttpProxyAuth::None => {}
+        HttpProxyAuth::Credentials(expected_user, expected_password) => {
+            // Both are always compared, to not tell whether the user exists
+            let is_user = constant_time_eq(user.as_bytes(), expected_user.as_bytes());
+            if !(expected_password.matches(password) & is_user) {
+                return future::ready(err_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED));
+            }
+        }
+        HttpProxyAuth::Users { htpasswd, acl } => {
+            if !htpasswd.verify(user, password) {
+                return future::ready(err_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED));
+            }
+            let is_allowed = match (acl, &forward_to) {
+                (None, _) => true,
+                (Some(acl), Some((host, port))) => acl.is_allowed(user, host, *port),
+                (Some(_), None) => false,
+            };
+            if !is_allowed {
+                info!("User {} is not allowed to reach {}", user, req.uri());
+                return future::ready(err_response(StatusCode::FORBIDDEN));
+            }
+        }
     }
 
-    future::ready(err_response())
+    future::ready(ok_response(forward_to))
 }
 
 pub async fn run_server(
     bind: SocketAddr,
     timeout: Option<Duration>,
-    credentials: Option<(String, String)>,
+    auth: HttpProxyAuth,
 ) -> Result<HttpProxyListener, anyhow::Error> {
     info!(
         "Starting http proxy server listening cnx on {} with credentials {:?}",
-        bind, credentials
+        bind, auth
     );
 
     let listener = TcpListener::bind(bind)
@@ -98,11 +164,9 @@ pub async fn run_server(
             .keep_alive(false);
         builder
     };
-    let auth_header =
-        credentials.map(|(user, pass)| base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass)));
     let tasks = JoinSet::<Option<(TcpStream, Option<(Host, u16)>)>>::new();
 
-    let proxy_cfg = Arc::new((auth_header, http1));
+    let proxy_cfg = Arc::new((auth, http1));
     let listener = stream::unfold((listener, tasks, proxy_cfg), |(listener, mut tasks, proxy_cfg)| async {
         loop {
             let (mut stream, forward_to) = select! {
@@ -112,7 +176,7 @@ pub async fn run_server(
                     match cnx {
                         Some(Ok(Some((stream, Some(f))))) => (stream, Some(f)),
                         Some(Ok(Some((_, None)))) =>{
-                            error!("Error while trying to parse connect request");
+                            debug!("Http proxy connection closed without an accepted connect request");
                             continue
                         },
                         None | Some(Ok(None)) => continue,
@@ -142,11 +206,11 @@ pub async fn run_server(
                 let proxy_cfg = proxy_cfg.clone();
                 async move {
                     let http1 = &proxy_cfg.1;
-                    let auth_header = &proxy_cfg.0;
+                    let auth = &proxy_cfg.0;
                     let forward_to = Mutex::new(None);
                     let conn_fut = http1.serve_connection(
                         hyper_util::rt::TokioIo::new(&mut stream),
-                        service_fn(|req| handle_request(auth_header, &forward_to, req)),
+                        service_fn(|req| handle_request(auth, &forward_to, req)),
                     );
 
                     match conn_fut.await {
@@ -1,6 +1,13 @@
 pub mod dns;
+pub mod exec;
+#[cfg(unix)]
+pub mod gssapi;
 pub mod http_proxy;
+pub mod pac;
+pub mod packet;
 pub mod socks5;
+#[cfg(windows)]
+pub mod sspi;
 pub mod stdio;
 pub mod tcp;
 pub mod tls;
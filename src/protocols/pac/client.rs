@@ -0,0 +1,241 @@
+use crate::protocols;
+use crate::protocols::dns::DnsResolver;
+use crate::protocols::pac::{PacProxy, PacScript};
+use crate::somark::SoMark;
+use crate::tunnel::spawn_until_cancelled;
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::header::HOST;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use std::net::IpAddr;
+use std::sync::{Arc, Once};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tokio::time;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use url::{Host, Url};
+
+/// Retrieve the content of a PAC file. Supported locations are file:// and http:// (i.e: WPAD http://wpad/wpad.dat)
+pub async fn fetch_pac_script(
+    pac_url: &Url,
+    so_mark: SoMark,
+    connect_timeout: Duration,
+    dns_resolver: &DnsResolver,
+) -> anyhow::Result<String> {
+    match pac_url.scheme() {
+        "file" => {
+            let path = pac_url
+                .to_file_path()
+                .map_err(|_| anyhow!("Invalid PAC file path {}", pac_url))?;
+            std::fs::read_to_string(&path).with_context(|| format!("Cannot read PAC file {:?}", path))
+        }
+        "http" => timeout(connect_timeout * 2, fetch_http(pac_url, so_mark, connect_timeout, dns_resolver))
+            .await
+            .map_err(|_| anyhow!("Timeout while fetching PAC file {}", pac_url))?,
+        scheme => Err(anyhow!("Unsupported scheme {} for PAC file url", scheme)),
+    }
+}
+
+async fn fetch_http(
+    pac_url: &Url,
+    so_mark: SoMark,
+    connect_timeout: Duration,
+    dns_resolver: &DnsResolver,
+) -> anyhow::Result<String> {
+    let host = pac_url
+        .host()
+        .with_context(|| format!("Invalid PAC file url {}", pac_url))?
+        .to_owned();
+    let port = pac_url.port_or_known_default().unwrap_or(80);
+    let stream = protocols::tcp::connect(&host, port, so_mark, connect_timeout, dns_resolver).await?;
+
+    let (mut request_sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .with_context(|| format!("Cannot do http handshake to fetch PAC file {}", pac_url))?;
+    tokio::spawn(async move {
+        if let Err(err) = cnx.await {
+            debug!("PAC file http connection error: {:?}", err)
+        }
+    });
+
+    let path = match pac_url.query() {
+        Some(query) => format!("{}?{}", pac_url.path(), query),
+        None => pac_url.path().to_string(),
+    };
+    let req = Request::get(path)
+        .header(HOST, format!("{}:{}", host, port))
+        .body(Empty::<Bytes>::new())?;
+    let response = request_sender.send_request(req).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("Cannot fetch PAC file {}: {}", pac_url, response.status()));
+    }
+
+    let body = response.into_body().collect().await?.to_bytes();
+    String::from_utf8(body.to_vec()).with_context(|| format!("PAC file {} is not valid utf-8", pac_url))
+}
+
+/// How often the PAC file is fetched again, for the changes of the network
+const PAC_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone)]
+enum PacState {
+    Fetching,
+    /// Cannot be retrieved, i.e: because we are not in the corporate network anymore
+    Unavailable,
+    Loaded(Arc<PacScript>),
+}
+
+/// PAC file of the client. It is fetched and parsed once, then refreshed in the background every
+/// PAC_REFRESH_INTERVAL, instead of for every connection
+pub struct PacFile {
+    url: Url,
+    refresh: Once,
+    state: watch::Sender<PacState>,
+    /// The client stopped
+    cancel: CancellationToken,
+}
+
+impl PacFile {
+    pub fn new(url: Url, cancel: CancellationToken) -> Self {
+        Self {
+            url,
+            refresh: Once::new(),
+            cancel,
+            state: watch::Sender::new(PacState::Fetching),
+        }
+    }
+
+    /// The last script fetched, the refresh is started by the first call and stops with the client.
+    /// None if the PAC file cannot be retrieved
+    async fn script(
+        self: &Arc<Self>,
+        so_mark: SoMark,
+        connect_timeout: Duration,
+        dns_resolver: &DnsResolver,
+    ) -> Option<Arc<PacScript>> {
+        self.refresh.call_once(|| {
+            let pac_file = self.clone();
+            let dns_resolver = dns_resolver.clone();
+            spawn_until_cancelled(&self.cancel, async move {
+                pac_file.refresh(so_mark, connect_timeout, &dns_resolver).await
+            });
+        });
+
+        let mut state = self.state.subscribe();
+        let state = state
+            .wait_for(|state| !matches!(state, PacState::Fetching))
+            .await
+            .ok()?;
+        match &*state {
+            PacState::Loaded(script) => Some(script.clone()),
+            PacState::Fetching | PacState::Unavailable => None,
+        }
+    }
+
+    async fn refresh(&self, so_mark: SoMark, connect_timeout: Duration, dns_resolver: &DnsResolver) {
+        loop {
+            let script = fetch_pac_script(&self.url, so_mark, connect_timeout, dns_resolver)
+                .await
+                .and_then(|script| PacScript::parse(&script));
+            let state = match script {
+                Ok(script) => PacState::Loaded(Arc::new(script)),
+                Err(err) => {
+                    warn!("Cannot retrieve PAC file {}, connecting directly: {:?}", self.url, err);
+                    PacState::Unavailable
+                }
+            };
+            self.state.send_replace(state);
+            time::sleep(PAC_REFRESH_INTERVAL).await;
+        }
+    }
+}
+
+/// Connect to `host:port` using the proxies returned by the PAC file for `target_url`.
+/// Every proxy returned is tried in order, until one succeeds. If the PAC file cannot be retrieved,
+/// i.e: because we are not in the corporate network anymore, the connection is done directly.
+pub async fn connect_with_pac(
+    pac_file: &Arc<PacFile>,
+    target_url: &Url,
+    host: &Host<String>,
+    port: u16,
+    so_mark: SoMark,
+    connect_timeout: Duration,
+    dns_resolver: &DnsResolver,
+) -> anyhow::Result<TcpStream> {
+    let proxies = match pac_file.script(so_mark, connect_timeout, dns_resolver).await {
+        Some(script) => {
+            let target_url = target_url.clone();
+            let dns_resolver = dns_resolver.clone();
+            let runtime = Handle::current();
+            tokio::task::spawn_blocking(move || {
+                // The script is blocking, the resolutions of its dns helpers wait for the resolver of the client
+                let dns_resolve = |host: &str| {
+                    let addrs = runtime.block_on(dns_resolver.lookup_host(host, 0)).ok()?;
+                    addrs.into_iter().find_map(|addr| match addr.ip() {
+                        IpAddr::V4(ip) => Some(ip),
+                        IpAddr::V6(_) => None,
+                    })
+                };
+                script.find_proxy_for_url(&target_url, &dns_resolve)
+            })
+            .await??
+        }
+        None => vec![PacProxy::Direct],
+    };
+    info!("PAC file selected {:?} to connect to {}", proxies, target_url);
+
+    let mut last_err = None;
+    for proxy in proxies {
+        let ret = match &proxy {
+            PacProxy::Direct => protocols::tcp::connect(host, port, so_mark, connect_timeout, dns_resolver).await,
+            PacProxy::Proxy(proxy_url) => {
+                protocols::tcp::connect_with_proxy_chain(
+                    std::slice::from_ref(proxy_url),
+                    host,
+                    port,
+                    so_mark,
+                    connect_timeout,
+                    dns_resolver,
+                )
+                .await
+            }
+        };
+
+        match ret {
+            Ok(stream) => return Ok(stream),
+            Err(err) => {
+                warn!("Cannot connect using {:?} from PAC file: {:?}", proxy, err);
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| anyhow!("PAC file did not return any usable proxy")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pac_file_cached() {
+        let path = std::env::temp_dir().join(format!("wstunnel-pac-{}.js", std::process::id()));
+        std::fs::write(&path, "function FindProxyForURL(url, host) { return 'DIRECT'; }").unwrap();
+        let cancel = CancellationToken::new();
+        let pac_file = Arc::new(PacFile::new(Url::from_file_path(&path).unwrap(), cancel.clone()));
+        let dns_resolver = DnsResolver::default();
+        let script = || pac_file.script(SoMark::new(None), Duration::from_secs(1), &dns_resolver);
+
+        assert!(script().await.is_some());
+        // Not fetched again until it is refreshed
+        std::fs::remove_file(&path).unwrap();
+        assert!(script().await.is_some());
+        cancel.cancel();
+    }
+}
@@ -0,0 +1,825 @@
+//! Tiny interpreter for the subset of javascript used by PAC files.
+//!
+//! Supported: function declarations, var/let/const, if/else, return, string/number/boolean literals,
+//! comparison/logical/arithmetic operators, ternary, string methods (toLowerCase, indexOf, substring, ...)
+//! and the builtin functions provided by the caller. Loops, objects, arrays and regex literals are not supported.
+
+use anyhow::{anyhow, Context};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Undefined,
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+}
+
+impl Value {
+    pub fn truthy(&self) -> bool {
+        match self {
+            Self::Undefined | Self::Null => false,
+            Self::Bool(b) => *b,
+            Self::Num(n) => *n != 0.0 && !n.is_nan(),
+            Self::Str(s) => !s.is_empty(),
+        }
+    }
+
+    pub fn as_string(&self) -> String {
+        match self {
+            Self::Undefined => "undefined".to_string(),
+            Self::Null => "null".to_string(),
+            Self::Bool(b) => b.to_string(),
+            Self::Num(n) if n.fract() == 0.0 && n.is_finite() => format!("{}", *n as i64),
+            Self::Num(n) => n.to_string(),
+            Self::Str(s) => s.clone(),
+        }
+    }
+
+    pub fn as_number(&self) -> f64 {
+        match self {
+            Self::Undefined => f64::NAN,
+            Self::Null => 0.0,
+            Self::Bool(b) => *b as u8 as f64,
+            Self::Num(n) => *n,
+            Self::Str(s) if s.trim().is_empty() => 0.0,
+            Self::Str(s) => s.trim().parse().unwrap_or(f64::NAN),
+        }
+    }
+
+    fn loose_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Undefined | Self::Null, Self::Undefined | Self::Null) => true,
+            (Self::Undefined | Self::Null, _) | (_, Self::Undefined | Self::Null) => false,
+            (Self::Str(a), Self::Str(b)) => a == b,
+            (a, b) => a.as_number() == b.as_number(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Punct(&'static str),
+}
+
+const PUNCTUATIONS: &[&str] = &[
+    "===", "!==", "==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", ",", ";", ".", "!", "<", ">", "+", "-", "*",
+    "/", "%", "?", ":", "=",
+];
+
+fn tokenize(src: &str) -> anyhow::Result<Vec<Token>> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i += 2;
+        } else if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' {
+                    i += 1;
+                    match chars.get(i) {
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some(c) => s.push(*c),
+                        None => break,
+                    }
+                } else {
+                    s.push(chars[i]);
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(anyhow!("Unterminated string literal"));
+            }
+            i += 1;
+            tokens.push(Token::Str(s));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            let num: String = chars[start..i].iter().collect();
+            let num = match num.strip_prefix("0x").or_else(|| num.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16).map(|n| n as f64).ok(),
+                None => num.parse::<f64>().ok(),
+            };
+            tokens.push(Token::Num(num.with_context(|| "Invalid number literal")?));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 3)].iter().collect();
+            let punct = PUNCTUATIONS
+                .iter()
+                .find(|p| rest.starts_with(**p))
+                .ok_or_else(|| anyhow!("Unexpected character '{}'", c))?;
+            i += punct.len();
+            tokens.push(Token::Punct(punct));
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Lit(Value),
+    Ident(String),
+    Assign(String, Box<Expr>),
+    Member(Box<Expr>, String),
+    Call(Box<Expr>, Vec<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Empty,
+    Expr(Expr),
+    Var(Vec<(String, Option<Expr>)>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    Return(Option<Expr>),
+    Block(Vec<Stmt>),
+    Function(Arc<Function>),
+}
+
+#[derive(Debug)]
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+/// Nesting of the statements and expressions of a script, the parser and the interpreter are recursive
+const MAX_NESTING_DEPTH: usize = 128;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn enter(&mut self) -> anyhow::Result<()> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return Err(anyhow!("Script is nested too deeply"));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Parse with the rule one level deeper in the script
+    fn nested<T>(&mut self, rule: impl FnOnce(&mut Self) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        self.enter()?;
+        let ret = rule(self);
+        self.depth -= 1;
+        ret
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> anyhow::Result<Token> {
+        let token = self.tokens.get(self.pos).cloned().context("Unexpected end of script")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(id)) if id == keyword)
+    }
+
+    fn eat_punct(&mut self, punct: &str) -> bool {
+        let found = self.is_punct(punct);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> anyhow::Result<()> {
+        if !self.eat_punct(punct) {
+            return Err(anyhow!("Expected '{}' but got {:?}", punct, self.peek()));
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> anyhow::Result<String> {
+        match self.next()? {
+            Token::Ident(id) => Ok(id),
+            token => Err(anyhow!("Expected an identifier but got {:?}", token)),
+        }
+    }
+
+    fn program(&mut self) -> anyhow::Result<Vec<Stmt>> {
+        let mut stmts = Vec::new();
+        while self.peek().is_some() {
+            stmts.push(self.statement()?);
+        }
+        Ok(stmts)
+    }
+
+    fn statement(&mut self) -> anyhow::Result<Stmt> {
+        self.nested(Self::parse_statement)
+    }
+
+    fn parse_statement(&mut self) -> anyhow::Result<Stmt> {
+        if self.eat_punct(";") {
+            return Ok(Stmt::Empty);
+        }
+
+        if self.eat_punct("{") {
+            let mut stmts = Vec::new();
+            while !self.eat_punct("}") {
+                stmts.push(self.statement()?);
+            }
+            return Ok(Stmt::Block(stmts));
+        }
+
+        if self.is_keyword("function") {
+            self.pos += 1;
+            let name = self.ident()?;
+            self.expect_punct("(")?;
+            let mut params = Vec::new();
+            while !self.eat_punct(")") {
+                params.push(self.ident()?);
+                if !self.is_punct(")") {
+                    self.expect_punct(",")?;
+                }
+            }
+            self.expect_punct("{")?;
+            let mut body = Vec::new();
+            while !self.eat_punct("}") {
+                body.push(self.statement()?);
+            }
+            return Ok(Stmt::Function(Arc::new(Function { name, params, body })));
+        }
+
+        if self.is_keyword("var") || self.is_keyword("let") || self.is_keyword("const") {
+            self.pos += 1;
+            let mut decls = Vec::new();
+            loop {
+                let name = self.ident()?;
+                let init = if self.eat_punct("=") {
+                    Some(self.expression()?)
+                } else {
+                    None
+                };
+                decls.push((name, init));
+                if !self.eat_punct(",") {
+                    break;
+                }
+            }
+            self.eat_punct(";");
+            return Ok(Stmt::Var(decls));
+        }
+
+        if self.is_keyword("if") {
+            self.pos += 1;
+            self.expect_punct("(")?;
+            let cond = self.expression()?;
+            self.expect_punct(")")?;
+            let then = Box::new(self.statement()?);
+            let otherwise = if self.is_keyword("else") {
+                self.pos += 1;
+                Some(Box::new(self.statement()?))
+            } else {
+                None
+            };
+            return Ok(Stmt::If(cond, then, otherwise));
+        }
+
+        if self.is_keyword("return") {
+            self.pos += 1;
+            let value = if self.is_punct(";") || self.is_punct("}") {
+                None
+            } else {
+                Some(self.expression()?)
+            };
+            self.eat_punct(";");
+            return Ok(Stmt::Return(value));
+        }
+
+        let expr = self.expression()?;
+        self.eat_punct(";");
+        Ok(Stmt::Expr(expr))
+    }
+
+    fn expression(&mut self) -> anyhow::Result<Expr> {
+        self.nested(Self::parse_expression)
+    }
+
+    fn parse_expression(&mut self) -> anyhow::Result<Expr> {
+        let expr = self.ternary()?;
+        if self.eat_punct("=") {
+            let Expr::Ident(name) = expr else {
+                return Err(anyhow!("Invalid assignment target"));
+            };
+            return Ok(Expr::Assign(name, Box::new(self.expression()?)));
+        }
+        Ok(expr)
+    }
+
+    fn ternary(&mut self) -> anyhow::Result<Expr> {
+        let cond = self.or()?;
+        if !self.eat_punct("?") {
+            return Ok(cond);
+        }
+        let then = self.expression()?;
+        self.expect_punct(":")?;
+        let otherwise = self.expression()?;
+        Ok(Expr::Cond(Box::new(cond), Box::new(then), Box::new(otherwise)))
+    }
+
+    // Long chains of conditions are common in PAC files, they are not nested
+    fn or(&mut self) -> anyhow::Result<Expr> {
+        let mut operands = vec![self.and()?];
+        while self.eat_punct("||") {
+            operands.push(self.and()?);
+        }
+        Ok(match operands.len() {
+            1 => operands.remove(0),
+            _ => Expr::Or(operands),
+        })
+    }
+
+    fn and(&mut self) -> anyhow::Result<Expr> {
+        let mut operands = vec![self.binary(0)?];
+        while self.eat_punct("&&") {
+            operands.push(self.binary(0)?);
+        }
+        Ok(match operands.len() {
+            1 => operands.remove(0),
+            _ => Expr::And(operands),
+        })
+    }
+
+    fn binary(&mut self, level: usize) -> anyhow::Result<Expr> {
+        const LEVELS: &[&[&str]] = &[
+            &["===", "!==", "==", "!="],
+            &["<=", ">=", "<", ">"],
+            &["+", "-"],
+            &["*", "/", "%"],
+        ];
+        let Some(operators) = LEVELS.get(level) else {
+            return self.unary();
+        };
+
+        let depth = self.depth;
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = operators.iter().find(|op| self.is_punct(op)) {
+            self.pos += 1;
+            // Each operation nests the previous ones
+            self.enter()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.binary(level + 1)?));
+        }
+        self.depth = depth;
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Expr> {
+        if self.eat_punct("!") {
+            return Ok(Expr::Not(Box::new(self.nested(Self::unary)?)));
+        }
+        if self.eat_punct("-") {
+            return Ok(Expr::Neg(Box::new(self.nested(Self::unary)?)));
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> anyhow::Result<Expr> {
+        let depth = self.depth;
+        let mut expr = self.primary()?;
+        loop {
+            if !self.is_punct(".") && !self.is_punct("(") {
+                self.depth = depth;
+                return Ok(expr);
+            }
+            // Each member access or call nests the previous ones
+            self.enter()?;
+            if self.eat_punct(".") {
+                expr = Expr::Member(Box::new(expr), self.ident()?);
+            } else if self.eat_punct("(") {
+                let mut args = Vec::new();
+                while !self.eat_punct(")") {
+                    args.push(self.expression()?);
+                    if !self.is_punct(")") {
+                        self.expect_punct(",")?;
+                    }
+                }
+                expr = Expr::Call(Box::new(expr), args);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> anyhow::Result<Expr> {
+        match self.next()? {
+            Token::Str(s) => Ok(Expr::Lit(Value::Str(s))),
+            Token::Num(n) => Ok(Expr::Lit(Value::Num(n))),
+            Token::Punct("(") => {
+                let expr = self.expression()?;
+                self.expect_punct(")")?;
+                Ok(expr)
+            }
+            Token::Ident(id) => Ok(match id.as_str() {
+                "true" => Expr::Lit(Value::Bool(true)),
+                "false" => Expr::Lit(Value::Bool(false)),
+                "null" => Expr::Lit(Value::Null),
+                "undefined" => Expr::Lit(Value::Undefined),
+                _ => Expr::Ident(id),
+            }),
+            token => Err(anyhow!("Unexpected token {:?}", token)),
+        }
+    }
+}
+
+enum Flow {
+    Normal,
+    Return(Value),
+}
+
+pub type Builtin<'a> = &'a dyn Fn(&str, &[Value]) -> Option<Value>;
+
+/// Script parsed once, to be run by as many interpreters as needed
+pub struct Script {
+    program: Vec<Stmt>,
+}
+
+impl Script {
+    pub fn parse(script: &str) -> anyhow::Result<Self> {
+        let program = Parser {
+            tokens: tokenize(script)?,
+            pos: 0,
+            depth: 0,
+        }
+        .program()?;
+
+        Ok(Self { program })
+    }
+}
+
+pub struct Interpreter<'a> {
+    functions: HashMap<String, Arc<Function>>,
+    globals: HashMap<String, Value>,
+    builtins: Builtin<'a>,
+    depth: usize,
+}
+
+const MAX_CALL_DEPTH: usize = 64;
+
+impl<'a> Interpreter<'a> {
+    /// Run the top level statements of the script.
+    /// `builtins` is called for every function that is not defined by the script itself.
+    pub fn new(script: &Script, builtins: Builtin<'a>) -> anyhow::Result<Self> {
+        let program = &script.program;
+        let mut interpreter = Self {
+            functions: HashMap::new(),
+            globals: HashMap::new(),
+            builtins,
+            depth: 0,
+        };
+        // function declarations are hoisted
+        for stmt in program {
+            if let Stmt::Function(f) = stmt {
+                interpreter.functions.insert(f.name.clone(), f.clone());
+            }
+        }
+        let mut scope = None;
+        for stmt in program {
+            interpreter.exec(stmt, &mut scope)?;
+        }
+
+        Ok(interpreter)
+    }
+
+    pub fn call(&mut self, name: &str, args: &[Value]) -> anyhow::Result<Value> {
+        let Some(function) = self.functions.get(name).cloned() else {
+            return (self.builtins)(name, args).ok_or_else(|| anyhow!("Function {} is not defined", name));
+        };
+
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(anyhow!("Maximum call depth exceeded"));
+        }
+        self.depth += 1;
+        let mut scope: Option<HashMap<String, Value>> = Some(
+            function
+                .params
+                .iter()
+                .enumerate()
+                .map(|(ix, param)| (param.clone(), args.get(ix).cloned().unwrap_or(Value::Undefined)))
+                .collect(),
+        );
+        let mut ret = Ok(Value::Undefined);
+        for stmt in &function.body {
+            match self.exec(stmt, &mut scope) {
+                Ok(Flow::Normal) => continue,
+                Ok(Flow::Return(value)) => ret = Ok(value),
+                Err(err) => ret = Err(err),
+            }
+            break;
+        }
+        self.depth -= 1;
+
+        ret
+    }
+
+    fn exec(&mut self, stmt: &Stmt, scope: &mut Option<HashMap<String, Value>>) -> anyhow::Result<Flow> {
+        match stmt {
+            Stmt::Empty | Stmt::Function(_) => {}
+            Stmt::Expr(expr) => {
+                self.eval(expr, scope)?;
+            }
+            Stmt::Var(decls) => {
+                for (name, init) in decls {
+                    let value = match init {
+                        Some(expr) => self.eval(expr, scope)?,
+                        None => Value::Undefined,
+                    };
+                    match scope {
+                        Some(locals) => locals.insert(name.clone(), value),
+                        None => self.globals.insert(name.clone(), value),
+                    };
+                }
+            }
+            Stmt::If(cond, then, otherwise) => {
+                if self.eval(cond, scope)?.truthy() {
+                    return self.exec(then, scope);
+                } else if let Some(otherwise) = otherwise {
+                    return self.exec(otherwise, scope);
+                }
+            }
+            Stmt::Return(expr) => {
+                let value = match expr {
+                    Some(expr) => self.eval(expr, scope)?,
+                    None => Value::Undefined,
+                };
+                return Ok(Flow::Return(value));
+            }
+            Stmt::Block(stmts) => {
+                for stmt in stmts {
+                    if let Flow::Return(value) = self.exec(stmt, scope)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            }
+        }
+
+        Ok(Flow::Normal)
+    }
+
+    fn eval(&mut self, expr: &Expr, scope: &mut Option<HashMap<String, Value>>) -> anyhow::Result<Value> {
+        Ok(match expr {
+            Expr::Lit(value) => value.clone(),
+            Expr::Ident(name) => scope
+                .as_ref()
+                .and_then(|locals| locals.get(name))
+                .or_else(|| self.globals.get(name))
+                .cloned()
+                .ok_or_else(|| anyhow!("{} is not defined", name))?,
+            Expr::Assign(name, expr) => {
+                let value = self.eval(expr, scope)?;
+                match scope {
+                    Some(locals) if locals.contains_key(name) => locals.insert(name.clone(), value.clone()),
+                    _ => self.globals.insert(name.clone(), value.clone()),
+                };
+                value
+            }
+            Expr::Member(obj, prop) => match (self.eval(obj, scope)?, prop.as_str()) {
+                (Value::Str(s), "length") => Value::Num(s.encode_utf16().count() as f64),
+                (_, prop) => return Err(anyhow!("Unsupported property {}", prop)),
+            },
+            Expr::Call(callee, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg, scope))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                match callee.as_ref() {
+                    Expr::Ident(name) => self.call(name, &args)?,
+                    Expr::Member(obj, method) => {
+                        let obj = self.eval(obj, scope)?.as_string();
+                        string_method(&obj, method, &args)?
+                    }
+                    _ => return Err(anyhow!("Expression is not callable")),
+                }
+            }
+            Expr::Not(expr) => Value::Bool(!self.eval(expr, scope)?.truthy()),
+            Expr::Neg(expr) => Value::Num(-self.eval(expr, scope)?.as_number()),
+            // The first falsy operand, or the last one
+            Expr::And(operands) => {
+                let mut value = Value::Undefined;
+                for operand in operands {
+                    value = self.eval(operand, scope)?;
+                    if !value.truthy() {
+                        break;
+                    }
+                }
+                value
+            }
+            // The first truthy operand, or the last one
+            Expr::Or(operands) => {
+                let mut value = Value::Undefined;
+                for operand in operands {
+                    value = self.eval(operand, scope)?;
+                    if value.truthy() {
+                        break;
+                    }
+                }
+                value
+            }
+            Expr::Cond(cond, then, otherwise) => {
+                if self.eval(cond, scope)?.truthy() {
+                    self.eval(then, scope)?
+                } else {
+                    self.eval(otherwise, scope)?
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs, scope)?;
+                let rhs = self.eval(rhs, scope)?;
+                binary_op(op, &lhs, &rhs)
+            }
+        })
+    }
+}
+
+fn binary_op(op: &str, lhs: &Value, rhs: &Value) -> Value {
+    match op {
+        "===" => Value::Bool(lhs == rhs),
+        "!==" => Value::Bool(lhs != rhs),
+        "==" => Value::Bool(lhs.loose_eq(rhs)),
+        "!=" => Value::Bool(!lhs.loose_eq(rhs)),
+        "+" => match (lhs, rhs) {
+            (Value::Str(_), _) | (_, Value::Str(_)) => Value::Str(lhs.as_string() + &rhs.as_string()),
+            _ => Value::Num(lhs.as_number() + rhs.as_number()),
+        },
+        "-" => Value::Num(lhs.as_number() - rhs.as_number()),
+        "*" => Value::Num(lhs.as_number() * rhs.as_number()),
+        "/" => Value::Num(lhs.as_number() / rhs.as_number()),
+        "%" => Value::Num(lhs.as_number() % rhs.as_number()),
+        _ => {
+            let ordering = match (lhs, rhs) {
+                (Value::Str(a), Value::Str(b)) => a.partial_cmp(b),
+                _ => lhs.as_number().partial_cmp(&rhs.as_number()),
+            };
+            Value::Bool(ordering.is_some_and(|ordering| match op {
+                "<" => ordering.is_lt(),
+                ">" => ordering.is_gt(),
+                "<=" => ordering.is_le(),
+                _ => ordering.is_ge(),
+            }))
+        }
+    }
+}
+
+fn string_method(s: &str, method: &str, args: &[Value]) -> anyhow::Result<Value> {
+    let arg_str = |ix: usize| args.get(ix).map(Value::as_string).unwrap_or_default();
+    // Indexes are in chars, as PAC files only deal with hostnames and urls
+    let chars: Vec<char> = s.chars().collect();
+    let arg_index = |ix: usize, default: usize| {
+        args.get(ix)
+            .map(|v| v.as_number())
+            .filter(|n| !n.is_nan())
+            .map_or(default, |n| n.clamp(0.0, chars.len() as f64) as usize)
+    };
+    let char_index = |byte_ix: Option<usize>| byte_ix.map_or(-1.0, |byte_ix| s[..byte_ix].chars().count() as f64);
+
+    Ok(match method {
+        "toLowerCase" => Value::Str(s.to_lowercase()),
+        "toUpperCase" => Value::Str(s.to_uppercase()),
+        "trim" => Value::Str(s.trim().to_string()),
+        "indexOf" => Value::Num(char_index(s.find(&arg_str(0)))),
+        "lastIndexOf" => Value::Num(char_index(s.rfind(&arg_str(0)))),
+        "startsWith" => Value::Bool(s.starts_with(&arg_str(0))),
+        "endsWith" => Value::Bool(s.ends_with(&arg_str(0))),
+        "includes" => Value::Bool(s.contains(&arg_str(0))),
+        "charAt" => Value::Str(chars.get(arg_index(0, 0)).map(|c| c.to_string()).unwrap_or_default()),
+        "substring" => {
+            let (start, end) = (arg_index(0, 0), arg_index(1, chars.len()));
+            Value::Str(chars[start.min(end)..start.max(end)].iter().collect())
+        }
+        "substr" => {
+            let start = arg_index(0, 0);
+            let len = args.get(1).map_or(chars.len(), |v| v.as_number().max(0.0) as usize);
+            Value::Str(chars[start..].iter().take(len).collect())
+        }
+        _ => return Err(anyhow!("Unsupported string method {}", method)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_builtins(_: &str, _: &[Value]) -> Option<Value> {
+        None
+    }
+
+    fn run(script: &str, args: &[Value]) -> Value {
+        let script = Script::parse(script).unwrap();
+        Interpreter::new(&script, &no_builtins)
+            .unwrap()
+            .call("f", args)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_statements() {
+        let script = r#"
+            /* global */
+            var suffix = ".corp";
+            function f(host) {
+                var h = host.toLowerCase(); // comment
+                if (h == "direct") return "DIRECT";
+                else if (h.indexOf(suffix) > 0) { return "PROXY " + h.substring(0, 3) + ":" + (3000 + 128); }
+                return h.length > 5 ? 'SOCKS5 long' : "SOCKS5 short";
+            }
+        "#;
+
+        assert_eq!(run(script, &[Value::Str("DIRECT".into())]), Value::Str("DIRECT".into()));
+        assert_eq!(
+            run(script, &[Value::Str("www.corp".into())]),
+            Value::Str("PROXY www:3128".into())
+        );
+        assert_eq!(run(script, &[Value::Str("example".into())]), Value::Str("SOCKS5 long".into()));
+        assert_eq!(run(script, &[Value::Str("ex".into())]), Value::Str("SOCKS5 short".into()));
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(run("function f() { return !(1 == '1') || null; }", &[]), Value::Null);
+        assert_eq!(run("function f() { return 1 === '1'; }", &[]), Value::Bool(false));
+        assert_eq!(run("function f() { return 2 * 3 - 4 / 2 + 10 % 3; }", &[]), Value::Num(5.0));
+        assert_eq!(run("function f() { return 'a' && 'b'; }", &[]), Value::Str("b".into()));
+        assert_eq!(
+            run("function f() { return 0 || '' || 'c' || 'd'; }", &[]),
+            Value::Str("c".into())
+        );
+        assert_eq!(run("function f() { return 1 && 0 && 'c'; }", &[]), Value::Num(0.0));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Script::parse("function f( {").is_err());
+        assert!(Script::parse("var a = 'unterminated").is_err());
+        let script = Script::parse("function f() { return g(); }").unwrap();
+        assert!(Interpreter::new(&script, &no_builtins).unwrap().call("f", &[]).is_err());
+        let script = Script::parse("function f() { return f(); }").unwrap();
+        assert!(Interpreter::new(&script, &no_builtins).unwrap().call("f", &[]).is_err());
+    }
+
+    #[test]
+    fn test_nesting_depth() {
+        let nested = |depth: usize, open: &str, close: &str| {
+            format!("function f() {{ return {}1{}; }}", open.repeat(depth), close.repeat(depth))
+        };
+        // Evaluated as deep as possible, in recursive calls
+        let script = format!(
+            "function f(n) {{ return n > 0 ? {}f(n - 1){} : 0; }}",
+            "(".repeat(120),
+            ")".repeat(120)
+        );
+        assert_eq!(run(&script, &[Value::Num(63.0)]), Value::Num(0.0));
+        assert_eq!(run(&nested(100, "!", ""), &[]), Value::Bool(true));
+
+        assert!(Script::parse(&nested(1000, "(", ")")).is_err());
+        assert!(Script::parse(&nested(1000, "!", "")).is_err());
+        assert!(Script::parse(&nested(1000, "1 + ", "")).is_err());
+        assert!(Script::parse(&nested(1000, "", ".length")).is_err());
+        assert!(Script::parse(&format!("function f() {{ {}return 1; }}", "if (1) ".repeat(1000))).is_err());
+        // Conditions are chained without nesting
+        assert_eq!(run(&nested(1000, "0 || ", ""), &[]), Value::Num(1.0));
+    }
+}
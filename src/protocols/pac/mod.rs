@@ -0,0 +1,7 @@
+mod client;
+mod js;
+mod script;
+
+pub use client::{connect_with_pac, PacFile};
+pub use script::PacProxy;
+pub use script::PacScript;
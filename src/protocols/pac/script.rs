@@ -0,0 +1,425 @@
+use crate::protocols::pac::js::{Interpreter, Script, Value};
+use anyhow::Context as _;
+use chrono::{Datelike, Local, NaiveDateTime, Timelike, Utc};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use tracing::warn;
+use url::Url;
+
+/// One of the entries returned by the FindProxyForURL function of a PAC file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacProxy {
+    Direct,
+    Proxy(Url),
+}
+
+/// Resolution of the hosts given to the dns helpers of the PAC scripts
+pub type PacDnsResolve<'a> = &'a dyn Fn(&str) -> Option<Ipv4Addr>;
+
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+const MONTHS: [&str; 12] = [
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+
+/// Standard helpers available to PAC scripts, implemented natively
+/// https://developer.mozilla.org/en-US/docs/Web/HTTP/Proxy_servers_and_tunneling/Proxy_Auto-Configuration_PAC_file
+fn pac_builtins(name: &str, args: &[Value], dns_resolve: PacDnsResolve) -> Option<Value> {
+    let arg = |ix: usize| args.get(ix).map(Value::as_string).unwrap_or_default();
+    let ret = match name {
+        "isPlainHostName" => Value::Bool(!arg(0).contains('.')),
+        "dnsDomainIs" => Value::Bool(arg(0).ends_with(&arg(1))),
+        "localHostOrDomainIs" => {
+            let (host, hostdom) = (arg(0), arg(1));
+            Value::Bool(host == hostdom || hostdom.starts_with(&format!("{}.", host)))
+        }
+        "dnsDomainLevels" => Value::Num(arg(0).matches('.').count() as f64),
+        "isResolvable" => Value::Bool(dns_resolve(&arg(0)).is_some()),
+        "dnsResolve" => dns_resolve(&arg(0)).map_or(Value::Null, |ip| Value::Str(ip.to_string())),
+        "myIpAddress" => Value::Str(my_ip_address().to_string()),
+        "shExpMatch" => Value::Bool(sh_exp_match(arg(0).as_bytes(), arg(1).as_bytes())),
+        "isInNet" => {
+            let ip = match arg(0).parse::<Ipv4Addr>() {
+                Ok(ip) => Some(ip),
+                Err(_) => dns_resolve(&arg(0)),
+            };
+            let (Some(ip), Ok(pattern), Ok(mask)) = (ip, arg(1).parse::<Ipv4Addr>(), arg(2).parse::<Ipv4Addr>()) else {
+                return Some(Value::Bool(false));
+            };
+            let mask = u32::from(mask);
+            Value::Bool(u32::from(ip) & mask == u32::from(pattern) & mask)
+        }
+        "weekdayRange" => {
+            let (args, now) = split_gmt(args);
+            Value::Bool(weekday_range(args, now))
+        }
+        "dateRange" => {
+            let (args, now) = split_gmt(args);
+            Value::Bool(date_range(args, now))
+        }
+        "timeRange" => {
+            let (args, now) = split_gmt(args);
+            Value::Bool(time_range(args, now))
+        }
+        _ => return None,
+    };
+
+    Some(ret)
+}
+
+fn my_ip_address() -> IpAddr {
+    // No packet is sent, connecting an udp socket only selects the outgoing interface
+    UdpSocket::bind("0.0.0.0:0")
+        .and_then(|sock| sock.connect("198.51.100.1:53").map(|_| sock))
+        .and_then(|sock| sock.local_addr())
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::from([127, 0, 0, 1]))
+}
+
+/// Shell expression matching, only `*` and `?` wildcards are supported.
+/// On a mismatch, only the last `*` is retried one character further, so it is linear in practice
+fn sh_exp_match(input: &[u8], pattern: &[u8]) -> bool {
+    let (mut i, mut p) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+    while i < input.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                last_star = Some((p, i));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == input[i] => {
+                i += 1;
+                p += 1;
+            }
+            _ => match last_star {
+                Some((star, matched)) => {
+                    last_star = Some((star, matched + 1));
+                    p = star + 1;
+                    i = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p.min(pattern.len())..].iter().all(|c| *c == b'*')
+}
+
+/// The arguments without the trailing "GMT", and the current time in UTC if it was there, else in the local timezone
+fn split_gmt(args: &[Value]) -> (&[Value], NaiveDateTime) {
+    match args.split_last() {
+        Some((Value::Str(tz), args)) if tz.eq_ignore_ascii_case("GMT") => (args, Utc::now().naive_utc()),
+        _ => (args, Local::now().naive_local()),
+    }
+}
+
+/// Inclusive range, wrapping around when the start is after the end, i.e: from friday to monday
+fn in_range<T: PartialOrd>(start: T, value: T, end: T) -> bool {
+    if start <= end {
+        start <= value && value <= end
+    } else {
+        start <= value || value <= end
+    }
+}
+
+/// weekdayRange(wd1 [, wd2]), with the days as "SUN", "MON", ...
+fn weekday_range(args: &[Value], now: NaiveDateTime) -> bool {
+    let weekday = |day: &Value| WEEKDAYS.iter().position(|d| day.as_string().eq_ignore_ascii_case(d));
+    let today = now.weekday().num_days_from_sunday() as usize;
+    match args {
+        [day] => weekday(day) == Some(today),
+        [first, last] => match (weekday(first), weekday(last)) {
+            (Some(first), Some(last)) => in_range(first, today, last),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Day of the month, month or year of an argument of dateRange. Ordered from the most significant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum DatePart {
+    Year(i32),
+    Month(u32),
+    Day(u32),
+}
+
+impl DatePart {
+    fn parse(value: &Value) -> Option<Self> {
+        if let Value::Str(month) = value {
+            if let Some(month) = MONTHS.iter().position(|m| month.eq_ignore_ascii_case(m)) {
+                return Some(Self::Month(month as u32 + 1));
+            }
+        }
+        match value.as_number() {
+            n if n.fract() != 0.0 => None,
+            n if (1.0..=31.0).contains(&n) => Some(Self::Day(n as u32)),
+            n if (1000.0..=9999.0).contains(&n) => Some(Self::Year(n as i32)),
+            _ => None,
+        }
+    }
+
+    /// The same part of the date
+    fn of(self, now: NaiveDateTime) -> Self {
+        match self {
+            Self::Year(_) => Self::Year(now.year()),
+            Self::Month(_) => Self::Month(now.month()),
+            Self::Day(_) => Self::Day(now.day()),
+        }
+    }
+}
+
+/// dateRange with a day, month or year, or a range of them with the same parts for its start and end,
+/// i.e: (day1, day2), (month1, year1, month2, year2) or (day1, month1, year1, day2, month2, year2)
+fn date_range(args: &[Value], now: NaiveDateTime) -> bool {
+    let Some(mut parts) = args.iter().map(DatePart::parse).collect::<Option<Vec<_>>>() else {
+        return false;
+    };
+    if let [part] = parts.as_slice() {
+        return *part == part.of(now);
+    }
+    if parts.is_empty() || parts.len() % 2 != 0 || parts.len() > 6 {
+        return false;
+    }
+
+    let mut end = parts.split_off(parts.len() / 2);
+    let mut start = parts;
+    start.sort();
+    end.sort();
+    // The start and the end have the same parts, each one at most once
+    let kind = std::mem::discriminant::<DatePart>;
+    if start.iter().map(kind).ne(end.iter().map(kind))
+        || start.windows(2).any(|parts| kind(&parts[0]) == kind(&parts[1]))
+    {
+        return false;
+    }
+    let today: Vec<DatePart> = start.iter().map(|part| part.of(now)).collect();
+
+    // Only the ranges without a year can wrap around the end of the year
+    if start.iter().any(|part| matches!(part, DatePart::Year(_))) {
+        start <= today && today <= end
+    } else {
+        in_range(start, today, end)
+    }
+}
+
+/// timeRange(hour1 [, hour2]), (hour1, min1, hour2, min2) or (hour1, min1, sec1, hour2, min2, sec2).
+/// The end is inclusive: timeRange(12, 13) is true from 12:00:00 to 13:59:59
+fn time_range(args: &[Value], now: NaiveDateTime) -> bool {
+    let Some(args) = args
+        .iter()
+        .map(|arg| arg.as_number())
+        .map(|n| (n.fract() == 0.0 && (0.0..60.0).contains(&n)).then_some(n as u32))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return false;
+    };
+
+    let seconds = |hour: u32, minute: u32, second: u32| hour * 3600 + minute * 60 + second;
+    let (start, end) = match args.as_slice() {
+        [hour] => return now.hour() == *hour,
+        [h1, h2] => (seconds(*h1, 0, 0), seconds(*h2, 59, 59)),
+        [h1, m1, h2, m2] => (seconds(*h1, *m1, 0), seconds(*h2, *m2, 59)),
+        [h1, m1, s1, h2, m2, s2] => (seconds(*h1, *m1, *s1), seconds(*h2, *m2, *s2)),
+        _ => return false,
+    };
+
+    in_range(start, seconds(now.hour(), now.minute(), now.second()), end)
+}
+
+/// PAC file parsed once, to find the proxies of every connection
+pub struct PacScript(Script);
+
+impl PacScript {
+    pub fn parse(script: &str) -> anyhow::Result<Self> {
+        Script::parse(script).map(Self).context("Error while parsing PAC file")
+    }
+
+    /// Evaluate the script and returns the proxies to use, in order of preference, to reach the given url.
+    /// This is a blocking call, as PAC scripts are allowed to do synchronous DNS resolutions with dns_resolve.
+    /// Only the subset of javascript commonly used by PAC files is supported (see [`Interpreter`])
+    pub fn find_proxy_for_url(&self, url: &Url, dns_resolve: PacDnsResolve) -> anyhow::Result<Vec<PacProxy>> {
+        let host = url
+            .host_str()
+            .context("Cannot evaluate PAC file for an url without host")?;
+
+        let builtins = |name: &str, args: &[Value]| pac_builtins(name, args, dns_resolve);
+        let mut interpreter = Interpreter::new(&self.0, &builtins).context("Error while evaluating PAC file")?;
+        let ret = interpreter
+            .call(
+                "FindProxyForURL",
+                &[Value::Str(url.as_str().to_string()), Value::Str(host.to_string())],
+            )
+            .context("Error while evaluating PAC file")?;
+
+        parse_pac_result(&ret.as_string())
+    }
+}
+
+/// Parse the string returned by FindProxyForURL, i.e: "PROXY proxy.corp:3128; SOCKS5 10.0.0.1:1080; DIRECT"
+/// Unsupported entries are skipped. An empty result means direct connection.
+fn parse_pac_result(result: &str) -> anyhow::Result<Vec<PacProxy>> {
+    let mut proxies = Vec::new();
+    for entry in result.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut parts = entry.split_whitespace();
+        let kind = parts.next().unwrap_or_default().to_ascii_uppercase();
+        let addr = parts.next();
+        let proxy = match (kind.as_str(), addr) {
+            ("DIRECT", _) => PacProxy::Direct,
+            ("PROXY" | "HTTP", Some(addr)) => PacProxy::Proxy(
+                Url::parse(&format!("http://{}", addr))
+                    .with_context(|| format!("Invalid PAC proxy entry {}", entry))?,
+            ),
+            ("SOCKS" | "SOCKS5", Some(addr)) => PacProxy::Proxy(
+                Url::parse(&format!("socks5://{}", addr))
+                    .with_context(|| format!("Invalid PAC proxy entry {}", entry))?,
+            ),
+            _ => {
+                warn!("Unsupported PAC entry {}, skipping it", entry);
+                continue;
+            }
+        };
+        proxies.push(proxy);
+    }
+
+    if proxies.is_empty() {
+        proxies.push(PacProxy::Direct);
+    }
+
+    Ok(proxies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use test_case::test_case;
+
+    fn find_proxy_for_url(script: &str, url: &Url) -> anyhow::Result<Vec<PacProxy>> {
+        let resolve = |host: &str| (host == "intranet").then_some(Ipv4Addr::new(10, 1, 2, 3));
+        PacScript::parse(script)?.find_proxy_for_url(url, &resolve)
+    }
+
+    #[test_case("DIRECT" => vec![PacProxy::Direct] ; "direct")]
+    #[test_case("" => vec![PacProxy::Direct] ; "empty")]
+    #[test_case("PROXY proxy.corp:3128; DIRECT" => vec![PacProxy::Proxy(Url::parse("http://proxy.corp:3128").unwrap()), PacProxy::Direct] ; "proxy with fallback")]
+    #[test_case("SOCKS5 10.0.0.1:1080" => vec![PacProxy::Proxy(Url::parse("socks5://10.0.0.1:1080").unwrap())] ; "socks")]
+    #[test_case("HTTPS proxy.corp:443; DIRECT" => vec![PacProxy::Direct] ; "unsupported entry")]
+    fn test_parse_pac_result(input: &str) -> Vec<PacProxy> {
+        parse_pac_result(input).unwrap()
+    }
+
+    #[test_case("www.example.com", "*.example.com" => true ; "star prefix")]
+    #[test_case("example.com", "*.example.com" => false ; "star needs the dot")]
+    #[test_case("host1.lan", "host?.lan" => true ; "question mark")]
+    #[test_case("http://a/b/c", "*/b/*" => true ; "star in the middle")]
+    #[test_case("abcbc", "*bc" => true ; "star retried")]
+    #[test_case("abc", "abc**" => true ; "trailing stars")]
+    #[test_case("abc", "ab" => false ; "longer input")]
+    #[test_case("", "*" => true ; "empty input")]
+    #[test_case(&"a".repeat(100), &format!("{}b", "*a".repeat(50)) => false ; "backtracking")]
+    fn test_sh_exp_match(input: &str, pattern: &str) -> bool {
+        sh_exp_match(input.as_bytes(), pattern.as_bytes())
+    }
+
+    // Friday 2024-03-15 14:30:15
+    fn now() -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, 15)
+            .unwrap()
+            .and_hms_opt(14, 30, 15)
+            .unwrap()
+    }
+
+    fn values(args: &[&str]) -> Vec<Value> {
+        args.iter()
+            .map(|arg| match arg.parse::<f64>() {
+                Ok(n) => Value::Num(n),
+                Err(_) => Value::Str(arg.to_string()),
+            })
+            .collect()
+    }
+
+    #[test_case(&["FRI"] => true ; "day")]
+    #[test_case(&["MON"] => false ; "other day")]
+    #[test_case(&["MON", "FRI"] => true ; "range")]
+    #[test_case(&["FRI", "MON"] => true ; "range around the weekend")]
+    #[test_case(&["SAT", "THU"] => false ; "outside of the range")]
+    #[test_case(&["FOO"] => false ; "invalid day")]
+    fn test_weekday_range(args: &[&str]) -> bool {
+        weekday_range(&values(args), now())
+    }
+
+    #[test_case(&["15"] => true ; "day")]
+    #[test_case(&["MAR"] => true ; "month")]
+    #[test_case(&["2023"] => false ; "year")]
+    #[test_case(&["1", "15"] => true ; "day range")]
+    #[test_case(&["NOV", "FEB"] => false ; "month range around the new year")]
+    #[test_case(&["OCT", "MAR"] => true ; "month range ending this month")]
+    #[test_case(&["16", "MAR", "1", "APR"] => false ; "day and month range")]
+    #[test_case(&["MAR", "2023", "FEB", "2024"] => false ; "month and year range")]
+    #[test_case(&["1", "JAN", "2024", "31", "DEC", "2024"] => true ; "full range")]
+    #[test_case(&["1", "JAN", "MAR", "2024"] => false ; "different parts")]
+    #[test_case(&["1", "2", "3", "4"] => false ; "repeated part")]
+    fn test_date_range(args: &[&str]) -> bool {
+        date_range(&values(args), now())
+    }
+
+    #[test_case(&["14"] => true ; "hour")]
+    #[test_case(&["9", "14"] => true ; "hour range")]
+    #[test_case(&["22", "6"] => false ; "hour range around midnight")]
+    #[test_case(&["14", "0", "14", "30"] => true ; "minute range")]
+    #[test_case(&["14", "31", "15", "0"] => false ; "later minute range")]
+    #[test_case(&["14", "30", "0", "14", "30", "10"] => false ; "second range")]
+    #[test_case(&["14", "foo"] => false ; "invalid hour")]
+    fn test_time_range(args: &[&str]) -> bool {
+        time_range(&values(args), now())
+    }
+
+    #[test]
+    fn test_split_gmt() {
+        let with_gmt = values(&["MON", "FRI", "GMT"]);
+        assert_eq!(split_gmt(&with_gmt).0, values(&["MON", "FRI"]));
+        let without_gmt = values(&["MON", "FRI"]);
+        assert_eq!(split_gmt(&without_gmt).0, without_gmt);
+    }
+
+    #[test]
+    fn test_find_proxy_for_url() {
+        let script = r#"
+            function FindProxyForURL(url, host) {
+                if (isPlainHostName(host) || shExpMatch(host, "*.local")) return "DIRECT";
+                if (dnsDomainIs(host, ".corp.com")) return "PROXY proxy.corp.com:3128; DIRECT";
+                return "SOCKS5 127.0.0.1:1080";
+            }
+        "#;
+
+        let proxies = find_proxy_for_url(script, &Url::parse("https://server/").unwrap()).unwrap();
+        assert_eq!(proxies, vec![PacProxy::Direct]);
+        let proxies = find_proxy_for_url(script, &Url::parse("https://printer.local/").unwrap()).unwrap();
+        assert_eq!(proxies, vec![PacProxy::Direct]);
+        let proxies = find_proxy_for_url(script, &Url::parse("https://ws.corp.com:443/").unwrap()).unwrap();
+        assert_eq!(
+            proxies,
+            vec![
+                PacProxy::Proxy(Url::parse("http://proxy.corp.com:3128").unwrap()),
+                PacProxy::Direct
+            ]
+        );
+        let proxies = find_proxy_for_url(script, &Url::parse("wss://example.com/").unwrap()).unwrap();
+        assert_eq!(proxies, vec![PacProxy::Proxy(Url::parse("socks5://127.0.0.1:1080").unwrap())]);
+    }
+
+    #[test]
+    fn test_is_in_net() {
+        let script = r#"
+            function FindProxyForURL(url, host) {
+                return isInNet(host, "10.0.0.0", "255.0.0.0") ? "DIRECT" : "PROXY proxy:8080";
+            }
+        "#;
+        let proxies = find_proxy_for_url(script, &Url::parse("https://10.2.3.4/").unwrap()).unwrap();
+        assert_eq!(proxies, vec![PacProxy::Direct]);
+        let proxies = find_proxy_for_url(script, &Url::parse("https://192.168.1.1/").unwrap()).unwrap();
+        assert_eq!(proxies, vec![PacProxy::Proxy(Url::parse("http://proxy:8080").unwrap())]);
+        // The hosts are resolved with the resolver of the client
+        let proxies = find_proxy_for_url(script, &Url::parse("https://intranet/").unwrap()).unwrap();
+        assert_eq!(proxies, vec![PacProxy::Direct]);
+    }
+}
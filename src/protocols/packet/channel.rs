@@ -0,0 +1,289 @@
+use super::destination;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex, OwnedMutexGuard};
+use tracing::debug;
+
+/// Each packet is sent in the tunnel after its length, as an u16 in big endian
+const LENGTH_SIZE: usize = 2;
+
+/// Channel of the ip packets of a tun tunnel: the queue is given to what reads and writes the packets, i.e: the
+/// tun interface of a mobile VPN app, and the device to the tunnel
+pub fn channel(capacity: usize) -> (PacketQueue, PacketDevice) {
+    let (outbound_tx, outbound_rx) = mpsc::channel(capacity);
+    let (inbound_tx, inbound_rx) = mpsc::channel(capacity);
+    let queue = PacketQueue {
+        sender: PacketSender { outbound: outbound_tx },
+        receiver: PacketReceiver { inbound: inbound_rx },
+    };
+    let device = PacketDevice {
+        outbound: Arc::new(Mutex::new(outbound_rx)),
+        inbound: inbound_tx,
+    };
+
+    (queue, device)
+}
+
+/// Side of the channel of the program reading and writing the packets
+pub struct PacketQueue {
+    sender: PacketSender,
+    receiver: PacketReceiver,
+}
+
+impl PacketQueue {
+    /// Send the packet in the tunnel, waiting for room in the channel. Err once the tunnel is stopped
+    pub async fn send(&self, packet: Bytes) -> anyhow::Result<()> {
+        self.sender.send(packet).await
+    }
+
+    /// Send the packet in the tunnel, or drop it if the channel is full like a network interface does.
+    /// Err once the tunnel is stopped
+    pub fn try_send(&self, packet: Bytes) -> anyhow::Result<()> {
+        self.sender.try_send(packet)
+    }
+
+    /// Next packet received from the tunnel. None once the tunnel is stopped
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.receiver.recv().await
+    }
+
+    /// Same as recv, without waiting. None if there is no packet
+    pub fn try_recv(&mut self) -> Option<Bytes> {
+        self.receiver.try_recv()
+    }
+
+    /// Sender of the packets to the tunnel, for another task than the one receiving them
+    #[cfg_attr(not(target_os = "linux"), expect(dead_code))]
+    pub(crate) fn sender(&self) -> mpsc::Sender<Bytes> {
+        self.sender.outbound.clone()
+    }
+
+    /// The halves sending and receiving the packets, so one thread can wait for the packets of the tunnel while
+    /// another one sends packets in it
+    pub fn into_split(self) -> (PacketSender, PacketReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+/// Sending half of the queue, see PacketQueue::into_split
+#[derive(Clone)]
+pub struct PacketSender {
+    outbound: mpsc::Sender<Bytes>,
+}
+
+impl PacketSender {
+    /// Same as PacketQueue::send
+    pub async fn send(&self, packet: Bytes) -> anyhow::Result<()> {
+        self.outbound
+            .send(packet)
+            .await
+            .map_err(|_| anyhow::anyhow!("The tun tunnel is stopped"))
+    }
+
+    /// Same as PacketQueue::try_send
+    pub fn try_send(&self, packet: Bytes) -> anyhow::Result<()> {
+        match self.outbound.try_send(packet) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Closed(_)) => Err(anyhow::anyhow!("The tun tunnel is stopped")),
+        }
+    }
+}
+
+/// Receiving half of the queue, see PacketQueue::into_split
+pub struct PacketReceiver {
+    inbound: mpsc::Receiver<Bytes>,
+}
+
+impl PacketReceiver {
+    /// Same as PacketQueue::recv
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.inbound.recv().await
+    }
+
+    /// Same as PacketQueue::try_recv
+    pub fn try_recv(&mut self) -> Option<Bytes> {
+        self.inbound.try_recv().ok()
+    }
+}
+
+/// Side of the channel of the tunnel. It is kept across the connections to the server, only one of them uses it
+/// at a time
+#[derive(Clone)]
+pub struct PacketDevice {
+    outbound: Arc<Mutex<mpsc::Receiver<Bytes>>>,
+    inbound: mpsc::Sender<Bytes>,
+}
+
+impl PacketDevice {
+    /// The packets of the queue as a stream, each one after its length, and the stream of the packets to give back.
+    /// Waits for the previous connection to release them
+    pub(crate) async fn streams(&self) -> (PacketReader, PacketWriter) {
+        let outbound = self.outbound.clone().lock_owned().await;
+        let reader = PacketReader {
+            packets: outbound,
+            frame: Bytes::new(),
+        };
+        let writer = PacketWriter {
+            packets: self.inbound.clone(),
+            buffer: BytesMut::new(),
+            allow_destination: None,
+        };
+
+        (reader, writer)
+    }
+
+    /// True once the queue is dropped, the tunnel must stop
+    pub(crate) fn is_closed(&self) -> bool {
+        self.inbound.is_closed()
+    }
+}
+
+pub struct PacketReader {
+    packets: OwnedMutexGuard<mpsc::Receiver<Bytes>>,
+    frame: Bytes,
+}
+
+impl AsyncRead for PacketReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.frame.is_empty() {
+            let Some(packet) = ready!(this.packets.poll_recv(cx)) else {
+                return Poll::Ready(Ok(()));
+            };
+            let Ok(len) = u16::try_from(packet.len()) else {
+                debug!("Dropping a packet of {} bytes, too large for the tunnel", packet.len());
+                continue;
+            };
+            let mut frame = BytesMut::with_capacity(LENGTH_SIZE + packet.len());
+            frame.put_u16(len);
+            frame.put(packet);
+            this.frame = frame.freeze();
+        }
+
+        let len = this.frame.len().min(buf.remaining());
+        buf.put_slice(&this.frame.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct PacketWriter {
+    packets: mpsc::Sender<Bytes>,
+    buffer: BytesMut,
+    allow_destination: Option<Box<dyn Fn(IpAddr) -> bool + Send>>,
+}
+
+impl PacketWriter {
+    /// Drop the packets written to a destination which is not allowed, or without a destination
+    pub(crate) fn filter_destinations(mut self, allow: impl Fn(IpAddr) -> bool + Send + 'static) -> Self {
+        self.allow_destination = Some(Box::new(allow));
+        self
+    }
+}
+
+impl AsyncWrite for PacketWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.buffer.extend_from_slice(buf);
+        while this.buffer.len() >= LENGTH_SIZE {
+            let len = u16::from_be_bytes([this.buffer[0], this.buffer[1]]) as usize;
+            if this.buffer.len() < LENGTH_SIZE + len {
+                break;
+            }
+            this.buffer.advance(LENGTH_SIZE);
+            let packet = this.buffer.split_to(len).freeze();
+            if let Some(allow) = &this.allow_destination {
+                if !destination(&packet).is_some_and(allow) {
+                    debug!("Dropping a packet of the tun tunnel to a destination which is not allowed");
+                    continue;
+                }
+            }
+            // The packets are dropped when the queue is full, instead of slowing down all the tunnel
+            match this.packets.try_send(packet) {
+                Ok(()) | Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Closed(_)) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            }
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_packets_framing() {
+        let (mut queue, device) = channel(8);
+        let (mut reader, mut writer) = device.streams().await;
+
+        queue.send(Bytes::from_static(b"packet")).await.unwrap();
+        let mut frame = [0u8; 8];
+        reader.read_exact(&mut frame).await.unwrap();
+        assert_eq!(&frame, b"\x00\x06packet");
+
+        // The frames can be split in any way by the transport
+        writer.write_all(b"\x00\x03ab").await.unwrap();
+        assert_eq!(queue.try_recv(), None);
+        writer.write_all(b"c\x00\x01d\x00").await.unwrap();
+        assert_eq!(queue.recv().await.unwrap(), Bytes::from_static(b"abc"));
+        assert_eq!(queue.recv().await.unwrap(), Bytes::from_static(b"d"));
+
+        drop(queue);
+        assert!(device.is_closed());
+        assert_eq!(reader.read(&mut frame).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_filter_destinations() {
+        let (mut queue, device) = channel(8);
+        let (_reader, writer) = device.streams().await;
+        let mut writer = writer.filter_destinations(|ip| ip == IpAddr::from([10, 0, 0, 1]));
+
+        let mut packet = [0u8; 20];
+        packet[0] = 0x45;
+        packet[16..].copy_from_slice(&[10, 0, 0, 3]);
+        writer.write_all(b"\x00\x14").await.unwrap();
+        writer.write_all(&packet).await.unwrap();
+        packet[16..].copy_from_slice(&[10, 0, 0, 1]);
+        writer.write_all(b"\x00\x14").await.unwrap();
+        writer.write_all(&packet).await.unwrap();
+        // Without a destination
+        writer.write_all(b"\x00\x01d").await.unwrap();
+
+        assert_eq!(queue.recv().await.unwrap(), Bytes::copy_from_slice(&packet));
+        assert_eq!(queue.try_recv(), None);
+    }
+
+    #[tokio::test]
+    async fn test_split_queue() {
+        let (queue, device) = channel(8);
+        let (mut reader, mut writer) = device.streams().await;
+        let (sender, mut receiver) = queue.into_split();
+
+        // Waiting for a packet does not prevent sending one
+        let waiting = tokio::spawn(async move { receiver.recv().await });
+        sender.try_send(Bytes::from_static(b"ping")).unwrap();
+        let mut frame = [0u8; 6];
+        reader.read_exact(&mut frame).await.unwrap();
+        assert_eq!(&frame, b"\x00\x04ping");
+
+        writer.write_all(b"\x00\x04pong").await.unwrap();
+        assert_eq!(waiting.await.unwrap(), Some(Bytes::from_static(b"pong")));
+    }
+}
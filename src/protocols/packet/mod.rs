@@ -0,0 +1,73 @@
+//! Ip packets of the tun tunnels. They are carried in the stream of the tunnel, each one after its length
+
+mod channel;
+#[cfg(target_os = "linux")]
+mod tun;
+
+pub use channel::{channel, PacketDevice, PacketQueue, PacketReceiver, PacketSender};
+#[cfg(target_os = "linux")]
+pub use tun::{TunInterface, TunNetwork};
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Network of the ip packets of the tun tunnels on the server, i.e: an interface routing them
+pub trait PacketNetwork: Send + Sync + 'static {
+    /// Device exchanging the packets of the client using this address in its tunnel. Called again when the client
+    /// reconnects, an address still used by the tunnel of another client must be rejected
+    fn attach(&self, client_ip: IpAddr) -> anyhow::Result<PacketDevice>;
+}
+
+/// Source address of the ipv4 or ipv6 packet. None if it is truncated
+pub fn source(packet: &[u8]) -> Option<IpAddr> {
+    address(packet, 12, 8)
+}
+
+/// Destination address of the ipv4 or ipv6 packet. None if it is truncated
+pub fn destination(packet: &[u8]) -> Option<IpAddr> {
+    address(packet, 16, 24)
+}
+
+fn address(packet: &[u8], ipv4_offset: usize, ipv6_offset: usize) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => {
+            let bytes: [u8; 4] = packet.get(ipv4_offset..ipv4_offset + 4)?.try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(bytes)))
+        }
+        6 => {
+            let bytes: [u8; 16] = packet.get(ipv6_offset..ipv6_offset + 16)?.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(bytes)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const IPV4_PACKET: [u8; 20] = [0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 2, 1, 1, 1, 1];
+
+    #[test_case(&IPV4_PACKET => Some("10.0.0.2".parse().unwrap()) ; "ipv4")]
+    #[test_case(&ipv6_packet() => Some("fd00::2".parse().unwrap()) ; "ipv6")]
+    #[test_case(&IPV4_PACKET[..15] => None ; "truncated")]
+    #[test_case(&[0x15, 0, 0] => None ; "unknown version")]
+    #[test_case(&[] => None ; "empty")]
+    fn test_source(packet: &[u8]) -> Option<IpAddr> {
+        source(packet)
+    }
+
+    #[test_case(&IPV4_PACKET => Some("1.1.1.1".parse().unwrap()) ; "ipv4")]
+    #[test_case(&ipv6_packet() => Some("2606:4700::1111".parse().unwrap()) ; "ipv6")]
+    #[test_case(&ipv6_packet()[..39] => None ; "truncated")]
+    fn test_destination(packet: &[u8]) -> Option<IpAddr> {
+        destination(packet)
+    }
+
+    fn ipv6_packet() -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+        packet.extend_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&"2606:4700::1111".parse::<Ipv6Addr>().unwrap().octets());
+        packet
+    }
+}
@@ -0,0 +1,288 @@
+use super::{channel, destination, source, PacketDevice, PacketNetwork, PacketQueue};
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use ipnet::IpNet;
+use nix::libc;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, info, warn};
+
+/// Large enough for the packets of any mtu
+const MAX_PACKET_SIZE: usize = u16::MAX as usize;
+
+/// Packets waiting to be sent in the tunnel of a client of the server
+const CLIENT_QUEUE_CAPACITY: usize = 1024;
+
+/// Tun interface of linux, removed by the kernel once closed
+pub struct TunInterface {
+    fd: AsyncFd<OwnedFd>,
+    name: String,
+}
+
+impl TunInterface {
+    /// Create an interface with this address and mtu, and route the networks to it. Requires CAP_NET_ADMIN and the
+    /// ip command
+    pub async fn create(address: IpNet, mtu: u16, routes: &[IpNet]) -> anyhow::Result<Self> {
+        let interface = Self::open().context("Cannot create a tun interface, CAP_NET_ADMIN is required")?;
+        interface
+            .ip(&["addr", "add", &address.to_string(), "dev", &interface.name])
+            .await?;
+        interface
+            .ip(&["link", "set", "dev", &interface.name, "mtu", &mtu.to_string(), "up"])
+            .await?;
+        for route in routes {
+            interface
+                .ip(&["route", "add", &route.to_string(), "dev", &interface.name])
+                .await?;
+        }
+
+        Ok(interface)
+    }
+
+    fn open() -> io::Result<Self> {
+        // SAFETY: the path is a valid c string
+        let fd = unsafe { libc::open(c"/dev/net/tun".as_ptr(), libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the fd has just been opened and is owned by nobody else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: ifreq is plain data, valid when zeroed
+        let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, src) in request.ifr_name.iter_mut().zip(b"wstun%d") {
+            *dst = *src as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        // SAFETY: the request is valid for TUNSETIFF, which writes the name of the new interface in it
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut request) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the name is nul terminated, the kernel does not use all its bytes
+        let name = unsafe { CStr::from_ptr(request.ifr_name.as_ptr()) };
+
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            name: name.to_string_lossy().into_owned(),
+        })
+    }
+
+    async fn ip(&self, args: &[&str]) -> anyhow::Result<()> {
+        let output = Command::new("ip")
+            .args(args)
+            .output()
+            .await
+            .context("Cannot run the ip command to configure the tun interface")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Cannot configure the tun interface with ip {}: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Next packet sent to the interface
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fd
+            .async_io(Interest::READABLE, |fd| {
+                // SAFETY: the buffer is valid for its length
+                let ret = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(ret as usize)
+                }
+            })
+            .await
+    }
+
+    /// Give the packet to the network stack, as received by the interface
+    pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.fd
+            .async_io(Interest::WRITABLE, |fd| {
+                // SAFETY: the packet is valid for its length
+                let ret = unsafe { libc::write(fd.as_raw_fd(), packet.as_ptr().cast(), packet.len()) };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            })
+            .await
+    }
+
+    /// Exchange the packets of the interface with the ones of the tunnel, until the tunnel is stopped
+    pub async fn run(self, mut queue: PacketQueue) -> anyhow::Result<()> {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            tokio::select! {
+                ret = self.recv(&mut buf) => {
+                    let len = ret.with_context(|| format!("Cannot read from the tun interface {}", self.name))?;
+                    queue.try_send(Bytes::copy_from_slice(&buf[..len]))?;
+                }
+                packet = queue.recv() => {
+                    let Some(packet) = packet else { return Ok(()) };
+                    if let Err(err) = self.send(&packet).await {
+                        debug!("Dropping a packet of the tunnel refused by {}: {}", self.name, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+type Clients = Arc<Mutex<HashMap<IpAddr, mpsc::Sender<Bytes>>>>;
+
+/// Tun interface of the server for the tun tunnels. The packets of the clients are routed by the system, and the
+/// ones for the address of a client are sent in its tunnel
+pub struct TunNetwork {
+    interface: Arc<TunInterface>,
+    network: IpNet,
+    clients: Clients,
+    _stop: DropGuard,
+}
+
+impl TunNetwork {
+    /// Create the interface with the address of the server in the network of the clients, i.e: 10.0.0.1/24
+    pub async fn create(network: IpNet, mtu: u16) -> anyhow::Result<Self> {
+        let interface = Arc::new(TunInterface::create(network, mtu, &[]).await?);
+        info!(
+            "Tun tunnels use the interface {} with the address {}",
+            interface.name(),
+            network
+        );
+
+        let clients = Clients::default();
+        let stop = CancellationToken::new();
+        let cancel = stop.clone();
+        tokio::spawn({
+            let interface = interface.clone();
+            let clients = clients.clone();
+            async move { cancel.run_until_cancelled(route_to_clients(interface, clients)).await }
+        });
+
+        Ok(Self {
+            interface,
+            network,
+            clients,
+            _stop: stop.drop_guard(),
+        })
+    }
+}
+
+impl PacketNetwork for TunNetwork {
+    fn attach(&self, client_ip: IpAddr) -> anyhow::Result<PacketDevice> {
+        let reserved = [self.network.addr(), self.network.network(), self.network.broadcast()];
+        if !self.network.contains(&client_ip) || reserved.contains(&client_ip) {
+            return Err(anyhow!(
+                "The address {} cannot be used in the network {} of the tun tunnels",
+                client_ip,
+                self.network
+            ));
+        }
+
+        let (mut queue, device) = channel(CLIENT_QUEUE_CAPACITY);
+        let sender = queue.sender();
+        register_client(&self.clients, client_ip, &sender)?;
+
+        let interface = self.interface.clone();
+        let clients = self.clients.clone();
+        tokio::spawn(async move {
+            while let Some(packet) = queue.recv().await {
+                // A client can only use its own address
+                if source(&packet) != Some(client_ip) {
+                    debug!(
+                        "Dropping a packet of the tun tunnel of {} with another source address",
+                        client_ip
+                    );
+                    continue;
+                }
+                if let Err(err) = interface.send(&packet).await {
+                    debug!("Dropping a packet of {} refused by {}: {}", client_ip, interface.name(), err);
+                }
+            }
+
+            let mut clients = clients.lock();
+            if clients
+                .get(&client_ip)
+                .is_some_and(|client| client.same_channel(&sender))
+            {
+                clients.remove(&client_ip);
+            }
+        });
+
+        Ok(device)
+    }
+}
+
+/// The address of a client stays taken until the previous tunnel using it is closed
+fn register_client(clients: &Clients, client_ip: IpAddr, sender: &mpsc::Sender<Bytes>) -> anyhow::Result<()> {
+    let mut clients = clients.lock();
+    if clients.get(&client_ip).is_some_and(|client| !client.is_closed()) {
+        return Err(anyhow!("The address {} is already used by another tun tunnel", client_ip));
+    }
+    clients.insert(client_ip, sender.clone());
+
+    Ok(())
+}
+
+async fn route_to_clients(interface: Arc<TunInterface>, clients: Clients) {
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let len = match interface.recv(&mut buf).await {
+            Ok(len) => len,
+            Err(err) => {
+                warn!("Cannot read from the tun interface {}: {}", interface.name(), err);
+                return;
+            }
+        };
+        let packet = &buf[..len];
+        let Some(client_ip) = destination(packet) else {
+            continue;
+        };
+        // Dropped when the tunnel of the client is full, like by a network interface
+        if let Some(client) = clients.lock().get(&client_ip) {
+            let _ = client.try_send(Bytes::copy_from_slice(packet));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_client() {
+        let clients = Clients::default();
+        let client_ip = IpAddr::from([10, 0, 0, 2]);
+        let (sender, receiver) = mpsc::channel(1);
+        register_client(&clients, client_ip, &sender).unwrap();
+
+        // The address cannot be taken over while its tunnel is open
+        let (other_sender, _other_receiver) = mpsc::channel(1);
+        assert!(register_client(&clients, client_ip, &other_sender).is_err());
+        assert!(clients.lock()[&client_ip].same_channel(&sender));
+        register_client(&clients, IpAddr::from([10, 0, 0, 3]), &other_sender).unwrap();
+
+        drop(receiver);
+        register_client(&clients, client_ip, &other_sender).unwrap();
+        assert!(clients.lock()[&client_ip].same_channel(&other_sender));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::protocols::gssapi::GssServerContext;
+use anyhow::{anyhow, Context};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+const SOCKS5_VERSION: u8 = 0x05;
+const GSSAPI_METHOD: u8 = 0x01;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+
+const GSSAPI_VERSION: u8 = 0x01;
+const MSG_AUTHENTICATION: u8 = 0x01;
+const MSG_PROTECTION: u8 = 0x02;
+const MSG_ABORT: u8 = 0xff;
+
+// Not part of RFC 1961, but the de facto value used by clients (i.e: curl, dante) to not encapsulate the traffic
+const PROTECTION_LEVEL_CLEAR: u8 = 0x00;
+
+/// Perform the method negotiation and the GSSAPI authentication of RFC 1961 on a new socks5 connection.
+/// Only the clear protection level is supported, traffic is not encapsulated once the client is authenticated.
+/// Returns the kerberos principal of the client
+pub async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    service: Option<&str>,
+) -> anyhow::Result<String> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS5_VERSION {
+        return Err(anyhow!("Unsupported socks version {}", header[0]));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let ctx = if methods.contains(&GSSAPI_METHOD) {
+        GssServerContext::new(service)
+    } else {
+        Err(anyhow!("Client does not support GSSAPI authentication, methods {:?}", methods))
+    };
+    let mut ctx = match ctx {
+        Ok(ctx) => ctx,
+        Err(err) => {
+            stream.write_all(&[SOCKS5_VERSION, NO_ACCEPTABLE_METHOD]).await?;
+            return Err(err);
+        }
+    };
+    stream.write_all(&[SOCKS5_VERSION, GSSAPI_METHOD]).await?;
+
+    // Security context establishment
+    while !ctx.is_complete() {
+        let token = read_message(stream, MSG_AUTHENTICATION).await?;
+        let reply = match ctx.step(&token) {
+            Ok(reply) => reply,
+            Err(err) => {
+                stream.write_all(&[GSSAPI_VERSION, MSG_ABORT]).await?;
+                return Err(err);
+            }
+        };
+        if let Some(reply) = reply {
+            write_message(stream, MSG_AUTHENTICATION, &reply).await?;
+        }
+    }
+
+    // Protection level negotiation. Clients in NEC mode send the level unwrapped
+    let token = read_message(stream, MSG_PROTECTION).await?;
+    let is_nec = token.len() == 1;
+    let requested_level = if is_nec { token } else { ctx.unwrap(&token)? };
+    debug!("Socks5 GSSAPI client requested protection level {:?}", requested_level);
+    let reply = if is_nec {
+        vec![PROTECTION_LEVEL_CLEAR]
+    } else {
+        ctx.wrap(&[PROTECTION_LEVEL_CLEAR])?
+    };
+    write_message(stream, MSG_PROTECTION, &reply).await?;
+
+    ctx.client_name()
+}
+
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S, expected_type: u8) -> anyhow::Result<Vec<u8>> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    match header {
+        [GSSAPI_VERSION, MSG_ABORT] => return Err(anyhow!("Client aborted GSSAPI authentication")),
+        [GSSAPI_VERSION, msg_type] if msg_type == expected_type => {}
+        [version, msg_type] => {
+            return Err(anyhow!(
+                "Unexpected GSSAPI message version {} type {}, expected type {}",
+                version,
+                msg_type,
+                expected_type
+            ))
+        }
+    }
+
+    let len = stream.read_u16().await?;
+    let mut token = vec![0u8; len as usize];
+    stream
+        .read_exact(&mut token)
+        .await
+        .with_context(|| "Cannot read GSSAPI token")?;
+
+    Ok(token)
+}
+
+async fn write_message<S: AsyncWrite + Unpin>(stream: &mut S, msg_type: u8, token: &[u8]) -> anyhow::Result<()> {
+    let len = u16::try_from(token.len()).map_err(|_| anyhow!("GSSAPI token is too big"))?;
+    let mut msg = Vec::with_capacity(token.len() + 4);
+    msg.extend_from_slice(&[GSSAPI_VERSION, msg_type]);
+    msg.extend_from_slice(&len.to_be_bytes());
+    msg.extend_from_slice(token);
+    stream.write_all(&msg).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reject_client_without_gssapi() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[SOCKS5_VERSION, 2, 0x00, 0x02]).await.unwrap();
+
+        assert!(authenticate(&mut server, None).await.is_err());
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [SOCKS5_VERSION, NO_ACCEPTABLE_METHOD]);
+    }
+
+    #[tokio::test]
+    async fn test_message_framing() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        write_message(&mut client, MSG_AUTHENTICATION, b"token").await.unwrap();
+        assert_eq!(read_message(&mut server, MSG_AUTHENTICATION).await.unwrap(), b"token");
+
+        write_message(&mut client, MSG_PROTECTION, &[0]).await.unwrap();
+        assert!(read_message(&mut server, MSG_AUTHENTICATION).await.is_err());
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[GSSAPI_VERSION, MSG_ABORT]).await.unwrap();
+        assert!(read_message(&mut server, MSG_AUTHENTICATION).await.is_err());
+    }
+}
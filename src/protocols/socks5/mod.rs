@@ -1,3 +1,5 @@
+#[cfg(unix)]
+mod gssapi_auth;
 mod tcp_server;
 mod udp_server;
 
@@ -1,13 +1,15 @@
 use super::udp_server::{Socks5UdpStream, Socks5UdpStreamWriter};
+use crate::tunnel::active_tunnels::FailureReason;
 use crate::tunnel::LocalProtocol;
 use anyhow::Context;
-use fast_socks5::server::{Config, DenyAuthentication, SimpleUserPassword, Socks5Server};
+use fast_socks5::server::{Config, DenyAuthentication, SimpleUserPassword, Socks5Server, Socks5Socket};
 use fast_socks5::util::target_addr::TargetAddr;
 use fast_socks5::{consts, ReplyError};
 use futures_util::{stream, Stream, StreamExt};
 use std::io::{Error, IoSlice};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Poll;
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
@@ -58,6 +60,53 @@ impl Socks5Stream {
     }
 }
 
+impl Socks5ReadHalf {
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(s) => s.peer_addr().ok(),
+            Self::Udp(s) => Some(s.peer_addr()),
+        }
+    }
+}
+
+impl Socks5WriteHalf {
+    /// Send the reply of a CONNECT request, once the tunnel toward the destination is established or could not be.
+    /// `bound_addr` is the address used to connect to the destination, it is reported as BND.ADDR/BND.PORT.
+    /// Nothing is sent for UDP streams, as the UDP ASSOCIATE request has already been answered.
+    pub async fn send_reply(mut self, established: Result<Option<SocketAddr>, FailureReason>) -> std::io::Result<Self> {
+        if let Self::Tcp(writer) = &mut self {
+            let (reply, bound_addr) = match established {
+                Ok(bound_addr) => (ReplyError::Succeeded, bound_addr),
+                Err(reason) => (failure_reply(reason), None),
+            };
+            let bound_addr = bound_addr.unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0));
+            writer.write_all(&new_reply(&reply, bound_addr)).await?;
+        }
+
+        Ok(self)
+    }
+}
+
+/// REP of the reply to a CONNECT request whose tunnel could not be opened
+const fn failure_reply(reason: FailureReason) -> ReplyError {
+    match reason {
+        FailureReason::Refused => ReplyError::ConnectionRefused,
+        FailureReason::Dns | FailureReason::Timeout => ReplyError::HostUnreachable,
+        FailureReason::Restriction => ReplyError::ConnectionNotAllowed,
+        FailureReason::Tls | FailureReason::Other => ReplyError::GeneralFailure,
+    }
+}
+
+/// Address of the udp server given to the clients doing an UDP ASSOCIATE.
+/// When listening on all interfaces, use the address the client reached us on, as not all clients
+/// understand that an unspecified address means the same host as the socks5 server (i.e: reverse socks5 exposed on 0.0.0.0)
+fn udp_relay_addr(bind: SocketAddr, local_addr: Option<SocketAddr>) -> SocketAddr {
+    match local_addr {
+        Some(local_addr) if bind.ip().is_unspecified() => SocketAddr::new(local_addr.ip().to_canonical(), bind.port()),
+        _ => bind,
+    }
+}
+
 impl Stream for Socks5Listener {
     type Item = anyhow::Result<(Socks5Stream, (Host, u16))>;
 
@@ -66,15 +115,25 @@ impl Stream for Socks5Listener {
     }
 }
 
+const GSSAPI_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `gssapi` enables kerberos authentication (RFC 1961) instead of login/password. It contains the service name to
+/// accept tickets for (i.e: rcmd@proxy.corp), an empty one accepts any principal of the keytab.
 pub async fn run_server(
     bind: SocketAddr,
     timeout: Option<Duration>,
     credentials: Option<(String, String)>,
+    gssapi: Option<String>,
 ) -> Result<Socks5Listener, anyhow::Error> {
     info!(
-        "Starting SOCKS5 server listening cnx on {} with credentials {:?}",
-        bind, credentials
+        "Starting SOCKS5 server listening cnx on {} with credentials of user {:?} gssapi {:?}",
+        bind,
+        credentials.as_ref().map(|(user, _)| user),
+        gssapi
     );
+    if cfg!(not(unix)) && gssapi.is_some() {
+        return Err(anyhow::anyhow!("GSSAPI authentication for socks5 is only supported on unix"));
+    }
 
     let server = Socks5Server::<DenyAuthentication>::bind(bind)
         .await
@@ -92,99 +151,122 @@ pub async fn run_server(
     cfg.set_dns_resolve(false);
     cfg.set_execute_command(false);
     cfg.set_udp_support(true);
+    // With GSSAPI, authentication is done by us before handing over the connection to fast_socks5
+    let mut gssapi_cfg = Config::<SimpleUserPassword>::default();
+    gssapi_cfg.set_skip_auth(true);
+    gssapi_cfg.set_dns_resolve(false);
+    gssapi_cfg.set_execute_command(false);
+    gssapi_cfg.set_udp_support(true);
+    let gssapi_cfg = Arc::new(gssapi_cfg);
 
     let udp_server = super::udp_server::run_server(bind, timeout).await?;
     let server = server.with_config(cfg);
-    let stream = stream::unfold((server, Box::pin(udp_server)), move |(server, mut udp_server)| async move {
-        let mut acceptor = server.incoming();
-        loop {
-            let cnx = select! {
-                biased;
-
-                cnx = acceptor.next() => match cnx {
-                    None => return None,
-                    Some(Err(err)) => {
-                        drop(acceptor);
-                        return Some((Err(anyhow::Error::new(err)), (server, udp_server)));
-                    }
-                    Some(Ok(cnx)) => cnx,
-                },
-
-                // new incoming udp stream
-                udp_conn = udp_server.next() => {
-                    drop(acceptor);
-                    return match udp_conn {
-                        Some(Ok(stream)) => {
-                            let dest = stream.destination();
-                            let writer = stream.writer();
-                            Some((Ok((Socks5Stream::Udp((stream, writer)), dest)), (server, udp_server)))
-                        }
+    let stream = stream::unfold((server, Box::pin(udp_server)), move |(server, mut udp_server)| {
+        let gssapi = gssapi.clone();
+        let gssapi_cfg = gssapi_cfg.clone();
+        async move {
+            let mut acceptor = server.incoming();
+            loop {
+                let cnx = select! {
+                    biased;
+
+                    cnx = acceptor.next() => match cnx {
+                        None => return None,
                         Some(Err(err)) => {
-                            Some((Err(anyhow::Error::new(err)), (server, udp_server)))
+                            drop(acceptor);
+                            return Some((Err(anyhow::Error::new(err)), (server, udp_server)));
                         }
-                        None => {
-                            None
-                        }
-                    };
-                }
-            };
-
-            let cnx = match cnx.upgrade_to_socks5().await {
-                Ok(cnx) => cnx,
-                Err(err) => {
-                    warn!("Rejecting socks5 cnx: {}", err);
-                    continue;
-                }
-            };
-
-            let Some(target) = cnx.target_addr() else {
-                warn!("Rejecting socks5 cnx: no target addr");
-                continue;
-            };
+                        Some(Ok(cnx)) => cnx,
+                    },
 
-            let (host, port) = match target {
-                TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
-                TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
-                TargetAddr::Domain(host, port) => (Host::Domain(host.clone()), *port),
-            };
-
-            // Special case for UDP Associate where we return the bind addr of the udp server
-            if matches!(cnx.cmd(), Some(fast_socks5::Socks5Command::UDPAssociate)) {
-                let mut cnx = cnx.into_inner();
-                let ret = cnx.write_all(&new_reply(&ReplyError::Succeeded, bind)).await;
+                    // new incoming udp stream
+                    udp_conn = udp_server.next() => {
+                        drop(acceptor);
+                        return match udp_conn {
+                            Some(Ok(stream)) => {
+                                let dest = stream.destination();
+                                let writer = stream.writer();
+                                Some((Ok((Socks5Stream::Udp((stream, writer)), dest)), (server, udp_server)))
+                            }
+                            Some(Err(err)) => {
+                                Some((Err(anyhow::Error::new(err)), (server, udp_server)))
+                            }
+                            None => {
+                                None
+                            }
+                        };
+                    }
+                };
+
+                let cnx = match &gssapi {
+                    Some(service) => {
+                        let mut stream = cnx.into_inner();
+                        let service = Some(service.as_str()).filter(|s| !s.is_empty());
+                        match tokio::time::timeout(GSSAPI_HANDSHAKE_TIMEOUT, gssapi_authenticate(&mut stream, service))
+                            .await
+                        {
+                            Ok(Ok(principal)) => info!("Socks5 client authenticated with kerberos as {}", principal),
+                            Ok(Err(err)) => {
+                                warn!("Rejecting socks5 cnx: {:?}", err);
+                                continue;
+                            }
+                            Err(_) => {
+                                warn!("Rejecting socks5 cnx: GSSAPI authentication took too long");
+                                continue;
+                            }
+                        }
+                        Socks5Socket::new(stream, gssapi_cfg.clone())
+                    }
+                    None => cnx,
+                };
+
+                let cnx = match cnx.upgrade_to_socks5().await {
+                    Ok(cnx) => cnx,
+                    Err(err) => {
+                        warn!("Rejecting socks5 cnx: {}", err);
+                        continue;
+                    }
+                };
 
-                if let Err(err) = ret {
-                    warn!("Cannot reply to socks5 udp client: {}", err);
+                let Some(target) = cnx.target_addr() else {
+                    warn!("Rejecting socks5 cnx: no target addr");
                     continue;
-                }
-                tokio::spawn(async move {
-                    let mut buf = [0u8; 8];
-                    loop {
-                        match cnx.read(&mut buf).await {
-                            Ok(0) => return,
-                            Err(_) => return,
-                            _ => {}
-                        }
+                };
+
+                let (host, port) = match target {
+                    TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
+                    TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
+                    TargetAddr::Domain(host, port) => (Host::Domain(host.clone()), *port),
+                };
+
+                // Special case for UDP Associate where we return the bind addr of the udp server
+                if matches!(cnx.cmd(), Some(fast_socks5::Socks5Command::UDPAssociate)) {
+                    let mut cnx = cnx.into_inner();
+                    let relay_addr = udp_relay_addr(bind, cnx.local_addr().ok());
+                    let ret = cnx.write_all(&new_reply(&ReplyError::Succeeded, relay_addr)).await;
+
+                    if let Err(err) = ret {
+                        warn!("Cannot reply to socks5 udp client: {}", err);
+                        continue;
                     }
-                });
-                continue;
-            };
+                    tokio::spawn(async move {
+                        let mut buf = [0u8; 8];
+                        loop {
+                            match cnx.read(&mut buf).await {
+                                Ok(0) => return,
+                                Err(_) => return,
+                                _ => {}
+                            }
+                        }
+                    });
+                    continue;
+                };
 
-            let mut cnx = cnx.into_inner();
-            let ret = cnx
-                .write_all(&new_reply(
-                    &ReplyError::Succeeded,
-                    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
-                ))
-                .await;
-
-            if let Err(err) = ret {
-                warn!("Cannot reply to socks5 client: {}", err);
-                continue;
+                // The reply is sent later on with Socks5WriteHalf::send_reply, once we know the bound address
+                let cnx = cnx.into_inner();
+                drop(acceptor);
+                return Some((Ok((Socks5Stream::Tcp(cnx), (host, port))), (server, udp_server)));
             }
-
-            drop(acceptor);
-            return Some((Ok((Socks5Stream::Tcp(cnx), (host, port))), (server, udp_server)));
         }
     });
 
@@ -195,6 +277,16 @@ pub async fn run_server(
     Ok(listener)
 }
 
+#[cfg(unix)]
+async fn gssapi_authenticate(stream: &mut TcpStream, service: Option<&str>) -> anyhow::Result<String> {
+    super::gssapi_auth::authenticate(stream, service).await
+}
+
+#[cfg(not(unix))]
+async fn gssapi_authenticate(_stream: &mut TcpStream, _service: Option<&str>) -> anyhow::Result<String> {
+    Err(anyhow::anyhow!("GSSAPI authentication for socks5 is only supported on unix"))
+}
+
 fn new_reply(error: &ReplyError, sock_addr: SocketAddr) -> Vec<u8> {
     let (addr_type, mut ip_oct, mut port) = match sock_addr {
         SocketAddr::V4(sock) => (
@@ -275,3 +367,25 @@ impl AsyncWrite for Socks5WriteHalf {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("127.0.0.1:1080", Some("127.0.0.1:4242") => "127.0.0.1:1080" ; "bound on an address")]
+    #[test_case("0.0.0.0:1080", Some("192.168.1.2:4242") => "192.168.1.2:1080" ; "bound on all interfaces")]
+    #[test_case("[::]:1080", Some("[::ffff:192.168.1.2]:4242") => "192.168.1.2:1080" ; "bound on all interfaces with ipv4 client")]
+    #[test_case("[::]:1080", None => "[::]:1080" ; "unknown local address")]
+    fn test_udp_relay_addr(bind: &str, local_addr: Option<&str>) -> String {
+        udp_relay_addr(bind.parse().unwrap(), local_addr.map(|addr| addr.parse().unwrap())).to_string()
+    }
+
+    #[test_case(FailureReason::Refused => consts::SOCKS5_REPLY_CONNECTION_REFUSED ; "refused")]
+    #[test_case(FailureReason::Dns => consts::SOCKS5_REPLY_HOST_UNREACHABLE ; "dns")]
+    #[test_case(FailureReason::Restriction => consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED ; "restriction")]
+    #[test_case(FailureReason::Tls => consts::SOCKS5_REPLY_GENERAL_FAILURE ; "tls")]
+    fn test_failure_reply(reason: FailureReason) -> u8 {
+        failure_reply(reason).as_u8()
+    }
+}
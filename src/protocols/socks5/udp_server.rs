@@ -160,6 +160,10 @@ impl Socks5UdpStream {
         }
     }
 
+    pub const fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
     pub fn writer(&self) -> Socks5UdpStreamWriter {
         Socks5UdpStreamWriter {
             send_socket: self.send_socket.clone(),
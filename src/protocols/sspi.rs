@@ -0,0 +1,168 @@
+//! Negotiate (Kerberos, or NTLM as fallback) authentication through the SSPI of windows.
+//! Contrary to the gssapi module, the credentials of the logged-on user are used, nothing needs to be given.
+
+use anyhow::anyhow;
+use std::{iter, ptr};
+use windows_sys::Win32::Foundation::{
+    SEC_E_OK, SEC_I_COMPLETE_AND_CONTINUE, SEC_I_COMPLETE_NEEDED, SEC_I_CONTINUE_NEEDED,
+};
+use windows_sys::Win32::Security::Authentication::Identity::{
+    AcquireCredentialsHandleW, CompleteAuthToken, DeleteSecurityContext, FreeContextBuffer, FreeCredentialsHandle,
+    InitializeSecurityContextW, SecBuffer, SecBufferDesc, ISC_REQ_ALLOCATE_MEMORY, ISC_REQ_MUTUAL_AUTH,
+    SECBUFFER_TOKEN, SECBUFFER_VERSION, SECPKG_CRED_OUTBOUND, SECURITY_NATIVE_DREP,
+};
+use windows_sys::Win32::Security::Credentials::SecHandle;
+
+/// Security context of the initiator side, authenticating with the credentials of the current windows user
+pub struct SspiClientContext {
+    credentials: SecHandle,
+    ctx: Option<SecHandle>,
+    target: Vec<u16>,
+    complete: bool,
+}
+
+// Handles are only owned references to the state kept by the SSPI, they can be moved between threads
+unsafe impl Send for SspiClientContext {}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(iter::once(0)).collect()
+}
+
+impl SspiClientContext {
+    /// `target` is a service principal name, i.e: HTTP/proxy.corp
+    pub fn new(target: &str) -> anyhow::Result<Self> {
+        let package = wide("Negotiate");
+        let mut credentials = SecHandle { dwLower: 0, dwUpper: 0 };
+        let mut expiry: i64 = 0;
+        let status = unsafe {
+            AcquireCredentialsHandleW(
+                ptr::null(),
+                package.as_ptr(),
+                SECPKG_CRED_OUTBOUND,
+                ptr::null(),
+                ptr::null(),
+                None,
+                ptr::null(),
+                &mut credentials,
+                &mut expiry,
+            )
+        };
+        if status != SEC_E_OK {
+            return Err(anyhow!(
+                "Cannot acquire the windows credentials of the current user: error {:#x}",
+                status
+            ));
+        }
+
+        Ok(Self {
+            credentials,
+            ctx: None,
+            target: wide(target),
+            complete: false,
+        })
+    }
+
+    fn ctx_ptr(&self) -> *const SecHandle {
+        self.ctx.as_ref().map_or(ptr::null(), ptr::from_ref)
+    }
+
+    pub const fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Consume the token sent by the acceptor (none for the first call), and produce the next one to send back.
+    pub fn step(&mut self, input_token: Option<&[u8]>) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.complete {
+            return Ok(None);
+        }
+
+        // The SSPI never writes in the input buffer, it only takes a mut pointer
+        let mut input_data = input_token.map(<[u8]>::to_vec).unwrap_or_default();
+        let mut input_buffer = SecBuffer {
+            cbBuffer: input_data.len() as u32,
+            BufferType: SECBUFFER_TOKEN,
+            pvBuffer: input_data.as_mut_ptr().cast(),
+        };
+        let input = SecBufferDesc {
+            ulVersion: SECBUFFER_VERSION,
+            cBuffers: 1,
+            pBuffers: &mut input_buffer,
+        };
+        let mut output_buffer = SecBuffer {
+            cbBuffer: 0,
+            BufferType: SECBUFFER_TOKEN,
+            pvBuffer: ptr::null_mut(),
+        };
+        let mut output = SecBufferDesc {
+            ulVersion: SECBUFFER_VERSION,
+            cBuffers: 1,
+            pBuffers: &mut output_buffer,
+        };
+
+        let mut new_ctx = SecHandle { dwLower: 0, dwUpper: 0 };
+        let mut attributes: u32 = 0;
+        let mut expiry: i64 = 0;
+        let status = unsafe {
+            InitializeSecurityContextW(
+                &self.credentials,
+                self.ctx_ptr(),
+                self.target.as_ptr(),
+                ISC_REQ_MUTUAL_AUTH | ISC_REQ_ALLOCATE_MEMORY,
+                0,
+                SECURITY_NATIVE_DREP,
+                if input_token.is_some() {
+                    ptr::from_ref(&input)
+                } else {
+                    ptr::null()
+                },
+                0,
+                &mut new_ctx,
+                &mut output,
+                &mut attributes,
+                &mut expiry,
+            )
+        };
+        if self.ctx.is_none() && status >= 0 {
+            self.ctx = Some(new_ctx);
+        }
+
+        let completed = if status == SEC_I_COMPLETE_NEEDED || status == SEC_I_COMPLETE_AND_CONTINUE {
+            unsafe { CompleteAuthToken(self.ctx_ptr(), &output) }
+        } else {
+            SEC_E_OK
+        };
+        let token = if output_buffer.pvBuffer.is_null() {
+            vec![]
+        } else {
+            let token = unsafe {
+                std::slice::from_raw_parts(output_buffer.pvBuffer as *const u8, output_buffer.cbBuffer as usize)
+                    .to_vec()
+            };
+            unsafe { FreeContextBuffer(output_buffer.pvBuffer) };
+            token
+        };
+        if status < 0 {
+            return Err(anyhow!("Cannot generate negotiate token: error {:#x}", status));
+        }
+        if completed != SEC_E_OK {
+            return Err(anyhow!("Cannot complete negotiate token: error {:#x}", completed));
+        }
+
+        self.complete = status == SEC_E_OK || status == SEC_I_COMPLETE_NEEDED;
+        if !self.complete && status != SEC_I_CONTINUE_NEEDED && status != SEC_I_COMPLETE_AND_CONTINUE {
+            return Err(anyhow!("Unexpected negotiate status: {:#x}", status));
+        }
+        Ok(if token.is_empty() { None } else { Some(token) })
+    }
+}
+
+impl Drop for SspiClientContext {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(ctx) = &self.ctx {
+                DeleteSecurityContext(ctx);
+            }
+            FreeCredentialsHandle(&self.credentials);
+        }
+    }
+}
@@ -1,26 +1,36 @@
+use nix::libc;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, ReadBuf};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::sync::oneshot;
 use tokio_fd::AsyncFd;
 use tracing::info;
 
 pub struct WsStdin {
-    stdin: AsyncFd,
+    stdin: Pin<Box<dyn AsyncRead + Send>>,
     _receiver: oneshot::Receiver<()>,
 }
 
 impl AsyncRead for WsStdin {
-    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
-        unsafe { self.map_unchecked_mut(|s| &mut s.stdin) }.poll_read(cx, buf)
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        self.stdin.as_mut().poll_read(cx, buf)
     }
 }
 
-pub async fn run_server() -> Result<((WsStdin, AsyncFd), oneshot::Sender<()>), anyhow::Error> {
+pub async fn run_server() -> Result<((WsStdin, Pin<Box<dyn AsyncWrite + Send>>), oneshot::Sender<()>), anyhow::Error> {
     info!("Starting STDIO server");
 
-    let stdin = AsyncFd::try_from(nix::libc::STDIN_FILENO)?;
-    let stdout = AsyncFd::try_from(nix::libc::STDOUT_FILENO)?;
+    // Regular files and /dev/null cannot be polled, they are read and written by a blocking thread instead
+    let stdin: Pin<Box<dyn AsyncRead + Send>> = match AsyncFd::try_from(libc::STDIN_FILENO) {
+        Ok(stdin) => Box::pin(stdin),
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => Box::pin(tokio::io::stdin()),
+        Err(err) => return Err(err.into()),
+    };
+    let stdout: Pin<Box<dyn AsyncWrite + Send>> = match AsyncFd::try_from(libc::STDOUT_FILENO) {
+        Ok(stdout) => Box::pin(stdout),
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => Box::pin(tokio::io::stdout()),
+        Err(err) => return Err(err.into()),
+    };
     let (tx, rx) = oneshot::channel::<()>();
 
     Ok(((WsStdin { stdin, _receiver: rx }, stdout), tx))
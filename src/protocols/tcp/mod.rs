@@ -1,5 +1,11 @@
+mod ntlm_hash;
+mod proxy_auth;
+mod proxy_chain;
 mod server;
 
+pub use ntlm_hash::md5;
+pub use proxy_chain::connect_with_proxy_chain;
+
 pub use server::configure_socket;
 pub use server::connect;
 pub use server::connect_with_http_proxy;
@@ -0,0 +1,64 @@
+//! MD4, MD5 and HMAC-MD5 needed by NTLM and apr1 htpasswd hashes. They are broken as general purpose hashes and must not be used elsewhere.
+
+use md4::{Digest, Md4};
+use md5::Md5;
+
+/// RFC 1320
+pub fn md4(data: &[u8]) -> [u8; 16] {
+    Md4::digest(data).into()
+}
+
+/// RFC 1321
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    Md5::digest(data).into()
+}
+
+/// RFC 2104
+pub fn hmac_md5(key: &[u8], data: &[&[u8]]) -> [u8; 16] {
+    let mut block_key = [0u8; 64];
+    if key.len() > 64 {
+        block_key[..16].copy_from_slice(&md5(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner: Vec<u8> = block_key.iter().map(|b| b ^ 0x36).collect();
+    data.iter().for_each(|d| inner.extend_from_slice(d));
+    let mut outer: Vec<u8> = block_key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&md5(&inner));
+
+    md5(&outer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn hex(digest: [u8; 16]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test_case(b"" => "31d6cfe0d16ae931b73c59d7e0c089c0" ; "empty")]
+    #[test_case(b"abc" => "a448017aaf21d8525fc10ae87aa6729d" ; "abc")]
+    #[test_case(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890" => "e33b4ddc9c38f2199c3e7b164fcc0536" ; "multi block")]
+    fn test_md4(input: &[u8]) -> String {
+        hex(md4(input))
+    }
+
+    #[test_case(b"" => "d41d8cd98f00b204e9800998ecf8427e" ; "empty")]
+    #[test_case(b"abc" => "900150983cd24fb0d6963f7d28e17f72" ; "abc")]
+    #[test_case(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890" => "57edf4a22be3c955ac49da2e2107b67a" ; "multi block")]
+    fn test_md5(input: &[u8]) -> String {
+        hex(md5(input))
+    }
+
+    #[test]
+    fn test_hmac_md5() {
+        // RFC 2202 test case 2
+        assert_eq!(
+            hex(hmac_md5(b"Jefe", &[b"what do ya want ", b"for nothing?"])),
+            "750c783e6ab0b503eaa86e310a5db738"
+        );
+    }
+}
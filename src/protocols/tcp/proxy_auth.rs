@@ -0,0 +1,337 @@
+//! Connection oriented authentication schemes for upstream http proxies (NTLM, Negotiate/Kerberos).
+//!
+//! Contrary to Basic, those schemes need several round-trips on the same connection before the proxy
+//! accepts the CONNECT request. Each leg consumes the challenge sent by the proxy and produces the next token.
+
+use crate::protocols::tcp::ntlm_hash::{hmac_md5, md4};
+use anyhow::{anyhow, Context};
+use rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(any(unix, windows))]
+use tracing::debug;
+
+#[cfg(unix)]
+use crate::protocols::gssapi::GssClientContext;
+#[cfg(windows)]
+use crate::protocols::sspi::SspiClientContext;
+
+pub enum ProxyAuthenticator {
+    Ntlm(NtlmAuthenticator),
+    #[cfg(any(unix, windows))]
+    Negotiate(NegotiateAuthenticator),
+}
+
+impl ProxyAuthenticator {
+    /// Pick the best authentication scheme among the ones offered by the proxy in its `Proxy-Authenticate` headers.
+    /// Negotiate (Kerberos) is preferred when available, as it does not need any credentials.
+    pub fn from_challenges(
+        offered: &[String],
+        credentials: Option<(&str, &str)>,
+        proxy_host: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let is_offered = |scheme: &str| {
+            offered.iter().any(|o| {
+                o.split_whitespace()
+                    .next()
+                    .is_some_and(|s| s.eq_ignore_ascii_case(scheme))
+            })
+        };
+
+        #[cfg(any(unix, windows))]
+        if is_offered("Negotiate") {
+            match NegotiateAuthenticator::new(proxy_host) {
+                Ok(auth) => return Ok(Some(Self::Negotiate(auth))),
+                Err(err) => debug!("Cannot use Negotiate authentication with http proxy: {:?}", err),
+            }
+        }
+        #[cfg(not(any(unix, windows)))]
+        let _ = proxy_host;
+
+        if is_offered("NTLM") {
+            let Some((user, password)) = credentials else {
+                return Err(anyhow!(
+                    "Http proxy requires NTLM authentication but no credentials were provided"
+                ));
+            };
+            return Ok(Some(Self::Ntlm(NtlmAuthenticator::new(user, password))));
+        }
+        #[cfg(not(any(unix, windows)))]
+        if is_offered("Negotiate") {
+            return Err(anyhow!(
+                "Http proxy requires Negotiate authentication, which is not supported on this platform. Enable NTLM on the proxy instead"
+            ));
+        }
+
+        Ok(None)
+    }
+
+    pub const fn scheme(&self) -> &'static str {
+        match self {
+            Self::Ntlm(_) => "NTLM",
+            #[cfg(any(unix, windows))]
+            Self::Negotiate(_) => "Negotiate",
+        }
+    }
+
+    /// Produce the next token to send to the proxy from its last challenge (if any).
+    /// Returns None when the handshake is complete on our side.
+    pub fn step(&mut self, challenge: Option<&[u8]>) -> anyhow::Result<Option<Vec<u8>>> {
+        match self {
+            Self::Ntlm(auth) => auth.step(challenge),
+            #[cfg(any(unix, windows))]
+            Self::Negotiate(auth) => auth.step(challenge),
+        }
+    }
+}
+
+pub struct NtlmAuthenticator {
+    domain: String,
+    user: String,
+    password: String,
+    leg: u8,
+}
+
+const NTLMSSP_SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+const NTLM_NEGOTIATE_FLAGS: u32 = 0x0000_0001 // NEGOTIATE_UNICODE
+    | 0x0000_0002 // NEGOTIATE_OEM
+    | 0x0000_0004 // REQUEST_TARGET
+    | 0x0000_0200 // NEGOTIATE_NTLM
+    | 0x0000_8000 // NEGOTIATE_ALWAYS_SIGN
+    | 0x0008_0000 // NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | 0x2000_0000 // NEGOTIATE_128
+    | 0x8000_0000; // NEGOTIATE_56
+
+impl NtlmAuthenticator {
+    /// User can be given as `DOMAIN\user`, `user@domain` or just `user`
+    pub fn new(user: &str, password: &str) -> Self {
+        let (domain, user) = if let Some((domain, user)) = user.split_once('\\') {
+            (domain, user)
+        } else if let Some((user, domain)) = user.split_once('@') {
+            (domain, user)
+        } else {
+            ("", user)
+        };
+
+        Self {
+            domain: domain.to_string(),
+            user: user.to_string(),
+            password: password.to_string(),
+            leg: 0,
+        }
+    }
+
+    fn step(&mut self, challenge: Option<&[u8]>) -> anyhow::Result<Option<Vec<u8>>> {
+        self.leg += 1;
+        match (self.leg, challenge) {
+            (1, _) => Ok(Some(ntlm_negotiate_message())),
+            (2, Some(challenge)) => {
+                let challenge = NtlmChallenge::parse(challenge)?;
+                let mut client_challenge = [0u8; 8];
+                rand::thread_rng().fill(&mut client_challenge);
+                Ok(Some(self.authenticate_message(&challenge, client_challenge, filetime_now())))
+            }
+            (2, None) => Err(anyhow!("Http proxy did not send back an NTLM challenge")),
+            _ => Ok(None),
+        }
+    }
+
+    fn authenticate_message(&self, challenge: &NtlmChallenge, client_challenge: [u8; 8], timestamp: u64) -> Vec<u8> {
+        let nt_hash = md4(&utf16le(&self.password));
+        let ntlmv2_hash = hmac_md5(&nt_hash, &[&utf16le(&(self.user.to_uppercase() + &self.domain))]);
+
+        // NTLMv2 client blob
+        let mut blob = Vec::with_capacity(32 + challenge.target_info.len());
+        blob.extend_from_slice(&[0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        blob.extend_from_slice(&timestamp.to_le_bytes());
+        blob.extend_from_slice(&client_challenge);
+        blob.extend_from_slice(&[0u8; 4]);
+        blob.extend_from_slice(&challenge.target_info);
+        blob.extend_from_slice(&[0u8; 4]);
+
+        let nt_proof = hmac_md5(&ntlmv2_hash, &[&challenge.server_challenge, &blob]);
+        let nt_response = [nt_proof.as_slice(), &blob].concat();
+        let lm_response = [
+            hmac_md5(&ntlmv2_hash, &[&challenge.server_challenge, &client_challenge]).as_slice(),
+            &client_challenge,
+        ]
+        .concat();
+
+        let domain = utf16le(&self.domain);
+        let user = utf16le(&self.user);
+        let workstation = utf16le("WSTUNNEL");
+
+        const HEADER_LEN: usize = 64;
+        let mut payload = Vec::new();
+        let mut msg = Vec::with_capacity(HEADER_LEN);
+        msg.extend_from_slice(NTLMSSP_SIGNATURE);
+        msg.extend_from_slice(&3u32.to_le_bytes());
+        for field in [&lm_response, &nt_response, &domain, &user, &workstation, &vec![]] {
+            let offset = (HEADER_LEN + payload.len()) as u32;
+            msg.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            msg.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            msg.extend_from_slice(&offset.to_le_bytes());
+            payload.extend_from_slice(field);
+        }
+        msg.extend_from_slice(&(challenge.flags & NTLM_NEGOTIATE_FLAGS).to_le_bytes());
+        msg.extend_from_slice(&payload);
+
+        msg
+    }
+}
+
+fn ntlm_negotiate_message() -> Vec<u8> {
+    let mut msg = Vec::with_capacity(32);
+    msg.extend_from_slice(NTLMSSP_SIGNATURE);
+    msg.extend_from_slice(&1u32.to_le_bytes());
+    msg.extend_from_slice(&NTLM_NEGOTIATE_FLAGS.to_le_bytes());
+    msg.extend_from_slice(&[0u8; 16]); // empty domain and workstation
+    msg
+}
+
+struct NtlmChallenge {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl NtlmChallenge {
+    fn parse(msg: &[u8]) -> anyhow::Result<Self> {
+        if msg.len() < 48 || &msg[..8] != NTLMSSP_SIGNATURE || msg[8..12] != 2u32.to_le_bytes() {
+            return Err(anyhow!("Invalid NTLM challenge message received from http proxy"));
+        }
+
+        let u16_at = |ix: usize| u16::from_le_bytes([msg[ix], msg[ix + 1]]) as usize;
+        let u32_at = |ix: usize| u32::from_le_bytes([msg[ix], msg[ix + 1], msg[ix + 2], msg[ix + 3]]);
+        let (info_len, info_offset) = (u16_at(40), u32_at(44) as usize);
+        let target_info = msg
+            .get(info_offset..info_offset + info_len)
+            .context("Invalid NTLM target info in challenge message")?
+            .to_vec();
+
+        Ok(Self {
+            flags: u32_at(20),
+            server_challenge: msg[24..32].try_into()?,
+            target_info,
+        })
+    }
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+// Number of 100ns since 1601-01-01 (Windows FILETIME)
+fn filetime_now() -> u64 {
+    const EPOCH_DIFF_SECS: u64 = 11_644_473_600;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now.as_secs() + EPOCH_DIFF_SECS) * 10_000_000 + now.subsec_nanos() as u64 / 100
+}
+
+#[cfg(unix)]
+pub struct NegotiateAuthenticator {
+    ctx: GssClientContext,
+}
+
+#[cfg(windows)]
+pub struct NegotiateAuthenticator {
+    ctx: SspiClientContext,
+}
+
+#[cfg(any(unix, windows))]
+impl NegotiateAuthenticator {
+    #[cfg(unix)]
+    /// Uses the kerberos credentials of the current user (i.e: from kinit) to authenticate against HTTP@proxy_host
+    fn new(proxy_host: &str) -> anyhow::Result<Self> {
+        let ctx = GssClientContext::new(&format!("HTTP@{}", proxy_host))?;
+        Ok(Self { ctx })
+    }
+
+    #[cfg(windows)]
+    /// Uses the credentials of the logged-on windows user to authenticate against HTTP/proxy_host
+    fn new(proxy_host: &str) -> anyhow::Result<Self> {
+        let ctx = SspiClientContext::new(&format!("HTTP/{}", proxy_host))?;
+        Ok(Self { ctx })
+    }
+
+    fn step(&mut self, challenge: Option<&[u8]>) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.ctx.is_complete() {
+            return Ok(None);
+        }
+
+        self.ctx
+            .step(challenge)
+            .context("Cannot generate negotiate token for http proxy")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ntlm_user_domain_parsing() {
+        let auth = NtlmAuthenticator::new("CORP\\john", "pass");
+        assert_eq!((auth.domain.as_str(), auth.user.as_str()), ("CORP", "john"));
+        let auth = NtlmAuthenticator::new("john@corp.local", "pass");
+        assert_eq!((auth.domain.as_str(), auth.user.as_str()), ("corp.local", "john"));
+        let auth = NtlmAuthenticator::new("john", "pass");
+        assert_eq!((auth.domain.as_str(), auth.user.as_str()), ("", "john"));
+    }
+
+    #[test]
+    fn test_ntlmv2_response() {
+        // Test vector from [MS-NLMP] 4.2.4
+        let auth = NtlmAuthenticator::new("Domain\\User", "Password");
+        let challenge = NtlmChallenge {
+            flags: 0xe28a8233,
+            server_challenge: [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+            target_info: vec![
+                0x02, 0x00, 0x0c, 0x00, 0x44, 0x00, 0x6f, 0x00, 0x6d, 0x00, 0x61, 0x00, 0x69, 0x00, 0x6e, 0x00, 0x01,
+                0x00, 0x0c, 0x00, 0x53, 0x00, 0x65, 0x00, 0x72, 0x00, 0x76, 0x00, 0x65, 0x00, 0x72, 0x00, 0x00, 0x00,
+                0x00, 0x00,
+            ],
+        };
+        let msg = auth.authenticate_message(&challenge, [0xaa; 8], 0);
+
+        // NTProofStr is the first 16 bytes of the NT response
+        let nt_offset = u32::from_le_bytes(msg[24..28].try_into().unwrap()) as usize;
+        assert_eq!(
+            &msg[nt_offset..nt_offset + 16],
+            &[0x68, 0xcd, 0x0a, 0xb8, 0x51, 0xe5, 0x1c, 0x96, 0xaa, 0xbc, 0x92, 0x7b, 0xeb, 0xef, 0x6a, 0x1c]
+        );
+    }
+
+    #[test]
+    fn test_pick_scheme() {
+        let offered = vec!["Basic realm=\"proxy\"".to_string(), "NTLM".to_string()];
+        let auth = ProxyAuthenticator::from_challenges(&offered, Some(("user", "pass")), "proxy").unwrap();
+        assert_eq!(auth.map(|a| a.scheme()), Some("NTLM"));
+
+        let offered = vec!["Basic realm=\"proxy\"".to_string()];
+        let auth = ProxyAuthenticator::from_challenges(&offered, Some(("user", "pass")), "proxy").unwrap();
+        assert!(auth.is_none());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_negotiate_sspi() {
+        // Without a domain controller, the SSPI falls back to NTLM inside the Negotiate token
+        let offered = vec!["Negotiate".to_string(), "NTLM".to_string()];
+        let mut auth = ProxyAuthenticator::from_challenges(&offered, None, "proxy")
+            .unwrap()
+            .unwrap();
+        assert_eq!(auth.scheme(), "Negotiate");
+        assert!(auth.step(None).unwrap().is_some_and(|token| !token.is_empty()));
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    #[test]
+    fn test_negotiate_unsupported() {
+        let offered = vec!["Negotiate".to_string()];
+        assert!(ProxyAuthenticator::from_challenges(&offered, None, "proxy").is_err());
+
+        let offered = vec!["Negotiate".to_string(), "NTLM".to_string()];
+        let auth = ProxyAuthenticator::from_challenges(&offered, Some(("user", "pass")), "proxy").unwrap();
+        assert_eq!(auth.map(|a| a.scheme()), Some("NTLM"));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::protocols::dns::DnsResolver;
+use crate::protocols::tcp::server::{connect, http_connect_handshake};
+use crate::somark::SoMark;
+use anyhow::{anyhow, Context};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{debug, info, instrument};
+use url::{Host, Url};
+
+/// Returns the connect timeout to use for a hop of the chain.
+/// It can be overridden per hop with the `timeout_sec` query parameter, i.e: socks5://proxy:1080?timeout_sec=5
+pub fn hop_timeout(hop: &Url, default_timeout: Duration) -> Duration {
+    hop.query_pairs()
+        .find(|(k, _)| k == "timeout_sec")
+        .and_then(|(_, v)| v.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(default_timeout)
+}
+
+/// Open a TCP connection toward `host:port` by traversing every proxy of the chain in order.
+/// The first proxy is contacted directly, each next hop (and finally the destination) is reached through the
+/// previous one. Supported hops are `http://` (CONNECT) and `socks5://` proxies.
+#[instrument(level = "info", name = "proxy_chain", skip_all)]
+pub async fn connect_with_proxy_chain(
+    chain: &[Url],
+    host: &Host<String>,
+    port: u16,
+    so_mark: SoMark,
+    connect_timeout: Duration,
+    dns_resolver: &DnsResolver,
+) -> Result<TcpStream, anyhow::Error> {
+    let Some(first_hop) = chain.first() else {
+        return connect(host, port, so_mark, connect_timeout, dns_resolver).await;
+    };
+
+    let (first_host, first_port) = hop_host_port(first_hop)?;
+    info!("Connecting to first proxy of the chain {}:{}", first_host, first_port);
+    let mut socket = connect(
+        &first_host,
+        first_port,
+        so_mark,
+        hop_timeout(first_hop, connect_timeout),
+        dns_resolver,
+    )
+    .await?;
+
+    // Each proxy is asked to connect to the next one, the last proxy of the chain connect to the real destination
+    for (ix, hop) in chain.iter().enumerate() {
+        let (next_host, next_port) = match chain.get(ix + 1) {
+            Some(next_hop) => hop_host_port(next_hop)?,
+            None => (host.clone(), port),
+        };
+        let timeout_hop = hop_timeout(hop, connect_timeout);
+        debug!("Asking proxy {} to connect to {}:{}", hop, next_host, next_port);
+
+        match hop.scheme() {
+            "http" => http_connect_handshake(&mut socket, hop, &next_host, next_port, timeout_hop).await?,
+            "socks5" | "socks5h" => {
+                timeout(timeout_hop, socks5_connect_handshake(&mut socket, hop, &next_host, next_port))
+                    .await
+                    .map_err(|_| {
+                        anyhow!("Cannot connect through socks5 proxy {hop}. Proxy took too long to connect")
+                    })??
+            }
+            scheme => return Err(anyhow!("Unsupported proxy scheme {} in proxy chain", scheme)),
+        }
+    }
+
+    info!("Proxy chain connected to remote host {}:{}", host, port);
+    Ok(socket)
+}
+
+fn hop_host_port(hop: &Url) -> anyhow::Result<(Host<String>, u16)> {
+    let host = match hop
+        .host()
+        .with_context(|| format!("Cannot parse proxy host from {}", hop))?
+    {
+        // url lib does not parse ip addresses for non-special schemes (i.e: socks5)
+        Host::Domain(host) => Host::parse(host).unwrap_or_else(|_| Host::Domain(host.to_string())),
+        host => host.to_owned(),
+    };
+    let port = match hop.scheme() {
+        "socks5" | "socks5h" => hop.port().unwrap_or(1080),
+        _ => hop.port_or_known_default().unwrap_or(80),
+    };
+
+    Ok((host, port))
+}
+
+/// Minimal SOCKS5 client handshake (RFC 1928/1929) for a CONNECT command.
+/// Username/password authentication is used if the proxy url contains credentials.
+async fn socks5_connect_handshake(
+    socket: &mut TcpStream,
+    proxy: &Url,
+    host: &Host<String>,
+    port: u16,
+) -> anyhow::Result<()> {
+    const SOCKS5_VERSION: u8 = 0x05;
+    const NO_AUTH: u8 = 0x00;
+    const USER_PASS_AUTH: u8 = 0x02;
+
+    let credentials = proxy.password().map(|password| {
+        (
+            urlencoding::decode(proxy.username()).map(|x| x.into_owned()),
+            urlencoding::decode(password).map(|x| x.into_owned()),
+        )
+    });
+    let credentials = match credentials {
+        Some((Ok(user), Ok(password))) => Some((user, password)),
+        Some(_) => return Err(anyhow!("Cannot urldecode socks5 proxy credentials")),
+        None => None,
+    };
+
+    // Greetings
+    let auth_method = if credentials.is_some() { USER_PASS_AUTH } else { NO_AUTH };
+    socket.write_all(&[SOCKS5_VERSION, 1, auth_method]).await?;
+    let mut reply = [0u8; 2];
+    socket.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION || reply[1] != auth_method {
+        return Err(anyhow!("Socks5 proxy {} refused our authentication method", proxy));
+    }
+
+    if let Some((user, password)) = credentials {
+        if user.len() > 255 || password.len() > 255 {
+            return Err(anyhow!("Socks5 proxy login or password is too long"));
+        }
+        let mut auth = Vec::with_capacity(3 + user.len() + password.len());
+        auth.push(0x01);
+        auth.push(user.len() as u8);
+        auth.extend_from_slice(user.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        socket.write_all(&auth).await?;
+        socket.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(anyhow!("Socks5 proxy {} rejected our credentials", proxy));
+        }
+    }
+
+    // Connect request
+    let mut request = vec![SOCKS5_VERSION, 0x01, 0x00];
+    match host {
+        Host::Ipv4(ip) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        Host::Ipv6(ip) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+        Host::Domain(domain) => {
+            if domain.len() > 255 {
+                return Err(anyhow!("Domain name {} is too long for socks5", domain));
+            }
+            request.push(0x03);
+            request.push(domain.len() as u8);
+            request.extend_from_slice(domain.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    socket.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    socket.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(anyhow!(
+            "Socks5 proxy {} cannot connect to {}:{}, error code {}",
+            proxy,
+            host,
+            port,
+            reply[1]
+        ));
+    }
+
+    // Drain the bound address of the reply
+    let addr_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => socket.read_u8().await? as usize,
+        atyp => return Err(anyhow!("Socks5 proxy {} replied with an invalid address type {}", proxy, atyp)),
+    };
+    let mut bound_addr = vec![0u8; addr_len + 2];
+    socket.read_exact(&mut bound_addr).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hop_timeout() {
+        let default = Duration::from_secs(10);
+        assert_eq!(hop_timeout(&Url::parse("http://proxy:3128").unwrap(), default), default);
+        assert_eq!(
+            hop_timeout(&Url::parse("socks5://proxy:1080?timeout_sec=3").unwrap(), default),
+            Duration::from_secs(3)
+        );
+    }
+
+    #[test]
+    fn test_hop_host_port() {
+        let (host, port) = hop_host_port(&Url::parse("socks5://proxy").unwrap()).unwrap();
+        assert_eq!(host, Host::Domain("proxy".to_string()));
+        assert_eq!(port, 1080);
+
+        let (_, port) = hop_host_port(&Url::parse("http://proxy").unwrap()).unwrap();
+        assert_eq!(port, 80);
+    }
+}
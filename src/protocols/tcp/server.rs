@@ -9,6 +9,7 @@ use socket2::{SockRef, TcpKeepalive};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::tcp::proxy_auth::ProxyAuthenticator;
 use crate::somark::SoMark;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -42,7 +43,7 @@ pub fn configure_socket(socket: SockRef, so_mark: SoMark) -> Result<(), anyhow::
         .set_tcp_keepalive(&tcp_keepalive)
         .with_context(|| format!("cannot set tcp_keepalive on socket: {:?}", io::Error::last_os_error()))?;
 
-    so_mark.set_mark(socket).context("cannot set SO_MARK on socket")?;
+    so_mark.apply(socket)?;
 
     Ok(())
 }
@@ -126,11 +127,16 @@ pub async fn connect(
                     "Cannot connect to tcp endpoint {addr} due to timeout of {}s elapsed",
                     connect_timeout.as_secs()
                 );
+                last_err.get_or_insert_with(|| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"));
             }
         }
     }
 
-    cnx.ok_or_else(|| anyhow!("Cannot connect to tcp endpoint {}:{} reason {:?}", host, port, last_err))
+    cnx.ok_or_else(|| match last_err {
+        // Keep the io error in the chain, to classify the failure
+        Some(err) => anyhow::Error::new(err).context(format!("Cannot connect to tcp endpoint {}:{}", host, port)),
+        None => anyhow!("Cannot connect to tcp endpoint {}:{}, no address found", host, port),
+    })
 }
 
 #[instrument(level = "info", name = "http_proxy", skip_all)]
@@ -149,22 +155,138 @@ pub async fn connect_with_http_proxy(
     let mut socket = connect(&proxy_host, proxy_port, so_mark, connect_timeout, dns_resolver).await?;
     debug!("Connected to http proxy {}", socket.peer_addr().unwrap());
 
-    let authorization = if let Some((user, password)) = proxy.password().map(|p| (proxy.username(), p)) {
+    http_connect_handshake(&mut socket, proxy, host, port, connect_timeout).await?;
+    Ok(socket)
+}
+
+/// Ask the http proxy on the other side of `socket` to open a tunnel (CONNECT) toward `host:port`.
+/// On success, the socket can be used as if directly connected to the destination.
+///
+/// Credentials from the proxy url are sent pre-emptively with Basic auth. If the proxy answers with a 407 asking
+/// for a connection oriented scheme (NTLM, Negotiate), the multi-leg handshake is done on the same connection.
+pub async fn http_connect_handshake(
+    socket: &mut TcpStream,
+    proxy: &Url,
+    host: &Host<String>,
+    port: u16,
+    connect_timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let credentials = if let Some((user, password)) = proxy.password().map(|p| (proxy.username(), p)) {
         let user = urlencoding::decode(user).with_context(|| format!("Cannot urldecode proxy user: {}", user))?;
         let password =
             urlencoding::decode(password).with_context(|| format!("Cannot urldecode proxy password: {}", password))?;
-        let creds = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
-        format!("Proxy-Authorization: Basic {}\r\n", creds)
+        Some((user, password))
     } else {
-        "".to_string()
+        None
     };
 
-    let connect_request = format!("CONNECT {host}:{port} HTTP/1.0\r\nHost: {host}:{port}\r\n{authorization}\r\n");
+    let mut authorization = credentials.as_ref().map(|(user, password)| {
+        let creds = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+        format!("Basic {}", creds)
+    });
+    let mut authenticator: Option<ProxyAuthenticator> = None;
+
+    // NTLM needs 2 legs, Negotiate usually 1 or 2. Bound it to avoid looping forever with a misbehaving proxy
+    for _ in 0..5 {
+        let response = send_connect_request(socket, host, port, authorization.as_deref(), connect_timeout).await?;
+        match response.status {
+            200 => {
+                info!("Http proxy accepted connection to remote host {}:{}", host, port);
+                return Ok(());
+            }
+            407 => {
+                if authenticator.is_none() {
+                    let proxy_host = proxy.host_str().unwrap_or_default();
+                    let creds = credentials.as_ref().map(|(u, p)| (&**u, &**p));
+                    authenticator = ProxyAuthenticator::from_challenges(&response.authenticate, creds, proxy_host)?;
+                }
+
+                let Some(auth) = authenticator.as_mut() else {
+                    return Err(anyhow!(
+                        "Cannot connect to http proxy. Proxy requires an unsupported authentication or rejected our credentials: {:?}",
+                        response.authenticate
+                    ));
+                };
+
+                let challenge = response.challenge(auth.scheme())?;
+                let Some(token) = auth.step(challenge.as_deref())? else {
+                    return Err(anyhow!(
+                        "Cannot connect to http proxy. Proxy rejected our {} authentication",
+                        auth.scheme()
+                    ));
+                };
+
+                if !response.keep_alive {
+                    return Err(anyhow!(
+                        "Cannot connect to http proxy. Proxy closed the connection during {} authentication",
+                        auth.scheme()
+                    ));
+                }
+
+                debug!("Http proxy requested {} authentication, sending next token", auth.scheme());
+                authorization = Some(format!(
+                    "{} {}",
+                    auth.scheme(),
+                    base64::engine::general_purpose::STANDARD.encode(token)
+                ));
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Cannot connect to http proxy. Proxy returned an invalid response: {}",
+                    String::from_utf8_lossy(&response.raw)
+                ));
+            }
+        }
+    }
+
+    Err(anyhow!("Cannot connect to http proxy. Too many authentication round-trips"))
+}
+
+struct ProxyResponse {
+    status: u16,
+    authenticate: Vec<String>,
+    keep_alive: bool,
+    raw: BytesMut,
+}
+
+impl ProxyResponse {
+    fn challenge(&self, scheme: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(challenge) = self
+            .authenticate
+            .iter()
+            .filter_map(|h| h.split_once(' '))
+            .find(|(s, _)| s.eq_ignore_ascii_case(scheme))
+            .map(|(_, challenge)| challenge.trim())
+        else {
+            return Ok(None);
+        };
+
+        let challenge = base64::engine::general_purpose::STANDARD
+            .decode(challenge)
+            .with_context(|| format!("Invalid {} challenge from http proxy", scheme))?;
+        Ok(Some(challenge))
+    }
+}
+
+async fn send_connect_request(
+    socket: &mut TcpStream,
+    host: &Host<String>,
+    port: u16,
+    authorization: Option<&str>,
+    connect_timeout: Duration,
+) -> Result<ProxyResponse, anyhow::Error> {
+    let authorization = authorization
+        .map(|auth| format!("Proxy-Authorization: {}\r\n", auth))
+        .unwrap_or_default();
+    let connect_request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\nProxy-Connection: Keep-Alive\r\n{authorization}\r\n"
+    );
     debug!("Sending request:\n{}", connect_request);
     socket.write_all(connect_request.as_bytes()).await?;
 
+    static END_HTTP_RESPONSE: &[u8; 4] = b"\r\n\r\n";
     let mut buf = BytesMut::with_capacity(1024);
-    loop {
+    let headers_end = loop {
         let nb_bytes = tokio::time::timeout(connect_timeout, socket.read_buf(&mut buf)).await;
         match nb_bytes {
             Ok(Ok(0)) => {
@@ -181,31 +303,57 @@ pub async fn connect_with_http_proxy(
             }
         };
 
-        static END_HTTP_RESPONSE: &[u8; 4] = b"\r\n\r\n"; // It is reversed from \r\n\r\n as we reverse scan the buffer
-        if buf.len() > 50 * 1024
-            || buf
-                .windows(END_HTTP_RESPONSE.len())
-                .any(|window| window == END_HTTP_RESPONSE)
+        if let Some(pos) = buf
+            .windows(END_HTTP_RESPONSE.len())
+            .position(|window| window == END_HTTP_RESPONSE)
         {
-            break;
+            break pos + END_HTTP_RESPONSE.len();
+        }
+        if buf.len() > 50 * 1024 {
+            break buf.len();
+        }
+    };
+    debug!("Got response from proxy:\n{}", String::from_utf8_lossy(&buf));
+
+    let headers = String::from_utf8_lossy(&buf[..headers_end]).to_string();
+    let mut lines = headers.lines();
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .unwrap_or(0);
+    let mut response = ProxyResponse {
+        status,
+        authenticate: vec![],
+        keep_alive: headers.starts_with("HTTP/1.1"),
+        raw: buf.clone(),
+    };
+    let mut content_length = 0;
+    for (name, value) in lines.filter_map(|l| l.split_once(':')) {
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Proxy-Authenticate") {
+            response.authenticate.push(value.to_string());
+        } else if name.eq_ignore_ascii_case("Content-Length") {
+            content_length = value.parse::<usize>().unwrap_or(0);
+        } else if name.eq_ignore_ascii_case("Connection") || name.eq_ignore_ascii_case("Proxy-Connection") {
+            response.keep_alive = value.eq_ignore_ascii_case("keep-alive");
         }
     }
 
-    static OK_RESPONSE_10: &[u8] = b"HTTP/1.0 200 ";
-    static OK_RESPONSE_11: &[u8] = b"HTTP/1.1 200 ";
-    if !buf
-        .windows(OK_RESPONSE_10.len())
-        .any(|window| window == OK_RESPONSE_10 || window == OK_RESPONSE_11)
-    {
-        return Err(anyhow!(
-            "Cannot connect to http proxy. Proxy returned an invalid response: {}",
-            String::from_utf8_lossy(&buf)
-        ));
+    // Drain the body of the error response, so the next leg of the authentication starts on a clean stream
+    if status != 200 && response.keep_alive {
+        let mut remaining = content_length.saturating_sub(buf.len() - headers_end);
+        let mut drain = [0u8; 1024];
+        while remaining > 0 {
+            let len = remaining.min(drain.len());
+            tokio::time::timeout(connect_timeout, socket.read_exact(&mut drain[..len]))
+                .await
+                .map_err(|_| anyhow!("Cannot connect to http proxy. Proxy took too long to send its response"))??;
+            remaining -= len;
+        }
     }
 
-    debug!("Got response from proxy:\n{}", String::from_utf8_lossy(&buf));
-    info!("Http proxy accepted connection to remote host {}:{}", host, port);
-    Ok(socket)
+    Ok(response)
 }
 
 #[cfg_attr(not(target_os = "linux"), expect(unused_variables))]
@@ -289,7 +437,7 @@ mod tests {
             server_port,
             SoMark::new(None),
             Duration::from_secs(1),
-            &DnsResolver::System,
+            &DnsResolver::default(),
         )
         .await
         .unwrap();
@@ -6,6 +6,8 @@ pub use server::connect;
 #[cfg(target_os = "linux")]
 pub use server::mk_send_socket_tproxy;
 pub use server::run_server;
+#[cfg(feature = "udp-stream")]
+pub use server::UdpServerBuilder;
 pub use server::UdpStream;
 pub use server::UdpStreamWriter;
 pub use server::WsUdpSocket;
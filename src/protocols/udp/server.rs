@@ -2,9 +2,7 @@ use anyhow::{anyhow, Context};
 use futures_util::{stream, Stream};
 
 use parking_lot::RwLock;
-use pin_project::{pin_project, pinned_drop};
 use std::collections::HashMap;
-use std::future::Future;
 use std::io::{Error, ErrorKind};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::{io, task};
@@ -18,12 +16,13 @@ use std::task::{ready, Poll};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::UdpSocket;
-use tokio::sync::futures::Notified;
 
 use crate::protocols::dns::DnsResolver;
 use crate::somark::SoMark;
+use crate::state_dump::INTERNAL_STATE;
 use tokio::sync::Notify;
 use tokio::time::{sleep, timeout, Interval};
+use tokio_util::sync::{CancellationToken, ReusableBoxFuture};
 use tracing::{debug, error, info};
 use url::Host;
 
@@ -33,50 +32,44 @@ struct IoInner {
 }
 struct UdpServer {
     listener: Arc<UdpSocket>,
-    peers: HashMap<SocketAddr, Pin<Arc<IoInner>>, ahash::RandomState>,
+    /// Address the listener is bound to, to report its peers in the state dumps
+    local_addr: SocketAddr,
+    peers: HashMap<SocketAddr, Arc<IoInner>, ahash::RandomState>,
     keys_to_delete: Arc<RwLock<Vec<SocketAddr>>>,
     cnx_timeout: Option<Duration>,
+    /// Ends the server and the streams of its peers
+    cancel: CancellationToken,
 }
 
 impl UdpServer {
-    pub fn new(listener: UdpSocket, timeout: Option<Duration>) -> Self {
+    pub fn new(
+        listener: UdpSocket,
+        timeout: Option<Duration>,
+        recv_buffer_size: Option<usize>,
+        send_buffer_size: Option<usize>,
+        cancel: CancellationToken,
+    ) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
         let socket = SockRef::from(&listener);
-
-        // Increase receive buffer
-        const BUF_SIZES: [usize; 7] = [64usize, 32usize, 16usize, 8usize, 4usize, 2usize, 1usize];
-        for size in BUF_SIZES.iter() {
-            if let Err(err) = socket.set_recv_buffer_size(size * 1024 * 1024) {
-                warn!("Cannot increase UDP server recv buffer to {} Mib: {}", size, err);
-                warn!("This is not fatal, but can lead to packet loss if you have too much throughput. You must monitor packet loss in this case");
-                continue;
-            }
-
-            if *size != BUF_SIZES[0] {
-                info!("Increased UDP server recv buffer to {} Mib", size);
-            }
-
-            break;
+        if let Some(size) = recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        } else {
+            increase_recv_buffer(&socket);
         }
-
-        for size in BUF_SIZES.iter() {
-            if let Err(err) = socket.set_send_buffer_size(size * 1024 * 1024) {
-                warn!("Cannot increase UDP server send buffer to {} Mib: {}", size, err);
-                warn!("This is not fatal, but can lead to packet loss if you have too much throughput. You must monitor packet loss in this case");
-                continue;
-            }
-
-            if *size != BUF_SIZES[0] {
-                info!("Increased UDP server send buffer to {} Mib", size);
-            }
-            break;
+        if let Some(size) = send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        } else {
+            increase_send_buffer(&socket);
         }
 
-        Self {
+        Ok(Self {
             listener: Arc::new(listener),
+            local_addr,
             peers: HashMap::with_hasher(ahash::RandomState::new()),
             keys_to_delete: Default::default(),
             cnx_timeout: timeout,
-        }
+            cancel,
+        })
     }
 
     #[inline]
@@ -90,44 +83,151 @@ impl UdpServer {
         let mut keys_to_delete = self.keys_to_delete.write();
         for key in keys_to_delete.iter() {
             self.peers.remove(key);
+            INTERNAL_STATE.remove_udp_peer(self.local_addr, *key);
         }
         keys_to_delete.clear();
     }
+
+    fn add_peer(&mut self, peer: SocketAddr, io: Arc<IoInner>) {
+        self.peers.insert(peer, io);
+        INTERNAL_STATE.add_udp_peer(self.local_addr, peer);
+    }
     pub fn clone_socket(&self) -> Arc<UdpSocket> {
         self.listener.clone()
     }
+
+    /// Wait for the next datagram of a new peer, and return its stream. The datagrams of the known peers are
+    /// handed to their stream in the meantime
+    async fn next_peer(
+        &mut self,
+        peer_with_data: Option<SocketAddr>,
+        mk_send_socket: impl Fn(&Arc<UdpSocket>) -> anyhow::Result<Arc<UdpSocket>>,
+    ) -> Option<UdpStream> {
+        // New returned peer hasn't read its data yet, await for it.
+        if let Some(await_peer) = peer_with_data {
+            if let Some(peer) = self.peers.get(&await_peer) {
+                peer.has_read_data.notified().await;
+            }
+        };
+
+        loop {
+            self.clean_dead_keys();
+            let peer_addr = match self.listener.peek_sender().await {
+                Ok(ret) => ret,
+                Err(err) => {
+                    error!("Cannot read from UDP server. Closing server: {}", err);
+                    return None;
+                }
+            };
+
+            match self.peers.get(&peer_addr) {
+                Some(io) => {
+                    io.has_data_to_read.notify_one();
+                    io.has_read_data.notified().await;
+                }
+                None => {
+                    info!("New UDP connection from {}", peer_addr);
+                    let (udp_client, io) = UdpStream::new(
+                        self.clone_socket(),
+                        mk_send_socket(&self.listener).ok()?,
+                        peer_addr,
+                        self.cnx_timeout,
+                        Arc::downgrade(&self.keys_to_delete),
+                        self.cancel.clone(),
+                    );
+                    // Stored until the stream is read for the first time
+                    io.has_data_to_read.notify_one();
+                    self.add_peer(peer_addr, io);
+                    return Some(udp_client);
+                }
+            }
+        }
+    }
+}
+
+const BUF_SIZES: [usize; 7] = [64usize, 32usize, 16usize, 8usize, 4usize, 2usize, 1usize];
+
+/// Largest receive buffer the system allows, up to 64 MiB
+fn increase_recv_buffer(socket: &SockRef) {
+    for size in BUF_SIZES.iter() {
+        if let Err(err) = socket.set_recv_buffer_size(size * 1024 * 1024) {
+            warn!("Cannot increase UDP server recv buffer to {} Mib: {}", size, err);
+            warn!("This is not fatal, but can lead to packet loss if you have too much throughput. You must monitor packet loss in this case");
+            continue;
+        }
+
+        if *size != BUF_SIZES[0] {
+            info!("Increased UDP server recv buffer to {} Mib", size);
+        }
+
+        break;
+    }
+}
+
+/// Largest send buffer the system allows, up to 64 MiB
+fn increase_send_buffer(socket: &SockRef) {
+    for size in BUF_SIZES.iter() {
+        if let Err(err) = socket.set_send_buffer_size(size * 1024 * 1024) {
+            warn!("Cannot increase UDP server send buffer to {} Mib: {}", size, err);
+            warn!("This is not fatal, but can lead to packet loss if you have too much throughput. You must monitor packet loss in this case");
+            continue;
+        }
+
+        if *size != BUF_SIZES[0] {
+            info!("Increased UDP server send buffer to {} Mib", size);
+        }
+        break;
+    }
+}
+
+impl Drop for UdpServer {
+    fn drop(&mut self) {
+        for (peer, io) in &self.peers {
+            INTERNAL_STATE.remove_udp_peer(self.local_addr, *peer);
+            // The streams waiting for a datagram end once the server is cancelled
+            if self.cancel.is_cancelled() {
+                io.has_data_to_read.notify_one();
+            }
+        }
+    }
 }
 
-#[pin_project(PinnedDrop)]
+/// Datagrams of one peer of the udp server, as a stream. Each read returns a single datagram, truncated if the buffer
+/// is too small for it, and each write of its UdpStreamWriter sends one.
+/// The datagrams of a peer are only received once the server stream is polled, and the server waits for the stream
+/// of that peer to read the datagram before receiving the next one, so a peer whose stream is not read blocks the
+/// others. With a timeout, reading fails with TimedOut once no datagram is received from the peer for that long.
+/// Once the stream is dropped, the next datagram of the peer starts a new stream. Reading returns end of file once
+/// the cancellation token of the server is cancelled
 pub struct UdpStream {
     recv_socket: Arc<UdpSocket>,
     send_socket: Arc<UdpSocket>,
     peer: SocketAddr,
-    #[pin]
     watchdog_deadline: Option<Interval>,
     data_read_before_deadline: bool,
-    has_been_notified: bool,
-    #[pin]
-    pending_notification: Option<Notified<'static>>,
-    io: Pin<Arc<IoInner>>,
+    /// Until the server notifies that the next datagram of the socket is from this peer
+    has_data_to_read: ReusableBoxFuture<'static, ()>,
+    waiting_data: bool,
+    io: Arc<IoInner>,
     keys_to_delete: Weak<RwLock<Vec<SocketAddr>>>,
+    cancel: CancellationToken,
 }
 
-#[pinned_drop]
-impl PinnedDrop for UdpStream {
-    fn drop(self: Pin<&mut Self>) {
+impl Drop for UdpStream {
+    fn drop(&mut self) {
         if let Some(keys_to_delete) = self.keys_to_delete.upgrade() {
             keys_to_delete.write().push(self.peer);
         }
 
-        // safety: we are dropping the notification as we extend its lifetime to 'static unsafely
-        // So it must be gone before we drop its parent. It should never happen but in case
-        let mut project = self.project();
-        project.pending_notification.as_mut().set(None);
-        project.io.has_read_data.notify_one();
+        // The server may be waiting for this stream to read its datagram
+        self.io.has_read_data.notify_one();
     }
 }
 
+async fn data_to_read(io: Arc<IoInner>) {
+    io.has_data_to_read.notified().await
+}
+
 impl UdpStream {
     fn new(
         recv_socket: Arc<UdpSocket>,
@@ -135,38 +235,39 @@ impl UdpStream {
         peer: SocketAddr,
         watchdog_deadline: Option<Duration>,
         keys_to_delete: Weak<RwLock<Vec<SocketAddr>>>,
-    ) -> (Self, Pin<Arc<IoInner>>) {
-        let has_data_to_read = Notify::new();
-        let has_read_data = Notify::new();
-        let io = Arc::pin(IoInner {
-            has_data_to_read,
-            has_read_data,
+        cancel: CancellationToken,
+    ) -> (Self, Arc<IoInner>) {
+        let io = Arc::new(IoInner {
+            has_data_to_read: Notify::new(),
+            has_read_data: Notify::new(),
         });
-        let mut s = Self {
+        let s = Self {
             recv_socket,
             send_socket,
             peer,
             watchdog_deadline: watchdog_deadline
                 .map(|timeout| tokio::time::interval_at(tokio::time::Instant::now() + timeout, timeout)),
             data_read_before_deadline: false,
-            has_been_notified: false,
-            pending_notification: None,
+            has_data_to_read: ReusableBoxFuture::new(data_to_read(io.clone())),
+            waiting_data: true,
             io: io.clone(),
             keys_to_delete,
+            cancel,
         };
 
-        let pending_notification =
-            unsafe { std::mem::transmute::<Notified<'_>, Notified<'static>>(s.io.has_data_to_read.notified()) };
-        s.pending_notification = Some(pending_notification);
-
         (s, io)
     }
 
-    #[cfg_attr(not(target_os = "linux"), expect(dead_code))]
+    #[cfg_attr(all(not(target_os = "linux"), not(feature = "udp-stream")), expect(dead_code))]
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.send_socket.local_addr()
     }
 
+    pub const fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// To send datagrams to the peer, from another task than the one reading the stream
     pub fn writer(&self) -> UdpStreamWriter {
         UdpStreamWriter {
             send_socket: self.send_socket.clone(),
@@ -177,38 +278,45 @@ impl UdpStream {
 
 impl AsyncRead for UdpStream {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, obuf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        let mut project = self.project();
+        let this = self.get_mut();
+        if this.cancel.is_cancelled() {
+            return Poll::Ready(Ok(()));
+        }
+
         // Look that the timeout for client has not elapsed
-        if let Some(mut deadline) = project.watchdog_deadline.as_pin_mut() {
+        if let Some(deadline) = &mut this.watchdog_deadline {
             if deadline.poll_tick(cx).is_ready() {
-                if !*project.data_read_before_deadline {
+                if !this.data_read_before_deadline {
                     return Poll::Ready(Err(Error::new(
                         ErrorKind::TimedOut,
-                        format!("UDP stream timeout with {}", project.peer),
+                        format!("UDP stream timeout with {}", this.peer),
                     )));
                 };
 
-                *project.data_read_before_deadline = false;
+                this.data_read_before_deadline = false;
                 while deadline.poll_tick(cx).is_ready() {}
             }
         }
 
-        if let Some(notified) = project.pending_notification.as_mut().as_pin_mut() {
-            ready!(notified.poll(cx));
-            project.pending_notification.as_mut().set(None);
+        if this.waiting_data {
+            ready!(this.has_data_to_read.poll(cx));
+            this.waiting_data = false;
+            // Woken up by the server being cancelled, the datagram in the socket is not for this peer
+            if this.cancel.is_cancelled() {
+                return Poll::Ready(Ok(()));
+            }
         }
 
-        let peer = ready!(project.recv_socket.poll_recv_from(cx, obuf))?;
-        debug_assert_eq!(peer, *project.peer);
-        *project.data_read_before_deadline = true;
+        let peer = ready!(this.recv_socket.poll_recv_from(cx, obuf))?;
+        debug_assert_eq!(peer, this.peer);
+        this.data_read_before_deadline = true;
 
         // re-arm notification
-        let notified: Notified<'static> = unsafe { std::mem::transmute(project.io.has_data_to_read.notified()) };
-        project.pending_notification.as_mut().set(Some(notified));
-        project.pending_notification.as_pin_mut().unwrap().enable();
+        this.has_data_to_read.set(data_to_read(this.io.clone()));
+        this.waiting_data = true;
 
         // Let know server that we have read data
-        project.io.has_read_data.notify_one();
+        this.io.has_read_data.notify_one();
 
         Poll::Ready(Ok(()))
     }
@@ -239,62 +347,108 @@ pub async fn run_server(
     configure_listener: impl Fn(&UdpSocket) -> anyhow::Result<()>,
     mk_send_socket: impl Fn(&Arc<UdpSocket>) -> anyhow::Result<Arc<UdpSocket>>,
 ) -> Result<impl Stream<Item = io::Result<UdpStream>>, anyhow::Error> {
-    info!(
-        "Starting UDP server listening cnx on {} with cnx timeout of {}s",
-        bind,
-        timeout.unwrap_or(Duration::from_secs(0)).as_secs()
-    );
-
-    let listener = UdpSocket::bind(bind)
-        .await
-        .with_context(|| format!("Cannot create UDP server {:?}", bind))?;
-    configure_listener(&listener)?;
-
-    let udp_server = UdpServer::new(listener, timeout);
-    let stream = stream::unfold(
-        (udp_server, None, mk_send_socket),
-        |(mut server, peer_with_data, mk_send_socket)| async move {
-            // New returned peer hasn't read its data yet, await for it.
-            if let Some(await_peer) = peer_with_data {
-                if let Some(peer) = server.peers.get(&await_peer) {
-                    peer.has_read_data.notified().await;
-                }
-            };
+    let mut server = UdpServerBuilder::new(bind);
+    server.timeout = timeout;
+    server.listen_with(configure_listener, mk_send_socket).await
+}
 
-            loop {
-                server.clean_dead_keys();
-                let peer_addr = match server.listener.peek_sender().await {
-                    Ok(ret) => ret,
-                    Err(err) => {
-                        error!("Cannot read from UDP server. Closing server: {}", err);
-                        return None;
-                    }
-                };
+/// Udp server handing out a UdpStream for each new peer, like a tcp listener does with connections
+#[derive(Debug, Clone)]
+pub struct UdpServerBuilder {
+    bind: SocketAddr,
+    timeout: Option<Duration>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    cancel: Option<CancellationToken>,
+}
 
-                match server.peers.get(&peer_addr) {
-                    Some(io) => {
-                        io.has_data_to_read.notify_one();
-                        io.has_read_data.notified().await;
-                    }
-                    None => {
-                        info!("New UDP connection from {}", peer_addr);
-                        let (udp_client, io) = UdpStream::new(
-                            server.clone_socket(),
-                            mk_send_socket(&server.listener).ok()?,
-                            peer_addr,
-                            server.cnx_timeout,
-                            Arc::downgrade(&server.keys_to_delete),
-                        );
-                        io.has_data_to_read.notify_waiters();
-                        server.peers.insert(peer_addr, io);
-                        return Some((Ok(udp_client), (server, Some(peer_addr), mk_send_socket)));
-                    }
-                }
-            }
-        },
-    );
+#[cfg_attr(not(feature = "udp-stream"), allow(dead_code))]
+impl UdpServerBuilder {
+    /// Without timeout, and with the largest socket buffers the system allows, up to 64 MiB
+    pub const fn new(bind: SocketAddr) -> Self {
+        Self {
+            bind,
+            timeout: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            cancel: None,
+        }
+    }
+
+    /// The streams fail with TimedOut once their peer sends nothing for this long
+    pub const fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// SO_RCVBUF of the socket, in bytes. It is shared by all the peers
+    pub const fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// SO_SNDBUF of the socket, in bytes. It is shared by all the peers
+    pub const fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// End the server stream and the streams of its peers once this token is cancelled
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Bind the socket. The returned stream must be polled for the streams of the peers to receive their datagrams
+    pub async fn listen(self) -> anyhow::Result<impl Stream<Item = io::Result<UdpStream>>> {
+        self.listen_with(|_| Ok(()), |listener| Ok(listener.clone())).await
+    }
 
-    Ok(stream)
+    async fn listen_with(
+        self,
+        configure_listener: impl Fn(&UdpSocket) -> anyhow::Result<()>,
+        mk_send_socket: impl Fn(&Arc<UdpSocket>) -> anyhow::Result<Arc<UdpSocket>>,
+    ) -> anyhow::Result<impl Stream<Item = io::Result<UdpStream>>> {
+        let Self {
+            bind,
+            timeout,
+            recv_buffer_size,
+            send_buffer_size,
+            cancel,
+        } = self;
+        info!(
+            "Starting UDP server listening cnx on {} with cnx timeout of {}s",
+            bind,
+            timeout.unwrap_or(Duration::from_secs(0)).as_secs()
+        );
+
+        let listener = UdpSocket::bind(bind)
+            .await
+            .with_context(|| format!("Cannot create UDP server {:?}", bind))?;
+        configure_listener(&listener)?;
+
+        let udp_server = UdpServer::new(
+            listener,
+            timeout,
+            recv_buffer_size,
+            send_buffer_size,
+            cancel.unwrap_or_default(),
+        )
+        .with_context(|| format!("Cannot set the buffer sizes of UDP server {:?}", bind))?;
+        let stream = stream::unfold(
+            (udp_server, None, mk_send_socket),
+            |(mut server, peer_with_data, mk_send_socket)| async move {
+                let cancel = server.cancel.clone();
+                let udp_client = cancel
+                    .run_until_cancelled(server.next_peer(peer_with_data, &mk_send_socket))
+                    .await??;
+                let peer_addr = udp_client.peer_addr();
+                Some((Ok(udp_client), (server, Some(peer_addr), mk_send_socket)))
+            },
+        );
+
+        Ok(stream)
+    }
 }
 
 #[derive(Clone)]
@@ -310,15 +464,13 @@ impl WsUdpSocket {
 
 impl AsyncRead for WsUdpSocket {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        unsafe { self.map_unchecked_mut(|x| &mut x.socket) }
-            .poll_recv_from(cx, buf)
-            .map(|x| x.map(|_| ()))
+        self.socket.poll_recv_from(cx, buf).map(|x| x.map(|_| ()))
     }
 }
 
 impl AsyncWrite for WsUdpSocket {
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
-        unsafe { self.map_unchecked_mut(|x| &mut x.socket) }.poll_send(cx, buf)
+        self.socket.poll_send(cx, buf)
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<Result<(), Error>> {
@@ -366,9 +518,7 @@ pub async fn connect(
             }
         };
 
-        so_mark
-            .set_mark(SockRef::from(&socket))
-            .context("cannot set SO_MARK on socket")?;
+        so_mark.apply(SockRef::from(&socket))?;
 
         // Spawn the connection attempt in the join set.
         // We include a delay of ix * 250 milliseconds, as per RFC8305.
@@ -498,7 +648,7 @@ pub fn mk_send_socket_tproxy(listener: &Arc<UdpSocket>) -> anyhow::Result<Arc<Ud
 mod tests {
     use super::*;
     use futures_util::{pin_mut, StreamExt};
-    use tokio::io::AsyncReadExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::time::error::Elapsed;
     use tokio::time::timeout;
 
@@ -619,6 +769,70 @@ mod tests {
         assert_eq!(&buf[..6], b"fffff\0");
     }
 
+    #[tokio::test]
+    async fn test_builder() {
+        let server_addr: SocketAddr = "[::1]:1238".parse().unwrap();
+        let server = UdpServerBuilder::new(server_addr)
+            .timeout(Duration::from_secs(1))
+            .recv_buffer_size(256 * 1024)
+            .send_buffer_size(256 * 1024)
+            .listen()
+            .await
+            .unwrap();
+        pin_mut!(server);
+
+        let client = UdpSocket::bind("[::1]:0").await.unwrap();
+        assert!(client.send_to(b"hello".as_ref(), server_addr).await.is_ok());
+        let mut stream = timeout(Duration::from_millis(100), server.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(stream.peer_addr(), client.local_addr().unwrap());
+        assert_eq!(stream.local_addr().unwrap(), server_addr);
+
+        let mut buf = [0u8; 25];
+        assert!(matches!(stream.read(&mut buf).await, Ok(5)));
+        assert_eq!(&buf[..5], b"hello");
+
+        stream.writer().write_all(b"world").await.unwrap();
+        let ret = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(ret, (5, server_addr));
+        assert_eq!(&buf[..5], b"world");
+    }
+
+    #[tokio::test]
+    async fn test_builder_cancelled() {
+        let server_addr: SocketAddr = "[::1]:1239".parse().unwrap();
+        let cancel = CancellationToken::new();
+        let server = UdpServerBuilder::new(server_addr)
+            .cancellation_token(cancel.clone())
+            .listen()
+            .await
+            .unwrap();
+        pin_mut!(server);
+
+        let client = UdpSocket::bind("[::1]:0").await.unwrap();
+        assert!(client.send_to(b"hello".as_ref(), server_addr).await.is_ok());
+        let mut stream = timeout(Duration::from_millis(100), server.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let mut buf = [0u8; 25];
+        assert!(matches!(stream.read(&mut buf).await, Ok(5)));
+
+        // The stream waiting for the next datagram and the server end
+        let reader = tokio::spawn(async move { stream.read(&mut buf).await.unwrap() });
+        tokio::task::yield_now().await;
+        cancel.cancel();
+        assert!(timeout(Duration::from_millis(100), server.next())
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(timeout(Duration::from_millis(100), reader).await.unwrap().unwrap(), 0);
+    }
+
     #[tokio::test]
     async fn test_udp_should_timeout() {
         let server_addr: SocketAddr = "[::1]:1237".parse().unwrap();
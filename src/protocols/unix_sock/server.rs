@@ -1,19 +1,23 @@
 use anyhow::Context;
 use futures_util::Stream;
+use std::fs::{DirBuilder, Permissions};
 use std::io;
-use std::path::Path;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::Poll;
 use tokio::net::{UnixListener, UnixStream};
 use tracing::log::info;
+use uuid::Uuid;
 
 pub struct UnixListenerStream {
     inner: UnixListener,
-    path_to_delete: bool,
+    /// The path of the socket, if it did not exist before and must be removed with the listener
+    path_to_delete: Option<PathBuf>,
 }
 
 impl UnixListenerStream {
-    pub const fn new(listener: UnixListener, path_to_delete: bool) -> Self {
+    pub const fn new(listener: UnixListener, path_to_delete: Option<PathBuf>) -> Self {
         Self {
             inner: listener,
             path_to_delete,
@@ -23,13 +27,7 @@ impl UnixListenerStream {
 
 impl Drop for UnixListenerStream {
     fn drop(&mut self) {
-        if self.path_to_delete {
-            let Ok(addr) = &self.inner.local_addr() else {
-                return;
-            };
-            let Some(path) = addr.as_pathname() else {
-                return;
-            };
+        if let Some(path) = &self.path_to_delete {
             let _ = std::fs::remove_file(path);
         }
     }
@@ -47,12 +45,75 @@ impl Stream for UnixListenerStream {
     }
 }
 
-pub async fn run_server(socket_path: &Path) -> Result<UnixListenerStream, anyhow::Error> {
+/// `mode` sets the permissions of the socket file (i.e: 0o660), otherwise they are derived from the umask
+pub async fn run_server(socket_path: &Path, mode: Option<u32>) -> Result<UnixListenerStream, anyhow::Error> {
     info!("Starting Unix socket server listening cnx on {:?}", socket_path);
 
-    let path_to_delete = !socket_path.exists();
-    let listener = UnixListener::bind(socket_path)
-        .with_context(|| format!("Cannot create Unix socket server {:?}", socket_path))?;
+    let path_to_delete = (!socket_path.exists()).then(|| socket_path.to_path_buf());
+    let listener = match mode {
+        Some(mode) => bind_with_mode(socket_path, mode),
+        None => UnixListener::bind(socket_path),
+    }
+    .with_context(|| format!("Cannot create Unix socket server {:?}", socket_path))?;
 
     Ok(UnixListenerStream::new(listener, path_to_delete))
 }
+
+/// The socket is created in a private directory next to its path, where nobody else can connect to it, and only linked
+/// to its path once its permissions are set. Unlike changing the umask, it does not affect the files created meanwhile
+/// by the rest of the process
+fn bind_with_mode(socket_path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let file_name = socket_path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name in the socket path"))?;
+    let parent = socket_path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    // On the same filesystem as the socket path, to link it there
+    let private_dir = parent.join(format!(".{}.{}", file_name.to_string_lossy(), Uuid::now_v7().simple()));
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let private_path = private_dir.join(file_name);
+
+    let listener = (|| {
+        let listener = std::os::unix::net::UnixListener::bind(&private_path)?;
+        std::fs::set_permissions(&private_path, Permissions::from_mode(mode))?;
+        // Fails like bind if the path already exists
+        std::fs::hard_link(&private_path, socket_path)?;
+        listener.set_nonblocking(true)?;
+        UnixListener::from_std(listener)
+    })();
+    let _ = std::fs::remove_file(&private_path);
+    let _ = std::fs::remove_dir(&private_dir);
+
+    listener
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileTypeExt;
+
+    #[tokio::test]
+    async fn test_socket_mode() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-sock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wstunnel.sock");
+        let listener = run_server(&path, Some(0o600)).await.unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // Only the socket is left, not its private directory
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let _client = UnixStream::connect(&path).await.unwrap();
+
+        // Like bind, an existing path is not replaced
+        assert!(run_server(&path, Some(0o600)).await.is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        drop(listener);
+        assert!(!path.exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! Restrictions built in code, i.e: by a program embedding the server or a provisioning tool writing the
+//! --restrict-config file, instead of read from a file
+
+use crate::restrictions::types::{
+    AllowConfig, MatchConfig, QuotaConfig, RestrictionConfig, RestrictionsRules, TimeWindowConfig,
+};
+use anyhow::{anyhow, bail};
+use hyper::header::HeaderName;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct RestrictionsBuilder {
+    restrictions: Vec<RestrictionConfig>,
+}
+
+impl RestrictionsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn restriction(mut self, restriction: RestrictionBuilder) -> Self {
+        self.restrictions.push(restriction.0);
+        self
+    }
+
+    /// Checked like a restrictions file, and sorted by decreasing priority
+    pub fn build(self) -> anyhow::Result<RestrictionsRules> {
+        let mut restrictions = self.restrictions;
+        for restriction in &restrictions {
+            validate(restriction).map_err(|err| anyhow!("Invalid restriction {}: {}", restriction.name, err))?;
+        }
+        restrictions.sort_by_key(|restriction| std::cmp::Reverse(restriction.priority));
+
+        Ok(RestrictionsRules { restrictions })
+    }
+}
+
+fn validate(restriction: &RestrictionConfig) -> anyhow::Result<()> {
+    if restriction.r#match.is_empty() {
+        bail!("it has no match, it must have at least one");
+    }
+    for allow in &restriction.allow {
+        if let AllowConfig::Tunnel(tunnel) = allow {
+            if let Some(name) = &tunnel.forwarded_header {
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| anyhow!("invalid header name {}", name))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Restriction applying to the clients matching all its matches, allowing the tunnels of any of its allow rules
+#[derive(Debug)]
+pub struct RestrictionBuilder(RestrictionConfig);
+
+impl RestrictionBuilder {
+    /// Without any match nor allow rule, with the priority 0
+    pub fn new(name: impl Into<String>) -> Self {
+        Self(RestrictionConfig {
+            name: name.into(),
+            priority: 0,
+            r#match: vec![],
+            allow: vec![],
+            quota: QuotaConfig::default(),
+            time_window: TimeWindowConfig::default(),
+            max_session_duration: None,
+            interceptors: vec![],
+        })
+    }
+
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.0.priority = priority;
+        self
+    }
+
+    pub fn matching(mut self, r#match: MatchConfig) -> Self {
+        self.0.r#match.push(r#match);
+        self
+    }
+
+    /// i.e: AllowTunnelConfig { port: vec![443..=443], ..Default::default() }
+    pub fn allow(mut self, allow: impl Into<AllowConfig>) -> Self {
+        self.0.allow.push(allow.into());
+        self
+    }
+
+    pub fn quota(mut self, quota: QuotaConfig) -> Self {
+        self.0.quota = quota;
+        self
+    }
+
+    pub fn time_window(mut self, time_window: TimeWindowConfig) -> Self {
+        self.0.time_window = time_window;
+        self
+    }
+
+    pub fn max_session_duration(mut self, duration: Duration) -> Self {
+        self.0.max_session_duration = Some(duration);
+        self
+    }
+
+    /// Stream interceptor registered by the program embedding the server
+    pub fn interceptor(mut self, name: impl Into<String>) -> Self {
+        self.0.interceptors.push(name.into());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restrictions::types::{
+        host_glob, AllowReverseTunnelConfig, AllowTunnelConfig, ReverseTunnelConfigProtocol, TimeZoneConfig,
+    };
+    use crate::secret::Secret;
+    use chrono::{NaiveTime, Weekday};
+    use regex::Regex;
+
+    #[test]
+    fn test_build_and_serialize() {
+        let restrictions = RestrictionsBuilder::new()
+            .restriction(
+                RestrictionBuilder::new("Web")
+                    .matching(MatchConfig::PathPrefix(Regex::new("^web-").unwrap()))
+                    .allow(AllowTunnelConfig {
+                        port: vec![80..=80, 8000..=8100],
+                        host: host_glob("*.example.com").unwrap(),
+                        forwarded_header: Some("X-Forwarded-For".to_string()),
+                        ..Default::default()
+                    })
+                    .max_session_duration(Duration::from_secs(3600)),
+            )
+            .restriction(
+                RestrictionBuilder::new("Admin")
+                    .priority(10)
+                    .matching(MatchConfig::PathPrefixSecret(Secret::new("s3cr3t".to_string())))
+                    .allow(AllowTunnelConfig::default())
+                    .allow(AllowReverseTunnelConfig {
+                        protocol: vec![ReverseTunnelConfigProtocol::Tcp],
+                        port_mapping: [(80, 8080)].into(),
+                        ..Default::default()
+                    })
+                    .quota(QuotaConfig {
+                        max_concurrent_connections: Some(5),
+                        ..Default::default()
+                    })
+                    .time_window(TimeWindowConfig {
+                        allowed_hours: vec![(
+                            NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                            NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+                        )],
+                        allowed_days: vec![Weekday::Mon, Weekday::Tue],
+                        timezone: TimeZoneConfig::Fixed("+02:00".parse().unwrap()),
+                    })
+                    .interceptor("audit"),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(restrictions.restrictions[0].name, "Admin");
+
+        // Serialized, it is the same restrictions file
+        let yaml = serde_yaml::to_string(&restrictions).unwrap();
+        let parsed: RestrictionsRules = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(serde_yaml::to_string(&parsed).unwrap(), yaml);
+
+        let admin = &parsed.restrictions[0];
+        assert!(matches!(&admin.r#match[0], MatchConfig::PathPrefixSecret(secret) if secret.matches("s3cr3t")));
+        assert_eq!(admin.quota.max_concurrent_connections, Some(5));
+        assert_eq!(admin.time_window.allowed_days, vec![Weekday::Mon, Weekday::Tue]);
+        assert_eq!(admin.interceptors, vec!["audit".to_string()]);
+        let AllowConfig::ReverseTunnel(reverse) = &admin.allow[1] else {
+            panic!("expected a reverse tunnel rule");
+        };
+        assert_eq!(reverse.port_mapping.get(&80), Some(&8080));
+
+        let web = &parsed.restrictions[1];
+        assert_eq!(web.max_session_duration, Some(Duration::from_secs(3600)));
+        let AllowConfig::Tunnel(tunnel) = &web.allow[0] else {
+            panic!("expected a tunnel rule");
+        };
+        assert_eq!(tunnel.port, vec![80..=80, 8000..=8100]);
+        assert!(tunnel.host.is_match("www.example.com"));
+        assert!(!tunnel.host.is_match("www.example.com.evil"));
+    }
+
+    #[test]
+    fn test_build_invalid() {
+        let without_match = RestrictionsBuilder::new()
+            .restriction(RestrictionBuilder::new("Empty").allow(AllowTunnelConfig::default()))
+            .build();
+        assert!(without_match.is_err());
+
+        let bad_header = RestrictionsBuilder::new()
+            .restriction(
+                RestrictionBuilder::new("Bad")
+                    .matching(MatchConfig::Any)
+                    .allow(AllowTunnelConfig {
+                        forwarded_header: Some("Bad Header".to_string()),
+                        ..Default::default()
+                    }),
+            )
+            .build();
+        assert!(bad_header.is_err());
+    }
+}
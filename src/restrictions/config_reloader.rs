@@ -1,5 +1,7 @@
 use super::types::RestrictionsRules;
 use crate::restrictions::config_reloader::RestrictionsRulesReloaderState::{Config, Static};
+#[cfg(unix)]
+use crate::tunnel::spawn_until_cancelled;
 use anyhow::Context;
 use arc_swap::ArcSwap;
 use log::trace;
@@ -9,10 +11,12 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 
 struct ConfigReloaderState {
-    fs_watcher: Mutex<RecommendedWatcher>,
+    /// None if the file cannot be watched, i.e: no more inotify watches available. Only SIGHUP reloads the config then
+    fs_watcher: Mutex<Option<RecommendedWatcher>>,
     config_path: PathBuf,
 }
 
@@ -23,7 +27,7 @@ enum RestrictionsRulesReloaderState {
 }
 
 impl RestrictionsRulesReloaderState {
-    fn fs_watcher(&self) -> &Mutex<RecommendedWatcher> {
+    fn fs_watcher(&self) -> &Mutex<Option<RecommendedWatcher>> {
         match self {
             Static => unreachable!(),
             Config(this) => &this.fs_watcher,
@@ -38,7 +42,12 @@ pub struct RestrictionsRulesReloader {
 }
 
 impl RestrictionsRulesReloader {
-    pub fn new(restrictions_rules: RestrictionsRules, config_path: Option<PathBuf>) -> anyhow::Result<Self> {
+    /// The config file is reloaded on SIGHUP until the token is cancelled
+    pub fn new(
+        restrictions_rules: RestrictionsRules,
+        config_path: Option<PathBuf>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<Self> {
         // If there is no custom certificate and private key, there is nothing to watch
         let config_path = if let Some(config_path) = config_path {
             config_path
@@ -50,29 +59,57 @@ impl RestrictionsRulesReloader {
         };
         let reloader = Self {
             state: Config(Arc::new(ConfigReloaderState {
-                fs_watcher: Mutex::new(notify::recommended_watcher(|_| {})?),
+                fs_watcher: Mutex::new(None),
                 config_path,
             })),
             restrictions: Arc::new(ArcSwap::from_pointee(restrictions_rules)),
         };
 
         info!("Starting to watch restriction config file for changes to reload them");
+        if let Err(err) = reloader.watch_config() {
+            warn!(
+                "Cannot watch restrictions config file for changes, it will only be reloaded on SIGHUP: {:?}",
+                err
+            );
+        }
+        #[cfg(unix)]
+        reloader.reload_on_sighup(cancel)?;
+
+        Ok(reloader)
+    }
+
+    fn watch_config(&self) -> anyhow::Result<()> {
+        let Config(cfg) = &self.state else {
+            return Ok(());
+        };
+
         let mut watcher = notify::recommended_watcher({
-            let reloader = reloader.clone();
+            let reloader = self.clone();
 
             move |event: notify::Result<notify::Event>| Self::handle_config_fs_event(&reloader, event)
         })
         .with_context(|| "Cannot create restriction config watcher")?;
+        watcher.watch(&cfg.config_path, notify::RecursiveMode::NonRecursive)?;
+        *cfg.fs_watcher.lock() = Some(watcher);
 
-        match &reloader.state {
-            Static => {}
-            Config(cfg) => {
-                watcher.watch(&cfg.config_path, notify::RecursiveMode::NonRecursive)?;
-                *cfg.fs_watcher.lock() = watcher
+        Ok(())
+    }
+
+    /// Fallback for when the file cannot be watched or when changes are not seen (i.e: network filesystem)
+    #[cfg(unix)]
+    fn reload_on_sighup(&self, cancel: &CancellationToken) -> anyhow::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sighup = signal(SignalKind::hangup()).with_context(|| "Cannot listen for SIGHUP")?;
+        let reloader = self.clone();
+        spawn_until_cancelled(cancel, async move {
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading restrictions config file");
+                reloader.reload_restrictions_config();
             }
-        }
+        });
 
-        Ok(reloader)
+        Ok(())
     }
 
     pub fn reload_restrictions_config(&self) {
@@ -106,18 +143,22 @@ impl RestrictionsRulesReloader {
                 );
                 thread::sleep(Duration::from_secs(10));
             }
-            let mut watcher = this.state.fs_watcher().lock();
-            let _ = watcher.unwatch(&path);
-            let Ok(_) = watcher
-                .watch(&path, notify::RecursiveMode::NonRecursive)
-                .map_err(|err| {
-                    error!("Cannot re-set a watch for Restriction config file {:?}: {:?}", path, err);
-                    error!("Restriction config file will not be auto-reloaded anymore");
-                })
-            else {
-                return;
-            };
-            drop(watcher);
+            {
+                let mut watcher = this.state.fs_watcher().lock();
+                let Some(watcher) = watcher.as_mut() else {
+                    return;
+                };
+                let _ = watcher.unwatch(&path);
+                let Ok(_) = watcher
+                    .watch(&path, notify::RecursiveMode::NonRecursive)
+                    .map_err(|err| {
+                        error!("Cannot re-set a watch for Restriction config file {:?}: {:?}", path, err);
+                        error!("Restriction config file will not be auto-reloaded anymore");
+                    })
+                else {
+                    return;
+                };
+            }
 
             // Generate a fake event to force-reload the config
             let event = notify::Event {
@@ -165,3 +206,44 @@ impl RestrictionsRulesReloader {
         }
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn restriction_yaml(name: &str) -> String {
+        format!("restrictions:\n  - name: {name}\n    match:\n      - !Any\n    allow:\n      - !Tunnel\n")
+    }
+
+    #[tokio::test]
+    async fn test_reload_on_sighup_without_watcher() {
+        let path = std::env::temp_dir().join(format!("wstunnel-sighup-{}.yaml", std::process::id()));
+        std::fs::write(&path, restriction_yaml("before")).unwrap();
+        let cancel = CancellationToken::new();
+        let rules = RestrictionsRules::from_config_file(&path).unwrap();
+        let reloader = RestrictionsRulesReloader::new(rules, Some(path.clone()), &cancel).unwrap();
+
+        // Behave as if the file could not be watched, only SIGHUP is left to reload it
+        *reloader.state.fs_watcher().lock() = None;
+        std::fs::write(&path, restriction_yaml("after")).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(reloader.restrictions_rules().load().restrictions[0].name, "before");
+
+        nix::sys::signal::raise(nix::sys::signal::Signal::SIGHUP).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reloader.restrictions_rules().load().restrictions[0].name != "after" {
+            assert!(Instant::now() < deadline, "restrictions were not reloaded on SIGHUP");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // An invalid file keeps the previous restrictions
+        std::fs::write(&path, "restrictions: [").unwrap();
+        nix::sys::signal::raise(nix::sys::signal::Signal::SIGHUP).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(reloader.restrictions_rules().load().restrictions[0].name, "after");
+
+        cancel.cancel();
+        let _ = std::fs::remove_file(&path);
+    }
+}
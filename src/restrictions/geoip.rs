@@ -0,0 +1,466 @@
+use anyhow::{anyhow, bail, ensure, Context};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+const DATA_SECTION_SEPARATOR: usize = 16;
+const MAX_DATA_DEPTH: u8 = 32;
+
+/// Where a client comes from, according to the loaded databases
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoIpInfo {
+    /// ISO 3166-1 alpha-2 code, i.e: FR
+    pub country: Option<String>,
+    /// Autonomous system number, i.e: 13335
+    pub asn: Option<u32>,
+}
+
+/// MaxMind databases (GeoLite2/GeoIP2 Country, City or ASN) used to find where the clients come from,
+/// by the geoip matchers of the restrictions and of --allow-from/--deny-from
+#[derive(Default)]
+pub struct GeoIpDatabases(Vec<MaxMindDb>);
+
+impl GeoIpDatabases {
+    pub fn load(paths: &[impl AsRef<Path>]) -> anyhow::Result<Self> {
+        let databases = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                std::fs::read(path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(MaxMindDb::new)
+                    .with_context(|| format!("Cannot load geoip database {}", path.display()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self(databases))
+    }
+
+    /// Country and ASN of this address, merged from all the databases. Empty if none is loaded
+    pub fn lookup(&self, ip: IpAddr) -> GeoIpInfo {
+        let mut info = GeoIpInfo::default();
+        for db in &self.0 {
+            match db.lookup(ip) {
+                Ok(Some(record)) => {
+                    let found = GeoIpInfo::from(&record);
+                    info.country = info.country.or(found.country);
+                    info.asn = info.asn.or(found.asn);
+                }
+                Ok(None) => {}
+                Err(err) => tracing::warn!("Cannot lookup {} in geoip database: {:?}", ip, err),
+            }
+        }
+
+        info
+    }
+}
+
+/// Source of the clients allowed or denied to connect to the server, by network, country or ASN
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceFilter {
+    Cidr(IpNet),
+    Country(String),
+    Asn(u32),
+}
+
+impl SourceFilter {
+    /// The geoip info of the source is only looked up if needed
+    pub fn matches<'a>(&self, ip: IpAddr, geoip: impl FnOnce() -> &'a GeoIpInfo) -> bool {
+        match self {
+            Self::Cidr(cidr) => cidr.contains(&ip.to_canonical()),
+            Self::Country(country) => geoip()
+                .country
+                .as_ref()
+                .is_some_and(|code| code.eq_ignore_ascii_case(country)),
+            Self::Asn(asn) => geoip().asn == Some(*asn),
+        }
+    }
+}
+
+impl FromStr for SourceFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(country) = s.strip_prefix("country:") {
+            ensure!(
+                country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()),
+                "invalid country code {}, expected 2 letters",
+                country
+            );
+            return Ok(Self::Country(country.to_ascii_uppercase()));
+        }
+        if let Some(asn) = s.strip_prefix("asn:") {
+            let asn = asn.trim_start_matches("AS").trim_start_matches("as");
+            return Ok(Self::Asn(asn.parse().with_context(|| format!("invalid asn {}", asn))?));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Self::Cidr(IpNet::from(ip)));
+        }
+
+        Ok(Self::Cidr(s.parse()?))
+    }
+}
+
+impl From<&DataValue> for GeoIpInfo {
+    fn from(record: &DataValue) -> Self {
+        let country = ["country", "registered_country"].iter().find_map(|key| {
+            match record.get(key).and_then(|country| country.get("iso_code")) {
+                Some(DataValue::String(code)) => Some(code.clone()),
+                _ => None,
+            }
+        });
+        let asn = match record.get("autonomous_system_number") {
+            Some(DataValue::Uint(asn)) => u32::try_from(*asn).ok(),
+            _ => None,
+        };
+
+        Self { country, asn }
+    }
+}
+
+/// Value of the data section of a MaxMind database
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataValue {
+    String(String),
+    Bytes(Vec<u8>),
+    Uint(u128),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+    Map(Vec<(String, DataValue)>),
+    Array(Vec<DataValue>),
+}
+
+impl DataValue {
+    fn get(&self, key: &str) -> Option<&DataValue> {
+        match self {
+            Self::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+/// Reader of the MaxMind DB format https://maxmind.github.io/MaxMind-DB/
+pub struct MaxMindDb {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u16,
+    data_section: usize,
+    data_section_end: usize,
+    ipv4_start: usize,
+}
+
+impl MaxMindDb {
+    pub fn new(buf: Vec<u8>) -> anyhow::Result<Self> {
+        let marker = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| anyhow!("not a MaxMind database, metadata not found"))?;
+        let (metadata, _) = decode(&buf[marker + METADATA_MARKER.len()..], 0, 0)?;
+
+        let uint = |key: &str| match metadata.get(key) {
+            Some(DataValue::Uint(value)) => usize::try_from(*value).map_err(anyhow::Error::from),
+            _ => Err(anyhow!("invalid metadata, missing {}", key)),
+        };
+        let node_count = uint("node_count")?;
+        let record_size = uint("record_size")?;
+        let ip_version = uint("ip_version")? as u16;
+        ensure!(matches!(record_size, 24 | 28 | 32), "unsupported record size {}", record_size);
+        ensure!(matches!(ip_version, 4 | 6), "unsupported ip version {}", ip_version);
+
+        let data_section = node_count * record_size / 4 + DATA_SECTION_SEPARATOR;
+        ensure!(data_section <= marker, "invalid database, search tree is truncated");
+
+        let mut db = Self {
+            buf,
+            node_count,
+            record_size,
+            ip_version,
+            data_section,
+            data_section_end: marker,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            // IPv4 addresses are looked up in ::/96
+            for _ in 0..96 {
+                if db.ipv4_start >= db.node_count {
+                    break;
+                }
+                db.ipv4_start = db.read_record(db.ipv4_start, 0)?;
+            }
+        }
+
+        Ok(db)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> anyhow::Result<Option<DataValue>> {
+        let (bits, bit_count, mut node) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32, self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+            IpAddr::V6(ip) => (u128::from(ip), 128, 0),
+        };
+
+        for i in (0..bit_count).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.read_record(node, ((bits >> i) & 1) as usize)?;
+        }
+
+        match node.cmp(&self.node_count) {
+            std::cmp::Ordering::Equal => Ok(None),
+            std::cmp::Ordering::Greater => {
+                let offset = node - self.node_count - DATA_SECTION_SEPARATOR;
+                let (value, _) = decode(&self.buf[self.data_section..self.data_section_end], offset, 0)?;
+                Ok(Some(value))
+            }
+            std::cmp::Ordering::Less => bail!("invalid database, search tree is too deep"),
+        }
+    }
+
+    fn read_record(&self, node: usize, bit: usize) -> anyhow::Result<usize> {
+        let node_size = self.record_size / 4;
+        let b = self
+            .buf
+            .get(node * node_size..(node + 1) * node_size)
+            .ok_or_else(|| anyhow!("invalid database, node {} is out of the search tree", node))?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+
+        Ok(match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => ((b[3] as usize & 0xF0) << 20) | be(&b[0..3]),
+            (28, _) => ((b[3] as usize & 0x0F) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        })
+    }
+}
+
+/// Decode the value at this offset of the section, returns it with the offset following it
+fn decode(section: &[u8], offset: usize, depth: u8) -> anyhow::Result<(DataValue, usize)> {
+    ensure!(depth < MAX_DATA_DEPTH, "invalid database, data is too deeply nested");
+    let bytes = |offset: usize, len: usize| {
+        section
+            .get(offset..offset + len)
+            .ok_or_else(|| anyhow!("invalid database, data is truncated"))
+    };
+    let be = |bytes: &[u8]| bytes.iter().fold(0u128, |acc, b| (acc << 8) | u128::from(*b));
+
+    let ctrl = bytes(offset, 1)?[0];
+    let mut offset = offset + 1;
+    let mut kind = ctrl >> 5;
+
+    // Pointer to another value of the section
+    if kind == 1 {
+        let len = ((ctrl >> 3) & 0x3) as usize + 1;
+        let value = be(bytes(offset, len)?) as usize;
+        let pointer = match len {
+            1 => (ctrl as usize & 0x7) << 8 | value,
+            2 => ((ctrl as usize & 0x7) << 16 | value) + 2048,
+            3 => ((ctrl as usize & 0x7) << 24 | value) + 526_336,
+            _ => value,
+        };
+        let (value, _) = decode(section, pointer, depth + 1)?;
+        return Ok((value, offset + len));
+    }
+
+    if kind == 0 {
+        kind = 7 + bytes(offset, 1)?[0];
+        offset += 1;
+    }
+
+    let mut size = (ctrl & 0x1F) as usize;
+    if size >= 29 {
+        let len = size - 28;
+        size = [29, 285, 65_821][len - 1] + be(bytes(offset, len)?) as usize;
+        offset += len;
+    }
+
+    let value = match kind {
+        2 => DataValue::String(String::from_utf8(bytes(offset, size)?.to_vec())?),
+        3 => {
+            ensure!(size == 8, "invalid database, double of {} bytes", size);
+            DataValue::Double(f64::from_be_bytes(bytes(offset, 8)?.try_into()?))
+        }
+        4 => DataValue::Bytes(bytes(offset, size)?.to_vec()),
+        5 | 6 | 9 | 10 => {
+            ensure!(size <= 16, "invalid database, integer of {} bytes", size);
+            DataValue::Uint(be(bytes(offset, size)?))
+        }
+        8 => {
+            ensure!(size <= 4, "invalid database, integer of {} bytes", size);
+            DataValue::Int(be(bytes(offset, size)?) as u32 as i32)
+        }
+        15 => {
+            ensure!(size == 4, "invalid database, float of {} bytes", size);
+            DataValue::Double(f64::from(f32::from_be_bytes(bytes(offset, 4)?.try_into()?)))
+        }
+        14 => return Ok((DataValue::Bool(size != 0), offset)),
+        7 => {
+            let mut entries = Vec::with_capacity(size.min(64));
+            for _ in 0..size {
+                let (key, next) = decode(section, offset, depth + 1)?;
+                let DataValue::String(key) = key else {
+                    bail!("invalid database, map key is not a string");
+                };
+                let (value, next) = decode(section, next, depth + 1)?;
+                entries.push((key, value));
+                offset = next;
+            }
+            return Ok((DataValue::Map(entries), offset));
+        }
+        11 => {
+            let mut values = Vec::with_capacity(size.min(64));
+            for _ in 0..size {
+                let (value, next) = decode(section, offset, depth + 1)?;
+                values.push(value);
+                offset = next;
+            }
+            return Ok((DataValue::Array(values), offset));
+        }
+        kind => bail!("invalid database, unsupported data type {}", kind),
+    };
+
+    Ok((value, offset + size))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn encode_string(s: &str) -> Vec<u8> {
+        let mut buf = match s.len() {
+            len @ 0..29 => vec![(2 << 5) | len as u8],
+            len => vec![(2 << 5) | 29, (len - 29) as u8],
+        };
+        buf.extend_from_slice(s.as_bytes());
+        buf
+    }
+
+    fn encode_uint32(value: u32) -> Vec<u8> {
+        let mut buf = vec![(6 << 5) | 4];
+        buf.extend_from_slice(&value.to_be_bytes());
+        buf
+    }
+
+    fn encode_map(entries: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+        let mut buf = vec![(7 << 5) | entries.len() as u8];
+        for (key, value) in entries {
+            buf.extend(encode_string(key));
+            buf.extend(value);
+        }
+        buf
+    }
+
+    /// Database with 24 bits records, where only the network is found with this record
+    fn database(ip_version: u8, network: IpNet, record: Vec<u8>) -> Vec<u8> {
+        let node_count = network.prefix_len() as usize;
+        let found = node_count + DATA_SECTION_SEPARATOR;
+        let bits = match network.network() {
+            IpAddr::V4(ip) => u128::from(u32::from(ip)) << 96,
+            IpAddr::V6(ip) => u128::from(ip),
+        };
+
+        let mut buf = vec![];
+        for i in 0..node_count {
+            let next = if i + 1 == node_count { found } else { i + 1 };
+            let records = if (bits >> (127 - i)) & 1 == 0 {
+                [next, node_count]
+            } else {
+                [node_count, next]
+            };
+            for record in records {
+                buf.extend_from_slice(&(record as u32).to_be_bytes()[1..]);
+            }
+        }
+        buf.extend_from_slice(&[0; DATA_SECTION_SEPARATOR]);
+        buf.extend(record);
+        buf.extend_from_slice(METADATA_MARKER);
+        buf.extend(encode_map(vec![
+            ("node_count", encode_uint32(node_count as u32)),
+            ("record_size", vec![(5 << 5) | 1, 24]),
+            ("ip_version", vec![(5 << 5) | 1, ip_version]),
+        ]));
+        buf
+    }
+
+    pub(crate) fn country_record(country: &str) -> Vec<u8> {
+        encode_map(vec![("country", encode_map(vec![("iso_code", encode_string(country))]))])
+    }
+
+    pub(crate) fn asn_record(asn: u32) -> Vec<u8> {
+        encode_map(vec![("autonomous_system_number", encode_uint32(asn))])
+    }
+
+    /// Ipv4 database, where only the network is found with this record
+    pub(crate) fn geoip_databases(network: &str, record: Vec<u8>) -> GeoIpDatabases {
+        GeoIpDatabases(vec![MaxMindDb::new(database(4, network.parse().unwrap(), record)).unwrap()])
+    }
+
+    #[test_case("192.0.2.1" => Some("FR".to_string()) ; "in network")]
+    #[test_case("192.0.3.1" => None ; "out of network")]
+    #[test_case("::ffff:192.0.2.1" => Some("FR".to_string()) ; "ipv4 mapped")]
+    #[test_case("2001:db8::1" => None ; "ipv6 in ipv4 database")]
+    fn test_lookup_country_ipv4(ip: &str) -> Option<String> {
+        let db = MaxMindDb::new(database(4, "192.0.2.0/24".parse().unwrap(), country_record("FR"))).unwrap();
+        db.lookup(ip.parse().unwrap())
+            .unwrap()
+            .map(|record| GeoIpInfo::from(&record).country.unwrap())
+    }
+
+    #[test_case("2001:db8::1" => Some(13335) ; "in network")]
+    #[test_case("2001:db9::1" => None ; "out of network")]
+    #[test_case("192.0.2.1" => None ; "ipv4")]
+    fn test_lookup_asn_ipv6(ip: &str) -> Option<u32> {
+        let record = encode_map(vec![
+            ("autonomous_system_number", encode_uint32(13335)),
+            ("autonomous_system_organization", encode_string("Example")),
+        ]);
+        let db = MaxMindDb::new(database(6, "2001:db8::/32".parse().unwrap(), record)).unwrap();
+        db.lookup(ip.parse().unwrap())
+            .unwrap()
+            .and_then(|record| GeoIpInfo::from(&record).asn)
+    }
+
+    #[test]
+    fn test_invalid_database() {
+        assert!(MaxMindDb::new(b"not a database".to_vec()).is_err());
+    }
+
+    #[test_case("10.0.0.0/8" => SourceFilter::Cidr("10.0.0.0/8".parse().unwrap()) ; "cidr")]
+    #[test_case("10.0.0.1" => SourceFilter::Cidr("10.0.0.1/32".parse().unwrap()) ; "ip")]
+    #[test_case("country:fr" => SourceFilter::Country("FR".to_string()) ; "country")]
+    #[test_case("asn:AS13335" => SourceFilter::Asn(13335) ; "asn")]
+    fn test_parse_source_filter(filter: &str) -> SourceFilter {
+        filter.parse().unwrap()
+    }
+
+    #[test_case("country:FRA" ; "bad country")]
+    #[test_case("asn:abc" ; "bad asn")]
+    #[test_case("foo" ; "bad cidr")]
+    fn test_parse_invalid_source_filter(filter: &str) {
+        assert!(filter.parse::<SourceFilter>().is_err());
+    }
+
+    #[test]
+    fn test_source_filter_matches() {
+        let ip = "192.0.2.1".parse().unwrap();
+        let info = GeoIpInfo {
+            country: Some("FR".to_string()),
+            asn: Some(13335),
+        };
+        let geoip = || &info;
+
+        assert!(SourceFilter::Country("FR".to_string()).matches(ip, geoip));
+        assert!(!SourceFilter::Country("DE".to_string()).matches(ip, geoip));
+        assert!(SourceFilter::Asn(13335).matches(ip, geoip));
+        assert!(SourceFilter::Cidr("192.0.2.0/24".parse().unwrap()).matches(ip, geoip));
+        let unknown = GeoIpInfo::default();
+        assert!(!SourceFilter::Country("FR".to_string()).matches(ip, || &unknown));
+    }
+}
@@ -12,8 +12,11 @@ use regex::Regex;
 use types::RestrictionsRules;
 
 use crate::restrictions::types::{default_cidr, default_host};
+use crate::secret::Secret;
 
-pub mod config_reloader;
+pub mod builder;
+pub(crate) mod config_reloader;
+pub(crate) mod geoip;
 pub mod types;
 
 impl RestrictionsRules {
@@ -22,18 +25,25 @@ impl RestrictionsRules {
         Ok(restrictions)
     }
 
-    pub fn from_path_prefix(path_prefixes: &[String], restrict_to: &[(String, u16)]) -> anyhow::Result<Self> {
+    pub fn from_path_prefix(
+        path_prefixes: &[String],
+        hmac_secrets: &[String],
+        restrict_to: &[(String, u16)],
+    ) -> anyhow::Result<Self> {
         let tunnels_restrictions = if restrict_to.is_empty() {
             let r = types::AllowConfig::Tunnel(types::AllowTunnelConfig {
                 protocol: vec![],
                 port: vec![],
                 host: default_host(),
                 cidr: default_cidr(),
+                proxy_protocol: None,
+                forwarded_header: None,
             });
             let reverse_tunnel = types::AllowConfig::ReverseTunnel(types::AllowReverseTunnelConfig {
                 protocol: vec![],
                 port: vec![],
                 port_mapping: Default::default(),
+                isolated_port: vec![],
                 cidr: default_cidr(),
             });
 
@@ -50,11 +60,14 @@ impl RestrictionsRules {
                                 port: vec![RangeInclusive::new(*port, *port)],
                                 host: reg,
                                 cidr: default_cidr(),
+                                proxy_protocol: None,
+                                forwarded_header: None,
                             }),
                             types::AllowConfig::ReverseTunnel(types::AllowReverseTunnelConfig {
                                 protocol: vec![],
                                 port: vec![RangeInclusive::new(*port, *port)],
                                 port_mapping: Default::default(),
+                                isolated_port: vec![],
                                 cidr: vec![IpNet::new(ip, if ip.is_ipv4() { 32 } else { 128 })?],
                             }),
                         ]
@@ -65,11 +78,14 @@ impl RestrictionsRules {
                                 port: vec![RangeInclusive::new(*port, *port)],
                                 host: reg,
                                 cidr: default_cidr(),
+                                proxy_protocol: None,
+                                forwarded_header: None,
                             }),
                             types::AllowConfig::ReverseTunnel(types::AllowReverseTunnelConfig {
                                 protocol: vec![],
                                 port: vec![],
                                 port_mapping: Default::default(),
+                                isolated_port: vec![],
                                 cidr: default_cidr(),
                             }),
                         ]
@@ -83,25 +99,48 @@ impl RestrictionsRules {
                 .collect()
         };
 
-        let restrictions = if path_prefixes.is_empty() {
+        let restrictions = if path_prefixes.is_empty() && hmac_secrets.is_empty() {
             // if no path prefixes are provided, we allow all
             let r = types::RestrictionConfig {
                 name: "Allow All".to_string(),
+                priority: 0,
                 r#match: vec![types::MatchConfig::Any],
                 allow: tunnels_restrictions,
+                quota: Default::default(),
+                time_window: Default::default(),
+                max_session_duration: None,
+                interceptors: vec![],
             };
             vec![r]
         } else {
+            // Path prefixes are the secrets of the clients, they are not put in the names which are logged
             path_prefixes
                 .iter()
-                .map(|path_prefix| {
-                    let reg = Regex::new(&format!("^{}$", regex::escape(path_prefix)))?;
+                .enumerate()
+                .map(|(ix, path_prefix)| {
                     Ok(types::RestrictionConfig {
-                        name: format!("Allow path prefix {}", path_prefix),
-                        r#match: vec![types::MatchConfig::PathPrefix(reg)],
+                        name: format!("Allow path prefix {}", ix),
+                        priority: 0,
+                        r#match: vec![types::MatchConfig::PathPrefixSecret(Secret::new(path_prefix.clone()))],
                         allow: tunnels_restrictions.clone(),
+                        quota: Default::default(),
+                        time_window: Default::default(),
+                        max_session_duration: None,
+                        interceptors: vec![],
                     })
                 })
+                .chain(hmac_secrets.iter().enumerate().map(|(ix, secret)| {
+                    Ok(types::RestrictionConfig {
+                        name: format!("Allow hmac path prefix {}", ix),
+                        priority: 0,
+                        r#match: vec![types::MatchConfig::PathPrefixHmac(Secret::new(secret.clone()))],
+                        allow: tunnels_restrictions.clone(),
+                        quota: Default::default(),
+                        time_window: Default::default(),
+                        max_session_duration: None,
+                        interceptors: vec![],
+                    })
+                }))
                 .collect::<Result<Vec<_>, anyhow::Error>>()?
         };
 
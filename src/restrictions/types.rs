@@ -1,88 +1,278 @@
+use crate::secret::Secret;
 use crate::tunnel::LocalProtocol;
+use chrono::{FixedOffset, NaiveTime, Weekday};
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use regex::Regex;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
+use std::fmt;
 use std::ops::RangeInclusive;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Deserialize)]
+/// Restrictions of the server, read from --restrict-config or built with RestrictionsBuilder. Serialized, they are
+/// a valid --restrict-config file
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct RestrictionsRules {
+    /// Sorted by decreasing priority, restrictions with the same priority keep the order of the configuration
+    #[serde(deserialize_with = "deserialize_by_priority")]
     pub restrictions: Vec<RestrictionConfig>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct RestrictionConfig {
     pub name: String,
+    /// Restrictions with a higher priority are checked first, the first one allowing a tunnel is used
+    #[serde(default)]
+    pub priority: i32,
     #[serde(deserialize_with = "deserialize_non_empty_vec")]
     pub r#match: Vec<MatchConfig>,
     pub allow: Vec<AllowConfig>,
+    #[serde(flatten)]
+    pub quota: QuotaConfig,
+    #[serde(flatten)]
+    pub time_window: TimeWindowConfig,
+    /// Tunnels allowed by this restriction are closed after this duration, so the client has to authenticate again
+    /// and revoked credentials cannot keep a tunnel open forever
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_session_duration: Option<Duration>,
+    /// Names of the stream interceptors wrapping the tunnels allowed by this restriction, registered by the program
+    /// embedding the server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interceptors: Vec<String>,
+}
+
+/// Limits shared by all the tunnels allowed by a restriction, enforced while forwarding their traffic
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QuotaConfig {
+    /// Bytes per second, for both directions of all the tunnels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_connections: Option<u32>,
+    /// Bytes transferred in both directions per UTC day, tunnels are closed once it is reached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes_per_day: Option<u64>,
+    /// Bytes transferred in both directions per UTC month by each identity (subject of its bearer token, or else its
+    /// path prefix), tunnels are closed once it is reached. Counters survive restarts with --quota-state-file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes_per_month_per_identity: Option<u64>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum MatchConfig {
     Any,
     #[serde(with = "serde_regex")]
     PathPrefix(Regex),
+    /// Exact path prefix used as a password, compared in constant time and never logged
+    PathPrefixSecret(Secret),
+    /// Time-limited path prefix, derived from this secret and the current date
+    PathPrefixHmac(Secret),
+    /// Subject (sub claim) of the bearer token of the client, with --jwt-auth-secret or --jwt-auth-jwks
+    #[serde(with = "serde_regex")]
+    JwtSubject(Regex),
+    /// Scope the bearer token of the client must have (scope or scp claim)
+    JwtScope(String),
+    /// Networks the client must come from
+    SourceCidr(Vec<IpNet>),
+    /// Countries (ISO 3166-1 alpha-2 codes) the client must come from, with --geoip-database
+    SourceCountry(Vec<String>),
+    /// Autonomous systems the client must come from, with --geoip-database
+    SourceAsn(Vec<u32>),
+}
+
+/// As written in the configuration, without the secrets
+impl fmt::Display for MatchConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "!Any"),
+            Self::PathPrefix(regex) => write!(f, "!PathPrefix {}", regex),
+            Self::PathPrefixSecret(_) => write!(f, "!PathPrefixSecret"),
+            Self::PathPrefixHmac(_) => write!(f, "!PathPrefixHmac"),
+            Self::JwtSubject(regex) => write!(f, "!JwtSubject {}", regex),
+            Self::JwtScope(scope) => write!(f, "!JwtScope {}", scope),
+            Self::SourceCidr(cidrs) => write!(f, "!SourceCidr {:?}", cidrs),
+            Self::SourceCountry(countries) => write!(f, "!SourceCountry {:?}", countries),
+            Self::SourceAsn(asns) => write!(f, "!SourceAsn {:?}", asns),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-#[cfg_attr(test, derive(derive_more::From))]
+#[derive(Debug, Clone, Deserialize, Serialize, derive_more::From)]
 pub enum AllowConfig {
     ReverseTunnel(AllowReverseTunnelConfig),
     Tunnel(AllowTunnelConfig),
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// The default allows the tunnels to any destination
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AllowTunnelConfig {
     #[serde(default)]
     pub protocol: Vec<TunnelConfigProtocol>,
 
-    #[serde(deserialize_with = "deserialize_port_range")]
+    #[serde(deserialize_with = "deserialize_port_range", serialize_with = "serialize_port_range")]
     #[serde(default)]
     pub port: Vec<RangeInclusive<u16>>,
 
-    #[serde(with = "serde_regex")]
+    /// A plain string is an unanchored regex, !Regex is anchored to the whole host, and !Glob is a case-insensitive
+    /// pattern where * matches a single label and ** any number of them
+    #[serde(deserialize_with = "deserialize_host", serialize_with = "serialize_host")]
     #[serde(default = "default_host")]
     pub host: Regex,
 
     #[serde(default = "default_cidr")]
     pub cidr: Vec<IpNet>,
+
+    /// PROXY protocol header sent to the destination of tcp tunnels, instead of the one requested by the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+
+    /// Header with the address of the client (i.e: X-Forwarded-For), added to the first http request of tcp tunnels
+    #[serde(deserialize_with = "deserialize_header_name")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_header: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Eq, PartialEq)]
+pub enum ProxyProtocolConfig {
+    /// Never sent, even if the client requests it
+    Disabled,
+    V1,
+    V2,
+}
+
+/// The default allows the reverse tunnels on any port
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AllowReverseTunnelConfig {
     #[serde(default)]
     pub protocol: Vec<ReverseTunnelConfigProtocol>,
 
-    #[serde(deserialize_with = "deserialize_port_range")]
+    #[serde(deserialize_with = "deserialize_port_range", serialize_with = "serialize_port_range")]
     #[serde(default)]
     pub port: Vec<RangeInclusive<u16>>,
 
-    #[serde(deserialize_with = "deserialize_port_mapping")]
+    #[serde(
+        deserialize_with = "deserialize_port_mapping",
+        serialize_with = "serialize_port_mapping"
+    )]
     #[serde(default)]
     pub port_mapping: HashMap<u16, u16>,
 
+    /// Give each client (identified by its path prefix) its own reverse socks5 listener,
+    /// with a port allocated from those ranges instead of the one requested by the client.
+    #[serde(deserialize_with = "deserialize_port_range", serialize_with = "serialize_port_range")]
+    #[serde(default)]
+    pub isolated_port: Vec<RangeInclusive<u16>>,
+
     #[serde(default = "default_cidr")]
     pub cidr: Vec<IpNet>,
 }
 
-#[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+impl Default for AllowTunnelConfig {
+    fn default() -> Self {
+        Self {
+            protocol: vec![],
+            port: vec![],
+            host: default_host(),
+            cidr: default_cidr(),
+            proxy_protocol: None,
+            forwarded_header: None,
+        }
+    }
+}
+
+impl Default for AllowReverseTunnelConfig {
+    fn default() -> Self {
+        Self {
+            protocol: vec![],
+            port: vec![],
+            port_mapping: HashMap::new(),
+            isolated_port: vec![],
+            cidr: default_cidr(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
 pub enum TunnelConfigProtocol {
     Tcp,
     Udp,
+    Exec,
+    Tun,
     Unknown,
 }
 
-#[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
 pub enum ReverseTunnelConfigProtocol {
     Tcp,
     Udp,
     Socks5,
     Unix,
     HttpProxy,
+    HttpIngress,
     Unknown,
 }
 
+/// When a restriction applies, outside of it the restriction is ignored. Checked only when tunnels are opened
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TimeWindowConfig {
+    /// i.e: 09:00-18:00, a range ending before its start spans midnight (22:00-06:00)
+    #[serde(
+        deserialize_with = "deserialize_hours_range",
+        serialize_with = "serialize_hours_range"
+    )]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_hours: Vec<(NaiveTime, NaiveTime)>,
+
+    /// i.e: Mon-Fri or Sat
+    #[serde(deserialize_with = "deserialize_days_range", serialize_with = "serialize_days")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_days: Vec<Weekday>,
+
+    #[serde(default)]
+    pub timezone: TimeZoneConfig,
+}
+
+/// Timezone of the time windows: UTC, Local (the one of the server, following its daylight saving time) or a fixed
+/// offset like +02:00
+#[derive(Debug, Clone, Default)]
+pub enum TimeZoneConfig {
+    #[default]
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl<'de> Deserialize<'de> for TimeZoneConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let timezone = String::deserialize(deserializer)?;
+        match timezone.as_str() {
+            "UTC" | "Utc" | "utc" => Ok(Self::Utc),
+            "Local" | "local" => Ok(Self::Local),
+            offset => offset.parse().map(Self::Fixed).map_err(|_| {
+                serde::de::Error::custom(format!("Invalid timezone {}, expected UTC, Local or +HH:MM", timezone))
+            }),
+        }
+    }
+}
+
+impl Serialize for TimeZoneConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Utc => serializer.serialize_str("UTC"),
+            Self::Local => serializer.serialize_str("Local"),
+            Self::Fixed(offset) => serializer.collect_str(offset),
+        }
+    }
+}
+
 pub fn default_host() -> Regex {
     Regex::new("^.*$").unwrap()
 }
@@ -91,6 +281,116 @@ pub fn default_cidr() -> Vec<IpNet> {
     vec![IpNet::V4(Ipv4Net::default()), IpNet::V6(Ipv6Net::default())]
 }
 
+/// Anchored regex of the host of a tunnel, as given to !Glob
+pub fn host_glob(glob: &str) -> anyhow::Result<Regex> {
+    Ok(Regex::new(&glob_to_regex(glob))?)
+}
+
+fn deserialize_by_priority<'de, D>(deserializer: D) -> Result<Vec<RestrictionConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut restrictions = Vec::<RestrictionConfig>::deserialize(deserializer)?;
+    restrictions.sort_by_key(|restriction| std::cmp::Reverse(restriction.priority));
+    Ok(restrictions)
+}
+
+fn deserialize_host<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    enum TaggedHost {
+        Regex(String),
+        Glob(String),
+    }
+
+    struct HostVisitor;
+    impl<'de> serde::de::Visitor<'de> for HostVisitor {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a regex, !Regex \"^anchored$\" or !Glob \"*.example.com\"")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, regex: &str) -> Result<Self::Value, E> {
+            Ok(regex.to_string())
+        }
+
+        fn visit_enum<A: serde::de::EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+            match TaggedHost::deserialize(serde::de::value::EnumAccessDeserializer::new(data))? {
+                TaggedHost::Regex(regex) => Ok(format!("^(?:{})$", regex)),
+                TaggedHost::Glob(glob) => Ok(glob_to_regex(&glob)),
+            }
+        }
+    }
+
+    let regex = deserializer.deserialize_any(HostVisitor)?;
+    Regex::new(&regex).map_err(serde::de::Error::custom)
+}
+
+/// Anchored regex of a glob on a hostname: ** matches any characters, * any characters of a single label,
+/// and ? a single character. Hostnames are case-insensitive
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("(?i)^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^.]*"),
+            '?' => regex.push_str("[^.]"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+
+    regex
+}
+
+fn deserialize_header_name<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    hyper::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| serde::de::Error::custom(format!("Invalid header name {}", name)))?;
+    Ok(Some(name))
+}
+
+/// Seconds, or a number followed by s, m, h or d (i.e: 8h)
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum DurationValue {
+        Secs(u64),
+        Text(String),
+    }
+
+    let text = match DurationValue::deserialize(deserializer)? {
+        DurationValue::Secs(secs) => return Ok(Some(Duration::from_secs(secs))),
+        DurationValue::Text(text) => text,
+    };
+    let (value, multiplier) = match text.char_indices().last() {
+        Some((ix, 's')) => (&text[..ix], 1),
+        Some((ix, 'm')) => (&text[..ix], 60),
+        Some((ix, 'h')) => (&text[..ix], 60 * 60),
+        Some((ix, 'd')) => (&text[..ix], 24 * 60 * 60),
+        _ => (text.as_str(), 1),
+    };
+    let secs = value
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| serde::de::Error::custom(format!("Invalid duration {}, expected i.e: 30m, 8h or 1d", text)))?;
+
+    Ok(Some(Duration::from_secs(secs * multiplier)))
+}
+
 fn deserialize_port_range<'de, D>(deserializer: D) -> Result<Vec<RangeInclusive<u16>>, D::Error>
 where
     D: Deserializer<'de>,
@@ -117,6 +417,46 @@ where
     Ok(ranges)
 }
 
+fn deserialize_hours_range<'de, D>(deserializer: D) -> Result<Vec<(NaiveTime, NaiveTime)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let ranges: Vec<String> = Deserialize::deserialize(deserializer)?;
+    ranges
+        .into_iter()
+        .map(|range| {
+            let (start, end) = range
+                .split_once('-')
+                .ok_or_else(|| serde::de::Error::custom(format!("Invalid allowed_hours entry: {}", range)))?;
+            let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(serde::de::Error::custom)?;
+            let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(serde::de::Error::custom)?;
+            Ok((start, end))
+        })
+        .collect()
+}
+
+fn deserialize_days_range<'de, D>(deserializer: D) -> Result<Vec<Weekday>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let ranges: Vec<String> = Deserialize::deserialize(deserializer)?;
+    let mut days = vec![];
+    for range in ranges {
+        let (first, last) = range.split_once('-').unwrap_or((&range, &range));
+        let first = first.trim().parse::<Weekday>().map_err(serde::de::Error::custom)?;
+        let last = last.trim().parse::<Weekday>().map_err(serde::de::Error::custom)?;
+
+        let mut day = first;
+        days.push(day);
+        while day != last {
+            day = day.succ();
+            days.push(day);
+        }
+    }
+
+    Ok(days)
+}
+
 fn deserialize_port_mapping<'de, D>(deserializer: D) -> Result<HashMap<u16, u16>, D::Error>
 where
     D: Deserializer<'de>,
@@ -153,6 +493,47 @@ where
     }
 }
 
+/// A plain string, which is the regex as is
+fn serialize_host<S: Serializer>(host: &Regex, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(host.as_str())
+}
+
+fn serialize_duration<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => serializer.serialize_u64(duration.as_secs()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_port_range<S: Serializer>(ranges: &[RangeInclusive<u16>], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(ranges.iter().map(|range| {
+        if range.start() == range.end() {
+            range.start().to_string()
+        } else {
+            format!("{}..{}", range.start(), range.end())
+        }
+    }))
+}
+
+fn serialize_hours_range<S: Serializer>(ranges: &[(NaiveTime, NaiveTime)], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        ranges
+            .iter()
+            .map(|(start, end)| format!("{}-{}", start.format("%H:%M"), end.format("%H:%M"))),
+    )
+}
+
+fn serialize_days<S: Serializer>(days: &[Weekday], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(days.iter().map(|day| day.to_string()))
+}
+
+/// Sorted by port, so the output does not change from one run to another
+fn serialize_port_mapping<S: Serializer>(mappings: &HashMap<u16, u16>, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut mappings: Vec<_> = mappings.iter().collect();
+    mappings.sort();
+    serializer.collect_seq(mappings.into_iter().map(|(from, to)| format!("{}:{}", from, to)))
+}
+
 impl From<&LocalProtocol> for ReverseTunnelConfigProtocol {
     fn from(value: &LocalProtocol) -> Self {
         match value {
@@ -163,12 +544,16 @@ impl From<&LocalProtocol> for ReverseTunnelConfigProtocol {
             | LocalProtocol::TProxyTcp { .. }
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
-            | LocalProtocol::Unix { .. } => Self::Unknown,
+            | LocalProtocol::Unix { .. }
+            | LocalProtocol::Exec
+            | LocalProtocol::Tun
+            | LocalProtocol::TunInterface { .. } => Self::Unknown,
             LocalProtocol::ReverseTcp => Self::Tcp,
             LocalProtocol::ReverseUdp { .. } => Self::Udp,
             LocalProtocol::ReverseSocks5 { .. } => Self::Socks5,
             LocalProtocol::ReverseUnix { .. } => Self::Unix,
             LocalProtocol::ReverseHttpProxy { .. } => Self::HttpProxy,
+            LocalProtocol::ReverseHttpIngress { .. } => Self::HttpIngress,
         }
     }
 }
@@ -185,9 +570,13 @@ impl From<&LocalProtocol> for TunnelConfigProtocol {
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
             | LocalProtocol::ReverseHttpProxy { .. }
-            | LocalProtocol::Unix { .. } => Self::Unknown,
+            | LocalProtocol::ReverseHttpIngress { .. }
+            | LocalProtocol::Unix { .. }
+            | LocalProtocol::TunInterface { .. } => Self::Unknown,
             LocalProtocol::Tcp { .. } => Self::Tcp,
             LocalProtocol::Udp { .. } => Self::Udp,
+            LocalProtocol::Exec => Self::Exec,
+            LocalProtocol::Tun => Self::Tun,
         }
     }
 }
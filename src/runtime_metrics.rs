@@ -0,0 +1,85 @@
+use serde::Serialize;
+
+/// Metrics of the tokio runtime running the tunnels, to see if the async internals are the bottleneck.
+/// The poll counts and durations are only collected when built with RUSTFLAGS="--cfg tokio_unstable"
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RuntimeMetricsInfo {
+    pub workers: usize,
+    pub alive_tasks: usize,
+    /// Tasks waiting to be picked by a worker
+    pub global_queue_depth: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unstable: Option<UnstableRuntimeMetrics>,
+}
+
+/// Totals over all the workers, since the start of the runtime
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct UnstableRuntimeMetrics {
+    pub spawned_tasks: u64,
+    pub blocking_threads: usize,
+    pub polls: u64,
+    pub busy_ms: u64,
+    /// Weighted average of the workers, of the recent polls
+    pub mean_poll_us: u64,
+}
+
+/// Snapshot of the metrics of the current runtime, the default outside of one
+pub fn runtime_metrics() -> RuntimeMetricsInfo {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return RuntimeMetricsInfo::default();
+    };
+    let metrics = runtime.metrics();
+
+    RuntimeMetricsInfo {
+        workers: metrics.num_workers(),
+        alive_tasks: metrics.num_alive_tasks(),
+        global_queue_depth: metrics.global_queue_depth(),
+        unstable: unstable_metrics(&metrics),
+    }
+}
+
+#[cfg(all(tokio_unstable, target_has_atomic = "64"))]
+fn unstable_metrics(metrics: &tokio::runtime::RuntimeMetrics) -> Option<UnstableRuntimeMetrics> {
+    let workers = 0..metrics.num_workers();
+    let polls: u64 = workers.clone().map(|worker| metrics.worker_poll_count(worker)).sum();
+    let weighted_poll_time: f64 = workers
+        .clone()
+        .map(|worker| metrics.worker_mean_poll_time(worker).as_secs_f64() * metrics.worker_poll_count(worker) as f64)
+        .sum();
+
+    Some(UnstableRuntimeMetrics {
+        spawned_tasks: metrics.spawned_tasks_count(),
+        blocking_threads: metrics.num_blocking_threads(),
+        polls,
+        busy_ms: workers
+            .map(|worker| metrics.worker_total_busy_duration(worker).as_millis() as u64)
+            .sum(),
+        mean_poll_us: (weighted_poll_time / polls.max(1) as f64 * 1_000_000.0) as u64,
+    })
+}
+
+#[cfg(not(all(tokio_unstable, target_has_atomic = "64")))]
+fn unstable_metrics(_metrics: &tokio::runtime::RuntimeMetrics) -> Option<UnstableRuntimeMetrics> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_metrics() {
+        assert_eq!(runtime_metrics().workers, 0);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .build()
+            .unwrap();
+        let metrics = runtime.block_on(async {
+            let _task = tokio::spawn(std::future::pending::<()>());
+            runtime_metrics()
+        });
+        assert_eq!(metrics.workers, 2);
+        assert_eq!(metrics.alive_tasks, 1);
+    }
+}
@@ -0,0 +1,191 @@
+use anyhow::anyhow;
+use nix::libc;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_EXECUTE: u64 = 1 << 0;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_MAKE_SOCK: u64 = 1 << 9;
+/// Every filesystem access known by the first version of Landlock
+const ACCESS_ABI_1: u64 = (1 << 13) - 1;
+/// Truncating files, restricted since the third version of Landlock
+const ACCESS_TRUNCATE: u64 = 1 << 14;
+
+const READ: u64 = ACCESS_READ_FILE | ACCESS_READ_DIR;
+const READ_WRITE: u64 = READ | ACCESS_WRITE_FILE | ACCESS_MAKE_REG | ACCESS_REMOVE_FILE | ACCESS_MAKE_SOCK;
+
+/// Directories of the system the server may need: shared libraries, dns and tls configuration, cpu count...
+const SYSTEM_DIRS: &[&str] = &["/etc", "/usr", "/lib", "/lib64", "/bin", "/sbin", "/proc", "/sys"];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// Paths the process keeps access to, everything else of the filesystem is denied
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FilesystemRules {
+    pub read: Vec<PathBuf>,
+    pub read_write: Vec<PathBuf>,
+    /// Whether commands can be executed from the system directories, for exec tunnels
+    pub execute: bool,
+}
+
+impl FilesystemRules {
+    /// Access to a file and to the files replacing it, by giving access to its directory
+    pub fn read_file(&mut self, path: &Path) {
+        self.read.push(parent_dir(path));
+    }
+
+    pub fn write_file(&mut self, path: &Path) {
+        self.read_write.push(parent_dir(path));
+    }
+
+    /// Restrict the filesystem of the calling thread and of the threads it creates afterward. Kernels without
+    /// Landlock only get a warning, as the process still works without the sandbox
+    pub fn restrict_self(&self) -> anyhow::Result<()> {
+        // SAFETY: asking for the version does not read any attribute
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            warn!(
+                "Landlock is not available on this kernel, the filesystem is not restricted: {}",
+                io::Error::last_os_error()
+            );
+            return Ok(());
+        }
+
+        let truncate = if abi >= 3 { ACCESS_TRUNCATE } else { 0 };
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_ABI_1 | truncate,
+        };
+        // SAFETY: the attribute is valid for its size
+        let ruleset = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            return Err(anyhow!("Cannot create Landlock ruleset: {}", io::Error::last_os_error()));
+        }
+        // SAFETY: the fd has just been created and is owned by nobody else
+        let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as libc::c_int) };
+
+        let execute = if self.execute { ACCESS_EXECUTE } else { 0 };
+        let rules = SYSTEM_DIRS
+            .iter()
+            .map(|dir| (Path::new(dir), READ | execute))
+            .chain([(Path::new("/dev"), ACCESS_READ_FILE | ACCESS_WRITE_FILE)])
+            .chain(self.read.iter().map(|path| (path.as_path(), READ)))
+            .chain(
+                self.read_write
+                    .iter()
+                    .map(|path| (path.as_path(), READ_WRITE | truncate)),
+            );
+        for (path, access) in rules {
+            add_rule(&ruleset, path, access)?;
+        }
+
+        // SAFETY: plain syscalls on a valid fd
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(anyhow!("Cannot set no_new_privs: {}", io::Error::last_os_error()));
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0 {
+                return Err(anyhow!("Cannot enforce Landlock ruleset: {}", io::Error::last_os_error()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Allow the access beneath the path. Missing paths are skipped, the process cannot reach them anyway
+fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> anyhow::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: the path is a valid c string
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        debug!("Skipping sandbox rule for {}: {}", path.display(), io::Error::last_os_error());
+        return Ok(());
+    }
+    // SAFETY: the fd has just been opened and is owned by nobody else
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // Rules on a file cannot grant accesses of directories
+    let access = if path.is_dir() {
+        access
+    } else {
+        access & (ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_WRITE_FILE | ACCESS_TRUNCATE)
+    };
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: fd.as_raw_fd(),
+    };
+    // SAFETY: the attribute is valid and the fds are open
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "Cannot add Landlock rule for {}: {}",
+            path.display(),
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_rules_are_on_their_directory() {
+        let mut rules = FilesystemRules::default();
+        rules.read_file(Path::new("/etc/wstunnel/restrictions.yaml"));
+        rules.write_file(Path::new("quota.json"));
+        assert_eq!(rules.read, vec![PathBuf::from("/etc/wstunnel")]);
+        assert_eq!(rules.read_write, vec![PathBuf::from(".")]);
+    }
+}
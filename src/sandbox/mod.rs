@@ -0,0 +1,87 @@
+//! Opt-in hardening of the server process on linux (--sandbox): Landlock restricts the filesystem to the files given
+//! in its configuration, and a seccomp filter denies the syscalls it does not need once it is listening.
+
+#[cfg(target_os = "linux")]
+mod landlock;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod seccomp;
+
+use crate::config::Server;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use url::Url;
+
+/// Restrict the filesystem of the calling thread and of the ones it creates afterward to what the server needs.
+/// Landlock applies per thread, so it must be called before the threads of the runtime are started
+#[cfg(target_os = "linux")]
+pub fn restrict_server_filesystem(args: &Server) -> anyhow::Result<()> {
+    filesystem_rules(args).restrict_self()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict_server_filesystem(_args: &Server) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("--sandbox is only available on linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn filesystem_rules(args: &Server) -> landlock::FilesystemRules {
+    let mut rules = landlock::FilesystemRules {
+        execute: !args.exec_command.is_empty(),
+        ..Default::default()
+    };
+
+    let read_files = [
+        &args.tls_certificate,
+        &args.tls_private_key,
+        &args.tls_client_ca_certs,
+        &args.restrict_config,
+        &args.totp_secrets,
+    ];
+    for path in read_files.into_iter().flatten().chain(&args.geoip_database) {
+        rules.read_file(path);
+    }
+    let jwks_file = args.jwt_auth_jwks.as_ref().and_then(|url| url.to_file_path().ok());
+    // The revocation list is a file path, unless it is an url
+    let revocation_file = args
+        .revocation_list
+        .as_deref()
+        .and_then(|source| match Url::parse(source) {
+            Ok(url) => url.to_file_path().ok(),
+            Err(_) => Some(PathBuf::from(source)),
+        });
+    for path in jwks_file.iter().chain(&revocation_file) {
+        rules.read_file(path);
+    }
+
+    for path in [
+        &args.audit_log,
+        &args.access_log_file,
+        &args.quota_state_file,
+        &args.admin_socket,
+        &args.state_dump_file,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        rules.write_file(path);
+    }
+    // Files are looked up in the chroot once it is entered
+    rules.read.extend(args.chroot.clone());
+    rules.read_write.extend(args.sandbox_allow_path.iter().cloned());
+
+    rules
+}
+
+/// Deny the syscalls the server does not need once it is listening, for all the threads of the process
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) fn restrict_syscalls() -> anyhow::Result<()> {
+    seccomp::restrict_syscalls()
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub(crate) fn restrict_syscalls() -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "The seccomp filter of --sandbox is only available on linux x86_64 and aarch64, disable it with --sandbox-without-seccomp"
+    ))
+}
@@ -0,0 +1,305 @@
+use anyhow::anyhow;
+use nix::libc;
+use std::io;
+use std::mem::offset_of;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+/// Syscalls of the x32 abi on x86_64, which reuse the numbers of x86_64 with this bit set
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Syscalls the server needs once it is listening: io, sockets, threads and memory of the runtime, the files it
+/// reloads, the commands of exec tunnels and the unix sockets of reverse tunnels
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // io
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_preadv2,
+    libc::SYS_pwritev2,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_pipe2,
+    libc::SYS_eventfd2,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    // files
+    libc::SYS_openat,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_inotify_init1,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+    // sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_connect,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_shutdown,
+    // polling
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    // memory
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_membarrier,
+    // threads and processes
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_pidfd_open,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    // signals
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    // time and misc
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    libc::SYS_uname,
+    libc::SYS_sysinfo,
+    // legacy variants still used by the libc on x86_64
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chmod,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_select,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_vfork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+];
+
+/// Deny, for all the threads of the process, the syscalls the server does not need once it is listening.
+/// Denied syscalls fail with EPERM instead of killing the process, so an operation missing from the list only fails
+pub fn restrict_syscalls() -> anyhow::Result<()> {
+    let mut filter = build_filter(ALLOWED_SYSCALLS);
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // Required to install a filter without CAP_SYS_ADMIN, TSYNC sets it on the other threads
+    // SAFETY: prctl and seccomp are given valid arguments, the program outlives the calls
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(anyhow!("Cannot set no_new_privs: {}", io::Error::last_os_error()));
+        }
+        if libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const libc::sock_fprog,
+        ) != 0
+        {
+            return Err(anyhow!("Cannot install seccomp filter: {}", io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+/// BPF program allowing the syscalls of the list, and denying the others with EPERM. Syscalls of another
+/// architecture kill the process, as their numbers do not match the ones of the list
+fn build_filter(allowed: &[libc::c_long]) -> Vec<libc::sock_filter> {
+    let arch_offset = offset_of!(libc::seccomp_data, arch) as u32;
+    let nr_offset = offset_of!(libc::seccomp_data, nr) as u32;
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    // Jumps are relative and limited to 255 instructions
+    assert!(allowed.len() < usize::from(u8::MAX), "too many syscalls for the seccomp filter");
+
+    let mut filter = vec![
+        bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, arch_offset),
+        bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+        bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, nr_offset),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.push(bpf_jump(
+        libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+        X32_SYSCALL_BIT,
+        allowed.len() as u8,
+        0,
+    ));
+    for (ix, syscall) in allowed.iter().enumerate() {
+        // Jump over the remaining checks and the deny, to the allow
+        let to_allow = (allowed.len() - ix) as u8;
+        filter.push(bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            *syscall as u32,
+            to_allow,
+            0,
+        ));
+    }
+    filter.push(bpf_stmt(libc::BPF_RET | libc::BPF_K, deny));
+    filter.push(bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+
+    filter
+}
+
+const fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+const fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the filter like the kernel does, for a syscall of the architecture of the server
+    fn run_filter(filter: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let ins = &filter[pc];
+            let code = u32::from(ins.code);
+            if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS {
+                acc = if ins.k == offset_of!(libc::seccomp_data, arch) as u32 {
+                    arch
+                } else {
+                    nr
+                };
+            } else if code == libc::BPF_RET | libc::BPF_K {
+                return ins.k;
+            } else {
+                let matched = if code & 0xf0 == libc::BPF_JGE {
+                    acc >= ins.k
+                } else {
+                    acc == ins.k
+                };
+                pc += usize::from(if matched { ins.jt } else { ins.jf });
+            }
+            pc += 1;
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = build_filter(ALLOWED_SYSCALLS);
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+        for syscall in ALLOWED_SYSCALLS {
+            assert_eq!(run_filter(&filter, AUDIT_ARCH, *syscall as u32), libc::SECCOMP_RET_ALLOW);
+        }
+        assert_eq!(run_filter(&filter, AUDIT_ARCH, libc::SYS_ptrace as u32), deny);
+        assert_eq!(run_filter(&filter, AUDIT_ARCH, libc::SYS_mount as u32), deny);
+        assert_eq!(run_filter(&filter, AUDIT_ARCH, libc::SYS_setuid as u32), deny);
+        assert_eq!(
+            run_filter(&filter, 0x4000_0003, libc::SYS_read as u32),
+            libc::SECCOMP_RET_KILL_PROCESS
+        );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(run_filter(&filter, AUDIT_ARCH, X32_SYSCALL_BIT | libc::SYS_read as u32), deny);
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::Context;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+/// Secret shared with the clients (path prefix, password, ...). It is wiped from memory once dropped, compared in
+/// constant time, and never printed in logs
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(secret: String) -> Self {
+        Self(Zeroizing::new(secret))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Constant time comparison, so the time to reject a guess does not tell how much of it is right
+    pub fn matches(&self, candidate: &str) -> bool {
+        constant_time_eq(self.0.as_bytes(), candidate.as_bytes())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let secret = String::deserialize(deserializer)?;
+        resolve_secret(&secret)
+            .map(Self::new)
+            .map_err(|err| D::Error::custom(format!("{:#}", err)))
+    }
+}
+
+/// The secret itself, i.e: in a restrictions file written by a provisioning tool
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.expose())
+    }
+}
+
+/// Value of an option holding a secret, so the secret does not show in the command line of the process (ps):
+/// 'env:VAR' is read from the environment variable VAR, 'file:PATH' from the file PATH without its trailing newline,
+/// anything else is the secret itself
+pub fn resolve_secret(value: &str) -> anyhow::Result<String> {
+    if let Some(var) = value.strip_prefix("env:") {
+        return std::env::var(var).with_context(|| format!("Cannot read secret from environment variable {}", var));
+    }
+    if let Some(path) = value.strip_prefix("file:") {
+        let secret = std::fs::read_to_string(path).with_context(|| format!("Cannot read secret from file {}", path))?;
+        return Ok(secret.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    Ok(value.to_string())
+}
+
+/// Only the length of the values can be deduced from the time it takes
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Path of the request with its prefix redacted, as the path prefix can be the secret of the client
+pub fn redact_path_prefix(path: &str) -> String {
+    match path.strip_prefix('/').and_then(|path| path.split_once('/')) {
+        Some((_prefix, rest)) => format!("/<redacted>/{}", rest),
+        None => "<redacted>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn test_secret() {
+        let secret = Secret::new("my-secret".to_string());
+        assert!(secret.matches("my-secret"));
+        assert!(!secret.matches("my-secreT"));
+        assert!(!secret.matches("my-secret-longer"));
+        assert!(!secret.matches(""));
+        assert_eq!(format!("{:?}", secret), "<redacted>");
+        assert_eq!(format!("{:?}", Some(secret)), "Some(<redacted>)");
+    }
+
+    #[test]
+    fn test_resolve_secret() {
+        assert_eq!(resolve_secret("my-secret").unwrap(), "my-secret");
+        assert_eq!(resolve_secret("").unwrap(), "");
+
+        assert_eq!(resolve_secret("env:PATH").unwrap(), std::env::var("PATH").unwrap());
+        assert!(resolve_secret("env:WSTUNNEL_TEST_RESOLVE_SECRET_UNSET").is_err());
+        // Same for the secrets of the restrictions file
+        assert!(serde_yaml::from_str::<Secret>("env:WSTUNNEL_TEST_RESOLVE_SECRET_UNSET").is_err());
+        assert!(serde_yaml::from_str::<Secret>("my-secret")
+            .unwrap()
+            .matches("my-secret"));
+
+        let path = std::env::temp_dir().join(format!("wstunnel-secret-{}", std::process::id()));
+        std::fs::write(&path, "from-file\n").unwrap();
+        assert_eq!(resolve_secret(&format!("file:{}", path.display())).unwrap(), "from-file");
+        std::fs::remove_file(&path).unwrap();
+        assert!(resolve_secret(&format!("file:{}", path.display())).is_err());
+    }
+
+    #[test_case("/my-secret/events" => "/<redacted>/events" ; "upgrade path")]
+    #[test_case("/my-secret/events?x=1" => "/<redacted>/events?x=1" ; "with query")]
+    #[test_case("/my-secret" => "<redacted>" ; "without events")]
+    #[test_case("" => "<redacted>" ; "empty")]
+    fn test_redact_path_prefix(path: &str) -> String {
+        redact_path_prefix(path)
+    }
+}
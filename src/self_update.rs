@@ -0,0 +1,228 @@
+//! `wstunnel update`: replace the binary by the latest release of a json feed, i.e:
+//! {"version": "10.2.0", "artifacts": {"linux-x86_64": {"url": "wstunnel-linux-x86_64", "sha256": "<hex>", "signature": "<base64>"}}}
+//! The signature is an ed25519 signature of `wstunnel <version> <platform> <sha256>`, so a binary cannot be served for
+//! another version or platform than the one it was signed for. The url of an artifact can be relative to the feed
+
+use crate::config::SelfUpdate;
+use crate::tunnel::server::http_client::{http_download, http_request};
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use hyper::{Method, StatusCode};
+use ring::{digest, signature};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use url::Url;
+
+const FEED_TIMEOUT: Duration = Duration::from_secs(30);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Deserialize)]
+struct ReleaseFeed {
+    version: String,
+    artifacts: BTreeMap<String, Artifact>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Artifact {
+    url: String,
+    sha256: String,
+    signature: String,
+}
+
+/// Name of the platform of the artifacts for this binary, i.e: linux-x86_64
+fn platform() -> String {
+    format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compare 10.1.2 like versions, with an optional v prefix. The pre-releases (-rc1) are compared as their release
+fn compare_versions(a: &str, b: &str) -> anyhow::Result<Ordering> {
+    let parse = |version: &str| -> anyhow::Result<Vec<u64>> {
+        let version = version.strip_prefix('v').unwrap_or(version);
+        let version = version.split(['-', '+']).next().unwrap_or_default();
+        version
+            .split('.')
+            .map(|number| number.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Invalid version {}", version))
+    };
+    Ok(parse(a)?.cmp(&parse(b)?))
+}
+
+impl Artifact {
+    /// Check the signature of the artifact for the version and platform, then that the binary is the one signed
+    fn verify(&self, public_key: &[u8], version: &str, platform: &str, binary: &[u8]) -> anyhow::Result<()> {
+        let message = format!("wstunnel {} {} {}", version, platform, self.sha256.to_ascii_lowercase());
+        let sig = base64::engine::general_purpose::STANDARD
+            .decode(self.signature.trim())
+            .context("Invalid base64 signature of the artifact")?;
+        signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message.as_bytes(), &sig)
+            .map_err(|_| anyhow!("Invalid signature of the artifact of {} for {}", version, platform))?;
+
+        let sha256 = to_hex(digest::digest(&digest::SHA256, binary).as_ref());
+        if !sha256.eq_ignore_ascii_case(&self.sha256) {
+            return Err(anyhow!(
+                "The sha256 of the downloaded binary is {}, not the signed {}",
+                sha256,
+                self.sha256
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Write the binary next to the one it replaces, check that it runs, then rename it over it
+fn replace_binary(path: &Path, binary: &[u8], version: &str) -> anyhow::Result<()> {
+    let path = fs::canonicalize(path).with_context(|| format!("Cannot find the binary {}", path.display()))?;
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".update");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let install = || -> anyhow::Result<()> {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(binary)?;
+        file.set_permissions(fs::metadata(&path)?.permissions())?;
+        file.sync_all()?;
+        drop(file);
+
+        // A binary for another libc or cpu, or a truncated one, would only be noticed after the restart
+        let output = std::process::Command::new(&tmp_path)
+            .arg("--version")
+            .output()
+            .context("Cannot run the new binary")?;
+        let output = String::from_utf8_lossy(&output.stdout);
+        let version = version.strip_prefix('v').unwrap_or(version);
+        if !output.contains(version) {
+            return Err(anyhow!(
+                "The new binary prints the version {:?}, not {}",
+                output.trim(),
+                version
+            ));
+        }
+
+        // Windows does not allow replacing a running binary, but it can be renamed
+        #[cfg(windows)]
+        {
+            let mut old_path = path.clone().into_os_string();
+            old_path.push(".old");
+            let _ = fs::remove_file(&old_path);
+            fs::rename(&path, &old_path)?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    };
+
+    install().map_err(|err| {
+        let _ = fs::remove_file(&tmp_path);
+        err.context(format!("Cannot replace the binary {}", path.display()))
+    })
+}
+
+pub async fn update(args: SelfUpdate) -> anyhow::Result<()> {
+    let feed = match (args.feed, option_env!("WSTUNNEL_UPDATE_FEED")) {
+        (Some(feed), _) => feed,
+        (None, Some(feed)) => Url::parse(feed).context("Invalid release feed WSTUNNEL_UPDATE_FEED of the build")?,
+        (None, None) => return Err(anyhow!("No release feed, give it with --feed")),
+    };
+    let public_key = args
+        .public_key
+        .as_deref()
+        .or(option_env!("WSTUNNEL_UPDATE_PUBLIC_KEY"))
+        .ok_or_else(|| anyhow!("No public key to verify the releases, give it with --public-key"))?;
+    let public_key = base64::engine::general_purpose::STANDARD
+        .decode(public_key.trim())
+        .context("Invalid base64 public key")?;
+
+    let (status, body) = http_request(Method::GET, &feed, None, FEED_TIMEOUT).await?;
+    if status != StatusCode::OK {
+        return Err(anyhow!("The release feed {} answered {}", feed, status));
+    }
+    let release: ReleaseFeed =
+        serde_json::from_slice(&body).with_context(|| format!("Invalid release feed {}", feed))?;
+
+    let current_version = env!("CARGO_PKG_VERSION");
+    let is_newer = compare_versions(&release.version, current_version)? == Ordering::Greater;
+    if args.dry_run {
+        match is_newer {
+            true => println!("wstunnel {} is available, running {}", release.version, current_version),
+            false => println!("wstunnel {} is up to date, the latest is {}", current_version, release.version),
+        }
+        return Ok(());
+    }
+    if !is_newer && !args.force {
+        println!("wstunnel {} is up to date, the latest is {}", current_version, release.version);
+        return Ok(());
+    }
+
+    let platform = platform();
+    let artifact = release
+        .artifacts
+        .get(&platform)
+        .ok_or_else(|| anyhow!("No binary for {} in the release {}", platform, release.version))?;
+    let url = feed
+        .join(&artifact.url)
+        .with_context(|| format!("Invalid url {} of the binary", artifact.url))?;
+    let binary = http_download(&url, DOWNLOAD_TIMEOUT).await?;
+    artifact.verify(&public_key, &release.version, &platform, &binary)?;
+
+    let path = match args.binary {
+        Some(path) => path,
+        None => std::env::current_exe().context("Cannot find the path of the running binary")?,
+    };
+    replace_binary(&path, &binary, &release.version)?;
+    println!(
+        "{} updated from {} to {}, restart wstunnel to run it",
+        path.display(),
+        current_version,
+        release.version
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use test_case::test_case;
+
+    #[test_case("10.2.0", "10.1.9" => Ordering::Greater ; "newer")]
+    #[test_case("v10.1.0", "10.1.0" => Ordering::Equal ; "v prefix")]
+    #[test_case("10.1.0-rc1", "10.1.0" => Ordering::Equal ; "pre-release")]
+    #[test_case("9.7.4", "10.0.0" => Ordering::Less ; "older")]
+    fn test_compare_versions(a: &str, b: &str) -> Ordering {
+        compare_versions(a, b).unwrap()
+    }
+
+    #[test]
+    fn test_verify_artifact() {
+        let rng = SystemRandom::new();
+        let key_pair = Ed25519KeyPair::from_pkcs8(Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref()).unwrap();
+        let public_key = key_pair.public_key().as_ref();
+        let binary = b"\x7fELF wstunnel";
+        let sha256 = to_hex(digest::digest(&digest::SHA256, binary).as_ref());
+        let sig = key_pair.sign(format!("wstunnel 10.2.0 linux-x86_64 {}", sha256).as_bytes());
+        let artifact = Artifact {
+            url: "wstunnel-linux-x86_64".to_string(),
+            sha256,
+            signature: base64::engine::general_purpose::STANDARD.encode(sig.as_ref()),
+        };
+
+        assert!(artifact.verify(public_key, "10.2.0", "linux-x86_64", binary).is_ok());
+        // Signed for another version, platform or binary
+        assert!(artifact.verify(public_key, "10.3.0", "linux-x86_64", binary).is_err());
+        assert!(artifact.verify(public_key, "10.2.0", "linux-aarch64", binary).is_err());
+        assert!(artifact
+            .verify(public_key, "10.2.0", "linux-x86_64", b"tampered")
+            .is_err());
+    }
+}
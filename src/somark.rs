@@ -1,8 +1,33 @@
-//! somark - abstraction to be used only on linux
+//! somark - options of the sockets opened by wstunnel to reach the server and the destinations
 //!
-//! on other platforms it's noop without memory footprint
+//! SO_MARK is only set on linux, on other platforms it's noop without memory footprint. The socket protector of
+//! an embedding app is called on every platform
 
+use arc_swap::ArcSwapOption;
 use socket2::SockRef;
+use std::sync::Arc;
+
+/// Called with each socket opened by wstunnel, before it connects. i.e: an Android VPN app calls
+/// VpnService.protect() on it, so the traffic of the tunnels is not routed back into its own VPN
+pub trait SocketProtector: Send + Sync + 'static {
+    fn protect(&self, socket: SockRef) -> std::io::Result<()>;
+}
+
+impl<F> SocketProtector for F
+where
+    F: Fn(SockRef) -> std::io::Result<()> + Send + Sync + 'static,
+{
+    fn protect(&self, socket: SockRef) -> std::io::Result<()> {
+        self(socket)
+    }
+}
+
+static SOCKET_PROTECTOR: ArcSwapOption<Box<dyn SocketProtector>> = ArcSwapOption::const_empty();
+
+/// Protector of all the sockets opened from now on by the process, replacing the previous one. None removes it
+pub fn set_socket_protector(protector: Option<Box<dyn SocketProtector>>) {
+    SOCKET_PROTECTOR.store(protector.map(Arc::new));
+}
 
 #[derive(Copy, Clone, Debug)]
 #[repr(transparent)]
@@ -20,15 +45,29 @@ impl SoMark {
         }
     }
 
+    /// Set SO_MARK and give the socket to the socket protector, if any
+    pub fn apply(self, socket: SockRef) -> std::io::Result<()> {
+        self.set_mark(&socket)
+            .map_err(|err| std::io::Error::new(err.kind(), format!("cannot set SO_MARK on socket: {}", err)))?;
+
+        if let Some(protector) = SOCKET_PROTECTOR.load().as_ref() {
+            protector
+                .protect(socket)
+                .map_err(|err| std::io::Error::new(err.kind(), format!("cannot protect socket: {}", err)))?;
+        }
+
+        Ok(())
+    }
+
     #[cfg(not(target_os = "linux"))]
     #[inline]
-    pub fn set_mark(self, _: SockRef) -> Result<(), std::convert::Infallible> {
+    fn set_mark(self, _: &SockRef) -> std::io::Result<()> {
         Ok(())
     }
 
     #[cfg(target_os = "linux")]
     #[inline]
-    pub fn set_mark(self, socket: SockRef) -> std::io::Result<()> {
+    fn set_mark(self, socket: &SockRef) -> std::io::Result<()> {
         let Some(so_mark) = self.inner else { return Ok(()) };
 
         socket.set_mark(so_mark).map_err(|_| std::io::Error::last_os_error())
@@ -0,0 +1,204 @@
+use crate::tunnel::active_tunnels::ActiveTunnels;
+use ahash::{AHashMap, AHashSet};
+use parking_lot::Mutex;
+use ring::digest;
+use serde::Serialize;
+use serde_json::json;
+use std::fmt::Debug;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Internal state not tracked anywhere else, gathered with the active tunnels when a dump is asked,
+/// to debug a process that looks stuck without attaching a debugger
+pub static INTERNAL_STATE: LazyLock<InternalState> = LazyLock::new(InternalState::default);
+
+type PoolOccupancyFn = Box<dyn Fn() -> PoolOccupancy + Send + Sync>;
+
+#[derive(Default)]
+pub struct InternalState {
+    config_hash: OnceLock<String>,
+    connection_pool: OnceLock<PoolOccupancyFn>,
+    /// Peers of each udp listener, by the address of the listener
+    udp_peers: Mutex<AHashMap<SocketAddr, AHashSet<SocketAddr>>>,
+    pending_reconnects: Mutex<AHashMap<u64, PendingReconnect>>,
+    next_reconnect_id: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PoolOccupancy {
+    pub connections: u32,
+    pub idle_connections: u32,
+}
+
+#[derive(Debug, Clone)]
+struct PendingReconnect {
+    destination: String,
+    disconnected_since: Instant,
+    retry_in: Duration,
+}
+
+#[derive(Debug, Serialize)]
+struct UdpListenerInfo {
+    listener: SocketAddr,
+    peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Serialize)]
+struct PendingReconnectInfo {
+    destination: String,
+    disconnected_secs: u64,
+    retry_in_secs: f64,
+}
+
+impl InternalState {
+    /// Hash of the configuration the process is running with, to compare it with the one it is expected to run with
+    pub fn set_config(&self, config: &impl Debug) {
+        let hash = digest::digest(&digest::SHA256, format!("{:?}", config).as_bytes());
+        let hash: String = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        let _ = self.config_hash.set(hash);
+    }
+
+    pub fn set_connection_pool(&self, occupancy: impl Fn() -> PoolOccupancy + Send + Sync + 'static) {
+        let _ = self.connection_pool.set(Box::new(occupancy));
+    }
+
+    pub(crate) fn add_udp_peer(&self, listener: SocketAddr, peer: SocketAddr) {
+        self.udp_peers.lock().entry(listener).or_default().insert(peer);
+    }
+
+    pub(crate) fn remove_udp_peer(&self, listener: SocketAddr, peer: SocketAddr) {
+        let mut udp_peers = self.udp_peers.lock();
+        if let Some(peers) = udp_peers.get_mut(&listener) {
+            peers.remove(&peer);
+            if peers.is_empty() {
+                udp_peers.remove(&listener);
+            }
+        }
+    }
+
+    /// Id to report the reconnections of a reverse tunnel with
+    pub(crate) fn new_reconnect_id(&self) -> u64 {
+        self.next_reconnect_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn reconnect_pending(
+        &self,
+        id: u64,
+        destination: String,
+        disconnected_since: Instant,
+        retry_in: Duration,
+    ) {
+        self.pending_reconnects.lock().insert(
+            id,
+            PendingReconnect {
+                destination,
+                disconnected_since,
+                retry_in,
+            },
+        );
+    }
+
+    pub(crate) fn reconnect_done(&self, id: u64) {
+        self.pending_reconnects.lock().remove(&id);
+    }
+
+    /// Snapshot of the active tunnels, udp peers, connection pool, pending reconnections and configuration hash
+    pub(crate) fn dump(&self, tunnels: &ActiveTunnels) -> serde_json::Value {
+        let mut udp_listeners: Vec<UdpListenerInfo> = self
+            .udp_peers
+            .lock()
+            .iter()
+            .map(|(listener, peers)| {
+                let mut peers: Vec<SocketAddr> = peers.iter().copied().collect();
+                peers.sort_unstable();
+                UdpListenerInfo {
+                    listener: *listener,
+                    peers,
+                }
+            })
+            .collect();
+        udp_listeners.sort_unstable_by_key(|info| info.listener);
+
+        let mut pending_reconnects: Vec<PendingReconnectInfo> = self
+            .pending_reconnects
+            .lock()
+            .values()
+            .map(|reconnect| PendingReconnectInfo {
+                destination: reconnect.destination.clone(),
+                disconnected_secs: reconnect.disconnected_since.elapsed().as_secs(),
+                retry_in_secs: reconnect.retry_in.as_secs_f64(),
+            })
+            .collect();
+        pending_reconnects.sort_unstable_by(|a, b| a.destination.cmp(&b.destination));
+
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "pid": std::process::id(),
+            "config_hash": self.config_hash.get(),
+            "connection_pool": self.connection_pool.get().map(|occupancy| occupancy()),
+            "pending_reconnects": pending_reconnects,
+            "udp_listeners": udp_listeners,
+            "tunnels": tunnels.list(),
+        })
+    }
+}
+
+/// Write the dump as one json line at the end of the file, or to the log without file
+pub(crate) fn write_dump(file: Option<&Path>, tunnels: &ActiveTunnels) {
+    let mut dump = INTERNAL_STATE.dump(tunnels);
+    let Some(path) = file else {
+        info!("Internal state: {}", dump);
+        return;
+    };
+
+    dump["timestamp"] = json!(chrono::Utc::now().to_rfc3339());
+    let ret = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", dump));
+    match ret {
+        Ok(()) => info!("Internal state dumped to {}", path.display()),
+        Err(err) => warn!("Cannot dump internal state to {}: {}", path.display(), err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump() {
+        let state = InternalState::default();
+        state.set_config(&("server", 8080));
+        state.set_connection_pool(|| PoolOccupancy {
+            connections: 3,
+            idle_connections: 2,
+        });
+        let listener = "127.0.0.1:5353".parse().unwrap();
+        state.add_udp_peer(listener, "127.0.0.1:4000".parse().unwrap());
+        state.add_udp_peer(listener, "127.0.0.1:3000".parse().unwrap());
+        state.remove_udp_peer(listener, "127.0.0.1:4000".parse().unwrap());
+        let reconnect = state.new_reconnect_id();
+        state.reconnect_pending(reconnect, "localhost:22".to_string(), Instant::now(), Duration::from_secs(2));
+
+        let dump = state.dump(&ActiveTunnels::default());
+        assert_eq!(dump["config_hash"].as_str().unwrap().len(), 64);
+        assert_eq!(dump["connection_pool"]["idle_connections"], 2);
+        assert_eq!(dump["udp_listeners"][0]["listener"], "127.0.0.1:5353");
+        assert_eq!(dump["udp_listeners"][0]["peers"], json!(["127.0.0.1:3000"]));
+        assert_eq!(dump["pending_reconnects"][0]["destination"], "localhost:22");
+        assert_eq!(dump["pending_reconnects"][0]["retry_in_secs"], 2.0);
+
+        state.reconnect_done(reconnect);
+        state.remove_udp_peer(listener, "127.0.0.1:3000".parse().unwrap());
+        let dump = state.dump(&ActiveTunnels::default());
+        assert_eq!(dump["pending_reconnects"], json!([]));
+        assert_eq!(dump["udp_listeners"], json!([]));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::protocols::dns::{DnsMetricsInfo, DNS_METRICS};
+use crate::runtime_metrics::{runtime_metrics, RuntimeMetricsInfo};
+use crate::tunnel::active_tunnels::{ActiveTunnels, TunnelCounters};
+use crate::tunnel::spawn_until_cancelled;
+use crate::tunnel::tunnel_metrics::{TunnelMetricsInfo, TUNNEL_METRICS};
+use anyhow::anyhow;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Push the metrics of the tunnels to a statsd server over udp, for setups without prometheus
+pub struct StatsdExporter {
+    /// host:port of the statsd server
+    pub addr: String,
+    /// Prepended to the name of the metrics, with a dot
+    pub prefix: String,
+    /// Dogstatsd tags added to every metric, i.e: env:prod
+    pub tags: Vec<String>,
+    pub interval: Duration,
+}
+
+impl StatsdExporter {
+    /// Send the metrics of these tunnels every interval, until the token is cancelled
+    pub async fn run(self, cancel: &CancellationToken, tunnels: Arc<ActiveTunnels>) -> anyhow::Result<()> {
+        let addr = tokio::net::lookup_host(&self.addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("Cannot resolve statsd address {}", self.addr))?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+        socket.connect(addr).await?;
+        info!("Sending metrics to statsd {} every {:?}", self.addr, self.interval);
+
+        spawn_until_cancelled(cancel, async move {
+            let mut previous = (tunnels.counters(), DNS_METRICS.snapshot(), runtime_metrics());
+            let mut interval = tokio::time::interval(self.interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let current = (tunnels.counters(), DNS_METRICS.snapshot(), runtime_metrics());
+                // Several metrics per datagram, each one on its own line
+                let payload = self.format(&current, &previous, &TUNNEL_METRICS.list());
+                if let Err(err) = socket.send(payload.as_bytes()).await {
+                    warn!("Cannot send metrics to statsd {}: {}", self.addr, err);
+                }
+                previous = current;
+            }
+        });
+
+        Ok(())
+    }
+
+    /// The counters are sent as the increase since the previous flush, as statsd expects.
+    /// The percentiles of the histograms of each -L/-R tunnel are gauges, tagged with the name of the tunnel
+    fn format(
+        &self,
+        (counters, dns, runtime): &(TunnelCounters, DnsMetricsInfo, RuntimeMetricsInfo),
+        (previous, previous_dns, previous_runtime): &(TunnelCounters, DnsMetricsInfo, RuntimeMetricsInfo),
+        forwards: &[TunnelMetricsInfo],
+    ) -> String {
+        let format_tags = |tags: &[String]| {
+            if tags.is_empty() {
+                String::new()
+            } else {
+                format!("|#{}", tags.join(","))
+            }
+        };
+        let tags = format_tags(&self.tags);
+        let metrics = [
+            ("tunnels.active", counters.active_tunnels as u64, "g"),
+            (
+                "tunnels.opened",
+                counters.opened_tunnels.saturating_sub(previous.opened_tunnels),
+                "c",
+            ),
+            (
+                "tunnels.failed",
+                counters.failed_tunnels.saturating_sub(previous.failed_tunnels),
+                "c",
+            ),
+            ("reconnects", counters.reconnects.saturating_sub(previous.reconnects), "c"),
+            (
+                "slow_consumers",
+                counters.slow_consumers.saturating_sub(previous.slow_consumers),
+                "c",
+            ),
+            ("bytes.sent", counters.bytes_sent.saturating_sub(previous.bytes_sent), "c"),
+            (
+                "bytes.received",
+                counters.bytes_received.saturating_sub(previous.bytes_received),
+                "c",
+            ),
+        ];
+
+        let mut payload = String::new();
+        for (name, value, kind) in metrics {
+            let _ = writeln!(payload, "{}.{}:{}|{}{}", self.prefix, name, value, kind, tags);
+        }
+        for (reason, failed) in &counters.failures {
+            let failed = failed.saturating_sub(previous.failures.get(reason).copied().unwrap_or_default());
+            let _ = writeln!(payload, "{}.tunnels.failed.{}:{}|c{}", self.prefix, reason, failed, tags);
+        }
+
+        let lookups = [
+            ("resolved", dns.resolved.saturating_sub(previous_dns.resolved)),
+            ("not_found", dns.not_found.saturating_sub(previous_dns.not_found)),
+            ("timeout", dns.timeout.saturating_sub(previous_dns.timeout)),
+            ("error", dns.error.saturating_sub(previous_dns.error)),
+        ];
+        for (outcome, value) in lookups {
+            let _ = writeln!(payload, "{}.dns.lookups.{}:{}|c{}", self.prefix, outcome, value, tags);
+        }
+        if dns.duration_ms.count > 0 {
+            for (percentile, value) in [
+                ("p50", dns.duration_ms.p50),
+                ("p90", dns.duration_ms.p90),
+                ("p99", dns.duration_ms.p99),
+            ] {
+                let _ = writeln!(payload, "{}.dns.duration_ms.{}:{}|g{}", self.prefix, percentile, value, tags);
+            }
+        }
+
+        let mut runtime_metrics = vec![
+            ("runtime.workers", runtime.workers as u64, "g"),
+            ("runtime.alive_tasks", runtime.alive_tasks as u64, "g"),
+            ("runtime.global_queue_depth", runtime.global_queue_depth as u64, "g"),
+        ];
+        if let Some(unstable) = &runtime.unstable {
+            let previous = previous_runtime.unstable.clone().unwrap_or_default();
+            runtime_metrics.extend([
+                (
+                    "runtime.spawned_tasks",
+                    unstable.spawned_tasks.saturating_sub(previous.spawned_tasks),
+                    "c",
+                ),
+                ("runtime.blocking_threads", unstable.blocking_threads as u64, "g"),
+                ("runtime.polls", unstable.polls.saturating_sub(previous.polls), "c"),
+                ("runtime.busy_ms", unstable.busy_ms.saturating_sub(previous.busy_ms), "c"),
+                ("runtime.mean_poll_us", unstable.mean_poll_us, "g"),
+            ]);
+        }
+        for (name, value, kind) in runtime_metrics {
+            let _ = writeln!(payload, "{}.{}:{}|{}{}", self.prefix, name, value, kind, tags);
+        }
+
+        for forward in forwards {
+            let tags = format_tags(&[self.tags.as_slice(), &[format!("forward:{}", forward.name)]].concat());
+            let histograms = [
+                ("first_byte_ms", &forward.first_byte_ms),
+                ("sent_bytes_per_sec", &forward.sent_bytes_per_sec),
+                ("received_bytes_per_sec", &forward.received_bytes_per_sec),
+            ];
+            for (name, histogram) in histograms.into_iter().filter(|(_, histogram)| histogram.count > 0) {
+                for (percentile, value) in [("p50", histogram.p50), ("p90", histogram.p90), ("p99", histogram.p99)] {
+                    let _ = writeln!(payload, "{}.forward.{}.{}:{}|g{}", self.prefix, name, percentile, value, tags);
+                }
+            }
+        }
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::dns::metrics::DnsMetrics;
+    use crate::runtime_metrics::UnstableRuntimeMetrics;
+    use crate::tunnel::active_tunnels::FailureReason;
+
+    fn counters(opened_tunnels: u64, bytes_sent: u64, dns_failures: u64) -> TunnelCounters {
+        TunnelCounters {
+            active_tunnels: 1,
+            opened_tunnels,
+            failed_tunnels: dns_failures,
+            failures: [
+                (FailureReason::Dns.name(), dns_failures),
+                (FailureReason::Tls.name(), 0),
+            ]
+            .into(),
+            reconnects: 0,
+            slow_consumers: 0,
+            bytes_sent,
+            bytes_received: 0,
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let exporter = StatsdExporter {
+            addr: "localhost:8125".to_string(),
+            prefix: "wstunnel".to_string(),
+            tags: vec!["env:prod".to_string(), "role:server".to_string()],
+            interval: Duration::from_secs(10),
+        };
+        let dns = DnsMetrics::default();
+        let previous = (counters(3, 400, 1), dns.snapshot(), RuntimeMetricsInfo::default());
+        dns.observe(&Ok(vec!["127.0.0.1:443".parse().unwrap()]), Duration::from_millis(3));
+        let runtime = RuntimeMetricsInfo {
+            workers: 4,
+            alive_tasks: 12,
+            global_queue_depth: 0,
+            unstable: Some(UnstableRuntimeMetrics {
+                spawned_tasks: 20,
+                blocking_threads: 1,
+                polls: 300,
+                busy_ms: 15,
+                mean_poll_us: 40,
+            }),
+        };
+        let payload = exporter.format(&(counters(5, 1000, 3), dns.snapshot(), runtime), &previous, &[]);
+        assert_eq!(
+            payload.lines().collect::<Vec<_>>(),
+            [
+                "wstunnel.tunnels.active:1|g|#env:prod,role:server",
+                "wstunnel.tunnels.opened:2|c|#env:prod,role:server",
+                "wstunnel.tunnels.failed:2|c|#env:prod,role:server",
+                "wstunnel.reconnects:0|c|#env:prod,role:server",
+                "wstunnel.slow_consumers:0|c|#env:prod,role:server",
+                "wstunnel.bytes.sent:600|c|#env:prod,role:server",
+                "wstunnel.bytes.received:0|c|#env:prod,role:server",
+                "wstunnel.tunnels.failed.dns:2|c|#env:prod,role:server",
+                "wstunnel.tunnels.failed.tls:0|c|#env:prod,role:server",
+                "wstunnel.dns.lookups.resolved:1|c|#env:prod,role:server",
+                "wstunnel.dns.lookups.not_found:0|c|#env:prod,role:server",
+                "wstunnel.dns.lookups.timeout:0|c|#env:prod,role:server",
+                "wstunnel.dns.lookups.error:0|c|#env:prod,role:server",
+                "wstunnel.dns.duration_ms.p50:3|g|#env:prod,role:server",
+                "wstunnel.dns.duration_ms.p90:3|g|#env:prod,role:server",
+                "wstunnel.dns.duration_ms.p99:3|g|#env:prod,role:server",
+                "wstunnel.runtime.workers:4|g|#env:prod,role:server",
+                "wstunnel.runtime.alive_tasks:12|g|#env:prod,role:server",
+                "wstunnel.runtime.global_queue_depth:0|g|#env:prod,role:server",
+                "wstunnel.runtime.spawned_tasks:20|c|#env:prod,role:server",
+                "wstunnel.runtime.blocking_threads:1|g|#env:prod,role:server",
+                "wstunnel.runtime.polls:300|c|#env:prod,role:server",
+                "wstunnel.runtime.busy_ms:15|c|#env:prod,role:server",
+                "wstunnel.runtime.mean_poll_us:40|g|#env:prod,role:server",
+            ]
+        );
+    }
+}
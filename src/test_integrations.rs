@@ -1,67 +1,149 @@
+use crate::builder::{TunnelClientBuilder, TunnelServerBuilder};
 use crate::protocols;
-use crate::protocols::dns::DnsResolver;
+use crate::protocols::dns::{DnsResolver, IpFamily};
+use crate::protocols::packet;
+use crate::protocols::packet::{PacketDevice, PacketNetwork, PacketQueue};
 use crate::restrictions::types;
 use crate::restrictions::types::{AllowConfig, MatchConfig, RestrictionConfig, RestrictionsRules};
 use crate::somark::SoMark;
-use crate::tunnel::client::{WsClient, WsClientConfig};
+use crate::tunnel::client::{ReconnectPolicy, UpgradeSecretArgs, UpgradeSecrets, WsClient, WsClientConfig};
+use crate::tunnel::connectors::TcpTunnelConnector;
+use crate::tunnel::events::TunnelEvent;
+use crate::tunnel::interceptor::{InterceptedRead, InterceptedWrite, StreamInfo, StreamInterceptor};
 use crate::tunnel::listeners::{TcpTunnelListener, UdpTunnelListener};
+use crate::tunnel::server::{TunnelEventHandler, TunnelInfo};
 use crate::tunnel::server::{WsServer, WsServerConfig};
 use crate::tunnel::transport::{TransportAddr, TransportScheme};
-use bytes::BytesMut;
+use crate::tunnel::transport::{TransportRead, TransportWrite, TunnelTransport};
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use bytes::{Bytes, BytesMut};
+use futures_util::future::BoxFuture;
 use futures_util::StreamExt;
-use hyper::http::HeaderValue;
+use http_body_util::Either;
+use hyper::body::Incoming;
+use hyper::http::{request, response, HeaderMap, HeaderName, HeaderValue, Request, Response};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use regex::Regex;
 use rstest::{fixture, rstest};
 use scopeguard::defer;
 use serial_test::serial;
-use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::TcpStream;
 use tokio::pin;
-use url::Host;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use url::{Host, Url};
 
 #[fixture]
 fn dns_resolver() -> DnsResolver {
-    DnsResolver::new_from_urls(&[], None, SoMark::new(None), true).expect("Cannot create DNS resolver")
+    DnsResolver::new_from_urls(&[], None, SoMark::new(None), IpFamily::Any).expect("Cannot create DNS resolver")
 }
 
 #[fixture]
-fn server_no_tls(dns_resolver: DnsResolver) -> WsServer {
-    let server_config = WsServerConfig {
+fn server_config(dns_resolver: DnsResolver) -> WsServerConfig {
+    WsServerConfig {
         socket_so_mark: SoMark::new(None),
         bind: "127.0.0.1:8080".parse().unwrap(),
         websocket_ping_frequency: Some(Duration::from_secs(10)),
+        slow_consumer_timeout: Some(Duration::from_secs(10)),
         timeout_connect: Duration::from_secs(10),
         websocket_mask_frame: false,
         tls: None,
         dns_resolver,
         restriction_config: None,
+        deny_private_destinations: false,
+        allow_from: vec![],
+        deny_from: vec![],
+        trusted_proxies: vec![],
         http_proxy: None,
         remote_server_idle_timeout: Duration::from_secs(30),
-    };
+        remote_server_no_connection_timeout: None,
+        remote_server_max_lifetime: None,
+        exec_commands: Default::default(),
+        bench_endpoint: false,
+        http_ingress: vec![],
+        bearer_auth: None,
+        ldap_auth: None,
+        revocation_list: None,
+        totp_verifier: None,
+        ban_policy: None,
+        audit_log: None,
+        access_log: None,
+        rate_limiter: None,
+        reuse_port: false,
+        accept_loops: 1,
+        spa_gate: None,
+        auth_webhook: None,
+        event_handler: None,
+        transport: None,
+        interceptors: Default::default(),
+        packet_network: None,
+        privilege_drop: None,
+        restrict_syscalls: false,
+        cancel: Default::default(),
+        events: Default::default(),
+        instance: Default::default(),
+        registries: Default::default(),
+        capture: None,
+        geoip: Default::default(),
+    }
+}
+
+#[fixture]
+fn server_no_tls(server_config: WsServerConfig) -> WsServer {
     WsServer::new(server_config)
 }
 
 #[fixture]
-async fn client_ws(dns_resolver: DnsResolver) -> WsClient {
-    let client_config = WsClientConfig {
+fn client_config(dns_resolver: DnsResolver) -> WsClientConfig {
+    WsClientConfig {
         remote_addr: TransportAddr::new(TransportScheme::Ws, Host::Ipv4("127.0.0.1".parse().unwrap()), 8080, None)
             .unwrap(),
         socket_so_mark: SoMark::new(None),
-        http_upgrade_path_prefix: "wstunnel".to_string(),
-        http_upgrade_credentials: None,
-        http_headers: HashMap::new(),
+        upgrade_secrets: Arc::new(
+            UpgradeSecrets::new(UpgradeSecretArgs {
+                path_prefix: "wstunnel".to_string(),
+                ..Default::default()
+            })
+            .unwrap(),
+        ),
+        http_upgrade_totp_command: None,
         http_headers_file: None,
         http_header_host: HeaderValue::from_static("127.0.0.1:8080"),
         timeout_connect: Duration::from_secs(10),
         websocket_ping_frequency: Some(Duration::from_secs(10)),
+        slow_consumer_timeout: Some(Duration::from_secs(10)),
         websocket_mask_frame: false,
         dns_resolver,
         http_proxy: None,
-    };
+        connection_via: vec![],
+        pac_file: None,
+        reverse_accept_hook: None,
+        spa_knocker: None,
+        reconnect: ReconnectPolicy::default(),
+        reloadable: Default::default(),
+        transport: None,
+        interceptors: Default::default(),
+        cancel: Default::default(),
+        events: Default::default(),
+        instance: Default::default(),
+        capture: None,
+    }
+}
 
+#[fixture]
+async fn client_ws(client_config: WsClientConfig) -> WsClient {
     WsClient::new(client_config, 1, Duration::from_secs(1)).await.unwrap()
 }
 
@@ -80,19 +162,27 @@ fn no_restrictions() -> RestrictionsRules {
         port: vec![],
         host: default_host(),
         cidr: default_cidr(),
+        proxy_protocol: None,
+        forwarded_header: None,
     });
     let reverse_tunnel = AllowConfig::ReverseTunnel(types::AllowReverseTunnelConfig {
         protocol: vec![],
         port: vec![],
         port_mapping: Default::default(),
+        isolated_port: vec![],
         cidr: default_cidr(),
     });
 
     RestrictionsRules {
         restrictions: vec![RestrictionConfig {
             name: "".to_string(),
+            priority: 0,
             r#match: vec![MatchConfig::Any],
             allow: vec![tunnels, reverse_tunnel],
+            quota: Default::default(),
+            time_window: Default::default(),
+            max_session_duration: None,
+            interceptors: vec![],
         }],
     }
 }
@@ -151,6 +241,701 @@ async fn test_tcp_tunnel(
     assert_eq!(&buf[..6], b"world!");
 }
 
+#[cfg(unix)]
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_server_accept_loops(
+    #[future] client_ws: WsClient,
+    mut server_config: WsServerConfig,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    server_config.accept_loops = 2;
+    let server_h = tokio::spawn(WsServer::new(server_config).serve(no_restrictions));
+    defer! { server_h.abort(); };
+    let client_ws = client_ws.await;
+
+    // The listeners are bound with SO_REUSEPORT, another process can listen on the same address
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.set_reuseaddr(true).unwrap();
+    socket.set_reuseport(true).unwrap();
+    socket.bind("127.0.0.1:8080".parse().unwrap()).unwrap();
+    drop(socket.listen(1).unwrap());
+
+    let server = TcpTunnelListener::new(TUNNEL_LISTEN.0, (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()), false)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        client_ws.run_tunnel(server).await.unwrap();
+    });
+
+    // Whichever accept loop gets them, all the connections are tunneled
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    for _ in 0..4 {
+        let mut client = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await
+        .unwrap();
+        client.write_all(b"Hello").await.unwrap();
+        let mut dd = tcp_listener.next().await.unwrap().unwrap();
+        let mut buf = BytesMut::new();
+        dd.read_buf(&mut buf).await.unwrap();
+        assert_eq!(&buf[..5], b"Hello");
+    }
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_reverse_tunnel_waits_for_server(
+    client_config: WsClientConfig,
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+) {
+    // No connection is opened before the reverse tunnel, the server is not started yet
+    let client = WsClient::new(client_config, 0, Duration::from_secs(1)).await.unwrap();
+    let instance = client.config.instance.clone();
+    tokio::spawn(async move {
+        let cfg = client.config.clone();
+        let endpoint = ENDPOINT_LISTEN.1;
+        let connector = TcpTunnelConnector::new(
+            &endpoint,
+            ENDPOINT_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(1),
+            &cfg.dns_resolver,
+        );
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::ReverseTcp,
+            host: TUNNEL_LISTEN.1,
+            port: TUNNEL_LISTEN.0.port(),
+        };
+        client.run_reverse_tunnel(remote, connector).await.unwrap();
+    });
+
+    // The client backs off, and registers the reverse listener once the server is up
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client = loop {
+        match TcpStream::connect(TUNNEL_LISTEN.0).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    };
+
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+    assert_eq!(instance.active_tunnels.counters().reconnects, 1);
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_embedded_client_and_server(dns_resolver: DnsResolver) {
+    let server = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap()).spawn();
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .local_to_remote("tcp://127.0.0.1:9998:127.0.0.1:9999")
+        .unwrap()
+        .spawn();
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client_cnx = loop {
+        let cnx = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await;
+        match cnx {
+            Ok(cnx) => break cnx,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+
+    client_cnx.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+
+    // Both listeners are closed, with the running connection
+    client.shutdown().await.unwrap();
+    server.shutdown().await.unwrap();
+    assert!(TcpStream::connect(TUNNEL_LISTEN.0).await.is_err());
+    assert!(TcpStream::connect("127.0.0.1:8080").await.is_err());
+    buf.clear();
+    assert!(matches!(dd.read_buf(&mut buf).await, Ok(0) | Err(_)));
+}
+
+#[rstest]
+#[case::ftp("ftp://127.0.0.1:8080")]
+#[case::unix("unix:///tmp/wstunnel.sock")]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+async fn test_embedded_client_invalid_scheme(#[case] url: &str) {
+    let client = TunnelClientBuilder::new(Url::parse(url).unwrap()).spawn();
+    let err = client.join().await.unwrap_err();
+    assert!(err.to_string().contains("expected ws, wss, http or https"), "{err:?}");
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_embedded_cancellation_token(dns_resolver: DnsResolver) {
+    let token = CancellationToken::new();
+    let server = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .cancellation_token(&token)
+        .spawn();
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .local_to_remote("tcp://127.0.0.1:9998:127.0.0.1:9999")
+        .unwrap()
+        .cancellation_token(&token)
+        .spawn();
+    let client_token = client.cancellation_token();
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client_cnx = loop {
+        let cnx = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await;
+        match cnx {
+            Ok(cnx) => break cnx,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    client_cnx.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+
+    // The token of the caller stops both, with the connection running through them
+    token.cancel();
+    client.join().await.unwrap();
+    server.join().await.unwrap();
+    assert!(client_token.is_cancelled());
+    assert!(TcpStream::connect(TUNNEL_LISTEN.0).await.is_err());
+    buf.clear();
+    assert!(matches!(client_cnx.read_buf(&mut buf).await, Ok(0) | Err(_)));
+    assert!(matches!(dd.read_buf(&mut buf).await, Ok(0) | Err(_)));
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_embedded_events(dns_resolver: DnsResolver) {
+    let server = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap()).spawn();
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .local_to_remote("tcp://127.0.0.1:9998:127.0.0.1:9999")
+        .unwrap()
+        .spawn();
+    let mut server_events = server.subscribe();
+    let mut client_events = client.subscribe();
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client_cnx = loop {
+        let cnx = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await;
+        match cnx {
+            Ok(cnx) => break cnx,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    client_cnx.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+
+    match client_events.recv().await.unwrap() {
+        TunnelEvent::TransportConnected { server } => assert_eq!(server, "ws://127.0.0.1:8080"),
+        event => panic!("unexpected event {:?}", event),
+    }
+    match server_events.recv().await.unwrap() {
+        TunnelEvent::TunnelOpened { tunnel } => assert_eq!(tunnel.destination, "127.0.0.1:9999"),
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    // Closed once both sides of the tunnel are
+    drop(client_cnx);
+    drop(dd);
+    match server_events.recv().await.unwrap() {
+        TunnelEvent::TunnelClosed { bytes_from_client, .. } => assert_eq!(bytes_from_client, 5),
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    client.shutdown().await.unwrap();
+    server.shutdown().await.unwrap();
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_embedded_add_tunnel() {
+    let server = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap()).spawn();
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap()).spawn();
+    let (id, local_addr) = loop {
+        match client.add_tunnel("-L tcp://127.0.0.1:0:127.0.0.1:9999").await {
+            Ok(tunnel) => break tunnel,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    assert_eq!(id, "-L tcp://127.0.0.1:0:127.0.0.1:9999");
+    assert!(client.add_tunnel(&id).await.is_err());
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client_cnx = TcpStream::connect(local_addr).await.unwrap();
+    client_cnx.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+
+    let status = client.status().await.unwrap();
+    assert_eq!(status.tunnels.len(), 1);
+    assert_eq!(status.tunnels[0].listen, local_addr.to_string());
+    assert!(client.remove_tunnel(&id).await.unwrap());
+    assert!(!client.remove_tunnel(&id).await.unwrap());
+    assert!(TcpStream::connect(local_addr).await.is_err());
+    assert!(server.add_tunnel(&id).await.is_err());
+
+    client.shutdown().await.unwrap();
+    server.shutdown().await.unwrap();
+}
+
+/// Network giving the queue of each attached client to the test
+struct TestPacketNetwork(mpsc::UnboundedSender<(IpAddr, PacketQueue)>);
+
+impl PacketNetwork for TestPacketNetwork {
+    fn attach(&self, client_ip: IpAddr) -> anyhow::Result<PacketDevice> {
+        let (queue, device) = packet::channel(16);
+        self.0.send((client_ip, queue))?;
+        Ok(device)
+    }
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_embedded_packet_tunnel() {
+    let (attached_tx, mut attached_rx) = mpsc::unbounded_channel();
+    let server = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .packet_network(TestPacketNetwork(attached_tx))
+        .configure(|args| args.deny_private_destinations = true)
+        .spawn();
+    let (mut client_queue, device) = packet::channel(16);
+    let client_ip = IpAddr::from([10, 0, 0, 2]);
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .packet_tunnel(client_ip, device)
+        .spawn();
+
+    let (ip, mut server_queue) = attached_rx.recv().await.unwrap();
+    assert_eq!(ip, client_ip);
+    let ipv4_packet = |destination: [u8; 4]| {
+        let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 2];
+        packet.extend_from_slice(&destination);
+        Bytes::from(packet)
+    };
+    // Private destinations are denied, even in the network of the tun tunnels
+    client_queue.send(ipv4_packet([10, 0, 0, 3])).await.unwrap();
+    client_queue.send(Bytes::from_static(b"request")).await.unwrap();
+    client_queue.send(ipv4_packet([1, 1, 1, 1])).await.unwrap();
+    assert_eq!(server_queue.recv().await.unwrap(), ipv4_packet([1, 1, 1, 1]));
+    assert_eq!(server_queue.try_recv(), None);
+    server_queue.send(Bytes::from_static(b"response")).await.unwrap();
+    assert_eq!(client_queue.recv().await.unwrap(), Bytes::from_static(b"response"));
+
+    client.shutdown().await.unwrap();
+    server.shutdown().await.unwrap();
+}
+
+/// Upgrade requests and responses as lines of text: the request or status line, then the headers until an empty line
+struct LineTransport;
+
+async fn write_head(tx: &mut TransportWrite, first_line: String, headers: &HeaderMap) -> std::io::Result<()> {
+    let mut head = first_line + "\n";
+    for (name, value) in headers {
+        head += &format!("{}: {}\n", name, value.to_str().unwrap_or_default());
+    }
+    tx.write_all(format!("{head}\n").as_bytes()).await?;
+    tx.flush().await
+}
+
+async fn read_head(rx: &mut BufReader<TransportRead>) -> anyhow::Result<(String, HeaderMap)> {
+    let mut first_line = String::new();
+    rx.read_line(&mut first_line).await?;
+    let mut headers = HeaderMap::new();
+    loop {
+        let mut line = String::new();
+        rx.read_line(&mut line).await?;
+        let Some((name, value)) = line.trim_end().split_once(": ") else {
+            return Ok((first_line.trim_end().to_string(), headers));
+        };
+        headers.append(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+    }
+}
+
+impl TunnelTransport for LineTransport {
+    fn connect(
+        &self,
+        rx: TransportRead,
+        mut tx: TransportWrite,
+        request: Request<()>,
+    ) -> BoxFuture<'static, anyhow::Result<(response::Parts, TransportRead, TransportWrite)>> {
+        Box::pin(async move {
+            write_head(&mut tx, request.uri().to_string(), request.headers()).await?;
+            let mut rx = BufReader::new(rx);
+            let (status, headers) = read_head(&mut rx).await?;
+            let mut response = Response::builder().status(status.as_str()).body(())?;
+            *response.headers_mut() = headers;
+            Ok((response.into_parts().0, Box::pin(rx) as TransportRead, tx))
+        })
+    }
+
+    fn accept(
+        &self,
+        rx: TransportRead,
+        tx: TransportWrite,
+    ) -> BoxFuture<'static, anyhow::Result<(request::Parts, TransportRead, TransportWrite)>> {
+        Box::pin(async move {
+            let mut rx = BufReader::new(rx);
+            let (uri, headers) = read_head(&mut rx).await?;
+            let mut request = Request::builder().uri(uri).body(())?;
+            *request.headers_mut() = headers;
+            Ok((request.into_parts().0, Box::pin(rx) as TransportRead, tx))
+        })
+    }
+
+    fn respond<'a>(
+        &'a self,
+        tx: &'a mut TransportWrite,
+        response: response::Parts,
+    ) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move { write_head(tx, response.status.as_str().to_string(), &response.headers).await })
+    }
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_pluggable_transport(dns_resolver: DnsResolver) {
+    let server = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .transport(LineTransport)
+        .spawn();
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .local_to_remote("tcp://127.0.0.1:9998:127.0.0.1:9999")
+        .unwrap()
+        .transport(LineTransport)
+        .spawn();
+    defer! { drop(client); drop(server); };
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client_cnx = loop {
+        let cnx = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await;
+        match cnx {
+            Ok(cnx) => break cnx,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+
+    client_cnx.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+
+    dd.write_all(b"world").await.unwrap();
+    buf.clear();
+    client_cnx.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"world");
+}
+
+/// Accepts the clients but denies their tunnels
+#[derive(Default)]
+struct DenyStreams {
+    connects: AtomicUsize,
+    denied: AtomicUsize,
+}
+
+impl TunnelEventHandler for Arc<DenyStreams> {
+    fn on_connect(&self, _peer: SocketAddr) -> bool {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn on_stream_open(&self, tunnel: &TunnelInfo) -> bool {
+        assert_eq!(tunnel.destination, "127.0.0.1:9999");
+        self.denied.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_socks5_failure_reply(dns_resolver: DnsResolver) {
+    let server = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .restrict_to("127.0.0.1", 1)
+        .spawn();
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .local_to_remote("socks5://127.0.0.1:9998")
+        .unwrap()
+        .spawn();
+    defer! { drop(client); drop(server); };
+
+    let mut client_cnx = loop {
+        let cnx = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await;
+        match cnx {
+            Ok(cnx) => break cnx,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+
+    let mut reply = [0u8; 10];
+    client_cnx.write_all(&[5, 1, 0]).await.unwrap();
+    client_cnx.read_exact(&mut reply[..2]).await.unwrap();
+    assert_eq!(reply[..2], [5, 0]);
+    let port = ENDPOINT_LISTEN.0.port().to_be_bytes();
+    client_cnx
+        .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+        .await
+        .unwrap();
+
+    // The destination is refused by the server, the client is told so instead of waiting
+    client_cnx.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..2], [5, 2]);
+    let mut buf = BytesMut::new();
+    assert_eq!(client_cnx.read_buf(&mut buf).await.unwrap_or(0), 0);
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_event_handler_denies_stream(dns_resolver: DnsResolver) {
+    let handler = Arc::new(DenyStreams::default());
+    let server = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .event_handler(handler.clone())
+        .spawn();
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .local_to_remote("tcp://127.0.0.1:9998:127.0.0.1:9999")
+        .unwrap()
+        .spawn();
+    defer! { drop(client); drop(server); };
+
+    let mut client_cnx = loop {
+        let cnx = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await;
+        match cnx {
+            Ok(cnx) => break cnx,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+
+    // The tunnel is closed without connecting to its destination
+    client_cnx.write_all(b"Hello").await.unwrap();
+    let mut buf = BytesMut::new();
+    assert_eq!(client_cnx.read_buf(&mut buf).await.unwrap_or(0), 0);
+    assert_eq!(handler.denied.load(Ordering::Relaxed), 1);
+    assert!(handler.connects.load(Ordering::Relaxed) >= 1);
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tunnel_service_mounted(dns_resolver: DnsResolver) {
+    let service = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .into_service()
+        .await
+        .unwrap()
+        .mount_path("/tunnel");
+    // Web application of its own, serving the tunnels under /tunnel
+    let app_listener = tokio::net::TcpListener::bind("127.0.0.1:8080").await.unwrap();
+    let app = tokio::spawn(async move {
+        loop {
+            let (stream, peer) = app_listener.accept().await.unwrap();
+            let service = service.clone();
+            let app = service_fn(move |mut req: Request<Incoming>| {
+                let mut service = service.clone();
+                async move {
+                    if !req.uri().path().starts_with("/tunnel/") {
+                        return Ok(Response::new(Either::Left("app".to_string())));
+                    }
+                    req.extensions_mut().insert(peer);
+                    tower_service::Service::call(&mut service, req).await
+                }
+            });
+            tokio::spawn(
+                http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), app)
+                    .with_upgrades(),
+            );
+        }
+    });
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .local_to_remote("tcp://127.0.0.1:9998:127.0.0.1:9999")
+        .unwrap()
+        .http_upgrade_path_prefix("tunnel/v1")
+        .spawn();
+    defer! { drop(client); app.abort(); };
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client_cnx = loop {
+        let cnx = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await;
+        match cnx {
+            Ok(cnx) => break cnx,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+
+    client_cnx.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+
+    dd.write_all(b"world").await.unwrap();
+    buf.clear();
+    client_cnx.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"world");
+}
+
+/// Upper cases the bytes read from the local side
+struct Uppercase;
+
+struct UppercaseRead(InterceptedRead);
+
+impl AsyncRead for UppercaseRead {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let ret = self.0.as_mut().poll_read(cx, buf);
+        buf.filled_mut()[filled..].make_ascii_uppercase();
+        ret
+    }
+}
+
+impl StreamInterceptor for Uppercase {
+    fn intercept(
+        &self,
+        _stream: &StreamInfo,
+        rx: InterceptedRead,
+        tx: InterceptedWrite,
+    ) -> (InterceptedRead, InterceptedWrite) {
+        (Box::pin(UppercaseRead(rx)), tx)
+    }
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_stream_interceptors(dns_resolver: DnsResolver) {
+    let restrictions = std::env::temp_dir().join(format!("wstunnel-interceptors-{}.yaml", std::process::id()));
+    std::fs::write(
+        &restrictions,
+        "restrictions:\n  - name: upper\n    match:\n      - !Any\n    interceptors: [upper]\n    allow:\n      - !Tunnel\n",
+    )
+    .unwrap();
+    let server = TunnelServerBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .restrict_config(restrictions.clone())
+        .interceptor("upper", Uppercase)
+        .spawn();
+    let client = TunnelClientBuilder::new(Url::parse("ws://127.0.0.1:8080").unwrap())
+        .local_to_remote("tcp://127.0.0.1:9998:127.0.0.1:9999?interceptor=upper")
+        .unwrap()
+        .interceptor("upper", Uppercase)
+        .spawn();
+    defer! { drop(client); drop(server); let _ = std::fs::remove_file(&restrictions); };
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client_cnx = loop {
+        let cnx = protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            Duration::from_secs(10),
+            &dns_resolver,
+        )
+        .await;
+        match cnx {
+            Ok(cnx) => break cnx,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+
+    // By the interceptor of the client
+    client_cnx.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"HELLO");
+
+    // By the interceptor of the restriction on the server
+    dd.write_all(b"world").await.unwrap();
+    buf.clear();
+    client_cnx.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"WORLD");
+}
+
 #[rstest]
 #[timeout(Duration::from_secs(10))]
 #[tokio::test]
@@ -0,0 +1,335 @@
+use crate::protocols::dns::DnsLookupError;
+use crate::tunnel::client::TransferStats;
+use crate::tunnel::transport::ServerRejection;
+use ahash::AHashMap;
+use hickory_resolver::error::ResolveError;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// Tunnels currently open by a client or server, listed by the 'tunnels' admin command
+#[derive(Default)]
+pub struct ActiveTunnels {
+    next_key: AtomicU64,
+    tunnels: Mutex<AHashMap<u64, (ActiveTunnel, Weak<TransferStats>)>>,
+    /// Indexed by the reason of the failure
+    failed: [AtomicU64; FailureReason::ALL.len()],
+    reconnects: AtomicU64,
+    slow_consumers: AtomicU64,
+    /// Traffic of the tunnels already closed
+    closed_bytes_sent: AtomicU64,
+    closed_bytes_received: AtomicU64,
+}
+
+/// Why a tunnel could not be opened, so dashboards can tell the failures of the network from the refusals of the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    Dns,
+    Refused,
+    Timeout,
+    Tls,
+    /// Refused by the restrictions, the authentication or the quotas of the server
+    Restriction,
+    Other,
+}
+
+impl FailureReason {
+    const ALL: [Self; 6] = [
+        Self::Dns,
+        Self::Refused,
+        Self::Timeout,
+        Self::Tls,
+        Self::Restriction,
+        Self::Other,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::Refused => "refused",
+            Self::Timeout => "timeout",
+            Self::Tls => "tls",
+            Self::Restriction => "restriction",
+            Self::Other => "other",
+        }
+    }
+
+    /// Reason of the failure of a tunnel, from the errors of its chain
+    pub fn classify(err: &anyhow::Error) -> Self {
+        if let Some(failure) = err.downcast_ref::<ClassifiedFailure>() {
+            return failure.reason;
+        }
+        if let Some(rejection) = err.downcast_ref::<ServerRejection>() {
+            return Self::from_status(rejection.status);
+        }
+        if err.downcast_ref::<DnsLookupError>().is_some() || err.chain().any(|cause| cause.is::<ResolveError>()) {
+            return Self::Dns;
+        }
+
+        let is_tls_error = |cause: &(dyn std::error::Error + 'static)| cause.is::<tokio_rustls::rustls::Error>();
+        for cause in err.chain() {
+            if is_tls_error(cause) {
+                return Self::Tls;
+            }
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return Self::Timeout;
+            }
+            let Some(err) = cause.downcast_ref::<io::Error>() else {
+                continue;
+            };
+            // tokio-rustls reports the errors of the handshake as io errors
+            if err.get_ref().is_some_and(|inner| is_tls_error(inner)) {
+                return Self::Tls;
+            }
+            match err.kind() {
+                io::ErrorKind::ConnectionRefused => return Self::Refused,
+                io::ErrorKind::TimedOut => return Self::Timeout,
+                _ => {}
+            }
+        }
+
+        Self::Other
+    }
+
+    /// Reason of the failure of a tunnel refused by the server with this status
+    pub const fn from_status(status: u16) -> Self {
+        match status {
+            401 | 402 | 403 | 429 => Self::Restriction,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Failure already classified, reported after the error it comes from is gone
+#[derive(Debug, derive_more::Display, derive_more::Error)]
+#[display("{message}")]
+pub struct ClassifiedFailure {
+    pub reason: FailureReason,
+    pub message: String,
+}
+
+#[derive(Clone)]
+pub struct ActiveTunnel {
+    pub id: String,
+    pub source: Option<SocketAddr>,
+    pub destination: String,
+    pub protocol: String,
+    /// Subject of the bearer token of the client, or its path prefix. Only known by the server
+    pub identity: Option<String>,
+    pub started_at: Instant,
+    /// Notified to close the tunnel with the 'close' admin command, only by the client
+    pub close: Option<Arc<Notify>>,
+}
+
+/// Keep the tunnel in the list until it is dropped, with the stats of the streams of the tunnel
+pub struct ActiveTunnelGuard {
+    key: u64,
+    tunnels: Weak<ActiveTunnels>,
+}
+
+impl ActiveTunnelGuard {
+    /// Keep the traffic of the tunnel in the totals once it is closed
+    pub(crate) fn record_closed(&self, sent: u64, received: u64) {
+        if let Some(tunnels) = self.tunnels.upgrade() {
+            tunnels.closed_bytes_sent.fetch_add(sent, Ordering::Relaxed);
+            tunnels.closed_bytes_received.fetch_add(received, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for ActiveTunnelGuard {
+    fn drop(&mut self) {
+        if let Some(tunnels) = self.tunnels.upgrade() {
+            tunnels.tunnels.lock().remove(&self.key);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActiveTunnelInfo {
+    pub id: String,
+    pub source: Option<SocketAddr>,
+    pub destination: String,
+    pub protocol: String,
+    pub identity: Option<String>,
+    pub age_secs: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Average of both directions since the tunnel is open
+    pub bytes_per_sec: u64,
+}
+
+/// Counters since the start of the client or server, returned by the 'stats' admin command
+#[derive(Debug, Serialize)]
+pub struct TunnelCounters {
+    pub active_tunnels: usize,
+    pub opened_tunnels: u64,
+    pub failed_tunnels: u64,
+    /// The failed tunnels, by the reason of their failure
+    pub failures: BTreeMap<&'static str, u64>,
+    pub reconnects: u64,
+    /// Times a side of a tunnel did not accept writes for the slow consumer timeout
+    pub slow_consumers: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl ActiveTunnels {
+    /// List the tunnel until its stats are dropped. The stats count the bytes read from the local side of the
+    /// tunnel (sent), and written to it (received)
+    pub fn register(self: &Arc<Self>, tunnel: ActiveTunnel, stats: &Arc<TransferStats>) {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        self.tunnels.lock().insert(key, (tunnel, Arc::downgrade(stats)));
+        stats.set_active_tunnel(ActiveTunnelGuard {
+            key,
+            tunnels: Arc::downgrade(self),
+        });
+    }
+
+    /// A tunnel could not be opened
+    pub fn record_failure(&self, reason: FailureReason) {
+        self.failed[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A reverse tunnel is connected again to the server, after losing it
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A side of a tunnel has not accepted writes for the slow consumer timeout
+    pub fn record_slow_consumer(&self) {
+        self.slow_consumers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> TunnelCounters {
+        let stats = self.active_stats();
+        TunnelCounters {
+            active_tunnels: stats.len(),
+            opened_tunnels: self.next_key.load(Ordering::Relaxed),
+            failed_tunnels: self.failed.iter().map(|failed| failed.load(Ordering::Relaxed)).sum(),
+            failures: FailureReason::ALL
+                .iter()
+                .map(|reason| (reason.name(), self.failed[*reason as usize].load(Ordering::Relaxed)))
+                .collect(),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            slow_consumers: self.slow_consumers.load(Ordering::Relaxed),
+            bytes_sent: self.closed_bytes_sent.load(Ordering::Relaxed)
+                + stats.iter().map(|(_, stats)| stats.sent()).sum::<u64>(),
+            bytes_received: self.closed_bytes_received.load(Ordering::Relaxed)
+                + stats.iter().map(|(_, stats)| stats.received()).sum::<u64>(),
+        }
+    }
+
+    /// The stats must be dropped outside the lock, as the last one removes its tunnel
+    fn active_stats(&self) -> Vec<(ActiveTunnel, Arc<TransferStats>)> {
+        self.tunnels
+            .lock()
+            .values()
+            .filter_map(|(tunnel, stats)| Some((tunnel.clone(), stats.upgrade()?)))
+            .collect()
+    }
+
+    /// Close the open tunnel with this id, false if there is none or if it cannot be closed
+    pub fn close(&self, id: &str) -> bool {
+        let tunnels = self.tunnels.lock();
+        let Some(close) = tunnels
+            .values()
+            .find(|(tunnel, _)| tunnel.id == id)
+            .and_then(|(tunnel, _)| tunnel.close.as_ref())
+        else {
+            return false;
+        };
+        close.notify_one();
+        true
+    }
+
+    /// Open tunnels, the ones transferring the most first
+    pub fn list(&self) -> Vec<ActiveTunnelInfo> {
+        let mut tunnels: Vec<ActiveTunnelInfo> = self
+            .active_stats()
+            .into_iter()
+            .map(|(tunnel, stats)| {
+                let age = tunnel.started_at.elapsed();
+                let bytes = stats.sent() + stats.received();
+                ActiveTunnelInfo {
+                    id: tunnel.id,
+                    source: tunnel.source,
+                    destination: tunnel.destination,
+                    protocol: tunnel.protocol,
+                    identity: tunnel.identity,
+                    age_secs: age.as_secs(),
+                    bytes_sent: stats.sent(),
+                    bytes_received: stats.received(),
+                    bytes_per_sec: (bytes as f64 / age.as_secs_f64().max(1.0)) as u64,
+                }
+            })
+            .collect();
+        tunnels.sort_by(|a, b| b.bytes_per_sec.cmp(&a.bytes_per_sec).then(b.age_secs.cmp(&a.age_secs)));
+
+        tunnels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::client::CountingStream;
+    use anyhow::anyhow;
+    use test_case::test_case;
+    use tokio::io::AsyncWriteExt;
+
+    #[test_case(anyhow!(io::Error::from(io::ErrorKind::ConnectionRefused)).context("Cannot connect to tcp endpoint") => FailureReason::Refused ; "refused")]
+    #[test_case(anyhow!(io::Error::from(io::ErrorKind::TimedOut)) => FailureReason::Timeout ; "timeout")]
+    #[test_case(anyhow!(io::Error::from(io::ErrorKind::NotFound)).context(DnsLookupError).context("cannot resolve domain") => FailureReason::Dns ; "dns")]
+    #[test_case(anyhow!(io::Error::new(io::ErrorKind::InvalidData, tokio_rustls::rustls::Error::DecryptError)) => FailureReason::Tls ; "tls")]
+    #[test_case(anyhow!(ServerRejection { status: 403, reason: "forbidden".to_string() }).context("handshake") => FailureReason::Restriction ; "restriction")]
+    #[test_case(anyhow!(ServerRejection { status: 500, reason: "internal error".to_string() }) => FailureReason::Other ; "server error")]
+    #[test_case(anyhow!("invalid jwt") => FailureReason::Other ; "other")]
+    fn test_classify(err: anyhow::Error) -> FailureReason {
+        FailureReason::classify(&err)
+    }
+
+    #[tokio::test]
+    async fn test_active_tunnels() {
+        let tunnels = Arc::new(ActiveTunnels::default());
+        let stats = Arc::new(TransferStats::default());
+        tunnels.register(
+            ActiveTunnel {
+                id: "test_active_tunnels".to_string(),
+                source: None,
+                destination: "example.com:443".to_string(),
+                protocol: "Tcp".to_string(),
+                identity: None,
+                started_at: Instant::now(),
+                close: Some(Arc::new(Notify::new())),
+            },
+            &stats,
+        );
+        let mut stream = CountingStream::new(Vec::new(), Some(stats));
+        stream.write_all(b"hello").await.unwrap();
+
+        let find = || {
+            tunnels
+                .list()
+                .into_iter()
+                .find(|tunnel| tunnel.id == "test_active_tunnels")
+        };
+        let tunnel = find().unwrap();
+        assert_eq!(tunnel.bytes_received, 5);
+        assert_eq!(tunnel.bytes_sent, 0);
+        assert!(tunnels.close("test_active_tunnels"));
+        assert!(!tunnels.close("unknown"));
+
+        drop(stream);
+        assert!(find().is_none());
+        let counters = tunnels.counters();
+        assert_eq!(counters.active_tunnels, 0);
+        assert_eq!(counters.bytes_received, 5);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::log_filter::{log_filter, reload_log_filter};
+use crate::protocols::dns::DNS_METRICS;
+use crate::protocols::unix_sock::UnixListenerStream;
+use crate::runtime_metrics::runtime_metrics;
+use crate::state_dump::INTERNAL_STATE;
+use crate::tunnel::active_tunnels::ActiveTunnels;
+use crate::tunnel::tunnel_metrics::TUNNEL_METRICS;
+use futures_util::StreamExt;
+use serde_json::json;
+use std::future::Future;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tracing::{info, warn};
+
+/// Serve admin commands, one command per line, each answered by the json line returned by `exec_command`
+pub(crate) async fn serve_admin_commands<F, Fut>(mut listener: UnixListenerStream, exec_command: F)
+where
+    F: Fn(String) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = serde_json::Value> + Send,
+{
+    while let Some(stream) = listener.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Error accepting admin connection: {:?}", err);
+                continue;
+            }
+        };
+
+        let exec_command = exec_command.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_admin_client(stream, exec_command).await {
+                warn!("Admin connection closed with error: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn handle_admin_client<F, Fut>(stream: UnixStream, exec_command: F) -> anyhow::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = serde_json::Value>,
+{
+    let (rx, mut tx) = stream.into_split();
+    let mut lines = BufReader::new(rx).lines();
+    while let Some(command) = lines.next_line().await? {
+        info!("Executing admin command: {}", command);
+        let mut response = serde_json::to_vec(&exec_command(command).await)?;
+        response.push(b'\n');
+        tx.write_all(&response).await?;
+    }
+
+    Ok(())
+}
+
+/// Commands available on both the client and the server. None if the command is not one of them
+/// tunnels            => the tunnels currently open, with their source, destination, age and traffic
+/// stats              => the number of tunnels open, opened, failed to open by reason, of reconnections and of slow consumers
+///                       since the start, with the first byte latency and throughput histograms of each tunnel configured
+///                       with -L/-R, the outcomes and durations of the dns lookups and the metrics of the tokio runtime
+/// log-level [FILTER] => the current log filter, or replace it without restarting (i.e: info,wstunnel::protocols::udp=trace)
+/// dump               => snapshot of the internal state, as dumped on SIGUSR1
+pub(crate) fn exec_common_command(command: &str, tunnels: &ActiveTunnels) -> Option<serde_json::Value> {
+    let mut args = command.split_whitespace();
+    let response = match (args.next(), args.next()) {
+        (Some("tunnels"), None) => json!(tunnels.list()),
+        (Some("stats"), None) => {
+            let mut stats = json!(tunnels.counters());
+            stats["forwards"] = json!(TUNNEL_METRICS.list());
+            stats["dns"] = json!(DNS_METRICS.snapshot());
+            stats["runtime"] = json!(runtime_metrics());
+            stats
+        }
+        (Some("dump"), None) => INTERNAL_STATE.dump(tunnels),
+        (Some("log-level"), None) => match log_filter() {
+            Some(filter) => json!({ "log_level": filter }),
+            None => json!({ "error": "The log filter cannot be changed at runtime" }),
+        },
+        (Some("log-level"), Some(filter)) => match reload_log_filter(filter) {
+            Ok(()) => json!({ "log_level": filter }),
+            Err(err) => json!({ "error": err.to_string() }),
+        },
+        _ => return None,
+    };
+
+    Some(response)
+}
@@ -0,0 +1,183 @@
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Bytes per second available to the streams sharing the bucket. It holds at most 1 second of bandwidth,
+/// and can go in debt when streams transfer at the same time
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Full, the first second of bandwidth is available right away
+    pub(crate) fn new(rate: u64) -> Self {
+        Self {
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// How many bytes can be transferred now, or how long to wait for the bandwidth to be available
+    pub(crate) fn allowance(&mut self, rate: u64, wanted: usize) -> Result<usize, Duration> {
+        let rate = rate as f64;
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * rate).min(rate);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            Ok(wanted.min(self.tokens as usize))
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    pub(crate) fn consume(&mut self, len: usize) {
+        self.tokens -= len as f64;
+    }
+}
+
+/// Bandwidth shared by both directions of all the connections of a tunnel, with ?max_bandwidth=BYTES_PER_SEC
+pub struct BandwidthLimit {
+    rate: u64,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl BandwidthLimit {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            rate: bytes_per_sec,
+            bucket: Mutex::new(TokenBucket::new(bytes_per_sec)),
+        }
+    }
+
+    fn poll_allowance(
+        &self,
+        throttle: &mut Option<Pin<Box<Sleep>>>,
+        wanted: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = throttle.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                *throttle = None;
+            }
+
+            match self.bucket.lock().allowance(self.rate, wanted) {
+                Ok(len) => return Poll::Ready(len),
+                Err(wait) => *throttle = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+
+    fn consume(&self, len: usize) {
+        self.bucket.lock().consume(len);
+    }
+}
+
+/// Local side of a tunnel, throttled when the tunnel has a bandwidth limit
+#[pin_project]
+pub(crate) struct ThrottledStream<T> {
+    #[pin]
+    inner: T,
+    limit: Option<Arc<BandwidthLimit>>,
+    throttle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> ThrottledStream<T> {
+    pub(crate) const fn new(inner: T, limit: Option<Arc<BandwidthLimit>>) -> Self {
+        Self {
+            inner,
+            limit,
+            throttle: None,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for ThrottledStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let Some(limit) = this.limit else {
+            return this.inner.poll_read(cx, buf);
+        };
+        let allowed = ready!(limit.poll_allowance(this.throttle, buf.remaining(), cx));
+
+        let before = buf.filled().len();
+        if allowed < buf.remaining() {
+            let mut limited = vec![0; allowed];
+            let mut limited = ReadBuf::new(&mut limited);
+            ready!(this.inner.poll_read(cx, &mut limited))?;
+            buf.put_slice(limited.filled());
+        } else {
+            ready!(this.inner.poll_read(cx, buf))?;
+        }
+        limit.consume(buf.filled().len() - before);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for ThrottledStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let Some(limit) = this.limit else {
+            return this.inner.poll_write(cx, buf);
+        };
+        let allowed = ready!(limit.poll_allowance(this.throttle, buf.len(), cx));
+
+        let len = ready!(this.inner.poll_write(cx, &buf[..allowed]))?;
+        limit.consume(len);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_bandwidth_shared_by_streams() {
+        let limit = Arc::new(BandwidthLimit::new(1000));
+        let (client, mut server) = tokio::io::duplex(2048);
+        let mut reader = ThrottledStream::new(client, Some(limit.clone()));
+        let (other, _other_server) = tokio::io::duplex(2048);
+        let mut writer = ThrottledStream::new(other, Some(limit));
+
+        let start = Instant::now();
+        writer.write_all(&[0; 800]).await.unwrap();
+        server.write_all(&[0; 700]).await.unwrap();
+        let mut buf = [0; 700];
+        reader.read_exact(&mut buf).await.unwrap();
+        // 1000 bytes of burst for both streams, then 1000 bytes per second
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[tokio::test]
+    async fn test_unlimited() {
+        let (client, mut server) = tokio::io::duplex(2048);
+        let mut stream = ThrottledStream::new(client, None);
+        server.write_all(&[0; 1500]).await.unwrap();
+
+        let start = Instant::now();
+        let mut buf = [0; 1500];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
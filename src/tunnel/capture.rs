@@ -0,0 +1,353 @@
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use anyhow::Context;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use std::fs::File;
+use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher};
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+use url::Host;
+
+/// Packets carry raw ip, without link layer header
+const LINKTYPE_RAW: u16 = 101;
+/// Data is split in several packets, so the length of an ipv4 packet fits in its header
+const MAX_SEGMENT_SIZE: usize = 16 * 1024;
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Plaintext of the tunnels of a client or server, written as pcapng with --capture-file, for debugging only
+pub struct CaptureFile(Mutex<BufWriter<File>>);
+
+impl CaptureFile {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = File::create(path).with_context(|| format!("Cannot create capture file {}", path.display()))?;
+        let mut file = BufWriter::new(file);
+
+        // Section header, then the only interface, all packets refer to it
+        write_block(
+            &mut file,
+            0x0A0D_0D0A,
+            &[&0x1A2B_3C4D_u32.to_le_bytes(), &[1, 0, 0, 0], &(-1_i64).to_le_bytes()],
+        )?;
+        write_block(
+            &mut file,
+            0x0000_0001,
+            &[&LINKTYPE_RAW.to_le_bytes(), &[0, 0], &0_u32.to_le_bytes()],
+        )?;
+        file.flush()?;
+
+        Ok(Self(Mutex::new(file)))
+    }
+}
+
+/// A pcapng block, padded to 32 bits and with its length at both ends
+fn write_block(file: &mut impl Write, block_type: u32, body: &[&[u8]]) -> std::io::Result<()> {
+    let len: usize = body.iter().map(|part| part.len()).sum();
+    let padding = (4 - len % 4) % 4;
+    let total_len = (12 + len + padding) as u32;
+    file.write_all(&block_type.to_le_bytes())?;
+    file.write_all(&total_len.to_le_bytes())?;
+    for part in body {
+        file.write_all(part)?;
+    }
+    file.write_all(&[0; 3][..padding])?;
+    file.write_all(&total_len.to_le_bytes())
+}
+
+/// Enhanced packet block of the interface, with an optional comment shown by wireshark
+fn write_packet(file: &mut impl Write, packet: &[u8], comment: Option<&str>) -> std::io::Result<()> {
+    let micros = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut header = Vec::with_capacity(20);
+    header.extend_from_slice(&0_u32.to_le_bytes());
+    header.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    header.extend_from_slice(&(micros as u32).to_le_bytes());
+    header.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    header.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    let packet_padding = &[0; 3][..(4 - packet.len() % 4) % 4];
+
+    let mut options = Vec::new();
+    if let Some(comment) = comment {
+        options.extend_from_slice(&1_u16.to_le_bytes());
+        options.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        options.extend_from_slice(comment.as_bytes());
+        options.extend_from_slice(&[0; 3][..(4 - comment.len() % 4) % 4]);
+        options.extend_from_slice(&[0; 4]);
+    }
+
+    write_block(file, 0x0000_0006, &[&header, packet, packet_padding, &options])
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    ToServer,
+    ToClient,
+}
+
+/// Logical connection of a tunnel, from the application using it to its destination.
+/// Its packets have synthetic ip and tcp/udp headers, the checksums of the tcp/udp ones are left empty
+pub(crate) struct CaptureFlow {
+    capture: Arc<CaptureFile>,
+    client: SocketAddr,
+    server: SocketAddr,
+    tcp: bool,
+    /// The local side of the tunnel is the application (-L on the client, -R on the server), else the destination
+    local_is_client: bool,
+    /// Next sequence number of the client and of the server, for tcp
+    seq: Mutex<(u32, u32)>,
+    /// Shown on the first packet, with the real destination
+    comment: Mutex<Option<String>>,
+}
+
+impl CaptureFlow {
+    /// The source is made up when it is not known
+    pub(crate) fn new(
+        capture: &Arc<CaptureFile>,
+        source: Option<SocketAddr>,
+        destination: &RemoteAddr,
+        local_is_client: bool,
+    ) -> Arc<Self> {
+        static NEXT_PORT: AtomicU16 = AtomicU16::new(0);
+
+        let client = source.unwrap_or_else(|| {
+            let port = 32768 + NEXT_PORT.fetch_add(1, Ordering::Relaxed) % 32768;
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)
+        });
+        let server_ip = match &destination.host {
+            Host::Ipv4(ip) => IpAddr::V4(*ip),
+            Host::Ipv6(ip) => IpAddr::V6(*ip),
+            // Same domain, same address, in the range reserved for benchmarks (198.18.0.0/15)
+            Host::Domain(domain) => {
+                let hash = BuildHasherDefault::<DefaultHasher>::default().hash_one(domain) as u32;
+                IpAddr::V4(Ipv4Addr::from(0xC612_0000 | (hash & 0x0001_FFFF)))
+            }
+        };
+        let tcp = !matches!(
+            destination.protocol,
+            LocalProtocol::Udp { .. } | LocalProtocol::ReverseUdp { .. } | LocalProtocol::TProxyUdp { .. }
+        );
+
+        let flow = Arc::new(Self {
+            capture: capture.clone(),
+            client,
+            server: SocketAddr::new(server_ip, destination.port),
+            tcp,
+            local_is_client,
+            seq: Mutex::new((1, 1)),
+            comment: Mutex::new(Some(format!(
+                "{} -> {}:{} {:?}",
+                client, destination.host, destination.port, destination.protocol
+            ))),
+        });
+        if tcp {
+            flow.write(Direction::ToServer, 0, 0, TCP_SYN, &[]);
+            flow.write(Direction::ToClient, 0, 1, TCP_SYN | TCP_ACK, &[]);
+            flow.write(Direction::ToServer, 1, 1, TCP_ACK, &[]);
+        }
+
+        flow
+    }
+
+    fn record(&self, from_local: bool, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let direction = if from_local == self.local_is_client {
+            Direction::ToServer
+        } else {
+            Direction::ToClient
+        };
+        if !self.tcp {
+            self.write(direction, 0, 0, 0, data);
+            return;
+        }
+
+        let mut seq = self.seq.lock();
+        for segment in data.chunks(MAX_SEGMENT_SIZE) {
+            let (client_seq, server_seq) = &mut *seq;
+            let (own, other) = match direction {
+                Direction::ToServer => (client_seq, *server_seq),
+                Direction::ToClient => (server_seq, *client_seq),
+            };
+            self.write(direction, *own, other, TCP_PSH | TCP_ACK, segment);
+            *own = own.wrapping_add(segment.len() as u32);
+        }
+    }
+
+    fn write(&self, direction: Direction, seq: u32, ack: u32, flags: u8, data: &[u8]) {
+        let (src, dst) = match direction {
+            Direction::ToServer => (self.client, self.server),
+            Direction::ToClient => (self.server, self.client),
+        };
+
+        let mut transport = Vec::with_capacity(20 + data.len());
+        transport.extend_from_slice(&src.port().to_be_bytes());
+        transport.extend_from_slice(&dst.port().to_be_bytes());
+        if self.tcp {
+            transport.extend_from_slice(&seq.to_be_bytes());
+            transport.extend_from_slice(&ack.to_be_bytes());
+            transport.extend_from_slice(&[5 << 4, flags]);
+            transport.extend_from_slice(&u16::MAX.to_be_bytes());
+            transport.extend_from_slice(&[0; 4]);
+        } else {
+            // A datagram too big for udp is truncated, it is only for debugging
+            let len = (8 + data.len()).min(u16::MAX as usize) as u16;
+            transport.extend_from_slice(&len.to_be_bytes());
+            transport.extend_from_slice(&[0; 2]);
+        }
+        transport.extend_from_slice(data);
+        let packet = ip_packet(src.ip(), dst.ip(), if self.tcp { 6 } else { 17 }, &transport);
+
+        let comment = self.comment.lock().take();
+        let mut file = self.capture.0.lock();
+        if let Err(err) = write_packet(&mut *file, &packet, comment.as_deref()).and_then(|_| file.flush()) {
+            warn!("Cannot write to capture file: {}", err);
+        }
+    }
+}
+
+impl Drop for CaptureFlow {
+    fn drop(&mut self) {
+        if !self.tcp {
+            return;
+        }
+        let (client_seq, server_seq) = *self.seq.get_mut();
+        self.write(Direction::ToServer, client_seq, server_seq, TCP_FIN | TCP_ACK, &[]);
+        self.write(
+            Direction::ToClient,
+            server_seq,
+            client_seq.wrapping_add(1),
+            TCP_FIN | TCP_ACK,
+            &[],
+        );
+    }
+}
+
+/// Ipv4 packet if both addresses are, else ipv6 with the ipv4 address mapped
+fn ip_packet(src: IpAddr, dst: IpAddr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(40 + payload.len());
+    match (src.to_canonical(), dst.to_canonical()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let len = (20 + payload.len()).min(u16::MAX as usize) as u16;
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&len.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = ipv4_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(payload.len().min(u16::MAX as usize) as u16).to_be_bytes());
+            packet.extend_from_slice(&[protocol, 64]);
+            packet.extend_from_slice(&to_v6(src).octets());
+            packet.extend_from_slice(&to_v6(dst).octets());
+        }
+    }
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// One's complement of the one's complement sum of the 16 bits words of the header
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Record what goes through the local side of a tunnel in the capture file
+#[pin_project]
+pub(crate) struct CaptureStream<T> {
+    #[pin]
+    inner: T,
+    flow: Option<Arc<CaptureFlow>>,
+}
+
+impl<T> CaptureStream<T> {
+    pub(crate) const fn new(inner: T, flow: Option<Arc<CaptureFlow>>) -> Self {
+        Self { inner, flow }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for CaptureStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let ret = this.inner.poll_read(cx, buf);
+        if let Some(flow) = this.flow {
+            flow.record(true, &buf.filled()[before..]);
+        }
+        ret
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for CaptureStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let ret = this.inner.poll_write(cx, buf);
+        if let (Some(flow), Poll::Ready(Ok(len))) = (this.flow, &ret) {
+            flow.record(false, &buf[..*len]);
+        }
+        ret
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv4_packet() {
+        let packet = ip_packet(
+            Ipv4Addr::new(127, 0, 0, 1).into(),
+            Ipv4Addr::new(192, 0, 2, 1).into(),
+            17,
+            &[0; 8],
+        );
+        assert_eq!(packet.len(), 28);
+        assert_eq!(&packet[2..4], &28_u16.to_be_bytes());
+        // The checksum of a header with a valid checksum is 0
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+    }
+
+    #[test]
+    fn test_write_packet() {
+        let mut file = Vec::new();
+        write_packet(&mut file, &[1, 2, 3, 4, 5], Some("hello")).unwrap();
+        // type, length, interface, timestamp (2), lengths (2), packet padded, comment option padded, end of options
+        let len = 12 + 20 + 8 + 4 + 8 + 4;
+        assert_eq!(file.len(), len);
+        assert_eq!(&file[4..8], &(len as u32).to_le_bytes());
+        assert_eq!(&file[len - 4..], &(len as u32).to_le_bytes());
+    }
+}
@@ -0,0 +1,236 @@
+use crate::tunnel::active_tunnels::ActiveTunnelGuard;
+use crate::tunnel::listeners::ClientAddr;
+use crate::tunnel::RemoteAddr;
+use anyhow::Context;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Access log of the dynamic proxy listeners (socks5, http proxy), one line per proxied connection.
+/// Lines are emitted with the `wstunnel::access_log` tracing target, or written as json to a dedicated file.
+#[derive(Clone)]
+pub struct AccessLog {
+    listener: &'static str,
+    file: Option<Arc<Mutex<File>>>,
+}
+
+#[derive(Serialize)]
+struct AccessLogLine<'a> {
+    timestamp: u64,
+    connection_id: String,
+    listener: &'static str,
+    client_addr: Option<SocketAddr>,
+    destination: String,
+    bytes_sent: u64,
+    bytes_received: u64,
+    duration_ms: u128,
+    result: &'a str,
+}
+
+impl AccessLog {
+    pub fn new(listener: &'static str, file: Option<Arc<Mutex<File>>>) -> Self {
+        Self { listener, file }
+    }
+
+    pub fn open_file(path: &Path) -> anyhow::Result<Arc<Mutex<File>>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open access log file {}", path.display()))?;
+
+        Ok(Arc::new(Mutex::new(file)))
+    }
+
+    pub fn log(
+        &self,
+        connection_id: Uuid,
+        client_addr: Option<SocketAddr>,
+        destination: &RemoteAddr,
+        stats: &TransferStats,
+        duration: Duration,
+        result: &anyhow::Result<()>,
+    ) {
+        let result = match result {
+            Ok(_) => "ok".to_string(),
+            Err(err) => format!("error: {}", err),
+        };
+        let line = AccessLogLine {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            connection_id: connection_id.to_string(),
+            listener: self.listener,
+            client_addr,
+            destination: format!("{}:{}", destination.host, destination.port),
+            bytes_sent: stats.sent(),
+            bytes_received: stats.received(),
+            duration_ms: duration.as_millis(),
+            result: &result,
+        };
+
+        let Some(file) = &self.file else {
+            info!(
+                target: "wstunnel::access_log",
+                connection_id = line.connection_id,
+                listener = line.listener,
+                client_addr = ?line.client_addr,
+                destination = line.destination,
+                bytes_sent = line.bytes_sent,
+                bytes_received = line.bytes_received,
+                duration_ms = line.duration_ms as u64,
+                result = line.result,
+            );
+            return;
+        };
+
+        let ret = serde_json::to_vec(&line)
+            .map_err(std::io::Error::from)
+            .and_then(|mut json| {
+                json.push(b'\n');
+                file.lock().write_all(&json)
+            });
+        if let Err(err) = ret {
+            warn!("Cannot write access log: {}", err);
+        }
+    }
+}
+
+/// Bytes sent by the client to the destination, and received from it
+#[derive(Default)]
+pub struct TransferStats {
+    sent: AtomicU64,
+    received: AtomicU64,
+    first_sent_at: OnceLock<Instant>,
+    first_received_at: OnceLock<Instant>,
+    /// Listed by the 'tunnels' admin command while the stats are alive
+    active_tunnel: OnceLock<ActiveTunnelGuard>,
+}
+
+impl TransferStats {
+    pub(crate) fn set_active_tunnel(&self, guard: ActiveTunnelGuard) {
+        let _ = self.active_tunnel.set(guard);
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// When the first byte was read from the local side of the tunnel
+    pub fn first_sent_at(&self) -> Option<Instant> {
+        self.first_sent_at.get().copied()
+    }
+
+    /// When the first byte was written to the local side of the tunnel
+    pub fn first_received_at(&self) -> Option<Instant> {
+        self.first_received_at.get().copied()
+    }
+}
+
+impl Drop for TransferStats {
+    fn drop(&mut self) {
+        if let Some(active_tunnel) = self.active_tunnel.get() {
+            active_tunnel.record_closed(self.sent(), self.received());
+        }
+    }
+}
+
+/// Count the bytes going through the local side of a tunnel
+#[pin_project]
+pub struct CountingStream<T> {
+    #[pin]
+    inner: T,
+    stats: Option<Arc<TransferStats>>,
+}
+
+impl<T> CountingStream<T> {
+    pub const fn new(inner: T, stats: Option<Arc<TransferStats>>) -> Self {
+        Self { inner, stats }
+    }
+
+    pub fn into_parts(self) -> (T, Option<Arc<TransferStats>>) {
+        (self.inner, self.stats)
+    }
+}
+
+impl<T: ClientAddr> ClientAddr for CountingStream<T> {
+    fn client_addr(&self) -> Option<SocketAddr> {
+        self.inner.client_addr()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for CountingStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let ret = this.inner.poll_read(cx, buf);
+        if let Some(stats) = this.stats {
+            let len = buf.filled().len() - before;
+            if len > 0 {
+                stats.first_sent_at.get_or_init(Instant::now);
+                stats.sent.fetch_add(len as u64, Ordering::Relaxed);
+            }
+        }
+        ret
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for CountingStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let ret = this.inner.poll_write(cx, buf);
+        if let (Some(stats), Poll::Ready(Ok(len))) = (this.stats, &ret) {
+            if *len > 0 {
+                stats.first_received_at.get_or_init(Instant::now);
+            }
+            stats.received.fetch_add(*len as u64, Ordering::Relaxed);
+        }
+        ret
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_counting_stream() {
+        let stats = Arc::new(TransferStats::default());
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut stream = CountingStream::new(client, Some(stats.clone()));
+
+        server.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(b"hello world").await.unwrap();
+
+        assert_eq!(stats.sent.load(Ordering::Relaxed), 5);
+        assert_eq!(stats.received.load(Ordering::Relaxed), 11);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::client_tunnels::{parse_forward, ClientTunnels, TunnelOrigin};
+use crate::protocols::unix_sock::UnixListenerStream;
+use crate::tunnel::active_tunnels::ActiveTunnels;
+use crate::tunnel::admin::{exec_common_command, serve_admin_commands};
+use serde_json::json;
+use std::sync::Arc;
+
+/// Serve the admin commands of the client, one command per line, each answered by a json line.
+/// forwards   => the -L/-R tunnels listening, started from the arguments, the --config file or the admin commands
+/// add -L ARG => start a tunnel, with the syntax of the arguments (i.e: add -L tcp://1212:google.com:443). Same with -R
+/// remove ID  => stop listening for the tunnel with this id, as listed by 'forwards'. Its open tunnels are left to drain
+/// close ID   => close the open tunnel with this id, as listed by 'tunnels'
+/// reload     => reload the --config file, as on SIGHUP
+/// status     => the state of the connection to the server and of each -L/-R tunnel: its listener, connections,
+///               errors, reconnections and last error. Stable json schema, for the scripts and GUIs monitoring it
+/// tunnels    => the tunnels currently open, with their source, destination, age and traffic
+/// stats      => the number of tunnels open, opened, failed to open and of reverse tunnels reconnected since the start,
+///               with the first byte latency and throughput histograms of each -L/-R tunnel
+/// log-level [FILTER] => the current log filter, or replace it without restarting (i.e: info,wstunnel::protocols::udp=trace)
+pub(crate) async fn run_admin_server(listener: UnixListenerStream, tunnels: Arc<ClientTunnels>) {
+    serve_admin_commands(listener, move |command: String| {
+        let tunnels = tunnels.clone();
+        async move { exec_command(&tunnels, &command).await }
+    })
+    .await
+}
+
+async fn exec_command(tunnels: &ClientTunnels, command: &str) -> serde_json::Value {
+    let command = command.trim();
+    let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+    match (name, args.trim()) {
+        ("forwards", "") => json!(tunnels.list().await),
+        ("status", "") => json!(tunnels.status().await),
+        ("add", forward) if !forward.is_empty() => match parse_forward(forward) {
+            Ok((id, tunnel)) => match tunnels.start(id.clone(), tunnel, TunnelOrigin::Admin).await {
+                Ok(_) => json!({ "added": id }),
+                Err(err) => json!({ "error": format!("{:#}", err) }),
+            },
+            Err(err) => json!({ "error": format!("{:#}", err) }),
+        },
+        ("remove", id) if !id.is_empty() => match tunnels.stop(id).await {
+            true => json!({ "removed": id }),
+            false => json!({ "error": format!("No tunnel with id {}", id) }),
+        },
+        ("reload", "") => match tunnels.reload_config() {
+            true => json!({ "reloading": true }),
+            false => json!({ "error": "The client has no --config file to reload" }),
+        },
+        _ => exec_tunnel_command(command, tunnels.active_tunnels()).unwrap_or_else(|| {
+            json!({
+                "error": "Unknown command, expected 'forwards', 'add -L|-R ARG', 'remove ID', 'close ID', 'reload', 'status', 'tunnels', 'stats', 'dump' or 'log-level [FILTER]'"
+            })
+        }),
+    }
+}
+
+/// The commands about the open tunnels, None if the command is not one of them
+fn exec_tunnel_command(command: &str, tunnels: &ActiveTunnels) -> Option<serde_json::Value> {
+    let mut args = command.split_whitespace();
+    match (args.next(), args.next(), args.next()) {
+        (Some("close"), Some(id), None) => match tunnels.close(id) {
+            true => Some(json!({ "closed": id })),
+            false => Some(json!({ "error": format!("No open tunnel with id {}", id) })),
+        },
+        _ => exec_common_command(command, tunnels),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("tunnels" => true ; "tunnels")]
+    #[test_case("stats" => true ; "stats")]
+    #[test_case("dump" => true ; "dump")]
+    #[test_case("close 0190b2a5-0000-7000-8000-000000000000" => false ; "close unknown tunnel")]
+    #[test_case("list" => false ; "unknown command")]
+    fn test_exec_tunnel_command(command: &str) -> bool {
+        exec_tunnel_command(command, &ActiveTunnels::default()).is_some_and(|response| response.get("error").is_none())
+    }
+}
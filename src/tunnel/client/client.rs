@@ -1,22 +1,46 @@
+use crate::config::TunnelOverrides;
+use crate::health::HEALTH;
+use crate::protocols::packet::PacketDevice;
+use crate::state_dump::INTERNAL_STATE;
 use crate::tunnel;
-use crate::tunnel::client::cnx_pool::WsConnection;
+use crate::tunnel::active_tunnels::{ActiveTunnel, FailureReason};
+use crate::tunnel::bandwidth::{BandwidthLimit, ThrottledStream};
+use crate::tunnel::capture::{CaptureFlow, CaptureStream};
+use crate::tunnel::client::access_log::{AccessLog, CountingStream, TransferStats};
+use crate::tunnel::client::cnx_pool::{LastConnectError, WsConnection};
+use crate::tunnel::client::failover::ServerFailover;
+use crate::tunnel::client::l4_transport_stream::TransportStream;
+use crate::tunnel::client::reconnect::Backoff;
+use crate::tunnel::client::reverse_hook::on_reverse_accept;
 use crate::tunnel::client::WsClientConfig;
 use crate::tunnel::connectors::TunnelConnector;
-use crate::tunnel::listeners::TunnelListener;
+use crate::tunnel::events::TunnelEvent;
+use crate::tunnel::interceptor::{intercept, InterceptedRead, InterceptedWrite, StreamInfo, StreamInterceptor};
+use crate::tunnel::listeners::{ClientAddr, TunnelListener};
+use crate::tunnel::server::protocol_name;
 use crate::tunnel::tls_reloader::TlsReloader;
 use crate::tunnel::transport::io::{TunnelReader, TunnelWriter};
-use crate::tunnel::transport::{jwt_token_to_tunnel, TransportScheme};
-use crate::tunnel::RemoteAddr;
-use anyhow::Context;
-use futures_util::pin_mut;
+use crate::tunnel::transport::{
+    jwt_token_to_tunnel, TransportScheme, BOUND_ADDR_HEADER, PEER_ADDR_HEADER, REVERSE_LISTENER_HEADER,
+};
+use crate::tunnel::tunnel_metrics::{TunnelMetrics, TUNNEL_METRICS};
+use crate::tunnel::{spawn_until_cancelled, RemoteAddr};
+use anyhow::{anyhow, Context};
+use futures_util::{future, pin_mut};
 use hyper::header::COOKIE;
+use hyper::http::response::Parts;
 use log::debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::oneshot;
+use tokio::select;
+use tokio::sync::{oneshot, Notify};
 use tokio_stream::StreamExt;
-use tracing::{error, event, span, Instrument, Level, Span};
+use tracing::{error, event, info, span, Instrument, Level, Span};
 use url::Host;
 use uuid::Uuid;
 
@@ -24,7 +48,21 @@ use uuid::Uuid;
 pub struct WsClient {
     pub config: Arc<WsClientConfig>,
     pub cnx_pool: bb8::Pool<WsConnection>,
+    pub(crate) last_connect_error: LastConnectError,
     _tls_reloader: Arc<TlsReloader>,
+    /// Histograms of the connections of the configured tunnel served by this client
+    tunnel_metrics: Option<Arc<TunnelMetrics>>,
+    /// With --failover-server, the connections go to the active server instead, or to the one the tunnel is pinned to
+    failover: Option<Arc<ServerFailover>>,
+    pinned_server: Option<usize>,
+    /// Overrides of the tunnel, see TunnelOverrides
+    connection_timeout: Option<Duration>,
+    bandwidth: Option<Arc<BandwidthLimit>>,
+    interceptors: Vec<Arc<dyn StreamInterceptor>>,
+    /// Websocket pings of the tunnel, i.e: --stdio-keepalive
+    ping_frequency: Option<Duration>,
+    /// Notified when one of its reverse tunnels gives up reconnecting with --reconnect-give-up exit
+    gave_up: Arc<Notify>,
 }
 
 impl WsClient {
@@ -34,7 +72,8 @@ impl WsClient {
         connection_retry_max_backoff_sec: Duration,
     ) -> anyhow::Result<Self> {
         let config = Arc::new(config);
-        let cnx = WsConnection::new(config.clone());
+        let last_connect_error = LastConnectError::default();
+        let cnx = WsConnection::new(config.clone(), last_connect_error.clone());
         let tls_reloader = TlsReloader::new_for_client(config.clone()).with_context(|| "Cannot create tls reloader")?;
         let cnx_pool = bb8::Pool::builder()
             .max_size(1000)
@@ -48,56 +87,261 @@ impl WsClient {
         Ok(Self {
             config,
             cnx_pool,
+            last_connect_error,
             _tls_reloader: Arc::new(tls_reloader),
+            tunnel_metrics: None,
+            failover: None,
+            pinned_server: None,
+            connection_timeout: None,
+            bandwidth: None,
+            interceptors: Vec::new(),
+            ping_frequency: None,
+            gave_up: Arc::new(Notify::new()),
         })
     }
+
+    pub fn with_failover(mut self, failover: Arc<ServerFailover>) -> Self {
+        self.failover = Some(failover);
+        self
+    }
+
+    /// Apply the options given to this tunnel only: the server it is pinned to, 0 being the primary,
+    /// its connect timeout, its bandwidth limit and its interceptors
+    pub fn with_overrides(mut self, overrides: &TunnelOverrides) -> anyhow::Result<Self> {
+        if let Some(index) = overrides.server {
+            let servers = self.failover.as_ref().map_or(1, |failover| failover.server_count());
+            if index >= servers {
+                return Err(anyhow!(
+                    "Cannot pin tunnel to server {}, there are only {} servers with --failover-server",
+                    index,
+                    servers
+                ));
+            }
+            self.pinned_server = Some(index);
+        }
+        self.connection_timeout = overrides.connection_timeout;
+        self.bandwidth = overrides.max_bandwidth.map(|rate| Arc::new(BandwidthLimit::new(rate)));
+        self.interceptors = self.config.interceptors.select(&overrides.interceptors)?;
+        Ok(self)
+    }
+
+    /// Send the websocket pings of the tunnel at this frequency, instead of the one of the client
+    pub fn with_ping_frequency(mut self, frequency: Duration) -> Self {
+        self.ping_frequency = Some(frequency);
+        self
+    }
+
+    /// Timeout to establish the tunnel, or for reverse tunnels to connect to their local destination
+    pub fn timeout_connect(&self) -> Duration {
+        self.connection_timeout.unwrap_or_else(|| self.config.timeout_connect())
+    }
+
+    /// Client of the server the next connection of the tunnel goes to
+    fn server(&self) -> Self {
+        let Some(failover) = &self.failover else {
+            return self.clone();
+        };
+        let mut client = failover.server(self.pinned_server).clone();
+        client.tunnel_metrics = self.tunnel_metrics.clone();
+        client.connection_timeout = self.connection_timeout;
+        client.bandwidth = self.bandwidth.clone();
+        client.interceptors = self.interceptors.clone();
+        client.ping_frequency = self.ping_frequency;
+        client.gave_up = self.gave_up.clone();
+        client
+    }
+
+    /// Resolve once one of its reverse tunnels gave up reconnecting and the client must exit
+    pub(crate) async fn tunnel_gave_up(&self) {
+        self.gave_up.notified().await
+    }
+
+    /// Wrap the local streams of a connection of the tunnel with its interceptors
+    fn intercept(
+        &self,
+        request_id: Uuid,
+        remote: &RemoteAddr,
+        rx: impl AsyncRead + Send + 'static,
+        tx: impl AsyncWrite + Send + 'static,
+    ) -> (InterceptedRead, InterceptedWrite) {
+        let stream = StreamInfo {
+            id: request_id.to_string(),
+            protocol: protocol_name(&remote.protocol),
+            destination: format!("{}:{}", remote.host, remote.port),
+            restriction: None,
+        };
+        intercept(&self.interceptors, &stream, Box::pin(rx), Box::pin(tx))
+    }
+
+    /// Record the latency and throughput of the connections of the configured tunnel with this name
+    pub fn with_tunnel_metrics(mut self, name: String) -> Self {
+        self.tunnel_metrics = Some(TUNNEL_METRICS.register(name));
+        self
+    }
+
+    /// Open a connection to the server now and leave it in the pool for the first tunnel, to fail at startup
+    /// when the server cannot be reached
+    pub async fn connect_now(&self) -> anyhow::Result<()> {
+        let client = self.server();
+        let cnx = client
+            .cnx_pool
+            .dedicated_connection()
+            .await
+            .with_context(|| format!("Cannot connect to the server {:?}", client.config.remote_addr))?;
+        // The pool is already full with --connection-min-idle, the connection is then not needed
+        let _ = client.cnx_pool.add(cnx);
+        Ok(())
+    }
+
+    pub(crate) fn tunnel_metrics(&self) -> Option<&Arc<TunnelMetrics>> {
+        self.tunnel_metrics.as_ref()
+    }
 }
 
 impl WsClient {
-    async fn connect_to_server<R, W>(
+    /// Connection to the server for a new tunnel, from the pool. The reverse tunnels make a single attempt instead of
+    /// waiting for the pool to reconnect, they retry on their own following the --reconnect-* options
+    pub(crate) async fn server_connection(&self, dest_addr: &RemoteAddr) -> anyhow::Result<TransportStream> {
+        let cnx = if dest_addr.protocol.is_reverse_tunnel() {
+            self.cnx_pool
+                .dedicated_connection()
+                .await
+                .context("failed to connect to the server")?
+        } else {
+            self.cnx_pool
+                .get()
+                .await
+                .map_err(|err| self.last_connect_error.pool_error(err))?
+                .take()
+        };
+        cnx.ok_or_else(|| anyhow!("connection to the server is already used"))
+    }
+
+    /// Open a tunnel to the server with the transport of the client or the protocol of its url, and keep track of the result for the health endpoints
+    async fn connect_transport(
+        &self,
+        request_id: Uuid,
+        remote_cfg: &RemoteAddr,
+    ) -> anyhow::Result<(TunnelReader, TunnelWriter, Parts)> {
+        let transport = match (&self.config.transport, self.config.remote_addr.scheme()) {
+            (Some(transport), _) => {
+                tunnel::transport::pluggable::connect(request_id, self, remote_cfg, transport.as_ref()).await
+            }
+            (None, TransportScheme::Ws | TransportScheme::Wss) => {
+                tunnel::transport::websocket::connect(request_id, self, remote_cfg)
+                    .await
+                    .map(|(r, w, response)| (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response))
+            }
+            (None, TransportScheme::Http | TransportScheme::Https) => {
+                tunnel::transport::http2::connect(request_id, self, remote_cfg)
+                    .await
+                    .map(|(r, w, response)| (TunnelReader::Http2(r), TunnelWriter::Http2(w), response))
+            }
+        };
+        let server = format!("{:?}", self.config.remote_addr);
+        match &transport {
+            Ok(_) => {
+                HEALTH.transport_connected();
+                self.config.events.send(TunnelEvent::TransportConnected { server });
+            }
+            Err(err) => {
+                HEALTH.transport_failed(err);
+                self.config.events.send(TunnelEvent::TransportFailed {
+                    server,
+                    error: err.to_string(),
+                });
+            }
+        }
+
+        transport
+    }
+
+    async fn connect_to_server<R, W, F, Fut>(
         &self,
         request_id: Uuid,
         remote_cfg: &RemoteAddr,
         duplex_stream: (R, W),
+        on_established: &F,
+        stats: Option<Arc<TransferStats>>,
+        capture: Option<Arc<CaptureFlow>>,
     ) -> anyhow::Result<()>
     where
         R: AsyncRead + Send + 'static,
         W: AsyncWrite + Send + 'static,
+        F: Fn(W, Result<Option<SocketAddr>, FailureReason>) -> Fut,
+        Fut: Future<Output = std::io::Result<W>>,
     {
-        // Connect to server with the correct protocol
-        let (ws_rx, ws_tx, response) = match self.config.remote_addr.scheme() {
-            TransportScheme::Ws | TransportScheme::Wss => {
-                tunnel::transport::websocket::connect(request_id, self, remote_cfg)
-                    .await
-                    .map(|(r, w, response)| (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response))?
-            }
-            TransportScheme::Http | TransportScheme::Https => {
-                tunnel::transport::http2::connect(request_id, self, remote_cfg)
-                    .await
-                    .map(|(r, w, response)| (TunnelReader::Http2(r), TunnelWriter::Http2(w), response))?
+        let transport = self.connect_transport(request_id, remote_cfg);
+        let transport = match self.connection_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, transport)
+                .await
+                .with_context(|| format!("Cannot establish the tunnel within {:?}", timeout))
+                .and_then(|transport| transport),
+            None => transport.await,
+        };
+        let (local_rx, local_tx) = duplex_stream;
+        let (ws_rx, ws_tx, response) = match transport {
+            Ok(transport) => transport,
+            Err(err) => {
+                // The local side is told why, i.e: socks5 clients get a failure reply instead of waiting
+                let _ = on_established(local_tx, Err(FailureReason::classify(&err))).await;
+                return Err(err);
             }
         };
 
         debug!("Server response: {:?}", response);
-        let (local_rx, local_tx) = duplex_stream;
+        let bound_addr = response
+            .headers
+            .get(BOUND_ADDR_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<SocketAddr>().ok());
+        let local_tx = on_established(local_tx, Ok(bound_addr)).await?;
+        let (local_rx, local_tx) = self.intercept(request_id, remote_cfg, local_rx, local_tx);
+        let local_rx = CaptureStream::new(CountingStream::new(local_rx, stats.clone()), capture.clone());
+        let local_tx = CaptureStream::new(CountingStream::new(local_tx, stats), capture);
+        let local_rx = ThrottledStream::new(local_rx, self.bandwidth.clone());
+        let local_tx = ThrottledStream::new(local_tx, self.bandwidth.clone());
         let (close_tx, close_rx) = oneshot::channel::<()>();
 
         // Forward local tx to websocket tx
-        let ping_frequency = self.config.websocket_ping_frequency;
-        tokio::spawn(
-            super::super::transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, ping_frequency)
-                .instrument(Span::current()),
+        let ping_frequency = self.ping_frequency.or_else(|| self.config.websocket_ping_frequency());
+        let slow_consumer_timeout = self.config.slow_consumer_timeout();
+        spawn_until_cancelled(
+            &self.config.cancel,
+            super::super::transport::io::propagate_local_to_remote(
+                local_rx,
+                ws_tx,
+                close_tx,
+                ping_frequency,
+                slow_consumer_timeout.clone(),
+            )
+            .instrument(Span::current()),
         );
 
         // Forward websocket rx to local rx
-        let _ = super::super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).await;
+        let _ =
+            super::super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx, slow_consumer_timeout)
+                .await;
 
         Ok(())
     }
 
     pub async fn run_tunnel(self, tunnel_listener: impl TunnelListener) -> anyhow::Result<()> {
+        self.run_tunnel_with_hook(tunnel_listener, |local_tx, _| future::ready(Ok(local_tx)))
+            .await
+    }
+
+    /// Same as run_tunnel, but `on_established` is called with the address used by the server to connect to
+    /// the destination, once the tunnel is established and before any data is forwarded (i.e: to reply to socks5 clients).
+    /// It is called with the reason of the failure instead when the tunnel cannot be opened
+    pub async fn run_tunnel_with_hook<L, F, Fut>(self, tunnel_listener: L, on_established: F) -> anyhow::Result<()>
+    where
+        L: TunnelListener,
+        F: Fn(L::Writer, Result<Option<SocketAddr>, FailureReason>) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<L::Writer>> + Send,
+    {
         pin_mut!(tunnel_listener);
-        while let Some(cnx) = tunnel_listener.next().await {
+        while let Some(cnx) = self.next_connection(&mut tunnel_listener).await {
             let (cnx_stream, remote_addr) = match cnx {
                 Ok((cnx_stream, remote_addr)) => (cnx_stream, remote_addr),
                 Err(err) => {
@@ -106,74 +350,296 @@ impl WsClient {
                 }
             };
 
+            self.spawn_tunnel(cnx_stream, remote_addr, on_established.clone(), None);
+        }
+
+        Ok(())
+    }
+
+    /// Same as run_tunnel_with_hook, with a line written to the access log for each connection once it is closed
+    pub async fn run_tunnel_with_access_log<L, F, Fut>(
+        self,
+        tunnel_listener: L,
+        on_established: F,
+        access_log: AccessLog,
+    ) -> anyhow::Result<()>
+    where
+        L: TunnelListener,
+        L::Reader: ClientAddr,
+        F: Fn(L::Writer, Result<Option<SocketAddr>, FailureReason>) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<L::Writer>> + Send,
+    {
+        pin_mut!(tunnel_listener);
+        while let Some(cnx) = self.next_connection(&mut tunnel_listener).await {
+            let (cnx_stream, remote_addr) = match cnx {
+                Ok((cnx_stream, remote_addr)) => (cnx_stream, remote_addr),
+                Err(err) => {
+                    error!("Error accepting connection: {:?}", err);
+                    continue;
+                }
+            };
+
+            let client_addr = cnx_stream.0.client_addr();
+            self.spawn_tunnel(
+                cnx_stream,
+                remote_addr,
+                on_established.clone(),
+                Some((access_log.clone(), client_addr)),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Next connection accepted by the listener, None once the client stops
+    async fn next_connection<L: TunnelListener>(
+        &self,
+        tunnel_listener: &mut Pin<&mut L>,
+    ) -> Option<anyhow::Result<((L::Reader, L::Writer), RemoteAddr)>> {
+        self.config
+            .cancel
+            .run_until_cancelled(tunnel_listener.next())
+            .await
+            .flatten()
+    }
+
+    fn spawn_tunnel<R, W, F, Fut>(
+        &self,
+        cnx_stream: (R, W),
+        remote_addr: RemoteAddr,
+        on_established: F,
+        access_log: Option<(AccessLog, Option<SocketAddr>)>,
+    ) where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+        F: Fn(W, Result<Option<SocketAddr>, FailureReason>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<W>> + Send,
+    {
+        let request_id = Uuid::now_v7();
+        let span = span!(
+            Level::INFO,
+            "tunnel",
+            connection_id = request_id.to_string(),
+            destination = format!("{}:{}", remote_addr.host, remote_addr.port)
+        );
+        let client = self.server();
+        let tunnel = async move {
+            let started_at = Instant::now();
+            let stats = Arc::new(TransferStats::default());
+            let close = Arc::new(Notify::new());
+            let source = access_log.as_ref().and_then(|(_, client_addr)| *client_addr);
+            client.config.instance.active_tunnels.register(
+                ActiveTunnel {
+                    id: request_id.to_string(),
+                    source,
+                    destination: format!("{}:{}", remote_addr.host, remote_addr.port),
+                    protocol: protocol_name(&remote_addr.protocol),
+                    identity: None,
+                    started_at,
+                    close: Some(close.clone()),
+                },
+                &stats,
+            );
+            let tunnel = client.connect_to_server(
+                request_id,
+                &remote_addr,
+                cnx_stream,
+                &on_established,
+                Some(stats.clone()),
+                client
+                    .config
+                    .capture
+                    .as_ref()
+                    .map(|capture| CaptureFlow::new(capture, source, &remote_addr, true)),
+            );
+            let ret = select! {
+                ret = tunnel => ret,
+                _ = close.notified() => {
+                    info!("Tunnel closed by the admin command");
+                    Ok(())
+                }
+                _ = client.config.cancel.cancelled() => {
+                    info!("Tunnel closed as the client stops");
+                    Ok(())
+                }
+            };
+            match (&ret, &client.tunnel_metrics) {
+                (Err(err), metrics) => {
+                    client
+                        .config
+                        .instance
+                        .active_tunnels
+                        .record_failure(FailureReason::classify(err));
+                    if let Some(metrics) = metrics {
+                        metrics.record_error(err);
+                    }
+                }
+                (Ok(()), Some(metrics)) => metrics.observe(&stats, started_at, stats.first_received_at()),
+                (Ok(()), None) => {}
+            }
+            if let Some((access_log, client_addr)) = access_log {
+                access_log.log(request_id, client_addr, &remote_addr, &stats, started_at.elapsed(), &ret);
+            }
+            let _ = ret.map_err(|err| error!("{:?}", err));
+        }
+        .instrument(span);
+
+        tokio::spawn(tunnel);
+    }
+
+    /// Exchange the packets of the device with the server, reconnecting with the policy of the reverse tunnels.
+    /// Stops with the client, or once the queue of the device is dropped
+    pub async fn run_packet_tunnel(self, remote_addr: RemoteAddr, device: PacketDevice) -> anyhow::Result<()> {
+        let cancel = self.config.cancel.clone();
+        cancel
+            .run_until_cancelled(self.packet_tunnel_loop(remote_addr, device))
+            .await
+            .unwrap_or(Ok(()))
+    }
+
+    async fn packet_tunnel_loop(self, remote_addr: RemoteAddr, device: PacketDevice) -> anyhow::Result<()> {
+        let mut backoff = Backoff::new(self.config.reconnect.clone());
+        while !device.is_closed() {
             let request_id = Uuid::now_v7();
             let span = span!(
                 Level::INFO,
                 "tunnel",
-                id = request_id.to_string(),
-                remote = format!("{}:{}", remote_addr.host, remote_addr.port)
+                connection_id = request_id.to_string(),
+                destination = format!("tun://{}", remote_addr.host)
             );
-            let client = self.clone();
-            let tunnel = async move {
-                let _ = client
-                    .connect_to_server(request_id, &remote_addr, cnx_stream)
-                    .await
-                    .map_err(|err| error!("{:?}", err));
+            let established = AtomicBool::new(false);
+            let on_established = |local_tx, result: Result<_, _>| {
+                established.store(result.is_ok(), Ordering::Relaxed);
+                future::ready(Ok(local_tx))
+            };
+            let streams = device.streams().await;
+            let ret = self
+                .connect_to_server(request_id, &remote_addr, streams, &on_established, None, None)
+                .instrument(span.clone())
+                .await;
+            if let Err(err) = &ret {
+                if let Some(metrics) = &self.tunnel_metrics {
+                    metrics.record_error(err);
+                }
             }
-            .instrument(span);
 
-            tokio::spawn(tunnel);
+            // A tunnel closed after being established reconnects right away, as the interface is still up
+            if established.load(Ordering::Relaxed) {
+                event!(parent: &span, Level::INFO, "Tun tunnel closed, reconnecting");
+                backoff = Backoff::new(self.config.reconnect.clone());
+                continue;
+            }
+            let Some(delay) = backoff.next_delay() else {
+                backoff.give_up(&self.gave_up);
+                return Err(anyhow!(
+                    "Tun tunnel {} gives up after {} attempts to connect",
+                    remote_addr.host,
+                    backoff.attempts()
+                ));
+            };
+            event!(parent: &span, Level::ERROR, "Retrying in {:?}, cannot connect to remote server: {:?}", delay, ret);
+            tokio::time::sleep(delay).await;
         }
 
         Ok(())
     }
 
+    /// Keep a connection waiting on the reverse listener of the server until the client stops
     pub async fn run_reverse_tunnel(
         self,
         remote_addr: RemoteAddr,
         connector: impl TunnelConnector,
     ) -> anyhow::Result<()> {
+        let cancel = self.config.cancel.clone();
+        cancel
+            .run_until_cancelled(self.reverse_tunnel_loop(remote_addr, connector))
+            .await
+            .unwrap_or(Ok(()))
+    }
+
+    async fn reverse_tunnel_loop(
+        self,
+        mut remote_addr: RemoteAddr,
+        connector: impl TunnelConnector,
+    ) -> anyhow::Result<()> {
+        let mut backoff = Backoff::new(self.config.reconnect.clone());
+        let mut disconnected_since: Option<Instant> = None;
+        let reconnect_id = INTERNAL_STATE.new_reconnect_id();
         loop {
-            let client = self.clone();
+            let client = self.server();
             let request_id = Uuid::now_v7();
             let span = span!(
                 Level::INFO,
                 "tunnel",
-                id = request_id.to_string(),
-                remote = format!("{}:{}", remote_addr.host, remote_addr.port)
+                connection_id = request_id.to_string(),
+                destination = format!("{}:{}", remote_addr.host, remote_addr.port)
             );
-            // Correctly configure tunnel cfg
-            let (ws_rx, ws_tx, response) = match client.config.remote_addr.scheme() {
-                TransportScheme::Ws | TransportScheme::Wss => {
-                    match tunnel::transport::websocket::connect(request_id, &client, &remote_addr)
-                        .instrument(span.clone())
-                        .await
-                    {
-                        Ok((r, w, response)) => (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response),
-                        Err(err) => {
-                            event!(parent: &span, Level::ERROR, "Retrying in 1sec, cannot connect to remote server: {:?}", err);
-                            tokio::time::sleep(Duration::from_secs(1)).await;
-                            continue;
-                        }
-                    }
-                }
-                TransportScheme::Http | TransportScheme::Https => {
-                    match tunnel::transport::http2::connect(request_id, &client, &remote_addr)
-                        .instrument(span.clone())
-                        .await
-                    {
-                        Ok((r, w, response)) => (TunnelReader::Http2(r), TunnelWriter::Http2(w), response),
-                        Err(err) => {
-                            event!(parent: &span, Level::ERROR, "Retrying in 1sec, cannot connect to remote server: {:?}", err);
-                            tokio::time::sleep(Duration::from_secs(1)).await;
-                            continue;
-                        }
+            let transport = client
+                .connect_transport(request_id, &remote_addr)
+                .instrument(span.clone())
+                .await;
+            let (ws_rx, ws_tx, response) = match transport {
+                Ok(transport) => transport,
+                Err(err) => {
+                    // The server drops the reverse listener once it stops receiving our requests,
+                    // so keep retrying until it is registered again, without hammering the server.
+                    let since = *disconnected_since.get_or_insert_with(Instant::now);
+                    let destination = format!("{}:{}", remote_addr.host, remote_addr.port);
+                    let Some(delay) = backoff.next_delay() else {
+                        INTERNAL_STATE.reconnect_done(reconnect_id);
+                        backoff.give_up(&self.gave_up);
+                        return Err(err.context(format!(
+                            "Reverse tunnel {} gives up after {} attempts to reconnect",
+                            destination,
+                            backoff.attempts()
+                        )));
+                    };
+                    if let Some(metrics) = &client.tunnel_metrics {
+                        metrics.set_last_error(&err);
                     }
+                    INTERNAL_STATE.reconnect_pending(reconnect_id, destination, since, delay);
+                    event!(parent: &span, Level::ERROR, "Retrying in {:?}, cannot connect to remote server: {:?}", delay, err);
+                    tokio::time::sleep(delay).await;
+                    continue;
                 }
             };
+            if let Some(since) = disconnected_since.take() {
+                client.config.instance.active_tunnels.record_reconnect();
+                if let Some(metrics) = &client.tunnel_metrics {
+                    metrics.record_reconnect();
+                }
+                INTERNAL_STATE.reconnect_done(reconnect_id);
+                event!(parent: &span, Level::INFO, "Reverse tunnel re-established after {:?} of downtime", since.elapsed());
+            }
+            backoff.connected();
+            // The server answers once a connection is accepted on the reverse listener
+            let started_at = Instant::now();
 
             // Connect to endpoint
             event!(parent: &span, Level::DEBUG, "Server response: {:?}", response);
+            if let Some(listener) = response
+                .headers
+                .get(REVERSE_LISTENER_HEADER)
+                .and_then(|h| h.to_str().ok())
+            {
+                event!(parent: &span, Level::INFO, "Server allocated a dedicated reverse listener on {}", listener);
+
+                // We asked for any free port, the server only answered with the one it has chosen.
+                // Keep using it for the next requests, so the port stays the same across reconnections
+                if remote_addr.port == 0 {
+                    match listener.parse::<SocketAddr>() {
+                        Ok(addr) => {
+                            event!(parent: &span, Level::INFO, "Allocated port {} for remote forward", addr.port());
+                            remote_addr.port = addr.port();
+                        }
+                        Err(err) => {
+                            event!(parent: &span, Level::ERROR, "Invalid reverse listener address {}: {:?}", listener, err);
+                            tokio::time::sleep(client.config.reconnect.initial_delay).await;
+                        }
+                    }
+                    continue;
+                }
+            }
             let remote = response
                 .headers
                 .get(COOKIE)
@@ -185,27 +651,74 @@ impl WsClient {
                     port: jwt.claims.rp,
                 });
 
+            let peer_addr = response
+                .headers
+                .get(PEER_ADDR_HEADER)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.parse::<SocketAddr>().ok());
+            let hook = client.config.reverse_accept_hook.as_deref();
+            if !on_reverse_accept(hook, &remote_addr, peer_addr, remote.as_ref())
+                .instrument(span.clone())
+                .await
+            {
+                // Dropping the transport closes the connection accepted by the server
+                continue;
+            }
+
             let (local_rx, local_tx) = match connector.connect(&remote).instrument(span.clone()).await {
                 Ok(s) => s,
                 Err(err) => {
                     event!(parent: &span, Level::ERROR, "Cannot connect to {remote:?}: {err:?}");
+                    if let Some(metrics) = &client.tunnel_metrics {
+                        metrics.record_error(&err);
+                    }
                     continue;
                 }
             };
 
+            let destination = remote.as_ref().unwrap_or(&remote_addr);
+            let (local_rx, local_tx) = client.intercept(request_id, destination, local_rx, local_tx);
+            let stats = Arc::new(TransferStats::default());
+            let capture = client
+                .config
+                .capture
+                .as_ref()
+                .map(|capture| CaptureFlow::new(capture, peer_addr, destination, false));
+            let local_rx = CaptureStream::new(CountingStream::new(local_rx, Some(stats.clone())), capture.clone());
+            let local_tx = CaptureStream::new(CountingStream::new(local_tx, Some(stats.clone())), capture);
+            let local_rx = ThrottledStream::new(local_rx, client.bandwidth.clone());
+            let local_tx = ThrottledStream::new(local_tx, client.bandwidth.clone());
             let (close_tx, close_rx) = oneshot::channel::<()>();
             let tunnel = async move {
-                let ping_frequency = client.config.websocket_ping_frequency;
-                tokio::spawn(
-                    super::super::transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, ping_frequency)
-                        .in_current_span(),
+                let ping_frequency = client.config.websocket_ping_frequency();
+                let slow_consumer_timeout = client.config.slow_consumer_timeout();
+                spawn_until_cancelled(
+                    &client.config.cancel,
+                    super::super::transport::io::propagate_local_to_remote(
+                        local_rx,
+                        ws_tx,
+                        close_tx,
+                        ping_frequency,
+                        slow_consumer_timeout.clone(),
+                    )
+                    .in_current_span(),
                 );
 
                 // Forward websocket rx to local rx
-                let _ = super::super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).await;
+                let _ = super::super::transport::io::propagate_remote_to_local(
+                    local_tx,
+                    ws_rx,
+                    close_rx,
+                    slow_consumer_timeout,
+                )
+                .await;
+                // The destination is on our side, it answers with the bytes read from it
+                if let Some(metrics) = &client.tunnel_metrics {
+                    metrics.observe(&stats, started_at, stats.first_sent_at());
+                }
             }
             .instrument(span.clone());
-            tokio::spawn(tunnel);
+            spawn_until_cancelled(&self.config.cancel, tunnel);
         }
     }
 }
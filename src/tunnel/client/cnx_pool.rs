@@ -1,19 +1,47 @@
 use crate::protocols;
 use crate::protocols::tls;
+use crate::tunnel::active_tunnels::{ClassifiedFailure, FailureReason};
 use crate::tunnel::client::l4_transport_stream::TransportStream;
 use crate::tunnel::client::WsClientConfig;
-use bb8::ManageConnection;
+use anyhow::anyhow;
+use bb8::{ManageConnection, RunError};
 use bytes::Bytes;
+use parking_lot::Mutex;
+use std::io;
 use std::ops::Deref;
 use std::sync::Arc;
-use tracing::instrument;
+use tracing::{info_span, instrument, Instrument};
+use url::Url;
+
+/// When the client opens its first connection to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum ConnectMode {
+    /// On the first tunneled connection
+    #[default]
+    Lazy,
+    /// At startup, the client exits if the server cannot be reached
+    Startup,
+    /// At startup, and a connection is then always kept open and ready for the next tunnel
+    Eager,
+}
+
+impl ConnectMode {
+    /// Idle connections the pool keeps open
+    pub fn min_idle(self, connection_min_idle: u32) -> u32 {
+        match self {
+            Self::Eager => connection_min_idle.max(1),
+            Self::Lazy | Self::Startup => connection_min_idle,
+        }
+    }
+}
 
 #[derive(Clone)]
-pub struct WsConnection(Arc<WsClientConfig>);
+pub struct WsConnection(Arc<WsClientConfig>, LastConnectError);
 
 impl WsConnection {
-    pub fn new(config: Arc<WsClientConfig>) -> Self {
-        Self(config)
+    pub fn new(config: Arc<WsClientConfig>, last_error: LastConnectError) -> Self {
+        Self(config, last_error)
     }
 }
 
@@ -29,11 +57,73 @@ impl ManageConnection for WsConnection {
     type Connection = Option<TransportStream>;
     type Error = anyhow::Error;
 
-    #[instrument(level = "trace", name = "cnx_server", skip_all)]
+    #[instrument(level = "info", name = "cnx_server", skip_all)]
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        let timeout = self.timeout_connect;
+        let ret = self.connect_to_server().await;
+        if let Err(err) = &ret {
+            self.1.set(err);
+        }
+        ret
+    }
+
+    async fn is_valid(&self, _conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        Ok(())
+    }
 
-        let tcp_stream = if let Some(http_proxy) = &self.http_proxy {
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        conn.is_none()
+    }
+}
+
+impl WsConnection {
+    async fn connect_to_server(&self) -> anyhow::Result<Option<TransportStream>> {
+        let Some(spa_knocker) = &self.spa_knocker else {
+            return self.connect_transport().await;
+        };
+        spa_knocker.knock(self.remote_addr.host(), &self.dns_resolver).await?;
+        let ret = self.connect_transport().await;
+        if ret.is_err() {
+            spa_knocker.forget();
+        }
+        ret
+    }
+
+    async fn connect_transport(&self) -> anyhow::Result<Option<TransportStream>> {
+        let timeout = self.timeout_connect();
+
+        let tcp_stream = if let Some(pac_file) = &self.pac_file {
+            let scheme = if self.remote_addr.tls().is_some() {
+                "https"
+            } else {
+                "http"
+            };
+            let target_url = Url::parse(&format!(
+                "{}://{}:{}/",
+                scheme,
+                self.remote_addr.host(),
+                self.remote_addr.port()
+            ))?;
+            protocols::pac::connect_with_pac(
+                pac_file,
+                &target_url,
+                self.remote_addr.host(),
+                self.remote_addr.port(),
+                self.socket_so_mark,
+                timeout,
+                &self.dns_resolver,
+            )
+            .await?
+        } else if !self.connection_via.is_empty() {
+            protocols::tcp::connect_with_proxy_chain(
+                &self.connection_via,
+                self.remote_addr.host(),
+                self.remote_addr.port(),
+                self.socket_so_mark,
+                timeout,
+                &self.dns_resolver,
+            )
+            .await?
+        } else if let Some(http_proxy) = &self.http_proxy {
             protocols::tcp::connect_with_http_proxy(
                 http_proxy,
                 self.remote_addr.host(),
@@ -55,18 +145,52 @@ impl ManageConnection for WsConnection {
         };
 
         if self.remote_addr.tls().is_some() {
-            let tls_stream = tls::connect(self, tcp_stream).await?;
+            let tls_stream = tls::connect(self, tcp_stream)
+                .instrument(info_span!("tls_handshake"))
+                .await?;
             Ok(Some(TransportStream::from_client_tls(tls_stream, Bytes::default())))
         } else {
             Ok(Some(TransportStream::from_tcp(tcp_stream, Bytes::default())))
         }
     }
+}
 
-    async fn is_valid(&self, _conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        Ok(())
+/// Failure of the last connection attempt to the server. The pool retries them until its timeout, and then only
+/// reports that it timed out
+#[derive(Clone, Default)]
+pub struct LastConnectError(Arc<Mutex<Option<ClassifiedFailure>>>);
+
+impl LastConnectError {
+    fn set(&self, err: &anyhow::Error) {
+        *self.0.lock() = Some(ClassifiedFailure {
+            reason: FailureReason::classify(err),
+            message: format!("{:#}", err),
+        });
     }
 
-    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.is_none()
+    /// Error of a connection taken from the pool, with the reason of the last failed attempt
+    pub fn pool_error(&self, err: RunError<anyhow::Error>) -> anyhow::Error {
+        let err = match err {
+            RunError::User(err) => err,
+            RunError::TimedOut => match self.0.lock().take() {
+                Some(failure) => anyhow!(failure),
+                None => anyhow!(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a connection")),
+            },
+        };
+        err.context("failed to get a connection to the server from the pool")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(ConnectMode::Lazy, 0 => 0 ; "lazy")]
+    #[test_case(ConnectMode::Startup, 0 => 0 ; "startup")]
+    #[test_case(ConnectMode::Eager, 0 => 1 ; "eager")]
+    #[test_case(ConnectMode::Eager, 4 => 4 ; "eager with min idle")]
+    fn test_min_idle(mode: ConnectMode, connection_min_idle: u32) -> u32 {
+        mode.min_idle(connection_min_idle)
     }
 }
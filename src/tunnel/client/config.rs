@@ -1,6 +1,19 @@
+use crate::config::parsers::{parse_bearer_token, parse_http_credentials};
+use crate::instance::InstanceState;
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::pac::PacFile;
+use crate::secret::resolve_secret;
 use crate::somark::SoMark;
-use crate::tunnel::transport::TransportAddr;
+use crate::tunnel::capture::CaptureFile;
+use crate::tunnel::client::config_file::ReloadableClientConfig;
+use crate::tunnel::client::{ReconnectPolicy, TotpCommand};
+use crate::tunnel::events::TunnelEvents;
+use crate::tunnel::interceptor::Interceptors;
+use crate::tunnel::spa::SpaKnocker;
+use crate::tunnel::transport::io::SlowConsumerTimeout;
+use crate::tunnel::transport::{current_hmac_path_prefix, TransportAddr, TunnelTransport};
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
 use hyper::header::{HeaderName, HeaderValue};
 use parking_lot::RwLock;
 use std::collections::HashMap;
@@ -10,25 +23,87 @@ use std::sync::{Arc, LazyLock};
 use std::time::Duration;
 use tokio_rustls::rustls::pki_types::{DnsName, ServerName};
 use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
 use url::{Host, Url};
 
 #[derive(Clone)]
 pub struct WsClientConfig {
     pub remote_addr: TransportAddr,
     pub socket_so_mark: SoMark,
-    pub http_upgrade_path_prefix: String,
-    pub http_upgrade_credentials: Option<HeaderValue>,
-    pub http_headers: HashMap<HeaderName, HeaderValue>,
+    pub upgrade_secrets: Arc<UpgradeSecrets>,
+    pub http_upgrade_totp_command: Option<TotpCommand>,
     pub http_headers_file: Option<PathBuf>,
     pub http_header_host: HeaderValue,
     pub timeout_connect: Duration,
     pub websocket_ping_frequency: Option<Duration>,
+    /// Report the sides of the tunnels not accepting writes for this long
+    pub slow_consumer_timeout: Option<Duration>,
     pub websocket_mask_frame: bool,
     pub http_proxy: Option<Url>,
+    pub connection_via: Vec<Url>,
+    pub pac_file: Option<Arc<PacFile>>,
+    pub reverse_accept_hook: Option<String>,
+    /// Knocks on the server before connecting to it, when it requires single packet authorization
+    pub spa_knocker: Option<Arc<SpaKnocker>>,
+    /// Of the reverse tunnels, the forward ones only retry for --connection-retry-max-backoff
+    pub reconnect: ReconnectPolicy,
     pub dns_resolver: DnsResolver,
+    /// Headers and timeouts of the --config file, replaced when it is reloaded
+    pub reloadable: Arc<ArcSwap<ReloadableClientConfig>>,
+    /// Of a third party, instead of websocket or http2
+    pub transport: Option<Arc<dyn TunnelTransport>>,
+    /// Enabled by the ?interceptor option of the tunnels
+    pub interceptors: Interceptors,
+    /// Cancelled when the client stops, with its listeners, reconnect loops and tunnels
+    pub cancel: CancellationToken,
+    pub events: TunnelEvents,
+    /// Open tunnels and status of the client, shared with the program running it
+    pub instance: InstanceState,
+    /// Written with the plaintext of the tunnels, with --capture-file
+    pub capture: Option<Arc<CaptureFile>>,
 }
 
 impl WsClientConfig {
+    pub(super) fn slow_consumer_timeout(&self) -> Option<SlowConsumerTimeout> {
+        self.slow_consumer_timeout.map(|timeout| SlowConsumerTimeout {
+            timeout,
+            tunnels: self.instance.active_tunnels.clone(),
+        })
+    }
+
+    /// Path prefix of the upgrade request, derived from the secret when the server requires a time-limited one
+    pub fn upgrade_path_prefix(&self) -> String {
+        let secrets = self.upgrade_secrets.resolved.load();
+        match &secrets.path_prefix_hmac_secret {
+            Some(secret) => current_hmac_path_prefix(secret),
+            None => secrets.path_prefix.clone(),
+        }
+    }
+
+    /// Authorization header of the upgrade request, with the basic credentials or the bearer token
+    pub fn http_upgrade_credentials(&self) -> Option<HeaderValue> {
+        self.upgrade_secrets.resolved.load().authorization.clone()
+    }
+
+    /// The headers of the -H arguments, overridden by the ones of the --config file
+    pub fn http_headers(&self) -> HashMap<HeaderName, HeaderValue> {
+        let mut headers = self.upgrade_secrets.resolved.load().http_headers.clone();
+        headers.extend(self.reloadable.load().http_headers.clone());
+        headers
+    }
+
+    pub fn timeout_connect(&self) -> Duration {
+        self.reloadable.load().timeout_connect.unwrap_or(self.timeout_connect)
+    }
+
+    /// A frequency of 0 in the --config file disables the pings
+    pub fn websocket_ping_frequency(&self) -> Option<Duration> {
+        match self.reloadable.load().websocket_ping_frequency {
+            Some(frequency) => Some(frequency).filter(|frequency| !frequency.is_zero()),
+            None => self.websocket_ping_frequency,
+        }
+    }
+
     pub fn tls_server_name(&self) -> ServerName<'static> {
         static INVALID_DNS_NAME: LazyLock<DnsName> =
             LazyLock::new(|| DnsName::try_from("dns-name-invalid.com").unwrap());
@@ -49,6 +124,85 @@ impl WsClientConfig {
     }
 }
 
+/// Secrets of the upgrade request as given in the arguments, each of them can be an env:VAR or file:PATH reference
+#[derive(Debug, Clone, Default)]
+pub struct UpgradeSecretArgs {
+    pub path_prefix: String,
+    pub path_prefix_hmac_secret: Option<String>,
+    /// USER[:PASS], sent as basic authentication
+    pub credentials: Option<String>,
+    pub bearer_token: Option<String>,
+    pub http_headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+#[derive(Default)]
+struct ResolvedUpgradeSecrets {
+    path_prefix: String,
+    path_prefix_hmac_secret: Option<String>,
+    authorization: Option<HeaderValue>,
+    http_headers: HashMap<HeaderName, HeaderValue>,
+}
+
+/// Resolved when created, and again when the --config file of the client is reloaded, so the secrets read from
+/// files can be rotated without restarting the client
+pub struct UpgradeSecrets {
+    args: UpgradeSecretArgs,
+    resolved: ArcSwap<ResolvedUpgradeSecrets>,
+}
+
+impl UpgradeSecrets {
+    pub fn new(args: UpgradeSecretArgs) -> anyhow::Result<Self> {
+        let resolved = ArcSwap::from_pointee(args.resolve()?);
+        Ok(Self { args, resolved })
+    }
+
+    /// The current secrets are kept if one of them cannot be read anymore
+    pub fn reload(&self) -> anyhow::Result<()> {
+        self.resolved.store(Arc::new(self.args.resolve()?));
+        Ok(())
+    }
+}
+
+impl UpgradeSecretArgs {
+    fn resolve(&self) -> anyhow::Result<ResolvedUpgradeSecrets> {
+        // The errors of the parsers are not kept, they would print the secrets
+        let authorization = match (&self.credentials, &self.bearer_token) {
+            (Some(credentials), _) => Some(
+                parse_http_credentials(&resolve_secret(credentials)?)
+                    .map_err(|_| anyhow!("Invalid http upgrade credentials"))?,
+            ),
+            (None, Some(token)) => Some(
+                parse_bearer_token(&resolve_secret(token)?)
+                    .map_err(|_| anyhow!("Invalid http upgrade bearer token"))?,
+            ),
+            (None, None) => None,
+        };
+        let http_headers = self
+            .http_headers
+            .iter()
+            .map(|(name, value)| {
+                let Ok(text) = value.to_str() else {
+                    return Ok((name.clone(), value.clone()));
+                };
+                let value = HeaderValue::try_from(resolve_secret(text)?)
+                    .map_err(|_| anyhow!("Invalid value of http header {}", name))?;
+                Ok((name.clone(), value))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(ResolvedUpgradeSecrets {
+            path_prefix: resolve_secret(&self.path_prefix)?,
+            path_prefix_hmac_secret: self
+                .path_prefix_hmac_secret
+                .as_deref()
+                .map(resolve_secret)
+                .transpose()?,
+            authorization,
+            http_headers,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct TlsClientConfig {
     pub tls_sni_disabled: bool,
@@ -64,3 +218,48 @@ impl TlsClientConfig {
         self.tls_connector.read().clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_secrets_reload() {
+        let path = std::env::temp_dir().join(format!("wstunnel-upgrade-secret-{}", std::process::id()));
+        std::fs::write(&path, "token-1\n").unwrap();
+        let secrets = UpgradeSecrets::new(UpgradeSecretArgs {
+            path_prefix: "env:WSTUNNEL_TEST_UNSET".to_string(),
+            bearer_token: Some(format!("file:{}", path.display())),
+            http_headers: vec![(
+                HeaderName::from_static("x-api-key"),
+                HeaderValue::from_str(&format!("file:{}", path.display())).unwrap(),
+            )],
+            ..Default::default()
+        });
+        assert!(secrets.is_err());
+
+        let secrets = UpgradeSecrets::new(UpgradeSecretArgs {
+            path_prefix: "v1".to_string(),
+            bearer_token: Some(format!("file:{}", path.display())),
+            http_headers: vec![(
+                HeaderName::from_static("x-api-key"),
+                HeaderValue::from_str(&format!("file:{}", path.display())).unwrap(),
+            )],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(secrets.resolved.load().authorization.as_ref().unwrap(), "Bearer token-1");
+
+        std::fs::write(&path, "token-2\n").unwrap();
+        secrets.reload().unwrap();
+        let resolved = secrets.resolved.load();
+        assert_eq!(resolved.path_prefix, "v1");
+        assert_eq!(resolved.authorization.as_ref().unwrap(), "Bearer token-2");
+        assert_eq!(resolved.http_headers[&HeaderName::from_static("x-api-key")], "token-2");
+
+        // The current secrets are kept when the file is gone
+        std::fs::remove_file(&path).unwrap();
+        assert!(secrets.reload().is_err());
+        assert_eq!(secrets.resolved.load().authorization.as_ref().unwrap(), "Bearer token-2");
+    }
+}
@@ -0,0 +1,249 @@
+use crate::config::parsers::{parse_duration_sec, parse_reverse_tunnel_arg, parse_tunnel_arg};
+use crate::config::LocalToRemote;
+use crate::secret::resolve_secret;
+use crate::tunnel::LocalProtocol;
+use anyhow::{anyhow, Context};
+use hyper::header::{HeaderName, HeaderValue};
+use log::trace;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+/// Format of the --config file, see client_config.yaml
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawClientConfigFile {
+    #[serde(default)]
+    local_to_remote: Vec<String>,
+    #[serde(default)]
+    remote_to_local: Vec<String>,
+    #[serde(default)]
+    http_headers: BTreeMap<String, String>,
+    connection_timeout: Option<RawDuration>,
+    websocket_ping_frequency: Option<RawDuration>,
+}
+
+/// Seconds, or with the suffix of the arguments, i.e: 30s, 5m, 1h
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Secs(u64),
+    Text(String),
+}
+
+/// The part of the client configuration changed without restarting it, it overrides the arguments
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReloadableClientConfig {
+    pub http_headers: HashMap<HeaderName, HeaderValue>,
+    pub timeout_connect: Option<Duration>,
+    pub websocket_ping_frequency: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+pub struct ClientConfigFile {
+    /// Keyed by their argument, i.e: "-L tcp://1212:google.com:443". A modified tunnel is a removed and a new one
+    pub tunnels: BTreeMap<String, LocalToRemote>,
+    pub reloadable: ReloadableClientConfig,
+}
+
+impl ClientConfigFile {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read client config file {}", path.display()))?;
+        Self::from_yaml(&content).with_context(|| format!("Invalid client config file {}", path.display()))
+    }
+
+    fn from_yaml(content: &str) -> anyhow::Result<Self> {
+        // An empty file is a valid config, without any tunnel
+        let raw: RawClientConfigFile = match content.trim().is_empty() {
+            true => RawClientConfigFile::default(),
+            false => serde_yaml::from_str(content)?,
+        };
+
+        let mut tunnels = BTreeMap::new();
+        for arg in raw.local_to_remote {
+            let tunnel = parse_tunnel_arg(&arg).with_context(|| format!("Invalid tunnel {}", arg))?;
+            if matches!(tunnel.local_protocol, LocalProtocol::Stdio { .. }) {
+                return Err(anyhow!("Invalid tunnel {}, stdio tunnels can only be given with -L", arg));
+            }
+            tunnels.insert(format!("-L {}", arg), tunnel);
+        }
+        for arg in raw.remote_to_local {
+            let tunnel = parse_reverse_tunnel_arg(&arg).with_context(|| format!("Invalid reverse tunnel {}", arg))?;
+            tunnels.insert(format!("-R {}", arg), tunnel);
+        }
+
+        let http_headers = raw
+            .http_headers
+            .iter()
+            .map(|(name, value)| {
+                let header =
+                    HeaderName::try_from(name).with_context(|| format!("Invalid http header name {}", name))?;
+                let value = resolve_secret(value).with_context(|| format!("Invalid value of http header {}", name))?;
+                let value =
+                    HeaderValue::try_from(value).with_context(|| format!("Invalid value of http header {}", name))?;
+                Ok((header, value))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let duration = |value: Option<RawDuration>| match value {
+            None => Ok(None),
+            Some(RawDuration::Secs(secs)) => Ok(Some(Duration::from_secs(secs))),
+            Some(RawDuration::Text(text)) => parse_duration_sec(&text).map(Some),
+        };
+
+        Ok(Self {
+            tunnels,
+            reloadable: ReloadableClientConfig {
+                http_headers,
+                timeout_connect: duration(raw.connection_timeout).context("Invalid connection_timeout")?,
+                websocket_ping_frequency: duration(raw.websocket_ping_frequency)
+                    .context("Invalid websocket_ping_frequency")?,
+            },
+        })
+    }
+}
+
+/// Notified when the config file changes, or when the client receives a SIGHUP
+pub struct ClientConfigWatcher {
+    changed: Arc<Notify>,
+    /// None if the file cannot be watched, i.e: no more inotify watches available. Only SIGHUP reloads the config then
+    _fs_watcher: Option<RecommendedWatcher>,
+}
+
+impl ClientConfigWatcher {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let changed = Arc::new(Notify::new());
+        let fs_watcher = match Self::watch_config(path, changed.clone()) {
+            Ok(watcher) => {
+                info!("Starting to watch client config file for changes to reload it");
+                Some(watcher)
+            }
+            Err(err) => {
+                warn!(
+                    "Cannot watch client config file for changes, it will only be reloaded on SIGHUP: {:?}",
+                    err
+                );
+                None
+            }
+        };
+        #[cfg(unix)]
+        Self::reload_on_sighup(changed.clone())?;
+
+        Ok(Self {
+            changed,
+            _fs_watcher: fs_watcher,
+        })
+    }
+
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    /// To reload the config on demand, i.e: from the admin socket
+    pub fn reload_notify(&self) -> Arc<Notify> {
+        self.changed.clone()
+    }
+
+    /// The directory is watched rather than the file, editors and config management tools replace the file
+    /// with a new one instead of modifying it
+    fn watch_config(path: &Path, changed: Arc<Notify>) -> anyhow::Result<RecommendedWatcher> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid client config file path {}", path.display()))?
+            .to_owned();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => {
+                    error!("Error while watching client config file for changes {:?}", err);
+                    return;
+                }
+            };
+
+            trace!("Received event: {:#?}", event);
+            let is_config = event.paths.iter().any(|p| p.file_name() == Some(&file_name));
+            if is_config && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                changed.notify_one();
+            }
+        })
+        .with_context(|| "Cannot create client config watcher")?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        Ok(watcher)
+    }
+
+    /// Fallback for when the file cannot be watched or when changes are not seen (i.e: network filesystem)
+    #[cfg(unix)]
+    fn reload_on_sighup(changed: Arc<Notify>) -> anyhow::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sighup = signal(SignalKind::hangup()).with_context(|| "Cannot listen for SIGHUP")?;
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading client config file");
+                changed.notify_one();
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_yaml() {
+        let config = ClientConfigFile::from_yaml(
+            r#"
+local_to_remote:
+  - tcp://1212:google.com:443
+  - socks5://127.0.0.1:1080
+remote_to_local:
+  - tcp://8080:localhost:80
+http_headers:
+  X-Team: infra
+connection_timeout: 5s
+websocket_ping_frequency: 0
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.tunnels.keys().collect::<Vec<_>>(),
+            [
+                "-L socks5://127.0.0.1:1080",
+                "-L tcp://1212:google.com:443",
+                "-R tcp://8080:localhost:80"
+            ]
+        );
+        assert_eq!(
+            config.tunnels["-R tcp://8080:localhost:80"].local_protocol,
+            LocalProtocol::ReverseTcp
+        );
+        assert_eq!(config.reloadable.http_headers[&HeaderName::from_static("x-team")], "infra");
+        assert_eq!(config.reloadable.timeout_connect, Some(Duration::from_secs(5)));
+        assert_eq!(config.reloadable.websocket_ping_frequency, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(ClientConfigFile::from_yaml("").unwrap().tunnels.is_empty());
+        assert!(ClientConfigFile::from_yaml("local_to_remote: [stdio://google.com:443]").is_err());
+        assert!(ClientConfigFile::from_yaml("local_to_remote: [tcp://google.com]").is_err());
+        assert!(ClientConfigFile::from_yaml("connection_timeout: forever").is_err());
+        assert!(ClientConfigFile::from_yaml("unknown: 1").is_err());
+        assert!(ClientConfigFile::from_yaml("http_headers: {X-Token: env:WSTUNNEL_TEST_UNSET}").is_err());
+    }
+}
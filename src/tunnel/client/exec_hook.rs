@@ -0,0 +1,60 @@
+use crate::protocols::exec::shell_command;
+use anyhow::Context;
+use std::net::SocketAddr;
+use std::process::ExitStatus;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+/// Command of --exec, run once the tunnels are started with the local addresses of the -L tunnels in its environment.
+/// It inherits the terminal of the client, i.e: for an interactive ssh session
+fn exec_command(command: &str, local_addrs: &[SocketAddr]) -> Command {
+    let mut cmd = shell_command(command);
+    if let Some(addr) = local_addrs.first() {
+        cmd.env("WSTUNNEL_LOCAL_ADDR", addr.to_string())
+            .env("WSTUNNEL_LOCAL_PORT", addr.port().to_string());
+    }
+    for (index, addr) in local_addrs.iter().enumerate() {
+        cmd.env(format!("WSTUNNEL_LOCAL_ADDR_{index}"), addr.to_string())
+            .env(format!("WSTUNNEL_LOCAL_PORT_{index}"), addr.port().to_string());
+    }
+    cmd.kill_on_drop(true);
+    cmd
+}
+
+/// Run the command until it exits, and return its exit code
+pub async fn run_exec_hook(command: &str, local_addrs: &[SocketAddr]) -> anyhow::Result<i32> {
+    info!("Running --exec command {}", command);
+    let status = exec_command(command, local_addrs)
+        .status()
+        .await
+        .with_context(|| format!("Cannot run --exec command {}", command))?;
+    if !status.success() {
+        warn!("--exec command {} exited with {}", command, status);
+    }
+
+    Ok(exit_code(status))
+}
+
+/// Killed by a signal, the exit code is the one of the shells
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("exit 3" => 3 ; "exit code")]
+    #[test_case("kill -TERM $$" => 143 ; "killed by a signal")]
+    #[test_case("test \"$WSTUNNEL_LOCAL_PORT\" = 1212 && test \"$WSTUNNEL_LOCAL_ADDR_1\" = '[::1]:53'" => 0 ; "local addresses")]
+    #[tokio::test]
+    async fn test_run_exec_hook(command: &str) -> i32 {
+        let local_addrs = ["127.0.0.1:1212".parse().unwrap(), "[::1]:53".parse().unwrap()];
+        run_exec_hook(command, &local_addrs).await.unwrap()
+    }
+}
@@ -0,0 +1,102 @@
+use crate::tunnel::client::WsClient;
+use anyhow::anyhow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The servers of the client, the primary first then the --failover-server in order of preference.
+/// The new connections of the tunnels go to the first one which answered the last check
+pub struct ServerFailover {
+    servers: Vec<WsClient>,
+    reachable: Vec<AtomicBool>,
+    active: AtomicUsize,
+}
+
+impl ServerFailover {
+    pub fn new(servers: Vec<WsClient>) -> Arc<Self> {
+        Arc::new(Self {
+            reachable: servers.iter().map(|_| AtomicBool::new(true)).collect(),
+            servers,
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn server_count(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Client of the server at this index, or of the active one
+    pub fn server(&self, index: Option<usize>) -> &WsClient {
+        let index = index.unwrap_or_else(|| self.active.load(Ordering::Relaxed));
+        &self.servers[index]
+    }
+
+    /// Check the servers in the background, for the whole life of the process
+    pub fn run_health_checks(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let checks = futures_util::future::join_all(self.servers.iter().map(check_server)).await;
+                let mut changed = false;
+                for ((server, reachable), check) in self.servers.iter().zip(&self.reachable).zip(checks) {
+                    // Only logged when it changes, the failing check is repeated until the server is back
+                    let was_reachable = reachable.swap(check.is_ok(), Ordering::Relaxed);
+                    changed |= was_reachable != check.is_ok();
+                    if let (true, Err(err)) = (was_reachable, check) {
+                        warn!("Server {:?} is unreachable: {:?}", server.config.remote_addr, err);
+                    }
+                }
+                if changed {
+                    self.select_server();
+                }
+            }
+        });
+    }
+
+    /// Prefer the primary, and keep the active server if none is reachable, they may be back before the others
+    fn select_server(&self) {
+        let previous = self.active.load(Ordering::Relaxed);
+        let Some(active) = self
+            .reachable
+            .iter()
+            .position(|reachable| reachable.load(Ordering::Relaxed))
+        else {
+            warn!(
+                "None of the servers is reachable, keeping {:?}",
+                self.servers[previous].config.remote_addr
+            );
+            return;
+        };
+        if active == previous {
+            return;
+        }
+
+        self.active.store(active, Ordering::Relaxed);
+        let (from, to) = (
+            &self.servers[previous].config.remote_addr,
+            &self.servers[active].config.remote_addr,
+        );
+        match active {
+            0 => info!(
+                "Primary server {:?} is reachable again, switching back to it from {:?}",
+                to, from
+            ),
+            _ if active > previous => warn!("Server {:?} is unreachable, failing over to {:?}", from, to),
+            _ => info!("Server {:?} is reachable again, switching to it from {:?}", to, from),
+        }
+    }
+}
+
+/// Lightweight check, a new connection to the server (tcp, tls and proxies) without any tunnel request
+async fn check_server(server: &WsClient) -> anyhow::Result<()> {
+    let timeout = server.config.timeout_connect();
+    match tokio::time::timeout(timeout, server.cnx_pool.dedicated_connection()).await {
+        Ok(Ok(Some(_))) => Ok(()),
+        Ok(Ok(None)) => Err(anyhow!("No connection to the server")),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Err(anyhow!("No answer after {:?}", timeout)),
+    }
+}
@@ -1,4 +1,6 @@
 use bytes::{Buf, Bytes};
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
 use std::cmp;
 use std::io::{Error, IoSlice};
 use std::pin::Pin;
@@ -37,6 +39,15 @@ impl TransportStream {
         }
     }
 
+    /// Connection of an http server other than the one of wstunnel, whose type is unknown
+    pub fn from_upgraded(upgraded: Upgraded) -> Self {
+        let (read, write) = tokio::io::split(TokioIo::new(upgraded));
+        Self {
+            read: TransportReadHalf::Upgraded(read, Bytes::new()),
+            write: TransportWriteHalf::Upgraded(write),
+        }
+    }
+
     pub fn from(self, read_buf: Bytes) -> Self {
         let mut read = self.read;
         *read.read_buf_mut() = read_buf;
@@ -55,6 +66,7 @@ pub enum TransportReadHalf {
     Plain(OwnedReadHalf, Bytes),
     Tls(ReadHalf<tokio_rustls::client::TlsStream<TcpStream>>, Bytes),
     TlsSrv(ReadHalf<tokio_rustls::server::TlsStream<TcpStream>>, Bytes),
+    Upgraded(ReadHalf<TokioIo<Upgraded>>, Bytes),
 }
 
 impl TransportReadHalf {
@@ -63,6 +75,7 @@ impl TransportReadHalf {
             Self::Plain(_, buf) => buf,
             Self::Tls(_, buf) => buf,
             Self::TlsSrv(_, buf) => buf,
+            Self::Upgraded(_, buf) => buf,
         }
     }
 }
@@ -71,6 +84,7 @@ pub enum TransportWriteHalf {
     Plain(OwnedWriteHalf),
     Tls(WriteHalf<tokio_rustls::client::TlsStream<TcpStream>>),
     TlsSrv(WriteHalf<tokio_rustls::server::TlsStream<TcpStream>>),
+    Upgraded(WriteHalf<TokioIo<Upgraded>>),
 }
 
 impl AsyncRead for TransportStream {
@@ -131,6 +145,7 @@ impl AsyncRead for TransportReadHalf {
             Self::Plain(cnx, _) => Pin::new(cnx).poll_read(cx, buf),
             Self::Tls(cnx, _) => Pin::new(cnx).poll_read(cx, buf),
             Self::TlsSrv(cnx, _) => Pin::new(cnx).poll_read(cx, buf),
+            Self::Upgraded(cnx, _) => Pin::new(cnx).poll_read(cx, buf),
         }
     }
 }
@@ -142,6 +157,7 @@ impl AsyncWrite for TransportWriteHalf {
             Self::Plain(cnx) => Pin::new(cnx).poll_write(cx, buf),
             Self::Tls(cnx) => Pin::new(cnx).poll_write(cx, buf),
             Self::TlsSrv(cnx) => Pin::new(cnx).poll_write(cx, buf),
+            Self::Upgraded(cnx) => Pin::new(cnx).poll_write(cx, buf),
         }
     }
 
@@ -151,6 +167,7 @@ impl AsyncWrite for TransportWriteHalf {
             Self::Plain(cnx) => Pin::new(cnx).poll_flush(cx),
             Self::Tls(cnx) => Pin::new(cnx).poll_flush(cx),
             Self::TlsSrv(cnx) => Pin::new(cnx).poll_flush(cx),
+            Self::Upgraded(cnx) => Pin::new(cnx).poll_flush(cx),
         }
     }
 
@@ -160,6 +177,7 @@ impl AsyncWrite for TransportWriteHalf {
             Self::Plain(cnx) => Pin::new(cnx).poll_shutdown(cx),
             Self::Tls(cnx) => Pin::new(cnx).poll_shutdown(cx),
             Self::TlsSrv(cnx) => Pin::new(cnx).poll_shutdown(cx),
+            Self::Upgraded(cnx) => Pin::new(cnx).poll_shutdown(cx),
         }
     }
 
@@ -173,6 +191,7 @@ impl AsyncWrite for TransportWriteHalf {
             Self::Plain(cnx) => Pin::new(cnx).poll_write_vectored(cx, bufs),
             Self::Tls(cnx) => Pin::new(cnx).poll_write_vectored(cx, bufs),
             Self::TlsSrv(cnx) => Pin::new(cnx).poll_write_vectored(cx, bufs),
+            Self::Upgraded(cnx) => Pin::new(cnx).poll_write_vectored(cx, bufs),
         }
     }
 
@@ -182,6 +201,7 @@ impl AsyncWrite for TransportWriteHalf {
             Self::Plain(cnx) => cnx.is_write_vectored(),
             Self::Tls(cnx) => cnx.is_write_vectored(),
             Self::TlsSrv(cnx) => cnx.is_write_vectored(),
+            Self::Upgraded(cnx) => cnx.is_write_vectored(),
         }
     }
 }
@@ -1,9 +1,29 @@
 #![allow(clippy::module_inception)]
+mod access_log;
+#[cfg(unix)]
+mod admin;
 mod client;
 mod cnx_pool;
 mod config;
+mod config_file;
+mod exec_hook;
+mod failover;
 pub mod l4_transport_stream;
+mod reconnect;
+mod reverse_hook;
+mod totp_command;
 
+pub use access_log::AccessLog;
+pub(crate) use access_log::{CountingStream, TransferStats};
+#[cfg(unix)]
+pub(crate) use admin::run_admin_server;
 pub use client::WsClient;
+pub use cnx_pool::ConnectMode;
 pub use config::TlsClientConfig;
 pub use config::WsClientConfig;
+pub use config::{UpgradeSecretArgs, UpgradeSecrets};
+pub use config_file::{ClientConfigFile, ClientConfigWatcher, ReloadableClientConfig};
+pub(crate) use exec_hook::run_exec_hook;
+pub use failover::ServerFailover;
+pub use reconnect::{GiveUp, ReconnectPolicy};
+pub use totp_command::TotpCommand;
@@ -0,0 +1,196 @@
+use rand::Rng;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// What a reverse tunnel does once it made --reconnect-max-attempts without reaching the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum GiveUp {
+    /// Stop the client, with an error
+    #[default]
+    Exit,
+    /// Stop this tunnel only, the others keep running
+    Stop,
+}
+
+/// Delays between the attempts of the reverse tunnels to reach the server again, after it failed
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Each delay is randomly moved by up to this fraction of it, so the clients do not all come back at once
+    pub jitter: f64,
+    /// Retry forever when None
+    pub max_attempts: Option<u32>,
+    pub give_up: GiveUp,
+    /// The delay grows again from where it was if the tunnel fails within this duration after reconnecting
+    pub reset_after: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: None,
+            give_up: GiveUp::Exit,
+            reset_after: Duration::ZERO,
+        }
+    }
+}
+
+/// Attempts of a tunnel to reconnect, following its policy
+pub(crate) struct Backoff {
+    policy: ReconnectPolicy,
+    delay: Duration,
+    attempts: u32,
+    connected_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            delay: policy.initial_delay,
+            policy,
+            attempts: 0,
+            connected_at: None,
+        }
+    }
+
+    /// Delay before the next attempt after a failure, or None once the tunnel must give up
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if let Some(connected_at) = self.connected_at.take() {
+            if connected_at.elapsed() >= self.policy.reset_after {
+                self.delay = self.policy.initial_delay;
+                self.attempts = 0;
+            }
+        }
+        if self.policy.max_attempts.is_some_and(|max| self.attempts >= max) {
+            return None;
+        }
+
+        self.attempts += 1;
+        let delay = self.delay;
+        self.delay = delay.mul_f64(self.policy.multiplier).min(self.policy.max_delay);
+        if self.policy.jitter > 0.0 {
+            let jitter = rand::thread_rng().gen_range(-self.policy.jitter..=self.policy.jitter);
+            return Some(delay.mul_f64(1.0 + jitter).min(self.policy.max_delay));
+        }
+        Some(delay)
+    }
+
+    pub fn connected(&mut self) {
+        self.connected_at.get_or_insert_with(Instant::now);
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Stop the client of the tunnel if the policy says so, the tunnel stops in any case
+    pub fn give_up(&self, client_gave_up: &Notify) {
+        if self.policy.give_up == GiveUp::Exit {
+            client_gave_up.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 3.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(policy());
+        let delays: Vec<_> = (0..4).map(|_| backoff.next_delay().unwrap().as_secs()).collect();
+        assert_eq!(delays, vec![1, 3, 9, 10]);
+
+        // Reset as soon as it reconnects by default
+        backoff.connected();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_backoff_max_attempts() {
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            max_attempts: Some(2),
+            ..policy()
+        });
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempts(), 2);
+    }
+
+    #[test]
+    fn test_backoff_reset_after() {
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            reset_after: Duration::from_secs(60),
+            max_attempts: Some(3),
+            ..policy()
+        });
+        backoff.next_delay();
+        backoff.next_delay();
+        // Failing again right after reconnecting counts as the same outage
+        backoff.connected();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_secs(9)));
+        assert_eq!(backoff.next_delay(), None);
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            jitter: 0.5,
+            ..policy()
+        });
+        for _ in 0..20 {
+            backoff.delay = Duration::from_secs(4);
+            let delay = backoff.next_delay().unwrap();
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(6));
+        }
+    }
+
+    #[test]
+    fn test_backoff_jitter_max_delay() {
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            jitter: 0.5,
+            ..policy()
+        });
+        backoff.delay = Duration::from_secs(10);
+        for _ in 0..20 {
+            assert!(backoff.next_delay().unwrap() <= Duration::from_secs(10));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_give_up() {
+        let exit = Notify::new();
+        let other_client = Notify::new();
+        Backoff::new(ReconnectPolicy {
+            give_up: GiveUp::Stop,
+            ..policy()
+        })
+        .give_up(&exit);
+        Backoff::new(policy()).give_up(&exit);
+
+        // Only the client of the tunnel is stopped
+        let stopped = tokio::time::timeout(Duration::from_millis(10), exit.notified()).await;
+        assert!(stopped.is_ok());
+        let stopped = tokio::time::timeout(Duration::from_millis(10), other_client.notified()).await;
+        assert!(stopped.is_err());
+        let stopped = tokio::time::timeout(Duration::from_millis(10), exit.notified()).await;
+        assert!(stopped.is_err());
+    }
+}
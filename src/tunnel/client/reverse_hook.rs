@@ -0,0 +1,115 @@
+use crate::protocols::exec::shell_command;
+use crate::tunnel::RemoteAddr;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+/// Time given to the hook to accept or reject a connection, after which the connection is rejected
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Event emitted when the server accepts a connection on one of our reverse tunnels,
+/// with the `wstunnel::reverse_accept` tracing target and given as json to the hook on its stdin.
+#[derive(Debug, Serialize)]
+struct ReverseAcceptEvent {
+    timestamp: u64,
+    listener: String,
+    peer_addr: Option<SocketAddr>,
+    /// Only for dynamic reverse tunnels (socks5, http proxy), the destination requested by the peer
+    destination: Option<String>,
+}
+
+/// Emit the event of the accepted connection, and run the hook if any.
+/// Return false if the connection must be rejected, i.e: the hook did not exit successfully
+pub async fn on_reverse_accept(
+    hook: Option<&str>,
+    listener: &RemoteAddr,
+    peer_addr: Option<SocketAddr>,
+    destination: Option<&RemoteAddr>,
+) -> bool {
+    let event = ReverseAcceptEvent {
+        timestamp: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        listener: format!("{}:{}", listener.host, listener.port),
+        peer_addr,
+        destination: destination.map(|dest| format!("{}:{}", dest.host, dest.port)),
+    };
+    info!(
+        target: "wstunnel::reverse_accept",
+        listener = event.listener,
+        peer_addr = ?event.peer_addr,
+        destination = ?event.destination,
+    );
+
+    let Some(hook) = hook else {
+        return true;
+    };
+    match tokio::time::timeout(HOOK_TIMEOUT, run_hook(hook, &event)).await {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(err)) => {
+            warn!("Cannot run reverse accept hook {}: {:?}", hook, err);
+            false
+        }
+        Err(_) => {
+            warn!("Reverse accept hook {} did not finish after {:?}", hook, HOOK_TIMEOUT);
+            false
+        }
+    }
+}
+
+async fn run_hook(hook: &str, event: &ReverseAcceptEvent) -> anyhow::Result<bool> {
+    let mut json = serde_json::to_vec(event)?;
+    json.push(b'\n');
+
+    let mut child = shell_command(hook)
+        .env("WSTUNNEL_LISTENER", &event.listener)
+        .env(
+            "WSTUNNEL_PEER_ADDR",
+            event.peer_addr.map(|addr| addr.to_string()).unwrap_or_default(),
+        )
+        .env("WSTUNNEL_DESTINATION", event.destination.as_deref().unwrap_or_default())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The hook is free to not read its stdin
+        let _ = stdin.write_all(&json).await;
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
+        info!(
+            "Reverse accept hook rejected the connection of {:?}: {}",
+            event.peer_addr, status
+        );
+    }
+    Ok(status.success())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tunnel::LocalProtocol;
+    use test_case::test_case;
+    use url::Host;
+
+    #[test_case(None => true ; "without hook")]
+    #[test_case(Some("grep -q '\"peer_addr\":\"1.2.3.4:5678\"'") => true ; "hook reading the event")]
+    #[test_case(Some("test \"$WSTUNNEL_PEER_ADDR\" = 1.2.3.4:5678") => true ; "hook reading the env")]
+    #[test_case(Some("exit 1") => false ; "hook rejecting")]
+    #[tokio::test]
+    async fn test_on_reverse_accept(hook: Option<&str>) -> bool {
+        let listener = RemoteAddr {
+            protocol: LocalProtocol::ReverseTcp,
+            host: Host::Ipv4([0, 0, 0, 0].into()),
+            port: 8080,
+        };
+        on_reverse_accept(hook, &listener, Some("1.2.3.4:5678".parse().unwrap()), None).await
+    }
+}
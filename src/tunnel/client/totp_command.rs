@@ -0,0 +1,107 @@
+use crate::protocols::exec::shell_command;
+use anyhow::{anyhow, Context};
+use hyper::header::HeaderValue;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+const TIME_STEP_SECS: u64 = 30;
+/// Time given to the command to print the code, i.e: for the user to touch a hardware token
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Command printing the TOTP code sent with the upgrade requests, when the server requires a second factor.
+/// The code is reused by all the upgrade requests of its 30 seconds time step, so the command runs at most once per step
+#[derive(Clone)]
+pub struct TotpCommand {
+    command: String,
+    last_code: Arc<Mutex<Option<(u64, HeaderValue)>>>,
+}
+
+impl TotpCommand {
+    pub fn new(command: String) -> Self {
+        Self {
+            command,
+            last_code: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Code of the current time step, running the command if it is not known yet
+    pub async fn code(&self) -> anyhow::Result<HeaderValue> {
+        // Hold the lock while the command runs, so concurrent upgrade requests do not ask for a code each
+        let mut last_code = self.last_code.lock().await;
+        let step = current_step();
+        if let Some((code_step, code)) = last_code.as_ref() {
+            if *code_step == step {
+                return Ok(code.clone());
+            }
+        }
+
+        let code = tokio::time::timeout(COMMAND_TIMEOUT, self.run())
+            .await
+            .map_err(|_| anyhow!("TOTP command did not print a code after {:?}", COMMAND_TIMEOUT))??;
+        // The code was maybe asked at the end of a step, remember it for the step it was printed in
+        *last_code = Some((current_step(), code.clone()));
+
+        Ok(code)
+    }
+
+    async fn run(&self) -> anyhow::Result<HeaderValue> {
+        let output = shell_command(&self.command)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .with_context(|| format!("Cannot run TOTP command {}", self.command))?;
+        if !output.status.success() {
+            return Err(anyhow!("TOTP command {} failed with {}", self.command, output.status));
+        }
+
+        let code = String::from_utf8_lossy(&output.stdout);
+        let code = code.trim();
+        if code.is_empty() {
+            return Err(anyhow!("TOTP command {} did not print a code", self.command));
+        }
+        Ok(HeaderValue::from_str(code)?)
+    }
+}
+
+fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / TIME_STEP_SECS
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_code_is_reused_within_step() {
+        let counter = std::env::temp_dir().join(format!("wstunnel-totp-{}", std::process::id()));
+        let _ = std::fs::remove_file(&counter);
+        let command = TotpCommand::new(format!("echo x >> {}; echo ' 123456 '", counter.display()));
+
+        let first = command.code().await.unwrap();
+        let second = command.code().await.unwrap();
+        let runs = std::fs::read_to_string(&counter).unwrap().lines().count();
+        std::fs::remove_file(&counter).unwrap();
+
+        assert_eq!(first, "123456");
+        assert_eq!(second, "123456");
+        // The step may have changed between the two calls
+        assert!((1..=2).contains(&runs));
+    }
+
+    #[tokio::test]
+    async fn test_failing_command() {
+        assert!(TotpCommand::new("echo 123456; exit 1".to_string())
+            .code()
+            .await
+            .is_err());
+        assert!(TotpCommand::new("true".to_string()).code().await.is_err());
+    }
+}
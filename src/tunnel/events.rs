@@ -0,0 +1,68 @@
+//! State changes of a client or server, broadcast to its subscribers: the event webhook of the server, and the
+//! programs embedding wstunnel
+
+use crate::tunnel::server::TunnelInfo;
+use serde::Serialize;
+use std::net::IpAddr;
+use tokio::sync::broadcast;
+
+/// Events not received yet by the slowest subscriber, it misses the oldest ones past this
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TunnelEvent {
+    /// Client: a tunnel is established with the server
+    TransportConnected { server: String },
+    /// Client: a tunnel cannot be established with the server
+    TransportFailed { server: String, error: String },
+    /// Server: a tunnel is allowed and connected to its destination
+    TunnelOpened {
+        #[serde(flatten)]
+        tunnel: TunnelInfo,
+    },
+    /// Server: both sides of the tunnel are closed
+    TunnelClosed {
+        #[serde(flatten)]
+        tunnel: TunnelInfo,
+        bytes_from_client: u64,
+        bytes_to_client: u64,
+        duration_ms: u128,
+    },
+    /// Server: a client failed to authenticate
+    #[serde(rename = "auth_failure")]
+    AuthFailed { source: IpAddr, reason: String },
+    /// Server: no restriction allows the tunnel
+    RestrictionDenied {
+        source: IpAddr,
+        protocol: String,
+        destination: String,
+        reason: String,
+    },
+}
+
+/// Events of a client or server, shared by all its parts
+#[derive(Debug, Clone)]
+pub struct TunnelEvents(broadcast::Sender<TunnelEvent>);
+
+impl Default for TunnelEvents {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl TunnelEvents {
+    /// Receive the events sent from now on. Lagging behind by more than 1024 events drops the oldest ones
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.0.subscribe()
+    }
+
+    /// Dropped if there is no subscriber
+    pub(crate) fn send(&self, event: TunnelEvent) {
+        let _ = self.0.send(event);
+    }
+
+    pub(crate) fn has_subscribers(&self) -> bool {
+        self.0.receiver_count() > 0
+    }
+}
@@ -0,0 +1,118 @@
+//! Interceptors of the streams of the tunnels, i.e: to throttle them, log their content or validate their protocol.
+//! They are registered by name by the program embedding wstunnel, then enabled by the restrictions of the server
+//! (interceptors: [NAME]) or by the tunnels of the client (?interceptor=NAME)
+
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub type InterceptedRead = Pin<Box<dyn AsyncRead + Send>>;
+pub type InterceptedWrite = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// Wraps the local streams of each tunnel it is enabled for. On the client they are the ones of the application using
+/// the tunnel, on the server the ones of the destination, so the reader returns the bytes to send through the tunnel
+/// and the writer receives the ones coming from it
+pub trait StreamInterceptor: Send + Sync + 'static {
+    fn intercept(
+        &self,
+        stream: &StreamInfo,
+        rx: InterceptedRead,
+        tx: InterceptedWrite,
+    ) -> (InterceptedRead, InterceptedWrite);
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub id: String,
+    pub protocol: String,
+    pub destination: String,
+    /// Name of the restriction which allowed the tunnel, on the server only
+    pub restriction: Option<String>,
+}
+
+/// Interceptors registered by the program embedding wstunnel, by name
+#[derive(Clone, Default)]
+pub struct Interceptors(HashMap<String, Arc<dyn StreamInterceptor>>);
+
+impl Interceptors {
+    pub fn insert(&mut self, name: impl Into<String>, interceptor: Arc<dyn StreamInterceptor>) {
+        self.0.insert(name.into(), interceptor);
+    }
+
+    /// The interceptors with these names, in the same order
+    pub fn select(&self, names: &[String]) -> anyhow::Result<Vec<Arc<dyn StreamInterceptor>>> {
+        names
+            .iter()
+            .map(|name| {
+                self.0
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Unknown stream interceptor {}, it is not registered", name))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.keys()).finish()
+    }
+}
+
+/// Wrap the streams with each interceptor, the first one being the closest to the local side
+pub fn intercept(
+    interceptors: &[Arc<dyn StreamInterceptor>],
+    stream: &StreamInfo,
+    mut rx: InterceptedRead,
+    mut tx: InterceptedWrite,
+) -> (InterceptedRead, InterceptedWrite) {
+    for interceptor in interceptors {
+        (rx, tx) = interceptor.intercept(stream, rx, tx);
+    }
+    (rx, tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Appends its name to the bytes read
+    struct Suffix(&'static [u8]);
+
+    impl StreamInterceptor for Suffix {
+        fn intercept(
+            &self,
+            _stream: &StreamInfo,
+            rx: InterceptedRead,
+            tx: InterceptedWrite,
+        ) -> (InterceptedRead, InterceptedWrite) {
+            (Box::pin(rx.chain(self.0)), tx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_intercept_in_order() {
+        let mut interceptors = Interceptors::default();
+        interceptors.insert("a", Arc::new(Suffix(b"a")));
+        interceptors.insert("b", Arc::new(Suffix(b"b")));
+        let selected = interceptors.select(&["b".to_string(), "a".to_string()]).unwrap();
+        let stream = StreamInfo {
+            id: "id".to_string(),
+            protocol: "tcp".to_string(),
+            destination: "localhost:80".to_string(),
+            restriction: None,
+        };
+
+        let (mut rx, mut tx) = intercept(&selected, &stream, Box::pin(&b"local-"[..]), Box::pin(tokio::io::sink()));
+        let mut buf = Vec::new();
+        rx.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"local-ba");
+        tx.write_all(b"ignored").await.unwrap();
+
+        assert!(interceptors.select(&["c".to_string()]).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use crate::protocols::http_proxy;
-use crate::protocols::http_proxy::HttpProxyListener;
+use crate::protocols::http_proxy::{HttpProxyAuth, HttpProxyListener};
 use crate::tunnel::{LocalProtocol, RemoteAddr};
 use anyhow::{anyhow, Context};
 use std::net::SocketAddr;
@@ -18,10 +18,10 @@ impl HttpProxyTunnelListener {
     pub async fn new(
         bind_addr: SocketAddr,
         timeout: Option<Duration>,
-        credentials: Option<(String, String)>,
+        auth: HttpProxyAuth,
         proxy_protocol: bool,
     ) -> anyhow::Result<Self> {
-        let listener = http_proxy::run_server(bind_addr, timeout, credentials)
+        let listener = http_proxy::run_server(bind_addr, timeout, auth)
             .await
             .with_context(|| anyhow!("Cannot start http proxy server on {}", bind_addr))?;
 
@@ -23,8 +23,12 @@ pub use udp::UdpTunnelListener;
 #[cfg(unix)]
 pub use unix_sock::UnixTunnelListener;
 
+use crate::protocols::socks5::Socks5ReadHalf;
+use crate::protocols::udp::UdpStream;
 use crate::tunnel::RemoteAddr;
+use std::net::SocketAddr;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::tcp::OwnedReadHalf;
 use tokio_stream::Stream;
 
 pub trait TunnelListener: Stream<Item = anyhow::Result<((Self::Reader, Self::Writer), RemoteAddr)>> {
@@ -41,3 +45,26 @@ where
     type Reader = R;
     type Writer = W;
 }
+
+/// Address of the client connected to a listener, for the listeners that have one
+pub trait ClientAddr {
+    fn client_addr(&self) -> Option<SocketAddr>;
+}
+
+impl ClientAddr for OwnedReadHalf {
+    fn client_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+}
+
+impl ClientAddr for UdpStream {
+    fn client_addr(&self) -> Option<SocketAddr> {
+        Some(self.peer_addr())
+    }
+}
+
+impl ClientAddr for Socks5ReadHalf {
+    fn client_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr()
+    }
+}
@@ -17,8 +17,9 @@ impl Socks5TunnelListener {
         bind_addr: SocketAddr,
         timeout: Option<Duration>,
         credentials: Option<(String, String)>,
+        gssapi: Option<String>,
     ) -> anyhow::Result<Self> {
-        let listener = socks5::run_server(bind_addr, timeout, credentials)
+        let listener = socks5::run_server(bind_addr, timeout, credentials, gssapi)
             .await
             .with_context(|| anyhow!("Cannot start Socks5 server on {}", bind_addr))?;
 
@@ -27,6 +27,10 @@ impl TcpTunnelListener {
             proxy_protocol,
         })
     }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.as_ref().local_addr()
+    }
 }
 
 impl Stream for TcpTunnelListener {
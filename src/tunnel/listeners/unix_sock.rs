@@ -16,8 +16,8 @@ pub struct UnixTunnelListener {
 }
 
 impl UnixTunnelListener {
-    pub async fn new(path: &Path, dest: (Host, u16), proxy_protocol: bool) -> anyhow::Result<Self> {
-        let listener = unix_sock::run_server(path)
+    pub async fn new(path: &Path, dest: (Host, u16), proxy_protocol: bool, mode: Option<u32>) -> anyhow::Result<Self> {
+        let listener = unix_sock::run_server(path, mode)
             .await
             .with_context(|| anyhow!("Cannot start Unix domain server on {}", path.display()))?;
 
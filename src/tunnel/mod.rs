@@ -1,79 +1,27 @@
+pub mod active_tunnels;
+#[cfg(unix)]
+mod admin;
+pub mod bandwidth;
+pub mod capture;
 pub mod client;
 pub mod connectors;
+pub mod events;
+pub mod interceptor;
 pub mod listeners;
 pub mod server;
+pub mod spa;
 mod tls_reloader;
 pub mod transport;
+pub mod tunnel_metrics;
 
-use serde::{Deserialize, Serialize};
+pub use crate::protocol::LocalProtocol;
 use std::fmt::Debug;
+use std::future::Future;
 use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::path::PathBuf;
-use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use url::Host;
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub enum LocalProtocol {
-    Tcp {
-        proxy_protocol: bool,
-    },
-    Udp {
-        timeout: Option<Duration>,
-    },
-    Stdio {
-        proxy_protocol: bool,
-    },
-    Socks5 {
-        timeout: Option<Duration>,
-        credentials: Option<(String, String)>,
-    },
-    TProxyTcp,
-    TProxyUdp {
-        timeout: Option<Duration>,
-    },
-    HttpProxy {
-        timeout: Option<Duration>,
-        credentials: Option<(String, String)>,
-        proxy_protocol: bool,
-    },
-    ReverseTcp,
-    ReverseUdp {
-        timeout: Option<Duration>,
-    },
-    ReverseSocks5 {
-        timeout: Option<Duration>,
-        credentials: Option<(String, String)>,
-    },
-    ReverseHttpProxy {
-        timeout: Option<Duration>,
-        credentials: Option<(String, String)>,
-    },
-    ReverseUnix {
-        path: PathBuf,
-    },
-    Unix {
-        path: PathBuf,
-        proxy_protocol: bool,
-    },
-}
-
-impl LocalProtocol {
-    pub const fn is_reverse_tunnel(&self) -> bool {
-        matches!(
-            self,
-            Self::ReverseTcp
-                | Self::ReverseUdp { .. }
-                | Self::ReverseSocks5 { .. }
-                | Self::ReverseUnix { .. }
-                | Self::ReverseHttpProxy { .. }
-        )
-    }
-
-    pub const fn is_dynamic_reverse_tunnel(&self) -> bool {
-        matches!(self, Self::ReverseSocks5 { .. } | Self::ReverseHttpProxy { .. })
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct RemoteAddr {
     pub protocol: LocalProtocol,
@@ -95,3 +43,13 @@ pub fn try_to_sock_addr((host, port): (Host, u16)) -> anyhow::Result<SocketAddr>
         Host::Ipv6(ip) => Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))),
     }
 }
+
+/// Spawn a task dropped once the token is cancelled, i.e: when the client or server running it stops
+pub(crate) fn spawn_until_cancelled<F>(cancel: &CancellationToken, fut: F) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let cancel = cancel.clone();
+    tokio::spawn(async move { cancel.run_until_cancelled(fut).await })
+}
@@ -0,0 +1,169 @@
+use crate::secret::redact_path_prefix;
+use anyhow::Context;
+use hyper::body::Body;
+use hyper::header::{REFERER, USER_AGENT};
+use hyper::{Request, Response};
+use parking_lot::Mutex;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum AccessLogFormat {
+    /// host ident authuser [date] "request" status bytes
+    Common,
+    /// The common format followed by "referer" "user-agent"
+    #[default]
+    Combined,
+}
+
+/// Access log of the http requests answered by the server, upgraded or rejected, in the Common/Combined Log Format
+/// of web servers, for the tooling already parsing them (i.e: fail2ban). Written to its file, or to the logs without
+pub struct HttpAccessLog {
+    file: Option<Mutex<File>>,
+    format: AccessLogFormat,
+}
+
+/// What is logged of a request, taken before it is consumed by its handler
+pub(super) struct HttpAccessEntry {
+    log: Arc<HttpAccessLog>,
+    host: IpAddr,
+    date: String,
+    request: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl HttpAccessLog {
+    pub fn new(path: Option<&Path>, format: AccessLogFormat) -> anyhow::Result<Self> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Cannot open access log file {}", path.display()))?,
+            )),
+            None => None,
+        };
+
+        Ok(Self { file, format })
+    }
+
+    pub(super) fn entry<B>(self: &Arc<Self>, req: &Request<B>, host: IpAddr) -> HttpAccessEntry {
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string())
+        };
+
+        HttpAccessEntry {
+            log: self.clone(),
+            host,
+            date: chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+            request: format!("{} {} {:?}", req.method(), redact_path_prefix(path), req.version()),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        }
+    }
+
+    fn write(&self, line: String) {
+        let Some(file) = &self.file else {
+            info!(target: "wstunnel::access_log", "{}", line);
+            return;
+        };
+
+        if let Err(err) = writeln!(file.lock(), "{}", line) {
+            warn!("Cannot write access log: {}", err);
+        }
+    }
+}
+
+impl HttpAccessEntry {
+    pub(super) fn write<B: Body>(self, response: &Response<B>) {
+        let line = self.line(response);
+        self.log.write(line);
+    }
+
+    /// The size of the body is unknown for the tunnels, as it is streamed
+    fn line<B: Body>(&self, response: &Response<B>) -> String {
+        let bytes = response
+            .body()
+            .size_hint()
+            .exact()
+            .map_or_else(|| "-".to_string(), |len| len.to_string());
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            self.host,
+            self.date,
+            escape(&self.request),
+            response.status().as_u16(),
+            bytes
+        );
+        if self.log.format == AccessLogFormat::Combined {
+            let quoted = |value: &Option<String>| value.as_deref().map_or("-".to_string(), escape);
+            let _ = write!(line, " \"{}\" \"{}\"", quoted(&self.referer), quoted(&self.user_agent));
+        }
+
+        line
+    }
+}
+
+/// The values are between double quotes, a client must not be able to forge the fields of its line
+fn escape(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{Empty, Full};
+    use hyper::body::Bytes;
+    use test_case::test_case;
+
+    #[test_case(AccessLogFormat::Common => r#"10.0.0.1 - - [DATE] "GET /<redacted>/events HTTP/1.1" 400 24"# ; "common")]
+    #[test_case(AccessLogFormat::Combined => r#"10.0.0.1 - - [DATE] "GET /<redacted>/events HTTP/1.1" 400 24 "-" "curl/8.0 \"injected\"""# ; "combined")]
+    fn test_line(format: AccessLogFormat) -> String {
+        let log = Arc::new(HttpAccessLog::new(None, format).unwrap());
+        let req = Request::builder()
+            .uri("/my-secret/events")
+            .header(USER_AGENT, r#"curl/8.0 "injected""#)
+            .body(())
+            .unwrap();
+        let mut entry = log.entry(&req, "10.0.0.1".parse().unwrap());
+        entry.date = "DATE".to_string();
+
+        let response = Response::builder()
+            .status(400)
+            .body(Full::new(Bytes::from("Invalid protocol request")))
+            .unwrap();
+        entry.line(&response)
+    }
+
+    #[test]
+    fn test_write_to_file() {
+        let path = std::env::temp_dir().join(format!("wstunnel-access-{}.log", std::process::id()));
+        let log = Arc::new(HttpAccessLog::new(Some(&path), AccessLogFormat::Combined).unwrap());
+        let req = Request::builder()
+            .uri("/v1/events")
+            .header(REFERER, "https://example.com/")
+            .body(())
+            .unwrap();
+        log.entry(&req, "::1".parse().unwrap())
+            .write(&Response::builder().status(101).body(Empty::<Bytes>::new()).unwrap());
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(lines.starts_with("::1 - - ["));
+        assert!(lines.ends_with(
+            r#"] "GET /<redacted>/events HTTP/1.1" 101 0 "https://example.com/" "-"
+"#
+        ));
+    }
+}
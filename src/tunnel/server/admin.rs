@@ -0,0 +1,70 @@
+use crate::protocols::unix_sock::UnixListenerStream;
+use crate::tunnel::active_tunnels::ActiveTunnels;
+use crate::tunnel::admin::{exec_common_command, serve_admin_commands};
+use crate::tunnel::server::ServerRegistries;
+use crate::tunnel::server::WsServerConfig;
+use futures_util::future;
+use serde_json::json;
+use std::sync::Arc;
+
+/// Serve the admin commands of the server, one command per line, each answered by a json line.
+/// list       => the reverse tunnels currently listening, with their owner and traffic counters
+/// close ID   => stop the reverse tunnel server with this id from listening
+/// bans       => the sources currently banned for abusing the server
+/// unban IP   => lift the ban of this source, or of all of them with 'unban all'
+/// tunnels    => the tunnels currently open, with their source, destination, identity, age and traffic
+/// stats      => the number of tunnels open, opened and failed to open since the start
+/// log-level [FILTER] => the current log filter, or replace it without restarting (i.e: info,wstunnel::protocols::udp=trace)
+pub async fn run_admin_server(listener: UnixListenerStream, config: Arc<WsServerConfig>) {
+    serve_admin_commands(listener, move |command: String| {
+        future::ready(exec_command(&command, &config.registries, &config.instance.active_tunnels))
+    })
+    .await
+}
+
+fn exec_command(command: &str, registries: &ServerRegistries, tunnels: &ActiveTunnels) -> serde_json::Value {
+    let mut args = command.split_whitespace();
+    match (args.next(), args.next()) {
+        (Some("list"), None) => json!(registries.reverse_tunnels.list()),
+        (Some("close"), Some(id)) => match id.parse::<u64>() {
+            Ok(id) if registries.reverse_tunnels.close(id) => json!({ "closed": id }),
+            Ok(id) => json!({ "error": format!("No reverse tunnel with id {}", id) }),
+            Err(_) => json!({ "error": format!("Invalid reverse tunnel id {}", id) }),
+        },
+        (Some("bans"), None) => json!(registries.bans.list()),
+        (Some("unban"), Some("all")) => json!({ "unbanned": registries.bans.unban(None) }),
+        (Some("unban"), Some(ip)) => match ip.parse() {
+            Ok(ip) => json!({ "unbanned": registries.bans.unban(Some(ip)) }),
+            Err(_) => json!({ "error": format!("Invalid ip address {}", ip) }),
+        },
+        _ => exec_common_command(command, tunnels).unwrap_or_else(|| {
+            json!({
+                "error": "Unknown command, expected 'list', 'close ID', 'bans', 'unban IP|all', 'tunnels', 'stats', 'dump' or 'log-level [FILTER]'"
+            })
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("list" => true ; "list")]
+    #[test_case("close 0" => false ; "unknown id")]
+    #[test_case("close abc" => false ; "invalid id")]
+    #[test_case("bans" => true ; "bans")]
+    #[test_case("unban 192.0.2.1" => true ; "unban ip")]
+    #[test_case("unban all" => true ; "unban all")]
+    #[test_case("unban foo" => false ; "unban invalid ip")]
+    #[test_case("tunnels" => true ; "tunnels")]
+    #[test_case("stats" => true ; "stats")]
+    #[test_case("dump" => true ; "dump")]
+    #[test_case("log-level" => false ; "log level not reloadable")]
+    #[test_case("delete 1" => false ; "unknown command")]
+    fn test_exec_command(command: &str) -> bool {
+        exec_command(command, &ServerRegistries::default(), &ActiveTunnels::default())
+            .get("error")
+            .is_none()
+    }
+}
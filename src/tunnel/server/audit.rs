@@ -0,0 +1,251 @@
+use crate::tunnel::client::{CountingStream, TransferStats};
+use crate::tunnel::events::{TunnelEvent, TunnelEvents};
+use crate::tunnel::server::event_handler::{TunnelEventHandler, TunnelInfo, TunnelStats};
+use anyhow::Context;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+/// Audit log of the server, one json line each time a tunnel is opened or closed.
+/// Written directly to its file, whatever the log level is.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+struct AuditLogLine<'a> {
+    timestamp: u64,
+    event: &'static str,
+    #[serde(flatten)]
+    tunnel: &'a TunnelInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_from_client: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_to_client: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u128>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open audit log file {}", path.display()))?;
+
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    fn write(&self, line: &AuditLogLine) {
+        let ret = serde_json::to_vec(line)
+            .map_err(std::io::Error::from)
+            .and_then(|mut json| {
+                json.push(b'\n');
+                self.file.lock().write_all(&json)
+            });
+        if let Err(err) = ret {
+            warn!("Cannot write audit log: {}", err);
+        }
+    }
+}
+
+/// Report the opening of the tunnel to the audit log and the subscribers of the events, its closing is reported to
+/// them and to the event handler once both of its streams are dropped
+pub(super) fn open_tunnel(
+    tunnel: TunnelInfo,
+    log: Option<Arc<AuditLog>>,
+    events: TunnelEvents,
+    handler: Option<Arc<dyn TunnelEventHandler>>,
+) -> Arc<AuditedTunnel> {
+    if let Some(log) = &log {
+        log.write(&AuditLogLine {
+            timestamp: now(),
+            event: "open",
+            tunnel: &tunnel,
+            bytes_from_client: None,
+            bytes_to_client: None,
+            duration_ms: None,
+        });
+    }
+    events.send(TunnelEvent::TunnelOpened { tunnel: tunnel.clone() });
+
+    Arc::new(AuditedTunnel {
+        log,
+        events,
+        handler,
+        tunnel,
+        stats: Arc::new(TransferStats::default()),
+        opened_at: Instant::now(),
+    })
+}
+
+pub(super) struct AuditedTunnel {
+    log: Option<Arc<AuditLog>>,
+    events: TunnelEvents,
+    handler: Option<Arc<dyn TunnelEventHandler>>,
+    tunnel: TunnelInfo,
+    stats: Arc<TransferStats>,
+    opened_at: Instant,
+}
+
+impl Drop for AuditedTunnel {
+    fn drop(&mut self) {
+        // The local side of the tunnel reads what is sent to the client, and writes what it receives from it
+        let bytes_from_client = self.stats.received();
+        let bytes_to_client = self.stats.sent();
+        let duration = self.opened_at.elapsed();
+        let duration_ms = duration.as_millis();
+        if let Some(log) = &self.log {
+            log.write(&AuditLogLine {
+                timestamp: now(),
+                event: "close",
+                tunnel: &self.tunnel,
+                bytes_from_client: Some(bytes_from_client),
+                bytes_to_client: Some(bytes_to_client),
+                duration_ms: Some(duration_ms),
+            });
+        }
+        self.events.send(TunnelEvent::TunnelClosed {
+            tunnel: self.tunnel.clone(),
+            bytes_from_client,
+            bytes_to_client,
+            duration_ms,
+        });
+        if let Some(handler) = &self.handler {
+            let stats = TunnelStats {
+                bytes_from_client,
+                bytes_to_client,
+                duration,
+            };
+            handler.on_stream_close(&self.tunnel, &stats);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Local side of an audited tunnel, counting its traffic until the tunnel is closed
+#[pin_project]
+pub(super) struct AuditStream<T> {
+    #[pin]
+    inner: CountingStream<T>,
+    _tunnel: Arc<AuditedTunnel>,
+}
+
+impl<T> AuditStream<T> {
+    pub(super) fn new(inner: T, tunnel: Arc<AuditedTunnel>) -> Self {
+        Self {
+            inner: CountingStream::new(inner, Some(tunnel.stats.clone())),
+            _tunnel: tunnel,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for AuditStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for AuditStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::server::utils::protocol_name;
+    use crate::tunnel::LocalProtocol;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[derive(Default)]
+    struct ClosedTunnels(Mutex<Vec<(String, TunnelStats)>>);
+
+    impl TunnelEventHandler for ClosedTunnels {
+        fn on_stream_close(&self, tunnel: &TunnelInfo, stats: &TunnelStats) {
+            self.0.lock().push((tunnel.id.clone(), *stats));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_tunnel_lifecycle() {
+        let path = std::env::temp_dir().join(format!("wstunnel-audit-{}.jsonl", std::process::id()));
+        let log = Arc::new(AuditLog::open(&path).unwrap());
+        let handler = Arc::new(ClosedTunnels::default());
+        let tunnel = open_tunnel(
+            TunnelInfo {
+                id: "1".to_string(),
+                source: "192.0.2.1".parse().unwrap(),
+                forwarded_for: None,
+                path_prefix: "s3cr3t-prefix".to_string(),
+                identity: "sub:alice".to_string(),
+                subject: Some("alice".to_string()),
+                protocol: protocol_name(&LocalProtocol::Tcp { proxy_protocol: false }),
+                destination: "localhost:80".to_string(),
+                restriction: "Allow all".to_string(),
+            },
+            Some(log),
+            TunnelEvents::default(),
+            Some(handler.clone()),
+        );
+
+        let (client, mut server) = tokio::io::duplex(64);
+        let (local_rx, local_tx) = tokio::io::split(client);
+        let mut local_rx = AuditStream::new(local_rx, tunnel.clone());
+        let mut local_tx = AuditStream::new(local_tx, tunnel);
+        server.write_all(b"hello").await.unwrap();
+        local_rx.read_exact(&mut [0; 5]).await.unwrap();
+        local_tx.write_all(b"hello world").await.unwrap();
+        drop(local_rx);
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(lines.lines().count(), 1);
+        assert!(handler.0.lock().is_empty());
+        drop(local_tx);
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!lines.contains("s3cr3t"));
+        let lines: Vec<serde_json::Value> = lines.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "open");
+        assert_eq!(lines[0]["subject"], "alice");
+        assert_eq!(lines[0]["identity"], "sub:alice");
+        assert_eq!(lines[0]["protocol"], "Tcp");
+        assert!(lines[0].get("bytes_to_client").is_none());
+        assert_eq!(lines[1]["event"], "close");
+        assert_eq!(lines[1]["restriction"], "Allow all");
+        assert_eq!(lines[1]["bytes_to_client"], 5);
+        assert_eq!(lines[1]["bytes_from_client"], 11);
+
+        let closed = handler.0.lock();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].0, "1");
+        assert_eq!((closed[0].1.bytes_to_client, closed[0].1.bytes_from_client), (5, 11));
+    }
+}
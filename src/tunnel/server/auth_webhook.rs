@@ -0,0 +1,163 @@
+use crate::tunnel::server::http_client::http_request;
+use ahash::AHashMap;
+use hyper::{Method, StatusCode};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use url::Url;
+
+/// Expired decisions are purged once this many are cached
+const MAX_CACHED_DECISIONS: usize = 10_000;
+const MAX_REASON_LEN: usize = 256;
+
+/// Ok if the tunnel is allowed, otherwise the reason it is denied
+type Decision = Result<(), String>;
+
+/// Ask an external http endpoint whether a tunnel request is allowed, after the restrictions allowed it.
+/// The request is POSTed as json: 2xx allows it, 401/403 deny it with the body of the response as reason.
+/// Other responses or errors allow it only if the webhook fails open.
+pub struct AuthWebhook {
+    url: Url,
+    timeout: Duration,
+    cache_ttl: Duration,
+    fail_open: bool,
+    cache: Mutex<AHashMap<AuthWebhookRequest, (Decision, Instant)>>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
+pub(super) struct AuthWebhookRequest {
+    pub path_prefix: String,
+    pub subject: Option<String>,
+    pub source: IpAddr,
+    pub protocol: String,
+    pub host: String,
+    pub port: u16,
+    pub restriction: String,
+}
+
+impl AuthWebhook {
+    pub fn new(url: Url, timeout: Duration, cache_ttl: Duration, fail_open: bool) -> Self {
+        Self {
+            url,
+            timeout,
+            cache_ttl,
+            fail_open,
+            cache: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    pub(super) async fn authorize(&self, request: AuthWebhookRequest) -> Decision {
+        if let Some(decision) = self.cached(&request) {
+            return decision;
+        }
+
+        let decision = match self.call(&request).await {
+            Ok(decision) => decision,
+            Err(err) => {
+                warn!("Authorization webhook {} failed: {:?}", self.url, err);
+                return if self.fail_open {
+                    Ok(())
+                } else {
+                    Err("authorization webhook is unavailable".to_string())
+                };
+            }
+        };
+
+        if !self.cache_ttl.is_zero() {
+            let now = Instant::now();
+            let mut cache = self.cache.lock();
+            if cache.len() >= MAX_CACHED_DECISIONS {
+                cache.retain(|_, (_, expire_at)| *expire_at > now);
+            }
+            cache.insert(request, (decision.clone(), now + self.cache_ttl));
+        }
+
+        decision
+    }
+
+    fn cached(&self, request: &AuthWebhookRequest) -> Option<Decision> {
+        let cache = self.cache.lock();
+        let (decision, expire_at) = cache.get(request)?;
+        (*expire_at > Instant::now()).then(|| decision.clone())
+    }
+
+    /// The decision of the webhook, or an error if it did not give one
+    async fn call(&self, request: &AuthWebhookRequest) -> anyhow::Result<Decision> {
+        let body = serde_json::to_vec(request)?;
+        let (status, body) = http_request(Method::POST, &self.url, Some(body), self.timeout).await?;
+        decision(status, &body)
+    }
+}
+
+fn decision(status: StatusCode, body: &[u8]) -> anyhow::Result<Decision> {
+    if status.is_success() {
+        return Ok(Ok(()));
+    }
+    if status != StatusCode::UNAUTHORIZED && status != StatusCode::FORBIDDEN {
+        return Err(anyhow::anyhow!("unexpected http status {}", status));
+    }
+
+    let reason = String::from_utf8_lossy(body);
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Ok(Err("denied by the authorization webhook".to_string()));
+    }
+    Ok(Err(reason.chars().take(MAX_REASON_LEN).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(StatusCode::OK, "" => Some(Ok(())) ; "allowed")]
+    #[test_case(StatusCode::NO_CONTENT, "" => Some(Ok(())) ; "allowed without content")]
+    #[test_case(StatusCode::FORBIDDEN, " outside of the maintenance window\n" => Some(Err("outside of the maintenance window".to_string())) ; "denied with reason")]
+    #[test_case(StatusCode::UNAUTHORIZED, "" => Some(Err("denied by the authorization webhook".to_string())) ; "denied")]
+    #[test_case(StatusCode::INTERNAL_SERVER_ERROR, "" => None ; "error")]
+    #[test_case(StatusCode::NOT_FOUND, "" => None ; "not found")]
+    fn test_decision(status: StatusCode, body: &str) -> Option<Decision> {
+        decision(status, body.as_bytes()).ok()
+    }
+
+    fn request() -> AuthWebhookRequest {
+        AuthWebhookRequest {
+            path_prefix: "v1".to_string(),
+            subject: None,
+            source: "192.0.2.1".parse().unwrap(),
+            protocol: "Tcp".to_string(),
+            host: "localhost".to_string(),
+            port: 80,
+            restriction: "Allow all".to_string(),
+        }
+    }
+
+    #[test_case(true => Ok(()) ; "fail open")]
+    #[test_case(false => Err("authorization webhook is unavailable".to_string()) ; "fail closed")]
+    #[tokio::test]
+    async fn test_unavailable_webhook(fail_open: bool) -> Decision {
+        // Nothing listens on the discard port
+        let url = "http://127.0.0.1:9/authorize".parse().unwrap();
+        let webhook = AuthWebhook::new(url, Duration::from_secs(1), Duration::from_secs(60), fail_open);
+        webhook.authorize(request()).await
+    }
+
+    #[tokio::test]
+    async fn test_cached_decision() {
+        let url = "http://127.0.0.1:9/authorize".parse().unwrap();
+        let webhook = AuthWebhook::new(url, Duration::from_secs(1), Duration::from_secs(60), false);
+        webhook
+            .cache
+            .lock()
+            .insert(request(), (Err("denied".to_string()), Instant::now() + Duration::from_secs(60)));
+
+        assert_eq!(webhook.authorize(request()).await, Err("denied".to_string()));
+        let other = AuthWebhookRequest { port: 443, ..request() };
+        assert_eq!(
+            webhook.authorize(other).await,
+            Err("authorization webhook is unavailable".to_string())
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Sources with failures older than the find time are forgotten once this many are tracked
+const MAX_TRACKED_SOURCES: usize = 100_000;
+
+/// Ban a source once it has accumulated max_failures (failed upgrades, bad authentication, restriction violations)
+/// within find_time. It is then rejected before the TLS handshake for ban_time
+#[derive(Debug, Clone, Copy)]
+pub struct BanPolicy {
+    pub max_failures: u32,
+    pub find_time: Duration,
+    pub ban_time: Duration,
+}
+
+/// Sources of the server which are banned for abusing it, shared with the admin socket
+#[derive(Default)]
+pub struct BanList {
+    failures: Mutex<AHashMap<IpAddr, Failures>>,
+    banned: Mutex<AHashMap<IpAddr, Instant>>,
+}
+
+struct Failures {
+    count: u32,
+    first_at: Instant,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BanInfo {
+    pub ip: IpAddr,
+    pub remaining_secs: u64,
+}
+
+impl BanList {
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let mut banned = self.banned.lock();
+        match banned.get(&ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                banned.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Count a failure of this source, and ban it if it reaches the limit of the policy
+    pub fn record_failure(&self, ip: IpAddr, policy: &BanPolicy, reason: &str) {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut failures = self.failures.lock();
+        if failures.len() >= MAX_TRACKED_SOURCES {
+            failures.retain(|_, failure| now.duration_since(failure.first_at) <= policy.find_time);
+        }
+
+        let failure = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            first_at: now,
+        });
+        if now.duration_since(failure.first_at) > policy.find_time {
+            failure.count = 0;
+            failure.first_at = now;
+        }
+        failure.count += 1;
+        if failure.count < policy.max_failures {
+            return;
+        }
+
+        failures.remove(&ip);
+        drop(failures);
+        warn!(
+            "Banning {} for {:?} after {} failures, last one: {}",
+            ip, policy.ban_time, policy.max_failures, reason
+        );
+        self.banned.lock().insert(ip, now + policy.ban_time);
+    }
+
+    pub fn list(&self) -> Vec<BanInfo> {
+        let now = Instant::now();
+        let mut banned = self.banned.lock();
+        banned.retain(|_, until| *until > now);
+        banned
+            .iter()
+            .map(|(ip, until)| BanInfo {
+                ip: *ip,
+                remaining_secs: until.duration_since(now).as_secs(),
+            })
+            .collect()
+    }
+
+    /// Lift the ban of this source, or of all of them. Returns how many sources were unbanned
+    pub fn unban(&self, ip: Option<IpAddr>) -> usize {
+        let mut banned = self.banned.lock();
+        match ip {
+            Some(ip) => {
+                let ip = ip.to_canonical();
+                self.failures.lock().remove(&ip);
+                banned.remove(&ip).map_or(0, |_| 1)
+            }
+            None => {
+                self.failures.lock().clear();
+                banned.drain().count()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: BanPolicy = BanPolicy {
+        max_failures: 3,
+        find_time: Duration::from_secs(60),
+        ban_time: Duration::from_secs(3600),
+    };
+
+    #[test]
+    fn test_ban_after_max_failures() {
+        let bans = BanList::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        bans.record_failure(ip, &POLICY, "test");
+        bans.record_failure(ip, &POLICY, "test");
+        assert!(!bans.is_banned(ip));
+        bans.record_failure(ip, &POLICY, "test");
+        assert!(bans.is_banned(ip));
+        assert!(bans.is_banned("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!bans.is_banned("192.0.2.2".parse().unwrap()));
+
+        let list = bans.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].ip, ip);
+        assert!(list[0].remaining_secs > 3500);
+    }
+
+    #[test]
+    fn test_failures_expire_after_find_time() {
+        let bans = BanList::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let policy = BanPolicy {
+            find_time: Duration::ZERO,
+            ..POLICY
+        };
+
+        for _ in 0..5 {
+            bans.record_failure(ip, &policy, "test");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!bans.is_banned(ip));
+    }
+
+    #[test]
+    fn test_unban() {
+        let bans = BanList::default();
+        let policy = BanPolicy {
+            max_failures: 1,
+            ..POLICY
+        };
+        for ip in ["192.0.2.1", "192.0.2.2", "2001:db8::1"] {
+            bans.record_failure(ip.parse().unwrap(), &policy, "test");
+        }
+
+        assert_eq!(bans.unban(Some("192.0.2.1".parse().unwrap())), 1);
+        assert_eq!(bans.unban(Some("192.0.2.1".parse().unwrap())), 0);
+        assert!(!bans.is_banned("192.0.2.1".parse().unwrap()));
+        assert_eq!(bans.unban(None), 2);
+        assert!(bans.list().is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+use crate::tunnel::server::http_client::http_request;
+use anyhow::{anyhow, Context};
+use arc_swap::ArcSwap;
+use hyper::header::AUTHORIZATION;
+use hyper::{HeaderMap, Method};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use url::Url;
+
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Claims of a validated bearer token, which restrictions can match on to pick the rules of the client
+#[derive(Debug, Clone, Default)]
+pub struct BearerClaims {
+    /// Token id (jti claim), to revoke a single token
+    pub id: Option<String>,
+    pub subject: Option<String>,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenClaims {
+    jti: Option<String>,
+    sub: Option<String>,
+    // Identity providers use either an OAuth2 space separated scope, or a list in scp
+    scope: Option<Scopes>,
+    scp: Option<Scopes>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scopes {
+    Spaced(String),
+    List(Vec<String>),
+}
+
+impl From<TokenClaims> for BearerClaims {
+    fn from(claims: TokenClaims) -> Self {
+        let scopes = [claims.scope, claims.scp]
+            .into_iter()
+            .flatten()
+            .flat_map(|scopes| match scopes {
+                Scopes::Spaced(scopes) => scopes.split_whitespace().map(str::to_string).collect(),
+                Scopes::List(scopes) => scopes,
+            })
+            .collect();
+
+        Self {
+            id: claims.jti,
+            subject: claims.sub,
+            scopes,
+        }
+    }
+}
+
+enum BearerKeys {
+    Secret(DecodingKey),
+    Jwks(Arc<ArcSwap<JwkSet>>),
+}
+
+/// Authentication of the clients with a JWT given as bearer token in the upgrade request,
+/// signed with a shared secret or by an identity provider publishing its keys as a JWKS
+pub struct BearerAuth {
+    keys: BearerKeys,
+    audience: Option<String>,
+    issuer: Option<String>,
+}
+
+impl BearerAuth {
+    pub fn from_secret(secret: &[u8], audience: Option<String>, issuer: Option<String>) -> Self {
+        Self {
+            keys: BearerKeys::Secret(DecodingKey::from_secret(secret)),
+            audience,
+            issuer,
+        }
+    }
+
+    /// Fetch the keys from the JWKS url (http, https or file), and refresh them periodically to follow key rotations
+    pub async fn from_jwks(url: Url, audience: Option<String>, issuer: Option<String>) -> anyhow::Result<Self> {
+        let jwks = fetch_jwks(&url).await?;
+        info!("Loaded {} keys from JWKS {}", jwks.keys.len(), url);
+        let jwks = Arc::new(ArcSwap::from_pointee(jwks));
+
+        tokio::spawn({
+            let jwks = jwks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(JWKS_REFRESH_INTERVAL).await;
+                    match fetch_jwks(&url).await {
+                        Ok(keys) => jwks.store(Arc::new(keys)),
+                        Err(err) => warn!("Cannot refresh JWKS {}, keeping the old keys: {:?}", url, err),
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            keys: BearerKeys::Jwks(jwks),
+            audience,
+            issuer,
+        })
+    }
+
+    /// Validate the bearer token of the Authorization header: signature, expiration and the audience/issuer if configured
+    pub fn authenticate(&self, headers: &HeaderMap) -> anyhow::Result<BearerClaims> {
+        let token = bearer_token(headers).ok_or_else(|| anyhow!("missing bearer token"))?;
+        self.validate(token)
+    }
+
+    fn validate(&self, token: &str) -> anyhow::Result<BearerClaims> {
+        let header = decode_header(token)?;
+        let key = match &self.keys {
+            BearerKeys::Secret(key) => {
+                if !matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+                    return Err(anyhow!("algorithm {:?} is not allowed with a secret", header.alg));
+                }
+                key.clone()
+            }
+            BearerKeys::Jwks(jwks) => {
+                let jwks = jwks.load();
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid),
+                    None => jwks.keys.first(),
+                };
+                let jwk = jwk.ok_or_else(|| anyhow!("no key {:?} in the JWKS", header.kid))?;
+                DecodingKey::from_jwk(jwk)?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        let claims = decode::<TokenClaims>(token, &key, &validation)?.claims;
+        Ok(claims.into())
+    }
+}
+
+/// Token of the Authorization header of the request, if it is a bearer one
+pub(super) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+async fn fetch_jwks(url: &Url) -> anyhow::Result<JwkSet> {
+    let body = match url.scheme() {
+        "file" => {
+            let path = url.to_file_path().map_err(|_| anyhow!("invalid file url {}", url))?;
+            std::fs::read(&path).with_context(|| format!("cannot read JWKS file {:?}", path))?
+        }
+        "http" | "https" => {
+            let (status, body) = http_request(Method::GET, url, None, JWKS_FETCH_TIMEOUT).await?;
+            if !status.is_success() {
+                return Err(anyhow!("http error {} while fetching JWKS {}", status, url));
+            }
+            body.to_vec()
+        }
+        scheme => return Err(anyhow!("unsupported JWKS url scheme {}", scheme)),
+    };
+
+    serde_json::from_slice(&body).with_context(|| format!("invalid JWKS from {}", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::time::SystemTime;
+    use test_case::test_case;
+
+    fn token(alg: Algorithm, secret: &[u8], claims: serde_json::Value) -> String {
+        encode(&Header::new(alg), &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test_case(None, json!({"sub": "alice", "exp": now() + 60, "scope": "tunnel admin"}) => Ok((Some("alice".to_string()), vec!["tunnel".to_string(), "admin".to_string()])) ; "oauth2 scope")]
+    #[test_case(None, json!({"exp": now() + 60, "scp": ["tunnel"]}) => Ok((None, vec!["tunnel".to_string()])) ; "scp list")]
+    #[test_case(None, json!({"sub": "alice", "exp": now() - 3600}) => Err(()) ; "expired")]
+    #[test_case(None, json!({"sub": "alice"}) => Err(()) ; "no expiration")]
+    #[test_case(None, json!({"sub": "alice", "exp": now() + 60, "aud": "other"}) => Ok((Some("alice".to_string()), vec![])) ; "audience not checked")]
+    #[test_case(Some("wstunnel"), json!({"sub": "alice", "exp": now() + 60, "aud": "other"}) => Err(()) ; "wrong audience")]
+    fn test_validate_secret(
+        audience: Option<&str>,
+        claims: serde_json::Value,
+    ) -> Result<(Option<String>, Vec<String>), ()> {
+        let auth = BearerAuth::from_secret(b"secret", audience.map(str::to_string), None);
+        auth.validate(&token(Algorithm::HS256, b"secret", claims))
+            .map(|claims| (claims.subject, claims.scopes))
+            .map_err(|_| ())
+    }
+
+    #[test]
+    fn test_validate_wrong_secret() {
+        let auth = BearerAuth::from_secret(b"secret", None, None);
+        let token = token(Algorithm::HS256, b"other", json!({"sub": "alice", "exp": now() + 60}));
+        assert!(auth.validate(&token).is_err());
+    }
+
+    #[test]
+    fn test_validate_jwks() {
+        // base64url of "secret"
+        let jwks: JwkSet = serde_json::from_value(json!({
+            "keys": [{"kty": "oct", "kid": "key1", "alg": "HS256", "k": "c2VjcmV0"}]
+        }))
+        .unwrap();
+        let auth = BearerAuth {
+            keys: BearerKeys::Jwks(Arc::new(ArcSwap::from_pointee(jwks))),
+            audience: None,
+            issuer: Some("idp".to_string()),
+        };
+
+        let claims = json!({"sub": "alice", "exp": now() + 60, "iss": "idp"});
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("key1".to_string());
+        let token = encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        assert_eq!(auth.validate(&token).unwrap().subject.as_deref(), Some("alice"));
+
+        header.kid = Some("key2".to_string());
+        let token = encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(auth.validate(&token).is_err());
+    }
+
+    #[test]
+    fn test_authenticate_header() {
+        let auth = BearerAuth::from_secret(b"secret", None, None);
+        let mut headers = HeaderMap::new();
+        assert!(auth.authenticate(&headers).is_err());
+
+        let token = token(Algorithm::HS256, b"secret", json!({"sub": "alice", "exp": now() + 60}));
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        assert_eq!(auth.authenticate(&headers).unwrap().subject.as_deref(), Some("alice"));
+    }
+}
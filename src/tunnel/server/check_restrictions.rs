@@ -0,0 +1,228 @@
+use crate::config::CheckRestrictions;
+use crate::restrictions::geoip::GeoIpDatabases;
+use crate::restrictions::types::{AllowConfig, RestrictionConfig, RestrictionsRules};
+use crate::tunnel::server::bearer_auth::BearerClaims;
+use crate::tunnel::server::utils::protocol_name;
+use crate::tunnel::RemoteAddr;
+use crate::DEFAULT_CLIENT_UPGRADE_PATH_PREFIX;
+use ahash::AHashSet;
+use anyhow::{anyhow, Context};
+use std::net::IpAddr;
+
+/// Check a restrictions file, and show which restriction the server would use to allow each of the tunnel requests
+/// of a client. Fails if the file is invalid, or if one of the requests is not allowed
+pub fn check_restrictions(args: CheckRestrictions) -> anyhow::Result<()> {
+    // Errors of the yaml parser contain the line and column
+    let restrictions = RestrictionsRules::from_config_file(&args.config)
+        .with_context(|| format!("Invalid restrictions file {}", args.config.display()))?;
+    println!(
+        "{}: {} restrictions are valid",
+        args.config.display(),
+        restrictions.restrictions.len()
+    );
+    for warning in lint(&restrictions) {
+        println!("warning: {}", warning);
+    }
+
+    let geoip = GeoIpDatabases::load(&args.geoip_database)?;
+
+    let path_prefix = args
+        .path_prefix
+        .or_else(|| args.identity.clone())
+        .unwrap_or_else(|| DEFAULT_CLIENT_UPGRADE_PATH_PREFIX.to_string());
+    let claims = (args.identity.is_some() || !args.scope.is_empty()).then(|| BearerClaims {
+        id: None,
+        subject: args.identity.clone(),
+        scopes: args.scope.clone(),
+    });
+
+    let mut denied = 0;
+    for request in &args.test {
+        let remote = RemoteAddr {
+            protocol: request.protocol.clone(),
+            host: request.remote.0.clone(),
+            port: request.remote.1,
+        };
+        println!(
+            "\n{} {}:{} from {} with path prefix '{}'{}",
+            protocol_name(&remote.protocol),
+            remote.host,
+            remote.port,
+            args.source,
+            path_prefix,
+            args.identity
+                .as_ref()
+                .map(|sub| format!(" and subject '{}'", sub))
+                .unwrap_or_default()
+        );
+
+        let (restriction, explanations) =
+            explain_tunnel(&remote, &path_prefix, claims.as_ref(), args.source, &geoip, &restrictions);
+        for explanation in explanations {
+            println!("  {}", explanation);
+        }
+        match restriction {
+            Some(restriction) => println!("=> allowed by restriction '{}'", restriction.name),
+            None => {
+                println!("=> not allowed");
+                denied += 1;
+            }
+        }
+    }
+
+    if denied > 0 {
+        return Err(anyhow!("{} of {} tunnel requests are not allowed", denied, args.test.len()));
+    }
+    Ok(())
+}
+
+/// Mistakes which are valid in the configuration, but probably not what is intended
+fn lint(restrictions: &RestrictionsRules) -> Vec<String> {
+    let mut warnings = vec![];
+    let mut names = AHashSet::new();
+    for restriction in &restrictions.restrictions {
+        if !names.insert(restriction.name.as_str()) {
+            warnings.push(format!(
+                "restriction name '{}' is used several times, they share the same quotas",
+                restriction.name
+            ));
+        }
+        if restriction.allow.is_empty() {
+            warnings.push(format!(
+                "restriction '{}' has no allow rule, it never allows any tunnel",
+                restriction.name
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Same as validate_tunnel, with why each restriction before the one allowing the tunnel does not allow it
+fn explain_tunnel<'a>(
+    remote: &RemoteAddr,
+    path_prefix: &str,
+    claims: Option<&BearerClaims>,
+    client_ip: IpAddr,
+    geoip: &GeoIpDatabases,
+    restrictions: &'a RestrictionsRules,
+) -> (Option<&'a RestrictionConfig>, Vec<String>) {
+    let mut explanations = vec![];
+    for (ix, restriction) in restrictions.restrictions.iter().enumerate() {
+        let name = format!("#{} '{}'", ix + 1, restriction.name);
+        if let Err(reason) = restriction.check_client(path_prefix, claims, client_ip, geoip) {
+            explanations.push(format!("{}: {}", name, reason));
+            continue;
+        }
+
+        let mut rejections = vec![];
+        for (rule_ix, allow) in restriction.allow.iter().enumerate() {
+            let rule = match allow {
+                AllowConfig::Tunnel(_) => format!("allow #{} !Tunnel", rule_ix + 1),
+                AllowConfig::ReverseTunnel(_) => format!("allow #{} !ReverseTunnel", rule_ix + 1),
+            };
+            match allow.check(remote) {
+                Ok(()) => {
+                    explanations.push(format!("{}: client matches, tunnel allowed by {}", name, rule));
+                    return (Some(restriction), explanations);
+                }
+                Err(reason) => rejections.push(format!("{}: {}", rule, reason)),
+            }
+        }
+        explanations.push(format!(
+            "{}: client matches, but no rule allows the tunnel ({})",
+            name,
+            rejections.join(", ")
+        ));
+    }
+
+    (None, explanations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::LocalProtocol;
+    use url::Host;
+
+    const RESTRICTIONS: &str = r#"
+restrictions:
+  - name: "admins"
+    match:
+      - !JwtScope "admin"
+    allow:
+      - !Tunnel {}
+  - name: "alice"
+    match:
+      - !PathPrefix "^alice$"
+    allow:
+      - !ReverseTunnel
+        port: ["8080"]
+      - !Tunnel
+        port: ["5432"]
+        host: "^db\\.internal$"
+  - name: "alice"
+    match:
+      - !Any
+    allow: []
+"#;
+
+    fn tcp(host: &str, port: u16) -> RemoteAddr {
+        RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Domain(host.to_string()),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_explain_allowed_tunnel() {
+        let restrictions: RestrictionsRules = serde_yaml::from_str(RESTRICTIONS).unwrap();
+        let (restriction, explanations) = explain_tunnel(
+            &tcp("db.internal", 5432),
+            "alice",
+            None,
+            "127.0.0.1".parse().unwrap(),
+            &GeoIpDatabases::default(),
+            &restrictions,
+        );
+
+        assert_eq!(restriction.map(|r| r.name.as_str()), Some("alice"));
+        assert_eq!(
+            explanations,
+            vec![
+                "#1 'admins': client does not match !JwtScope admin",
+                "#2 'alice': client matches, tunnel allowed by allow #2 !Tunnel",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explain_denied_tunnel() {
+        let restrictions: RestrictionsRules = serde_yaml::from_str(RESTRICTIONS).unwrap();
+        let (restriction, explanations) = explain_tunnel(
+            &tcp("db.internal", 22),
+            "alice",
+            None,
+            "127.0.0.1".parse().unwrap(),
+            &GeoIpDatabases::default(),
+            &restrictions,
+        );
+
+        assert!(restriction.is_none());
+        assert_eq!(explanations[1], "#2 'alice': client matches, but no rule allows the tunnel (allow #1 !ReverseTunnel: not a reverse tunnel, allow #2 !Tunnel: port 22 is not in the allowed ports [5432..=5432])");
+        assert_eq!(explanations[2], "#3 'alice': client matches, but no rule allows the tunnel ()");
+    }
+
+    #[test]
+    fn test_lint() {
+        let restrictions: RestrictionsRules = serde_yaml::from_str(RESTRICTIONS).unwrap();
+        assert_eq!(
+            lint(&restrictions),
+            vec![
+                "restriction name 'alice' is used several times, they share the same quotas",
+                "restriction 'alice' has no allow rule, it never allows any tunnel",
+            ]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use crate::tunnel::RemoteAddr;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Hooks of a program embedding the server into the lifecycle of its connections, for its own logging, policy or
+/// billing. The methods are called by the tasks serving the connections, so they must not block.
+/// By default, they accept everything
+pub trait TunnelEventHandler: Send + Sync + 'static {
+    /// A client connected to the server, before its tls handshake. False closes the connection
+    fn on_connect(&self, _peer: SocketAddr) -> bool {
+        true
+    }
+
+    /// The credentials of an upgrade request have been checked. False rejects a client they authenticated anyway,
+    /// i.e: a subject without credit left
+    fn on_auth(&self, _auth: &AuthInfo) -> bool {
+        true
+    }
+
+    /// A tunnel allowed by the restrictions is about to connect to its destination. False rejects it
+    fn on_stream_open(&self, _tunnel: &TunnelInfo) -> bool {
+        true
+    }
+
+    /// Both streams of the tunnel are closed
+    fn on_stream_close(&self, _tunnel: &TunnelInfo, _stats: &TunnelStats) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthInfo {
+    pub source: IpAddr,
+    pub path_prefix: String,
+    /// Of the bearer token or the LDAP user
+    pub subject: Option<String>,
+    /// Why the credentials were rejected, None when the client is authenticated
+    pub failure: Option<String>,
+}
+
+/// Who opened a tunnel, where to, and which restriction allowed it
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelInfo {
+    pub id: String,
+    pub source: IpAddr,
+    pub forwarded_for: Option<IpAddr>,
+    /// A secret of the client, never written to the audit log nor sent with the events
+    #[serde(skip)]
+    pub path_prefix: String,
+    /// The subject of its bearer token or LDAP user, else a hash of its path prefix
+    pub identity: String,
+    pub subject: Option<String>,
+    pub protocol: String,
+    pub destination: String,
+    pub restriction: String,
+}
+
+impl TunnelInfo {
+    pub(super) fn destination(remote: &RemoteAddr) -> String {
+        format!("{}:{}", remote.host, remote.port)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TunnelStats {
+    pub bytes_from_client: u64,
+    pub bytes_to_client: u64,
+    pub duration: Duration,
+}
@@ -0,0 +1,166 @@
+use crate::tunnel::events::{TunnelEvent, TunnelEvents};
+use crate::tunnel::server::http_client::http_request;
+use crate::tunnel::spawn_until_cancelled;
+use hyper::Method;
+use serde::Serialize;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+use url::Url;
+
+/// Events waiting to be sent, the newest ones are dropped when the webhook cannot keep up
+const MAX_PENDING_EVENTS: usize = 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// POST the lifecycle events of the tunnels as json to an http endpoint, to drive alerting or automation.
+/// Events are sent in order by a background task, and retried with a backoff when the endpoint fails
+pub struct EventWebhook {
+    tx: mpsc::Sender<EventLine>,
+    cancel: CancellationToken,
+}
+
+#[derive(Debug, Serialize)]
+struct EventLine {
+    timestamp: u64,
+    #[serde(flatten)]
+    event: TunnelEvent,
+}
+
+impl EventWebhook {
+    /// Each event is tried `retries` more times when the endpoint fails or does not answer with a 2xx.
+    /// The pending events are dropped once the token is cancelled
+    pub fn new(url: Url, retries: u32, cancel: &CancellationToken) -> Self {
+        let (tx, rx) = mpsc::channel(MAX_PENDING_EVENTS);
+        spawn_until_cancelled(cancel, send_events(url, retries, rx));
+        Self {
+            tx,
+            cancel: cancel.clone(),
+        }
+    }
+
+    /// Send the events of the server from now on, until it stops
+    pub fn subscribe(self, events: &TunnelEvents) {
+        let mut rx = events.subscribe();
+        let cancel = self.cancel.clone();
+        spawn_until_cancelled(&cancel, async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => self.send(event),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Dropping {} events, the event webhook is not keeping up", missed)
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    fn send(&self, event: TunnelEvent) {
+        let line = EventLine {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            event,
+        };
+        if self.tx.try_send(line).is_err() {
+            warn!("Dropping event, the event webhook is not keeping up");
+        }
+    }
+}
+
+async fn send_events(url: Url, retries: u32, mut rx: mpsc::Receiver<EventLine>) {
+    while let Some(line) = rx.recv().await {
+        let body = match serde_json::to_vec(&line) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Cannot serialize event {:?}: {}", line, err);
+                continue;
+            }
+        };
+
+        let mut backoff = RETRY_MIN_BACKOFF;
+        for attempt in 0..=retries {
+            let err = match http_request(Method::POST, &url, Some(body.clone()), REQUEST_TIMEOUT).await {
+                Ok((status, _)) if status.is_success() => break,
+                Ok((status, _)) => format!("status {}", status),
+                Err(err) => err.to_string(),
+            };
+            if attempt == retries {
+                warn!("Dropping event, the event webhook {} failed: {}", url, err);
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::server::TunnelInfo;
+    use serde_json::json;
+
+    #[test]
+    fn test_event_line() {
+        let line = EventLine {
+            timestamp: 1,
+            event: TunnelEvent::RestrictionDenied {
+                source: "192.0.2.1".parse().unwrap(),
+                protocol: "Tcp".to_string(),
+                destination: "example.com:22".to_string(),
+                reason: "no restriction allows port 22".to_string(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&line).unwrap(),
+            json!({
+                "timestamp": 1,
+                "event": "restriction_denied",
+                "source": "192.0.2.1",
+                "protocol": "Tcp",
+                "destination": "example.com:22",
+                "reason": "no restriction allows port 22",
+            })
+        );
+
+        // The path prefix of the client is a secret, the webhook only gets its identity
+        let line = EventLine {
+            timestamp: 1,
+            event: TunnelEvent::TunnelOpened {
+                tunnel: TunnelInfo {
+                    id: "1".to_string(),
+                    source: "192.0.2.1".parse().unwrap(),
+                    forwarded_for: None,
+                    path_prefix: "s3cr3t-prefix".to_string(),
+                    identity: "path_prefix:sha256:0123456789abcdef".to_string(),
+                    subject: None,
+                    protocol: "Tcp".to_string(),
+                    destination: "example.com:22".to_string(),
+                    restriction: "Allow all".to_string(),
+                },
+            },
+        };
+        let body = serde_json::to_string(&line).unwrap();
+        assert!(!body.contains("s3cr3t"));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            json!({
+                "timestamp": 1,
+                "event": "tunnel_opened",
+                "id": "1",
+                "source": "192.0.2.1",
+                "forwarded_for": null,
+                "identity": "path_prefix:sha256:0123456789abcdef",
+                "subject": null,
+                "protocol": "Tcp",
+                "destination": "example.com:22",
+                "restriction": "Allow all",
+            })
+        );
+    }
+}
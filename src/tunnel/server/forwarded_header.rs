@@ -0,0 +1,139 @@
+use pin_project::pin_project;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+
+/// Longest request line waited for, beyond it the destination is not considered to speak http
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+
+/// Add a header with the address of the client after the request line of the first http request written to the
+/// destination. Following requests of the same connection are forwarded as is. If the request line is not complete
+/// when the data is flushed, the destination is not considered to speak http and nothing is added
+#[pin_project]
+pub(super) struct ForwardedHeaderStream<T> {
+    #[pin]
+    inner: T,
+    /// Header line to add, until it is added or given up on
+    header: Option<Vec<u8>>,
+    /// Beginning of the request line, not written yet
+    request_line: Vec<u8>,
+    /// Accepted from the caller, but not written to the destination yet
+    pending: Vec<u8>,
+}
+
+impl<T> ForwardedHeaderStream<T> {
+    pub(super) fn new(inner: T, header_name: &str, client_ip: IpAddr) -> Self {
+        Self {
+            inner,
+            header: Some(format!("{}: {}\r\n", header_name, client_ip.to_canonical()).into_bytes()),
+            request_line: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<T: AsyncWrite> ForwardedHeaderStream<T> {
+    fn poll_write_pending(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        while !this.pending.is_empty() {
+            let len = ready!(this.inner.as_mut().poll_write(cx, this.pending))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.pending.drain(..len);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Forward the beginning of the request line as is
+    fn give_up(self: Pin<&mut Self>) {
+        let this = self.project();
+        *this.header = None;
+        this.pending.append(this.request_line);
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for ForwardedHeaderStream<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.as_mut().poll_write_pending(cx))?;
+
+        let this = self.as_mut().project();
+        let Some(header) = this.header.take() else {
+            return this.inner.poll_write(cx, buf);
+        };
+
+        let Some(end) = buf.iter().position(|c| *c == b'\n') else {
+            this.request_line.extend_from_slice(buf);
+            *this.header = Some(header);
+            if this.request_line.len() > MAX_REQUEST_LINE {
+                self.give_up();
+            }
+            return Poll::Ready(Ok(buf.len()));
+        };
+
+        this.pending.append(this.request_line);
+        this.pending.extend_from_slice(&buf[..=end]);
+        this.pending.extend_from_slice(&header);
+        Poll::Ready(Ok(end + 1))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.request_line.is_empty() {
+            self.as_mut().give_up();
+        }
+        ready!(self.as_mut().poll_write_pending(cx))?;
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.request_line.is_empty() {
+            self.as_mut().give_up();
+        }
+        ready!(self.as_mut().poll_write_pending(cx))?;
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    async fn forward(writes: &[&[u8]]) -> String {
+        let mut stream = ForwardedHeaderStream::new(Vec::new(), "X-Forwarded-For", "::ffff:10.0.0.1".parse().unwrap());
+        for data in writes {
+            stream.write_all(data).await.unwrap();
+        }
+        stream.shutdown().await.unwrap();
+        String::from_utf8(stream.inner).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_header() {
+        assert_eq!(
+            forward(&[b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\nGET /other HTTP/1.1\r\n\r\n"]).await,
+            "GET / HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\nHost: example.com\r\n\r\nGET /other HTTP/1.1\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_header_split_request_line() {
+        assert_eq!(
+            forward(&[b"GET / HT", b"TP/1.1\r\nHost: example.com\r\n\r\n"]).await,
+            "GET / HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\nHost: example.com\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_header_not_http() {
+        let mut stream = ForwardedHeaderStream::new(Vec::new(), "X-Forwarded-For", "10.0.0.1".parse().unwrap());
+        stream.write_all(b"\x16\x03\x01").await.unwrap();
+        stream.flush().await.unwrap();
+        stream.write_all(b"binary\r\n").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(stream.inner, b"\x16\x03\x01binary\r\n");
+    }
+}
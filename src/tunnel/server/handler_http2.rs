@@ -1,13 +1,14 @@
 use crate::restrictions::types::RestrictionsRules;
-use crate::tunnel::server::utils::{bad_request, inject_cookie, HttpResponse};
+use crate::tunnel::server::utils::{bad_request, failure_reason, inject_cookie, HttpResponse};
 use crate::tunnel::server::WsServer;
+use crate::tunnel::spawn_until_cancelled;
 use crate::tunnel::transport;
 use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
 use bytes::Bytes;
 use futures_util::StreamExt;
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyStream, Either, StreamBody};
-use hyper::body::{Frame, Incoming};
+use http_body_util::{Either, StreamBody};
+use hyper::body::{Body, Frame};
 use hyper::header::CONTENT_TYPE;
 use hyper::{Request, Response, StatusCode};
 use std::net::SocketAddr;
@@ -16,23 +17,36 @@ use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::ReceiverStream;
 use tracing::{Instrument, Span};
 
-pub(super) async fn http_server_upgrade(
+pub(super) async fn http_server_upgrade<B>(
     server: WsServer,
     restrictions: Arc<RestrictionsRules>,
     restrict_path_prefix: Option<String>,
     client_addr: SocketAddr,
-    mut req: Request<Incoming>,
-) -> HttpResponse {
-    let (remote_addr, local_rx, local_tx, need_cookie) = match server
+    req: Request<B>,
+) -> HttpResponse
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<anyhow::Error>,
+{
+    // Only the head of the request is borrowed while it is checked, the body is not always Sync
+    let (parts, ws_rx) = req.into_parts();
+    let mut req = Request::from_parts(parts, ());
+    let (remote_addr, local_rx, local_tx, need_cookie, response_headers) = match server
         .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
         .await
     {
         Ok(ret) => ret,
-        Err(err) => return err,
+        Err(err) => {
+            server
+                .config
+                .instance
+                .active_tunnels
+                .record_failure(failure_reason(&err));
+            return err;
+        }
     };
 
     let req_content_type = req.headers_mut().remove(CONTENT_TYPE);
-    let ws_rx = BodyStream::new(req.into_body());
     let (ws_tx, rx) = mpsc::channel::<Bytes>(1024);
     let body = BoxBody::new(StreamBody::new(
         ReceiverStream::new(rx).map(|s| -> anyhow::Result<Frame<Bytes>> { Ok(Frame::data(s)) }),
@@ -43,16 +57,31 @@ pub(super) async fn http_server_upgrade(
         .body(Either::Right(body))
         .expect("bug: failed to build response");
 
-    tokio::spawn(
+    let slow_consumer_timeout = server.config.slow_consumer_timeout();
+    let cancel = server.config.cancel.clone();
+    spawn_until_cancelled(
+        &server.config.cancel,
         async move {
             let (close_tx, close_rx) = oneshot::channel::<()>();
-            tokio::task::spawn(
-                transport::io::propagate_remote_to_local(local_tx, Http2TunnelRead::new(ws_rx), close_rx)
-                    .instrument(Span::current()),
+            spawn_until_cancelled(
+                &cancel,
+                transport::io::propagate_remote_to_local(
+                    local_tx,
+                    Http2TunnelRead::new(ws_rx),
+                    close_rx,
+                    slow_consumer_timeout.clone(),
+                )
+                .instrument(Span::current()),
             );
 
-            let _ =
-                transport::io::propagate_local_to_remote(local_rx, Http2TunnelWrite::new(ws_tx), close_tx, None).await;
+            let _ = transport::io::propagate_local_to_remote(
+                local_rx,
+                Http2TunnelWrite::new(ws_tx),
+                close_tx,
+                None,
+                slow_consumer_timeout,
+            )
+            .await;
         }
         .instrument(Span::current()),
     );
@@ -60,6 +89,7 @@ pub(super) async fn http_server_upgrade(
     if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
         return bad_request();
     }
+    response.headers_mut().extend(response_headers);
 
     if let Some(content_type) = req_content_type {
         response.headers_mut().insert(CONTENT_TYPE, content_type);
@@ -0,0 +1,88 @@
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::server::utils::{failure_reason, inject_cookie};
+use crate::tunnel::server::WsServer;
+use crate::tunnel::spawn_until_cancelled;
+use crate::tunnel::transport;
+use crate::tunnel::transport::pluggable::{StreamTunnelRead, StreamTunnelWrite};
+use crate::tunnel::transport::{TransportRead, TransportWrite, TunnelTransport};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{Request, Response, StatusCode};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tracing::{error, warn, Instrument, Span};
+
+/// Serve the single tunnel of a connection accepted by the server, over the transport of a third party
+pub(super) async fn transport_server_upgrade(
+    server: WsServer,
+    restrictions: Arc<RestrictionsRules>,
+    restrict_path_prefix: Option<String>,
+    client_addr: SocketAddr,
+    transport: Arc<dyn TunnelTransport>,
+    (rx, tx): (TransportRead, TransportWrite),
+) {
+    let (parts, rx, mut tx) = match transport.accept(rx, tx).await {
+        Ok(ret) => ret,
+        Err(err) => {
+            warn!("Rejecting connection with bad upgrade request: {:?}", err);
+            server.record_failure(client_addr.ip(), "bad upgrade request");
+            return;
+        }
+    };
+    let req = Request::from_parts(parts, ());
+
+    let (remote_addr, local_rx, local_tx, need_cookie, response_headers) = match server
+        .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
+        .await
+    {
+        Ok(ret) => ret,
+        Err(err) => {
+            server
+                .config
+                .instance
+                .active_tunnels
+                .record_failure(failure_reason(&err));
+            let _ = transport.respond(&mut tx, err.into_parts().0).await;
+            return;
+        }
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .body(Empty::<Bytes>::new())
+        .expect("bug: failed to build response");
+    if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
+        error!("Cannot send the tunnel info to the client");
+        return;
+    }
+    response.headers_mut().extend(response_headers);
+    if let Err(err) = transport.respond(&mut tx, response.into_parts().0).await {
+        error!("Error while sending the upgrade response: {:?}", err);
+        return;
+    }
+
+    let slow_consumer_timeout = server.config.slow_consumer_timeout();
+    let (close_tx, close_rx) = oneshot::channel::<()>();
+    let cancel = &server.config.cancel;
+    spawn_until_cancelled(
+        cancel,
+        transport::io::propagate_remote_to_local(
+            local_tx,
+            StreamTunnelRead::new(rx),
+            close_rx,
+            slow_consumer_timeout.clone(),
+        )
+        .instrument(Span::current()),
+    );
+
+    let _ = cancel
+        .run_until_cancelled(transport::io::propagate_local_to_remote(
+            local_rx,
+            StreamTunnelWrite::new(tx),
+            close_tx,
+            None,
+            slow_consumer_timeout,
+        ))
+        .await;
+}
@@ -1,12 +1,13 @@
 use crate::restrictions::types::RestrictionsRules;
-use crate::tunnel::server::utils::{bad_request, inject_cookie, HttpResponse};
+use crate::secret::redact_path_prefix;
+use crate::tunnel::server::utils::{bad_request, failure_reason, inject_cookie, HttpResponse};
 use crate::tunnel::server::WsServer;
+use crate::tunnel::spawn_until_cancelled;
 use crate::tunnel::transport;
 use crate::tunnel::transport::websocket::mk_websocket_tunnel;
 use fastwebsockets::Role;
 use http_body_util::combinators::BoxBody;
 use http_body_util::Either;
-use hyper::body::Incoming;
 use hyper::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
 use hyper::{Request, Response};
 use std::net::SocketAddr;
@@ -14,36 +15,55 @@ use std::sync::Arc;
 use tokio::sync::oneshot;
 use tracing::{error, warn, Instrument, Span};
 
-pub(super) async fn ws_server_upgrade(
+pub(super) async fn ws_server_upgrade<B>(
     server: WsServer,
     restrictions: Arc<RestrictionsRules>,
     restrict_path_prefix: Option<String>,
     client_addr: SocketAddr,
-    mut req: Request<Incoming>,
+    req: Request<B>,
 ) -> HttpResponse {
+    // The body of an upgrade request is empty, and not always Sync
+    let mut req = req.map(|_| ());
     if !fastwebsockets::upgrade::is_upgrade_request(&req) {
-        warn!("Rejecting connection with bad upgrade request: {}", req.uri());
+        warn!(
+            "Rejecting connection with bad upgrade request: {}",
+            redact_path_prefix(req.uri().path())
+        );
+        server.record_failure(client_addr.ip(), "bad upgrade request");
         return bad_request();
     }
 
     let mask_frame = server.config.websocket_mask_frame;
-    let (remote_addr, local_rx, local_tx, need_cookie) = match server
+    let (remote_addr, local_rx, local_tx, need_cookie, response_headers) = match server
         .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
         .await
     {
         Ok(ret) => ret,
-        Err(err) => return err,
+        Err(err) => {
+            server
+                .config
+                .instance
+                .active_tunnels
+                .record_failure(failure_reason(&err));
+            return err;
+        }
     };
 
     let (response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
         Ok(ret) => ret,
         Err(err) => {
-            warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
+            warn!(
+                "Rejecting connection with bad upgrade request: {} {}",
+                err,
+                redact_path_prefix(req.uri().path())
+            );
             return bad_request();
         }
     };
 
-    tokio::spawn(
+    let cancel = server.config.cancel.clone();
+    spawn_until_cancelled(
+        &cancel,
         async move {
             let (ws_rx, ws_tx) = match fut.await {
                 Ok(ws) => match mk_websocket_tunnel(ws, Role::Server, mask_frame) {
@@ -60,8 +80,11 @@ pub(super) async fn ws_server_upgrade(
             };
             let (close_tx, close_rx) = oneshot::channel::<()>();
 
-            tokio::task::spawn(
-                transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).instrument(Span::current()),
+            let slow_consumer_timeout = server.config.slow_consumer_timeout();
+            spawn_until_cancelled(
+                &server.config.cancel,
+                transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx, slow_consumer_timeout.clone())
+                    .instrument(Span::current()),
             );
 
             let _ = transport::io::propagate_local_to_remote(
@@ -69,6 +92,7 @@ pub(super) async fn ws_server_upgrade(
                 ws_tx,
                 close_tx,
                 server.config.websocket_ping_frequency,
+                slow_consumer_timeout,
             )
             .await;
             Ok(())
@@ -80,6 +104,7 @@ pub(super) async fn ws_server_upgrade(
     if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
         return bad_request();
     }
+    response.headers_mut().extend(response_headers);
 
     response
         .headers_mut()
@@ -0,0 +1,100 @@
+use crate::protocols;
+use crate::protocols::dns::DnsResolver;
+use crate::somark::SoMark;
+use anyhow::anyhow;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{ACCEPT, CONTENT_TYPE, HOST, LOCATION, USER_AGENT};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::pki_types::ServerName;
+use url::{Position, Url};
+
+/// Minimal http(s) client for the services wstunnel relies on (JWKS, authorization webhook, error reports).
+/// Sends a json request on a new connection and returns the status and body of the response
+pub(crate) async fn http_request(
+    method: Method,
+    url: &Url,
+    json_body: Option<Vec<u8>>,
+    timeout: Duration,
+) -> anyhow::Result<(StatusCode, Bytes)> {
+    let response = tokio::time::timeout(timeout, send(method, url, json_body, "application/json", timeout))
+        .await
+        .map_err(|_| anyhow!("timeout while requesting {}", url))??;
+    Ok((response.status(), response.into_body()))
+}
+
+/// Download the body of a file, following the redirects of the servers hosting the releases
+pub(crate) async fn http_download(url: &Url, timeout: Duration) -> anyhow::Result<Bytes> {
+    const MAX_REDIRECTS: usize = 5;
+
+    let download = async {
+        let mut url = url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let response = send(Method::GET, &url, None, "*/*", timeout).await?;
+            if !response.status().is_redirection() {
+                return match response.status() {
+                    StatusCode::OK => Ok(response.into_body()),
+                    status => Err(anyhow!("{} answered {}", url, status)),
+                };
+            }
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .ok_or_else(|| anyhow!("{} answered {} without location", url, response.status()))?;
+            url = url.join(location.to_str()?)?;
+        }
+        Err(anyhow!("too many redirects while downloading {}", url))
+    };
+
+    tokio::time::timeout(timeout, download)
+        .await
+        .map_err(|_| anyhow!("timeout while downloading {}", url))?
+}
+
+async fn send(
+    method: Method,
+    url: &Url,
+    json_body: Option<Vec<u8>>,
+    accept: &str,
+    timeout: Duration,
+) -> anyhow::Result<Response<Bytes>> {
+    let host = url.host().ok_or_else(|| anyhow!("no host in url {}", url))?.to_owned();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let stream = protocols::tcp::connect(&host, port, SoMark::new(None), timeout, &DnsResolver::default()).await?;
+
+    let mut req = Request::builder()
+        .method(method)
+        .uri(&url[Position::BeforePath..])
+        .header(HOST, &url[Position::BeforeHost..Position::AfterPort])
+        .header(ACCEPT, accept)
+        .header(USER_AGENT, concat!("wstunnel/", env!("CARGO_PKG_VERSION")));
+    if json_body.is_some() {
+        req = req.header(CONTENT_TYPE, "application/json");
+    }
+    let req = req.body(Full::new(Bytes::from(json_body.unwrap_or_default())))?;
+
+    match url.scheme() {
+        "https" => {
+            let tls_connector = protocols::tls::tls_connector(true, vec![b"http/1.1".to_vec()], true, None, None)?;
+            let server_name = ServerName::try_from(host.to_string())?;
+            let stream = tls_connector.connect(server_name, stream).await?;
+            send_request(stream, req).await
+        }
+        "http" => send_request(stream, req).await,
+        scheme => Err(anyhow!("unsupported url scheme {}", scheme)),
+    }
+}
+
+async fn send_request<S>(stream: S, req: Request<Full<Bytes>>) -> anyhow::Result<Response<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(cnx);
+
+    let (parts, body) = sender.send_request(req).await?.into_parts();
+    Ok(Response::from_parts(parts, body.collect().await?.to_bytes()))
+}
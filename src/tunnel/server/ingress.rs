@@ -0,0 +1,279 @@
+use crate::tunnel::server::reverse_tunnel::{ReverseTunnelRegistry, ReverseTunnelServer};
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use ahash::AHashMap;
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::io;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, span, warn, Instrument, Level};
+use url::Host;
+
+/// Maximum size of the head of the first request of a connection, which contains the Host header used to route it
+const MAX_REQUEST_HEAD_SIZE: usize = 16 * 1024;
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+type IngressConnection = anyhow::Result<((IngressReader, IngressWriter), RemoteAddr)>;
+type IngressListener = ReceiverStream<IngressConnection>;
+
+/// Read half of a connection accepted by the ingress, plain or tls.
+/// Not a type alias of the boxed stream, else the futures of the reverse tunnel server are not provably Send
+pub struct IngressReader(Pin<Box<dyn AsyncRead + Send>>);
+
+impl AsyncRead for IngressReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_read(cx, buf)
+    }
+}
+
+/// Write half of a connection accepted by the ingress, plain or tls
+pub struct IngressWriter(Pin<Box<dyn AsyncWrite + Send>>);
+
+impl AsyncWrite for IngressWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.0.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_shutdown(cx)
+    }
+}
+
+/// Hostnames served by the http ingress, each one routed to the reverse tunnels of the client which registered it
+pub struct HttpIngress {
+    routes: Arc<Mutex<AHashMap<String, mpsc::Sender<IngressConnection>>>>,
+    tunnels: Mutex<AHashMap<String, Arc<ReverseTunnelServer<IngressListener>>>>,
+    registry: Arc<ReverseTunnelRegistry>,
+}
+
+impl HttpIngress {
+    pub fn new(registry: Arc<ReverseTunnelRegistry>) -> Self {
+        Self {
+            routes: Arc::new(Mutex::new(AHashMap::new())),
+            tunnels: Mutex::new(AHashMap::new()),
+            registry,
+        }
+    }
+
+    /// Reverse tunnel server of the hostname, and the future registering its route when it needs to be started.
+    /// Like other reverse tunnels, the clients of the same owner share the route and connections are balanced
+    /// between them.
+    pub fn route(
+        &self,
+        hostname: &str,
+        owner: &str,
+    ) -> (
+        Arc<ReverseTunnelServer<IngressListener>>,
+        impl Future<Output = anyhow::Result<IngressListener>> + Send + 'static,
+    ) {
+        let hostname = hostname.to_ascii_lowercase();
+        let tunnels = self
+            .tunnels
+            .lock()
+            .entry(hostname.clone())
+            .or_insert_with(|| Arc::new(ReverseTunnelServer::new("http-ingress", self.registry.clone())))
+            .clone();
+
+        let routes = self.routes.clone();
+        let owner = owner.to_string();
+        let register_route = async move {
+            let (tx, rx) = mpsc::channel(10);
+            info!("Routing http ingress requests for host {} to client '{}'", hostname, owner);
+            routes.lock().insert(hostname, tx);
+            Ok(ReceiverStream::new(rx))
+        };
+
+        (tunnels, register_route)
+    }
+
+    async fn route_connection<R, W>(
+        &self,
+        mut rx: R,
+        mut tx: W,
+        peer_addr: SocketAddr,
+        bind: SocketAddr,
+        scheme: &str,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (head, head_len) = tokio::time::timeout(REQUEST_HEAD_TIMEOUT, read_request_head(&mut rx))
+            .await
+            .map_err(|_| anyhow!("timeout while reading the request head"))??;
+        let Some(hostname) = find_host(&head[..head_len]) else {
+            tx.write_all(&error_response("400 Bad Request", "Missing Host header"))
+                .await?;
+            return Err(anyhow!("request without Host header"));
+        };
+
+        let route = self
+            .routes
+            .lock()
+            .get(&hostname)
+            .filter(|route| !route.is_closed())
+            .cloned();
+        let Some(route) = route else {
+            tx.write_all(&error_response("404 Not Found", &format!("No tunnel for host {}", hostname)))
+                .await?;
+            return Err(anyhow!("no tunnel for host {}", hostname));
+        };
+
+        info!("Routing http ingress request for host {}", hostname);
+        let head = add_forwarded_headers(head, head_len, peer_addr.ip(), scheme);
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::ReverseHttpIngress {
+                hostname: hostname.clone(),
+            },
+            host: Host::Domain(hostname.clone()),
+            port: bind.port(),
+        };
+        let rx = IngressReader(Box::pin(Cursor::new(head).chain(rx)));
+        let tx = IngressWriter(Box::pin(tx));
+        route
+            .send(Ok(((rx, tx), remote)))
+            .await
+            .map_err(|_| anyhow!("tunnel for host {} is closed", hostname))
+    }
+}
+
+/// Accept the http(s) connections of the ingress listener, and route them to the reverse tunnels matching
+/// the Host header of their first request. With a tls acceptor, tls is terminated by the ingress.
+pub async fn run_ingress_server(
+    listener: TcpListener,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    ingress: Arc<HttpIngress>,
+) -> anyhow::Result<()> {
+    let bind = listener.local_addr()?;
+    let scheme = if tls_acceptor.is_some() { "https" } else { "http" };
+    info!("Starting http ingress listening on {}://{}", scheme, bind);
+
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(ret) => ret,
+            Err(err) => {
+                warn!("Error while accepting http ingress connection {:?}", err);
+                continue;
+            }
+        };
+
+        let span = span!(Level::INFO, "ingress", peer = peer_addr.to_string());
+        let tls_acceptor = tls_acceptor.clone();
+        let ingress = ingress.clone();
+        let fut = async move {
+            let ret = match tls_acceptor {
+                Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let (rx, tx) = tokio::io::split(stream);
+                        ingress.route_connection(rx, tx, peer_addr, bind, scheme).await
+                    }
+                    Err(err) => Err(anyhow!("error while accepting TLS connection {}", err)),
+                },
+                None => {
+                    let (rx, tx) = stream.into_split();
+                    ingress.route_connection(rx, tx, peer_addr, bind, scheme).await
+                }
+            };
+
+            if let Err(err) = ret {
+                warn!("Cannot route http ingress connection: {:?}", err);
+            }
+        };
+        tokio::spawn(fut.instrument(span));
+    }
+}
+
+/// Read until the end of the head of the request. Return everything read, and the length of the head
+async fn read_request_head(rx: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<(Vec<u8>, usize)> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok((buf, pos + 4));
+        }
+        if buf.len() > MAX_REQUEST_HEAD_SIZE {
+            return Err(anyhow!("request head is bigger than {} bytes", MAX_REQUEST_HEAD_SIZE));
+        }
+        if rx.read_buf(&mut buf).await? == 0 {
+            return Err(anyhow!("connection closed before the end of the request head"));
+        }
+    }
+}
+
+/// Hostname of the Host header, without its port
+fn find_host(head: &[u8]) -> Option<String> {
+    let head = std::str::from_utf8(head).ok()?;
+    let host = head
+        .split("\r\n")
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim())?;
+
+    let host = host
+        .rsplit_once(':')
+        .filter(|(_, port)| port.parse::<u16>().is_ok())
+        .map_or(host, |(host, _)| host);
+    if host.is_empty() {
+        return None;
+    }
+
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Let the application know who is the real client, only for the first request of the connection
+fn add_forwarded_headers(mut request: Vec<u8>, head_len: usize, client_ip: IpAddr, scheme: &str) -> Vec<u8> {
+    let headers = format!("X-Forwarded-For: {}\r\nX-Forwarded-Proto: {}\r\n", client_ip, scheme);
+    let end_of_headers = head_len - 2;
+    request.splice(end_of_headers..end_of_headers, headers.into_bytes());
+    request
+}
+
+fn error_response(status: &str, msg: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        msg.len(),
+        msg
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("GET / HTTP/1.1\r\nHost: app.example.com\r\n\r\n" => Some("app.example.com".to_string()) ; "host")]
+    #[test_case("GET / HTTP/1.1\r\nAccept: */*\r\nhost:App.Example.com:8443\r\n\r\n" => Some("app.example.com".to_string()) ; "host with port")]
+    #[test_case("GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n" => Some("[::1]".to_string()) ; "ipv6 host")]
+    #[test_case("GET / HTTP/1.1\r\nAccept: */*\r\n\r\n" => None ; "no host")]
+    #[test_case("GET / HTTP/1.1\r\nHost: \r\n\r\n" => None ; "empty host")]
+    fn test_find_host(head: &str) -> Option<String> {
+        find_host(head.as_bytes())
+    }
+
+    #[test]
+    fn test_add_forwarded_headers() {
+        let request = b"POST / HTTP/1.1\r\nHost: app\r\n\r\nbody".to_vec();
+        let request = add_forwarded_headers(request, 30, IpAddr::from([1, 2, 3, 4]), "https");
+        assert_eq!(
+            String::from_utf8(request).unwrap(),
+            "POST / HTTP/1.1\r\nHost: app\r\nX-Forwarded-For: 1.2.3.4\r\nX-Forwarded-Proto: https\r\n\r\nbody"
+        );
+    }
+}
@@ -0,0 +1,627 @@
+use crate::protocols;
+use crate::protocols::dns::DnsResolver;
+use crate::secret::constant_time_eq;
+use crate::somark::SoMark;
+use crate::tunnel::server::bearer_auth::BearerClaims;
+use ahash::AHashMap;
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use hyper::header::AUTHORIZATION;
+use hyper::HeaderMap;
+use parking_lot::Mutex;
+use ring::digest;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::rustls::pki_types::ServerName;
+use url::Url;
+
+const LDAP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Successful logins are remembered for a while, to not bind to the directory for every tunnel of a client
+const CACHE_TTL: Duration = Duration::from_secs(60);
+/// Expired logins are purged once this many are remembered
+const MAX_CACHED_LOGINS: usize = 10_000;
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+const USERNAME_PLACEHOLDER: &str = "{username}";
+const RESULT_INVALID_CREDENTIALS: u8 = 49;
+
+/// Authentication of the clients with a username and password (Authorization: Basic) in the upgrade request, checked
+/// by binding as the user to an LDAP or Active Directory server. The DNs of the groups of the user, from its memberOf
+/// attribute, are given to the restrictions as scopes
+pub struct LdapAuth {
+    url: Url,
+    /// DN to bind with, where {username} is replaced by the escaped username
+    bind_dn: String,
+    base_dn: String,
+    /// Attribute holding the username in the entry of the user, to find its groups
+    user_attribute: String,
+    logins: Mutex<AHashMap<String, CachedLogin>>,
+}
+
+struct CachedLogin {
+    password_digest: digest::Digest,
+    expire_at: Instant,
+    claims: BearerClaims,
+}
+
+impl LdapAuth {
+    pub fn new(url: Url, bind_dn: String, base_dn: String, user_attribute: String) -> anyhow::Result<Self> {
+        if !matches!(url.scheme(), "ldap" | "ldaps") {
+            return Err(anyhow!("Invalid LDAP url {}, expected ldap:// or ldaps://", url));
+        }
+        if url.host().is_none() {
+            return Err(anyhow!("Invalid LDAP url {}, no host", url));
+        }
+        if !bind_dn.contains(USERNAME_PLACEHOLDER) {
+            return Err(anyhow!("The LDAP bind DN {} must contain {}", bind_dn, USERNAME_PLACEHOLDER));
+        }
+
+        Ok(Self {
+            url,
+            bind_dn,
+            base_dn,
+            user_attribute,
+            logins: Mutex::new(AHashMap::new()),
+        })
+    }
+
+    /// Check the username and password of the Authorization header against the directory
+    pub async fn authenticate(&self, headers: &HeaderMap) -> anyhow::Result<BearerClaims> {
+        let (username, password) = basic_credentials(headers).ok_or_else(|| anyhow!("missing basic credentials"))?;
+        // An empty password is an unauthenticated bind, which succeeds for any user
+        if username.is_empty() || password.is_empty() {
+            return Err(anyhow!("empty username or password"));
+        }
+
+        let password_digest = digest::digest(&digest::SHA256, password.as_bytes());
+        if let Some(login) = self.logins.lock().get(&username) {
+            if login.expire_at > Instant::now()
+                && constant_time_eq(login.password_digest.as_ref(), password_digest.as_ref())
+            {
+                return Ok(login.claims.clone());
+            }
+        }
+
+        let groups = tokio::time::timeout(LDAP_TIMEOUT, self.login(&username, &password))
+            .await
+            .map_err(|_| anyhow!("timeout of the LDAP server {}", self.url))??;
+        let claims = BearerClaims {
+            id: None,
+            subject: Some(username.clone()),
+            scopes: groups,
+        };
+
+        let mut logins = self.logins.lock();
+        if logins.len() >= MAX_CACHED_LOGINS {
+            let now = Instant::now();
+            logins.retain(|_, login| login.expire_at > now);
+        }
+        logins.insert(
+            username,
+            CachedLogin {
+                password_digest,
+                expire_at: Instant::now() + CACHE_TTL,
+                claims: claims.clone(),
+            },
+        );
+
+        Ok(claims)
+    }
+
+    /// Bind as the user, and return the normalized DNs of its groups
+    async fn login(&self, username: &str, password: &str) -> anyhow::Result<Vec<String>> {
+        let host = self.url.host().context("no host in LDAP url")?.to_owned();
+        let port = self
+            .url
+            .port()
+            .unwrap_or(if self.url.scheme() == "ldaps" { 636 } else { 389 });
+        let stream =
+            protocols::tcp::connect(&host, port, SoMark::new(None), LDAP_TIMEOUT, &DnsResolver::default()).await?;
+        let bind_dn = self.bind_dn.replace(USERNAME_PLACEHOLDER, &escape_dn_value(username));
+
+        if self.url.scheme() == "ldaps" {
+            let tls_connector = protocols::tls::tls_connector(true, vec![], true, None, None)?;
+            let stream = tls_connector
+                .connect(ServerName::try_from(host.to_string())?, stream)
+                .await?;
+            self.session(stream, &bind_dn, username, password).await
+        } else {
+            self.session(stream, &bind_dn, username, password).await
+        }
+    }
+
+    async fn session<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+        bind_dn: &str,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        stream.write_all(&bind_request(1, bind_dn, password)).await?;
+        let (op, response) = read_response(&mut stream).await?;
+        if op != TAG_BIND_RESPONSE {
+            return Err(anyhow!("unexpected response {:#04x} of the LDAP server to bind", op));
+        }
+        check_result(&response, "bind")?;
+
+        stream
+            .write_all(&search_request(2, &self.base_dn, &self.user_attribute, username))
+            .await?;
+        let mut groups = vec![];
+        loop {
+            let (op, response) = read_response(&mut stream).await?;
+            match op {
+                TAG_SEARCH_RESULT_ENTRY => groups.extend(member_of(&response)?.iter().map(|dn| normalize_dn(dn))),
+                TAG_SEARCH_RESULT_DONE => {
+                    check_result(&response, "search")?;
+                    break;
+                }
+                // References to other servers are not followed
+                _ => {}
+            }
+        }
+
+        let _ = stream.write_all(&unbind_request(3)).await;
+        Ok(groups)
+    }
+}
+
+/// Username and password of the Authorization: Basic header
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let credentials = headers
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Basic "))
+        .and_then(|credentials| {
+            base64::engine::general_purpose::STANDARD
+                .decode(credentials.trim())
+                .ok()
+        })
+        .and_then(|credentials| String::from_utf8(credentials).ok())?;
+    let (username, password) = credentials.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Escape the special characters of a DN attribute value (RFC 4514), so a username cannot change the bound DN
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (ix, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' | ' ' if ix == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' if ix == last => escaped.push_str("\\ "),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// DN of a group as its scope: the whole DN, so groups with the same CN in other OUs are other scopes, normalized with
+/// the attribute types in lowercase, without spaces around the separators and with the values escaped the same way
+/// (RFC 4514). i.e: 'CN=VPN Users, OU=Groups,DC=corp' is 'cn=VPN Users,ou=Groups,dc=corp'
+fn normalize_dn(dn: &str) -> String {
+    let bytes = dn.as_bytes();
+    let mut normalized = String::with_capacity(dn.len());
+    let mut ix = 0;
+    loop {
+        let Some(eq) = bytes[ix..].iter().position(|b| *b == b'=') else {
+            return dn.trim().to_string();
+        };
+        let attribute = dn[ix..ix + eq].trim().to_ascii_lowercase();
+        ix += eq + 1;
+
+        let mut value = vec![];
+        // Without the spaces at its end, unless they are escaped
+        let mut value_len = 0;
+        let separator = loop {
+            match bytes.get(ix) {
+                None => break None,
+                Some(separator @ (b',' | b'+' | b';')) => {
+                    ix += 1;
+                    break Some(*separator);
+                }
+                Some(b'\\') => {
+                    match bytes
+                        .get(ix + 1..ix + 3)
+                        .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    {
+                        Some(hex) => {
+                            let hex = std::str::from_utf8(hex).unwrap_or_default();
+                            value.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+                            ix += 3;
+                        }
+                        None => {
+                            let Some(escaped) = bytes.get(ix + 1) else {
+                                return dn.trim().to_string();
+                            };
+                            value.push(*escaped);
+                            ix += 2;
+                        }
+                    }
+                    value_len = value.len();
+                }
+                Some(b' ') if value.is_empty() => ix += 1,
+                Some(c) => {
+                    value.push(*c);
+                    ix += 1;
+                    if *c != b' ' {
+                        value_len = value.len();
+                    }
+                }
+            }
+        };
+        value.truncate(value_len);
+
+        normalized.push_str(&attribute);
+        normalized.push('=');
+        normalized.push_str(&escape_dn_value(&String::from_utf8_lossy(&value)));
+        match separator {
+            None => return normalized,
+            Some(b'+') => normalized.push('+'),
+            Some(_) => normalized.push(','),
+        }
+    }
+}
+
+// Minimal BER encoding of the LDAP messages (RFC 4511) needed to bind and search
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_BIND_REQUEST: u8 = 0x60;
+const TAG_BIND_RESPONSE: u8 = 0x61;
+const TAG_UNBIND_REQUEST: u8 = 0x42;
+const TAG_SEARCH_REQUEST: u8 = 0x63;
+const TAG_SEARCH_RESULT_ENTRY: u8 = 0x64;
+const TAG_SEARCH_RESULT_DONE: u8 = 0x65;
+const TAG_SIMPLE_AUTH: u8 = 0x80;
+const TAG_EQUALITY_FILTER: u8 = 0xa3;
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        let len = (content.len() as u32).to_be_bytes();
+        let skip = len.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (len.len() - skip) as u8);
+        out.extend_from_slice(&len[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn integer(tag: u8, value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut skip = bytes.iter().take_while(|b| **b == 0).count().min(3);
+    // Keep a leading zero for values which would be negative
+    if skip > 0 && bytes[skip] & 0x80 != 0 {
+        skip -= 1;
+    }
+    tlv(tag, &bytes[skip..])
+}
+
+fn message(id: u32, operation: Vec<u8>) -> Vec<u8> {
+    tlv(TAG_SEQUENCE, &[integer(TAG_INTEGER, id), operation].concat())
+}
+
+fn bind_request(id: u32, dn: &str, password: &str) -> Vec<u8> {
+    let bind = [
+        integer(TAG_INTEGER, 3),
+        tlv(TAG_OCTET_STRING, dn.as_bytes()),
+        tlv(TAG_SIMPLE_AUTH, password.as_bytes()),
+    ];
+    message(id, tlv(TAG_BIND_REQUEST, &bind.concat()))
+}
+
+/// Search the subtree of the base DN for the entry of the user, with only its memberOf attribute
+fn search_request(id: u32, base_dn: &str, user_attribute: &str, username: &str) -> Vec<u8> {
+    let filter = [
+        tlv(TAG_OCTET_STRING, user_attribute.as_bytes()),
+        tlv(TAG_OCTET_STRING, username.as_bytes()),
+    ];
+    let search = [
+        tlv(TAG_OCTET_STRING, base_dn.as_bytes()),
+        // whole subtree, never dereference aliases, no size limit
+        integer(TAG_ENUMERATED, 2),
+        integer(TAG_ENUMERATED, 0),
+        integer(TAG_INTEGER, 0),
+        integer(TAG_INTEGER, LDAP_TIMEOUT.as_secs() as u32),
+        tlv(TAG_BOOLEAN, &[0x00]),
+        tlv(TAG_EQUALITY_FILTER, &filter.concat()),
+        tlv(TAG_SEQUENCE, &tlv(TAG_OCTET_STRING, b"memberOf")),
+    ];
+    message(id, tlv(TAG_SEARCH_REQUEST, &search.concat()))
+}
+
+fn unbind_request(id: u32) -> Vec<u8> {
+    message(id, tlv(TAG_UNBIND_REQUEST, &[]))
+}
+
+/// Reader of the elements of a BER encoded value
+struct Ber<'a>(&'a [u8]);
+
+impl<'a> Ber<'a> {
+    fn next(&mut self) -> anyhow::Result<(u8, &'a [u8])> {
+        let [tag, len, rest @ ..] = self.0 else {
+            return Err(anyhow!("truncated LDAP message"));
+        };
+        let (len, rest) = if len & 0x80 == 0 {
+            (usize::from(*len), rest)
+        } else {
+            let size = usize::from(len & 0x7f);
+            if size > 4 || rest.len() < size {
+                return Err(anyhow!("invalid length in LDAP message"));
+            }
+            let len = rest[..size].iter().fold(0, |len, b| (len << 8) | usize::from(*b));
+            (len, &rest[size..])
+        };
+        if rest.len() < len {
+            return Err(anyhow!("truncated LDAP message"));
+        }
+
+        self.0 = &rest[len..];
+        Ok((*tag, &rest[..len]))
+    }
+
+    fn expect(&mut self, expected: u8) -> anyhow::Result<&'a [u8]> {
+        match self.next()? {
+            (tag, value) if tag == expected => Ok(value),
+            (tag, _) => Err(anyhow!(
+                "unexpected tag {:#04x} in LDAP message, expected {:#04x}",
+                tag,
+                expected
+            )),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Read a whole LDAP message, and return its operation tag and content
+async fn read_response<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != TAG_SEQUENCE {
+        return Err(anyhow!("invalid LDAP message"));
+    }
+    let len = if header[1] & 0x80 == 0 {
+        usize::from(header[1])
+    } else {
+        let size = usize::from(header[1] & 0x7f);
+        if size > 4 {
+            return Err(anyhow!("invalid length in LDAP message"));
+        }
+        let mut len = [0; 4];
+        stream.read_exact(&mut len[4 - size..]).await?;
+        u32::from_be_bytes(len) as usize
+    };
+    if len > MAX_MESSAGE_LEN {
+        return Err(anyhow!("LDAP message of {} bytes is too large", len));
+    }
+    let mut content = vec![0; len];
+    stream.read_exact(&mut content).await?;
+
+    let mut message = Ber(&content);
+    message.expect(TAG_INTEGER)?;
+    let (op, value) = message.next()?;
+    Ok((op, value.to_vec()))
+}
+
+/// Error of an LDAPResult with a result code other than success
+fn check_result(result: &[u8], operation: &str) -> anyhow::Result<()> {
+    let mut result = Ber(result);
+    let code = result.expect(TAG_ENUMERATED)?;
+    let _matched_dn = result.expect(TAG_OCTET_STRING)?;
+    let message = String::from_utf8_lossy(result.expect(TAG_OCTET_STRING)?);
+    match code {
+        [0] => Ok(()),
+        [RESULT_INVALID_CREDENTIALS] => Err(anyhow!("invalid credentials")),
+        code => Err(anyhow!("LDAP {} failed with code {:?}: {}", operation, code, message)),
+    }
+}
+
+/// Values of the memberOf attribute of a search result entry
+fn member_of(entry: &[u8]) -> anyhow::Result<Vec<String>> {
+    let mut entry = Ber(entry);
+    let _dn = entry.expect(TAG_OCTET_STRING)?;
+    let mut attributes = Ber(entry.expect(TAG_SEQUENCE)?);
+    let mut groups = vec![];
+    while !attributes.is_empty() {
+        let mut attribute = Ber(attributes.expect(TAG_SEQUENCE)?);
+        let name = attribute.expect(TAG_OCTET_STRING)?;
+        let mut values = Ber(attribute.expect(TAG_SET)?);
+        if !name.eq_ignore_ascii_case(b"memberOf") {
+            continue;
+        }
+        while !values.is_empty() {
+            groups.push(String::from_utf8_lossy(values.expect(TAG_OCTET_STRING)?).to_string());
+        }
+    }
+
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use test_case::test_case;
+    use tokio::net::TcpListener;
+
+    #[test_case("alice" => "alice" ; "plain")]
+    #[test_case("alice,ou=admins" => "alice\\,ou\\=admins" ; "injection")]
+    #[test_case("#alice " => "\\#alice\\ " ; "leading and trailing")]
+    #[test_case("a\\b" => "a\\\\b" ; "backslash")]
+    fn test_escape_dn_value(value: &str) -> String {
+        escape_dn_value(value)
+    }
+
+    #[test_case("CN=VPN Admins,OU=Groups,DC=corp,DC=example" => "cn=VPN Admins,ou=Groups,dc=corp,dc=example" ; "active directory")]
+    #[test_case("cn=devs,ou=groups,dc=example,dc=com" => "cn=devs,ou=groups,dc=example,dc=com" ; "openldap")]
+    #[test_case("CN = VPN Admins , OU=Groups;DC=corp" => "cn=VPN Admins,ou=Groups,dc=corp" ; "spaces and legacy separator")]
+    #[test_case("CN=Smith\\, John,OU=People,DC=corp" => "cn=Smith\\, John,ou=People,dc=corp" ; "escaped comma")]
+    #[test_case("CN=Smith\\2C John,OU=People,DC=corp" => "cn=Smith\\, John,ou=People,dc=corp" ; "hex escaped comma")]
+    #[test_case("CN=caf\\C3\\A9\\ ,OU=People" => "cn=caf\u{e9}\\ ,ou=People" ; "hex escaped utf8 and trailing space")]
+    #[test_case("CN=vpn+UID=1,DC=corp" => "cn=vpn+uid=1,dc=corp" ; "multi valued")]
+    #[test_case("not a dn" => "not a dn" ; "invalid")]
+    fn test_normalize_dn(dn: &str) -> String {
+        normalize_dn(dn)
+    }
+
+    #[test]
+    fn test_same_group_name_in_other_ou() {
+        let contractors = normalize_dn("CN=admins,OU=contractors,DC=corp,DC=example");
+        let it = normalize_dn("CN=admins,OU=IT,DC=corp,DC=example");
+        assert_ne!(contractors, it);
+        assert_eq!(it, "cn=admins,ou=IT,dc=corp,dc=example");
+    }
+
+    #[test]
+    fn test_bind_request() {
+        // Example of RFC 4511 encoding: messageID 1, version 3, dn "cn=a", simple "pw"
+        assert_eq!(
+            bind_request(1, "cn=a", "pw"),
+            [
+                0x30, 0x12, 0x02, 0x01, 0x01, 0x60, 0x0d, 0x02, 0x01, 0x03, 0x04, 0x04, b'c', b'n', b'=', b'a', 0x80,
+                0x02, b'p', b'w'
+            ]
+        );
+        assert_eq!(integer(TAG_INTEGER, 128), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(tlv(TAG_OCTET_STRING, &[0; 200])[..3], [0x04, 0x81, 200]);
+    }
+
+    fn result(op: u8, id: u32, code: u8, message: &str) -> Vec<u8> {
+        let result = [
+            integer(TAG_ENUMERATED, u32::from(code)),
+            tlv(TAG_OCTET_STRING, b""),
+            tlv(TAG_OCTET_STRING, message.as_bytes()),
+        ];
+        self::message(id, tlv(op, &result.concat()))
+    }
+
+    fn entry(id: u32, groups: &[&str]) -> Vec<u8> {
+        let values: Vec<u8> = groups
+            .iter()
+            .flat_map(|group| tlv(TAG_OCTET_STRING, group.as_bytes()))
+            .collect();
+        let attributes = [
+            tlv(
+                TAG_SEQUENCE,
+                &[
+                    tlv(TAG_OCTET_STRING, b"objectClass"),
+                    tlv(TAG_SET, &tlv(TAG_OCTET_STRING, b"person")),
+                ]
+                .concat(),
+            ),
+            tlv(
+                TAG_SEQUENCE,
+                &[tlv(TAG_OCTET_STRING, b"memberOf"), tlv(TAG_SET, &values)].concat(),
+            ),
+        ];
+        let entry = [
+            tlv(TAG_OCTET_STRING, b"uid=alice,dc=example"),
+            tlv(TAG_SEQUENCE, &attributes.concat()),
+        ];
+        message(id, tlv(TAG_SEARCH_RESULT_ENTRY, &entry.concat()))
+    }
+
+    /// Directory accepting the password "secret" for uid=alice
+    async fn ldap_server() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ldap://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (op, bind) = read_response(&mut stream).await.unwrap();
+                assert_eq!(op, TAG_BIND_REQUEST);
+                let mut bind = Ber(&bind);
+                bind.expect(TAG_INTEGER).unwrap();
+                let dn = bind.expect(TAG_OCTET_STRING).unwrap().to_vec();
+                let password = bind.expect(TAG_SIMPLE_AUTH).unwrap().to_vec();
+                if dn != b"uid=alice,dc=example" || password != b"secret" {
+                    let response = result(TAG_BIND_RESPONSE, 1, RESULT_INVALID_CREDENTIALS, "invalid credentials");
+                    stream.write_all(&response).await.unwrap();
+                    continue;
+                }
+                stream.write_all(&result(TAG_BIND_RESPONSE, 1, 0, "")).await.unwrap();
+
+                let (op, _) = read_response(&mut stream).await.unwrap();
+                assert_eq!(op, TAG_SEARCH_REQUEST);
+                stream
+                    .write_all(&entry(
+                        2,
+                        &["CN=devs,OU=groups,DC=example", "cn=Smith\\, John,ou=groups,dc=example"],
+                    ))
+                    .await
+                    .unwrap();
+                stream
+                    .write_all(&result(TAG_SEARCH_RESULT_DONE, 2, 0, ""))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        url
+    }
+
+    fn headers(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let credentials = base64::engine::general_purpose::STANDARD.encode(credentials);
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", credentials)).unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_ldap_auth() {
+        let auth = LdapAuth::new(
+            ldap_server().await,
+            "uid={username},dc=example".to_string(),
+            "dc=example".to_string(),
+            "uid".to_string(),
+        )
+        .unwrap();
+
+        let claims = auth.authenticate(&headers("alice:secret")).await.unwrap();
+        assert_eq!(claims.subject.as_deref(), Some("alice"));
+        assert_eq!(
+            claims.scopes,
+            vec!["cn=devs,ou=groups,dc=example", "cn=Smith\\, John,ou=groups,dc=example"]
+        );
+        // From the cache
+        assert!(auth.authenticate(&headers("alice:secret")).await.is_ok());
+
+        let err = auth.authenticate(&headers("alice:wrong")).await.unwrap_err();
+        assert_eq!(err.to_string(), "invalid credentials");
+        assert!(auth.authenticate(&headers("alice:")).await.is_err());
+        assert!(auth.authenticate(&headers("alice,dc=example:secret")).await.is_err());
+        assert!(auth.authenticate(&HeaderMap::new()).await.is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let url = Url::parse("ldaps://dc.corp.example").unwrap();
+        assert!(LdapAuth::new(url.clone(), "uid=alice".to_string(), String::new(), "uid".to_string()).is_err());
+        assert!(LdapAuth::new(
+            Url::parse("http://dc.corp.example").unwrap(),
+            "{username}@corp.example".to_string(),
+            String::new(),
+            "uid".to_string()
+        )
+        .is_err());
+        assert!(LdapAuth::new(url, "{username}@corp.example".to_string(), String::new(), "uid".to_string()).is_ok());
+    }
+}
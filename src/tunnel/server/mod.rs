@@ -1,10 +1,52 @@
 #![allow(clippy::module_inception)]
+#[cfg(unix)]
+mod access_log;
+mod admin;
+mod audit;
+mod auth_webhook;
+mod ban;
+mod bearer_auth;
+mod check_restrictions;
+mod event_handler;
+mod event_webhook;
+mod forwarded_header;
 mod handler_http2;
+mod handler_transport;
 mod handler_websocket;
+pub(crate) mod http_client;
+mod ingress;
+mod ldap_auth;
+mod privileges;
+mod quota;
+mod rate_limit;
+mod registries;
 mod reverse_tunnel;
+mod revocation;
 mod server;
+mod service;
+mod session;
+mod totp;
 mod utils;
 
+#[cfg(unix)]
+pub use access_log::{AccessLogFormat, HttpAccessLog};
+pub use admin::run_admin_server;
+pub use audit::AuditLog;
+pub use auth_webhook::AuthWebhook;
+pub use ban::BanPolicy;
+pub use bearer_auth::BearerAuth;
+pub use check_restrictions::check_restrictions;
+pub use event_handler::{AuthInfo, TunnelEventHandler, TunnelInfo, TunnelStats};
+pub use event_webhook::EventWebhook;
+pub use ldap_auth::LdapAuth;
+pub use privileges::PrivilegeDrop;
+pub use quota::QuotaStore;
+pub use rate_limit::RateLimiter;
+pub use registries::ServerRegistries;
+pub use revocation::RevocationList;
 pub use server::TlsServerConfig;
 pub use server::WsServer;
 pub use server::WsServerConfig;
+pub use service::TunnelService;
+pub use totp::TotpVerifier;
+pub(crate) use utils::protocol_name;
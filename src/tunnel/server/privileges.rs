@@ -0,0 +1,105 @@
+use anyhow::anyhow;
+use std::path::PathBuf;
+
+/// Unprivileged user and group the server switches to, and directory it is confined in, once its listening sockets
+/// are bound. So the server can be started as root to bind privileged ports, without keeping root afterward
+#[derive(Debug, Clone, Default)]
+pub struct PrivilegeDrop {
+    /// Name or uid
+    pub user: Option<String>,
+    /// Name or gid, the primary group of the user by default
+    pub group: Option<String>,
+    pub chroot: Option<PathBuf>,
+}
+
+impl PrivilegeDrop {
+    #[cfg(unix)]
+    pub(super) fn apply(&self) -> anyhow::Result<()> {
+        use anyhow::Context;
+        use nix::unistd::{chdir, chroot, setgid, setuid, Uid};
+        use tracing::info;
+
+        // Users and groups are resolved before the chroot, which usually has no /etc/passwd
+        let user = self.user.as_deref().map(lookup_user).transpose()?;
+        let gid = match &self.group {
+            Some(group) => Some(lookup_group(group)?),
+            None => user.and_then(|(_, gid)| gid),
+        };
+
+        if let Some(path) = &self.chroot {
+            chroot(path).with_context(|| format!("Cannot chroot to {}", path.display()))?;
+            chdir("/").context("Cannot change directory to the root of the chroot")?;
+            info!("Server is confined in {}", path.display());
+        }
+
+        if let Some(gid) = gid {
+            #[cfg(not(target_os = "macos"))]
+            nix::unistd::setgroups(&[gid]).context("Cannot drop the supplementary groups")?;
+            setgid(gid).with_context(|| format!("Cannot switch to group {}", gid))?;
+            info!("Server runs with group {}", gid);
+        }
+
+        if let Some((uid, _)) = user {
+            setuid(uid).with_context(|| format!("Cannot switch to user {}", uid))?;
+            if !uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+                return Err(anyhow!("Root privileges can be regained after switching to user {}", uid));
+            }
+            info!("Server runs with user {}", uid);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub(super) fn apply(&self) -> anyhow::Result<()> {
+        Err(anyhow!("Dropping privileges is only available on unix platforms"))
+    }
+}
+
+/// Uid of the user and its primary group, if it is known
+#[cfg(unix)]
+fn lookup_user(user: &str) -> anyhow::Result<(nix::unistd::Uid, Option<nix::unistd::Gid>)> {
+    use nix::unistd::{Uid, User};
+
+    if let Ok(uid) = user.parse::<u32>() {
+        let uid = Uid::from_raw(uid);
+        return Ok((uid, User::from_uid(uid)?.map(|entry| entry.gid)));
+    }
+
+    User::from_name(user)?
+        .map(|entry| (entry.uid, Some(entry.gid)))
+        .ok_or_else(|| anyhow!("Unknown user {}", user))
+}
+
+#[cfg(unix)]
+fn lookup_group(group: &str) -> anyhow::Result<nix::unistd::Gid> {
+    use nix::unistd::{Gid, Group};
+
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+
+    Group::from_name(group)?
+        .map(|entry| entry.gid)
+        .ok_or_else(|| anyhow!("Unknown group {}", group))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use nix::unistd::{Gid, Uid};
+
+    #[test]
+    fn test_lookup_user() {
+        assert_eq!(lookup_user("root").unwrap(), (Uid::from_raw(0), Some(Gid::from_raw(0))));
+        assert_eq!(lookup_user("0").unwrap(), (Uid::from_raw(0), Some(Gid::from_raw(0))));
+        assert_eq!(lookup_user("4242421").unwrap().0, Uid::from_raw(4242421));
+        assert!(lookup_user("wstunnel-unknown-user").is_err());
+    }
+
+    #[test]
+    fn test_lookup_group() {
+        assert_eq!(lookup_group("1234").unwrap(), Gid::from_raw(1234));
+        assert!(lookup_group("wstunnel-unknown-group").is_err());
+    }
+}
@@ -0,0 +1,543 @@
+use crate::restrictions::types::{QuotaConfig, RestrictionConfig};
+use crate::tunnel::bandwidth::TokenBucket;
+use ahash::AHashMap;
+use anyhow::Context as _;
+use chrono::{Datelike, Utc};
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::select;
+use tokio::time::Sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+/// How often the usage of the identities is written to the --quota-state-file, when it changed
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Usage of the quotas of a server, kept across reloads of the restrictions
+#[derive(Default)]
+pub struct QuotaUsages {
+    /// Of the restrictions with quotas, by name
+    restrictions: Mutex<AHashMap<String, Arc<QuotaUsage>>>,
+    /// Of the identities subject to a monthly quota, by identity. Kept across restarts with a QuotaStore
+    identities: Mutex<AHashMap<String, Arc<MonthlyUsage>>>,
+    /// Whether the usage of the identities changed since it was last saved
+    identities_changed: AtomicBool,
+}
+
+#[derive(Default)]
+struct QuotaUsage {
+    connections: AtomicU32,
+    day: AtomicU64,
+    bytes_today: AtomicU64,
+    bandwidth: Mutex<Option<TokenBucket>>,
+}
+
+#[derive(Default)]
+struct MonthlyUsage {
+    month: AtomicU64,
+    bytes_this_month: AtomicU64,
+}
+
+#[derive(Debug, derive_more::Display)]
+pub(super) enum QuotaExhausted {
+    /// A quota shared by all the clients of the restriction
+    #[display("{_0}")]
+    Restriction(String),
+    /// The monthly quota of the identity of the client
+    #[display("monthly quota of {_0} bytes of this identity is exhausted")]
+    Identity(u64),
+}
+
+impl QuotaUsages {
+    /// Reserve a connection in the quota of the restriction which allowed the tunnel, or explain why it is exhausted.
+    /// The connection is released when the ticket is dropped, with the streams of the tunnel.
+    pub(super) fn acquire(
+        self: &Arc<Self>,
+        restriction: &RestrictionConfig,
+        identity: &str,
+    ) -> Result<Option<Arc<QuotaTicket>>, QuotaExhausted> {
+        let quota = &restriction.quota;
+        if quota.max_bandwidth.is_none()
+            && quota.max_concurrent_connections.is_none()
+            && quota.max_bytes_per_day.is_none()
+            && quota.max_bytes_per_month_per_identity.is_none()
+        {
+            return Ok(None);
+        }
+
+        let identity_usage = quota
+            .max_bytes_per_month_per_identity
+            .map(|_| self.identities.lock().entry(identity.to_string()).or_default().clone());
+        if let (Some(max_bytes), Some(identity_usage)) = (quota.max_bytes_per_month_per_identity, &identity_usage) {
+            if identity_usage.bytes_this_month() >= max_bytes {
+                return Err(QuotaExhausted::Identity(max_bytes));
+            }
+        }
+
+        let usage = self
+            .restrictions
+            .lock()
+            .entry(restriction.name.clone())
+            .or_default()
+            .clone();
+        if let Some(max_bytes) = quota.max_bytes_per_day {
+            if usage.bytes_today() >= max_bytes {
+                return Err(QuotaExhausted::Restriction(format!(
+                    "daily quota of {} bytes is exhausted",
+                    max_bytes
+                )));
+            }
+        }
+
+        let connections = usage.connections.fetch_add(1, Ordering::Relaxed) + 1;
+        let ticket = QuotaTicket {
+            quota: quota.clone(),
+            usage,
+            identity_usage,
+            usages: self.clone(),
+        };
+        if let Some(max_connections) = quota.max_concurrent_connections {
+            if connections > max_connections {
+                return Err(QuotaExhausted::Restriction(format!(
+                    "too many concurrent connections, {} are allowed",
+                    max_connections
+                )));
+            }
+        }
+
+        Ok(Some(Arc::new(ticket)))
+    }
+}
+
+pub(super) struct QuotaTicket {
+    quota: QuotaConfig,
+    usage: Arc<QuotaUsage>,
+    identity_usage: Option<Arc<MonthlyUsage>>,
+    usages: Arc<QuotaUsages>,
+}
+
+impl Drop for QuotaTicket {
+    fn drop(&mut self) {
+        self.usage.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl QuotaUsage {
+    fn bytes_today(&self) -> u64 {
+        let today = today();
+        if self.day.swap(today, Ordering::Relaxed) != today {
+            self.bytes_today.store(0, Ordering::Relaxed);
+        }
+        self.bytes_today.load(Ordering::Relaxed)
+    }
+}
+
+impl MonthlyUsage {
+    fn bytes_this_month(&self) -> u64 {
+        let month = current_month();
+        if self.month.swap(month, Ordering::Relaxed) != month {
+            self.bytes_this_month.store(0, Ordering::Relaxed);
+        }
+        self.bytes_this_month.load(Ordering::Relaxed)
+    }
+}
+
+impl QuotaTicket {
+    /// How many bytes can be transferred now, or how long to wait for the bandwidth to be available
+    fn allowance(&self, wanted: usize) -> io::Result<Result<usize, Duration>> {
+        if let Some(max_bytes) = self.quota.max_bytes_per_day {
+            if self.usage.bytes_today() >= max_bytes {
+                return Err(io::Error::other(format!("daily quota of {} bytes is exhausted", max_bytes)));
+            }
+        }
+        if let (Some(max_bytes), Some(identity_usage)) =
+            (self.quota.max_bytes_per_month_per_identity, &self.identity_usage)
+        {
+            if identity_usage.bytes_this_month() >= max_bytes {
+                return Err(io::Error::other(QuotaExhausted::Identity(max_bytes).to_string()));
+            }
+        }
+
+        let Some(rate) = self.quota.max_bandwidth.filter(|rate| *rate > 0) else {
+            return Ok(Ok(wanted));
+        };
+
+        let mut bucket = self.usage.bandwidth.lock();
+        Ok(bucket
+            .get_or_insert_with(|| TokenBucket::new(rate))
+            .allowance(rate, wanted))
+    }
+
+    fn consume(&self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        if self.quota.max_bytes_per_day.is_some() {
+            self.usage.bytes_today.fetch_add(len as u64, Ordering::Relaxed);
+        }
+        if let Some(identity_usage) = &self.identity_usage {
+            identity_usage.bytes_this_month.fetch_add(len as u64, Ordering::Relaxed);
+            self.usages.identities_changed.store(true, Ordering::Relaxed);
+        }
+        if let Some(bucket) = self.usage.bandwidth.lock().as_mut() {
+            bucket.consume(len);
+        }
+    }
+
+    fn poll_allowance(
+        &self,
+        throttle: &mut Option<Pin<Box<Sleep>>>,
+        wanted: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if let Some(sleep) = throttle.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                *throttle = None;
+            }
+
+            match self.allowance(wanted)? {
+                Ok(len) => return Poll::Ready(Ok(len)),
+                Err(wait) => *throttle = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / SECS_PER_DAY
+}
+
+/// Months since year 0, in UTC
+fn current_month() -> u64 {
+    let now = Utc::now();
+    now.year() as u64 * 12 + u64::from(now.month0())
+}
+
+/// Persist the monthly usage of the identities in a json file, so their quotas are not reset by a restart.
+/// The file is written every few seconds while tunnels transfer data, the last seconds can be lost on a crash
+pub struct QuotaStore {
+    path: PathBuf,
+    usages: Arc<QuotaUsages>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QuotaStoreContent {
+    /// i.e: 2024-05, the counters of another month are ignored when loading them
+    month: String,
+    bytes: BTreeMap<String, u64>,
+}
+
+impl QuotaStore {
+    /// Restore the usage of the identities saved in the file, if it exists
+    pub fn open(path: PathBuf, usages: Arc<QuotaUsages>) -> anyhow::Result<Self> {
+        let store = Self { path, usages };
+        match std::fs::read(&store.path) {
+            Ok(content) => {
+                let content: QuotaStoreContent = serde_json::from_slice(&content)
+                    .with_context(|| format!("Invalid quota state file {}", store.path.display()))?;
+                store.restore(content);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("Cannot read quota state file {}", store.path.display()))
+            }
+        }
+
+        Ok(store)
+    }
+
+    fn restore(&self, content: QuotaStoreContent) {
+        let month = current_month();
+        if content.month != format_month(month) {
+            info!(
+                "Quota state file {} is from {}, starting a new month",
+                self.path.display(),
+                content.month
+            );
+            return;
+        }
+
+        let mut usages = self.usages.identities.lock();
+        for (identity, bytes) in content.bytes {
+            let usage = usages.entry(identity).or_default();
+            usage.month.store(month, Ordering::Relaxed);
+            usage.bytes_this_month.store(bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> QuotaStoreContent {
+        let month = current_month();
+        let bytes = self
+            .usages
+            .identities
+            .lock()
+            .iter()
+            .filter(|(_, usage)| usage.month.load(Ordering::Relaxed) == month)
+            .map(|(identity, usage)| (identity.clone(), usage.bytes_this_month.load(Ordering::Relaxed)))
+            .collect();
+
+        QuotaStoreContent {
+            month: format_month(month),
+            bytes,
+        }
+    }
+
+    /// Write the usage of the identities, through a temporary file so the file is never left half written
+    pub fn save(&self) -> anyhow::Result<()> {
+        let content = serde_json::to_vec_pretty(&self.snapshot())?;
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, &self.path))
+            .with_context(|| format!("Cannot write quota state file {}", self.path.display()))
+    }
+
+    /// Save the usage of the identities periodically when it changed, and a last time once the server stops
+    pub async fn run(self, cancel: CancellationToken) {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            let stopped = select! {
+                _ = interval.tick() => false,
+                _ = cancel.cancelled() => true,
+            };
+            if self.usages.identities_changed.swap(false, Ordering::Relaxed) {
+                if let Err(err) = self.save() {
+                    warn!("{:?}", err);
+                    self.usages.identities_changed.store(true, Ordering::Relaxed);
+                }
+            }
+            if stopped {
+                return;
+            }
+        }
+    }
+}
+
+fn format_month(month: u64) -> String {
+    format!("{:04}-{:02}", month / 12, month % 12 + 1)
+}
+
+/// Local side of a tunnel whose traffic counts in the quota of its restriction
+#[pin_project]
+pub(super) struct QuotaStream<T> {
+    #[pin]
+    inner: T,
+    ticket: Arc<QuotaTicket>,
+    throttle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> QuotaStream<T> {
+    pub(super) const fn new(inner: T, ticket: Arc<QuotaTicket>) -> Self {
+        Self {
+            inner,
+            ticket,
+            throttle: None,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for QuotaStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let allowed = ready!(this.ticket.poll_allowance(this.throttle, buf.remaining(), cx))?;
+
+        let before = buf.filled().len();
+        if allowed < buf.remaining() {
+            let mut limited = vec![0; allowed];
+            let mut limited = ReadBuf::new(&mut limited);
+            ready!(this.inner.poll_read(cx, &mut limited))?;
+            buf.put_slice(limited.filled());
+        } else {
+            ready!(this.inner.poll_read(cx, buf))?;
+        }
+        this.ticket.consume(buf.filled().len() - before);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for QuotaStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let allowed = ready!(this.ticket.poll_allowance(this.throttle, buf.len(), cx))?;
+
+        let len = ready!(this.inner.poll_write(cx, &buf[..allowed]))?;
+        this.ticket.consume(len);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    const IDENTITY: &str = "path_prefix:v1";
+
+    fn restriction(name: &str, quota: QuotaConfig) -> RestrictionConfig {
+        RestrictionConfig {
+            name: name.to_string(),
+            priority: 0,
+            r#match: vec![],
+            allow: vec![],
+            quota,
+            time_window: Default::default(),
+            max_session_duration: None,
+            interceptors: vec![],
+        }
+    }
+
+    #[test]
+    fn test_max_concurrent_connections() {
+        let restriction = restriction(
+            "test_max_concurrent_connections",
+            QuotaConfig {
+                max_concurrent_connections: Some(1),
+                ..Default::default()
+            },
+        );
+        let usages = Arc::new(QuotaUsages::default());
+
+        let ticket = usages.acquire(&restriction, IDENTITY).unwrap();
+        assert!(ticket.is_some());
+        assert!(usages.acquire(&restriction, IDENTITY).is_err());
+        drop(ticket);
+        assert!(usages.acquire(&restriction, IDENTITY).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_no_quota() {
+        assert!(Arc::new(QuotaUsages::default())
+            .acquire(&restriction("test_no_quota", QuotaConfig::default()), IDENTITY)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_max_bytes_per_day() {
+        let restriction = restriction(
+            "test_max_bytes_per_day",
+            QuotaConfig {
+                max_bytes_per_day: Some(10),
+                ..Default::default()
+            },
+        );
+        let usages = Arc::new(QuotaUsages::default());
+
+        let (client, _server) = tokio::io::duplex(64);
+        let mut stream = QuotaStream::new(client, usages.acquire(&restriction, IDENTITY).unwrap().unwrap());
+        stream.write_all(b"0123456789").await.unwrap();
+        assert!(stream.write_all(b"more").await.is_err());
+        assert!(usages.acquire(&restriction, IDENTITY).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_bandwidth() {
+        let restriction = restriction(
+            "test_max_bandwidth",
+            QuotaConfig {
+                max_bandwidth: Some(1000),
+                ..Default::default()
+            },
+        );
+        let usages = Arc::new(QuotaUsages::default());
+
+        let (client, mut server) = tokio::io::duplex(2048);
+        let mut stream = QuotaStream::new(client, usages.acquire(&restriction, IDENTITY).unwrap().unwrap());
+        server.write_all(&[0; 1500]).await.unwrap();
+
+        let start = Instant::now();
+        let mut buf = [0; 1500];
+        stream.read_exact(&mut buf).await.unwrap();
+        // 1000 bytes of burst, then 1000 bytes per second
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[tokio::test]
+    async fn test_max_bytes_per_month_per_identity() {
+        let restriction = restriction(
+            "test_max_bytes_per_month_per_identity",
+            QuotaConfig {
+                max_bytes_per_month_per_identity: Some(10),
+                ..Default::default()
+            },
+        );
+        let usages = Arc::new(QuotaUsages::default());
+
+        let (client, _server) = tokio::io::duplex(64);
+        let mut stream =
+            QuotaStream::new(client, usages.acquire(&restriction, "sub:test_month_alice").unwrap().unwrap());
+        stream.write_all(b"0123456789").await.unwrap();
+        assert!(stream.write_all(b"more").await.is_err());
+        assert!(matches!(
+            usages.acquire(&restriction, "sub:test_month_alice"),
+            Err(QuotaExhausted::Identity(10))
+        ));
+        // Other identities have their own quota
+        assert!(usages.acquire(&restriction, "sub:test_month_bob").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_quota_store() {
+        let path = std::env::temp_dir().join(format!("wstunnel-quota-{}.json", std::process::id()));
+        let month = format_month(current_month());
+        std::fs::write(
+            &path,
+            format!(r#"{{"month": "{month}", "bytes": {{"sub:test_store_alice": 42}}}}"#),
+        )
+        .unwrap();
+
+        let usages = Arc::new(QuotaUsages::default());
+        let store = QuotaStore::open(path.clone(), usages.clone()).unwrap();
+        let usage = usages.identities.lock().get("sub:test_store_alice").unwrap().clone();
+        assert_eq!(usage.bytes_this_month(), 42);
+
+        usage.bytes_this_month.fetch_add(8, Ordering::Relaxed);
+        store.save().unwrap();
+        let content: QuotaStoreContent = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(content.month, month);
+        assert_eq!(content.bytes.get("sub:test_store_alice"), Some(&50));
+    }
+
+    #[test]
+    fn test_quota_store_of_another_month() {
+        let path = std::env::temp_dir().join(format!("wstunnel-quota-old-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"month": "2000-01", "bytes": {"sub:test_store_old": 42}}"#).unwrap();
+        let usages = Arc::new(QuotaUsages::default());
+        QuotaStore::open(path.clone(), usages.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(usages.identities.lock().get("sub:test_store_old").is_none());
+    }
+
+    #[test]
+    fn test_format_month() {
+        assert_eq!(format_month(2024 * 12 + 4), "2024-05");
+    }
+}
@@ -0,0 +1,94 @@
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Sources whose bucket is full again are forgotten once this many are tracked
+const MAX_TRACKED_SOURCES: usize = 100_000;
+
+/// Limit the new connections of each source ip, with a token bucket refilled at `rate` per second and holding
+/// at most `burst` connections
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<AHashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    /// Take a token for a new connection of this source, false if it exceeds its rate
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip.to_canonical(), Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= MAX_TRACKED_SOURCES {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        if self.refill(bucket, now) < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+        bucket.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        // burst
+        assert!(limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(ip, now));
+        assert!(!limiter.allow_at(ip, now));
+
+        // other sources have their own bucket
+        assert!(limiter.allow_at("192.0.2.2".parse().unwrap(), now));
+
+        // 2 connections per second
+        let now = now + Duration::from_millis(500);
+        assert!(limiter.allow_at(ip, now));
+        assert!(!limiter.allow_at(ip, now));
+
+        // the bucket does not grow over the burst
+        let now = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.allow_at(ip, now));
+        }
+        assert!(!limiter.allow_at(ip, now));
+    }
+}
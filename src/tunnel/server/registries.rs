@@ -0,0 +1,43 @@
+use crate::tunnel::listeners::{HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener};
+use crate::tunnel::server::ban::BanList;
+use crate::tunnel::server::ingress::HttpIngress;
+use crate::tunnel::server::quota::QuotaUsages;
+use crate::tunnel::server::reverse_tunnel::{IsolatedPortAllocator, ReverseTunnelRegistry, ReverseTunnelServer};
+use std::sync::Arc;
+
+/// State of a server shared by its listeners and its admin socket, and not with the other servers of the process
+pub struct ServerRegistries {
+    /// Reverse tunnel servers currently listening, of all protocols
+    pub(super) reverse_tunnels: Arc<ReverseTunnelRegistry>,
+    pub(super) reverse_tcp: ReverseTunnelServer<TcpTunnelListener>,
+    pub(super) reverse_udp: ReverseTunnelServer<UdpTunnelListener>,
+    pub(super) reverse_socks5: ReverseTunnelServer<Socks5TunnelListener>,
+    pub(super) reverse_http_proxy: ReverseTunnelServer<HttpProxyTunnelListener>,
+    #[cfg(unix)]
+    pub(super) reverse_unix: ReverseTunnelServer<crate::tunnel::listeners::UnixTunnelListener>,
+    pub(super) http_ingress: Arc<HttpIngress>,
+    /// Port of the reverse socks5 listener of each isolated client
+    pub(super) isolated_ports: IsolatedPortAllocator,
+    pub(super) bans: BanList,
+    /// Persisted by the QuotaStore
+    pub quotas: Arc<QuotaUsages>,
+}
+
+impl Default for ServerRegistries {
+    fn default() -> Self {
+        let reverse_tunnels = Arc::new(ReverseTunnelRegistry::default());
+        Self {
+            reverse_tcp: ReverseTunnelServer::new("tcp", reverse_tunnels.clone()),
+            reverse_udp: ReverseTunnelServer::new("udp", reverse_tunnels.clone()),
+            reverse_socks5: ReverseTunnelServer::new("socks5", reverse_tunnels.clone()),
+            reverse_http_proxy: ReverseTunnelServer::new("http", reverse_tunnels.clone()),
+            #[cfg(unix)]
+            reverse_unix: ReverseTunnelServer::new("unix", reverse_tunnels.clone()),
+            http_ingress: Arc::new(HttpIngress::new(reverse_tunnels.clone())),
+            isolated_ports: IsolatedPortAllocator::new(),
+            bans: BanList::default(),
+            quotas: Arc::default(),
+            reverse_tunnels,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::tunnel::client::{CountingStream, TransferStats};
 use crate::tunnel::listeners::TunnelListener;
 use crate::tunnel::RemoteAddr;
 use ahash::AHashMap;
@@ -5,67 +6,211 @@ use anyhow::anyhow;
 use futures_util::{pin_mut, StreamExt};
 use log::warn;
 use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
 use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{oneshot, Notify};
 use tokio::{select, time};
+use tokio_util::sync::CancellationToken;
 use tracing::{info, Instrument, Span};
 
+type Connection<T> = ((<T as TunnelListener>::Reader, <T as TunnelListener>::Writer), RemoteAddr);
+
+/// Connections accepted while no client is waiting, kept for the next clients
+const MAX_PENDING_CONNECTIONS: usize = 10;
+
+/// Clients waiting for a connection of a reverse tunnel server, in the order they started waiting
+struct Waiters<T: TunnelListener> {
+    queue: VecDeque<oneshot::Sender<Connection<T>>>,
+    pending: VecDeque<Connection<T>>,
+    /// The server stopped listening, no client can wait for it anymore
+    closed: bool,
+}
+
 struct ReverseTunnelItem<T: TunnelListener> {
-    #[allow(clippy::type_complexity)]
-    receiver: async_channel::Receiver<((<T as TunnelListener>::Reader, <T as TunnelListener>::Writer), RemoteAddr)>,
+    waiters: Arc<Mutex<Waiters<T>>>,
+    client_arrived: Arc<Notify>,
     nb_seen_clients: Arc<AtomicUsize>,
+    nb_waiting_clients: Arc<AtomicUsize>,
+    nb_connections: Arc<AtomicU64>,
+    stats: Arc<TransferStats>,
+    owner: Arc<str>,
 }
 
 impl<T: TunnelListener> Clone for ReverseTunnelItem<T> {
     fn clone(&self) -> Self {
         Self {
-            receiver: self.receiver.clone(),
+            waiters: self.waiters.clone(),
+            client_arrived: self.client_arrived.clone(),
             nb_seen_clients: self.nb_seen_clients.clone(),
+            nb_waiting_clients: self.nb_waiting_clients.clone(),
+            nb_connections: self.nb_connections.clone(),
+            stats: self.stats.clone(),
+            owner: self.owner.clone(),
         }
     }
 }
 
+/// When a reverse tunnel server stops listening
+#[derive(Debug, Clone)]
+pub struct ReverseTunnelTimeouts {
+    /// No client requested the reverse tunnel for this duration
+    pub idle: Duration,
+    /// No connection has been accepted for this duration
+    pub no_connection: Option<Duration>,
+    /// The reverse tunnel server has been listening for this duration, even if still in use
+    pub max_lifetime: Option<Duration>,
+    /// The wstunnel server stopped
+    pub cancel: CancellationToken,
+}
+
 pub struct ReverseTunnelServer<T: TunnelListener> {
+    protocol: &'static str,
     servers: Arc<Mutex<AHashMap<SocketAddr, ReverseTunnelItem<T>>>>,
+    /// Where the listening servers are listed, with the ones of the other protocols
+    registry: Arc<ReverseTunnelRegistry>,
 }
 
 impl<T: TunnelListener> ReverseTunnelServer<T> {
-    pub fn new() -> Self {
+    pub fn new(protocol: &'static str, registry: Arc<ReverseTunnelRegistry>) -> Self {
         Self {
+            protocol,
             servers: Arc::new(Mutex::new(AHashMap::with_capacity(1))),
+            registry,
         }
     }
 
+    /// Wait for the next connection accepted by the listening server of bind_addr, starting it if needed.
+    /// Clients of the same owner (the identity they authenticated with) requesting the same bind address share the
+    /// listening server, and connections are dispatched round-robin between them: each one goes to the client
+    /// waiting for the longest time. The listening server can't be used by clients of another owner while it is running.
     pub async fn run_listening_server(
         &self,
         bind_addr: SocketAddr,
-        idle_timeout: Duration,
+        timeouts: ReverseTunnelTimeouts,
+        owner: &str,
         gen_listening_server: impl Future<Output = anyhow::Result<T>>,
-    ) -> anyhow::Result<((<T as TunnelListener>::Reader, <T as TunnelListener>::Writer), RemoteAddr)>
+    ) -> anyhow::Result<(
+        (
+            CountingStream<<T as TunnelListener>::Reader>,
+            CountingStream<<T as TunnelListener>::Writer>,
+        ),
+        RemoteAddr,
+    )>
     where
         T: TunnelListener + Send + 'static,
     {
         let listening_server = self.servers.lock().get(&bind_addr).cloned();
         let item = if let Some(listening_server) = listening_server {
+            if *listening_server.owner != *owner {
+                return Err(anyhow!("Reverse tunnel on {} is already used by another client", bind_addr));
+            }
             listening_server
         } else {
             let listening_server = gen_listening_server.await?;
-            let (tx, rx) = async_channel::bounded(10);
-            let nb_seen_clients = Arc::new(AtomicUsize::new(0));
-            let seen_clients = nb_seen_clients.clone();
-            let server = self.servers.clone();
-            let local_srv2 = bind_addr;
+            self.spawn_listening_server(bind_addr, timeouts, owner, listening_server)
+        };
+
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut waiters = item.waiters.lock();
+            if waiters.closed {
+                return Err(anyhow!("listening reverse server stopped"));
+            }
+            match waiters.pending.pop_front() {
+                Some(cnx) => drop(tx.send(cnx)),
+                None => waiters.queue.push_back(tx),
+            }
+        }
+        item.client_arrived.notify_one();
+        item.nb_seen_clients.fetch_add(1, Ordering::Relaxed);
+        item.nb_waiting_clients.fetch_add(1, Ordering::Relaxed);
+        let waiting_clients = item.nb_waiting_clients.clone();
+        scopeguard::defer!({
+            waiting_clients.fetch_sub(1, Ordering::Relaxed);
+        });
+        let ((local_rx, local_tx), remote) = rx.await.map_err(|_| anyhow!("listening reverse server stopped"))?;
+        item.nb_connections.fetch_add(1, Ordering::Relaxed);
+        let local_rx = CountingStream::new(local_rx, Some(item.stats.clone()));
+        let local_tx = CountingStream::new(local_tx, Some(item.stats));
+        Ok(((local_rx, local_tx), remote))
+    }
+
+    /// Register an already started listening server, i.e: one bound on a port chosen by the OS.
+    /// Clients get its connections by calling run_listening_server with the same bind address.
+    pub fn register_listening_server(
+        &self,
+        bind_addr: SocketAddr,
+        timeouts: ReverseTunnelTimeouts,
+        owner: &str,
+        listening_server: T,
+    ) where
+        T: TunnelListener + Send + 'static,
+    {
+        let item = self.spawn_listening_server(bind_addr, timeouts, owner, listening_server);
+        // Count the registration as a client, to give it the idle timeout to connect before the server is closed
+        item.nb_seen_clients.fetch_add(1, Ordering::Relaxed);
+    }
 
-            let fut = async move {
+    fn spawn_listening_server(
+        &self,
+        bind_addr: SocketAddr,
+        timeouts: ReverseTunnelTimeouts,
+        owner: &str,
+        listening_server: T,
+    ) -> ReverseTunnelItem<T>
+    where
+        T: TunnelListener + Send + 'static,
+    {
+        let waiters = Arc::new(Mutex::new(Waiters {
+            queue: VecDeque::new(),
+            pending: VecDeque::new(),
+            closed: false,
+        }));
+        let client_arrived = Arc::new(Notify::new());
+        let nb_seen_clients = Arc::new(AtomicUsize::new(0));
+        let seen_clients = nb_seen_clients.clone();
+        let nb_waiting_clients = Arc::new(AtomicUsize::new(0));
+        let nb_connections = Arc::new(AtomicU64::new(0));
+        let stats = Arc::new(TransferStats::default());
+        let server = self.servers.clone();
+        let local_srv2 = bind_addr;
+        let registry = self.registry.clone();
+        let (id, close) = registry.register(RegisteredTunnel {
+            protocol: self.protocol,
+            bind: bind_addr,
+            owner: owner.to_string(),
+            since: SystemTime::now(),
+            nb_waiting_clients: nb_waiting_clients.clone(),
+            nb_connections: nb_connections.clone(),
+            stats: stats.clone(),
+            close: Arc::new(Notify::new()),
+        });
+
+        let fut = {
+            let waiters = waiters.clone();
+            let client_arrived = client_arrived.clone();
+            async move {
                 scopeguard::defer!({
                     server.lock().remove(&local_srv2);
+                    registry.unregister(id);
+                    // Dropping the waiting clients stops them
+                    let mut waiters = waiters.lock();
+                    waiters.closed = true;
+                    waiters.queue.clear();
+                    waiters.pending.clear();
                 });
 
+                let idle_timeout = timeouts.idle;
                 let mut timer = time::interval(idle_timeout);
-                pin_mut!(listening_server);
+                let no_connection = time::sleep(timeouts.no_connection.unwrap_or(Duration::MAX));
+                let max_lifetime = time::sleep(timeouts.max_lifetime.unwrap_or(Duration::MAX));
+                pin_mut!(listening_server, no_connection, max_lifetime);
                 loop {
                     select! {
                         biased;
@@ -77,42 +222,373 @@ impl<T: TunnelListener> ReverseTunnelServer<T> {
                                     continue;
                                 }
                                 Some(Ok(cnx)) => {
-                                    if time::timeout(idle_timeout, tx.send(cnx)).await.is_err() {
+                                    if time::timeout(idle_timeout, dispatch(&waiters, &client_arrived, cnx)).await.is_err() {
                                         info!("New reverse connection failed to be picked by client after {}s. Closing reverse tunnel server", idle_timeout.as_secs());
                                         break;
                                     }
+                                    if let Some(timeout) = timeouts.no_connection {
+                                        no_connection.as_mut().reset(time::Instant::now() + timeout);
+                                    }
                                 }
                             }
                         },
                         _ = timer.tick() => {
 
                             // if no client connected to the reverse tunnel server, close it
-                            // <= 1 because the server itself has a receiver
-                            if seen_clients.swap(0, Ordering::Relaxed) == 0 && tx.receiver_count() <= 1 {
+                            let no_waiting_client = {
+                                let mut waiters = waiters.lock();
+                                waiters.queue.retain(|waiter| !waiter.is_closed());
+                                waiters.queue.is_empty()
+                            };
+                            if seen_clients.swap(0, Ordering::Relaxed) == 0 && no_waiting_client {
                                 info!("No client connected to reverse tunnel server for {}s. Closing reverse tunnel server", idle_timeout.as_secs());
                                 break;
                             }
                         },
+                        _ = &mut no_connection, if timeouts.no_connection.is_some() => {
+                            info!("No connection accepted by reverse tunnel server for {}s. Closing reverse tunnel server", timeouts.no_connection.unwrap_or_default().as_secs());
+                            break;
+                        },
+                        _ = &mut max_lifetime, if timeouts.max_lifetime.is_some() => {
+                            info!("Reverse tunnel server reached its max lifetime of {}s. Closing reverse tunnel server", timeouts.max_lifetime.unwrap_or_default().as_secs());
+                            break;
+                        },
+                        _ = close.notified() => {
+                            info!("Reverse tunnel server closed by an admin request");
+                            break;
+                        },
+                        _ = timeouts.cancel.cancelled() => {
+                            info!("Reverse tunnel server closed as the server stops");
+                            break;
+                        },
                     }
                 }
                 info!("Stopping listening reverse server");
-            };
-
-            tokio::spawn(fut.instrument(Span::current()));
-            let item = ReverseTunnelItem {
-                receiver: rx,
-                nb_seen_clients,
-            };
-            self.servers.lock().insert(bind_addr, item.clone());
-            item
+            }
         };
 
-        item.nb_seen_clients.fetch_add(1, Ordering::Relaxed);
-        let cnx = item
-            .receiver
-            .recv()
+        tokio::spawn(fut.instrument(Span::current()));
+        let item = ReverseTunnelItem {
+            waiters,
+            client_arrived,
+            nb_seen_clients,
+            nb_waiting_clients,
+            nb_connections,
+            stats,
+            owner: Arc::from(owner),
+        };
+        self.servers.lock().insert(bind_addr, item.clone());
+        item
+    }
+}
+
+/// Give the connection to the client waiting for the longest time, else keep it for the next client. Waits for a
+/// client when too many connections are already kept
+async fn dispatch<T: TunnelListener>(waiters: &Mutex<Waiters<T>>, client_arrived: &Notify, mut cnx: Connection<T>) {
+    loop {
+        {
+            let mut waiters = waiters.lock();
+            // The client may have stopped waiting, the connection then goes to the next one
+            while let Some(waiter) = waiters.queue.pop_front() {
+                match waiter.send(cnx) {
+                    Ok(()) => return,
+                    Err(returned) => cnx = returned,
+                }
+            }
+            if waiters.pending.len() < MAX_PENDING_CONNECTIONS {
+                waiters.pending.push_back(cnx);
+                return;
+            }
+        }
+        client_arrived.notified().await;
+    }
+}
+
+struct RegisteredTunnel {
+    protocol: &'static str,
+    bind: SocketAddr,
+    owner: String,
+    since: SystemTime,
+    nb_waiting_clients: Arc<AtomicUsize>,
+    nb_connections: Arc<AtomicU64>,
+    stats: Arc<TransferStats>,
+    close: Arc<Notify>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReverseTunnelInfo {
+    pub id: u64,
+    pub protocol: &'static str,
+    pub bind: SocketAddr,
+    /// Identity of the client which requested the reverse tunnel
+    pub owner: String,
+    /// Unix timestamp in seconds
+    pub since: u64,
+    /// Clients currently waiting for a connection, incoming connections are balanced between them
+    pub clients: usize,
+    pub connections: u64,
+    /// Bytes received from the connections accepted by the reverse tunnel server, and sent to them
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// Reverse tunnel servers currently listening, of all protocols
+pub struct ReverseTunnelRegistry {
+    next_id: AtomicU64,
+    tunnels: Mutex<BTreeMap<u64, RegisteredTunnel>>,
+}
+
+impl Default for ReverseTunnelRegistry {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            tunnels: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl ReverseTunnelRegistry {
+    fn register(&self, tunnel: RegisteredTunnel) -> (u64, Arc<Notify>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let close = tunnel.close.clone();
+        self.tunnels.lock().insert(id, tunnel);
+        (id, close)
+    }
+
+    fn unregister(&self, id: u64) {
+        self.tunnels.lock().remove(&id);
+    }
+
+    pub fn list(&self) -> Vec<ReverseTunnelInfo> {
+        self.tunnels
+            .lock()
+            .iter()
+            .map(|(id, tunnel)| ReverseTunnelInfo {
+                id: *id,
+                protocol: tunnel.protocol,
+                bind: tunnel.bind,
+                owner: tunnel.owner.clone(),
+                since: tunnel
+                    .since
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                clients: tunnel.nb_waiting_clients.load(Ordering::Relaxed),
+                connections: tunnel.nb_connections.load(Ordering::Relaxed),
+                bytes_in: tunnel.stats.sent(),
+                bytes_out: tunnel.stats.received(),
+            })
+            .collect()
+    }
+
+    /// Stop the reverse tunnel server from listening. Already established connections are kept.
+    /// Return false if there is no reverse tunnel with this id
+    pub fn close(&self, id: u64) -> bool {
+        let Some(tunnel) = self.tunnels.lock().get(&id).map(|tunnel| tunnel.close.clone()) else {
+            return false;
+        };
+        tunnel.notify_one();
+        true
+    }
+}
+
+/// Keep track of the port allocated to each client identity for isolated reverse listeners.
+/// An identity keeps the same port across reconnections, and a port is never shared between 2 identities.
+pub struct IsolatedPortAllocator {
+    ports: Mutex<AHashMap<String, u16>>,
+}
+
+impl IsolatedPortAllocator {
+    pub fn new() -> Self {
+        Self {
+            ports: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    pub fn allocate(&self, identity: &str, port_ranges: &[RangeInclusive<u16>]) -> anyhow::Result<u16> {
+        let mut ports = self.ports.lock();
+        if let Some(port) = ports.get(identity) {
+            if port_ranges.iter().any(|range| range.contains(port)) {
+                return Ok(*port);
+            }
+        }
+
+        let port = port_ranges
+            .iter()
+            .flat_map(|range| range.clone())
+            .find(|port| !ports.values().any(|p| p == port))
+            .ok_or_else(|| anyhow!("No more isolated port available for client {}", identity))?;
+        ports.insert(identity.to_string(), port);
+
+        Ok(port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::LocalProtocol;
+    use std::future;
+    use tokio::io::DuplexStream;
+    use tokio_stream::wrappers::ReceiverStream;
+    use url::Host;
+
+    type Cnx = anyhow::Result<((DuplexStream, DuplexStream), RemoteAddr)>;
+
+    fn new_cnx(port: u16) -> Cnx {
+        let (rx, tx) = tokio::io::duplex(16);
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::ReverseTcp,
+            host: Host::Ipv4([127, 0, 0, 1].into()),
+            port,
+        };
+        Ok(((rx, tx), remote))
+    }
+
+    #[tokio::test]
+    async fn test_reverse_listener_balanced_between_clients() {
+        let timeouts = ReverseTunnelTimeouts {
+            idle: Duration::from_secs(60),
+            no_connection: None,
+            max_lifetime: None,
+            cancel: CancellationToken::new(),
+        };
+        let bind: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let registry = Arc::new(ReverseTunnelRegistry::default());
+        let server = Arc::new(ReverseTunnelServer::new("tcp", registry.clone()));
+        let (tx, rx) = tokio::sync::mpsc::channel::<Cnx>(10);
+        server.register_listening_server(bind, timeouts.clone(), "client", ReceiverStream::new(rx));
+
+        // clients of another owner can't use the listener
+        let not_started = future::pending::<anyhow::Result<ReceiverStream<Cnx>>>();
+        assert!(server
+            .run_listening_server(bind, timeouts.clone(), "another client", not_started)
             .await
-            .map_err(|_| anyhow!("listening reverse server stopped"))?;
-        Ok(cnx)
+            .is_err());
+
+        // connections are dispatched in the order the clients started to wait
+        let mut clients = vec![];
+        for _ in 0..3 {
+            let server = server.clone();
+            let timeouts = timeouts.clone();
+            let waiting = registry.list().into_iter().find(|t| t.bind == bind).unwrap().clients;
+            clients.push(tokio::spawn(async move {
+                let not_started = future::pending::<anyhow::Result<ReceiverStream<Cnx>>>();
+                let (_, remote) = server
+                    .run_listening_server(bind, timeouts, "client", not_started)
+                    .await
+                    .unwrap();
+                remote.port
+            }));
+            while registry.list().into_iter().find(|t| t.bind == bind).unwrap().clients == waiting {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        // a client which stopped waiting is skipped
+        let stopped = clients.remove(1);
+        stopped.abort();
+        assert!(stopped.await.is_err());
+
+        tx.send(new_cnx(1)).await.unwrap();
+        tx.send(new_cnx(2)).await.unwrap();
+        assert_eq!(clients.remove(0).await.unwrap(), 1);
+        assert_eq!(clients.remove(0).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reverse_listener_allocated_before_client() {
+        // i.e: bound on port 0 by the server, the client asks for the port chosen once it gets it
+        let timeouts = ReverseTunnelTimeouts {
+            idle: Duration::from_secs(60),
+            no_connection: None,
+            max_lifetime: None,
+            cancel: CancellationToken::new(),
+        };
+        let bind: SocketAddr = "127.0.0.1:5".parse().unwrap();
+        let registry = Arc::new(ReverseTunnelRegistry::default());
+        let server = ReverseTunnelServer::new("tcp", registry.clone());
+        let (tx, rx) = tokio::sync::mpsc::channel::<Cnx>(10);
+        server.register_listening_server(bind, timeouts.clone(), "client", ReceiverStream::new(rx));
+        assert!(registry.list().into_iter().any(|t| t.bind == bind));
+
+        // the registered listener is used, instead of binding the port again
+        tx.send(new_cnx(1)).await.unwrap();
+        let already_bound = future::ready(Err::<ReceiverStream<Cnx>, _>(anyhow!("port already bound")));
+        let (_, remote) = server
+            .run_listening_server(bind, timeouts, "client", already_bound)
+            .await
+            .unwrap();
+        assert_eq!(remote.port, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reverse_listener_timeouts() {
+        let registry = Arc::new(ReverseTunnelRegistry::default());
+        let is_listening = |bind: SocketAddr| registry.list().into_iter().any(|t| t.bind == bind);
+        let server = ReverseTunnelServer::new("tcp", registry.clone());
+
+        // closed when no connection is accepted, even if it is still used
+        let no_connection = ReverseTunnelTimeouts {
+            idle: Duration::from_secs(60),
+            no_connection: Some(Duration::from_millis(100)),
+            max_lifetime: None,
+            cancel: CancellationToken::new(),
+        };
+        let bind: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel::<Cnx>(10);
+        server.register_listening_server(bind, no_connection, "client", ReceiverStream::new(rx));
+        assert!(is_listening(bind));
+        time::sleep(Duration::from_millis(60)).await;
+        tx.send(new_cnx(1)).await.unwrap();
+        time::sleep(Duration::from_millis(60)).await;
+        assert!(is_listening(bind));
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!is_listening(bind));
+
+        // closed after its max lifetime, even if it accepts connections
+        let max_lifetime = ReverseTunnelTimeouts {
+            idle: Duration::from_secs(60),
+            no_connection: None,
+            max_lifetime: Some(Duration::from_millis(100)),
+            cancel: CancellationToken::new(),
+        };
+        let bind: SocketAddr = "127.0.0.1:3".parse().unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel::<Cnx>(10);
+        server.register_listening_server(bind, max_lifetime, "client", ReceiverStream::new(rx));
+        tx.send(new_cnx(1)).await.unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!is_listening(bind));
+
+        // closed once the server stops
+        let stopped = ReverseTunnelTimeouts {
+            idle: Duration::from_secs(60),
+            no_connection: None,
+            max_lifetime: None,
+            cancel: CancellationToken::new(),
+        };
+        let bind: SocketAddr = "127.0.0.1:4".parse().unwrap();
+        let (_tx, rx) = tokio::sync::mpsc::channel::<Cnx>(10);
+        server.register_listening_server(bind, stopped.clone(), "client", ReceiverStream::new(rx));
+        assert!(is_listening(bind));
+        stopped.cancel.cancel();
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!is_listening(bind));
+    }
+
+    #[test]
+    fn test_isolated_port_allocation() {
+        let allocator = IsolatedPortAllocator::new();
+        let ranges = vec![2000..=2001];
+
+        assert_eq!(allocator.allocate("client1", &ranges).unwrap(), 2000);
+        assert_eq!(allocator.allocate("client2", &ranges).unwrap(), 2001);
+        // same identity get back the same port
+        assert_eq!(allocator.allocate("client1", &ranges).unwrap(), 2000);
+        // range exhausted
+        assert!(allocator.allocate("client3", &ranges).is_err());
+        // port is re-allocated if the range changed
+        assert_eq!(allocator.allocate("client1", &[3000..=3000]).unwrap(), 3000);
+        assert_eq!(allocator.allocate("client3", &ranges).unwrap(), 2000);
     }
 }
@@ -0,0 +1,259 @@
+use crate::tunnel::server::bearer_auth::BearerClaims;
+use crate::tunnel::server::http_client::http_request;
+use crate::tunnel::spawn_until_cancelled;
+use ahash::AHashSet;
+use anyhow::{anyhow, Context};
+use arc_swap::ArcSwap;
+use hyper::Method;
+use notify::{RecommendedWatcher, Watcher};
+use ring::digest;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use url::Url;
+
+const URL_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const URL_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Credentials which must not be accepted anymore, even if they are still valid.
+/// One per line, '#' starts a comment:
+/// path_prefix:SECRET  => the path prefix (static secret) used by clients
+/// jti:ID              => a bearer token by its id
+/// sub:SUBJECT         => all the bearer tokens of a subject
+/// sha256:HEX          => a bearer token by the sha256 of the token itself
+#[derive(Debug, Default)]
+struct RevokedCredentials {
+    path_prefixes: AHashSet<String>,
+    token_ids: AHashSet<String>,
+    subjects: AHashSet<String>,
+    token_hashes: AHashSet<String>,
+}
+
+enum RevocationSource {
+    File(PathBuf),
+    Url(Url),
+}
+
+/// Revocation list of the server, reloaded when its file changes (or on SIGHUP), or polled when it is an url
+pub struct RevocationList {
+    revoked: Arc<ArcSwap<RevokedCredentials>>,
+    _fs_watcher: Option<RecommendedWatcher>,
+}
+
+impl RevokedCredentials {
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut revoked = Self::default();
+        for (ix, line) in content.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (kind, value) = line
+                .split_once(':')
+                .ok_or_else(|| anyhow!("line {}: expected KIND:VALUE, got {}", ix + 1, line))?;
+            let value = value.trim().to_string();
+            match kind.trim() {
+                "path_prefix" => revoked.path_prefixes.insert(value),
+                "jti" => revoked.token_ids.insert(value),
+                "sub" => revoked.subjects.insert(value),
+                "sha256" => revoked.token_hashes.insert(value.to_ascii_lowercase()),
+                kind => {
+                    return Err(anyhow!(
+                        "line {}: unknown kind {}, expected path_prefix, jti, sub or sha256",
+                        ix + 1,
+                        kind
+                    ))
+                }
+            };
+        }
+
+        Ok(revoked)
+    }
+
+    fn check(&self, path_prefix: &str, claims: Option<&BearerClaims>, token: Option<&str>) -> Result<(), &'static str> {
+        if self.path_prefixes.contains(path_prefix) {
+            return Err("path prefix is revoked");
+        }
+        if let Some(claims) = claims {
+            if claims.id.as_ref().is_some_and(|id| self.token_ids.contains(id)) {
+                return Err("bearer token id is revoked");
+            }
+            if claims.subject.as_ref().is_some_and(|sub| self.subjects.contains(sub)) {
+                return Err("bearer token subject is revoked");
+            }
+        }
+        if let Some(token) = token.filter(|_| !self.token_hashes.is_empty()) {
+            let hash = digest::digest(&digest::SHA256, token.as_bytes());
+            let hash: String = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+            if self.token_hashes.contains(&hash) {
+                return Err("bearer token is revoked");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl RevocationList {
+    /// Load the revocation list from a file path or an http(s) url. It is reloaded until the token is cancelled
+    pub async fn new(source: &str, cancel: &CancellationToken) -> anyhow::Result<Self> {
+        let source = match Url::parse(source) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => RevocationSource::Url(url),
+            _ => RevocationSource::File(PathBuf::from(source)),
+        };
+        let revoked = Arc::new(ArcSwap::from_pointee(load(&source).await?));
+
+        let fs_watcher = match source {
+            RevocationSource::File(path) => {
+                #[cfg(unix)]
+                reload_on_sighup(path.clone(), revoked.clone(), cancel)?;
+                watch_file(path, revoked.clone())
+                    .map_err(|err| warn!("Cannot watch revocation list for changes: {:?}", err))
+                    .ok()
+            }
+            RevocationSource::Url(url) => {
+                poll_url(url, revoked.clone(), cancel);
+                None
+            }
+        };
+
+        Ok(Self {
+            revoked,
+            _fs_watcher: fs_watcher,
+        })
+    }
+
+    /// Ok if none of the credentials of the client is revoked, otherwise which one is
+    pub(super) fn check(
+        &self,
+        path_prefix: &str,
+        claims: Option<&BearerClaims>,
+        token: Option<&str>,
+    ) -> Result<(), &'static str> {
+        self.revoked.load().check(path_prefix, claims, token)
+    }
+}
+
+async fn load(source: &RevocationSource) -> anyhow::Result<RevokedCredentials> {
+    let content = match source {
+        RevocationSource::File(path) => {
+            std::fs::read_to_string(path).with_context(|| format!("Cannot read revocation list {}", path.display()))?
+        }
+        RevocationSource::Url(url) => {
+            let (status, body) = http_request(Method::GET, url, None, URL_FETCH_TIMEOUT).await?;
+            if !status.is_success() {
+                return Err(anyhow!("http error {} while fetching revocation list {}", status, url));
+            }
+            String::from_utf8(body.to_vec())?
+        }
+    };
+
+    RevokedCredentials::parse(&content).context("Invalid revocation list")
+}
+
+fn reload(path: &Path, revoked: &ArcSwap<RevokedCredentials>) {
+    let credentials = std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| RevokedCredentials::parse(&content));
+    match credentials {
+        Ok(credentials) => {
+            info!("Revocation list {} has been reloaded", path.display());
+            revoked.store(Arc::new(credentials));
+        }
+        Err(err) => error!("Cannot reload revocation list, keeping the old one. Error: {:?}", err),
+    }
+}
+
+/// Watch the directory of the file, so it is still seen when it is replaced instead of modified in place
+fn watch_file(path: PathBuf, revoked: Arc<ArcSwap<RevokedCredentials>>) -> anyhow::Result<RecommendedWatcher> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path.file_name().map(ToOwned::to_owned);
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+            if event.paths.iter().any(|p| p.file_name() == file_name.as_deref()) {
+                reload(&path, &revoked);
+            }
+        }
+        Ok(_) => {}
+        Err(err) => error!("Error while watching revocation list for changes {:?}", err),
+    })?;
+    watcher.watch(&dir, notify::RecursiveMode::NonRecursive)?;
+
+    Ok(watcher)
+}
+
+#[cfg(unix)]
+fn reload_on_sighup(
+    path: PathBuf,
+    revoked: Arc<ArcSwap<RevokedCredentials>>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = signal(SignalKind::hangup()).with_context(|| "Cannot listen for SIGHUP")?;
+    spawn_until_cancelled(cancel, async move {
+        while sighup.recv().await.is_some() {
+            reload(&path, &revoked);
+        }
+    });
+
+    Ok(())
+}
+
+fn poll_url(url: Url, revoked: Arc<ArcSwap<RevokedCredentials>>, cancel: &CancellationToken) {
+    spawn_until_cancelled(cancel, async move {
+        loop {
+            tokio::time::sleep(URL_REFRESH_INTERVAL).await;
+            match load(&RevocationSource::Url(url.clone())).await {
+                Ok(credentials) => revoked.store(Arc::new(credentials)),
+                Err(err) => warn!("Cannot refresh revocation list {}, keeping the old one: {:?}", url, err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const REVOCATION_LIST: &str = r#"
+# leaked laptop
+path_prefix:old-secret
+jti:01J9Z
+sub:bob@example.com   # left the company
+sha256:2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824
+"#;
+
+    fn claims(id: &str, subject: &str) -> BearerClaims {
+        BearerClaims {
+            id: Some(id.to_string()),
+            subject: Some(subject.to_string()),
+            scopes: vec![],
+        }
+    }
+
+    #[test_case("v1", None, None => Ok(()) ; "not revoked")]
+    #[test_case("old-secret", None, None => Err("path prefix is revoked") ; "path prefix")]
+    #[test_case("v1", Some(claims("01J9Z", "alice@example.com")), None => Err("bearer token id is revoked") ; "token id")]
+    #[test_case("v1", Some(claims("01JA0", "bob@example.com")), None => Err("bearer token subject is revoked") ; "subject")]
+    #[test_case("v1", Some(claims("01JA0", "alice@example.com")), Some("hello") => Err("bearer token is revoked") ; "token hash")]
+    #[test_case("v1", Some(claims("01JA0", "alice@example.com")), Some("world") => Ok(()) ; "other token")]
+    fn test_check(path_prefix: &str, claims: Option<BearerClaims>, token: Option<&str>) -> Result<(), &'static str> {
+        let revoked = RevokedCredentials::parse(REVOCATION_LIST).unwrap();
+        revoked.check(path_prefix, claims.as_ref(), token)
+    }
+
+    #[test_case("old-secret" ; "missing kind")]
+    #[test_case("password:old-secret" ; "unknown kind")]
+    fn test_parse_invalid(content: &str) {
+        assert!(RevokedCredentials::parse(content).is_err());
+    }
+}
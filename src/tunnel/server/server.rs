@@ -1,46 +1,94 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
 use futures_util::FutureExt;
 use http_body_util::Either;
+use ipnet::IpNet;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 
+use crate::health::HEALTH;
+use crate::instance::{InstanceState, InstanceStatus};
 use crate::protocols;
-use crate::tunnel::{try_to_sock_addr, LocalProtocol, RemoteAddr};
+use crate::tunnel::{spawn_until_cancelled, try_to_sock_addr, LocalProtocol, RemoteAddr};
 use arc_swap::ArcSwap;
 use hyper::body::Incoming;
+use hyper::header::{HeaderMap, HeaderValue};
 use hyper::server::conn::{http1, http2};
 use hyper::service::service_fn;
 use hyper::{http, Request, StatusCode, Version};
 use hyper_util::rt::{TokioExecutor, TokioTimer};
 use parking_lot::Mutex;
 use socket2::SockRef;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::pin::Pin;
-use std::sync::{Arc, LazyLock};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::bench::{self, BENCH_ENDPOINT};
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::packet::PacketNetwork;
 use crate::protocols::tls;
 use crate::restrictions::config_reloader::RestrictionsRulesReloader;
-use crate::restrictions::types::{RestrictionConfig, RestrictionsRules};
+use crate::restrictions::geoip::{GeoIpDatabases, SourceFilter};
+use crate::restrictions::types::{ProxyProtocolConfig, RestrictionConfig, RestrictionsRules};
+use crate::sandbox;
+use crate::secret::redact_path_prefix;
 use crate::somark::SoMark;
+use crate::tunnel::active_tunnels::{ActiveTunnel, FailureReason};
+use crate::tunnel::capture::{CaptureFile, CaptureFlow, CaptureStream};
+use crate::tunnel::client::{CountingStream, TransferStats};
 use crate::tunnel::connectors::{TcpTunnelConnector, TunnelConnector, UdpTunnelConnector};
-use crate::tunnel::listeners::{HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener};
+use crate::tunnel::events::{TunnelEvent, TunnelEvents};
+use crate::tunnel::interceptor::{intercept, Interceptors, StreamInfo};
+use crate::tunnel::listeners::{
+    ClientAddr, HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener,
+};
+use crate::tunnel::server::access_log::{HttpAccessEntry, HttpAccessLog};
+use crate::tunnel::server::audit::{open_tunnel, AuditLog, AuditStream};
+use crate::tunnel::server::auth_webhook::{AuthWebhook, AuthWebhookRequest};
+use crate::tunnel::server::ban::BanPolicy;
+use crate::tunnel::server::bearer_auth::{bearer_token, BearerAuth};
+use crate::tunnel::server::event_handler::{AuthInfo, TunnelEventHandler, TunnelInfo};
+use crate::tunnel::server::forwarded_header::ForwardedHeaderStream;
 use crate::tunnel::server::handler_http2::http_server_upgrade;
+use crate::tunnel::server::handler_transport::transport_server_upgrade;
 use crate::tunnel::server::handler_websocket::ws_server_upgrade;
-use crate::tunnel::server::reverse_tunnel::ReverseTunnelServer;
+use crate::tunnel::server::ingress::run_ingress_server;
+use crate::tunnel::server::ldap_auth::LdapAuth;
+use crate::tunnel::server::privileges::PrivilegeDrop;
+use crate::tunnel::server::quota::{QuotaExhausted, QuotaStream};
+use crate::tunnel::server::rate_limit::RateLimiter;
+use crate::tunnel::server::reverse_tunnel::ReverseTunnelTimeouts;
+use crate::tunnel::server::revocation::RevocationList;
+use crate::tunnel::server::session::SessionDeadlineStream;
+use crate::tunnel::server::totp::TotpVerifier;
 use crate::tunnel::server::utils::{
-    bad_request, extract_path_prefix, extract_tunnel_info, extract_x_forwarded_for, find_mapped_port, validate_tunnel,
-    HttpResponse,
+    bad_request, client_identity, explain_rejection, extract_connection_id, extract_path_prefix, extract_tunnel_info,
+    extract_x_forwarded_for, find_isolated_ports, find_mapped_port, find_tunnel_rule, forbidden,
+    is_allowed_destination, is_allowed_packet_destination, is_allowed_source, payment_required, protocol_name,
+    source_ip, too_many_requests, unauthorized, validate_tunnel, HttpResponse,
 };
+use crate::tunnel::server::ServerRegistries;
+use crate::tunnel::spa::SpaGate;
 use crate::tunnel::tls_reloader::TlsReloader;
+use crate::tunnel::transport::io::SlowConsumerTimeout;
+use crate::tunnel::transport::{
+    TunnelTransport, BOUND_ADDR_HEADER, PEER_ADDR_HEADER, REVERSE_LISTENER_HEADER, TOTP_HEADER,
+};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+use tokio::task::JoinSet;
 use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use tokio_rustls::TlsAcceptor;
-use tracing::{error, info, span, warn, Instrument, Level, Span};
-use url::Url;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, span, warn, Instrument, Level, Span};
+use url::{Host, Url};
+
+/// The clients choose the permissions of their reverse unix sockets, but cannot open them to the other users
+#[cfg(unix)]
+const REVERSE_UNIX_SOCKET_MODE_MASK: u32 = 0o770;
 
 #[derive(Debug)]
 pub struct TlsServerConfig {
@@ -56,13 +104,58 @@ pub struct WsServerConfig {
     pub socket_so_mark: SoMark,
     pub bind: SocketAddr,
     pub websocket_ping_frequency: Option<Duration>,
+    /// Report the sides of the tunnels not accepting writes for this long
+    pub slow_consumer_timeout: Option<Duration>,
     pub timeout_connect: Duration,
     pub websocket_mask_frame: bool,
     pub tls: Option<TlsServerConfig>,
     pub dns_resolver: DnsResolver,
     pub restriction_config: Option<PathBuf>,
+    pub deny_private_destinations: bool,
+    pub allow_from: Vec<SourceFilter>,
+    pub deny_from: Vec<SourceFilter>,
+    pub trusted_proxies: Vec<IpNet>,
     pub http_proxy: Option<Url>,
     pub remote_server_idle_timeout: Duration,
+    pub remote_server_no_connection_timeout: Option<Duration>,
+    pub remote_server_max_lifetime: Option<Duration>,
+    pub exec_commands: HashMap<String, String>,
+    /// Answer the measures of 'wstunnel bench' on the exec destination wstunnel-bench
+    pub bench_endpoint: bool,
+    pub http_ingress: Vec<Url>,
+    pub bearer_auth: Option<BearerAuth>,
+    pub ldap_auth: Option<LdapAuth>,
+    pub revocation_list: Option<RevocationList>,
+    pub totp_verifier: Option<TotpVerifier>,
+    pub ban_policy: Option<BanPolicy>,
+    pub audit_log: Option<Arc<AuditLog>>,
+    pub access_log: Option<Arc<HttpAccessLog>>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub reuse_port: bool,
+    pub accept_loops: u16,
+    pub spa_gate: Option<Arc<SpaGate>>,
+    pub auth_webhook: Option<AuthWebhook>,
+    pub event_handler: Option<Arc<dyn TunnelEventHandler>>,
+    /// Of a third party, instead of websocket or http2
+    pub transport: Option<Arc<dyn TunnelTransport>>,
+    /// Enabled by the interceptors of the restrictions
+    pub interceptors: Interceptors,
+    /// Where the packets of the tun tunnels go, they are rejected without it
+    pub packet_network: Option<Arc<dyn PacketNetwork>>,
+    pub privilege_drop: Option<PrivilegeDrop>,
+    pub restrict_syscalls: bool,
+    /// Cancelled when the server stops, with its listeners and tunnels
+    pub cancel: CancellationToken,
+    /// Subscribed to by the event webhook
+    pub events: TunnelEvents,
+    /// Open tunnels and status of the server, shared with the program running it
+    pub instance: InstanceState,
+    /// Reverse tunnels, bans and quotas of this server only
+    pub registries: Arc<ServerRegistries>,
+    /// Written with the plaintext of the tunnels, with --capture-file
+    pub capture: Option<Arc<CaptureFile>>,
+    /// Looked up by the geoip matchers of the restrictions and of allow_from/deny_from
+    pub geoip: Arc<GeoIpDatabases>,
 }
 
 #[derive(Clone)]
@@ -77,69 +170,399 @@ impl WsServer {
         }
     }
 
-    pub(super) async fn handle_tunnel_request(
+    /// Count a failure of the client towards its ban, if banning abusive sources is enabled
+    pub(super) fn record_failure(&self, ip: IpAddr, reason: &str) {
+        if let Some(policy) = &self.config.ban_policy {
+            self.config.registries.bans.record_failure(ip, policy, reason);
+        }
+    }
+
+    pub(super) fn access_log_entry<B>(&self, req: &Request<B>, client_addr: SocketAddr) -> Option<HttpAccessEntry> {
+        self.config
+            .access_log
+            .as_ref()
+            .map(|log| log.entry(req, client_addr.ip()))
+    }
+
+    /// Report the event to the subscribers of the server, i.e: the event webhook
+    fn send_event(&self, event: TunnelEvent) {
+        self.config.events.send(event);
+    }
+
+    /// The client failed to authenticate, count it towards its ban and report it
+    fn record_auth_failure(&self, ip: IpAddr, path_prefix: &str, reason: &str) {
+        self.record_failure(ip, reason);
+        self.send_event(TunnelEvent::AuthFailed {
+            source: ip,
+            reason: reason.to_string(),
+        });
+        if let Some(handler) = &self.config.event_handler {
+            handler.on_auth(&AuthInfo {
+                source: ip,
+                path_prefix: path_prefix.to_string(),
+                subject: None,
+                failure: Some(reason.to_string()),
+            });
+        }
+    }
+
+    pub(super) async fn handle_tunnel_request<B>(
         &self,
         restrictions: Arc<RestrictionsRules>,
         restrict_path_prefix: Option<String>,
         mut client_addr: SocketAddr,
-        req: &Request<Incoming>,
+        req: &Request<B>,
     ) -> Result<
         (
             RemoteAddr,
             Pin<Box<dyn AsyncRead + Send>>,
             Pin<Box<dyn AsyncWrite + Send>>,
             bool,
+            HeaderMap,
         ),
         HttpResponse,
     > {
+        // Known before the tunnel info is decoded, so the logs of rejected requests can be correlated with the client
+        if let Some(connection_id) = extract_connection_id(req.headers()) {
+            Span::current().record("connection_id", connection_id.to_string());
+        }
+
+        // Bans apply to the peer, the X-Forwarded-For header can be forged
+        let peer_ip = client_addr.ip();
+        let mut forwarded_for = None;
         if let Some((x_forward_for, x_forward_for_str)) = extract_x_forwarded_for(req) {
             info!("Request X-Forwarded-For: {x_forward_for:?}");
             Span::current().record("forwarded_for", x_forward_for_str);
-            client_addr.set_ip(x_forward_for);
+            forwarded_for = Some(x_forward_for);
         };
+        client_addr.set_ip(source_ip(req, peer_ip, &self.config.trusted_proxies));
 
         let path_prefix = extract_path_prefix(req.uri().path()).map_err(|err| {
-            warn!("Rejecting connection with {err}: {}", req.uri());
+            warn!("Rejecting connection with {err}: {}", redact_path_prefix(req.uri().path()));
+            self.record_failure(peer_ip, "bad path prefix");
             bad_request()
         })?;
 
         if let Some(restrict_path) = restrict_path_prefix {
             if path_prefix != restrict_path {
                 warn!(
-                    "Client requested upgrade path '{path_prefix}' does not match upgrade path restriction '{restrict_path}' (mTLS, etc.)"
+                    "Client requested upgrade path does not match upgrade path restriction '{restrict_path}' (mTLS, etc.)"
                 );
+                self.record_auth_failure(peer_ip, path_prefix, "upgrade path restriction violation");
                 return Err(bad_request());
             }
         }
 
-        let jwt = extract_tunnel_info(req)?;
+        let claims = match &self.config.bearer_auth {
+            Some(bearer_auth) => {
+                let claims = bearer_auth.authenticate(req.headers()).map_err(|err| {
+                    warn!("Rejecting connection with invalid bearer token: {err:?}");
+                    self.record_auth_failure(peer_ip, path_prefix, "invalid bearer token");
+                    unauthorized()
+                })?;
+                info!("Bearer token accepted for subject {:?}", claims.subject);
+                Some(claims)
+            }
+            None => None,
+        };
+        let claims = match &self.config.ldap_auth {
+            Some(ldap_auth) => {
+                let claims = ldap_auth
+                    .authenticate(req.headers())
+                    .instrument(info_span!("ldap_auth"))
+                    .await
+                    .map_err(|err| {
+                        warn!("Rejecting connection with invalid LDAP credentials: {err:?}");
+                        self.record_auth_failure(peer_ip, path_prefix, "invalid LDAP credentials");
+                        unauthorized()
+                    })?;
+                info!("LDAP credentials accepted for user {:?}", claims.subject);
+                Some(claims)
+            }
+            None => claims,
+        };
+
+        if let Some(revocation_list) = &self.config.revocation_list {
+            revocation_list
+                .check(path_prefix, claims.as_ref(), bearer_token(req.headers()))
+                .map_err(|reason| {
+                    warn!("Rejecting connection with revoked credentials: {reason}");
+                    self.record_auth_failure(peer_ip, path_prefix, "revoked credentials");
+                    unauthorized()
+                })?;
+        }
+
+        if let Some(totp_verifier) = &self.config.totp_verifier {
+            let code = req.headers().get(TOTP_HEADER).and_then(|h| h.to_str().ok());
+            totp_verifier
+                .verify(path_prefix, claims.as_ref(), code, peer_ip)
+                .map_err(|reason| {
+                    warn!("Rejecting connection with invalid second factor: {reason}");
+                    self.record_auth_failure(peer_ip, path_prefix, "invalid TOTP code");
+                    unauthorized()
+                })?;
+        }
+
+        if let Some(handler) = &self.config.event_handler {
+            let auth = AuthInfo {
+                source: peer_ip,
+                path_prefix: path_prefix.to_string(),
+                subject: claims.as_ref().and_then(|claims| claims.subject.clone()),
+                failure: None,
+            };
+            if !handler.on_auth(&auth) {
+                warn!("Rejecting connection denied by the event handler");
+                self.record_failure(peer_ip, "denied by the event handler");
+                return Err(unauthorized());
+            }
+        }
+
+        let jwt = extract_tunnel_info(req).inspect_err(|_| self.record_failure(peer_ip, "bad tunnel info"))?;
 
-        Span::current().record("id", &jwt.claims.id);
-        Span::current().record("remote", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
-        let remote = RemoteAddr::try_from(jwt.claims).map_err(|err| {
-            warn!("Rejecting connection with bad tunnel info: {err} {}", req.uri());
+        let tunnel_id = jwt.claims.id.clone();
+        Span::current().record("connection_id", &jwt.claims.id);
+        Span::current().record("destination", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
+        let mut remote = RemoteAddr::try_from(jwt.claims).map_err(|err| {
+            warn!(
+                "Rejecting connection with bad tunnel info: {err} {}",
+                redact_path_prefix(req.uri().path())
+            );
+            self.record_failure(peer_ip, "bad tunnel info");
             bad_request()
         })?;
 
-        let restriction = validate_tunnel(&remote, path_prefix, &restrictions).ok_or_else(|| {
-            warn!("Rejecting connection with not allowed destination: {remote:?}");
-            bad_request()
+        let client_ip = client_addr.ip();
+        let restriction = validate_tunnel(
+            &remote,
+            path_prefix,
+            claims.as_ref(),
+            client_ip,
+            &self.config.geoip,
+            &restrictions,
+        )
+        .ok_or_else(|| {
+            let reason = explain_rejection(
+                &remote,
+                path_prefix,
+                claims.as_ref(),
+                client_ip,
+                &self.config.geoip,
+                &restrictions,
+            );
+            warn!("Rejecting connection with not allowed destination: {remote:?}: {reason}");
+            self.record_failure(peer_ip, "restriction violation");
+            self.send_event(TunnelEvent::RestrictionDenied {
+                source: client_ip,
+                protocol: protocol_name(&remote.protocol),
+                destination: format!("{}:{}", remote.host, remote.port),
+                reason: reason.clone(),
+            });
+            forbidden(reason)
         })?;
+        if let Some(auth_webhook) = &self.config.auth_webhook {
+            let request = AuthWebhookRequest {
+                path_prefix: path_prefix.to_string(),
+                subject: claims.as_ref().and_then(|claims| claims.subject.clone()),
+                source: client_ip,
+                protocol: protocol_name(&remote.protocol),
+                host: remote.host.to_string(),
+                port: remote.port,
+                restriction: restriction.name.clone(),
+            };
+            auth_webhook
+                .authorize(request)
+                .instrument(info_span!("auth_webhook"))
+                .await
+                .map_err(|reason| {
+                    warn!("Rejecting connection denied by the authorization webhook: {remote:?}: {reason}");
+                    self.record_failure(peer_ip, "denied by the authorization webhook");
+                    self.send_event(TunnelEvent::RestrictionDenied {
+                        source: client_ip,
+                        protocol: protocol_name(&remote.protocol),
+                        destination: format!("{}:{}", remote.host, remote.port),
+                        reason: reason.clone(),
+                    });
+                    forbidden(reason)
+                })?;
+        }
         info!("Tunnel accepted due to matched restriction: {}", restriction.name);
+        let identity = client_identity(path_prefix, claims.as_ref(), restriction);
+        let quota = self
+            .config
+            .registries
+            .quotas
+            .acquire(restriction, &identity)
+            .map_err(|reason| {
+                warn!(
+                    "Rejecting connection of {identity} over the quota of restriction {}: {reason}",
+                    restriction.name
+                );
+                match reason {
+                    QuotaExhausted::Restriction(reason) => too_many_requests(reason),
+                    // A specific status, so clients can tell it apart from a temporary limit
+                    reason @ QuotaExhausted::Identity(_) => payment_required(reason.to_string()),
+                }
+            })?;
+
+        // Extra headers to send back to the client in the upgrade response
+        let mut response_headers = HeaderMap::new();
+
+        // Each client get its own reverse socks5 listener, instead of sharing the one it requested
+        if let (LocalProtocol::ReverseSocks5 { .. }, Some(port_ranges)) =
+            (&remote.protocol, find_isolated_ports(restriction))
+        {
+            remote.port = self
+                .config
+                .registries
+                .isolated_ports
+                .allocate(&identity, port_ranges)
+                .map_err(|err| {
+                    warn!("Rejecting connection: {err}");
+                    bad_request()
+                })?;
+            info!("Client is isolated on reverse listener {}:{}", remote.host, remote.port);
+            if let Ok(listener) = HeaderValue::from_str(&format!("{}:{}", remote.host, remote.port)) {
+                response_headers.insert(REVERSE_LISTENER_HEADER, listener);
+            }
+        }
 
         let req_protocol = remote.protocol.clone();
         let inject_cookie = req_protocol.is_dynamic_reverse_tunnel();
+        let active_tunnel = ActiveTunnel {
+            id: tunnel_id.clone(),
+            source: Some(client_addr),
+            destination: format!("{}:{}", remote.host, remote.port),
+            protocol: protocol_name(&remote.protocol),
+            identity: Some(identity.clone()),
+            started_at: Instant::now(),
+            close: None,
+        };
+        let tunnel_info = TunnelInfo {
+            id: tunnel_id,
+            source: peer_ip,
+            forwarded_for,
+            path_prefix: path_prefix.to_string(),
+            identity: identity.clone(),
+            subject: claims.as_ref().and_then(|claims| claims.subject.clone()),
+            protocol: protocol_name(&remote.protocol),
+            destination: TunnelInfo::destination(&remote),
+            restriction: restriction.name.clone(),
+        };
+        if let Some(handler) = &self.config.event_handler {
+            if !handler.on_stream_open(&tunnel_info) {
+                let reason = "denied by the event handler".to_string();
+                warn!("Rejecting connection {reason}: {remote:?}");
+                self.send_event(TunnelEvent::RestrictionDenied {
+                    source: client_ip,
+                    protocol: tunnel_info.protocol,
+                    destination: tunnel_info.destination,
+                    reason: reason.clone(),
+                });
+                return Err(forbidden(reason));
+            }
+        }
+        // The restrictions file may have been reloaded with interceptors which are not registered
+        let interceptors = self
+            .config
+            .interceptors
+            .select(&restriction.interceptors)
+            .map_err(|err| {
+                error!("Rejecting connection allowed by restriction {}: {err}", restriction.name);
+                bad_request()
+            })?;
+        let stream_info = StreamInfo {
+            id: tunnel_info.id.clone(),
+            protocol: tunnel_info.protocol.clone(),
+            destination: tunnel_info.destination.clone(),
+            restriction: Some(restriction.name.clone()),
+        };
+        let audited = self.config.audit_log.is_some()
+            || self.config.events.has_subscribers()
+            || self.config.event_handler.is_some();
+        let audit = audited.then_some(tunnel_info);
         let tunnel = self
-            .exec_tunnel(restriction, remote, client_addr)
+            .exec_tunnel(restriction, remote, client_addr, &identity, &mut response_headers)
+            .instrument(info_span!("connect_destination"))
             .await
             .map_err(|err| {
-                warn!("Rejecting connection with bad upgrade request: {err} {}", req.uri());
-                bad_request()
+                warn!(
+                    "Rejecting connection with bad upgrade request: {err} {}",
+                    redact_path_prefix(req.uri().path())
+                );
+                let mut response = bad_request();
+                response.extensions_mut().insert(FailureReason::classify(&err));
+                response
             })?;
 
-        let (remote_addr, local_rx, local_tx) = tunnel;
+        let (remote_addr, mut local_rx, mut local_tx) = tunnel;
+        (local_rx, local_tx) = intercept(&interceptors, &stream_info, local_rx, local_tx);
+        if let Some(max_duration) = restriction.max_session_duration {
+            local_rx = Box::pin(SessionDeadlineStream::new(local_rx, max_duration));
+        }
+        if let Some(quota) = quota {
+            local_rx = Box::pin(QuotaStream::new(local_rx, quota.clone()));
+            local_tx = Box::pin(QuotaStream::new(local_tx, quota));
+        }
+        if let Some(tunnel) = audit {
+            let tunnel = open_tunnel(
+                tunnel,
+                self.config.audit_log.clone(),
+                self.config.events.clone(),
+                self.config.event_handler.clone(),
+            );
+            local_rx = Box::pin(AuditStream::new(local_rx, tunnel.clone()));
+            local_tx = Box::pin(AuditStream::new(local_tx, tunnel));
+        }
+        let stats = Arc::new(TransferStats::default());
+        self.config.instance.active_tunnels.register(active_tunnel, &stats);
+        local_rx = Box::pin(CountingStream::new(local_rx, Some(stats.clone())));
+        local_tx = Box::pin(CountingStream::new(local_tx, Some(stats)));
+        // For reverse tunnels, the local side is the application connected to the listener of the server
+        let reverse = remote_addr.protocol.is_reverse_tunnel();
+        let capture = self
+            .config
+            .capture
+            .as_ref()
+            .map(|capture| CaptureFlow::new(capture, (!reverse).then_some(client_addr), &remote_addr, reverse));
+        if capture.is_some() {
+            local_rx = Box::pin(CaptureStream::new(local_rx, capture.clone()));
+            local_tx = Box::pin(CaptureStream::new(local_tx, capture));
+        }
+        HEALTH.transport_connected();
         info!("connected to {:?} {}:{}", req_protocol, remote_addr.host, remote_addr.port);
-        Ok((remote_addr, local_rx, local_tx, inject_cookie))
+        Ok((remote_addr, local_rx, local_tx, inject_cookie, response_headers))
+    }
+
+    /// Host to connect to for the destination of a tunnel. When private destinations are denied, it is the resolved
+    /// address which has been checked, so the destination cannot resolve to another one when connecting (dns rebinding)
+    async fn destination_host(&self, remote: &RemoteAddr, restriction: &RestrictionConfig) -> anyhow::Result<Host> {
+        if !self.config.deny_private_destinations {
+            return Ok(remote.host.clone());
+        }
+
+        let addrs = match &remote.host {
+            Host::Domain(domain) => self
+                .config
+                .dns_resolver
+                .lookup_host(domain, remote.port)
+                .await
+                .with_context(|| format!("cannot resolve domain: {}", domain))?
+                .into_iter()
+                .map(|addr| addr.ip())
+                .collect(),
+            Host::Ipv4(ip) => vec![IpAddr::V4(*ip)],
+            Host::Ipv6(ip) => vec![IpAddr::V6(*ip)],
+        };
+
+        match addrs.iter().find(|ip| is_allowed_destination(**ip, restriction)) {
+            Some(IpAddr::V4(ip)) => Ok(Host::Ipv4(*ip)),
+            Some(IpAddr::V6(ip)) => Ok(Host::Ipv6(*ip)),
+            None => Err(anyhow!(
+                "destination {} resolves only to private addresses {:?}, which are denied",
+                remote.host,
+                addrs
+            )),
+        }
     }
 
     async fn exec_tunnel(
@@ -147,11 +570,14 @@ impl WsServer {
         restriction: &RestrictionConfig,
         remote: RemoteAddr,
         client_address: SocketAddr,
+        owner: &str,
+        response_headers: &mut HeaderMap,
     ) -> anyhow::Result<(RemoteAddr, Pin<Box<dyn AsyncRead + Send>>, Pin<Box<dyn AsyncWrite + Send>>)> {
         match remote.protocol {
             LocalProtocol::Udp { timeout, .. } => {
+                let host = self.destination_host(&remote, restriction).await?;
                 let connector = UdpTunnelConnector::new(
-                    &remote.host,
+                    &host,
                     remote.port,
                     self.config.socket_so_mark,
                     timeout.unwrap_or(Duration::from_secs(10)),
@@ -165,8 +591,9 @@ impl WsServer {
                 Ok((remote, Box::pin(rx), Box::pin(tx)))
             }
             LocalProtocol::Tcp { proxy_protocol } => {
+                let host = self.destination_host(&remote, restriction).await?;
                 let connector = TcpTunnelConnector::new(
-                    &remote.host,
+                    &host,
                     remote.port,
                     self.config.socket_so_mark,
                     Duration::from_secs(10),
@@ -177,85 +604,189 @@ impl WsServer {
                     Some(proxy_url) => connector.connect_with_http_proxy(proxy_url, &None).await?,
                 };
 
-                if proxy_protocol {
-                    let header = ppp::v2::Builder::with_addresses(
-                        ppp::v2::Version::Two | ppp::v2::Command::Proxy,
-                        ppp::v2::Protocol::Stream,
-                        (client_address, tx.local_addr()?),
-                    )
-                    .build()?;
-                    let _ = tx.write_all(&header).await;
+                // The rule allowing the destination can override what the client requested
+                let rule = find_tunnel_rule(&remote, restriction);
+                let proxy_protocol = match rule.and_then(|rule| rule.proxy_protocol) {
+                    Some(version) => version,
+                    None if proxy_protocol => ProxyProtocolConfig::V2,
+                    None => ProxyProtocolConfig::Disabled,
+                };
+                match proxy_protocol {
+                    ProxyProtocolConfig::Disabled => {}
+                    ProxyProtocolConfig::V1 => {
+                        let header = ppp::v1::Addresses::from((client_address, tx.local_addr()?)).to_string();
+                        let _ = tx.write_all(header.as_bytes()).await;
+                    }
+                    ProxyProtocolConfig::V2 => {
+                        let header = ppp::v2::Builder::with_addresses(
+                            ppp::v2::Version::Two | ppp::v2::Command::Proxy,
+                            ppp::v2::Protocol::Stream,
+                            (client_address, tx.local_addr()?),
+                        )
+                        .build()?;
+                        let _ = tx.write_all(&header).await;
+                    }
+                }
+
+                // Let the client know from which address we are connected to the destination (i.e: socks5 BND.ADDR)
+                if let Some(bound_addr) = tx
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| HeaderValue::from_str(&addr.to_string()).ok())
+                {
+                    response_headers.insert(BOUND_ADDR_HEADER, bound_addr);
+                }
+
+                let tx: Pin<Box<dyn AsyncWrite + Send>> = match rule.and_then(|rule| rule.forwarded_header.as_ref()) {
+                    Some(header_name) => Box::pin(ForwardedHeaderStream::new(tx, header_name, client_address.ip())),
+                    None => Box::pin(tx),
+                };
+
+                Ok((remote, Box::pin(rx), tx))
+            }
+            LocalProtocol::ReverseHttpIngress { ref hostname } => {
+                let Some(ingress) = self.config.http_ingress.first() else {
+                    return Err(anyhow!("Http ingress is not enabled on the server"));
+                };
+                let bind = ingress
+                    .socket_addrs(|| ingress.port_or_known_default())?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("Cannot resolve http ingress address {}", ingress))?;
+                // All hostnames are served by the same ingress listeners, each one has its own reverse tunnel server
+                let (servers, register_route) = self.config.registries.http_ingress.route(hostname, owner);
+                let ((local_rx, local_tx), remote) = servers
+                    .run_listening_server(bind, self.config.reverse_tunnel_timeouts(), owner, register_route)
+                    .await?;
+
+                Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
+            }
+            LocalProtocol::Exec => {
+                let name = remote.host.to_string();
+                if self.config.bench_endpoint && name == BENCH_ENDPOINT {
+                    let (rx, tx) = bench::serve_bench();
+                    return Ok((remote, Box::pin(rx), Box::pin(tx)));
                 }
+                let Some(command) = self.config.exec_commands.get(&name) else {
+                    return Err(anyhow!("No command {} configured on the server", name));
+                };
+                let (rx, tx) = protocols::exec::spawn_command(&name, command)?;
+
+                Ok((remote, Box::pin(rx), Box::pin(tx)))
+            }
+            LocalProtocol::Tun => {
+                let Some(network) = &self.config.packet_network else {
+                    return Err(anyhow!("Tun tunnels are not enabled on the server"));
+                };
+                let client_ip = match &remote.host {
+                    Host::Ipv4(ip) => IpAddr::V4(*ip),
+                    Host::Ipv6(ip) => IpAddr::V6(*ip),
+                    Host::Domain(_) => return Err(anyhow!("Invalid address {} for a tun tunnel", remote.host)),
+                };
+                let (rx, tx) = network.attach(client_ip)?.streams().await;
+                // The packets are routed by the server, their destination is restricted like the one of other tunnels
+                let restriction = restriction.clone();
+                let deny_private_destinations = self.config.deny_private_destinations;
+                let tx = tx.filter_destinations(move |ip| {
+                    is_allowed_packet_destination(ip, &restriction, deny_private_destinations)
+                });
 
                 Ok((remote, Box::pin(rx), Box::pin(tx)))
             }
             LocalProtocol::ReverseTcp => {
-                static SERVERS: LazyLock<ReverseTunnelServer<TcpTunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
+                let servers = &self.config.registries.reverse_tcp;
 
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
+
+                // Port 0, let the OS pick a free port and only report it to the client.
+                // The client then requests this port like any other reverse tunnel
+                if bind.port() == 0 {
+                    let listening_server = TcpTunnelListener::new(bind, local_srv.clone(), false).await?;
+                    let bind = listening_server.local_addr()?;
+                    info!("Allocated port {} for reverse tunnel", bind.port());
+                    servers.register_listening_server(
+                        bind,
+                        self.config.reverse_tunnel_timeouts(),
+                        owner,
+                        listening_server,
+                    );
+                    response_headers.insert(REVERSE_LISTENER_HEADER, HeaderValue::from_str(&bind.to_string())?);
+
+                    let remote = RemoteAddr {
+                        protocol: LocalProtocol::ReverseTcp,
+                        host: local_srv.0,
+                        port: bind.port(),
+                    };
+                    return Ok((remote, Box::pin(tokio::io::empty()), Box::pin(tokio::io::sink())));
+                }
+
                 let listening_server = async { TcpTunnelListener::new(bind, local_srv.clone(), false).await };
-                let ((local_rx, local_tx), remote) = SERVERS
-                    .run_listening_server(bind, self.config.remote_server_idle_timeout, listening_server)
+                let ((local_rx, local_tx), remote) = servers
+                    .run_listening_server(bind, self.config.reverse_tunnel_timeouts(), owner, listening_server)
                     .await?;
+                insert_peer_addr(response_headers, &local_rx);
 
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
             }
             LocalProtocol::ReverseUdp { timeout } => {
-                static SERVERS: LazyLock<ReverseTunnelServer<UdpTunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
+                let servers = &self.config.registries.reverse_udp;
 
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
                 let listening_server = async { UdpTunnelListener::new(bind, local_srv.clone(), timeout).await };
-                let ((local_rx, local_tx), remote) = SERVERS
-                    .run_listening_server(bind, self.config.remote_server_idle_timeout, listening_server)
+                let ((local_rx, local_tx), remote) = servers
+                    .run_listening_server(bind, self.config.reverse_tunnel_timeouts(), owner, listening_server)
                     .await?;
+                insert_peer_addr(response_headers, &local_rx);
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
             }
             LocalProtocol::ReverseSocks5 { timeout, credentials } => {
-                static SERVERS: LazyLock<ReverseTunnelServer<Socks5TunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
+                let servers = &self.config.registries.reverse_socks5;
 
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
-                let listening_server = async { Socks5TunnelListener::new(bind, timeout, credentials).await };
-                let ((local_rx, local_tx), remote) = SERVERS
-                    .run_listening_server(bind, self.config.remote_server_idle_timeout, listening_server)
+                let listening_server = async { Socks5TunnelListener::new(bind, timeout, credentials, None).await };
+                let ((local_rx, local_tx), remote) = servers
+                    .run_listening_server(bind, self.config.reverse_tunnel_timeouts(), owner, listening_server)
                     .await?;
+                insert_peer_addr(response_headers, &local_rx);
+                // The destination is reached by the client, we don't know from which address
+                let (local_tx, stats) = local_tx.into_parts();
+                let local_tx = CountingStream::new(local_tx.send_reply(Ok(None)).await?, stats);
 
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
             }
             LocalProtocol::ReverseHttpProxy { timeout, credentials } => {
-                static SERVERS: LazyLock<ReverseTunnelServer<HttpProxyTunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
+                let servers = &self.config.registries.reverse_http_proxy;
 
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
-                let listening_server = async { HttpProxyTunnelListener::new(bind, timeout, credentials, false).await };
-                let ((local_rx, local_tx), remote) = SERVERS
-                    .run_listening_server(bind, self.config.remote_server_idle_timeout, listening_server)
+                let listening_server =
+                    async { HttpProxyTunnelListener::new(bind, timeout, credentials.into(), false).await };
+                let ((local_rx, local_tx), remote) = servers
+                    .run_listening_server(bind, self.config.reverse_tunnel_timeouts(), owner, listening_server)
                     .await?;
+                insert_peer_addr(response_headers, &local_rx);
 
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
             }
             #[cfg(unix)]
-            LocalProtocol::ReverseUnix { ref path } => {
+            LocalProtocol::ReverseUnix { ref path, mode } => {
                 use crate::tunnel::listeners::UnixTunnelListener;
-                static SERVERS: LazyLock<ReverseTunnelServer<UnixTunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
+                let servers = &self.config.registries.reverse_unix;
 
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
-                let listening_server = async { UnixTunnelListener::new(path, local_srv, false).await };
-                let ((local_rx, local_tx), remote) = SERVERS
-                    .run_listening_server(bind, self.config.remote_server_idle_timeout, listening_server)
+                let mode = mode.map(|mode| mode & REVERSE_UNIX_SOCKET_MODE_MASK);
+                let listening_server = async { UnixTunnelListener::new(path, local_srv, false, mode).await };
+                let ((local_rx, local_tx), remote) = servers
+                    .run_listening_server(bind, self.config.reverse_tunnel_timeouts(), owner, listening_server)
                     .await?;
 
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
@@ -270,22 +801,148 @@ impl WsServer {
             | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
-            | LocalProtocol::Unix { .. } => {
+            | LocalProtocol::Unix { .. }
+            | LocalProtocol::TunInterface { .. } => {
                 error!("Received an unsupported target protocol {:?}", remote);
                 Err(anyhow::anyhow!("Invalid upgrade request"))
             }
         }
     }
 
+    async fn start_http_ingress(&self) -> anyhow::Result<()> {
+        for url in &self.config.http_ingress {
+            let bind = url
+                .socket_addrs(|| url.port_or_known_default())?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("Cannot resolve http ingress address {}", url))?;
+
+            // Ingress is public, so it never requires client certificates even if mTLS is enabled for tunnels
+            let tls_acceptor = match (url.scheme(), &self.config.tls) {
+                ("http", _) => None,
+                ("https", Some(tls_config)) => {
+                    let tls_config = TlsServerConfig {
+                        tls_certificate: Mutex::new(tls_config.tls_certificate.lock().clone()),
+                        tls_key: Mutex::new(tls_config.tls_key.lock().clone_key()),
+                        tls_client_ca_certificates: None,
+                        tls_certificate_path: None,
+                        tls_key_path: None,
+                        tls_client_ca_certs_path: None,
+                    };
+                    Some(Arc::new(tls::tls_acceptor(&tls_config, Some(vec![b"http/1.1".to_vec()]))?))
+                }
+                ("https", None) => {
+                    return Err(anyhow!("Https ingress {} requires the server to listen with wss://", url));
+                }
+                (scheme, _) => return Err(anyhow!("Invalid http ingress scheme {}, expected http or https", scheme)),
+            };
+
+            let listener = TcpListener::bind(bind).await?;
+            let ingress = self.config.registries.http_ingress.clone();
+            spawn_until_cancelled(&self.config.cancel, async move {
+                if let Err(err) = run_ingress_server(listener, tls_acceptor, ingress).await {
+                    error!("Http ingress on {} stopped: {:?}", bind, err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Why the connection of a peer is rejected before its requests are read
+    pub(super) fn rejected_peer(&self, peer_addr: SocketAddr) -> Option<&'static str> {
+        if let Some(handler) = &self.config.event_handler {
+            if !handler.on_connect(peer_addr) {
+                return Some("denied by the event handler");
+            }
+        }
+        if !is_allowed_source(
+            peer_addr.ip(),
+            &self.config.allow_from,
+            &self.config.deny_from,
+            &self.config.geoip,
+        ) {
+            return Some("from a source not allowed");
+        }
+        if self.config.ban_policy.is_some() && self.config.registries.bans.is_banned(peer_addr.ip()) {
+            return Some("from a banned source");
+        }
+        if let Some(rate_limiter) = &self.config.rate_limiter {
+            if !rate_limiter.allow(peer_addr.ip()) {
+                return Some("from a source over its rate limit");
+            }
+        }
+        None
+    }
+
     pub async fn serve(self, restrictions: RestrictionsRules) -> anyhow::Result<()> {
         info!("Starting wstunnel server listening on {}", self.config.bind);
 
+        // Init TLS if needed
+        let tls_context = match &self.config.tls {
+            Some(tls_config) => Some(Arc::new(Mutex::new(TlsContext {
+                tls_acceptor: Arc::new(tls::tls_acceptor(
+                    tls_config,
+                    Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()]),
+                )?),
+                tls_reloader: TlsReloader::new_for_server(self.config.clone())?,
+                server_config: self.config.clone(),
+            }))),
+            None => None,
+        };
+
+        self.start_http_ingress().await?;
+
+        // Bind server and run forever to serve incoming connections.
+        let restrictions =
+            RestrictionsRulesReloader::new(restrictions, self.config.restriction_config.clone(), &self.config.cancel)?;
+        let reuse_port = self.config.reuse_port || self.config.accept_loops > 1;
+        let listeners = (0..self.config.accept_loops)
+            .map(|_| bind_listener(self.config.bind, reuse_port))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // Dropping the set stops the accept loops and the knocks, when the server is shut down
+        let mut tasks = JoinSet::new();
+        if let Some(spa_gate) = &self.config.spa_gate {
+            let socket = UdpSocket::bind(spa_gate.bind)
+                .await
+                .with_context(|| format!("Cannot listen for the knocks on udp {}", spa_gate.bind))?;
+            info!("Only accepting the sources which knocked on udp {}", spa_gate.bind);
+            tasks.spawn(spa_gate.clone().run(socket));
+        }
+        HEALTH.set_listening(true);
+        if let Some(privilege_drop) = &self.config.privilege_drop {
+            privilege_drop.apply()?;
+        }
+        if self.config.restrict_syscalls {
+            sandbox::restrict_syscalls()?;
+            info!("Server syscalls are restricted by seccomp");
+        }
+        self.config.instance.set_status(InstanceStatus::Ready);
+
+        for listener in listeners {
+            let restrictions = restrictions.restrictions_rules().clone();
+            tasks.spawn(self.clone().accept_loop(listener, restrictions, tls_context.clone()));
+        }
+        let cancel = self.config.cancel.clone();
+        while let Some(ret) = cancel.run_until_cancelled(tasks.join_next()).await.flatten() {
+            ret?;
+        }
+        Ok(())
+    }
+
+    async fn accept_loop(
+        self,
+        listener: TcpListener,
+        restrictions: Arc<ArcSwap<RestrictionsRules>>,
+        tls_context: Option<Arc<Mutex<TlsContext>>>,
+    ) {
         // setup upgrade request handler
         let mk_websocket_upgrade_fn = |server: WsServer,
                                        restrictions: Arc<ArcSwap<RestrictionsRules>>,
                                        restrict_path: Option<String>,
                                        client_addr: SocketAddr| {
             move |req: Request<Incoming>| {
+                let access = server.access_log_entry(&req, client_addr);
                 ws_server_upgrade(
                     server.clone(),
                     restrictions.load().clone(),
@@ -293,7 +950,7 @@ impl WsServer {
                     client_addr,
                     req,
                 )
-                .map::<anyhow::Result<_>, _>(Ok)
+                .map::<anyhow::Result<_>, _>(|response| Ok(log_access(access, response)))
                 .instrument(mk_span())
             }
         };
@@ -303,6 +960,7 @@ impl WsServer {
                                   restrict_path: Option<String>,
                                   client_addr: SocketAddr| {
             move |req: Request<Incoming>| {
+                let access = server.access_log_entry(&req, client_addr);
                 http_server_upgrade(
                     server.clone(),
                     restrictions.load().clone(),
@@ -310,7 +968,7 @@ impl WsServer {
                     client_addr,
                     req,
                 )
-                .map::<anyhow::Result<_>, _>(Ok)
+                .map::<anyhow::Result<_>, _>(|response| Ok(log_access(access, response)))
                 .instrument(mk_span())
             }
         };
@@ -323,52 +981,13 @@ impl WsServer {
                 let server = server.clone();
                 let restrictions = restrictions.clone();
                 let restrict_path = restrict_path.clone();
-                async move {
-                    if fastwebsockets::upgrade::is_upgrade_request(&req) {
-                        ws_server_upgrade(server.clone(), restrictions.load().clone(), restrict_path, client_addr, req)
-                            .map::<anyhow::Result<_>, _>(Ok)
-                            .await
-                    } else if req.version() == Version::HTTP_2 {
-                        http_server_upgrade(
-                            server.clone(),
-                            restrictions.load().clone(),
-                            restrict_path.clone(),
-                            client_addr,
-                            req,
-                        )
-                        .map::<anyhow::Result<_>, _>(Ok)
-                        .await
-                    } else {
-                        error!("Invalid protocol version request, got {:?} while expecting either websocket http1 upgrade or http2", req.version());
-                        Ok(http::Response::builder()
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(Either::Left("Invalid protocol request".to_string()))
-                            .unwrap())
-                    }
-                }
+                let access = server.access_log_entry(&req, client_addr);
+                auto_upgrade(server, restrictions.load().clone(), restrict_path, client_addr, req)
+                    .map::<anyhow::Result<_>, _>(|response| Ok(log_access(access, response)))
                     .instrument(mk_span())
             }
         };
 
-        // Init TLS if needed
-        let mut tls_context = if let Some(tls_config) = &self.config.tls {
-            let tls_context = TlsContext {
-                tls_acceptor: Arc::new(tls::tls_acceptor(
-                    tls_config,
-                    Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()]),
-                )?),
-                tls_reloader: TlsReloader::new_for_server(self.config.clone())?,
-                tls_config,
-            };
-            Some(tls_context)
-        } else {
-            None
-        };
-
-        // Bind server and run forever to serve incoming connections.
-        let restrictions = RestrictionsRulesReloader::new(restrictions, self.config.restriction_config.clone())?;
-        let listener = TcpListener::bind(&self.config.bind).await?;
-
         loop {
             let (stream, peer_addr) = match listener.accept().await {
                 Ok(ret) => ret,
@@ -379,22 +998,37 @@ impl WsServer {
             };
 
             let span = span!(Level::INFO, "cnx", peer = peer_addr.to_string(),);
+            if let Some(spa_gate) = &self.config.spa_gate {
+                if !spa_gate.is_admitted(peer_addr.ip()) {
+                    debug!(parent: &span, "Resetting connection from a source which did not knock");
+                    let _ = SockRef::from(&stream).set_linger(Some(Duration::ZERO));
+                    continue;
+                }
+            }
+            if let Some(reason) = self.rejected_peer(peer_addr) {
+                warn!(parent: &span, "Rejecting connection {reason}");
+                continue;
+            }
             info!(parent: &span, "Accepting connection");
             if let Err(err) = protocols::tcp::configure_socket(SockRef::from(&stream), SoMark::new(None)) {
                 warn!("Error while configuring server socket {:?}", err);
             }
 
             let server = self.clone();
-            let restrictions = restrictions.restrictions_rules().clone();
+            let restrictions = restrictions.clone();
 
             // Check if we need to enable TLS or not
-            match tls_context.as_mut() {
+            match &tls_context {
                 Some(tls) => {
                     // Reload TLS certificate if needed
-                    let tls_acceptor = tls.tls_acceptor().clone();
+                    let tls_acceptor = tls.lock().tls_acceptor().clone();
                     let fut = async move {
                         info!("Doing TLS handshake");
-                        let tls_stream = match tls_acceptor.accept(stream).await {
+                        let tls_stream = match tls_acceptor
+                            .accept(stream)
+                            .instrument(info_span!("tls_handshake"))
+                            .await
+                        {
                             Ok(tls_stream) => hyper_util::rt::TokioIo::new(tls_stream),
                             Err(err) => {
                                 error!("error while accepting TLS connection {}", err);
@@ -408,6 +1042,20 @@ impl WsServer {
                             .peer_certificates()
                             .and_then(tls::find_leaf_certificate)
                             .and_then(|c| tls::cn_from_certificate(&c));
+                        if let Some(transport) = server.config.transport.clone() {
+                            let (rx, tx) = tokio::io::split(tls_stream.into_inner());
+                            transport_server_upgrade(
+                                server,
+                                restrictions.load().clone(),
+                                restrict_path,
+                                peer_addr,
+                                transport,
+                                (Box::pin(rx), Box::pin(tx)),
+                            )
+                            .instrument(mk_span())
+                            .await;
+                            return;
+                        }
                         match tls_ctx.alpn_protocol() {
                             // http2
                             Some(b"h2") => {
@@ -444,11 +1092,25 @@ impl WsServer {
                     }
                     .instrument(span);
 
-                    tokio::spawn(fut);
+                    spawn_until_cancelled(&self.config.cancel, fut);
                 }
                 // HTTP without TLS
                 None => {
                     let fut = async move {
+                        if let Some(transport) = server.config.transport.clone() {
+                            let (rx, tx) = stream.into_split();
+                            transport_server_upgrade(
+                                server,
+                                restrictions.load().clone(),
+                                None,
+                                peer_addr,
+                                transport,
+                                (Box::pin(rx), Box::pin(tx)),
+                            )
+                            .instrument(mk_span())
+                            .await;
+                            return;
+                        }
                         let stream = hyper_util::rt::TokioIo::new(stream);
                         let mut conn_fut = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
                         if let Some(ping) = server.config.websocket_ping_frequency {
@@ -465,34 +1127,149 @@ impl WsServer {
                     }
                     .instrument(span);
 
-                    tokio::spawn(fut);
+                    spawn_until_cancelled(&self.config.cancel, fut);
                 }
             }
         }
     }
 }
 
-fn mk_span() -> Span {
+/// Listening socket of the server, bound with SO_REUSEPORT to share its address with other sockets
+fn bind_listener(addr: SocketAddr, reuse_port: bool) -> anyhow::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuseport(reuse_port)?;
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    if reuse_port {
+        return Err(anyhow!("SO_REUSEPORT is not available on this platform"));
+    }
+    socket
+        .bind(addr)
+        .with_context(|| format!("Cannot listen on {}", addr))?;
+    Ok(socket.listen(1024)?)
+}
+
+/// Upgrade request of a tunnel over websocket or http2, whichever the client uses
+pub(super) async fn auto_upgrade<B>(
+    server: WsServer,
+    restrictions: Arc<RestrictionsRules>,
+    restrict_path: Option<String>,
+    client_addr: SocketAddr,
+    req: Request<B>,
+) -> HttpResponse
+where
+    B: hyper::body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<anyhow::Error>,
+{
+    if fastwebsockets::upgrade::is_upgrade_request(&req) {
+        ws_server_upgrade(server, restrictions, restrict_path, client_addr, req).await
+    } else if req.version() == Version::HTTP_2 {
+        http_server_upgrade(server, restrictions, restrict_path, client_addr, req).await
+    } else {
+        error!(
+            "Invalid protocol version request, got {:?} while expecting either websocket http1 upgrade or http2",
+            req.version()
+        );
+        server.record_failure(client_addr.ip(), "bad upgrade request");
+        http::Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Either::Left("Invalid protocol request".to_string()))
+            .unwrap()
+    }
+}
+
+pub(super) fn log_access<B: hyper::body::Body>(
+    access: Option<HttpAccessEntry>,
+    response: http::Response<B>,
+) -> http::Response<B> {
+    if let Some(access) = access {
+        access.write(&response);
+    }
+    response
+}
+
+pub(super) fn mk_span() -> Span {
     span!(
         Level::INFO,
         "tunnel",
-        id = tracing::field::Empty,
-        remote = tracing::field::Empty,
+        connection_id = tracing::field::Empty,
+        destination = tracing::field::Empty,
         forwarded_for = tracing::field::Empty
     )
 }
 
+/// Let the client know which peer connected to its reverse tunnel
+fn insert_peer_addr(response_headers: &mut HeaderMap, local_rx: &impl ClientAddr) {
+    if let Some(peer_addr) = local_rx
+        .client_addr()
+        .and_then(|addr| HeaderValue::from_str(&addr.to_string()).ok())
+    {
+        response_headers.insert(PEER_ADDR_HEADER, peer_addr);
+    }
+}
+
+impl WsServerConfig {
+    fn reverse_tunnel_timeouts(&self) -> ReverseTunnelTimeouts {
+        ReverseTunnelTimeouts {
+            idle: self.remote_server_idle_timeout,
+            no_connection: self.remote_server_no_connection_timeout,
+            max_lifetime: self.remote_server_max_lifetime,
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    pub(super) fn slow_consumer_timeout(&self) -> Option<SlowConsumerTimeout> {
+        self.slow_consumer_timeout.map(|timeout| SlowConsumerTimeout {
+            timeout,
+            tunnels: self.instance.active_tunnels.clone(),
+        })
+    }
+}
+
 impl Debug for WsServerConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("WsServerConfig")
             .field("socket_so_mark", &self.socket_so_mark)
             .field("bind", &self.bind)
             .field("websocket_ping_frequency", &self.websocket_ping_frequency)
+            .field("slow_consumer_timeout", &self.slow_consumer_timeout)
             .field("timeout_connect", &self.timeout_connect)
             .field("websocket_mask_frame", &self.websocket_mask_frame)
             .field("restriction_config", &self.restriction_config)
+            .field("deny_private_destinations", &self.deny_private_destinations)
+            .field("allow_from", &self.allow_from)
+            .field("deny_from", &self.deny_from)
+            .field("trusted_proxies", &self.trusted_proxies)
             .field("tls", &self.tls.is_some())
             .field("remote_server_idle_timeout", &self.remote_server_idle_timeout)
+            .field("remote_server_no_connection_timeout", &self.remote_server_no_connection_timeout)
+            .field("remote_server_max_lifetime", &self.remote_server_max_lifetime)
+            .field("exec_commands", &self.exec_commands.keys().collect::<Vec<_>>())
+            .field("bench_endpoint", &self.bench_endpoint)
+            .field("http_ingress", &self.http_ingress)
+            .field("bearer_auth", &self.bearer_auth.is_some())
+            .field("ldap_auth", &self.ldap_auth.is_some())
+            .field("revocation_list", &self.revocation_list.is_some())
+            .field("totp_verifier", &self.totp_verifier.is_some())
+            .field("ban_policy", &self.ban_policy)
+            .field("audit_log", &self.audit_log.is_some())
+            .field("access_log", &self.access_log.is_some())
+            .field("rate_limiter", &self.rate_limiter.is_some())
+            .field("reuse_port", &self.reuse_port)
+            .field("accept_loops", &self.accept_loops)
+            .field("spa_bind", &self.spa_gate.as_ref().map(|gate| gate.bind))
+            .field("auth_webhook", &self.auth_webhook.is_some())
+            .field("event_handler", &self.event_handler.is_some())
+            .field("transport", &self.transport.is_some())
+            .field("interceptors", &self.interceptors)
+            .field("packet_network", &self.packet_network.is_some())
+            .field("privilege_drop", &self.privilege_drop)
+            .field("restrict_syscalls", &self.restrict_syscalls)
             .field(
                 "mTLS",
                 &self
@@ -505,16 +1282,16 @@ impl Debug for WsServerConfig {
     }
 }
 
-struct TlsContext<'a> {
+struct TlsContext {
     tls_acceptor: Arc<TlsAcceptor>,
     tls_reloader: TlsReloader,
-    tls_config: &'a TlsServerConfig,
+    server_config: Arc<WsServerConfig>,
 }
-impl TlsContext<'_> {
+impl TlsContext {
     #[inline]
     pub fn tls_acceptor(&mut self) -> &Arc<TlsAcceptor> {
-        if self.tls_reloader.should_reload_certificate() {
-            match tls::tls_acceptor(self.tls_config, Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()])) {
+        if let (true, Some(tls_config)) = (self.tls_reloader.should_reload_certificate(), &self.server_config.tls) {
+            match tls::tls_acceptor(tls_config, Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()])) {
                 Ok(acceptor) => self.tls_acceptor = Arc::new(acceptor),
                 Err(err) => error!("Cannot reload TLS certificate {:?}", err),
             };
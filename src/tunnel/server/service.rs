@@ -0,0 +1,124 @@
+//! Upgrade requests of the tunnels served by the http server of another program, i.e: mounted on `/tunnel/*` of an
+//! axum router, instead of the listener of wstunnel
+
+use crate::restrictions::config_reloader::RestrictionsRulesReloader;
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::events::TunnelEvent;
+use crate::tunnel::server::server::{auto_upgrade, log_access, mk_span};
+use crate::tunnel::server::utils::HttpResponse;
+use crate::tunnel::server::WsServer;
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use http_body_util::Either;
+use hyper::http::uri::PathAndQuery;
+use hyper::{http, Request, StatusCode, Uri};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::broadcast;
+use tracing::{warn, Instrument};
+
+/// Server of the tunnels as a `tower::Service`, for the http server of the caller. The address of the client is read
+/// from the `SocketAddr` extension of the requests, which the caller inserts, i.e: from the `ConnectInfo` of axum.
+/// The server must be configured with ws:// as tls is terminated by the caller, its listener is not started
+#[derive(Clone)]
+pub struct TunnelService {
+    server: WsServer,
+    restrictions: Arc<RestrictionsRulesReloader>,
+    mount_path: Option<Arc<str>>,
+}
+
+impl TunnelService {
+    pub(crate) fn new(server: WsServer, restrictions: RestrictionsRules) -> anyhow::Result<Self> {
+        let restrictions = RestrictionsRulesReloader::new(
+            restrictions,
+            server.config.restriction_config.clone(),
+            &server.config.cancel,
+        )?;
+        Ok(Self {
+            server,
+            restrictions: Arc::new(restrictions),
+            mount_path: None,
+        })
+    }
+
+    /// Path the service is mounted on, removed from the requests before their path prefix is read.
+    /// Not needed behind axum `nest_service`, which already removes it
+    pub fn mount_path(mut self, path: impl Into<String>) -> Self {
+        let path = path.into();
+        self.mount_path = Some(Arc::from(path.trim_end_matches('/')));
+        self
+    }
+
+    /// Events of the server from now on, i.e: its tunnels opened and closed
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.server.config.events.subscribe()
+    }
+
+    fn unmount<B>(&self, req: &mut Request<B>) -> Result<(), &'static str> {
+        let Some(mount_path) = &self.mount_path else {
+            return Ok(());
+        };
+        let Some(path) = req.uri().path().strip_prefix(mount_path.as_ref()) else {
+            return Err("outside of the mount path");
+        };
+        if !path.starts_with('/') {
+            return Err("outside of the mount path");
+        }
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).map_err(|_| "with an invalid path")?);
+        *req.uri_mut() = Uri::from_parts(parts).map_err(|_| "with an invalid path")?;
+        Ok(())
+    }
+}
+
+impl<B> tower_service::Service<Request<B>> for TunnelService
+where
+    B: hyper::body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<anyhow::Error>,
+{
+    type Response = HttpResponse;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<HttpResponse, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let Some(client_addr) = req.extensions().get::<SocketAddr>().copied() else {
+            warn!("Rejecting request without the address of its client, the SocketAddr extension is missing");
+            return std::future::ready(Ok(rejection(StatusCode::BAD_REQUEST))).boxed();
+        };
+        if let Err(reason) = self.unmount(&mut req) {
+            warn!("Rejecting request from {} {}", client_addr, reason);
+            return std::future::ready(Ok(rejection(StatusCode::NOT_FOUND))).boxed();
+        }
+        if let Some(reason) = self.server.rejected_peer(client_addr) {
+            warn!("Rejecting request from {} {}", client_addr, reason);
+            return std::future::ready(Ok(rejection(StatusCode::FORBIDDEN))).boxed();
+        }
+
+        let server = self.server.clone();
+        let restrictions = self.restrictions.restrictions_rules().load().clone();
+        let access = server.access_log_entry(&req, client_addr);
+        // The path prefix is only restricted by the client certificates of mTLS, which is terminated by the caller
+        auto_upgrade(server, restrictions, None, client_addr, req)
+            .map(|response| Ok(log_access(access, response)))
+            .instrument(mk_span())
+            .boxed()
+    }
+}
+
+fn rejection(status: StatusCode) -> HttpResponse {
+    http::Response::builder()
+        .status(status)
+        .body(Either::Left(String::new()))
+        .unwrap()
+}
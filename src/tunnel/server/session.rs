@@ -0,0 +1,69 @@
+use pin_project::pin_project;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::Sleep;
+use tracing::info;
+
+/// Stream of the destination of a tunnel, which ends once the max session duration of its restriction is reached.
+/// The end is forwarded to the client like a normal close of the destination, so the transport is closed gracefully
+/// and the client has to authenticate again to open a new tunnel
+#[pin_project]
+pub(super) struct SessionDeadlineStream<T> {
+    #[pin]
+    inner: T,
+    #[pin]
+    deadline: Sleep,
+    max_duration: Duration,
+}
+
+impl<T> SessionDeadlineStream<T> {
+    pub(super) fn new(inner: T, max_duration: Duration) -> Self {
+        Self {
+            inner,
+            deadline: tokio::time::sleep(max_duration),
+            max_duration,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for SessionDeadlineStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if this.deadline.poll(cx).is_ready() {
+            info!(
+                "Closing tunnel which reached its max session duration of {}s",
+                this.max_duration.as_secs()
+            );
+            return Poll::Ready(Ok(()));
+        }
+
+        this.inner.poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_session_deadline() {
+        let started_at = tokio::time::Instant::now();
+        let (client, mut destination) = tokio::io::duplex(64);
+        let mut stream = std::pin::pin!(SessionDeadlineStream::new(client, Duration::from_millis(100)));
+
+        destination.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The destination stays open, but the session ends
+        let mut buf = vec![];
+        assert_eq!(stream.read_to_end(&mut buf).await.unwrap(), 0);
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+    }
+}
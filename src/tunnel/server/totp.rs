@@ -0,0 +1,239 @@
+use crate::secret::constant_time_eq;
+use crate::tunnel::server::bearer_auth::BearerClaims;
+use ahash::AHashMap;
+use anyhow::{anyhow, Context};
+use parking_lot::Mutex;
+use ring::hmac;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::SystemTime;
+use zeroize::Zeroizing;
+
+const TIME_STEP_SECS: u64 = 30;
+const CODE_DIGITS: u32 = 6;
+/// Expired codes are purged once this many are remembered
+const MAX_USED_CODES: usize = 10_000;
+
+/// Second factor required from the clients, as a TOTP code (RFC 6238, HMAC-SHA1, 30s, 6 digits) in addition to
+/// their path prefix or bearer token. The shared secrets are read from a file, one identity per line,
+/// '#' starts a comment:
+/// sub:SUBJECT BASE32_SECRET          => clients authenticated with a bearer token of this subject
+/// path_prefix:PREFIX BASE32_SECRET   => clients using this path prefix
+///
+/// A code can be used by several tunnels of the same client while it is valid, but only from the address that
+/// used it first, so a code seen by someone else cannot be replayed from elsewhere.
+pub struct TotpVerifier {
+    secrets: AHashMap<String, Zeroizing<Vec<u8>>>,
+    used_codes: Mutex<AHashMap<(String, u64), (IpAddr, u64)>>,
+}
+
+impl TotpVerifier {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("Cannot read TOTP secrets {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid TOTP secrets {}", path.display()))
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut secrets = AHashMap::new();
+        for (ix, line) in content.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (identity, secret) = line
+                .rsplit_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("line {}: expected IDENTITY SECRET", ix + 1))?;
+            let identity = identity.trim();
+            if !identity.starts_with("sub:") && !identity.starts_with("path_prefix:") {
+                return Err(anyhow!(
+                    "line {}: unknown identity {}, expected sub:SUBJECT or path_prefix:PREFIX",
+                    ix + 1,
+                    identity
+                ));
+            }
+            let secret = decode_base32(secret).ok_or_else(|| anyhow!("line {}: secret is not valid base32", ix + 1))?;
+            secrets.insert(identity.to_string(), Zeroizing::new(secret));
+        }
+
+        Ok(Self {
+            secrets,
+            used_codes: Mutex::new(AHashMap::new()),
+        })
+    }
+
+    /// Ok if the code is valid for the identity of the client, otherwise why it is not
+    pub(super) fn verify(
+        &self,
+        path_prefix: &str,
+        claims: Option<&BearerClaims>,
+        code: Option<&str>,
+        source: IpAddr,
+    ) -> Result<(), &'static str> {
+        self.verify_at(path_prefix, claims, code, source, now())
+    }
+
+    fn verify_at(
+        &self,
+        path_prefix: &str,
+        claims: Option<&BearerClaims>,
+        code: Option<&str>,
+        source: IpAddr,
+        now: u64,
+    ) -> Result<(), &'static str> {
+        let (identity, secret) = claims
+            .and_then(|claims| claims.subject.as_ref())
+            .map(|sub| format!("sub:{}", sub))
+            .into_iter()
+            .chain([format!("path_prefix:{}", path_prefix)])
+            .find_map(|identity| self.secrets.get(&identity).map(|secret| (identity, secret)))
+            .ok_or("no TOTP secret for this identity")?;
+        let code = code.map(str::trim).ok_or("missing TOTP code")?;
+
+        // Be lenient with the clocks of the clients by one time step
+        let current_step = now / TIME_STEP_SECS;
+        let step = [current_step, current_step.saturating_sub(1), current_step + 1]
+            .into_iter()
+            .find(|step| constant_time_eq(totp_code(secret, *step).as_bytes(), code.as_bytes()))
+            .ok_or("invalid TOTP code")?;
+
+        let mut used_codes = self.used_codes.lock();
+        if used_codes.len() >= MAX_USED_CODES {
+            used_codes.retain(|_, (_, expire_step)| *expire_step >= current_step);
+        }
+        let (first_source, _) = used_codes.entry((identity, step)).or_insert((source, step + 1));
+        if *first_source != source {
+            return Err("TOTP code already used from another address");
+        }
+
+        Ok(())
+    }
+}
+
+/// TOTP code of the time step, as defined by RFC 4226 and RFC 6238
+fn totp_code(secret: &[u8], step: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &step.to_be_bytes());
+    let tag = tag.as_ref();
+    let offset = (tag[tag.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([tag[offset] & 0x7f, tag[offset + 1], tag[offset + 2], tag[offset + 3]]);
+
+    format!("{:0width$}", value % 10u32.pow(CODE_DIGITS), width = CODE_DIGITS as usize)
+}
+
+/// Decode base32 (RFC 4648) as given by authenticator apps, case insensitive, ignoring spaces and padding
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in input.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    (!out.is_empty()).then_some(out)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    // base32 of the RFC 6238 secret "12345678901234567890"
+    const SECRETS: &str = r#"
+# laptop of alice
+sub:alice@example.com GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ
+path_prefix:v1   gezdgnbvgy3tqojqgezdgnbvgy3tqojq
+"#;
+
+    const ALICE: &str = "127.0.0.1";
+
+    fn claims(subject: &str) -> BearerClaims {
+        BearerClaims {
+            id: None,
+            subject: Some(subject.to_string()),
+            scopes: vec![],
+        }
+    }
+
+    #[test_case("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ" => Some(b"12345678901234567890".to_vec()) ; "rfc secret")]
+    #[test_case("mzxw6===" => Some(b"foo".to_vec()) ; "padding and lowercase")]
+    #[test_case("MZXW6YTBOI" => Some(b"foobar".to_vec()) ; "no padding")]
+    #[test_case("MZXW1" => None ; "invalid char")]
+    #[test_case("" => None ; "empty")]
+    fn test_decode_base32(input: &str) -> Option<Vec<u8>> {
+        decode_base32(input)
+    }
+
+    // Test vectors of RFC 6238 for SHA1, truncated to 6 digits
+    #[test_case(59 => "287082" ; "59")]
+    #[test_case(1111111109 => "081804" ; "1111111109")]
+    #[test_case(1234567890 => "005924" ; "1234567890")]
+    #[test_case(2000000000 => "279037" ; "2000000000")]
+    fn test_totp_code(time: u64) -> String {
+        totp_code(b"12345678901234567890", time / TIME_STEP_SECS)
+    }
+
+    #[test_case("v1", None, Some("287082"), 59 => Ok(()) ; "path prefix")]
+    #[test_case("v2", Some(claims("alice@example.com")), Some("287082"), 59 => Ok(()) ; "subject")]
+    #[test_case("v1", Some(claims("bob@example.com")), Some("287082"), 59 => Ok(()) ; "unknown subject falls back to path prefix")]
+    #[test_case("v1", None, Some("287082"), 89 => Ok(()) ; "previous step")]
+    #[test_case("v1", None, Some("287082"), 29 => Ok(()) ; "next step")]
+    #[test_case("v1", None, Some("287082"), 119 => Err("invalid TOTP code") ; "expired")]
+    #[test_case("v1", None, Some("123456"), 59 => Err("invalid TOTP code") ; "wrong code")]
+    #[test_case("v1", None, None, 59 => Err("missing TOTP code") ; "missing code")]
+    #[test_case("v2", Some(claims("bob@example.com")), Some("287082"), 59 => Err("no TOTP secret for this identity") ; "no secret")]
+    fn test_verify(
+        path_prefix: &str,
+        claims: Option<BearerClaims>,
+        code: Option<&str>,
+        now: u64,
+    ) -> Result<(), &'static str> {
+        let verifier = TotpVerifier::parse(SECRETS).unwrap();
+        verifier.verify_at(path_prefix, claims.as_ref(), code, ALICE.parse().unwrap(), now)
+    }
+
+    #[test]
+    fn test_replay_from_another_address() {
+        let verifier = TotpVerifier::parse(SECRETS).unwrap();
+        let alice = ALICE.parse().unwrap();
+        let mallory = "192.0.2.1".parse().unwrap();
+        assert_eq!(verifier.verify_at("v1", None, Some("287082"), alice, 59), Ok(()));
+        assert_eq!(verifier.verify_at("v1", None, Some("287082"), alice, 60), Ok(()));
+        assert_eq!(
+            verifier.verify_at("v1", None, Some("287082"), mallory, 60),
+            Err("TOTP code already used from another address")
+        );
+        // The code is bound to the identity, not shared with the others using the same secret
+        let alice_token = claims("alice@example.com");
+        assert_eq!(
+            verifier.verify_at("v1", Some(&alice_token), Some("287082"), mallory, 60),
+            Ok(())
+        );
+    }
+
+    #[test_case("v1" ; "missing secret")]
+    #[test_case("user:alice GEZDGNBV" ; "unknown identity")]
+    #[test_case("path_prefix:v1 GEZDGNB1" ; "invalid secret")]
+    fn test_parse_invalid(content: &str) {
+        assert!(TotpVerifier::parse(content).is_err());
+    }
+}
@@ -1,24 +1,44 @@
+use crate::restrictions::geoip::{GeoIpDatabases, SourceFilter};
 use crate::restrictions::types::{
     AllowConfig, AllowReverseTunnelConfig, AllowTunnelConfig, MatchConfig, RestrictionConfig, RestrictionsRules,
-    ReverseTunnelConfigProtocol, TunnelConfigProtocol,
+    ReverseTunnelConfigProtocol, TimeWindowConfig, TimeZoneConfig, TunnelConfigProtocol,
 };
-use crate::tunnel::transport::{jwt_token_to_tunnel, tunnel_to_jwt_token, JwtTunnelConfig, JWT_HEADER_PREFIX};
-use crate::tunnel::RemoteAddr;
+use crate::tunnel::active_tunnels::FailureReason;
+use crate::tunnel::server::bearer_auth::BearerClaims;
+use crate::tunnel::transport::{
+    is_valid_hmac_path_prefix, jwt_token_to_tunnel, tunnel_to_jwt_token, JwtTunnelConfig, CONNECTION_ID_HEADER,
+    JWT_HEADER_PREFIX,
+};
+use crate::tunnel::{LocalProtocol, RemoteAddr};
 use bytes::Bytes;
+use chrono::{DateTime, Datelike, Local, Utc};
 use derive_more::{Display, Error};
 use http_body_util::combinators::BoxBody;
 use http_body_util::Either;
-use hyper::body::{Body, Incoming};
-use hyper::header::{HeaderValue, COOKIE, SEC_WEBSOCKET_PROTOCOL};
+use hyper::body::Body;
+use hyper::header::{HeaderMap, HeaderValue, COOKIE, SEC_WEBSOCKET_PROTOCOL, WWW_AUTHENTICATE};
 use hyper::{http, Request, Response, StatusCode};
+use ipnet::IpNet;
 use jsonwebtoken::TokenData;
-use std::net::IpAddr;
+use ring::digest;
+use std::cell::LazyCell;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
 use tracing::{error, info, warn};
 use url::Host;
 use uuid::Uuid;
 
 pub type HttpResponse = Response<Either<String, BoxBody<Bytes, anyhow::Error>>>;
 
+/// Reason of the failure of a tunnel request answered with this response
+pub(super) fn failure_reason(response: &HttpResponse) -> FailureReason {
+    response
+        .extensions()
+        .get::<FailureReason>()
+        .copied()
+        .unwrap_or_else(|| FailureReason::from_status(response.status().as_u16()))
+}
+
 pub(super) fn bad_request() -> HttpResponse {
     http::Response::builder()
         .status(StatusCode::BAD_REQUEST)
@@ -26,6 +46,35 @@ pub(super) fn bad_request() -> HttpResponse {
         .unwrap()
 }
 
+pub(super) fn unauthorized() -> HttpResponse {
+    http::Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Bearer")
+        .body(Either::Left("Invalid bearer token".to_string()))
+        .unwrap()
+}
+
+pub(super) fn too_many_requests(reason: String) -> HttpResponse {
+    http::Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .body(Either::Left(reason))
+        .unwrap()
+}
+
+pub(super) fn payment_required(reason: String) -> HttpResponse {
+    http::Response::builder()
+        .status(StatusCode::PAYMENT_REQUIRED)
+        .body(Either::Left(reason))
+        .unwrap()
+}
+
+pub(super) fn forbidden(reason: String) -> HttpResponse {
+    http::Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Either::Left(reason))
+        .unwrap()
+}
+
 /// Checks if the requested (remote) port has been mapped in the configuration to another port.
 /// If it is not mapped the original port number is returned.
 #[inline]
@@ -49,8 +98,56 @@ pub(super) fn find_mapped_port(req_port: u16, restriction: &RestrictionConfig) -
     remote_port
 }
 
+/// Identity of a client, for its quota and as the owner of its reverse listeners: the subject of its bearer token or
+/// LDAP user, else a hash of its path prefix, which is a secret and ends up in the logs, the quota state file and the
+/// admin socket. The path prefix of a restriction matching an hmac changes every day, all the clients knowing its secret
+/// are the same identity
+pub(super) fn client_identity(
+    path_prefix: &str,
+    claims: Option<&BearerClaims>,
+    restriction: &RestrictionConfig,
+) -> String {
+    if let Some(subject) = claims.and_then(|claims| claims.subject.as_ref()) {
+        return format!("sub:{}", subject);
+    }
+
+    match restriction
+        .r#match
+        .iter()
+        .any(|m| matches!(m, MatchConfig::PathPrefixHmac(_)))
+    {
+        true => format!("hmac:{}", restriction.name),
+        false => {
+            let hash = digest::digest(&digest::SHA256, path_prefix.as_bytes());
+            let hash: String = hash.as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect();
+            format!("path_prefix:sha256:{}", hash)
+        }
+    }
+}
+
+/// Returns the port ranges to allocate isolated reverse listeners from, if the restriction requires it.
 #[inline]
-pub(super) fn extract_x_forwarded_for(req: &Request<Incoming>) -> Option<(IpAddr, &str)> {
+pub(super) fn find_isolated_ports(restriction: &RestrictionConfig) -> Option<&[RangeInclusive<u16>]> {
+    restriction.allow.iter().find_map(|allow| match allow {
+        AllowConfig::ReverseTunnel(allow) if !allow.isolated_port.is_empty() => Some(allow.isolated_port.as_slice()),
+        _ => None,
+    })
+}
+
+/// First rule of the restriction allowing the forward tunnel, for the settings it applies to its destination
+#[inline]
+pub(super) fn find_tunnel_rule<'a>(
+    remote: &RemoteAddr,
+    restriction: &'a RestrictionConfig,
+) -> Option<&'a AllowTunnelConfig> {
+    restriction.allow.iter().find_map(|allow| match allow {
+        AllowConfig::Tunnel(allow) if allow.is_allowed(remote) => Some(allow),
+        _ => None,
+    })
+}
+
+#[inline]
+pub(super) fn extract_x_forwarded_for<B>(req: &Request<B>) -> Option<(IpAddr, &str)> {
     let x_forward_for = req.headers().get("X-Forwarded-For")?;
 
     // X-Forwarded-For: <client>, <proxy1>, <proxy2>
@@ -60,6 +157,40 @@ pub(super) fn extract_x_forwarded_for(req: &Request<Incoming>) -> Option<(IpAddr
     ip.map(|ip| (ip, x_forward_for))
 }
 
+/// Source of the tunnel, checked by the restrictions: the peer, or the client in its X-Forwarded-For header if the peer
+/// is a trusted proxy. The header is read from the right, as the addresses on the left of the last untrusted hop are
+/// the ones sent by the client, which can forge them
+pub(super) fn source_ip<B>(req: &Request<B>, peer_ip: IpAddr, trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|net| net.contains(&ip.to_canonical()));
+    if !is_trusted(peer_ip) {
+        return peer_ip;
+    }
+    let Some(x_forward_for) = req.headers().get("X-Forwarded-For").and_then(|h| h.to_str().ok()) else {
+        return peer_ip;
+    };
+
+    let mut source = peer_ip;
+    for hop in x_forward_for.rsplit(',') {
+        let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        source = ip;
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    source
+}
+
+/// Id of the tunnel sent by the client, only if it is a valid uuid so it cannot inject anything in the logs
+#[inline]
+pub(super) fn extract_connection_id(headers: &HeaderMap) -> Option<Uuid> {
+    headers
+        .get(CONNECTION_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| Uuid::parse_str(id).ok())
+}
+
 #[inline]
 pub(super) fn extract_path_prefix(path: &str) -> Result<&str, PathPrefixErr> {
     if !path.starts_with('/') {
@@ -84,7 +215,7 @@ pub(super) enum PathPrefixErr {
 }
 
 #[inline]
-pub(super) fn extract_tunnel_info(req: &Request<Incoming>) -> anyhow::Result<TokenData<JwtTunnelConfig>, HttpResponse> {
+pub(super) fn extract_tunnel_info<B>(req: &Request<B>) -> anyhow::Result<TokenData<JwtTunnelConfig>, HttpResponse> {
     let jwt = req
         .headers()
         .get(SEC_WEBSOCKET_PROTOCOL)
@@ -104,64 +235,245 @@ pub(super) fn extract_tunnel_info(req: &Request<Incoming>) -> anyhow::Result<Tok
 }
 
 impl RestrictionConfig {
-    /// Returns true if the path prefix matches the restriction or if the restriction is set to allow any path.
+    /// Returns true if the path prefix and the bearer token claims match the restriction or if the restriction is set
+    /// to allow any client. Restrictions on the claims never match clients without a bearer token.
     #[inline]
-    fn for_path(self: &RestrictionConfig, path_prefix: &str) -> bool {
-        self.r#match.iter().all(|m| match m {
-            MatchConfig::Any => true,
-            MatchConfig::PathPrefix(path) => path.is_match(path_prefix),
-        })
+    fn for_client(
+        self: &RestrictionConfig,
+        path_prefix: &str,
+        claims: Option<&BearerClaims>,
+        client_ip: IpAddr,
+        geoip: &GeoIpDatabases,
+    ) -> bool {
+        self.check_client(path_prefix, claims, client_ip, geoip).is_ok()
+    }
+
+    /// Same as for_client, but explain why the client does not match the restriction
+    pub(super) fn check_client(
+        self: &RestrictionConfig,
+        path_prefix: &str,
+        claims: Option<&BearerClaims>,
+        client_ip: IpAddr,
+        geoip: &GeoIpDatabases,
+    ) -> Result<(), String> {
+        if !self.time_window.contains(Utc::now()) {
+            return Err("outside of its time window".to_string());
+        }
+
+        let geoip = LazyCell::new(|| geoip.lookup(client_ip));
+        let mismatch = self.r#match.iter().find(|m| match m {
+            MatchConfig::Any => false,
+            MatchConfig::PathPrefix(path) => !path.is_match(path_prefix),
+            MatchConfig::PathPrefixSecret(secret) => !secret.matches(path_prefix),
+            MatchConfig::PathPrefixHmac(secret) => !is_valid_hmac_path_prefix(secret.expose(), path_prefix),
+            MatchConfig::JwtSubject(subject) => !claims
+                .and_then(|claims| claims.subject.as_deref())
+                .is_some_and(|sub| subject.is_match(sub)),
+            MatchConfig::JwtScope(scope) => !claims.is_some_and(|claims| claims.scopes.contains(scope)),
+            MatchConfig::SourceCidr(cidrs) => !cidrs.iter().any(|cidr| cidr.contains(&client_ip.to_canonical())),
+            MatchConfig::SourceCountry(countries) => !geoip
+                .country
+                .as_ref()
+                .is_some_and(|country| countries.iter().any(|c| c.eq_ignore_ascii_case(country))),
+            MatchConfig::SourceAsn(asns) => !geoip.asn.is_some_and(|asn| asns.contains(&asn)),
+        });
+
+        match mismatch {
+            None => Ok(()),
+            Some(m) => Err(format!("client does not match {}", m)),
+        }
+    }
+}
+
+impl TimeWindowConfig {
+    /// Returns true if the time is in the allowed days and hours, in the timezone of the restriction
+    fn contains(&self, now: DateTime<Utc>) -> bool {
+        if self.allowed_days.is_empty() && self.allowed_hours.is_empty() {
+            return true;
+        }
+
+        let now = match self.timezone {
+            TimeZoneConfig::Utc => now.naive_utc(),
+            TimeZoneConfig::Local => now.with_timezone(&Local).naive_local(),
+            TimeZoneConfig::Fixed(offset) => now.with_timezone(&offset).naive_local(),
+        };
+
+        let time = now.time();
+        let in_hours = self.allowed_hours.is_empty()
+            || self.allowed_hours.iter().any(|(start, end)| {
+                if start <= end {
+                    *start <= time && time < *end
+                } else {
+                    // Spans midnight
+                    *start <= time || time < *end
+                }
+            });
+
+        in_hours && (self.allowed_days.is_empty() || self.allowed_days.contains(&now.weekday()))
     }
 }
 
 impl AllowReverseTunnelConfig {
     #[inline]
     fn is_allowed(&self, remote: &RemoteAddr) -> bool {
+        self.check(remote).is_ok()
+    }
+
+    /// Same as is_allowed, but explain why the reverse tunnel is rejected
+    fn check(&self, remote: &RemoteAddr) -> Result<(), String> {
         if !remote.protocol.is_reverse_tunnel() {
-            return false;
+            return Err("not a reverse tunnel".to_string());
         }
 
-        if !self.port.is_empty() && !self.port.iter().any(|range| range.contains(&remote.port)) {
-            return false;
+        let protocol = ReverseTunnelConfigProtocol::from(&remote.protocol);
+        if !self.protocol.is_empty() && !self.protocol.contains(&protocol) {
+            return Err(format!(
+                "protocol {:?} is not in the allowed protocols {:?}",
+                protocol, self.protocol
+            ));
         }
 
-        if !self.protocol.is_empty()
-            && !self
-                .protocol
-                .contains(&ReverseTunnelConfigProtocol::from(&remote.protocol))
-        {
-            return false;
+        // Http ingress routes are identified by their hostname, they don't bind any address of their own
+        if let LocalProtocol::ReverseHttpIngress { .. } = remote.protocol {
+            return Ok(());
         }
 
-        match &remote.host {
-            Host::Domain(_) => false,
-            Host::Ipv4(ip) => self.cidr.iter().any(|cidr| cidr.contains(&IpAddr::from(*ip))),
-            Host::Ipv6(ip) => self.cidr.iter().any(|cidr| cidr.contains(&IpAddr::from(*ip))),
+        if !self.port.is_empty() && !self.port.iter().any(|range| range.contains(&remote.port)) {
+            return Err(format!("port {} is not in the allowed ports {:?}", remote.port, self.port));
+        }
+
+        let ip = match &remote.host {
+            Host::Domain(domain) => return Err(format!("bind address {} is not an ip", domain)),
+            Host::Ipv4(ip) => IpAddr::from(*ip),
+            Host::Ipv6(ip) => IpAddr::from(*ip),
+        };
+        if !self.cidr.iter().any(|cidr| cidr.contains(&ip)) {
+            return Err(format!("bind address {} is not in the allowed cidr {:?}", ip, self.cidr));
         }
+
+        Ok(())
     }
 }
 
 impl AllowTunnelConfig {
     #[inline]
     fn is_allowed(&self, remote: &RemoteAddr) -> bool {
+        self.check(remote).is_ok()
+    }
+
+    /// Same as is_allowed, but explain why the tunnel is rejected
+    fn check(&self, remote: &RemoteAddr) -> Result<(), String> {
         if remote.protocol.is_reverse_tunnel() {
-            return false;
+            return Err("not a forward tunnel".to_string());
         }
 
         if !self.port.is_empty() && !self.port.iter().any(|range| range.contains(&remote.port)) {
-            return false;
+            return Err(format!("port {} is not in the allowed ports {:?}", remote.port, self.port));
         }
 
-        if !self.protocol.is_empty() && !self.protocol.contains(&TunnelConfigProtocol::from(&remote.protocol)) {
-            return false;
+        let protocol = TunnelConfigProtocol::from(&remote.protocol);
+        if !self.protocol.is_empty() && !self.protocol.contains(&protocol) {
+            return Err(format!(
+                "protocol {:?} is not in the allowed protocols {:?}",
+                protocol, self.protocol
+            ));
         }
 
-        match &remote.host {
-            Host::Domain(host) => self.host.is_match(host),
-            Host::Ipv4(ip) => self.cidr.iter().any(|cidr| cidr.contains(&IpAddr::from(*ip))),
-            Host::Ipv6(ip) => self.cidr.iter().any(|cidr| cidr.contains(&IpAddr::from(*ip))),
+        let ip = match &remote.host {
+            Host::Domain(host) if self.host.is_match(host) => return Ok(()),
+            Host::Domain(host) => return Err(format!("host {} does not match {}", host, self.host)),
+            Host::Ipv4(ip) => IpAddr::from(*ip),
+            Host::Ipv6(ip) => IpAddr::from(*ip),
+        };
+        if !self.cidr.iter().any(|cidr| cidr.contains(&ip)) {
+            return Err(format!("ip {} is not in the allowed cidr {:?}", ip, self.cidr));
+        }
+
+        Ok(())
+    }
+}
+
+/// Name of the protocol of a tunnel, as in the restrictions, prefixed with Reverse for reverse tunnels
+pub(crate) fn protocol_name(protocol: &LocalProtocol) -> String {
+    if protocol.is_reverse_tunnel() {
+        format!("Reverse{:?}", ReverseTunnelConfigProtocol::from(protocol))
+    } else {
+        format!("{:?}", TunnelConfigProtocol::from(protocol))
+    }
+}
+
+/// Sources denied take precedence, then the source must be in the allowed ones if there are any
+pub(super) fn is_allowed_source(
+    ip: IpAddr,
+    allow_from: &[SourceFilter],
+    deny_from: &[SourceFilter],
+    geoip: &GeoIpDatabases,
+) -> bool {
+    let ip = ip.to_canonical();
+    let geoip = LazyCell::new(|| geoip.lookup(ip));
+    if deny_from.iter().any(|filter| filter.matches(ip, || &*geoip)) {
+        return false;
+    }
+
+    allow_from.is_empty() || allow_from.iter().any(|filter| filter.matches(ip, || &*geoip))
+}
+
+/// Loopback, link-local, private (RFC1918), shared (CGNAT) and unique local (ULA) addresses, only reachable from the network
+/// of the server. A NAT64 address is private if the ipv4 address it embeds is, and the local-use NAT64 prefix always is.
+pub(super) fn is_private_ip(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let is_shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+            ip.is_unspecified() || ip.is_loopback() || ip.is_link_local() || ip.is_private() || is_shared
         }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // 64:ff9b::/96 well-known prefix and 64:ff9b:1::/48 local-use prefix of NAT64
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., a, b, c, d] = ip.octets();
+                return is_private_ip(IpAddr::V4(Ipv4Addr::new(a, b, c, d)));
+            }
+            let is_local_nat64 = segments[..3] == [0x64, 0xff9b, 1];
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_unicast_link_local()
+                || ip.is_unique_local()
+                || is_local_nat64
+        }
+    }
+}
+
+/// With private destinations denied, a private address is only allowed if the restriction lists it explicitly in the cidr
+/// of one of its tunnels, the default catch-all cidr does not count
+pub(super) fn is_allowed_destination(ip: IpAddr, restriction: &RestrictionConfig) -> bool {
+    if !is_private_ip(ip) {
+        return true;
     }
+
+    restriction.allow.iter().any(|allow| match allow {
+        AllowConfig::Tunnel(allow) => allow
+            .cidr
+            .iter()
+            .any(|cidr| cidr.prefix_len() > 0 && cidr.contains(&ip.to_canonical())),
+        AllowConfig::ReverseTunnel(_) => false,
+    })
+}
+
+/// Destination of a packet of a tun tunnel, it must be in the cidr of a tun tunnel of the restriction
+pub(super) fn is_allowed_packet_destination(
+    ip: IpAddr,
+    restriction: &RestrictionConfig,
+    deny_private_destinations: bool,
+) -> bool {
+    let allowed = restriction.allow.iter().any(|allow| match allow {
+        AllowConfig::Tunnel(allow) => {
+            (allow.protocol.is_empty() || allow.protocol.contains(&TunnelConfigProtocol::Tun))
+                && allow.cidr.iter().any(|cidr| cidr.contains(&ip.to_canonical()))
+        }
+        AllowConfig::ReverseTunnel(_) => false,
+    });
+
+    allowed && (!deny_private_destinations || is_allowed_destination(ip, restriction))
 }
 
 impl AllowConfig {
@@ -172,11 +484,19 @@ impl AllowConfig {
             AllowConfig::Tunnel(config) => config.is_allowed(remote),
         }
     }
+
+    /// Same as is_allowed, but explain why the tunnel is rejected
+    pub(super) fn check(&self, remote: &RemoteAddr) -> Result<(), String> {
+        match self {
+            AllowConfig::ReverseTunnel(config) => config.check(remote),
+            AllowConfig::Tunnel(config) => config.check(remote),
+        }
+    }
 }
 
 /// Validate if the requested tunnel is allowed by the restrictions.
 ///
-/// Restrictions are checked one by one. If one matches the tunnel, the tunnel will be allowed.
+/// Restrictions are checked one by one, by decreasing priority. If one matches the tunnel, the tunnel will be allowed.
 /// If no restriction matches, the tunnel will be rejected.
 ///
 /// # Return value:
@@ -186,15 +506,54 @@ impl AllowConfig {
 pub(super) fn validate_tunnel<'a>(
     remote: &RemoteAddr,
     path_prefix: &str,
+    claims: Option<&BearerClaims>,
+    client_ip: IpAddr,
+    geoip: &GeoIpDatabases,
     restrictions: &'a RestrictionsRules,
 ) -> Option<&'a RestrictionConfig> {
     restrictions
         .restrictions
         .iter()
-        .filter(|restriction| restriction.for_path(path_prefix))
+        .filter(|restriction| restriction.for_client(path_prefix, claims, client_ip, geoip))
         .find(|restriction| restriction.allow.iter().any(|allow| allow.is_allowed(remote)))
 }
 
+/// Explain why validate_tunnel rejected a reverse tunnel, to let the client know which rule it violates.
+/// Forward tunnels are not detailed, to not leak information about the destinations allowed.
+pub(super) fn explain_rejection(
+    remote: &RemoteAddr,
+    path_prefix: &str,
+    claims: Option<&BearerClaims>,
+    client_ip: IpAddr,
+    geoip: &GeoIpDatabases,
+    restrictions: &RestrictionsRules,
+) -> String {
+    if !remote.protocol.is_reverse_tunnel() {
+        return "destination is not allowed".to_string();
+    }
+
+    let reasons: Vec<String> = restrictions
+        .restrictions
+        .iter()
+        .filter(|restriction| restriction.for_client(path_prefix, claims, client_ip, geoip))
+        .flat_map(|restriction| {
+            restriction.allow.iter().filter_map(move |allow| match allow {
+                AllowConfig::ReverseTunnel(config) => config
+                    .check(remote)
+                    .err()
+                    .map(|reason| format!("{}: {}", restriction.name, reason)),
+                AllowConfig::Tunnel(_) => None,
+            })
+        })
+        .collect();
+
+    if reasons.is_empty() {
+        "reverse tunnels are not allowed".to_string()
+    } else {
+        format!("reverse tunnel not allowed, {}", reasons.join(", "))
+    }
+}
+
 pub(super) fn inject_cookie(response: &mut http::Response<impl Body>, remote_addr: &RemoteAddr) -> Result<(), ()> {
     let Ok(header_val) = HeaderValue::from_str(&tunnel_to_jwt_token(Uuid::from_u128(0), remote_addr)) else {
         error!("Bad header value for reverse socks5: {} {}", remote_addr.host, remote_addr.port);
@@ -208,11 +567,28 @@ pub(super) fn inject_cookie(response: &mut http::Response<impl Body>, remote_add
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::restrictions::types::{AllowReverseTunnelConfig, AllowTunnelConfig};
-    use crate::tunnel::LocalProtocol;
-    use ipnet::{IpNet, Ipv4Net};
+    use crate::restrictions::geoip::tests::{asn_record, country_record, geoip_databases};
+    use crate::restrictions::types::{AllowReverseTunnelConfig, AllowTunnelConfig, ProxyProtocolConfig};
+    use ipnet::Ipv4Net;
     use regex::Regex;
     use std::net::Ipv6Addr;
+    use test_case::test_case;
+
+    const CLIENT_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[test_case(Some("01a14391-136a-7600-85e6-af044254ffa6") => Some("01a14391-136a-7600-85e6-af044254ffa6".to_string()) ; "uuid")]
+    #[test_case(Some("01a14391\nforged log line") => None ; "not an uuid")]
+    #[test_case(None => None ; "missing")]
+    fn test_extract_connection_id(header: Option<&str>) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        if let Some(header) = header {
+            headers.insert(
+                CONNECTION_ID_HEADER,
+                HeaderValue::from_str(header).unwrap_or(HeaderValue::from_static("-")),
+            );
+        }
+        extract_connection_id(&headers).map(|id| id.to_string())
+    }
 
     #[test]
     fn test_validate_tunnel() {
@@ -221,24 +597,37 @@ mod tests {
                 // tunnel
                 RestrictionConfig {
                     name: "restrict1".into(),
+                    priority: 0,
                     r#match: vec![MatchConfig::Any],
                     allow: vec![AllowConfig::Tunnel(AllowTunnelConfig {
                         protocol: vec![TunnelConfigProtocol::Tcp],
                         port: vec![80..=80],
                         cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 24).unwrap())],
                         host: Regex::new("example.com").unwrap(),
+                        proxy_protocol: None,
+                        forwarded_header: None,
                     })],
+                    quota: Default::default(),
+                    time_window: Default::default(),
+                    max_session_duration: None,
+                    interceptors: vec![],
                 },
                 // reverse tunnel
                 RestrictionConfig {
                     name: "restrict2".into(),
+                    priority: 0,
                     r#match: vec![MatchConfig::Any],
                     allow: vec![AllowConfig::ReverseTunnel(AllowReverseTunnelConfig {
                         protocol: vec![ReverseTunnelConfigProtocol::Tcp],
                         port: vec![80..=80],
                         cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 24).unwrap())],
                         port_mapping: Default::default(),
+                        isolated_port: vec![],
                     })],
+                    quota: Default::default(),
+                    time_window: Default::default(),
+                    max_session_duration: None,
+                    interceptors: vec![],
                 },
             ],
         };
@@ -249,7 +638,16 @@ mod tests {
             port: 80,
         };
         assert_eq!(
-            validate_tunnel(&remote, "/doesnt/matter", &restrictions).unwrap().name,
+            validate_tunnel(
+                &remote,
+                "/doesnt/matter",
+                None,
+                CLIENT_IP,
+                &GeoIpDatabases::default(),
+                &restrictions
+            )
+            .unwrap()
+            .name,
             restrictions.restrictions[0].name
         );
 
@@ -259,7 +657,16 @@ mod tests {
             port: 80,
         };
         assert_eq!(
-            validate_tunnel(&remote, "/doesnt/matter", &restrictions).unwrap().name,
+            validate_tunnel(
+                &remote,
+                "/doesnt/matter",
+                None,
+                CLIENT_IP,
+                &GeoIpDatabases::default(),
+                &restrictions
+            )
+            .unwrap()
+            .name,
             restrictions.restrictions[1].name
         );
 
@@ -268,14 +675,30 @@ mod tests {
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 81,
         };
-        assert!(validate_tunnel(&remote, "/doesnt/matter", &restrictions).is_none());
+        assert!(validate_tunnel(
+            &remote,
+            "/doesnt/matter",
+            None,
+            CLIENT_IP,
+            &GeoIpDatabases::default(),
+            &restrictions
+        )
+        .is_none());
 
         let remote = RemoteAddr {
             protocol: LocalProtocol::Tcp { proxy_protocol: false },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
-        assert!(validate_tunnel(&remote, "/doesnt/matter", &restrictions).is_none());
+        assert!(validate_tunnel(
+            &remote,
+            "/doesnt/matter",
+            None,
+            CLIENT_IP,
+            &GeoIpDatabases::default(),
+            &restrictions
+        )
+        .is_none());
 
         let remote = RemoteAddr {
             protocol: LocalProtocol::Tcp { proxy_protocol: false },
@@ -283,7 +706,16 @@ mod tests {
             port: 80,
         };
         assert_eq!(
-            validate_tunnel(&remote, "/doesnt/matter", &restrictions).unwrap().name,
+            validate_tunnel(
+                &remote,
+                "/doesnt/matter",
+                None,
+                CLIENT_IP,
+                &GeoIpDatabases::default(),
+                &restrictions
+            )
+            .unwrap()
+            .name,
             restrictions.restrictions[0].name
         );
 
@@ -292,14 +724,30 @@ mod tests {
             host: Host::Domain("not.com".into()),
             port: 80,
         };
-        assert!(validate_tunnel(&remote, "/doesnt/matter", &restrictions).is_none());
+        assert!(validate_tunnel(
+            &remote,
+            "/doesnt/matter",
+            None,
+            CLIENT_IP,
+            &GeoIpDatabases::default(),
+            &restrictions
+        )
+        .is_none());
 
         let remote = RemoteAddr {
             protocol: LocalProtocol::Tcp { proxy_protocol: false },
             host: Host::Ipv6(Ipv6Addr::LOCALHOST),
             port: 80,
         };
-        assert!(validate_tunnel(&remote, "/doesnt/matter", &restrictions).is_none());
+        assert!(validate_tunnel(
+            &remote,
+            "/doesnt/matter",
+            None,
+            CLIENT_IP,
+            &GeoIpDatabases::default(),
+            &restrictions
+        )
+        .is_none());
     }
 
     #[test]
@@ -309,6 +757,7 @@ mod tests {
             port: vec![80..=80],
             cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 8).unwrap())],
             port_mapping: Default::default(),
+            isolated_port: vec![],
         };
 
         let remote = RemoteAddr {
@@ -336,6 +785,7 @@ mod tests {
             port: vec![80..=80],
             cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 24).unwrap())],
             port_mapping: Default::default(),
+            isolated_port: vec![],
         };
 
         // wrong IP
@@ -393,6 +843,77 @@ mod tests {
         assert!(!AllowConfig::from(config.clone()).is_allowed(&remote));
     }
 
+    #[test]
+    fn test_explain_rejection() {
+        let restrictions = RestrictionsRules {
+            restrictions: vec![RestrictionConfig {
+                name: "localhost only".into(),
+                priority: 0,
+                r#match: vec![MatchConfig::Any],
+                allow: vec![AllowConfig::ReverseTunnel(AllowReverseTunnelConfig {
+                    protocol: vec![],
+                    port: vec![1025..=65535],
+                    cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 32).unwrap())],
+                    port_mapping: Default::default(),
+                    isolated_port: vec![],
+                })],
+                quota: Default::default(),
+                time_window: Default::default(),
+                max_session_duration: None,
+                interceptors: vec![],
+            }],
+        };
+
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::ReverseTcp,
+            host: Host::Ipv4([0, 0, 0, 0].into()),
+            port: 8080,
+        };
+        assert!(validate_tunnel(
+            &remote,
+            "/doesnt/matter",
+            None,
+            CLIENT_IP,
+            &GeoIpDatabases::default(),
+            &restrictions
+        )
+        .is_none());
+        assert_eq!(
+            explain_rejection(&remote, "/doesnt/matter", None, CLIENT_IP, &GeoIpDatabases::default(), &restrictions),
+            "reverse tunnel not allowed, localhost only: bind address 0.0.0.0 is not in the allowed cidr [127.0.0.1/32]"
+        );
+
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::ReverseTcp,
+            host: Host::Ipv4([127, 0, 0, 1].into()),
+            port: 80,
+        };
+        assert_eq!(
+            explain_rejection(
+                &remote,
+                "/doesnt/matter",
+                None,
+                CLIENT_IP,
+                &GeoIpDatabases::default(),
+                &restrictions
+            ),
+            "reverse tunnel not allowed, localhost only: port 80 is not in the allowed ports [1025..=65535]"
+        );
+
+        let restrictions = RestrictionsRules { restrictions: vec![] };
+        assert_eq!(
+            explain_rejection(
+                &remote,
+                "/doesnt/matter",
+                None,
+                CLIENT_IP,
+                &GeoIpDatabases::default(),
+                &restrictions
+            ),
+            "reverse tunnels are not allowed"
+        );
+    }
+
     #[test]
     fn test_tunnel_is_allowed() {
         let config = AllowTunnelConfig {
@@ -400,6 +921,8 @@ mod tests {
             port: vec![80..=80],
             cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 8).unwrap())],
             host: Regex::new(".*").unwrap(),
+            proxy_protocol: None,
+            forwarded_header: None,
         };
 
         let remote = RemoteAddr {
@@ -436,6 +959,8 @@ mod tests {
             port: vec![80..=80],
             cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 24).unwrap())],
             host: Regex::new("example.com").unwrap(),
+            proxy_protocol: None,
+            forwarded_header: None,
         };
 
         // wrong IP
@@ -519,4 +1044,440 @@ mod tests {
         assert_eq!(extract_path_prefix("prefix/a/events"), Err(PathPrefixErr::BadPathPrefix));
         assert_eq!(extract_path_prefix("prefix/a/b/events"), Err(PathPrefixErr::BadPathPrefix));
     }
+
+    #[test_case("127.0.0.1" => true ; "loopback")]
+    #[test_case("10.1.2.3" => true ; "rfc1918")]
+    #[test_case("169.254.169.254" => true ; "link local")]
+    #[test_case("fd00::1" => true ; "unique local")]
+    #[test_case("::ffff:192.168.1.1" => true ; "ipv4 mapped")]
+    #[test_case("100.64.0.1" => true ; "cgnat")]
+    #[test_case("100.127.255.254" => true ; "cgnat end")]
+    #[test_case("100.128.0.1" => false ; "after cgnat")]
+    #[test_case("64:ff9b::10.1.2.3" => true ; "nat64 of rfc1918")]
+    #[test_case("64:ff9b::127.0.0.1" => true ; "nat64 of loopback")]
+    #[test_case("64:ff9b::100.64.0.1" => true ; "nat64 of cgnat")]
+    #[test_case("64:ff9b::1.1.1.1" => false ; "nat64 of public ipv4")]
+    #[test_case("64:ff9b:1::1.1.1.1" => true ; "local-use nat64")]
+    #[test_case("1.1.1.1" => false ; "public ipv4")]
+    #[test_case("2606:4700::1111" => false ; "public ipv6")]
+    fn test_is_private_ip(ip: &str) -> bool {
+        is_private_ip(ip.parse().unwrap())
+    }
+
+    #[test_case(&["0.0.0.0/0"], "10.1.2.3" => false ; "catch-all cidr")]
+    #[test_case(&["10.0.0.0/8"], "10.1.2.3" => true ; "explicit cidr")]
+    #[test_case(&["192.168.0.0/16"], "10.1.2.3" => false ; "other cidr")]
+    #[test_case(&["0.0.0.0/0"], "1.1.1.1" => true ; "public address")]
+    fn test_is_allowed_destination(cidr: &[&str], ip: &str) -> bool {
+        let restriction = RestrictionConfig {
+            name: "restrict".into(),
+            priority: 0,
+            r#match: vec![MatchConfig::Any],
+            allow: vec![AllowConfig::Tunnel(AllowTunnelConfig {
+                protocol: vec![],
+                port: vec![],
+                cidr: cidr.iter().map(|cidr| cidr.parse().unwrap()).collect(),
+                host: Regex::new(".*").unwrap(),
+                proxy_protocol: None,
+                forwarded_header: None,
+            })],
+            quota: Default::default(),
+            time_window: Default::default(),
+            max_session_duration: None,
+            interceptors: vec![],
+        };
+        is_allowed_destination(ip.parse().unwrap(), &restriction)
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let mut restriction = RestrictionConfig {
+            name: "restrict".into(),
+            priority: 0,
+            r#match: vec![MatchConfig::Any],
+            allow: vec![],
+            quota: Default::default(),
+            time_window: Default::default(),
+            max_session_duration: None,
+            interceptors: vec![],
+        };
+        let claims = BearerClaims {
+            id: None,
+            subject: Some("alice".to_string()),
+            scopes: vec![],
+        };
+        assert_eq!(client_identity("v1", Some(&claims), &restriction), "sub:alice");
+        assert_eq!(client_identity("v1", None, &restriction), "path_prefix:sha256:3bfc269594ef6492");
+        assert!(!client_identity("s3cr3t-prefix", None, &restriction).contains("s3cr3t"));
+
+        restriction.r#match = vec![MatchConfig::PathPrefixHmac("secret".to_string().into())];
+        assert_eq!(client_identity("v1", None, &restriction), "hmac:restrict");
+        assert_eq!(client_identity("v1", Some(&claims), &restriction), "sub:alice");
+    }
+
+    #[test_case(vec![], &["0.0.0.0/0"], false, "1.1.1.1" => true ; "any protocol")]
+    #[test_case(vec![TunnelConfigProtocol::Tun], &["1.1.1.0/24"], false, "1.1.2.1" => false ; "other cidr")]
+    #[test_case(vec![TunnelConfigProtocol::Tcp], &["0.0.0.0/0"], false, "1.1.1.1" => false ; "other protocol")]
+    #[test_case(vec![TunnelConfigProtocol::Tun], &["0.0.0.0/0"], true, "10.0.0.3" => false ; "private denied")]
+    #[test_case(vec![TunnelConfigProtocol::Tun], &["10.0.0.0/24"], true, "10.0.0.3" => true ; "explicit private")]
+    #[test_case(vec![TunnelConfigProtocol::Tun], &["0.0.0.0/0"], true, "1.1.1.1" => true ; "public address")]
+    fn test_is_allowed_packet_destination(
+        protocol: Vec<TunnelConfigProtocol>,
+        cidr: &[&str],
+        deny_private_destinations: bool,
+        ip: &str,
+    ) -> bool {
+        let restriction = RestrictionConfig {
+            name: "restrict".into(),
+            priority: 0,
+            r#match: vec![MatchConfig::Any],
+            allow: vec![AllowConfig::Tunnel(AllowTunnelConfig {
+                protocol,
+                port: vec![],
+                cidr: cidr.iter().map(|cidr| cidr.parse().unwrap()).collect(),
+                host: Regex::new(".*").unwrap(),
+                proxy_protocol: None,
+                forwarded_header: None,
+            })],
+            quota: Default::default(),
+            time_window: Default::default(),
+            max_session_duration: None,
+            interceptors: vec![],
+        };
+        is_allowed_packet_destination(ip.parse().unwrap(), &restriction, deny_private_destinations)
+    }
+
+    #[test_case(MatchConfig::JwtSubject(Regex::new("^alice$").unwrap()), Some(("alice", "tunnel")) => true ; "subject")]
+    #[test_case(MatchConfig::JwtSubject(Regex::new("^alice$").unwrap()), Some(("bob", "tunnel")) => false ; "other subject")]
+    #[test_case(MatchConfig::JwtScope("tunnel".to_string()), Some(("bob", "tunnel")) => true ; "scope")]
+    #[test_case(MatchConfig::JwtScope("admin".to_string()), Some(("bob", "tunnel")) => false ; "missing scope")]
+    #[test_case(MatchConfig::JwtScope("tunnel".to_string()), None => false ; "no bearer token")]
+    #[test_case(MatchConfig::SourceCidr(vec!["127.0.0.0/8".parse().unwrap()]), None => true ; "source cidr")]
+    #[test_case(MatchConfig::SourceCidr(vec!["10.0.0.0/8".parse().unwrap()]), None => false ; "other source cidr")]
+    #[test_case(MatchConfig::SourceCountry(vec!["FR".to_string()]), None => false ; "source country without database")]
+    #[test_case(MatchConfig::SourceAsn(vec![13335]), None => false ; "source asn without database")]
+    #[test_case(MatchConfig::PathPrefixSecret("/doesnt/matter".to_string().into()), None => true ; "path prefix secret")]
+    #[test_case(MatchConfig::PathPrefixSecret("/doesnt/matteR".to_string().into()), None => false ; "other path prefix secret")]
+    fn test_restriction_for_client(m: MatchConfig, claims: Option<(&str, &str)>) -> bool {
+        let restriction = RestrictionConfig {
+            name: "restrict".into(),
+            priority: 0,
+            r#match: vec![m],
+            allow: vec![],
+            quota: Default::default(),
+            time_window: Default::default(),
+            max_session_duration: None,
+            interceptors: vec![],
+        };
+        let claims = claims.map(|(subject, scope)| BearerClaims {
+            id: None,
+            subject: Some(subject.to_string()),
+            scopes: vec![scope.to_string()],
+        });
+        restriction.for_client("/doesnt/matter", claims.as_ref(), CLIENT_IP, &GeoIpDatabases::default())
+    }
+
+    #[test_case("192.0.2.1", Some("10.1.2.3"), &[] => "192.0.2.1" ; "untrusted peer")]
+    #[test_case("192.0.2.1", Some("10.1.2.3"), &["192.0.2.0/24"] => "10.1.2.3" ; "trusted proxy")]
+    #[test_case("192.0.2.1", None, &["192.0.2.0/24"] => "192.0.2.1" ; "trusted proxy without header")]
+    #[test_case("192.0.2.1", Some("10.1.2.3, 198.51.100.7"), &["192.0.2.0/24"] => "198.51.100.7" ; "forged by the client behind the proxy")]
+    #[test_case("192.0.2.1", Some("198.51.100.7, 192.0.2.2"), &["192.0.2.0/24"] => "198.51.100.7" ; "chain of trusted proxies")]
+    #[test_case("192.0.2.1", Some("garbage"), &["192.0.2.0/24"] => "192.0.2.1" ; "invalid header")]
+    fn test_source_ip(peer: &str, x_forward_for: Option<&str>, trusted_proxies: &[&str]) -> String {
+        let mut req = Request::builder();
+        if let Some(x_forward_for) = x_forward_for {
+            req = req.header("X-Forwarded-For", x_forward_for);
+        }
+        let trusted_proxies: Vec<IpNet> = trusted_proxies.iter().map(|net| net.parse().unwrap()).collect();
+        source_ip(&req.body(()).unwrap(), peer.parse().unwrap(), &trusted_proxies).to_string()
+    }
+
+    #[test]
+    fn test_forged_forwarded_for_does_not_match_source_cidr() {
+        let restriction = RestrictionConfig {
+            name: "restrict".into(),
+            priority: 0,
+            r#match: vec![MatchConfig::SourceCidr(vec!["10.0.0.0/8".parse().unwrap()])],
+            allow: vec![],
+            quota: Default::default(),
+            time_window: Default::default(),
+            max_session_duration: None,
+            interceptors: vec![],
+        };
+        let req = Request::builder()
+            .header("X-Forwarded-For", "10.1.2.3")
+            .body(())
+            .unwrap();
+        let peer_ip = "192.0.2.1".parse().unwrap();
+        let geoip = GeoIpDatabases::default();
+
+        let source = source_ip(&req, peer_ip, &[]);
+        assert!(!restriction.for_client("/doesnt/matter", None, source, &geoip));
+
+        let source = source_ip(&req, peer_ip, &["192.0.2.0/24".parse().unwrap()]);
+        assert!(restriction.for_client("/doesnt/matter", None, source, &geoip));
+    }
+
+    #[test_case(MatchConfig::SourceCountry(vec!["FR".to_string()]), country_record("FR") ; "country")]
+    #[test_case(MatchConfig::SourceAsn(vec![13335]), asn_record(13335) ; "asn")]
+    fn test_forged_forwarded_for_does_not_match_geoip(m: MatchConfig, record: Vec<u8>) {
+        let restriction = RestrictionConfig {
+            name: "restrict".into(),
+            priority: 0,
+            r#match: vec![m],
+            allow: vec![],
+            quota: Default::default(),
+            time_window: Default::default(),
+            max_session_duration: None,
+            interceptors: vec![],
+        };
+        let req = Request::builder()
+            .header("X-Forwarded-For", "10.1.2.3")
+            .body(())
+            .unwrap();
+        let peer_ip = "192.0.2.1".parse().unwrap();
+        let geoip = geoip_databases("10.0.0.0/8", record);
+
+        let source = source_ip(&req, peer_ip, &[]);
+        assert!(!restriction.for_client("/doesnt/matter", None, source, &geoip));
+
+        let source = source_ip(&req, peer_ip, &["192.0.2.0/24".parse().unwrap()]);
+        assert!(restriction.for_client("/doesnt/matter", None, source, &geoip));
+    }
+
+    // 2026-10-16 is a friday
+    #[test_case("allowed_hours: ['09:00-18:00']", "2026-10-16T10:00:00Z" => true ; "in hours")]
+    #[test_case("allowed_hours: ['09:00-18:00']", "2026-10-16T18:00:00Z" => false ; "after hours")]
+    #[test_case("allowed_hours: ['22:00-06:00']", "2026-10-16T23:00:00Z" => true ; "in hours spanning midnight")]
+    #[test_case("allowed_hours: ['22:00-06:00']", "2026-10-16T12:00:00Z" => false ; "out of hours spanning midnight")]
+    #[test_case("allowed_days: ['Mon-Fri']", "2026-10-16T12:00:00Z" => true ; "in days")]
+    #[test_case("allowed_days: ['Sat', 'Sun']", "2026-10-16T12:00:00Z" => false ; "out of days")]
+    #[test_case("allowed_days: ['Fri-Mon']", "2026-10-19T12:00:00Z" => true ; "days spanning the week end")]
+    #[test_case("{allowed_hours: ['09:00-18:00'], timezone: '+02:00'}", "2026-10-16T17:00:00Z" => false ; "after hours with offset")]
+    #[test_case("{allowed_hours: ['09:00-18:00'], timezone: '-05:00'}", "2026-10-16T20:00:00Z" => true ; "in hours with offset")]
+    #[test_case("{}", "2026-10-16T03:00:00Z" => true ; "no time window")]
+    fn test_time_window(config: &str, now: &str) -> bool {
+        let time_window: TimeWindowConfig = serde_yaml::from_str(config).unwrap();
+        time_window.contains(now.parse().unwrap())
+    }
+
+    #[test]
+    fn test_restriction_with_quota_and_time_window() {
+        let config = r#"
+restrictions:
+  - name: "business hours"
+    match:
+      - !Any
+    max_concurrent_connections: 2
+    allowed_hours: ["09:00-18:00"]
+    timezone: Local
+    allow:
+      - !Tunnel
+        port: ["443"]
+"#;
+        let restrictions: RestrictionsRules = serde_yaml::from_str(config).unwrap();
+        let restriction = &restrictions.restrictions[0];
+        assert_eq!(restriction.quota.max_concurrent_connections, Some(2));
+        assert_eq!(restriction.time_window.allowed_hours.len(), 1);
+        assert!(matches!(restriction.time_window.timezone, TimeZoneConfig::Local));
+        assert!(matches!(restriction.allow[0], AllowConfig::Tunnel(_)));
+    }
+
+    #[test_case("max_session_duration: 3600" => Some(3600) ; "seconds")]
+    #[test_case("max_session_duration: 30m" => Some(1800) ; "minutes")]
+    #[test_case("max_session_duration: 8h" => Some(28800) ; "hours")]
+    #[test_case("max_session_duration: 1d" => Some(86400) ; "days")]
+    #[test_case("max_session_duration: soon" => None ; "invalid")]
+    fn test_max_session_duration(config: &str) -> Option<u64> {
+        let config = format!(
+            "restrictions:\n  - name: test\n    match:\n      - !Any\n    allow: []\n    {}\n",
+            config
+        );
+        serde_yaml::from_str::<RestrictionsRules>(&config)
+            .ok()
+            .and_then(|restrictions| restrictions.restrictions[0].max_session_duration)
+            .map(|duration| duration.as_secs())
+    }
+
+    #[test_case(LocalProtocol::Udp { timeout: None }, "monitoring.example.com", 161 => true ; "udp to the monitored host")]
+    #[test_case(LocalProtocol::Udp { timeout: None }, "example.com", 161 => false ; "udp to another host")]
+    #[test_case(LocalProtocol::Tcp { proxy_protocol: false }, "monitoring.example.com", 161 => false ; "tcp")]
+    #[test_case(LocalProtocol::ReverseSocks5 { timeout: None, credentials: None }, "127.0.0.1", 1080 => false ; "reverse socks5")]
+    #[test_case(LocalProtocol::ReverseTcp, "127.0.0.1", 8080 => false ; "reverse tcp")]
+    fn test_restriction_protocols(protocol: LocalProtocol, host: &str, port: u16) -> bool {
+        let config = r#"
+restrictions:
+  - name: "monitoring"
+    match:
+      - !JwtSubject "^monitoring$"
+    allow:
+      - !Tunnel
+        protocol: [Udp]
+        host: ^monitoring\.example\.com$
+"#;
+        let restrictions: RestrictionsRules = serde_yaml::from_str(config).unwrap();
+        let claims = BearerClaims {
+            id: None,
+            subject: Some("monitoring".to_string()),
+            scopes: vec![],
+        };
+        let remote = RemoteAddr {
+            protocol,
+            host: Host::parse(host).unwrap(),
+            port,
+        };
+        validate_tunnel(
+            &remote,
+            "v1",
+            Some(&claims),
+            CLIENT_IP,
+            &GeoIpDatabases::default(),
+            &restrictions,
+        )
+        .is_some()
+    }
+
+    #[test_case(r#"'corp\.example'"#, "corp.example.evil.com" => true ; "plain regex is unanchored")]
+    #[test_case(r#"!Regex 'corp\.example'"#, "corp.example.evil.com" => false ; "regex is anchored")]
+    #[test_case(r#"!Regex "db|cache""#, "db" => true ; "anchored regex alternation")]
+    #[test_case(r#"!Regex "db|cache""#, "dbx" => false ; "anchored regex alternation suffix")]
+    #[test_case(r#"!Glob "*.corp.example""#, "db.corp.example" => true ; "glob")]
+    #[test_case(r#"!Glob "*.corp.example""#, "DB.Corp.Example" => true ; "glob case insensitive")]
+    #[test_case(r#"!Glob "*.corp.example""#, "a.db.corp.example" => false ; "glob star single label")]
+    #[test_case(r#"!Glob "**.corp.example""#, "a.db.corp.example" => true ; "glob double star")]
+    #[test_case(r#"!Glob "*.corp.example""#, "dbxcorp.example" => false ; "glob dot is literal")]
+    #[test_case(r#"!Glob "db-?.corp.example""#, "db-1.corp.example" => true ; "glob question mark")]
+    fn test_restriction_host_patterns(host_pattern: &str, host: &str) -> bool {
+        let config = format!(
+            r#"
+restrictions:
+  - name: "hosts"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+        host: {}
+"#,
+            host_pattern
+        );
+        let restrictions: RestrictionsRules = serde_yaml::from_str(&config).unwrap();
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Domain(host.to_string()),
+            port: 443,
+        };
+        validate_tunnel(&remote, "v1", None, CLIENT_IP, &GeoIpDatabases::default(), &restrictions).is_some()
+    }
+
+    #[test]
+    fn test_restriction_priority() {
+        let config = r#"
+restrictions:
+  - name: "catch all"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+  - name: "limited"
+    priority: 10
+    match:
+      - !PathPrefix "^alice$"
+    max_bandwidth: 1024
+    allow:
+      - !Tunnel
+  - name: "also catch all"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+"#;
+        let restrictions: RestrictionsRules = serde_yaml::from_str(config).unwrap();
+        let names: Vec<&str> = restrictions.restrictions.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["limited", "catch all", "also catch all"]);
+
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Domain("example.com".to_string()),
+            port: 443,
+        };
+        let restriction =
+            validate_tunnel(&remote, "alice", None, CLIENT_IP, &GeoIpDatabases::default(), &restrictions).unwrap();
+        assert_eq!(restriction.name, "limited");
+        let restriction =
+            validate_tunnel(&remote, "bob", None, CLIENT_IP, &GeoIpDatabases::default(), &restrictions).unwrap();
+        assert_eq!(restriction.name, "catch all");
+    }
+
+    #[test]
+    fn test_find_tunnel_rule() {
+        let config = r#"
+restrictions:
+  - name: "backends"
+    match:
+      - !Any
+    allow:
+      - !ReverseTunnel
+      - !Tunnel
+        port: ["80"]
+        proxy_protocol: Disabled
+        forwarded_header: X-Forwarded-For
+      - !Tunnel
+        proxy_protocol: V1
+"#;
+        let restrictions: RestrictionsRules = serde_yaml::from_str(config).unwrap();
+        let restriction = &restrictions.restrictions[0];
+        let remote = |port| RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: true },
+            host: Host::Domain("example.com".to_string()),
+            port,
+        };
+
+        let rule = find_tunnel_rule(&remote(80), restriction).unwrap();
+        assert_eq!(rule.proxy_protocol, Some(ProxyProtocolConfig::Disabled));
+        assert_eq!(rule.forwarded_header.as_deref(), Some("X-Forwarded-For"));
+        let rule = find_tunnel_rule(&remote(443), restriction).unwrap();
+        assert_eq!(rule.proxy_protocol, Some(ProxyProtocolConfig::V1));
+        assert_eq!(rule.forwarded_header, None);
+    }
+
+    #[test]
+    fn test_restriction_invalid_forwarded_header() {
+        let config = r#"
+restrictions:
+  - name: "backends"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+        forwarded_header: "X-Forwarded For"
+"#;
+        assert!(serde_yaml::from_str::<RestrictionsRules>(config).is_err());
+    }
+
+    #[test]
+    fn test_restriction_invalid_host_pattern() {
+        let config = r#"
+restrictions:
+  - name: "hosts"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+        host: !Wildcard "*.example.com"
+"#;
+        assert!(serde_yaml::from_str::<RestrictionsRules>(config).is_err());
+    }
+
+    #[test_case("10.1.2.3", &[], &[] => true ; "no lists")]
+    #[test_case("10.1.2.3", &["10.0.0.0/8"], &[] => true ; "allowed")]
+    #[test_case("192.168.1.1", &["10.0.0.0/8"], &[] => false ; "not allowed")]
+    #[test_case("10.1.2.3", &["10.0.0.0/8"], &["10.1.2.0/24"] => false ; "denied takes precedence")]
+    #[test_case("::ffff:10.1.2.3", &[], &["10.0.0.0/8"] => false ; "ipv4 mapped denied")]
+    #[test_case("10.1.2.3", &["country:FR"], &[] => false ; "unknown country not allowed")]
+    #[test_case("10.1.2.3", &["10.0.0.0/8"], &["asn:13335"] => true ; "unknown asn not denied")]
+    fn test_is_allowed_source(ip: &str, allow_from: &[&str], deny_from: &[&str]) -> bool {
+        let allow_from: Vec<SourceFilter> = allow_from.iter().map(|filter| filter.parse().unwrap()).collect();
+        let deny_from: Vec<SourceFilter> = deny_from.iter().map(|filter| filter.parse().unwrap()).collect();
+        is_allowed_source(ip.parse().unwrap(), &allow_from, &deny_from, &GeoIpDatabases::default())
+    }
 }
@@ -0,0 +1,219 @@
+//! Single packet authorization: the server only accepts the connections of the sources which sent it a knock, a udp
+//! packet signed with the secret it shares with its clients.
+//! A knock is: version (1 byte) | unix time in seconds (8 bytes, big endian) | random nonce (16 bytes) | hmac-sha256
+//! of the previous fields (32 bytes)
+
+use crate::protocols::dns::DnsResolver;
+use ahash::AHashMap;
+use anyhow::{anyhow, Context};
+use parking_lot::Mutex;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+use url::Host;
+
+const VERSION: u8 = 1;
+const SIGNED_LEN: usize = 1 + 8 + 16;
+const KNOCK_LEN: usize = SIGNED_LEN + 32;
+
+/// Knocks sent longer ago, or later, are rejected. Their nonces are remembered twice as long to reject the replays
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// Knocks older than this are sent again by the client before its next connection
+const KNOCK_REFRESH: Duration = Duration::from_secs(10);
+
+/// Expired admissions are forgotten once this many sources are tracked
+const MAX_TRACKED_SOURCES: usize = 100_000;
+
+fn sign_knock(key: &hmac::Key, time: SystemTime, nonce: &[u8; 16]) -> [u8; KNOCK_LEN] {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut knock = [0; KNOCK_LEN];
+    knock[0] = VERSION;
+    knock[1..9].copy_from_slice(&secs.to_be_bytes());
+    knock[9..SIGNED_LEN].copy_from_slice(nonce);
+    let signature = hmac::sign(key, &knock[..SIGNED_LEN]);
+    knock[SIGNED_LEN..].copy_from_slice(signature.as_ref());
+    knock
+}
+
+/// Nonce of the knock, if it is signed with the key and was sent around now
+fn verify_knock(key: &hmac::Key, knock: &[u8], now: SystemTime) -> Result<[u8; 16], &'static str> {
+    if knock.len() != KNOCK_LEN || knock[0] != VERSION {
+        return Err("not a knock");
+    }
+    hmac::verify(key, &knock[..SIGNED_LEN], &knock[SIGNED_LEN..]).map_err(|_| "invalid signature")?;
+
+    let secs = u64::from_be_bytes(knock[1..9].try_into().unwrap_or_default());
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    if now.abs_diff(secs) > MAX_CLOCK_SKEW.as_secs() {
+        return Err("knock sent too long ago, or the clocks are not synchronized");
+    }
+    Ok(knock[9..SIGNED_LEN].try_into().unwrap_or_default())
+}
+
+/// Sources of the server admitted by their knock
+pub struct SpaGate {
+    pub bind: SocketAddr,
+    key: hmac::Key,
+    window: Duration,
+    admitted: Mutex<AHashMap<IpAddr, Instant>>,
+    nonces: Mutex<AHashMap<[u8; 16], Instant>>,
+}
+
+impl SpaGate {
+    pub fn new(bind: SocketAddr, secret: &[u8], window: Duration) -> Self {
+        Self {
+            bind,
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            window,
+            admitted: Mutex::new(AHashMap::new()),
+            nonces: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    pub fn is_admitted(&self, ip: IpAddr) -> bool {
+        matches!(self.admitted.lock().get(&ip.to_canonical()), Some(until) if *until > Instant::now())
+    }
+
+    /// Admit the source of a valid knock for the window
+    fn knock(&self, ip: IpAddr, knock: &[u8], now: Instant) -> Result<(), &'static str> {
+        let nonce = verify_knock(&self.key, knock, SystemTime::now())?;
+        {
+            let mut nonces = self.nonces.lock();
+            nonces.retain(|_, seen_at| now.duration_since(*seen_at) <= MAX_CLOCK_SKEW * 2);
+            if nonces.insert(nonce, now).is_some() {
+                return Err("replayed knock");
+            }
+        }
+
+        let mut admitted = self.admitted.lock();
+        if admitted.len() >= MAX_TRACKED_SOURCES {
+            admitted.retain(|_, until| *until > now);
+        }
+        admitted.insert(ip.to_canonical(), now + self.window);
+        Ok(())
+    }
+
+    /// Receive the knocks, the invalid ones are only logged at debug level as they are mostly scanners
+    pub async fn run(self: Arc<Self>, socket: UdpSocket) {
+        let mut buf = [0; KNOCK_LEN + 1];
+        loop {
+            let (len, peer_addr) = match socket.recv_from(&mut buf).await {
+                Ok(ret) => ret,
+                Err(err) => {
+                    warn!("Error while receiving a knock {:?}", err);
+                    continue;
+                }
+            };
+            match self.knock(peer_addr.ip(), &buf[..len], Instant::now()) {
+                Ok(()) => info!("Admitting {} for {:?} after its knock", peer_addr.ip(), self.window),
+                Err(err) => debug!("Ignoring knock from {}: {}", peer_addr, err),
+            }
+        }
+    }
+}
+
+/// Knocks of the client on the server before connecting to it
+pub struct SpaKnocker {
+    key: hmac::Key,
+    port: u16,
+    last_knock: Mutex<Option<Instant>>,
+}
+
+impl SpaKnocker {
+    pub fn new(secret: &[u8], port: u16) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            port,
+            last_knock: Mutex::new(None),
+        }
+    }
+
+    /// Knock on every address of the server, unless it was done recently
+    pub async fn knock(&self, host: &Host<String>, dns_resolver: &DnsResolver) -> anyhow::Result<()> {
+        if self.last_knock.lock().is_some_and(|at| at.elapsed() < KNOCK_REFRESH) {
+            return Ok(());
+        }
+
+        let addrs = match host {
+            Host::Domain(domain) => dns_resolver
+                .lookup_host(domain, self.port)
+                .await
+                .with_context(|| format!("cannot resolve domain: {}", domain))?,
+            Host::Ipv4(ip) => vec![SocketAddr::V4(SocketAddrV4::new(*ip, self.port))],
+            Host::Ipv6(ip) => vec![SocketAddr::V6(SocketAddrV6::new(*ip, self.port, 0, 0))],
+        };
+        let mut nonce = [0; 16];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("cannot generate the nonce of the knock"))?;
+        let knock = sign_knock(&self.key, SystemTime::now(), &nonce);
+        for addr in addrs {
+            let bind: SocketAddr = match addr {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = UdpSocket::bind(bind).await?;
+            socket
+                .send_to(&knock, addr)
+                .await
+                .with_context(|| format!("cannot send the knock to {}", addr))?;
+            debug!("Knocked on {}", addr);
+        }
+        *self.last_knock.lock() = Some(Instant::now());
+
+        // The server has to receive the knock before the connection
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(())
+    }
+
+    /// Knock again before the next connection, i.e: when the last one failed as the knock may have been lost
+    pub fn forget(&self) {
+        *self.last_knock.lock() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const NONCE: [u8; 16] = [7; 16];
+
+    #[test_case(b"secret", 0 => Ok(NONCE) ; "valid")]
+    #[test_case(b"secret", 25 => Ok(NONCE) ; "within the clock skew")]
+    #[test_case(b"secret", 60 => Err("knock sent too long ago, or the clocks are not synchronized") ; "too old")]
+    #[test_case(b"other", 0 => Err("invalid signature") ; "other secret")]
+    fn test_verify_knock(secret: &[u8], age_secs: u64) -> Result<[u8; 16], &'static str> {
+        let now = SystemTime::now();
+        let knock = sign_knock(
+            &hmac::Key::new(hmac::HMAC_SHA256, secret),
+            now - Duration::from_secs(age_secs),
+            &NONCE,
+        );
+        verify_knock(&hmac::Key::new(hmac::HMAC_SHA256, b"secret"), &knock, now)
+    }
+
+    #[test]
+    fn test_spa_gate() {
+        let gate = SpaGate::new("0.0.0.0:62201".parse().unwrap(), b"secret", Duration::from_secs(30));
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let knock = sign_knock(&gate.key, SystemTime::now(), &NONCE);
+        assert!(!gate.is_admitted(ip));
+
+        assert_eq!(gate.knock(ip, &knock, Instant::now()), Ok(()));
+        assert!(gate.is_admitted(ip));
+        assert!(gate.is_admitted("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!gate.is_admitted(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))));
+
+        // The same knock sent again, from another source
+        let other_ip = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+        assert_eq!(gate.knock(other_ip, &knock, Instant::now()), Err("replayed knock"));
+        assert!(!gate.is_admitted(other_ip));
+        assert_eq!(gate.knock(other_ip, &knock[..40], Instant::now()), Err("not a knock"));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::health::HEALTH;
 use crate::protocols::tls;
 use crate::tunnel::client::WsClientConfig;
 use crate::tunnel::server::WsServerConfig;
@@ -191,6 +192,7 @@ impl TlsReloader {
             match event.kind {
                 EventKind::Create(_) | EventKind::Modify(_) => match tls::load_certificates_from_pem(&this.cert_path) {
                     Ok(tls_certs) => {
+                        HEALTH.set_certificate(&tls_certs);
                         *tls.tls_certificate.lock() = tls_certs;
                         this.tls_reload_certificate.store(true, Ordering::Relaxed);
                     }
@@ -286,6 +288,7 @@ impl TlsReloader {
                     tls::load_private_key_from_file(&this.key_path),
                 ) {
                     (Ok(tls_certs), Ok(tls_key)) => {
+                        HEALTH.set_certificate(&tls_certs);
                         let tls_connector = tls::tls_connector(
                             tls.tls_verify_certificate,
                             this.client_config.remote_addr.scheme().alpn_protocols(),
@@ -329,6 +332,7 @@ impl TlsReloader {
                     tls::load_private_key_from_file(&this.key_path),
                 ) {
                     (Ok(tls_certs), Ok(tls_key)) => {
+                        HEALTH.set_certificate(&tls_certs);
                         let tls_connector = tls::tls_connector(
                             tls.tls_verify_certificate,
                             this.client_config.remote_addr.scheme().alpn_protocols(),
@@ -0,0 +1,83 @@
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use ring::hmac;
+
+/// Around midnight, the prefix of the other day is still accepted for this long, for the requests in flight and
+/// the clocks of the clients which are a bit off
+const MIDNIGHT_GRACE_PERIOD: TimeDelta = TimeDelta::hours(1);
+
+/// Path prefix valid for the given UTC date (YYYY-MM-DD): base64url(HMAC-SHA256(secret, date)) without padding.
+/// i.e: echo -n $(date -u +%F) | openssl dgst -sha256 -hmac SECRET -binary | basenc --base64url | tr -d =
+pub fn hmac_path_prefix(secret: &str, date: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, date.as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(tag.as_ref())
+}
+
+/// Path prefix of today, computed for each upgrade request as it changes every day
+pub fn current_hmac_path_prefix(secret: &str) -> String {
+    hmac_path_prefix(secret, &format_date(Utc::now().date_naive()))
+}
+
+/// Check the path prefix against the one of today, and against the one of yesterday (or tomorrow) during the grace
+/// period after (or before) midnight
+pub fn is_valid_hmac_path_prefix(secret: &str, path_prefix: &str) -> bool {
+    let Ok(tag) = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(path_prefix) else {
+        return false;
+    };
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    valid_dates(Utc::now())
+        .iter()
+        .any(|date| hmac::verify(&key, format_date(*date).as_bytes(), &tag).is_ok())
+}
+
+fn valid_dates(now: DateTime<Utc>) -> [NaiveDate; 3] {
+    [
+        (now - MIDNIGHT_GRACE_PERIOD).date_naive(),
+        now.date_naive(),
+        (now + MIDNIGHT_GRACE_PERIOD).date_naive(),
+    ]
+}
+
+fn format_date(date: NaiveDate) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("2026-10-16T12:00:00Z" => vec!["2026-10-16"] ; "middle of the day")]
+    #[test_case("2026-10-16T00:30:00Z" => vec!["2026-10-15", "2026-10-16"] ; "after midnight")]
+    #[test_case("2026-12-31T23:30:00Z" => vec!["2026-12-31", "2027-01-01"] ; "before midnight")]
+    #[test_case("2028-03-01T00:59:59Z" => vec!["2028-02-29", "2028-03-01"] ; "after a leap day")]
+    #[test_case("2028-03-01T01:00:00Z" => vec!["2028-03-01"] ; "end of grace period")]
+    fn test_valid_dates(now: &str) -> Vec<String> {
+        let mut dates: Vec<String> = valid_dates(now.parse().unwrap()).into_iter().map(format_date).collect();
+        dates.dedup();
+        dates
+    }
+
+    #[test]
+    fn test_hmac_path_prefix() {
+        // echo -n 2026-10-16 | openssl dgst -sha256 -hmac secret -binary | basenc --base64url | tr -d =
+        assert_eq!(
+            hmac_path_prefix("secret", "2026-10-16"),
+            "kXPJXtEZktXPoCPdNu3VDJOel8ryRR_RPrc_O93vnds"
+        );
+    }
+
+    #[test]
+    fn test_is_valid_hmac_path_prefix() {
+        let today = Utc::now().date_naive();
+        assert!(is_valid_hmac_path_prefix("secret", &current_hmac_path_prefix("secret")));
+        assert!(!is_valid_hmac_path_prefix(
+            "secret",
+            &hmac_path_prefix("secret", &format_date(today - TimeDelta::days(2)))
+        ));
+        assert!(!is_valid_hmac_path_prefix("other", &current_hmac_path_prefix("secret")));
+        assert!(!is_valid_hmac_path_prefix("secret", "not-a-valid-prefix!"));
+    }
+}
@@ -1,12 +1,15 @@
 use super::io::{TunnelRead, TunnelWrite, MAX_PACKET_LENGTH};
 use crate::tunnel::client::WsClient;
 use crate::tunnel::transport::jwt::tunnel_to_jwt_token;
-use crate::tunnel::transport::{headers_from_file, TransportScheme};
+use crate::tunnel::transport::{
+    headers_from_file, ServerRejection, TransportScheme, CONNECTION_ID_HEADER, TOTP_HEADER,
+};
 use crate::tunnel::RemoteAddr;
 use anyhow::{anyhow, Context};
 use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::UnsyncBoxBody;
 use http_body_util::{BodyExt, BodyStream, StreamBody};
-use hyper::body::{Frame, Incoming};
+use hyper::body::{Body, Frame};
 use hyper::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE};
 use hyper::http::response::Parts;
 use hyper::Request;
@@ -15,22 +18,29 @@ use log::{debug, error, warn};
 use std::future::Future;
 use std::io;
 use std::io::ErrorKind;
-use std::ops::DerefMut;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::sync::{mpsc, Notify};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
+use tracing::instrument;
 use uuid::Uuid;
 
 pub struct Http2TunnelRead {
-    inner: BodyStream<Incoming>,
+    inner: BodyStream<UnsyncBoxBody<Bytes, anyhow::Error>>,
 }
 
 impl Http2TunnelRead {
-    pub const fn new(inner: BodyStream<Incoming>) -> Self {
-        Self { inner }
+    /// The body of the request on the server, or of the response on the client
+    pub fn new<B>(body: B) -> Self
+    where
+        B: Body<Data = Bytes> + Send + 'static,
+        B::Error: Into<anyhow::Error>,
+    {
+        Self {
+            inner: BodyStream::new(body.map_err(Into::into).boxed_unsync()),
+        }
     }
 }
 
@@ -110,15 +120,13 @@ impl TunnelWrite for Http2TunnelWrite {
     }
 }
 
+#[instrument(level = "info", name = "upgrade", skip_all)]
 pub async fn connect(
     request_id: Uuid,
     client: &WsClient,
     dest_addr: &RemoteAddr,
 ) -> anyhow::Result<(Http2TunnelRead, Http2TunnelWrite, Parts)> {
-    let mut pooled_cnx = match client.cnx_pool.get().await {
-        Ok(cnx) => Ok(cnx),
-        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
-    }?;
+    let transport = client.server_connection(dest_addr).await?;
 
     // In http2 HOST header does not exist, it is explicitly set in the authority from the request uri
     let (headers_file, authority) =
@@ -150,10 +158,11 @@ pub async fn connect(
             authority
                 .as_deref()
                 .unwrap_or_else(|| client.config.http_header_host.to_str().unwrap_or("")),
-            &client.config.http_upgrade_path_prefix
+            client.config.upgrade_path_prefix()
         ))
         .header(COOKIE, tunnel_to_jwt_token(request_id, dest_addr))
         .header(CONTENT_TYPE, "application/json")
+        .header(CONNECTION_ID_HEADER, request_id.to_string())
         .version(hyper::Version::HTTP_2);
 
     let headers = match req.headers_mut() {
@@ -161,18 +170,23 @@ pub async fn connect(
         None => {
             return Err(anyhow!(
                 "failed to build HTTP request to contact the server {:?}. Most likely path_prefix `{}` or http headers is not valid",
-                req, client.config.http_upgrade_path_prefix
+                req, client.config.upgrade_path_prefix()
             ))
         }
     };
-    for (k, v) in &client.config.http_headers {
-        let _ = headers.remove(k);
-        headers.append(k, v.clone());
+    for (k, v) in client.config.http_headers() {
+        let _ = headers.remove(&k);
+        headers.append(k, v);
     }
 
-    if let Some(auth) = &client.config.http_upgrade_credentials {
+    if let Some(auth) = client.config.http_upgrade_credentials() {
         let _ = headers.remove(AUTHORIZATION);
-        headers.append(AUTHORIZATION, auth.clone());
+        headers.append(AUTHORIZATION, auth);
+    }
+
+    if let Some(totp_command) = &client.config.http_upgrade_totp_command {
+        let _ = headers.remove(TOTP_HEADER);
+        headers.append(TOTP_HEADER, totp_command.code().await?);
     }
 
     if let Some(headers_file) = headers_file {
@@ -191,11 +205,10 @@ pub async fn connect(
         )
     })?;
     debug!("with HTTP upgrade request {:?}", req);
-    let transport = pooled_cnx.deref_mut().take().unwrap();
     let (mut request_sender, cnx) = hyper::client::conn::http2::Builder::new(TokioExecutor::new())
         .timer(TokioTimer::new())
         .adaptive_window(true)
-        .keep_alive_interval(client.config.websocket_ping_frequency)
+        .keep_alive_interval(client.config.websocket_ping_frequency())
         .keep_alive_timeout(Duration::from_secs(10))
         .keep_alive_while_idle(false)
         .handshake(TokioIo::new(transport))
@@ -213,13 +226,17 @@ pub async fn connect(
         .with_context(|| format!("failed to send http2 request with the server {:?}", client.config.remote_addr))?;
 
     if !response.status().is_success() {
-        return Err(anyhow!(
-            "Http2 server rejected the connection: {:?}: {:?}",
-            response.status(),
-            String::from_utf8(response.into_body().collect().await?.to_bytes().to_vec()).unwrap_or_default()
-        ));
+        let status = response.status();
+        return Err(anyhow!(ServerRejection {
+            status: status.as_u16(),
+            reason: format!(
+                "Http2 server rejected the connection: {:?}: {:?}",
+                status,
+                String::from_utf8(response.into_body().collect().await?.to_bytes().to_vec()).unwrap_or_default()
+            ),
+        }));
     }
 
     let (parts, body) = response.into_parts();
-    Ok((Http2TunnelRead::new(BodyStream::new(body)), Http2TunnelWrite::new(tx), parts))
+    Ok((Http2TunnelRead::new(body), Http2TunnelWrite::new(tx), parts))
 }
@@ -1,21 +1,33 @@
+use crate::tunnel::active_tunnels::ActiveTunnels;
 use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
+use crate::tunnel::transport::pluggable::{StreamTunnelRead, StreamTunnelWrite};
 use crate::tunnel::transport::websocket::{WebsocketTunnelRead, WebsocketTunnelWrite};
 use bytes::{BufMut, BytesMut};
 use futures_util::{pin_mut, FutureExt};
+use pin_project::{pin_project, pinned_drop};
 use std::future::Future;
+use std::io;
 use std::io::ErrorKind;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tokio::select;
 use tokio::sync::{oneshot, Notify};
-use tokio::time::Instant;
+use tokio::time::{Instant, Sleep};
 use tracing::log::debug;
 use tracing::{error, info, warn};
 
 pub(super) static MAX_PACKET_LENGTH: usize = 64 * 1024;
 
+/// Report the sides of a tunnel not accepting writes for the timeout, counted in the open tunnels of the client or server
+#[derive(Clone)]
+pub struct SlowConsumerTimeout {
+    pub timeout: Duration,
+    pub tunnels: Arc<ActiveTunnels>,
+}
+
 pub trait TunnelWrite: Send + 'static {
     fn buf_mut(&mut self) -> &mut BytesMut;
     fn write(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
@@ -35,6 +47,7 @@ pub trait TunnelRead: Send + 'static {
 pub enum TunnelReader {
     Websocket(WebsocketTunnelRead),
     Http2(Http2TunnelRead),
+    Stream(StreamTunnelRead),
 }
 
 impl TunnelRead for TunnelReader {
@@ -42,6 +55,7 @@ impl TunnelRead for TunnelReader {
         match self {
             Self::Websocket(s) => s.copy(writer).await,
             Self::Http2(s) => s.copy(writer).await,
+            Self::Stream(s) => s.copy(writer).await,
         }
     }
 }
@@ -49,6 +63,7 @@ impl TunnelRead for TunnelReader {
 pub enum TunnelWriter {
     Websocket(WebsocketTunnelWrite),
     Http2(Http2TunnelWrite),
+    Stream(StreamTunnelWrite),
 }
 
 impl TunnelWrite for TunnelWriter {
@@ -56,6 +71,7 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.buf_mut(),
             Self::Http2(s) => s.buf_mut(),
+            Self::Stream(s) => s.buf_mut(),
         }
     }
 
@@ -63,6 +79,7 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.write().await,
             Self::Http2(s) => s.write().await,
+            Self::Stream(s) => s.write().await,
         }
     }
 
@@ -70,6 +87,7 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.ping().await,
             Self::Http2(s) => s.ping().await,
+            Self::Stream(s) => s.ping().await,
         }
     }
 
@@ -77,6 +95,7 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.close().await,
             Self::Http2(s) => s.close().await,
+            Self::Stream(s) => s.close().await,
         }
     }
 
@@ -84,6 +103,7 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.pending_operations_notify(),
             Self::Http2(s) => s.pending_operations_notify(),
+            Self::Stream(s) => s.pending_operations_notify(),
         }
     }
 
@@ -91,6 +111,7 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.handle_pending_operations().await,
             Self::Http2(s) => s.handle_pending_operations().await,
+            Self::Stream(s) => s.handle_pending_operations().await,
         }
     }
 }
@@ -100,9 +121,10 @@ pub async fn propagate_local_to_remote(
     mut ws_tx: impl TunnelWrite,
     mut close_tx: oneshot::Sender<()>,
     ping_frequency: Option<Duration>,
+    slow_consumer_timeout: Option<SlowConsumerTimeout>,
 ) -> anyhow::Result<()> {
-    let _guard = scopeguard::guard((), |_| {
-        info!("Closing local => remote tunnel");
+    let mut bytes = scopeguard::guard(0_u64, |bytes| {
+        info!(bytes, "Closing local => remote tunnel");
     });
 
     static MAX_PACKET_LENGTH: usize = 64 * 1024;
@@ -152,7 +174,7 @@ pub async fn propagate_local_to_remote(
             }
         };
 
-        let _read_len = match read_len {
+        let read_len = match read_len {
             Ok(0) => break,
             Ok(read_len) => read_len,
             Err(err) => {
@@ -161,8 +183,9 @@ pub async fn propagate_local_to_remote(
             }
         };
 
+        *bytes += read_len as u64;
         //debug!("read {} wasted {}% usable {} capa {}", read_len, 100 - (read_len * 100 / buffer.capacity()), buffer.as_slice().len(), buffer.capacity());
-        if let Err(err) = ws_tx.write().await {
+        if let Err(err) = write_or_report_slow_consumer(ws_tx.write(), slow_consumer_timeout.as_ref()).await {
             warn!("error while writing to tx tunnel {}", err);
             break;
         }
@@ -178,11 +201,18 @@ pub async fn propagate_remote_to_local(
     local_tx: impl AsyncWrite + Send,
     mut ws_rx: impl TunnelRead,
     mut close_rx: oneshot::Receiver<()>,
+    slow_consumer_timeout: Option<SlowConsumerTimeout>,
 ) -> anyhow::Result<()> {
-    let _guard = scopeguard::guard((), |_| {
-        info!("Closing local <= remote tunnel");
-    });
-
+    let local_tx = CloseLogWriter {
+        inner: SlowConsumerWriter {
+            inner: local_tx,
+            timeout: slow_consumer_timeout,
+            stall_timer: None,
+            stalled_since: None,
+            reported: false,
+        },
+        bytes: 0,
+    };
     pin_mut!(local_tx);
     loop {
         let msg = select! {
@@ -203,3 +233,170 @@ pub async fn propagate_remote_to_local(
 
     Ok(())
 }
+
+/// Await a write to the remote side of the tunnel, reporting it when it has not been accepted after the timeout.
+/// The writes stall when the network, or the other end of the tunnel, does not keep up
+async fn write_or_report_slow_consumer<T>(
+    write: impl Future<Output = T>,
+    slow_consumer_timeout: Option<&SlowConsumerTimeout>,
+) -> T {
+    pin_mut!(write);
+    let Some(slow_consumer_timeout) = slow_consumer_timeout else {
+        return write.await;
+    };
+    // Most writes complete right away, do not arm a timer for them
+    if let Poll::Ready(ret) = futures_util::poll!(&mut write) {
+        return ret;
+    }
+
+    let stalled_since = Instant::now();
+    if let Ok(ret) = tokio::time::timeout(slow_consumer_timeout.timeout, &mut write).await {
+        return ret;
+    }
+    report_slow_consumer("remote", slow_consumer_timeout);
+    let ret = write.await;
+    info!(
+        consumer = "remote",
+        stalled_ms = stalled_since.elapsed().as_millis() as u64,
+        "Slow consumer accepts writes again"
+    );
+    ret
+}
+
+fn report_slow_consumer(consumer: &'static str, SlowConsumerTimeout { timeout, tunnels }: &SlowConsumerTimeout) {
+    tunnels.record_slow_consumer();
+    warn!(
+        consumer,
+        stalled_secs = timeout.as_secs(),
+        "Slow consumer, the {} side of the tunnel has not accepted writes for {:?}",
+        consumer,
+        timeout
+    );
+}
+
+/// Report the local side of the tunnel once it has not accepted writes for the timeout.
+/// The writes stall when the local application does not read what it receives
+#[pin_project]
+struct SlowConsumerWriter<W> {
+    #[pin]
+    inner: W,
+    timeout: Option<SlowConsumerTimeout>,
+    /// Armed when a write is pending, until a write completes
+    #[pin]
+    stall_timer: Option<Sleep>,
+    stalled_since: Option<Instant>,
+    reported: bool,
+}
+
+impl<W: AsyncWrite> AsyncWrite for SlowConsumerWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+        let ret = this.inner.poll_write(cx, buf);
+        if ret.is_ready() {
+            if let Some(stalled_since) = this.stalled_since.take() {
+                if *this.reported {
+                    info!(
+                        consumer = "local",
+                        stalled_ms = stalled_since.elapsed().as_millis() as u64,
+                        "Slow consumer accepts writes again"
+                    );
+                }
+                *this.reported = false;
+                this.stall_timer.set(None);
+            }
+            return ret;
+        }
+
+        let Some(timeout) = this.timeout.as_ref() else {
+            return Poll::Pending;
+        };
+        if this.stalled_since.is_none() {
+            *this.stalled_since = Some(Instant::now());
+            this.stall_timer.set(Some(tokio::time::sleep(timeout.timeout)));
+        }
+        if let Some(stall_timer) = this.stall_timer.as_pin_mut() {
+            if !*this.reported && stall_timer.poll(cx).is_ready() {
+                *this.reported = true;
+                report_slow_consumer("local", timeout);
+            }
+        }
+        Poll::Pending
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// Count the bytes written to the local side of the tunnel, logged once it is closed
+#[pin_project(PinnedDrop)]
+struct CloseLogWriter<W> {
+    #[pin]
+    inner: W,
+    bytes: u64,
+}
+
+#[pinned_drop]
+impl<W> PinnedDrop for CloseLogWriter<W> {
+    fn drop(self: Pin<&mut Self>) {
+        info!(bytes = self.bytes, "Closing local <= remote tunnel");
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for CloseLogWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let len = ready!(this.inner.poll_write(cx, buf))?;
+        *this.bytes += len as u64;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_slow_consumer_writer() {
+        let (local, mut application) = tokio::io::duplex(16);
+        let tunnels = Arc::new(ActiveTunnels::default());
+        let writer = SlowConsumerWriter {
+            inner: local,
+            timeout: Some(SlowConsumerTimeout {
+                timeout: Duration::from_millis(50),
+                tunnels: tunnels.clone(),
+            }),
+            stall_timer: None,
+            stalled_since: None,
+            reported: false,
+        };
+        pin_mut!(writer);
+
+        writer.write_all(&[0; 16]).await.unwrap();
+        // The application does not read, the buffer of the duplex is full
+        assert!(tokio::time::timeout(Duration::from_millis(200), writer.write_all(&[0; 16]))
+            .await
+            .is_err());
+        assert_eq!(tunnels.counters().slow_consumers, 1);
+        assert!(writer.reported);
+
+        let mut buf = [0; 32];
+        application.read_exact(&mut buf[..16]).await.unwrap();
+        writer.write_all(&[0; 16]).await.unwrap();
+        assert!(!writer.reported);
+        assert!(writer.stalled_since.is_none());
+    }
+}
@@ -1,6 +1,6 @@
+pub use crate::protocol::{JwtTunnelConfig, JWT_HEADER_PREFIX};
 use crate::tunnel::{LocalProtocol, RemoteAddr};
 use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
-use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::ops::Deref;
 use std::sync::LazyLock;
@@ -8,7 +8,6 @@ use std::time::SystemTime;
 use url::Host;
 use uuid::Uuid;
 
-pub static JWT_HEADER_PREFIX: &str = "authorization.bearer.";
 static JWT_KEY: LazyLock<(Header, EncodingKey)> = LazyLock::new(|| {
     let now = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -25,14 +24,6 @@ static JWT_DECODE: LazyLock<(Validation, DecodingKey)> = LazyLock::new(|| {
     (validation, DecodingKey::from_secret(b"champignonfrais"))
 });
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct JwtTunnelConfig {
-    pub id: String,       // tunnel id
-    pub p: LocalProtocol, // protocol to use
-    pub r: String,        // remote host
-    pub rp: u16,          // remote port
-}
-
 impl JwtTunnelConfig {
     fn new(request_id: Uuid, dest: &RemoteAddr) -> Self {
         Self {
@@ -45,12 +36,16 @@ impl JwtTunnelConfig {
                 LocalProtocol::ReverseSocks5 { .. } => dest.protocol.clone(),
                 LocalProtocol::ReverseUnix { .. } => dest.protocol.clone(),
                 LocalProtocol::ReverseHttpProxy { .. } => dest.protocol.clone(),
+                LocalProtocol::Exec => dest.protocol.clone(),
+                LocalProtocol::ReverseHttpIngress { .. } => dest.protocol.clone(),
+                LocalProtocol::Tun => dest.protocol.clone(),
                 LocalProtocol::TProxyTcp => unreachable!("cannot use tproxy tcp as destination protocol"),
                 LocalProtocol::TProxyUdp { .. } => unreachable!("cannot use tproxy udp as destination protocol"),
                 LocalProtocol::Stdio { .. } => unreachable!("cannot use stdio as destination protocol"),
                 LocalProtocol::Unix { .. } => unreachable!("canont use unix as destination protocol"),
                 LocalProtocol::Socks5 { .. } => unreachable!("cannot use socks5 as destination protocol"),
                 LocalProtocol::HttpProxy { .. } => unreachable!("cannot use http proxy as destination protocol"),
+                LocalProtocol::TunInterface { .. } => unreachable!("cannot use tun interface as destination protocol"),
             },
             r: dest.host.to_string(),
             rp: dest.port,
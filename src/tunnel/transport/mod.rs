@@ -1,24 +1,49 @@
-use hyper::header::HOST;
-use hyper::http::{HeaderName, HeaderValue};
+use crate::tunnel::client::WsClientConfig;
+use hyper::header::{AUTHORIZATION, HOST};
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::str::FromStr;
 
 use tracing::error;
 
+mod hmac_path_prefix;
 pub mod http2;
 pub mod io;
 mod jwt;
+pub mod pluggable;
 mod types;
 pub mod websocket;
 
+pub use hmac_path_prefix::current_hmac_path_prefix;
+pub use hmac_path_prefix::is_valid_hmac_path_prefix;
 pub use jwt::jwt_token_to_tunnel;
 pub use jwt::tunnel_to_jwt_token;
 pub use jwt::JwtTunnelConfig;
 pub use jwt::JWT_HEADER_PREFIX;
+pub use pluggable::{TransportRead, TransportWrite, TunnelTransport};
 pub use types::TransportAddr;
 pub use types::TransportScheme;
 
+/// Header sent back by the server with the address of the reverse listener dedicated to the client
+pub static REVERSE_LISTENER_HEADER: &str = "x-wstunnel-reverse-listener";
+/// Header sent back by the server with the local address used to connect to the destination
+pub static BOUND_ADDR_HEADER: &str = "x-wstunnel-bound-addr";
+/// Header sent back by the server with the address of the peer whose connection was accepted by a reverse tunnel
+pub static PEER_ADDR_HEADER: &str = "x-wstunnel-peer-addr";
+/// Header sent by the client with its TOTP code, when the server requires a second factor
+pub static TOTP_HEADER: &str = "x-wstunnel-totp";
+/// Header sent by the client with the id of the tunnel, to correlate the logs of the client and of the server
+pub static CONNECTION_ID_HEADER: &str = "x-wstunnel-connection-id";
+
+/// The server answered the upgrade request of a tunnel with an error status
+#[derive(Debug, derive_more::Display, derive_more::Error)]
+#[display("{reason}")]
+pub struct ServerRejection {
+    pub status: u16,
+    pub reason: String,
+}
+
 #[allow(clippy::type_complexity)]
 #[inline]
 pub fn headers_from_file(path: &Path) -> (Option<(HeaderName, HeaderValue)>, Vec<(HeaderName, HeaderValue)>) {
@@ -48,3 +73,35 @@ pub fn headers_from_file(path: &Path) -> (Option<(HeaderName, HeaderValue)>, Vec
 
     (host_header, headers)
 }
+
+/// Add the headers of the client to an upgrade request: -H, the credentials, the TOTP code and the headers file.
+/// The Host header of the file is returned instead of added
+async fn upgrade_headers(
+    headers: &mut HeaderMap,
+    client_cfg: &WsClientConfig,
+) -> anyhow::Result<Option<(HeaderName, HeaderValue)>> {
+    for (k, v) in client_cfg.http_headers() {
+        let _ = headers.remove(&k);
+        headers.append(k, v);
+    }
+
+    if let Some(auth) = client_cfg.http_upgrade_credentials() {
+        let _ = headers.remove(AUTHORIZATION);
+        headers.append(AUTHORIZATION, auth);
+    }
+
+    if let Some(totp_command) = &client_cfg.http_upgrade_totp_command {
+        let _ = headers.remove(TOTP_HEADER);
+        headers.append(TOTP_HEADER, totp_command.code().await?);
+    }
+
+    let Some(headers_file_path) = &client_cfg.http_headers_file else {
+        return Ok(None);
+    };
+    let (host, headers_file) = headers_from_file(headers_file_path);
+    for (k, v) in headers_file {
+        let _ = headers.remove(&k);
+        headers.append(k, v);
+    }
+    Ok(host)
+}
@@ -0,0 +1,176 @@
+//! Transports of third parties, i.e: an obfuscation layer, replacing websocket and http2 between the client and the
+//! server. Each tunnel has its own connection to the server: the transport carries the upgrade request of the tunnel
+//! and its response, then the bytes of the tunnel.
+
+use super::io::{TunnelRead, TunnelReader, TunnelWrite, TunnelWriter, MAX_PACKET_LENGTH};
+use crate::tunnel::client::WsClient;
+use crate::tunnel::transport::jwt::tunnel_to_jwt_token;
+use crate::tunnel::transport::{upgrade_headers, ServerRejection, CONNECTION_ID_HEADER};
+use crate::tunnel::RemoteAddr;
+use anyhow::{anyhow, Context};
+use bytes::BytesMut;
+use futures_util::future::BoxFuture;
+use hyper::header::{COOKIE, HOST};
+use hyper::http::{request, response};
+use hyper::Request;
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+use tracing::{debug, instrument};
+use uuid::Uuid;
+
+pub type TransportRead = Pin<Box<dyn AsyncRead + Send>>;
+pub type TransportWrite = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// Framing of the tunnels over the connections between the client and the server. The connections are established by
+/// wstunnel, with tls if the server url requires it, before they are handed to the transport
+pub trait TunnelTransport: Send + Sync + 'static {
+    /// Client: send the upgrade request of a tunnel on a new connection to the server, and wait for its response.
+    /// The returned streams carry the bytes of the tunnel
+    fn connect(
+        &self,
+        rx: TransportRead,
+        tx: TransportWrite,
+        request: Request<()>,
+    ) -> BoxFuture<'static, anyhow::Result<(response::Parts, TransportRead, TransportWrite)>>;
+
+    /// Server: receive the upgrade request of a tunnel on a connection accepted by the server
+    fn accept(
+        &self,
+        rx: TransportRead,
+        tx: TransportWrite,
+    ) -> BoxFuture<'static, anyhow::Result<(request::Parts, TransportRead, TransportWrite)>>;
+
+    /// Server: answer the request received by accept(). The connection is closed after an error status
+    fn respond<'a>(&'a self, tx: &'a mut TransportWrite, response: response::Parts) -> BoxFuture<'a, io::Result<()>>;
+}
+
+pub struct StreamTunnelRead {
+    inner: TransportRead,
+    buf: BytesMut,
+}
+
+impl StreamTunnelRead {
+    pub fn new(inner: TransportRead) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::with_capacity(MAX_PACKET_LENGTH),
+        }
+    }
+}
+
+impl TunnelRead for StreamTunnelRead {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+        self.buf.clear();
+        match self.inner.read_buf(&mut self.buf).await {
+            Ok(0) => Err(io::Error::new(ErrorKind::BrokenPipe, "closed")),
+            Ok(_) => writer
+                .write_all(&self.buf)
+                .await
+                .map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, err)),
+            Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+        }
+    }
+}
+
+pub struct StreamTunnelWrite {
+    inner: TransportWrite,
+    buf: BytesMut,
+    pending_ops_notify: Arc<Notify>,
+}
+
+impl StreamTunnelWrite {
+    pub fn new(inner: TransportWrite) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::with_capacity(MAX_PACKET_LENGTH),
+            pending_ops_notify: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl TunnelWrite for StreamTunnelWrite {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    async fn write(&mut self) -> Result<(), io::Error> {
+        let ret = self.inner.write_all(&self.buf).await;
+        self.buf.clear();
+        ret?;
+        self.inner.flush().await
+    }
+
+    // The transport keeps its connection alive on its own, if it needs to
+    async fn ping(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), io::Error> {
+        self.inner.shutdown().await
+    }
+
+    fn pending_operations_notify(&mut self) -> Arc<Notify> {
+        self.pending_ops_notify.clone()
+    }
+
+    fn handle_pending_operations(&mut self) -> impl Future<Output = Result<(), io::Error>> + Send {
+        std::future::ready(Ok(()))
+    }
+}
+
+/// Upgrade request of a tunnel, with the same headers as the websocket and http2 ones
+async fn upgrade_request(request_id: Uuid, client: &WsClient, dest_addr: &RemoteAddr) -> anyhow::Result<Request<()>> {
+    let client_cfg = &client.config;
+    let mut req = Request::builder()
+        .method("GET")
+        .uri(format!("/{}/events", client_cfg.upgrade_path_prefix()))
+        .header(HOST, &client_cfg.http_header_host)
+        .header(COOKIE, tunnel_to_jwt_token(request_id, dest_addr))
+        .header(CONNECTION_ID_HEADER, request_id.to_string())
+        .body(())
+        .with_context(|| {
+            format!(
+                "failed to build HTTP request to contact the server {:?}. Most likely path_prefix `{}` is not valid",
+                client_cfg.remote_addr,
+                client_cfg.upgrade_path_prefix()
+            )
+        })?;
+    if let Some((host, val)) = upgrade_headers(req.headers_mut(), client_cfg).await? {
+        req.headers_mut().insert(host, val);
+    }
+    Ok(req)
+}
+
+#[instrument(level = "info", name = "upgrade", skip_all)]
+pub async fn connect(
+    request_id: Uuid,
+    client: &WsClient,
+    dest_addr: &RemoteAddr,
+    transport: &dyn TunnelTransport,
+) -> anyhow::Result<(TunnelReader, TunnelWriter, response::Parts)> {
+    let req = upgrade_request(request_id, client, dest_addr).await?;
+    debug!("with upgrade request {:?}", req);
+    let (rx, tx) = client.server_connection(dest_addr).await?.into_split();
+    let (response, rx, tx) = transport
+        .connect(Box::pin(rx), Box::pin(tx), req)
+        .await
+        .with_context(|| format!("failed to upgrade the connection to the server {:?}", client.config.remote_addr))?;
+
+    if !response.status.is_success() {
+        return Err(anyhow!(ServerRejection {
+            status: response.status.as_u16(),
+            reason: format!("server rejected the connection: {}", response.status),
+        }));
+    }
+
+    Ok((
+        TunnelReader::Stream(StreamTunnelRead::new(rx)),
+        TunnelWriter::Stream(StreamTunnelWrite::new(tx)),
+        response,
+    ))
+}
@@ -1,15 +1,17 @@
 use super::io::{TunnelRead, TunnelWrite, MAX_PACKET_LENGTH};
 use crate::tunnel::client::l4_transport_stream::{TransportReadHalf, TransportStream, TransportWriteHalf};
 use crate::tunnel::client::WsClient;
-use crate::tunnel::transport::headers_from_file;
 use crate::tunnel::transport::jwt::{tunnel_to_jwt_token, JWT_HEADER_PREFIX};
+use crate::tunnel::transport::{upgrade_headers, ServerRejection, CONNECTION_ID_HEADER};
 use crate::tunnel::RemoteAddr;
 use anyhow::{anyhow, Context};
 use bytes::{Bytes, BytesMut};
-use fastwebsockets::{CloseCode, Frame, OpCode, Payload, Role, WebSocket, WebSocketRead, WebSocketWrite};
+use fastwebsockets::{
+    CloseCode, Frame, OpCode, Payload, Role, WebSocket, WebSocketError, WebSocketRead, WebSocketWrite,
+};
 use http_body_util::Empty;
-use hyper::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
 use hyper::header::{CONNECTION, HOST, SEC_WEBSOCKET_KEY};
+use hyper::header::{SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
 use hyper::http::response::Parts;
 use hyper::upgrade::Upgraded;
 use hyper::Request;
@@ -18,7 +20,6 @@ use hyper_util::rt::TokioIo;
 use log::debug;
 use std::io;
 use std::io::ErrorKind;
-use std::ops::DerefMut;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
@@ -27,7 +28,7 @@ use tokio::net::TcpStream;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::Notify;
 use tokio_rustls::server::TlsStream;
-use tracing::trace;
+use tracing::{instrument, trace};
 use uuid::Uuid;
 
 pub struct WebsocketTunnelWrite {
@@ -234,25 +235,24 @@ impl TunnelRead for WebsocketTunnelRead {
     }
 }
 
+#[instrument(level = "info", name = "upgrade", skip_all)]
 pub async fn connect(
     request_id: Uuid,
     client: &WsClient,
     dest_addr: &RemoteAddr,
 ) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
     let client_cfg = &client.config;
-    let mut pooled_cnx = match client.cnx_pool.get().await {
-        Ok(cnx) => Ok(cnx),
-        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
-    }?;
+    let transport = client.server_connection(dest_addr).await?;
 
     let mut req = Request::builder()
         .method("GET")
-        .uri(format!("/{}/events", &client_cfg.http_upgrade_path_prefix))
+        .uri(format!("/{}/events", client_cfg.upgrade_path_prefix()))
         .header(HOST, &client_cfg.http_header_host)
         .header(UPGRADE, "websocket")
         .header(CONNECTION, "upgrade")
         .header(SEC_WEBSOCKET_KEY, fastwebsockets::handshake::generate_key())
         .header(SEC_WEBSOCKET_VERSION, "13")
+        .header(CONNECTION_ID_HEADER, request_id.to_string())
         .header(
             SEC_WEBSOCKET_PROTOCOL,
             format!("v1, {}{}", JWT_HEADER_PREFIX, tunnel_to_jwt_token(request_id, dest_addr)),
@@ -264,30 +264,13 @@ pub async fn connect(
         None => {
             return Err(anyhow!(
                 "failed to build HTTP request to contact the server {:?}. Most likely path_prefix `{}` or http headers is not valid",
-                req.body(Empty::<Bytes>::new()), client_cfg.http_upgrade_path_prefix
+                req.body(Empty::<Bytes>::new()), client_cfg.upgrade_path_prefix()
             ))
         }
     };
-    for (k, v) in &client_cfg.http_headers {
-        let _ = headers.remove(k);
-        headers.append(k, v.clone());
-    }
-
-    if let Some(auth) = &client_cfg.http_upgrade_credentials {
-        let _ = headers.remove(AUTHORIZATION);
-        headers.append(AUTHORIZATION, auth.clone());
-    }
-
-    if let Some(headers_file_path) = &client_cfg.http_headers_file {
-        let (host, headers_file) = headers_from_file(headers_file_path);
-        for (k, v) in headers_file {
-            let _ = headers.remove(&k);
-            headers.append(k, v);
-        }
-        if let Some((host, val)) = host {
-            let _ = headers.remove(&host);
-            headers.append(host, val);
-        }
+    if let Some((host, val)) = upgrade_headers(headers, client_cfg).await? {
+        let _ = headers.remove(&host);
+        headers.append(host, val);
     }
 
     let req = req.body(Empty::<Bytes>::new()).with_context(|| {
@@ -297,9 +280,24 @@ pub async fn connect(
         )
     })?;
     debug!("with HTTP upgrade request {:?}", req);
-    let transport = pooled_cnx.deref_mut().take().unwrap();
     let (ws, response) = fastwebsockets::handshake::client(&TokioExecutor::new(), req, transport)
         .await
+        .map_err(|err| match err {
+            // The reason is only in the body of the response, which is not available here. Look at the server logs
+            WebSocketError::InvalidStatusCode(403) => anyhow!(ServerRejection {
+                status: 403,
+                reason: "tunnel is not allowed by the restrictions of the server".to_string(),
+            }),
+            WebSocketError::InvalidStatusCode(402) => anyhow!(ServerRejection {
+                status: 402,
+                reason: "monthly quota of this client on the server is exhausted".to_string(),
+            }),
+            WebSocketError::InvalidStatusCode(status) => anyhow!(ServerRejection {
+                status,
+                reason: err.to_string(),
+            }),
+            err => anyhow!(err),
+        })
         .with_context(|| format!("failed to do websocket handshake with the server {:?}", client_cfg.remote_addr))?;
 
     let (ws_rx, ws_tx) = mk_websocket_tunnel(ws, Role::Client, client_cfg.websocket_mask_frame)?;
@@ -329,10 +327,14 @@ pub fn mk_websocket_tunnel(
                     WebSocket::after_handshake(transport, role)
                 }
                 Err(upgraded) => {
-                    let stream = hyper_util::server::conn::auto::upgrade::downcast::<TokioIo<TcpStream>>(upgraded)
-                        .map_err(|_| anyhow!("cannot downcast websocket server stream"))?;
-                    let transport = TransportStream::from_tcp(stream.io.into_inner(), stream.read_buf);
-                    WebSocket::after_handshake(transport, role)
+                    match hyper_util::server::conn::auto::upgrade::downcast::<TokioIo<TcpStream>>(upgraded) {
+                        Ok(stream) => {
+                            let transport = TransportStream::from_tcp(stream.io.into_inner(), stream.read_buf);
+                            WebSocket::after_handshake(transport, role)
+                        }
+                        // Served by the http server of the caller, see TunnelService
+                        Err(upgraded) => WebSocket::after_handshake(TransportStream::from_upgraded(upgraded), role),
+                    }
                 }
             }
         }
@@ -0,0 +1,283 @@
+use crate::tunnel::client::TransferStats;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Instant, SystemTime};
+
+/// Tunnels configured with -L/-R, with the histograms of the connections they forwarded
+pub static TUNNEL_METRICS: LazyLock<TunnelMetricsRegistry> = LazyLock::new(TunnelMetricsRegistry::default);
+
+pub(crate) const LATENCY_MS_BOUNDS: &[u64] = &[1, 2, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000];
+const THROUGHPUT_BOUNDS: &[u64] = &[
+    1 << 10,
+    1 << 12,
+    1 << 14,
+    1 << 16,
+    1 << 18,
+    1 << 20,
+    1 << 22,
+    1 << 24,
+    1 << 26,
+    1 << 28,
+    1 << 30,
+];
+
+#[derive(Default)]
+pub struct TunnelMetricsRegistry {
+    tunnels: Mutex<Vec<Arc<TunnelMetrics>>>,
+}
+
+impl TunnelMetricsRegistry {
+    /// Metrics of a configured tunnel, for the whole life of the process
+    pub fn register(&self, name: String) -> Arc<TunnelMetrics> {
+        let metrics = Arc::new(TunnelMetrics {
+            name,
+            registered_at: Instant::now(),
+            connections: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            first_byte_ms: Histogram::new(LATENCY_MS_BOUNDS),
+            sent_bytes_per_sec: Histogram::new(THROUGHPUT_BOUNDS),
+            received_bytes_per_sec: Histogram::new(THROUGHPUT_BOUNDS),
+        });
+        self.tunnels.lock().push(metrics.clone());
+        metrics
+    }
+
+    pub fn list(&self) -> Vec<TunnelMetricsInfo> {
+        self.tunnels
+            .lock()
+            .iter()
+            .map(|metrics| TunnelMetricsInfo {
+                name: metrics.name.clone(),
+                uptime_secs: metrics.registered_at.elapsed().as_secs(),
+                connections: metrics.connections.load(Ordering::Relaxed),
+                errors: metrics.errors.load(Ordering::Relaxed),
+                bytes_sent: metrics.bytes_sent.load(Ordering::Relaxed),
+                bytes_received: metrics.bytes_received.load(Ordering::Relaxed),
+                first_byte_ms: metrics.first_byte_ms.snapshot(),
+                sent_bytes_per_sec: metrics.sent_bytes_per_sec.snapshot(),
+                received_bytes_per_sec: metrics.received_bytes_per_sec.snapshot(),
+            })
+            .collect()
+    }
+}
+
+pub struct TunnelMetrics {
+    name: String,
+    registered_at: Instant,
+    /// Connections forwarded until they were closed, and the ones which could not be opened
+    connections: AtomicU64,
+    errors: AtomicU64,
+    /// Of the reverse tunnel, once the server could be reached again
+    reconnects: AtomicU64,
+    last_error: Mutex<Option<(SystemTime, String)>>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    /// From the start of a connection to the first byte coming back from its destination
+    first_byte_ms: Histogram,
+    /// Average rate of each direction of a connection, over its whole life
+    sent_bytes_per_sec: Histogram,
+    received_bytes_per_sec: Histogram,
+}
+
+impl TunnelMetrics {
+    /// Record a connection once it is closed. `first_byte_at` is when its destination answered, if it ever did
+    pub(crate) fn observe(&self, stats: &TransferStats, started_at: Instant, first_byte_at: Option<Instant>) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(stats.sent(), Ordering::Relaxed);
+        self.bytes_received.fetch_add(stats.received(), Ordering::Relaxed);
+        if let Some(first_byte_at) = first_byte_at {
+            self.first_byte_ms
+                .observe(first_byte_at.saturating_duration_since(started_at).as_millis() as u64);
+        }
+
+        let secs = started_at.elapsed().as_secs_f64().max(0.001);
+        if stats.sent() > 0 {
+            self.sent_bytes_per_sec.observe((stats.sent() as f64 / secs) as u64);
+        }
+        if stats.received() > 0 {
+            self.received_bytes_per_sec
+                .observe((stats.received() as f64 / secs) as u64);
+        }
+    }
+
+    pub(crate) fn record_error(&self, err: &anyhow::Error) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.set_last_error(err);
+    }
+
+    /// Error not counted as a failed connection, i.e: a reverse tunnel which cannot reach the server
+    pub(crate) fn set_last_error(&self, err: &anyhow::Error) {
+        *self.last_error.lock() = Some((SystemTime::now(), format!("{:#}", err)));
+    }
+
+    pub(crate) fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn last_error(&self) -> Option<(SystemTime, String)> {
+        self.last_error.lock().clone()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TunnelMetricsInfo {
+    pub name: String,
+    pub uptime_secs: u64,
+    pub connections: u64,
+    pub errors: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub first_byte_ms: HistogramSnapshot,
+    pub sent_bytes_per_sec: HistogramSnapshot,
+    pub received_bytes_per_sec: HistogramSnapshot,
+}
+
+/// Number of values up to each bound, plus the ones above the last bound
+pub(crate) struct Histogram {
+    bounds: &'static [u64],
+    buckets: Box<[AtomicU64]>,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum: u64,
+    pub max: u64,
+    /// Upper bound of the bucket holding the percentile, or the max if it is above the last bound
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    /// Cumulative, as prometheus does. The last bucket has no bound and counts all the values
+    pub buckets: Vec<HistogramBucket>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistogramBucket {
+    pub le: Option<u64>,
+    pub count: u64,
+}
+
+impl Histogram {
+    pub(crate) fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn observe(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> HistogramSnapshot {
+        let mut count = 0;
+        let buckets: Vec<HistogramBucket> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(ix, bucket)| {
+                count += bucket.load(Ordering::Relaxed);
+                HistogramBucket {
+                    le: self.bounds.get(ix).copied(),
+                    count,
+                }
+            })
+            .collect();
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |p: u64| {
+            let rank = (count * p).div_ceil(100);
+            buckets
+                .iter()
+                .find(|bucket| bucket.count >= rank)
+                .and_then(|bucket| bucket.le)
+                .map_or(max, |le| le.min(max))
+        };
+
+        HistogramSnapshot {
+            count,
+            sum: self.sum.load(Ordering::Relaxed),
+            max,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            buckets,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::client::CountingStream;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new(LATENCY_MS_BOUNDS);
+        assert_eq!(histogram.snapshot().p99, 0);
+
+        for value in [3, 4, 8, 20, 40, 60, 70, 80, 90, 45_000] {
+            histogram.observe(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 10);
+        assert_eq!(snapshot.sum, 45_375);
+        assert_eq!(snapshot.p50, 50);
+        assert_eq!(snapshot.p90, 100);
+        assert_eq!(snapshot.p99, 45_000);
+        assert_eq!(snapshot.buckets[2].le, Some(5));
+        assert_eq!(snapshot.buckets[2].count, 2);
+        assert_eq!(snapshot.buckets.last().unwrap().le, None);
+        assert_eq!(snapshot.buckets.last().unwrap().count, 10);
+    }
+
+    #[tokio::test]
+    async fn test_observe() {
+        let registry = TunnelMetricsRegistry::default();
+        let metrics = registry.register("L:tcp://127.0.0.1:8080:example.com:80".to_string());
+        let stats = Arc::new(TransferStats::default());
+        let started_at = Instant::now();
+        let mut stream = CountingStream::new(Vec::new(), Some(stats.clone()));
+        stream.write_all(b"hello").await.unwrap();
+        metrics.observe(&stats, started_at, stats.first_received_at());
+        metrics.record_error(&anyhow::anyhow!("connection refused"));
+        metrics.record_reconnect();
+
+        let forwards = registry.list();
+        assert_eq!(forwards.len(), 1);
+        assert_eq!(forwards[0].name, "L:tcp://127.0.0.1:8080:example.com:80");
+        assert_eq!(forwards[0].first_byte_ms.count, 1);
+        assert_eq!(forwards[0].received_bytes_per_sec.count, 1);
+        assert_eq!(forwards[0].sent_bytes_per_sec.count, 0);
+        assert_eq!(forwards[0].connections, 1);
+        assert_eq!(forwards[0].errors, 1);
+        assert_eq!(forwards[0].bytes_received, 5);
+        assert_eq!(metrics.reconnects(), 1);
+        assert_eq!(metrics.last_error().unwrap().1, "connection refused");
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{host_alias, Commands, Wstunnel};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command, CommandFactory};
+use serde_json::{Map, Value};
+use wstunnel::config::Client;
+use wstunnel::config_check::{self, CheckFormat};
+use wstunnel::{run_client, run_server};
+
+/// Validate the configuration of the client or the server like when it starts, and print it on stdout
+pub fn run_check(commands: Commands, matches: &ArgMatches, format: CheckFormat) -> anyhow::Result<()> {
+    let (name, matches) = matches.subcommand().expect("--check is an argument of a subcommand");
+    // Built, so the global arguments are part of the subcommands
+    let mut command = Wstunnel::command();
+    command.build();
+    let command = command.find_subcommand(name).expect("subcommand of the matches");
+
+    let mut config = Map::new();
+    config.insert(name.to_string(), effective_args(command, matches));
+
+    if let Some(alias) = host_alias::host_alias() {
+        config.insert("host_alias".to_string(), config_check::host_alias(alias)?);
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let check_client = |args: Client, config: &mut Map<String, Value>| -> anyhow::Result<()> {
+        if let Some(path) = &args.config {
+            config.insert("config_file".to_string(), config_check::client_config_file(path)?);
+        }
+        runtime.block_on(run_client(args))?;
+        Ok(())
+    };
+    match commands {
+        Commands::Client(args) => check_client(*args, &mut config)?,
+        Commands::Bench(args) => check_client(args.client, &mut config)?,
+        Commands::Server(args) => runtime.block_on(run_server(*args))?,
+        _ => unreachable!("only the client and the server have --check"),
+    }
+
+    print!("{}", config_check::to_string(&Value::Object(config), format)?);
+    Ok(())
+}
+
+/// Values of the arguments once merged with their environment variables and defaults, as given
+fn effective_args(command: &Command, matches: &ArgMatches) -> Value {
+    let mut args = Map::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if id == "check" || matches.value_source(id).is_none() {
+            continue;
+        }
+        let Some(values) = matches.get_raw(id) else {
+            continue;
+        };
+
+        // The defaults are not secrets
+        let is_default = matches.value_source(id) == Some(ValueSource::DefaultValue);
+        let mut values = values.map(|value| match is_default {
+            true => Value::String(value.to_string_lossy().into_owned()),
+            false => Value::String(config_check::redact_arg(id, &value.to_string_lossy())),
+        });
+        let value = match arg.get_action() {
+            ArgAction::SetTrue | ArgAction::SetFalse => Value::Bool(values.next() == Some(Value::from("true"))),
+            ArgAction::Append => Value::Array(values.collect()),
+            _ => values.next().unwrap_or(Value::Null),
+        };
+        args.insert(id.to_string(), value);
+    }
+
+    Value::Object(args)
+}
@@ -0,0 +1,75 @@
+use crate::Wstunnel;
+use anyhow::Context;
+use clap::CommandFactory;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Print the completion script of a shell, i.e: wstunnel completions bash > /etc/bash_completion.d/wstunnel
+///                                             wstunnel completions zsh > "${fpath[1]}/_wstunnel"
+#[derive(clap::Args, Debug)]
+pub struct Completions {
+    #[arg(value_name = "SHELL", verbatim_doc_comment)]
+    shell: clap_complete::Shell,
+}
+
+/// Print the man page of wstunnel, i.e: wstunnel man | man -l -
+#[derive(clap::Args, Debug)]
+pub struct Man {
+    /// Write the man pages of wstunnel and of each subcommand (wstunnel-client.1, ...) to this directory instead,
+    /// i.e: wstunnel man --out-dir /usr/local/share/man/man1
+    #[arg(long, value_name = "DIR", verbatim_doc_comment)]
+    out_dir: Option<PathBuf>,
+}
+
+pub fn run_completions(args: Completions) -> anyhow::Result<()> {
+    // Generated in memory, clap_complete panics when it cannot write, i.e: piped to head
+    let mut script = Vec::new();
+    clap_complete::generate(args.shell, &mut command(), "wstunnel", &mut script);
+    io::stdout().write_all(&script)?;
+    Ok(())
+}
+
+pub fn run_man(args: Man) -> anyhow::Result<()> {
+    match args.out_dir {
+        Some(dir) => clap_mangen::generate_to(command(), &dir)
+            .with_context(|| format!("Cannot write the man pages to {}", dir.display())),
+        None => Ok(clap_mangen::Man::new(command()).render(&mut io::stdout())?),
+    }
+}
+
+/// Named after the binary, not after the package
+fn command() -> clap::Command {
+    Wstunnel::command().name("wstunnel")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completions() {
+        command().debug_assert();
+
+        let mut script = Vec::new();
+        clap_complete::generate(clap_complete::Shell::Bash, &mut command(), "wstunnel", &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("complete -F _wstunnel"));
+        assert!(script.contains("--http-upgrade-path-prefix"));
+    }
+
+    #[test]
+    fn test_man_pages_of_subcommands() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-man-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        run_man(Man {
+            out_dir: Some(dir.clone()),
+        })
+        .unwrap();
+
+        let client = std::fs::read_to_string(dir.join("wstunnel-client.1")).unwrap();
+        assert!(client.contains("wstunnel\\-client"));
+        assert!(dir.join("wstunnel.1").exists());
+        assert!(dir.join("wstunnel-server.1").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
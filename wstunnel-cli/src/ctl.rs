@@ -0,0 +1,99 @@
+use anyhow::Context;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+/// Send a command to the --admin-socket of a running client or server, and print its json response
+#[derive(clap::Args, Debug)]
+pub struct Ctl {
+    /// Path of the --admin-socket of the wstunnel client or server to control
+    #[arg(value_name = "FILE_PATH", verbatim_doc_comment)]
+    admin_socket: PathBuf,
+
+    /// Print the response as a single json line, as answered by the admin socket, instead of for a human.
+    /// Can also be given after the command, i.e: wstunnel ctl /run/wstunnel/client.sock status --json
+    #[arg(long, verbatim_doc_comment)]
+    json: bool,
+
+    /// The admin command, i.e: forwards, status, add -L tcp://1212:google.com:443, remove ID, close ID, reload, stats
+    #[arg(
+        value_name = "COMMAND",
+        required = true,
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        verbatim_doc_comment
+    )]
+    command: Vec<String>,
+}
+
+/// Fails when the command is answered by an error, so it can be used in scripts
+pub fn run_ctl(mut args: Ctl) -> anyhow::Result<()> {
+    // Taken as part of the command when given after it
+    if args.command.len() > 1 && args.command.last().is_some_and(|arg| arg == "--json") {
+        args.command.pop();
+        args.json = true;
+    }
+    let mut stream = UnixStream::connect(&args.admin_socket)
+        .with_context(|| format!("Cannot connect to {}", args.admin_socket.display()))?;
+    let command = args.command.join(" ");
+    writeln!(stream, "{}", command)?;
+
+    let mut line = String::new();
+    if BufReader::new(&stream).read_line(&mut line)? == 0 {
+        anyhow::bail!("admin socket closed");
+    }
+    let response: Value =
+        serde_json::from_str(&line).with_context(|| format!("Unexpected response to {}: {}", command, line.trim()))?;
+    match (args.json, command.as_str()) {
+        (true, _) => println!("{}", response),
+        (false, "status") if response.get("error").is_none() => print_status(&response),
+        (false, _) => println!("{}", serde_json::to_string_pretty(&response)?),
+    }
+
+    match response.get("error").and_then(|err| err.as_str()) {
+        Some(err) => anyhow::bail!("{}", err),
+        None => Ok(()),
+    }
+}
+
+/// One line for the connection to the server, then one per tunnel
+fn print_status(status: &Value) {
+    let transport = &status["transport"];
+    let mut line = format!("server: {}", transport["state"].as_str().unwrap_or("unknown"));
+    if let Some(secs) = transport["last_connection_secs_ago"].as_u64() {
+        line.push_str(&format!(", last connected {}s ago", secs));
+    }
+    push_last_error(&mut line, &transport["last_error"]);
+    println!("{}", line);
+
+    for tunnel in status["tunnels"].as_array().into_iter().flatten() {
+        let side = match tunnel["reverse"].as_bool() {
+            Some(true) => "server",
+            _ => "client",
+        };
+        let mut line = format!(
+            "{}: {} on {} {}, {} connections, {} errors",
+            tunnel["id"].as_str().unwrap_or_default(),
+            match tunnel["running"].as_bool() {
+                Some(true) => "listening",
+                _ => "stopped",
+            },
+            side,
+            tunnel["listen"].as_str().unwrap_or_default(),
+            tunnel["connections"],
+            tunnel["errors"],
+        );
+        if tunnel["reverse"].as_bool() == Some(true) {
+            line.push_str(&format!(", {} reconnections", tunnel["reconnects"]));
+        }
+        push_last_error(&mut line, &tunnel["last_error"]);
+        println!("{}", line);
+    }
+}
+
+fn push_last_error(line: &mut String, last_error: &Value) {
+    if let (Some(message), Some(secs)) = (last_error["message"].as_str(), last_error["secs_ago"].as_u64()) {
+        line.push_str(&format!(", last error {}s ago: {}", secs, message));
+    }
+}
@@ -0,0 +1,39 @@
+use crate::Commands;
+use clap::Command;
+use std::sync::OnceLock;
+use wstunnel::host_aliases::{resolve_server, HostAlias};
+
+/// Alias given instead of the url of the server, once it is parsed
+static HOST_ALIAS: OnceLock<HostAlias> = OnceLock::new();
+
+/// The server of the client and of bench can also be the name of a host of the hosts file
+pub fn with_host_aliases(command: Command) -> Command {
+    let parse_server_or_alias = |arg: &str| -> anyhow::Result<_> {
+        let (server, alias) = resolve_server(arg)?;
+        if let Some(alias) = alias {
+            let _ = HOST_ALIAS.set(alias);
+        }
+        Ok(server)
+    };
+    let with_alias =
+        move |command: Command| command.mut_arg("remote_addr", |arg| arg.value_parser(parse_server_or_alias));
+    command
+        .mut_subcommand("client", with_alias)
+        .mut_subcommand("bench", with_alias)
+}
+
+/// Apply the alias given as server to the arguments
+pub fn apply_host_alias(commands: &mut Commands) -> anyhow::Result<()> {
+    let Some(alias) = HOST_ALIAS.get() else {
+        return Ok(());
+    };
+    match commands {
+        Commands::Client(args) => alias.apply(args),
+        Commands::Bench(args) => alias.apply(&mut args.client),
+        _ => Ok(()),
+    }
+}
+
+pub fn host_alias() -> Option<&'static HostAlias> {
+    HOST_ALIAS.get()
+}
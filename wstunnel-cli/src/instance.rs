@@ -0,0 +1,37 @@
+use crate::systemd;
+use std::path::PathBuf;
+use wstunnel::{InstanceState, InstanceStatus};
+
+/// Notify systemd when the client or server is ready or stopping, and dump its state on SIGUSR1
+pub fn follow_instance(instance: &InstanceState, state_dump_file: Option<PathBuf>) -> anyhow::Result<()> {
+    let mut status = instance.status();
+    tokio::spawn(async move {
+        while status.changed().await.is_ok() {
+            match *status.borrow_and_update() {
+                InstanceStatus::Starting => {}
+                InstanceStatus::Ready => systemd::notify_ready(),
+                InstanceStatus::Stopping => systemd::notify_stopping(),
+            }
+        }
+    });
+
+    #[cfg(unix)]
+    {
+        use anyhow::Context;
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigusr1 = signal(SignalKind::user_defined1()).context("Cannot listen for SIGUSR1")?;
+        let instance = instance.clone();
+        tokio::spawn(async move {
+            while sigusr1.recv().await.is_some() {
+                instance.dump(state_dump_file.as_deref());
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    if state_dump_file.is_some() {
+        anyhow::bail!("--state-dump-file is only available on unix platforms");
+    }
+
+    Ok(())
+}
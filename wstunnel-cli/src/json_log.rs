@@ -0,0 +1,132 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// One json object per line, with the fields of the event and of its spans (connection_id, peer, destination, ...) at
+/// the top level, so they can be queried by name. Fields of the inner spans and of the event win over the outer ones
+pub struct FlatJsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for FlatJsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), Value::String(timestamp));
+        line.insert("level".to_string(), Value::String(event.metadata().level().to_string()));
+        line.insert("target".to_string(), Value::String(event.metadata().target().to_string()));
+
+        if let Some(scope) = ctx.event_scope() {
+            let mut spans = vec![];
+            for span in scope.from_root() {
+                spans.push(Value::String(span.name().to_string()));
+                if let Some(fields) = span.extensions().get::<FormattedFields<JsonFields>>() {
+                    if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(fields) {
+                        line.extend(fields);
+                    }
+                }
+            }
+            line.insert("spans".to_string(), Value::Array(spans));
+        }
+
+        event.record(&mut JsonVisitor(&mut line));
+        // Events of the log crate have their real target and location in log.* fields
+        if let Some(target) = line.remove("log.target") {
+            line.insert("target".to_string(), target);
+        }
+        line.retain(|name, _| !name.starts_with("log."));
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), Value::String(format!("{:?}", value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_span_fields_are_flattened() {
+        let output = Output::default();
+        let layer = tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJsonFormat)
+            .with_writer({
+                let output = output.clone();
+                move || output.clone()
+            });
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _cnx = tracing::info_span!("cnx", connection_id = 42, peer = "10.0.0.1").entered();
+            let _tunnel = tracing::info_span!("tunnel", peer = "10.0.0.2").entered();
+            tracing::warn!(bytes = 12u64, "tunnel closed");
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["message"], "tunnel closed");
+        assert_eq!(line["connection_id"], 42);
+        assert_eq!(line["peer"], "10.0.0.2");
+        assert_eq!(line["bytes"], 12);
+        assert_eq!(line["spans"], serde_json::json!(["cnx", "tunnel"]));
+        assert!(line["timestamp"].is_string());
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{span, Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Spans of the lifecycle of a connection: accepted by the server, its http ingress, or a tunnel of the client
+const CONNECTION_SPANS: &[&str] = &["cnx", "ingress", "tunnel"];
+
+/// --log-sample-connections: only 1 in N connections logs its info and debug events, the other ones only their
+/// warnings and errors
+pub struct ConnectionSampling {
+    every: u64,
+    connections: AtomicU64,
+}
+
+/// Whether the connection of a span, and so of its children, is logged
+struct Sampled(bool);
+
+impl ConnectionSampling {
+    pub fn new(every: u64) -> Self {
+        Self {
+            every: every.max(1),
+            connections: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<S> Layer<S> for ConnectionSampling
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn event_enabled(&self, event: &Event<'_>, cx: Context<'_, S>) -> bool {
+        if *event.metadata().level() < Level::INFO {
+            return true;
+        }
+        let Some(span) = cx.event_span(event) else {
+            return true;
+        };
+        span.scope()
+            .find_map(|span| span.extensions().get::<Sampled>().map(|sampled| sampled.0))
+            .unwrap_or(true)
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, cx: Context<'_, S>) {
+        if !CONNECTION_SPANS.contains(&attrs.metadata().name()) {
+            return;
+        }
+        let Some(span) = cx.span(id) else {
+            return;
+        };
+        // The tunnels of a connection accepted by the server follow the sampling of their connection
+        if span
+            .scope()
+            .skip(1)
+            .any(|parent| parent.extensions().get::<Sampled>().is_some())
+        {
+            return;
+        }
+        let sampled = self
+            .connections
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.every);
+        span.extensions_mut().insert(Sampled(sampled));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Count the events which went through the sampling, per level
+    #[derive(Clone, Default)]
+    struct Logged(Arc<[AtomicU64; 2]>);
+
+    impl<S: Subscriber> Layer<S> for Logged {
+        fn on_event(&self, event: &Event<'_>, _cx: Context<'_, S>) {
+            let ix = usize::from(*event.metadata().level() == Level::WARN);
+            self.0[ix].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_one_in_n_connections() {
+        let logged = Logged::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(ConnectionSampling::new(3))
+            .with(logged.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..6 {
+                let _cnx = tracing::info_span!("cnx").entered();
+                tracing::info!("connection accepted");
+                // Its tunnel is not counted as another connection
+                let _tunnel = tracing::info_span!("tunnel").entered();
+                tracing::info!("tunnel opened");
+                tracing::warn!("tunnel closed with an error");
+            }
+            tracing::info!("outside of a connection");
+        });
+
+        // Connections 0 and 3, and the event outside of a connection
+        assert_eq!(logged.0[0].load(Ordering::Relaxed), 5);
+        // The warnings of every connection
+        assert_eq!(logged.0[1].load(Ordering::Relaxed), 6);
+    }
+}
@@ -1,12 +1,47 @@
-use clap::Parser;
+use crate::log_sampling::ConnectionSampling;
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use std::fs::File;
 use std::io;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 use tracing::warn;
 use tracing_subscriber::filter::Directive;
+use tracing_subscriber::fmt::format::JsonFields;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
-use wstunnel::config::{Client, Server};
+use tracing_subscriber::Layer;
+use wstunnel::config::{Bench, CertGen, CheckRestrictions, Client, SelfUpdate, Server};
+use wstunnel::profile::Profile;
 use wstunnel::LocalProtocol;
-use wstunnel::{run_client, run_server};
+use wstunnel::{
+    run_bench, run_cert_gen, run_check_restrictions, run_client_with_instance, run_self_update,
+    run_server_with_instance, InstanceState,
+};
+
+mod check;
+mod cli_docs;
+#[cfg(unix)]
+mod ctl;
+mod host_alias;
+mod instance;
+mod json_log;
+mod log_sampling;
+#[cfg(feature = "sentry")]
+mod sentry;
+#[cfg(windows)]
+mod service;
+mod syslog;
+mod systemd;
+#[cfg(feature = "opentelemetry")]
+mod telemetry;
+#[cfg(all(unix, feature = "tui"))]
+mod top;
 
 /// Use Websocket or HTTP2 protocol to tunnel {TCP,UDP} traffic
 /// wsTunnelClient <---> wsTunnelServer <---> RemoteHost
@@ -43,55 +78,419 @@ pub struct Wstunnel {
         default_value = "INFO"
     )]
     log_lvl: String,
+
+    /// Log less than --log-lvl: -q only the warnings and errors, -qq only the errors
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "verbose", verbatim_doc_comment)]
+    quiet: u8,
+
+    /// Log more than --log-lvl: -v the debug logs of wstunnel, -vv the debug logs of its libraries too, -vvv everything
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, verbatim_doc_comment)]
+    verbose: u8,
+
+    /// Only log 1 in N connections at info level, the other ones only log their warnings and errors.
+    /// For busy servers to keep useful logs without a line for each step of each connection. i.e: 100
+    #[arg(long, global = true, value_name = "N", verbatim_doc_comment)]
+    log_sample_connections: Option<u64>,
+
+    /// Format of the logs. json writes one object per line, with stable field names (connection_id, peer,
+    /// destination, bytes, ...) for log collectors like Loki or ELK
+    #[arg(
+        long,
+        global = true,
+        value_name = "FORMAT",
+        verbatim_doc_comment,
+        value_enum,
+        default_value_t = LogFormat::Text
+    )]
+    log_format: LogFormat,
+
+    /// Write the logs to this file instead of stdout, without colors. It is opened in append mode, so it can be
+    /// rotated by truncating it (i.e: copytruncate of logrotate)
+    #[arg(long, global = true, value_name = "FILE_PATH", verbatim_doc_comment)]
+    log_file: Option<PathBuf>,
+
+    /// Run in the background, detached from the terminal, for the platforms without systemd (BSDs, OpenWrt, ...).
+    /// The standard streams are closed, use it with --log-file or --log-syslog to keep the logs
+    #[cfg(unix)]
+    #[arg(long, global = true, verbatim_doc_comment)]
+    daemon: bool,
+
+    /// Write the pid of the process to this file, i.e: --pid-file /run/wstunnel.pid
+    /// Refuses to start if the pid in the file is still running, a stale file of a killed process is replaced
+    #[cfg(unix)]
+    #[arg(long, global = true, value_name = "FILE_PATH", verbatim_doc_comment)]
+    pid_file: Option<PathBuf>,
+
+    /// Also send the logs to this syslog server, as RFC 5424 messages with the severity of their level.
+    /// unix://PATH for the local daemon, udp://HOST:PORT or tcp://HOST:PORT (octet counting framing) for a remote one
+    /// i.e: --log-syslog unix:///dev/log
+    ///      --log-syslog udp://192.168.1.10:514
+    #[arg(long, global = true, value_name = "URL", verbatim_doc_comment)]
+    log_syslog: Option<syslog::SyslogTarget>,
+
+    /// Facility of the messages sent to --log-syslog
+    #[arg(
+        long,
+        global = true,
+        value_name = "FACILITY",
+        verbatim_doc_comment,
+        value_enum,
+        default_value_t = syslog::SyslogFacility::Daemon
+    )]
+    log_syslog_facility: syslog::SyslogFacility,
+
+    /// Export the traces of the connections and tunnels to this OpenTelemetry collector (OTLP over http),
+    /// to see where the latency is spent (tls handshake, upgrade, connection to the destination, ...).
+    /// Only the spans enabled by the log level are exported.
+    /// i.e: --otlp-endpoint http://localhost:4318/v1/traces
+    #[cfg(feature = "opentelemetry")]
+    #[arg(
+        long,
+        global = true,
+        value_name = "URL",
+        verbatim_doc_comment,
+        env = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"
+    )]
+    otlp_endpoint: Option<String>,
+
+    /// Report the panics and the error logs to this Sentry project, to learn about the crashes of remote instances.
+    /// The ip addresses, url credentials, bearer tokens and passwords/secrets are scrubbed from the messages sent.
+    /// i.e: --sentry-dsn https://PUBLIC_KEY@o0.ingest.sentry.io/PROJECT_ID
+    #[cfg(feature = "sentry")]
+    #[arg(long, global = true, value_name = "DSN", verbatim_doc_comment, env = "SENTRY_DSN")]
+    sentry_dsn: Option<url::Url>,
+
+    /// Environment of the events reported to --sentry-dsn, i.e: production, staging
+    #[cfg(feature = "sentry")]
+    #[arg(
+        long,
+        global = true,
+        value_name = "NAME",
+        verbatim_doc_comment,
+        env = "SENTRY_ENVIRONMENT"
+    )]
+    sentry_environment: Option<String>,
+
+    /// Address to serve the instrumentation of the tasks of the runtime on, for tokio-console
+    #[cfg(feature = "tokio-console")]
+    #[arg(
+        long,
+        global = true,
+        value_name = "ADDR",
+        verbatim_doc_comment,
+        env = "TOKIO_CONSOLE_BIND",
+        default_value = "127.0.0.1:6669"
+    )]
+    tokio_console_bind: std::net::SocketAddr,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
 }
 
 #[derive(clap::Subcommand, Debug)]
 pub enum Commands {
     Client(Box<Client>),
     Server(Box<Server>),
+    CheckRestrictions(Box<CheckRestrictions>),
+    /// Measure the round trip time, jitter and upload/download throughput through a tunnel to a server started
+    /// with --bench-endpoint. The results are printed on stdout as json
+    Bench(Box<Bench>),
+    /// Live view of the tunnels, throughput, failures and reconnects of a client or server, through its --admin-socket
+    #[cfg(all(unix, feature = "tui"))]
+    Top(top::Top),
+    /// Manage a running client or server through its --admin-socket: list, add and remove the tunnels of a client,
+    /// close a connection, reload the --config file, get the stats
+    #[cfg(unix)]
+    Ctl(ctl::Ctl),
+    /// Install, uninstall or run the client as a windows service, started at boot and logging to the event log
+    #[cfg(windows)]
+    Service(service::Service),
+    /// Generate the certificates of a server and of its clients for mTLS, signed by a private CA
+    #[command(subcommand)]
+    Cert(CertCommand),
+    /// Replace this binary by the latest release of the --feed, once the ed25519 signature of the release is verified
+    /// with --public-key and the new binary runs. The running client or server keeps its binary until it restarts
+    Update(Box<SelfUpdate>),
+    /// Print the completion script of a shell, generated from the arguments of this binary
+    Completions(cli_docs::Completions),
+    /// Print the man page, generated from the arguments of this binary
+    Man(cli_docs::Man),
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum CertCommand {
+    /// Generate a CA (ca.pem, ca.key), a server certificate valid for the --san names, and a client certificate per
+    /// --client name. i.e: wstunnel cert gen --out-dir certs --san wstunnel.example.com --client alice --client bob
+    Gen(Box<CertGen>),
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Wstunnel::parse();
+fn mk_env_filter(log_lvl: &str) -> anyhow::Result<EnvFilter> {
+    let mut env_filter = EnvFilter::builder().parse(log_lvl)?;
+    if !(log_lvl.contains("h2::") || log_lvl.contains("h2=")) {
+        env_filter = env_filter.add_directive(Directive::from_str("h2::codec=off")?);
+    }
+
+    Ok(env_filter)
+}
 
-    // Setup logging
-    let mut env_filter = EnvFilter::builder().parse(&args.log_lvl).expect("Invalid log level");
-    if !(args.log_lvl.contains("h2::") || args.log_lvl.contains("h2=")) {
-        env_filter = env_filter.add_directive(Directive::from_str("h2::codec=off").expect("Invalid log directive"));
+/// Log filter of the -q/-v tiers, overriding --log-lvl
+fn verbosity_filter(quiet: u8, verbose: u8) -> Option<&'static str> {
+    match (quiet, verbose) {
+        (0, 0) => None,
+        (1, _) => Some("WARN"),
+        (_, 0) => Some("ERROR"),
+        (_, 1) => Some("INFO,wstunnel=DEBUG"),
+        (_, 2) => Some("DEBUG"),
+        _ => Some("TRACE"),
     }
-    let logger = tracing_subscriber::fmt()
-        .with_ansi(args.no_color.is_none())
-        .with_env_filter(env_filter);
-
-    // stdio tunnel capture stdio, so need to log into stderr
-    if let Commands::Client(args) = &args.commands {
-        if args
-            .local_to_remote
-            .iter()
-            .filter(|x| matches!(x.local_protocol, LocalProtocol::Stdio { .. }))
-            .count()
-            > 0
-        {
-            logger.with_writer(io::stderr).init();
-        } else {
-            logger.init()
+}
+
+/// --profile of the client or bench subcommand, with the name of the subcommand
+fn profile(matches: &ArgMatches) -> Option<(String, Profile)> {
+    let (name, matches) = matches.subcommand()?;
+    let profile = matches.try_get_one::<Profile>("profile").ok().flatten()?;
+    Some((name.to_string(), *profile))
+}
+
+fn main() -> anyhow::Result<()> {
+    let command = || host_alias::with_host_aliases(Wstunnel::command());
+    let mut matches = command().get_matches();
+    // The options of a profile are defaults, so they are set before parsing again, for the arguments to override them
+    if let Some((name, profile)) = profile(&matches) {
+        matches = command()
+            .mut_subcommand(name, |command| profile.apply_defaults(command))
+            .get_matches();
+    }
+    let mut args = Wstunnel::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    host_alias::apply_host_alias(&mut args.commands)?;
+
+    // The dashboard owns the terminal, so it runs without logging
+    match args.commands {
+        Commands::Completions(args) => return cli_docs::run_completions(args),
+        Commands::Man(args) => return cli_docs::run_man(args),
+        Commands::Cert(CertCommand::Gen(args)) => return run_cert_gen(*args),
+        Commands::Update(args) => {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            return runtime.block_on(run_self_update(*args));
         }
-    } else {
-        logger.init();
+        _ => {}
+    }
+    #[cfg(all(unix, feature = "tui"))]
+    if let Commands::Top(args) = args.commands {
+        return top::run_top(args);
+    }
+    #[cfg(unix)]
+    if let Commands::Ctl(args) = args.commands {
+        return ctl::run_ctl(args);
+    }
+    // The service sets up its own logging, to the event log
+    #[cfg(windows)]
+    if let Commands::Service(args) = args.commands {
+        return service::run_service_command(args);
+    }
+
+    // Before daemonizing and logging, only the configuration is printed
+    let check = match &args.commands {
+        Commands::Client(args) => args.check,
+        Commands::Server(args) => args.check,
+        Commands::Bench(args) => args.client.check,
+        _ => None,
     };
+    if let Some(format) = check {
+        return check::run_check(args.commands, &matches, format);
+    }
+
+    let has_stdio_tunnel = matches!(&args.commands, Commands::Client(args) if args.stdio.is_some() || args
+        .local_to_remote
+        .iter()
+        .any(|x| matches!(x.local_protocol, LocalProtocol::Stdio { .. })));
+    // Before anything starts a thread, the daemon continues in a forked process
+    #[cfg(unix)]
+    if args.daemon {
+        if has_stdio_tunnel {
+            anyhow::bail!("--daemon cannot be used with a stdio tunnel, its standard streams are closed");
+        }
+        if !wstunnel::daemon::daemonize()? {
+            std::process::exit(0);
+        }
+    }
+    #[cfg(unix)]
+    let _pid_file = args
+        .pid_file
+        .as_deref()
+        .map(wstunnel::daemon::PidFile::create)
+        .transpose()?;
+
+    // Setup logging, the filter can be changed at runtime with the log-level admin command
+    // The logs of --stdio end up in the terminal of ssh, only the warnings are shown unless asked otherwise
+    let quiet_stdio = matches!(&args.commands, Commands::Client(args) if args.stdio.is_some())
+        && matches.value_source("log_lvl") == Some(ValueSource::DefaultValue);
+    let log_lvl = verbosity_filter(args.quiet, args.verbose)
+        .or(quiet_stdio.then_some("WARN"))
+        .map_or(args.log_lvl, str::to_string);
+    let (env_filter, env_filter_handle) = reload::Layer::new(mk_env_filter(&log_lvl).expect("Invalid log level"));
+    wstunnel::log_filter::set_log_filter_reloader(log_lvl, move |filter| {
+        env_filter_handle.reload(mk_env_filter(filter)?)?;
+        Ok(())
+    });
+    // stdio tunnel capture stdio, so need to log into stderr. Same for the results of bench
+    let log_to_stderr = matches!(&args.commands, Commands::Bench(_)) || has_stdio_tunnel;
+    let log_file = match &args.log_file {
+        Some(path) => Some(Arc::new(
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Cannot open log file {}", path.display()))?,
+        )),
+        None => None,
+    };
+    let writer = || match &log_file {
+        Some(file) => BoxMakeWriter::new(file.clone()),
+        None if log_to_stderr => BoxMakeWriter::new(io::stderr),
+        None => BoxMakeWriter::new(io::stdout),
+    };
+    let (text_logger, json_logger) = match args.log_format {
+        LogFormat::Text => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(args.no_color.is_none() && log_file.is_none())
+                    .with_writer(writer()),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .fmt_fields(JsonFields::new())
+                    .event_format(json_log::FlatJsonFormat)
+                    .with_writer(writer()),
+            ),
+        ),
+    };
+
+    // Without colors and timestamps, the syslog header already has the time and the severity
+    let syslog_logger = match &args.log_syslog {
+        Some(target) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .without_time()
+                .with_level(false)
+                .with_writer(syslog::SyslogMakeWriter::new(target, args.log_syslog_facility)?),
+        ),
+        None => None,
+    };
+
+    #[cfg(feature = "opentelemetry")]
+    let (otlp_layer, tracer_provider) = match &args.otlp_endpoint {
+        Some(endpoint) => {
+            let (layer, provider) = telemetry::otlp_layer(endpoint)?;
+            (Some(layer), Some(provider))
+        }
+        None => (None, None),
+    };
+    #[cfg(not(feature = "opentelemetry"))]
+    let otlp_layer = None::<tracing_subscriber::layer::Identity>;
+
+    #[cfg(feature = "sentry")]
+    let sentry_layer = match &args.sentry_dsn {
+        Some(dsn) => {
+            let reporter =
+                std::sync::Arc::new(wstunnel::sentry::SentryReporter::new(dsn, args.sentry_environment.clone())?);
+            reporter.install_panic_hook();
+            Some(sentry::SentryLayer::new(reporter))
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "sentry"))]
+    let sentry_layer = None::<tracing_subscriber::layer::Identity>;
+
+    // The spans and events of the runtime must reach tokio-console whatever the log level, so the filter only
+    // applies to the logs
+    #[cfg(feature = "tokio-console")]
+    let console_layer = Some(
+        console_subscriber::ConsoleLayer::builder()
+            .with_default_env()
+            .server_addr(args.tokio_console_bind)
+            .spawn(),
+    );
+    #[cfg(not(feature = "tokio-console"))]
+    let console_layer = None::<tracing_subscriber::layer::Identity>;
+
+    tracing_subscriber::registry()
+        .with(console_layer)
+        .with(args.log_sample_connections.map(ConnectionSampling::new))
+        .with(
+            Layer::and_then(text_logger, json_logger)
+                .and_then(syslog_logger)
+                .and_then(otlp_layer)
+                .and_then(sentry_layer)
+                .with_filter(env_filter),
+        )
+        .init();
+    #[cfg(all(feature = "tokio-console", not(tokio_unstable)))]
+    warn!("Built without RUSTFLAGS=\"--cfg tokio_unstable\", tokio-console will not see the tasks");
     if let Err(err) = fdlimit::raise_fd_limit() {
         warn!("Failed to set soft filelimit to hard file limit: {}", err)
     }
 
-    match args.commands {
-        Commands::Client(args) => {
-            run_client(*args).await?;
+    // Landlock only restricts the threads created after it, so it must be applied before the runtime starts its own
+    if let Commands::CheckRestrictions(args) = args.commands {
+        return run_check_restrictions(*args);
+    }
+
+    if let Commands::Server(args) = &args.commands {
+        if args.sandbox && !args.sandbox_without_landlock {
+            wstunnel::sandbox::restrict_server_filesystem(args)?;
         }
-        Commands::Server(args) => {
-            run_server(*args).await?;
+    }
+
+    let ret = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async move {
+            match args.commands {
+                Commands::Client(args) => {
+                    let instance = InstanceState::default();
+                    instance::follow_instance(&instance, args.state_dump_file.clone())?;
+                    // Before the runtime is dropped, which waits for the blocking read of the standard input
+                    if let Some(exit_code) = run_client_with_instance(*args, instance).await? {
+                        std::process::exit(exit_code);
+                    }
+                }
+                Commands::Server(args) => {
+                    let instance = InstanceState::default();
+                    instance::follow_instance(&instance, args.state_dump_file.clone())?;
+                    run_server_with_instance(*args, instance).await?;
+                }
+                Commands::Bench(args) => {
+                    run_bench(*args).await?;
+                }
+                Commands::CheckRestrictions(_) => unreachable!("restrictions are checked without the runtime"),
+                Commands::Cert(_) => unreachable!("the certificates are generated without the runtime"),
+                Commands::Update(_) => unreachable!("the binary is updated before the runtime starts"),
+                Commands::Completions(_) | Commands::Man(_) => unreachable!("the docs are printed without the runtime"),
+                #[cfg(all(unix, feature = "tui"))]
+                Commands::Top(_) => unreachable!("the dashboard runs without the runtime"),
+                #[cfg(unix)]
+                Commands::Ctl(_) => unreachable!("the admin commands are sent without the runtime"),
+                #[cfg(windows)]
+                Commands::Service(_) => unreachable!("the service runs its own runtime"),
+            }
+
+            Ok(())
+        });
+
+    #[cfg(feature = "opentelemetry")]
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(err) = tracer_provider.shutdown() {
+            warn!("Failed to export the last traces: {}", err);
         }
     }
 
-    Ok(())
+    ret
 }
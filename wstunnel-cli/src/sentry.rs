@@ -0,0 +1,51 @@
+use std::fmt::{Debug, Write};
+use std::sync::Arc;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+use wstunnel::sentry::{SentryReporter, Severity};
+
+/// Report the error events to Sentry, with their fields after the message
+pub struct SentryLayer {
+    reporter: Arc<SentryReporter>,
+}
+
+impl SentryLayer {
+    pub fn new(reporter: Arc<SentryReporter>) -> Self {
+        Self { reporter }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SentryLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let message = match visitor.fields.is_empty() {
+            true => visitor.message,
+            false => format!("{}{}", visitor.message, visitor.fields),
+        };
+        self.reporter
+            .capture(Severity::Error, &message, event.metadata().target());
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
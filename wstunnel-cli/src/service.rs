@@ -0,0 +1,388 @@
+use crate::{mk_env_filter, Commands, Wstunnel};
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::os::windows::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+    ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+    EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+};
+
+const SERVICE_NAME: &str = "wstunnel";
+/// Arguments of the client run by the service, one per line, in this file next to the binary
+const ARGUMENTS_FILE: &str = "wstunnel-service.args";
+/// Or in this REG_MULTI_SZ value, under HKEY_LOCAL_MACHINE
+const ARGUMENTS_REGISTRY_KEY: &str = r"SOFTWARE\wstunnel";
+const ARGUMENTS_REGISTRY_VALUE: &str = "Arguments";
+
+/// Run the client as a windows service, started at boot and logging to the event log
+#[derive(clap::Args, Debug)]
+pub struct Service {
+    #[command(subcommand)]
+    command: ServiceCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ServiceCommand {
+    /// Register the service, started at boot. The arguments of the client are read when the service starts, from
+    /// the file wstunnel-service.args next to the binary (one argument per line), else from the REG_MULTI_SZ value
+    /// HKEY_LOCAL_MACHINE\SOFTWARE\wstunnel\Arguments
+    /// i.e: wstunnel service install -- client wss://wstunnel.example.com -L socks5://127.0.0.1:1080
+    Install {
+        /// Arguments of the client, written to wstunnel-service.args. Without them, the file or registry is left as is
+        #[arg(last = true, value_name = "ARGS", verbatim_doc_comment)]
+        client_args: Vec<OsString>,
+    },
+    /// Stop the service and remove it
+    Uninstall,
+    /// Entrypoint of the service, only for the service manager
+    Run,
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+pub fn run_service_command(args: Service) -> anyhow::Result<()> {
+    match args.command {
+        ServiceCommand::Install { client_args } => install(client_args),
+        ServiceCommand::Uninstall => uninstall(),
+        ServiceCommand::Run => service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .with_context(|| "Cannot start the service, 'service run' is only for the service manager"),
+    }
+}
+
+fn install(client_args: Vec<OsString>) -> anyhow::Result<()> {
+    if !client_args.is_empty() {
+        parse_client_args(client_args.clone())?;
+        let path = arguments_file()?;
+        let content: Vec<String> = client_args
+            .iter()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        std::fs::write(&path, content.join("\r\n"))
+            .with_context(|| format!("Cannot write the arguments of the service to {}", path.display()))?;
+        println!("Arguments of the client written to {}", path.display());
+    }
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+    let service_info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from("wstunnel client"),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: vec![OsString::from("service"), OsString::from("run")],
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&service_info, ServiceAccess::CHANGE_CONFIG)
+        .with_context(|| "Cannot install the service, it needs an elevated prompt")?;
+    service.set_description("Tunnels over websocket or http2 to a wstunnel server")?;
+    println!(
+        "Service {} installed, started at boot. Start it now with: sc start {}",
+        SERVICE_NAME, SERVICE_NAME
+    );
+
+    Ok(())
+}
+
+fn uninstall() -> anyhow::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .with_context(|| format!("Cannot open service {}", SERVICE_NAME))?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    service.delete()?;
+    println!("Service {} uninstalled", SERVICE_NAME);
+
+    Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    // Reported to the event log directly, it may have failed before the logger is set up
+    if let Err(err) = run_service() {
+        let _ = EventSource::register().map(|source| source.report(Level::ERROR, &format!("{:?}", err)));
+    }
+}
+
+fn run_service() -> anyhow::Result<()> {
+    let args = parse_client_args(client_arguments()?)?;
+    let (env_filter, env_filter_handle) = tracing_subscriber::reload::Layer::new(mk_env_filter(&args.log_lvl)?);
+    wstunnel::log_filter::set_log_filter_reloader(args.log_lvl.clone(), move |filter| {
+        env_filter_handle.reload(mk_env_filter(filter)?)?;
+        Ok(())
+    });
+    // The event log has its own time and severity
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .without_time()
+                .with_level(false)
+                .with_writer(EventLogMakeWriter(Arc::new(EventSource::register()?)))
+                .with_filter(env_filter),
+        )
+        .init();
+    let Commands::Client(client_args) = args.commands else {
+        unreachable!("the arguments are checked to be the ones of a client");
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    let stop = Arc::new(tokio::sync::Notify::new());
+    let status = {
+        let stop = stop.clone();
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                stop.notify_one();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?
+    };
+    set_status(&status, ServiceState::Running, ServiceExitCode::Win32(0))?;
+    info!("Service {} is running", SERVICE_NAME);
+
+    let ret = runtime.block_on(async move {
+        tokio::select! {
+            ret = wstunnel::run_client(*client_args) => ret.map(|_| ()),
+            _ = stop.notified() => {
+                info!("Service {} is stopping", SERVICE_NAME);
+                Ok(())
+            }
+        }
+    });
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    if let Err(err) = &ret {
+        error!("{:?}", err);
+    }
+    let exit_code = match &ret {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    set_status(&status, ServiceState::Stopped, exit_code)?;
+
+    ret
+}
+
+fn set_status(status: &ServiceStatusHandle, state: ServiceState, exit_code: ServiceExitCode) -> anyhow::Result<()> {
+    status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })?;
+
+    Ok(())
+}
+
+/// Only the client can run as a service
+fn parse_client_args(client_args: Vec<OsString>) -> anyhow::Result<Wstunnel> {
+    let args = Wstunnel::try_parse_from(std::iter::once(OsString::from("wstunnel")).chain(client_args))?;
+    match args.commands {
+        Commands::Client(_) => Ok(args),
+        _ => Err(anyhow!(
+            "The service can only run a client, its arguments must start with 'client'"
+        )),
+    }
+}
+
+/// From the file next to the binary, else from the registry
+fn client_arguments() -> anyhow::Result<Vec<OsString>> {
+    let path = arguments_file()?;
+    if path.exists() {
+        let content = std::fs::read_to_string(&path).with_context(|| format!("Cannot read {}", path.display()))?;
+        return Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(OsString::from)
+            .collect());
+    }
+
+    let key = winreg::RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE)
+        .open_subkey(ARGUMENTS_REGISTRY_KEY)
+        .with_context(|| format!("No {} file nor registry key HKLM\\{}", path.display(), ARGUMENTS_REGISTRY_KEY))?;
+    let args: Vec<String> = key
+        .get_value(ARGUMENTS_REGISTRY_VALUE)
+        .with_context(|| format!("Cannot read HKLM\\{}\\{}", ARGUMENTS_REGISTRY_KEY, ARGUMENTS_REGISTRY_VALUE))?;
+
+    Ok(args.into_iter().map(OsString::from).collect())
+}
+
+fn arguments_file() -> anyhow::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let dir = exe
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid path of the binary {}", exe.display()))?;
+    Ok(dir.join(ARGUMENTS_FILE))
+}
+
+/// Source of the events written to the Application log. Without a message file registered for it, the event viewer
+/// shows the messages with a note that their description is missing
+struct EventSource(HANDLE);
+
+// The handle is only used by the thread-safe ReportEventW
+unsafe impl Send for EventSource {}
+unsafe impl Sync for EventSource {}
+
+impl EventSource {
+    fn register() -> anyhow::Result<Self> {
+        let name = wide(OsStr::new(SERVICE_NAME));
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+        if handle.is_null() {
+            return Err(anyhow!("Cannot register event source: {}", io::Error::last_os_error()));
+        }
+        Ok(Self(handle))
+    }
+
+    fn report(&self, level: Level, message: &str) {
+        let event_type: REPORT_EVENT_TYPE = match level {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = wide(OsStr::new(message));
+        let strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(
+                self.0,
+                event_type,
+                0,
+                0,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            );
+        }
+    }
+}
+
+impl Drop for EventSource {
+    fn drop(&mut self) {
+        unsafe { DeregisterEventSource(self.0) };
+    }
+}
+
+fn wide(value: &OsStr) -> Vec<u16> {
+    value.encode_wide().chain(std::iter::once(0)).collect()
+}
+
+/// Write each log event to the event log, with the type of its level
+#[derive(Clone)]
+struct EventLogMakeWriter(Arc<EventSource>);
+
+impl<'a> MakeWriter<'a> for EventLogMakeWriter {
+    type Writer = EventLogMessage;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.make_writer_for_level(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.make_writer_for_level(*meta.level())
+    }
+}
+
+impl EventLogMakeWriter {
+    fn make_writer_for_level(&self, level: Level) -> EventLogMessage {
+        EventLogMessage {
+            message: Vec::new(),
+            level,
+            source: self.0.clone(),
+        }
+    }
+}
+
+/// One event, reported once it is fully formatted
+struct EventLogMessage {
+    message: Vec<u8>,
+    level: Level,
+    source: Arc<EventSource>,
+}
+
+impl Write for EventLogMessage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.message.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventLogMessage {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.message);
+        self.source.report(self.level, message.trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_args(args: &str) -> Vec<OsString> {
+        args.split_whitespace().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_parse_client_args() {
+        let args = parse_client_args(os_args("client wss://wstunnel.example.com -L socks5://127.0.0.1:1080")).unwrap();
+        assert!(matches!(args.commands, Commands::Client(_)));
+
+        assert!(parse_client_args(os_args("server wss://0.0.0.0:443")).is_err());
+        assert!(parse_client_args(os_args("client")).is_err());
+    }
+
+    #[test]
+    fn test_install_client_args() {
+        let args = Wstunnel::try_parse_from(os_args(
+            "wstunnel service install -- client wss://wstunnel.example.com -L socks5://127.0.0.1:1080",
+        ))
+        .unwrap();
+        let Commands::Service(Service {
+            command: ServiceCommand::Install { client_args },
+        }) = args.commands
+        else {
+            panic!("expected the install subcommand");
+        };
+        assert_eq!(
+            client_args,
+            os_args("client wss://wstunnel.example.com -L socks5://127.0.0.1:1080")
+        );
+    }
+}
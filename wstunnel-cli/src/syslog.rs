@@ -0,0 +1,284 @@
+use anyhow::{anyhow, Context};
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Where to send the syslog messages, parsed from the value of --log-syslog
+#[derive(Clone, Debug)]
+pub enum SyslogTarget {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Udp(String),
+    Tcp(String),
+}
+
+impl FromStr for SyslogTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            #[cfg(unix)]
+            Some(("unix", path)) => Ok(Self::Unix(PathBuf::from(path))),
+            Some(("udp", addr)) => Ok(Self::Udp(addr.to_string())),
+            Some(("tcp", addr)) => Ok(Self::Tcp(addr.to_string())),
+            _ => Err(anyhow!(
+                "Invalid syslog target {}, expected unix://PATH, udp://HOST:PORT or tcp://HOST:PORT",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyslogFacility {
+    Kern,
+    User,
+    Mail,
+    Daemon,
+    Auth,
+    Syslog,
+    Lpr,
+    News,
+    Uucp,
+    Cron,
+    Authpriv,
+    Ftp,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    const fn code(self) -> u8 {
+        match self {
+            Self::Kern => 0,
+            Self::User => 1,
+            Self::Mail => 2,
+            Self::Daemon => 3,
+            Self::Auth => 4,
+            Self::Syslog => 5,
+            Self::Lpr => 6,
+            Self::News => 7,
+            Self::Uucp => 8,
+            Self::Cron => 9,
+            Self::Authpriv => 10,
+            Self::Ftp => 11,
+            Self::Local0 => 16,
+            Self::Local1 => 17,
+            Self::Local2 => 18,
+            Self::Local3 => 19,
+            Self::Local4 => 20,
+            Self::Local5 => 21,
+            Self::Local6 => 22,
+            Self::Local7 => 23,
+        }
+    }
+}
+
+enum Transport {
+    #[cfg(unix)]
+    Unix(UnixDatagram, PathBuf),
+    Udp(UdpSocket),
+    /// Messages are framed with their length (octet counting of RFC 6587). Connected again after an error
+    Tcp(Option<TcpStream>, String),
+}
+
+impl Transport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(socket, path) => socket.send_to(message, &*path).map(|_| ()),
+            Self::Udp(socket) => socket.send(message).map(|_| ()),
+            Self::Tcp(stream, addr) => {
+                let mut cnx = match stream.take() {
+                    Some(cnx) => cnx,
+                    None => TcpStream::connect(&*addr)?,
+                };
+                write!(cnx, "{} ", message.len())?;
+                cnx.write_all(message)?;
+                *stream = Some(cnx);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Send each log event as a RFC 5424 message, with the severity of its level
+#[derive(Clone)]
+pub struct SyslogMakeWriter {
+    transport: Arc<Mutex<Transport>>,
+    facility: SyslogFacility,
+    hostname: Arc<str>,
+}
+
+impl SyslogMakeWriter {
+    pub fn new(target: &SyslogTarget, facility: SyslogFacility) -> anyhow::Result<Self> {
+        let transport = match target {
+            #[cfg(unix)]
+            SyslogTarget::Unix(path) => Transport::Unix(UnixDatagram::unbound()?, path.clone()),
+            SyslogTarget::Udp(addr) => {
+                let server = addr
+                    .to_socket_addrs()
+                    .with_context(|| format!("Cannot resolve syslog server {}", addr))?
+                    .next()
+                    .ok_or_else(|| anyhow!("Cannot resolve syslog server {}", addr))?;
+                let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+                socket.connect(server)?;
+                Transport::Udp(socket)
+            }
+            SyslogTarget::Tcp(addr) => Transport::Tcp(None, addr.clone()),
+        };
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+            .map(|hostname| hostname.trim().to_string())
+            .unwrap_or_default();
+
+        Ok(Self {
+            transport: Arc::new(Mutex::new(transport)),
+            facility,
+            hostname: if hostname.is_empty() {
+                "-".into()
+            } else {
+                hostname.into()
+            },
+        })
+    }
+
+    fn message(&self, level: Level) -> SyslogMessage {
+        let severity = match level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let mut timestamp = String::new();
+        let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+
+        // <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+        let header = format!(
+            "<{}>1 {} {} wstunnel {} - - ",
+            self.facility.code() * 8 + severity,
+            timestamp,
+            self.hostname,
+            std::process::id()
+        );
+        SyslogMessage {
+            message: header.into_bytes(),
+            transport: self.transport.clone(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogMakeWriter {
+    type Writer = SyslogMessage;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.message(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.message(*meta.level())
+    }
+}
+
+/// One event, sent once it is fully formatted
+pub struct SyslogMessage {
+    message: Vec<u8>,
+    transport: Arc<Mutex<Transport>>,
+}
+
+impl Write for SyslogMessage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.message.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogMessage {
+    fn drop(&mut self) {
+        while self.message.last() == Some(&b'\n') {
+            self.message.pop();
+        }
+        let Ok(mut transport) = self.transport.lock() else {
+            return;
+        };
+        // Nowhere to report it, logging the error would come back here
+        let _ = transport.send(&self.message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_udp_message() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = SyslogTarget::from_str(&format!("udp://{}", server.local_addr().unwrap())).unwrap();
+        let writer = SyslogMakeWriter::new(&target, SyslogFacility::Daemon).unwrap();
+
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(writer)
+            .with_ansi(false)
+            .without_time()
+            .with_target(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || tracing::warn!("tunnel closed"));
+
+        let mut buf = [0; 1024];
+        let len = server.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        // daemon (3) * 8 + warning (4)
+        assert!(message.starts_with("<28>1 "), "{message}");
+        assert!(message.contains(&format!(" wstunnel {} - - ", std::process::id())), "{message}");
+        assert!(message.ends_with("WARN tunnel closed"), "{message}");
+    }
+
+    #[test]
+    fn test_tcp_octet_counting() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = SyslogTarget::from_str(&format!("tcp://{}", server.local_addr().unwrap())).unwrap();
+        let writer = SyslogMakeWriter::new(&target, SyslogFacility::Local0).unwrap();
+
+        for msg in ["first", "second"] {
+            let mut message = writer.make_writer();
+            message.write_all(msg.as_bytes()).unwrap();
+        }
+        drop(writer);
+
+        let (mut cnx, _) = server.accept().unwrap();
+        let mut received = String::new();
+        cnx.read_to_string(&mut received).unwrap();
+        let (len, rest) = received.split_once(' ').unwrap();
+        let (first, second) = rest.split_at(len.parse().unwrap());
+        // local0 (16) * 8 + info (6)
+        assert!(first.starts_with("<134>1 ") && first.ends_with(" - - first"), "{first}");
+        assert!(second.ends_with(" - - second"), "{second}");
+    }
+
+    #[test]
+    fn test_invalid_target() {
+        assert!(SyslogTarget::from_str("http://localhost:514").is_err());
+        assert!(SyslogTarget::from_str("localhost:514").is_err());
+    }
+}
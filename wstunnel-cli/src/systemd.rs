@@ -0,0 +1,98 @@
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Tell systemd that the service is ready (Type=notify), and start to answer its watchdog (WatchdogSec=).
+/// Nothing is done when not started by systemd
+pub fn notify_ready() {
+    if !sd_notify("READY=1") {
+        return;
+    }
+    info!("Notified systemd that the service is ready");
+
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    info!("Answering the systemd watchdog every {:?}", interval);
+    // Pinged from the runtime, systemd restarts the service if it does not run its tasks anymore
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            sd_notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// The service is shutting down, so systemd does not report it as failed while it drains
+pub fn notify_stopping() {
+    sd_notify("STOPPING=1");
+}
+
+/// Half of the timeout asked by systemd, if the watchdog is enabled for this process
+fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?;
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    parse_watchdog_interval(&usec, pid.as_deref(), std::process::id())
+}
+
+fn parse_watchdog_interval(usec: &str, watchdog_pid: Option<&str>, pid: u32) -> Option<Duration> {
+    // Set when the watchdog is for another process, i.e: the parent of a forked process
+    if watchdog_pid.is_some_and(|watchdog_pid| watchdog_pid.parse() != Ok(pid)) {
+        return None;
+    }
+    let usec: u64 = usec.parse().ok()?;
+
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Send the state to the socket of systemd, false if there is none
+#[cfg(unix)]
+fn sd_notify(state: &str) -> bool {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+    let ret = UnixDatagram::unbound().and_then(|socket| {
+        // Starting with @, the socket is in the abstract namespace
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(err) = ret {
+        warn!("Cannot notify systemd of {}: {}", state, err);
+    }
+
+    true
+}
+
+#[cfg(not(unix))]
+fn sd_notify(_state: &str) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_watchdog_interval() {
+        assert_eq!(parse_watchdog_interval("30000000", None, 42), Some(Duration::from_secs(15)));
+        assert_eq!(
+            parse_watchdog_interval("30000000", Some("42"), 42),
+            Some(Duration::from_secs(15))
+        );
+        // For another process
+        assert_eq!(parse_watchdog_interval("30000000", Some("1"), 42), None);
+        assert_eq!(parse_watchdog_interval("0", None, 42), None);
+        assert_eq!(parse_watchdog_interval("never", None, 42), None);
+    }
+}
@@ -0,0 +1,84 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Export the spans (connection to the server, tls handshake, upgrade, connection to the destination, tunnels) to an
+/// OTLP collector over http. The provider must be shut down before exiting, to flush the last spans
+pub fn otlp_layer<S>(endpoint: &str) -> anyhow::Result<(impl Layer<S>, SdkTracerProvider)>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder().with_http().with_endpoint(endpoint).build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("wstunnel").build())
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("wstunnel"));
+
+    Ok((layer, provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Accept one OTLP export request and return its body
+    fn fake_collector() -> (String, mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let mut stream = stream;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            tx.send(body).unwrap();
+        });
+
+        (endpoint, rx)
+    }
+
+    #[test]
+    fn test_spans_are_exported() {
+        let (endpoint, bodies) = fake_collector();
+        let (layer, provider) = otlp_layer(&endpoint).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _cnx = tracing::info_span!("cnx_server").entered();
+            let _tls = tracing::info_span!("tls_handshake").entered();
+        });
+        provider.shutdown().unwrap();
+
+        let body = bodies.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"cnx_server"));
+        assert!(contains(b"tls_handshake"));
+        assert!(contains(b"wstunnel"));
+    }
+}
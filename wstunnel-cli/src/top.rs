@@ -0,0 +1,288 @@
+use anyhow::Context;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::widgets::{Block, Paragraph, Row, Sparkline, Table};
+use ratatui::{DefaultTerminal, Frame};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Number of refreshes kept in the throughput history
+const HISTORY_LEN: usize = 300;
+
+/// Live view of the tunnels of a client or server, refreshed from its --admin-socket
+#[derive(clap::Args, Debug)]
+pub struct Top {
+    /// Path of the --admin-socket of the wstunnel client or server to watch
+    #[arg(value_name = "FILE_PATH", verbatim_doc_comment)]
+    admin_socket: PathBuf,
+
+    /// Seconds between two refreshes of the view
+    #[arg(long, value_name = "SECONDS", default_value = "1", verbatim_doc_comment)]
+    interval: u64,
+}
+
+/// As returned by the 'tunnels' admin command
+#[derive(Deserialize)]
+struct Tunnel {
+    id: String,
+    source: Option<String>,
+    destination: String,
+    protocol: String,
+    identity: Option<String>,
+    age_secs: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    bytes_per_sec: u64,
+}
+
+/// As returned by the 'stats' admin command
+#[derive(Deserialize, Default)]
+struct Counters {
+    active_tunnels: u64,
+    opened_tunnels: u64,
+    failed_tunnels: u64,
+    reconnects: u64,
+}
+
+struct AdminClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl AdminClient {
+    fn connect(path: &Path) -> anyhow::Result<Self> {
+        let writer = UnixStream::connect(path).with_context(|| format!("Cannot connect to {}", path.display()))?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    fn request<T: DeserializeOwned>(&mut self, command: &str) -> anyhow::Result<T> {
+        writeln!(self.writer, "{}", command)?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            anyhow::bail!("admin socket closed");
+        }
+        serde_json::from_str(&line).with_context(|| format!("Unexpected response to {}: {}", command, line.trim()))
+    }
+}
+
+#[derive(Default)]
+struct Dashboard {
+    tunnels: Vec<Tunnel>,
+    counters: Counters,
+    /// Bytes transferred per second, oldest first
+    throughput: VecDeque<u64>,
+    /// Bytes transferred by each tunnel at the previous refresh
+    previous_bytes: HashMap<String, u64>,
+    /// Failed tunnels and reconnects at the previous refresh, to highlight new ones
+    previous_failed: u64,
+    previous_reconnects: u64,
+    error: Option<String>,
+}
+
+impl Dashboard {
+    fn refresh(&mut self, client: &mut AdminClient, elapsed: Duration) -> anyhow::Result<()> {
+        let tunnels: Vec<Tunnel> = client.request("tunnels")?;
+        let counters: Counters = client.request("stats")?;
+
+        // Tunnels closed since the previous refresh are not counted, their last bytes are unknown
+        let bytes: HashMap<String, u64> = tunnels
+            .iter()
+            .map(|tunnel| (tunnel.id.clone(), tunnel.bytes_sent + tunnel.bytes_received))
+            .collect();
+        let transferred: u64 = bytes
+            .iter()
+            .map(|(id, bytes)| bytes.saturating_sub(self.previous_bytes.get(id).copied().unwrap_or(0)))
+            .sum();
+        if self.throughput.len() == HISTORY_LEN {
+            self.throughput.pop_front();
+        }
+        self.throughput
+            .push_back((transferred as f64 / elapsed.as_secs_f64().max(0.001)) as u64);
+
+        self.previous_bytes = bytes;
+        self.previous_failed = self.counters.failed_tunnels;
+        self.previous_reconnects = self.counters.reconnects;
+        self.tunnels = tunnels;
+        self.counters = counters;
+        self.error = None;
+        Ok(())
+    }
+
+    fn draw(&self, frame: &mut Frame, socket: &Path) {
+        let [header, sparkline, table] =
+            Layout::vertical([Constraint::Length(3), Constraint::Length(7), Constraint::Min(0)]).areas(frame.area());
+
+        let new_failures = self.counters.failed_tunnels.saturating_sub(self.previous_failed);
+        let new_reconnects = self.counters.reconnects.saturating_sub(self.previous_reconnects);
+        let highlight = |new: u64| {
+            if new > 0 {
+                Style::new().red().add_modifier(Modifier::BOLD)
+            } else {
+                Style::new()
+            }
+        };
+        let status = match &self.error {
+            Some(err) => ratatui::text::Line::from(err.as_str().red()),
+            None => ratatui::text::Line::from(vec![
+                format!("active {}  ", self.counters.active_tunnels).into(),
+                format!("opened {}  ", self.counters.opened_tunnels).into(),
+                ratatui::text::Span::styled(
+                    format!("failed {}  ", self.counters.failed_tunnels),
+                    highlight(new_failures),
+                ),
+                ratatui::text::Span::styled(
+                    format!("reconnects {}", self.counters.reconnects),
+                    highlight(new_reconnects),
+                ),
+            ]),
+        };
+        frame.render_widget(
+            Paragraph::new(status)
+                .block(Block::bordered().title(format!(" wstunnel top - {} (q to quit) ", socket.display()))),
+            header,
+        );
+
+        let history: Vec<u64> = self.throughput.iter().copied().collect();
+        let visible = history.len().saturating_sub(sparkline.width.saturating_sub(2) as usize);
+        frame.render_widget(
+            Sparkline::default()
+                .block(
+                    Block::bordered()
+                        .title(format!(" throughput {}/s ", human_bytes(history.last().copied().unwrap_or(0)))),
+                )
+                .data(&history[visible..])
+                .style(Style::new().green()),
+            sparkline,
+        );
+
+        let rows = self.tunnels.iter().map(|tunnel| {
+            Row::new(vec![
+                // The end of the uuid, its beginning is a timestamp shared by the tunnels opened together
+                tunnel
+                    .id
+                    .get(tunnel.id.len().saturating_sub(8)..)
+                    .unwrap_or_default()
+                    .to_string(),
+                tunnel.source.clone().unwrap_or_default(),
+                tunnel.destination.clone(),
+                tunnel.protocol.clone(),
+                tunnel.identity.clone().unwrap_or_default(),
+                human_duration(tunnel.age_secs),
+                format!("{}/s", human_bytes(tunnel.bytes_per_sec)),
+                human_bytes(tunnel.bytes_sent),
+                human_bytes(tunnel.bytes_received),
+            ])
+        });
+        let widths = [
+            Constraint::Length(8),
+            Constraint::Min(15),
+            Constraint::Min(20),
+            Constraint::Length(18),
+            Constraint::Min(10),
+            Constraint::Length(9),
+            Constraint::Length(11),
+            Constraint::Length(9),
+            Constraint::Length(9),
+        ];
+        frame.render_widget(
+            Table::new(rows, widths)
+                .header(
+                    Row::new(vec![
+                        "ID",
+                        "SOURCE",
+                        "DESTINATION",
+                        "PROTOCOL",
+                        "IDENTITY",
+                        "AGE",
+                        "RATE",
+                        "SENT",
+                        "RECEIVED",
+                    ])
+                    .style(Style::new().add_modifier(Modifier::BOLD)),
+                )
+                .block(Block::bordered().title(format!(" {} tunnels ", self.tunnels.len()))),
+            table,
+        );
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+fn human_duration(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+pub fn run_top(args: Top) -> anyhow::Result<()> {
+    let mut client = AdminClient::connect(&args.admin_socket)?;
+    let interval = Duration::from_secs(args.interval.max(1));
+
+    let mut terminal = ratatui::init();
+    let ret = run_dashboard(&mut terminal, &mut client, &args.admin_socket, interval);
+    ratatui::restore();
+    ret
+}
+
+fn run_dashboard(
+    terminal: &mut DefaultTerminal,
+    client: &mut AdminClient,
+    socket: &Path,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let mut dashboard = Dashboard::default();
+    let mut last_refresh = Instant::now();
+    if let Err(err) = dashboard.refresh(client, interval) {
+        dashboard.error = Some(format!("{:#}", err));
+    }
+    // The first refresh counts all the bytes of the tunnels already open, it is not a rate
+    dashboard.throughput.clear();
+
+    loop {
+        terminal.draw(|frame| dashboard.draw(frame, socket))?;
+
+        let timeout = interval.saturating_sub(last_refresh.elapsed());
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    return Ok(());
+                }
+            }
+            continue;
+        }
+
+        // The client or server may have been restarted, connect again to its socket
+        let ret = dashboard.refresh(client, last_refresh.elapsed()).or_else(|_| {
+            *client = AdminClient::connect(socket)?;
+            dashboard.refresh(client, last_refresh.elapsed())
+        });
+        if let Err(err) = ret {
+            dashboard.error = Some(format!("{:#}", err));
+        }
+        last_refresh = Instant::now();
+    }
+}
@@ -0,0 +1,84 @@
+/*
+ * C API of the wstunnel client, to embed it in the desktop and mobile apps written in other languages.
+ * Link with the libwstunnel_ffi library built by `cargo build -p wstunnel-ffi --release`.
+ *
+ * The functions returning an error code, or null, set the message of the error. It is read with
+ * wstunnel_last_error() on the same thread.
+ */
+#ifndef WSTUNNEL_H
+#define WSTUNNEL_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct WstunnelClient WstunnelClient;
+
+/* Message of the last error on this thread, valid until the next call on this thread. Null if there is none */
+const char *wstunnel_last_error(void);
+
+/*
+ * Start a client with the arguments of `wstunnel client`, i.e:
+ *   const char *args[] = {"-L", "tcp://1212:google.com:443", "wss://wstunnel.example.com"};
+ *   WstunnelClient *client = wstunnel_client_start(args, 3);
+ * Null if the arguments are invalid. The errors of its startup, i.e: a port already in use, are returned by
+ * wstunnel_client_stop()
+ */
+WstunnelClient *wstunnel_client_start(const char *const *args, size_t args_len);
+
+/*
+ * Same as wstunnel_client_start(), with a tun tunnel using this address, i.e: "10.0.0.2". The server must have a
+ * packet network. The packets of the tun interface of the app, i.e: the one of an Android VpnService or of an iOS
+ * NEPacketTunnelProvider, are exchanged with wstunnel_client_write_packet() and wstunnel_client_read_packet()
+ */
+WstunnelClient *wstunnel_client_start_tun(const char *const *args, size_t args_len, const char *address);
+
+/*
+ * Start a tunnel on the running client, as given to its arguments: i.e: "-L tcp://1212:google.com:443". Its id is
+ * the same string, without the extra spaces. 0 on success, -1 on error
+ */
+int wstunnel_client_add_tunnel(WstunnelClient *client, const char *tunnel);
+
+/* Stop listening for the tunnel with this id, its open tunnels are left to drain. 0 on success, -1 on error */
+int wstunnel_client_remove_tunnel(WstunnelClient *client, const char *id);
+
+/*
+ * Status of the client as json, the same as answered to the status admin command: the state of the connection to
+ * the server and of each tunnel. To free with wstunnel_string_free(). Null on error
+ */
+char *wstunnel_client_status(WstunnelClient *client);
+
+/*
+ * Send an ip packet in the tun tunnel of the client. It is dropped if the tunnel is too slow, like a network
+ * interface does. 0 on success, -1 on error
+ */
+int wstunnel_client_write_packet(WstunnelClient *client, const unsigned char *packet, size_t len);
+
+/*
+ * Wait up to timeout_ms for an ip packet of the tun tunnel of the client, and copy it into buf. Its length on
+ * success, 0 if there is none yet, -1 on error, i.e: if buf is too small for the packet, which is then dropped.
+ * wstunnel_client_write_packet() can be called from another thread meanwhile
+ */
+ptrdiff_t wstunnel_client_read_packet(WstunnelClient *client, unsigned char *buf, size_t len, unsigned int timeout_ms);
+
+/*
+ * Called with the file descriptor of each socket opened by the clients of the process from now on, before it
+ * connects, i.e: to call VpnService.protect() on Android so the tunnels are not routed into the VPN of the app.
+ * It returns 0 on success, the socket is closed otherwise. ctx is given back to the callback, from any thread, and
+ * must stay valid until the callback is replaced. A null callback removes it. Not available on windows
+ */
+void wstunnel_set_socket_protector(int (*callback)(int fd, void *ctx), void *ctx);
+
+/* Stop the client and free it. 0 if it ran without error, -1 with the error which stopped it */
+int wstunnel_client_stop(WstunnelClient *client);
+
+/* Free a string returned by this library */
+void wstunnel_string_free(char *value);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WSTUNNEL_H */
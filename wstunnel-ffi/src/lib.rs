@@ -0,0 +1,399 @@
+//! C API of the client, so the desktop and mobile apps written in other languages embed it instead of running the
+//! binary. Each client runs in its own tokio runtime. The functions returning an error code set the message of the
+//! error, read with wstunnel_last_error() on the same thread
+
+use bytes::Bytes;
+use clap::Parser;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::net::IpAddr;
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use wstunnel::builder::{TunnelClientBuilder, TunnelHandle};
+use wstunnel::config::Client;
+use wstunnel::packet::{PacketQueue, PacketReceiver, PacketSender};
+#[cfg(unix)]
+use wstunnel::{SockRef, SocketProtector};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+pub struct WstunnelClient {
+    runtime: Runtime,
+    handle: TunnelHandle,
+    /// Of the clients started with a tun tunnel. Only the reads are serialized, the writes do not wait for them
+    packets: Option<(PacketSender, Mutex<PacketReceiver>)>,
+}
+
+/// Arguments of `wstunnel client`
+#[derive(Parser)]
+#[command(name = "wstunnel client", no_binary_name = true)]
+struct ClientArgs {
+    #[command(flatten)]
+    client: Client,
+}
+
+fn set_last_error(err: impl std::fmt::Display) {
+    let message = CString::new(err.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// The string, or sets the last error if it is null or not valid utf-8
+unsafe fn to_str<'a>(value: *const c_char, name: &str) -> Option<&'a str> {
+    if value.is_null() {
+        set_last_error(format!("{} is null", name));
+        return None;
+    }
+    match unsafe { CStr::from_ptr(value) }.to_str() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            set_last_error(format!("{} is not valid utf-8", name));
+            None
+        }
+    }
+}
+
+fn into_c_string(value: String) -> *mut c_char {
+    match CString::new(value) {
+        Ok(value) => value.into_raw(),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Message of the last error on this thread, valid until the next call on this thread. Null if there is none
+#[no_mangle]
+pub extern "C" fn wstunnel_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Arguments of `wstunnel client` given as C strings, or sets the last error if they are invalid
+unsafe fn parse_args(args: *const *const c_char, args_len: usize) -> Option<Client> {
+    if args.is_null() && args_len > 0 {
+        set_last_error("args is null");
+        return None;
+    }
+    let args = match args_len {
+        0 => &[],
+        _ => unsafe { slice::from_raw_parts(args, args_len) },
+    };
+    let args = args
+        .iter()
+        .map(|arg| unsafe { to_str(*arg, "argument") })
+        .collect::<Option<Vec<_>>>()?;
+    match ClientArgs::try_parse_from(args) {
+        Ok(args) => Some(args.client),
+        Err(err) => {
+            set_last_error(err.render());
+            None
+        }
+    }
+}
+
+/// Start a client with the arguments of `wstunnel client`, i.e: {"-L", "tcp://1212:google.com:443",
+/// "wss://wstunnel.example.com"}. Null if the arguments are invalid. The errors of its startup, i.e: a port
+/// already in use, are returned by wstunnel_client_stop()
+///
+/// # Safety
+/// args must point to args_len valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_start(args: *const *const c_char, args_len: usize) -> *mut WstunnelClient {
+    let Some(args) = (unsafe { parse_args(args, args_len) }) else {
+        return ptr::null_mut();
+    };
+
+    start_client(TunnelClientBuilder::from_args(args), None)
+}
+
+/// Same as wstunnel_client_start(), with a tun tunnel using this address, i.e: "10.0.0.2". Its packets are
+/// exchanged with wstunnel_client_write_packet() and wstunnel_client_read_packet()
+///
+/// # Safety
+/// args must point to args_len valid C strings, address must be a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_start_tun(
+    args: *const *const c_char,
+    args_len: usize,
+    address: *const c_char,
+) -> *mut WstunnelClient {
+    let Some(address) = (unsafe { to_str(address, "address") }) else {
+        return ptr::null_mut();
+    };
+    let address = match address.parse::<IpAddr>() {
+        Ok(address) => address,
+        Err(err) => {
+            set_last_error(format!("Invalid address {}: {}", address, err));
+            return ptr::null_mut();
+        }
+    };
+    let Some(args) = (unsafe { parse_args(args, args_len) }) else {
+        return ptr::null_mut();
+    };
+
+    let (queue, device) = wstunnel::packet::channel(1024);
+    start_client(TunnelClientBuilder::from_args(args).packet_tunnel(address, device), Some(queue))
+}
+
+fn start_client(builder: TunnelClientBuilder, packets: Option<PacketQueue>) -> *mut WstunnelClient {
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            set_last_error(format!("Cannot start the tokio runtime: {}", err));
+            return ptr::null_mut();
+        }
+    };
+
+    let handle = {
+        let _runtime = runtime.enter();
+        builder.spawn()
+    };
+    Box::into_raw(Box::new(WstunnelClient {
+        runtime,
+        handle,
+        packets: packets.map(|queue| {
+            let (sender, receiver) = queue.into_split();
+            (sender, Mutex::new(receiver))
+        }),
+    }))
+}
+
+/// Start a tunnel on the running client, as given to its arguments: i.e: "-L tcp://1212:google.com:443". Its id is
+/// the same string, without the extra spaces. 0 on success, -1 on error
+///
+/// # Safety
+/// client must be a client returned by wstunnel_client_start() and not stopped yet, tunnel a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_add_tunnel(client: *mut WstunnelClient, tunnel: *const c_char) -> c_int {
+    let Some(client) = (unsafe { client.as_ref() }) else {
+        set_last_error("client is null");
+        return -1;
+    };
+    let Some(tunnel) = (unsafe { to_str(tunnel, "tunnel") }) else {
+        return -1;
+    };
+    match client.runtime.block_on(client.handle.add_tunnel(tunnel)) {
+        Ok(_) => 0,
+        Err(err) => {
+            set_last_error(format!("{:#}", err));
+            -1
+        }
+    }
+}
+
+/// Stop listening for the tunnel with this id, its open tunnels are left to drain. 0 on success, -1 on error,
+/// i.e: if there is no tunnel with this id
+///
+/// # Safety
+/// client must be a client returned by wstunnel_client_start() and not stopped yet, id a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_remove_tunnel(client: *mut WstunnelClient, id: *const c_char) -> c_int {
+    let Some(client) = (unsafe { client.as_ref() }) else {
+        set_last_error("client is null");
+        return -1;
+    };
+    let Some(id) = (unsafe { to_str(id, "id") }) else {
+        return -1;
+    };
+    match client.runtime.block_on(client.handle.remove_tunnel(id)) {
+        Ok(true) => 0,
+        Ok(false) => {
+            set_last_error(format!("No tunnel with id {}", id));
+            -1
+        }
+        Err(err) => {
+            set_last_error(format!("{:#}", err));
+            -1
+        }
+    }
+}
+
+/// Status of the client as json, the same as answered to the status admin command. To free with
+/// wstunnel_string_free(). Null on error, i.e: if the client has stopped by itself
+///
+/// # Safety
+/// client must be a client returned by wstunnel_client_start() and not stopped yet
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_status(client: *mut WstunnelClient) -> *mut c_char {
+    let Some(client) = (unsafe { client.as_ref() }) else {
+        set_last_error("client is null");
+        return ptr::null_mut();
+    };
+    if client.handle.is_finished() {
+        set_last_error("The client has stopped, see wstunnel_client_stop()");
+        return ptr::null_mut();
+    }
+    let status = client
+        .runtime
+        .block_on(client.handle.status())
+        .and_then(|status| Ok(serde_json::to_string(&status)?));
+    match status {
+        Ok(status) => into_c_string(status),
+        Err(err) => {
+            set_last_error(format!("{:#}", err));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Queue of the packets of the tun tunnel of the client, or sets the last error
+unsafe fn packet_queue<'a>(client: *mut WstunnelClient) -> Option<&'a (PacketSender, Mutex<PacketReceiver>)> {
+    let Some(client) = (unsafe { client.as_ref() }) else {
+        set_last_error("client is null");
+        return None;
+    };
+    if client.packets.is_none() {
+        set_last_error("The client has no tun tunnel, see wstunnel_client_start_tun()");
+    }
+    client.packets.as_ref()
+}
+
+/// Send an ip packet in the tun tunnel of the client. It is dropped if the tunnel is too slow, like a network
+/// interface does. 0 on success, -1 on error, i.e: once the client is stopped
+///
+/// # Safety
+/// client must be a client returned by wstunnel_client_start_tun() and not stopped yet, packet must point to len bytes
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_write_packet(
+    client: *mut WstunnelClient,
+    packet: *const u8,
+    len: usize,
+) -> c_int {
+    let Some((sender, _)) = (unsafe { packet_queue(client) }) else {
+        return -1;
+    };
+    if packet.is_null() {
+        set_last_error("packet is null");
+        return -1;
+    }
+    let packet = Bytes::copy_from_slice(unsafe { slice::from_raw_parts(packet, len) });
+    match sender.try_send(packet) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Wait up to timeout_ms for an ip packet of the tun tunnel of the client, and copy it into buf. Its length on
+/// success, 0 if there is none yet, -1 on error, i.e: if buf is too small for the packet, which is then dropped.
+/// wstunnel_client_write_packet() can be called from another thread meanwhile
+///
+/// # Safety
+/// client must be a client returned by wstunnel_client_start_tun() and not stopped yet, buf must point to len
+/// writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_read_packet(
+    client: *mut WstunnelClient,
+    buf: *mut u8,
+    len: usize,
+    timeout_ms: u32,
+) -> isize {
+    let Some((_, receiver)) = (unsafe { packet_queue(client) }) else {
+        return -1;
+    };
+    if buf.is_null() {
+        set_last_error("buf is null");
+        return -1;
+    }
+    let client = unsafe { &*client };
+    let mut receiver = receiver.lock().unwrap_or_else(|err| err.into_inner());
+    let packet = client
+        .runtime
+        .block_on(async { tokio::time::timeout(Duration::from_millis(timeout_ms.into()), receiver.recv()).await });
+    match packet {
+        Err(_) => 0,
+        Ok(None) => {
+            set_last_error("The client has stopped, see wstunnel_client_stop()");
+            -1
+        }
+        Ok(Some(packet)) if packet.len() > len => {
+            set_last_error(format!("Packet of {} bytes dropped, buf is too small", packet.len()));
+            -1
+        }
+        Ok(Some(packet)) => {
+            let buf = unsafe { slice::from_raw_parts_mut(buf, packet.len()) };
+            buf.copy_from_slice(&packet);
+            packet.len() as isize
+        }
+    }
+}
+
+/// Callback of the app and its context, the app keeps the context valid while it is set
+#[cfg(unix)]
+struct SocketProtectorCallback {
+    callback: extern "C" fn(fd: c_int, ctx: *mut c_void) -> c_int,
+    ctx: *mut c_void,
+}
+
+// The context is only given back to the callback of the app, which is responsible for its thread safety
+#[cfg(unix)]
+unsafe impl Send for SocketProtectorCallback {}
+#[cfg(unix)]
+unsafe impl Sync for SocketProtectorCallback {}
+
+#[cfg(unix)]
+impl SocketProtector for SocketProtectorCallback {
+    fn protect(&self, socket: SockRef) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        match (self.callback)(socket.as_raw_fd(), self.ctx) {
+            0 => Ok(()),
+            _ => Err(std::io::Error::other("refused by the socket protector of the app")),
+        }
+    }
+}
+
+/// Called with the file descriptor of each socket opened by the clients of the process from now on, before it
+/// connects, i.e: to call VpnService.protect() on Android. It returns 0 on success, the socket is closed otherwise.
+/// Null removes it
+///
+/// # Safety
+/// ctx is given to the callback from any thread, it must stay valid until the callback is replaced
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_set_socket_protector(
+    callback: Option<extern "C" fn(fd: c_int, ctx: *mut c_void) -> c_int>,
+    ctx: *mut c_void,
+) {
+    let protector =
+        callback.map(|callback| Box::new(SocketProtectorCallback { callback, ctx }) as Box<dyn SocketProtector>);
+    wstunnel::set_socket_protector(protector);
+}
+
+/// Stop the client and free it. 0 if it ran without error, -1 with the error which stopped it
+///
+/// # Safety
+/// client must be a client returned by wstunnel_client_start(), it cannot be used anymore
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_stop(client: *mut WstunnelClient) -> c_int {
+    if client.is_null() {
+        set_last_error("client is null");
+        return -1;
+    }
+    let WstunnelClient { runtime, handle, .. } = *unsafe { Box::from_raw(client) };
+    match runtime.block_on(handle.shutdown()) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(format!("{:#}", err));
+            -1
+        }
+    }
+}
+
+/// Free a string returned by this library
+///
+/// # Safety
+/// value must be a string returned by this library, or null
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_string_free(value: *mut c_char) {
+    if !value.is_null() {
+        drop(unsafe { CString::from_raw(value) });
+    }
+}
@@ -0,0 +1,109 @@
+use crate::protocol::upgrade_request;
+use js_sys::{Array, Function, Math, Promise, Uint8Array};
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+use wstunnel::protocol::LocalProtocol;
+
+/// Tunnel to a destination of the server, through a websocket of the browser
+#[wasm_bindgen]
+pub struct BrowserTunnel {
+    ws: WebSocket,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+#[wasm_bindgen]
+impl BrowserTunnel {
+    /// Open a tunnel to host:port through the server at ws[s]://wstunnel.example.com. The protocol is tcp, or udp with
+    /// udp_timeout_secs. on_data is called with an Uint8Array for each message of the destination (a datagram for
+    /// udp), on_close once the tunnel is closed
+    #[allow(clippy::too_many_arguments)]
+    pub async fn connect(
+        server: &str,
+        path_prefix: &str,
+        protocol: &str,
+        host: &str,
+        port: u16,
+        udp_timeout_secs: Option<u32>,
+        on_data: Function,
+        on_close: Function,
+    ) -> Result<BrowserTunnel, JsValue> {
+        let protocol = match protocol {
+            "tcp" => LocalProtocol::Tcp { proxy_protocol: false },
+            "udp" => LocalProtocol::Udp {
+                timeout: udp_timeout_secs.map(|secs| Duration::from_secs(secs.into())),
+            },
+            _ => return Err(JsValue::from_str("Invalid protocol, expected tcp or udp")),
+        };
+        let request = upgrade_request(server, path_prefix, &random_id(), &protocol, host, port)?;
+        let protocols = request.protocols.iter().map(JsValue::from).collect::<Array>();
+        let ws = WebSocket::new_with_str_sequence(&request.url, &protocols)?;
+        ws.set_binary_type(BinaryType::Arraybuffer);
+
+        // The reason of a failed upgrade, i.e: the tunnel is not allowed by the restrictions, is not given to the page
+        let opened = Promise::new(&mut |resolve, reject| {
+            ws.set_onopen(Some(&resolve));
+            ws.set_onerror(Some(&reject));
+        });
+        let ret = JsFuture::from(opened).await;
+        ws.set_onopen(None);
+        ws.set_onerror(None);
+        if ret.is_err() {
+            return Err(JsValue::from_str(
+                "Cannot open the tunnel, the server refused or cannot be reached",
+            ));
+        }
+
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            let data = Uint8Array::new(&event.data());
+            let _ = on_data.call1(&JsValue::NULL, &data);
+        });
+        ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |_: CloseEvent| {
+            let _ = on_close.call0(&JsValue::NULL);
+        });
+        ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        Ok(Self {
+            ws,
+            _on_message: on_message,
+            _on_close: on_close,
+        })
+    }
+
+    /// Send the bytes to the destination, as a single datagram for udp
+    pub fn send(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.ws.send_with_u8_array(data)
+    }
+
+    /// Bytes sent but not transmitted yet, to slow down before the browser buffers too much
+    pub fn buffered_amount(&self) -> u32 {
+        self.ws.buffered_amount()
+    }
+
+    pub fn close(&self) -> Result<(), JsValue> {
+        self.ws.close()
+    }
+}
+
+impl Drop for BrowserTunnel {
+    fn drop(&mut self) {
+        self.ws.set_onmessage(None);
+        self.ws.set_onclose(None);
+        let _ = self.ws.close();
+    }
+}
+
+/// Uuid v4 identifying the tunnel in the logs of the server
+fn random_id() -> String {
+    let mut bytes = [0u8; 16];
+    for byte in &mut bytes {
+        *byte = (Math::random() * 256.0) as u8;
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
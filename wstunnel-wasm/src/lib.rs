@@ -0,0 +1,11 @@
+//! Client of the tunnels compiled to wasm32, for the browsers. Each tunnel is a websocket of the browser to the
+//! server, opened with the same upgrade request as the client, so the server needs no change. The tunnels are given to
+//! the page, to a service worker intercepting its requests or to a local companion forwarding them to the sockets
+//! the browser cannot open. Only ws:// and wss:// servers can be reached, the browsers do not expose http2 streams
+
+#[cfg(target_arch = "wasm32")]
+mod browser;
+pub mod protocol;
+
+#[cfg(target_arch = "wasm32")]
+pub use browser::BrowserTunnel;
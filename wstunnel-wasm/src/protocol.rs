@@ -0,0 +1,77 @@
+//! Upgrade request of a tunnel, as sent by the client: the destination is given to the server in a jwt, sent as a
+//! websocket protocol
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use wstunnel::protocol::{JwtTunnelConfig, LocalProtocol, JWT_HEADER_PREFIX};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeRequest {
+    /// i.e: wss://wstunnel.example.com/v1/events
+    pub url: String,
+    /// Websocket protocols of the request, the server answers with the first one
+    pub protocols: Vec<String>,
+}
+
+/// Upgrade request of the tunnel with this id to host:port, through the server at ws[s]://wstunnel.example.com[:port]
+pub fn upgrade_request(
+    server: &str,
+    path_prefix: &str,
+    id: &str,
+    protocol: &LocalProtocol,
+    host: &str,
+    port: u16,
+) -> Result<UpgradeRequest, String> {
+    if !server.starts_with("ws://") && !server.starts_with("wss://") {
+        return Err(format!("Invalid server {}, expected ws:// or wss://", server));
+    }
+    let claims = JwtTunnelConfig {
+        id: id.to_string(),
+        p: protocol.clone(),
+        r: host.to_string(),
+        rp: port,
+    };
+    let claims = serde_json::to_vec(&claims).map_err(|err| format!("Cannot serialize the tunnel: {}", err))?;
+    // The server does not check the signature, the client signs it with a random key
+    let jwt = format!(
+        "{}.{}.",
+        URL_SAFE_NO_PAD.encode(br#"{"typ":"JWT","alg":"HS256"}"#),
+        URL_SAFE_NO_PAD.encode(claims)
+    );
+
+    Ok(UpgradeRequest {
+        url: format!("{}/{}/events", server.trim_end_matches('/'), path_prefix),
+        protocols: vec!["v1".to_string(), format!("{}{}", JWT_HEADER_PREFIX, jwt)],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_upgrade_request() {
+        let protocol = LocalProtocol::Udp {
+            timeout: Some(Duration::from_secs(30)),
+        };
+        let request = upgrade_request("wss://wstunnel.example.com/", "v1", "id", &protocol, "1.1.1.1", 53).unwrap();
+        assert_eq!(request.url, "wss://wstunnel.example.com/v1/events");
+        assert_eq!(request.protocols[0], "v1");
+
+        let jwt = request.protocols[1].strip_prefix(JWT_HEADER_PREFIX).unwrap();
+        let claims = jwt.split('.').nth(1).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(
+            claims,
+            serde_json::json!({
+                "id": "id",
+                "p": { "Udp": { "timeout": { "secs": 30, "nanos": 0 } } },
+                "r": "1.1.1.1",
+                "rp": 53,
+            })
+        );
+
+        assert!(upgrade_request("https://wstunnel.example.com", "v1", "id", &protocol, "1.1.1.1", 53).is_err());
+    }
+}